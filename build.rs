@@ -0,0 +1,21 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "ffi")]
+    write_ffi_header();
+}
+
+/// Regenerates `include/sd_controller.h` from the C interface in `src/ffi.rs`.
+#[cfg(feature = "ffi")]
+fn write_ffi_header() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let dir = std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let config = cbindgen::Config::from_file(dir.join("cbindgen.toml"))
+        .expect("cbindgen.toml should be valid");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(dir.join("src/ffi.rs"))
+        .generate()
+        .expect("the C interface should be expressible in C")
+        .write_to_file(dir.join("include/sd_controller.h"));
+}
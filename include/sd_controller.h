@@ -0,0 +1,138 @@
+#ifndef SD_CONTROLLER_H
+#define SD_CONTROLLER_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum SdctlStatus {
+  SDCTL_STATUS_OK = 0,
+  // A pointer was null, a string not UTF-8, or a buffer the wrong size.
+  SDCTL_STATUS_INVALID_ARGUMENT = 1,
+  SDCTL_STATUS_NOT_FOUND = 2,
+  // The host failed to read the device.
+  SDCTL_STATUS_IO = 3,
+  // No FAT or exFAT volume was found on the device.
+  SDCTL_STATUS_NO_FILESYSTEM = 4,
+  // The library panicked; the controller should not be used again.
+  SDCTL_STATUS_PANIC = 5,
+  // Any other error, described by `sdctl_last_error`.
+  SDCTL_STATUS_FAILED = 6,
+} SdctlStatus;
+
+// An open card or image, from `sdctl_open`.
+typedef struct SdctlController SdctlController;
+
+// One entry of a directory listing. `name` is the long name if the entry
+// has one, NUL-terminated.
+typedef struct SdctlDirEntry {
+  char *name;
+  uint64_t size;
+  uint32_t first_cluster;
+  // The FAT attribute bits.
+  uint8_t attributes;
+  bool is_dir;
+} SdctlDirEntry;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Opens the device node or image file at `path` read-only and finds the
+// volume on it: the first partition holding a filesystem, or the whole
+// device. A device without a volume still opens, for `sdctl_read_block`.
+//
+// # Safety
+//
+// `path` must be a NUL-terminated string and `out` point to writable
+// memory for a pointer.
+enum SdctlStatus sdctl_open(const char *path, struct SdctlController **out);
+
+// Closes a controller from `sdctl_open`. Null is ignored.
+//
+// # Safety
+//
+// `controller` must be null or come from `sdctl_open`, and not be used
+// again.
+void sdctl_close(struct SdctlController *controller);
+
+// The block size of the device, in bytes, or 0 for a null controller.
+//
+// # Safety
+//
+// `controller` must be null or come from `sdctl_open`.
+size_t sdctl_block_size(const struct SdctlController *controller);
+
+// The number of blocks on the device, or 0 for a null controller.
+//
+// # Safety
+//
+// `controller` must be null or come from `sdctl_open`.
+uint64_t sdctl_num_blocks(const struct SdctlController *controller);
+
+// Reads the block at absolute LBA `block` into `buffer`, whose length
+// `len` must be the block size.
+//
+// # Safety
+//
+// `controller` must come from `sdctl_open`, and `buffer` point to `len`
+// writable bytes.
+enum SdctlStatus sdctl_read_block(struct SdctlController *controller,
+                                  uint32_t block,
+                                  uint8_t *buffer,
+                                  size_t len);
+
+// Lists the directory at `path` on the volume, without `.`, `..` and the
+// volume label. On success `*entries` points to `*count` entries, to be
+// freed with `sdctl_free_dir`.
+//
+// # Safety
+//
+// `controller` must come from `sdctl_open`, `path` be a NUL-terminated
+// string, and `entries` and `count` point to writable memory.
+enum SdctlStatus sdctl_list_dir(struct SdctlController *controller,
+                                const char *path,
+                                struct SdctlDirEntry **entries,
+                                size_t *count);
+
+// Frees a listing from `sdctl_list_dir`. Null is ignored.
+//
+// # Safety
+//
+// `entries` and `count` must be as `sdctl_list_dir` returned them, and the
+// listing not be used again.
+void sdctl_free_dir(struct SdctlDirEntry *entries, size_t count);
+
+// Reads the whole file at `path` on the volume. On success `*data` points
+// to its `*len` bytes, to be freed with `sdctl_free_buffer`.
+//
+// # Safety
+//
+// `controller` must come from `sdctl_open`, `path` be a NUL-terminated
+// string, and `data` and `len` point to writable memory.
+enum SdctlStatus sdctl_read_file(struct SdctlController *controller,
+                                 const char *path,
+                                 uint8_t **data,
+                                 size_t *len);
+
+// Frees file contents from `sdctl_read_file`. Null is ignored.
+//
+// # Safety
+//
+// `data` and `len` must be as `sdctl_read_file` returned them, and the
+// contents not be used again.
+void sdctl_free_buffer(uint8_t *data, size_t len);
+
+// The message of the last error on the calling thread, or null if there
+// has been none. The string stays valid until the next call that fails.
+const char *sdctl_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SD_CONTROLLER_H */
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use sha2::{Digest as _, Sha256};
+
+use crate::block::{BlockDevice, ReadOnlyDevice};
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::hash::{Digest, HashAlgorithm};
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes imaged between progress updates.
+const CHUNK_BYTES: u64 = 1 << 20;
+
+/// An append-only record of what was done to a device, one JSON object to
+/// a line. Each line carries `seq`, the Unix time `at`, an `event` and its
+/// `details`, and a `signature`: the HMAC-SHA256 of the line without it,
+/// joined to the signature of the line before. Removing, reordering or
+/// editing a line breaks every signature after it.
+///
+/// The key should be kept apart from the log; with no key the signatures
+/// only chain the lines together, which shows accidental damage but not a
+/// log written over from scratch.
+pub struct AuditLog<W: Write> {
+    out: W,
+    key: Vec<u8>,
+    seq: u64,
+    last: String,
+}
+
+impl<W: Write> AuditLog<W> {
+    pub fn new(out: W, key: Option<&[u8]>) -> Self {
+        AuditLog {
+            out,
+            key: key.unwrap_or_default().to_vec(),
+            seq: 0,
+            last: String::new(),
+        }
+    }
+
+    /// Appends an entry and flushes it, so that the log is whole up to the
+    /// last operation even if the process dies.
+    pub fn record(&mut self, event: &str, details: Value) -> Result<(), SDError> {
+        let mut entry = json!({
+            "seq": self.seq,
+            "at": unix_time(),
+            "event": event,
+            "details": details,
+        });
+        let signature = sign(&self.key, &self.last, &entry.to_string());
+        entry["signature"] = Value::String(signature.clone());
+        writeln!(self.out, "{entry}")?;
+        self.out.flush()?;
+        self.seq += 1;
+        self.last = signature;
+        Ok(())
+    }
+
+    /// The signature of the last entry, which vouches for the whole log.
+    pub fn last_signature(&self) -> &str {
+        &self.last
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Checks every signature of an audit log written with `key`, returning the
+/// number of entries. Fails with `AuditLogTampered` at the first line that
+/// does not match.
+pub fn verify_audit_log<R: BufRead>(input: R, key: Option<&[u8]>) -> Result<u64, SDError> {
+    let key = key.unwrap_or_default();
+    let mut last = String::new();
+    let mut entries = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let tampered = SDError::AuditLogTampered { line: index + 1 };
+        let Ok(Value::Object(mut entry)) = serde_json::from_str::<Value>(&line) else {
+            return Err(tampered);
+        };
+        let Some(Value::String(signature)) = entry.remove("signature") else {
+            return Err(tampered);
+        };
+        if entry.get("seq").and_then(Value::as_u64) != Some(entries)
+            || sign(key, &last, &Value::Object(entry).to_string()) != signature
+        {
+            return Err(tampered);
+        }
+        last = signature;
+        entries += 1;
+    }
+    Ok(entries)
+}
+
+/// HMAC-SHA256 of the previous signature and `body`, in hex.
+fn sign(key: &[u8], previous: &str, body: &str) -> String {
+    const BLOCK: usize = 64;
+    let mut padded = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        padded[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(padded.map(|byte| byte ^ 0x36));
+    inner.update(previous.as_bytes());
+    inner.update(b"\n");
+    inner.update(body.as_bytes());
+    let mut outer = Sha256::new();
+    outer.update(padded.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// How `acquire` images a device.
+#[derive(Debug, Clone)]
+pub struct AcquireOptions {
+    /// Digests taken of the image as it is written. At least one is
+    /// needed.
+    pub algorithms: Vec<HashAlgorithm>,
+    /// Reads the device a second time after imaging and checks that it
+    /// hashes the same, which shows the card gave the same data twice.
+    pub verify: bool,
+    /// What the device is called in the log and report, such as its path.
+    pub device: Option<String>,
+    pub case: Option<String>,
+    pub examiner: Option<String>,
+}
+
+impl Default for AcquireOptions {
+    fn default() -> Self {
+        AcquireOptions {
+            algorithms: vec![HashAlgorithm::Sha256],
+            verify: true,
+            device: None,
+            case: None,
+            examiner: None,
+        }
+    }
+}
+
+/// What an acquisition took and found, for the case file.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct AcquisitionReport {
+    pub device: Option<String>,
+    pub case: Option<String>,
+    pub examiner: Option<String>,
+    /// Unix times.
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub block_size: usize,
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Digests of the image, by algorithm name.
+    pub digests: BTreeMap<&'static str, Digest>,
+    /// Whether a second read of the device hashed the same, if one was
+    /// made.
+    pub verified: Option<bool>,
+    /// The signature of the audit log's last entry.
+    pub audit_signature: String,
+}
+
+impl AcquisitionReport {
+    pub fn write_json<W: Write>(&self, out: W) -> Result<(), SDError> {
+        serde_json::to_writer_pretty(out, self).map_err(io::Error::from)?;
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> SDController<ReadOnlyDevice<D>> {
+    /// Images the whole device into `image` for evidence, hashing it on the
+    /// way and recording each step in `log`. A controller that cannot
+    /// write is required, so that the acquisition cannot change the card.
+    /// A read error ends it, after it has been logged.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn acquire<W: Write, L: Write, P: ProgressSink>(
+        &mut self,
+        image: &mut W,
+        log: &mut AuditLog<L>,
+        options: &AcquireOptions,
+        progress: &mut P,
+    ) -> Result<AcquisitionReport, SDError> {
+        let first = *options
+            .algorithms
+            .first()
+            .ok_or(SDError::Unsupported("acquiring without a hash algorithm"))?;
+        self.close_partition();
+        let started_at = unix_time();
+        let block_size = self.block_size();
+        let blocks = self.num_blocks();
+        let bytes = blocks * block_size as u64;
+        log.record(
+            "acquisition_started",
+            json!({
+                "tool": concat!("sd_controller ", env!("CARGO_PKG_VERSION")),
+                "device": options.device,
+                "case": options.case,
+                "examiner": options.examiner,
+                "block_size": block_size,
+                "blocks": blocks,
+                "algorithms": options.algorithms,
+            }),
+        )?;
+
+        let mut hashers = options
+            .algorithms
+            .iter()
+            .map(|algorithm| algorithm.hasher())
+            .collect::<Result<Vec<_>, _>>()?;
+        let chunk_blocks = (CHUNK_BYTES / block_size as u64).max(1);
+        let stopwatch = Stopwatch::start();
+        let mut block = 0;
+        while block < blocks {
+            let count = chunk_blocks.min(blocks - block);
+            let data = match self.read_blocks(block_index(block)?, count as u32) {
+                Ok(data) => data,
+                Err(e) => {
+                    log.record(
+                        "read_failed",
+                        json!({ "block": block, "count": count, "error": e.to_string() }),
+                    )?;
+                    return Err(e);
+                }
+            };
+            for hasher in &mut hashers {
+                hasher.update(&data);
+            }
+            image.write_all(&data)?;
+            block += count;
+            progress.report(&Progress {
+                phase: Phase::Reading,
+                bytes_done: block * block_size as u64,
+                bytes_total: bytes,
+                elapsed: stopwatch.elapsed(),
+            });
+        }
+        image.flush()?;
+        let digests: BTreeMap<&'static str, Digest> = options
+            .algorithms
+            .iter()
+            .zip(hashers)
+            .map(|(&algorithm, hasher)| {
+                let digest = Digest {
+                    algorithm,
+                    bytes: hasher.finish(),
+                };
+                (algorithm.name(), digest)
+            })
+            .collect();
+        log.record(
+            "image_written",
+            json!({ "bytes": bytes, "digests": digests }),
+        )?;
+
+        let verified = if options.verify {
+            let again = self.hash_device(0..blocks, first, progress)?;
+            let matches = digests.get(first.name()) == Some(&again);
+            log.record(
+                "verified",
+                json!({ "algorithm": first, "digest": again, "matches": matches }),
+            )?;
+            Some(matches)
+        } else {
+            None
+        };
+
+        log.record(
+            "acquisition_finished",
+            json!({ "bytes": bytes, "verified": verified }),
+        )?;
+        Ok(AcquisitionReport {
+            device: options.device.clone(),
+            case: options.case.clone(),
+            examiner: options.examiner.clone(),
+            started_at,
+            finished_at: unix_time(),
+            block_size,
+            blocks,
+            bytes,
+            digests,
+            verified,
+            audit_signature: log.last_signature().to_string(),
+        })
+    }
+}
@@ -0,0 +1,253 @@
+#[cfg(feature = "std")]
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+use crate::block::BlockDevice;
+#[cfg(feature = "std")]
+use crate::crc32::Crc32;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::fat::{FatEntry, FatVariant};
+
+/// What a cluster of the data region holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ClusterState {
+    Free,
+    Used,
+    /// Marked bad in the FAT, and never allocated again.
+    Bad,
+}
+
+/// The state of every cluster of a volume, in the order the clusters lie
+/// on the card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocationMap {
+    /// Indexed by cluster number less 2, the first data cluster.
+    pub clusters: Vec<ClusterState>,
+}
+
+impl AllocationMap {
+    pub fn state(&self, cluster: u32) -> Option<ClusterState> {
+        let index = cluster.checked_sub(2)?;
+        self.clusters.get(index as usize).copied()
+    }
+
+    pub fn count(&self, state: ClusterState) -> usize {
+        self.clusters.iter().filter(|&&s| s == state).count()
+    }
+
+    /// Cuts the map into cells of `options.clusters_per_cell` clusters,
+    /// `options.columns` to a row.
+    #[cfg(feature = "std")]
+    fn cells(&self, options: &MapOptions) -> Vec<Vec<Cell>> {
+        let per_cell = options.clusters_per_cell.max(1);
+        let cells: Vec<Cell> = self.clusters.chunks(per_cell).map(Cell::of).collect();
+        cells
+            .chunks(options.columns.max(1))
+            .map(<[Cell]>::to_vec)
+            .collect()
+    }
+
+    /// Writes the map as rows of characters, each led by its first
+    /// cluster: `#` for a cell whose clusters are all used, `+` for one
+    /// only partly used, `.` for a free one and `X` for one holding a bad
+    /// cluster.
+    ///
+    /// ```text
+    ///        2  ####+...........X.......
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn write_text<W: Write>(&self, out: &mut W, options: &MapOptions) -> io::Result<()> {
+        let per_row = options.columns.max(1) * options.clusters_per_cell.max(1);
+        for (row, cells) in self.cells(options).iter().enumerate() {
+            let line: String = cells.iter().map(|cell| cell.symbol()).collect();
+            writeln!(out, "{:>9}  {}", 2 + row * per_row, line)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the map as a PNG image of `options.scale` pixels square per
+    /// cell: blue for used, light blue for partly used, white for free and
+    /// red for bad. The image data is stored without compression, which
+    /// keeps this free of dependencies; even a map of millions of clusters
+    /// comes to a few megabytes.
+    #[cfg(feature = "std")]
+    pub fn write_png<W: Write>(&self, out: &mut W, options: &MapOptions) -> io::Result<()> {
+        let rows = self.cells(options);
+        let scale = options.scale.max(1) as usize;
+        let width = options.columns.max(1) * scale;
+        let height = rows.len().max(1) * scale;
+
+        // Palette indices, one byte per pixel after a filter byte per line.
+        let mut pixels = Vec::with_capacity((width + 1) * height);
+        for cells in &rows {
+            let mut line = vec![0u8];
+            for cell in cells {
+                line.extend(std::iter::repeat_n(cell.palette_index(), scale));
+            }
+            // Cells missing from the end of the last row show as free.
+            line.resize(width + 1, Cell::Free.palette_index());
+            for _ in 0..scale {
+                pixels.extend_from_slice(&line);
+            }
+        }
+        pixels.resize((width + 1) * height, 0);
+
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        // 8-bit palette indices, no interlacing.
+        header.extend_from_slice(&[8, 3, 0, 0, 0]);
+        write_chunk(out, b"IHDR", &header)?;
+        let palette: Vec<u8> = Cell::PALETTE.iter().flatten().copied().collect();
+        write_chunk(out, b"PLTE", &palette)?;
+        write_chunk(out, b"IDAT", &zlib_stored(&pixels))?;
+        write_chunk(out, b"IEND", &[])
+    }
+}
+
+/// How `AllocationMap` is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapOptions {
+    /// Cells per row.
+    pub columns: usize,
+    pub clusters_per_cell: usize,
+    /// Pixels per side of a cell in a PNG.
+    pub scale: u32,
+}
+
+impl MapOptions {
+    /// Chooses how many clusters each cell stands for so that a map of
+    /// `clusters` fits in `rows` rows.
+    pub fn fit(clusters: usize, columns: usize, rows: usize) -> Self {
+        MapOptions {
+            columns,
+            clusters_per_cell: clusters.div_ceil(columns.max(1) * rows.max(1)).max(1),
+            ..MapOptions::default()
+        }
+    }
+}
+
+impl Default for MapOptions {
+    fn default() -> Self {
+        MapOptions {
+            columns: 64,
+            clusters_per_cell: 1,
+            scale: 8,
+        }
+    }
+}
+
+/// What a run of clusters drawn as one cell holds, worst first.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    Bad,
+    Used,
+    Partial,
+    Free,
+}
+
+#[cfg(feature = "std")]
+impl Cell {
+    const PALETTE: [[u8; 3]; 4] = [
+        [0xD0, 0x20, 0x20],
+        [0x20, 0x50, 0xC0],
+        [0x90, 0xB8, 0xF0],
+        [0xFF, 0xFF, 0xFF],
+    ];
+
+    fn of(clusters: &[ClusterState]) -> Cell {
+        if clusters.contains(&ClusterState::Bad) {
+            Cell::Bad
+        } else if clusters.iter().all(|&s| s == ClusterState::Used) {
+            Cell::Used
+        } else if clusters.contains(&ClusterState::Used) {
+            Cell::Partial
+        } else {
+            Cell::Free
+        }
+    }
+
+    fn symbol(self) -> char {
+        match self {
+            Cell::Bad => 'X',
+            Cell::Used => '#',
+            Cell::Partial => '+',
+            Cell::Free => '.',
+        }
+    }
+
+    fn palette_index(self) -> u8 {
+        self as u8
+    }
+}
+
+#[cfg(feature = "std")]
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&crc.finish().to_be_bytes())
+}
+
+/// Wraps `data` in a zlib stream of stored deflate blocks.
+#[cfg(feature = "std")]
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xFFFF;
+    let mut stream = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    stream.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    stream
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Reads which clusters of the volume are free, used and bad: from the
+    /// FAT, or on exFAT from the allocation bitmap, which also covers the
+    /// contiguous files the FAT does not record, with bad clusters still
+    /// taken from the FAT.
+    pub fn alloc_map(&mut self) -> Result<AllocationMap, SDError> {
+        let layout = self.layout()?;
+        let table = self.load_fat(&layout)?;
+        let bitmap = if layout.variant == FatVariant::ExFat {
+            Some(self.read_allocation_bitmap()?)
+        } else {
+            None
+        };
+        let clusters = (2..layout.cluster_count + 2)
+            .map(|cluster| match (table.fat_entry(cluster), &bitmap) {
+                (FatEntry::Bad, _) => ClusterState::Bad,
+                (_, Some(bitmap)) if bitmap.is_allocated(cluster) => ClusterState::Used,
+                (_, Some(_)) | (FatEntry::Free, None) => ClusterState::Free,
+                (_, None) => ClusterState::Used,
+            })
+            .collect();
+        Ok(AllocationMap { clusters })
+    }
+}
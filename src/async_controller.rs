@@ -0,0 +1,231 @@
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::task::{self, JoinHandle};
+
+use crate::block::{BlockDevice, FileDevice};
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+use crate::partition::PartitionEntry;
+
+/// Bytes an `AsyncFatFileReader` fetches per blocking task, at least.
+const READ_AHEAD_BYTES: usize = 256 * 1024;
+
+/// An `SDController` for async code. Each call runs the blocking controller
+/// on Tokio's blocking thread pool, so device reads never stall the
+/// executor. Clones share the same controller, and calls on it are served
+/// one at a time.
+pub struct AsyncSDController<D: BlockDevice> {
+    inner: Arc<Mutex<SDController<D>>>,
+}
+
+impl<D: BlockDevice> Clone for AsyncSDController<D> {
+    fn clone(&self) -> Self {
+        AsyncSDController {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl AsyncSDController<FileDevice> {
+    /// Opens an SD card device node or raw image read-only.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        let file = tokio::fs::File::open(path).await?.into_std().await;
+        let device = task::spawn_blocking(move || FileDevice::from_file(file, 512, false))
+            .await
+            .map_err(join_error)??;
+        Ok(AsyncSDController::new(SDController::from_device(device)))
+    }
+}
+
+impl<D: BlockDevice + Send + 'static> AsyncSDController<D> {
+    pub fn new(controller: SDController<D>) -> Self {
+        AsyncSDController {
+            inner: Arc::new(Mutex::new(controller)),
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool with the controller, for
+    /// anything the async methods do not cover.
+    pub async fn with_controller<T, F>(&self, f: F) -> Result<T, SDError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut SDController<D>) -> Result<T, SDError> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        task::spawn_blocking(move || f(&mut lock(&inner)))
+            .await
+            .map_err(join_error)?
+    }
+
+    pub async fn read_block(&self, block_index: u32) -> Result<Vec<u8>, SDError> {
+        self.with_controller(move |controller| controller.read_block(block_index))
+            .await
+    }
+
+    pub async fn read_blocks(&self, start: u32, count: u32) -> Result<Vec<u8>, SDError> {
+        self.with_controller(move |controller| controller.read_blocks(start, count))
+            .await
+    }
+
+    /// See `SDController::open_volume`.
+    pub async fn open_volume(&self) -> Result<Option<usize>, SDError> {
+        self.with_controller(|controller| controller.open_volume())
+            .await
+    }
+
+    pub async fn open_partition(&self, index: usize) -> Result<PartitionEntry, SDError> {
+        self.with_controller(move |controller| controller.open_partition(index))
+            .await
+    }
+
+    pub async fn stat(&self, path: &str) -> Result<DirEntry, SDError> {
+        let path = path.to_string();
+        self.with_controller(move |controller| controller.stat(&path))
+            .await
+    }
+
+    /// The entries of the directory at `path`.
+    pub async fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, SDError> {
+        let path = path.to_string();
+        self.with_controller(move |controller| {
+            let entry = controller.stat(&path)?;
+            if !entry.is_dir() {
+                return Err(SDError::NotADirectory(path));
+            }
+            Ok(controller.open_dir(&entry)?.collect())
+        })
+        .await
+    }
+
+    /// The whole contents of the file at `path`.
+    pub async fn read_file(&self, path: &str) -> Result<Vec<u8>, SDError> {
+        let path = path.to_string();
+        self.with_controller(move |controller| controller.open(&path))
+            .await
+    }
+
+    /// Opens the file at `path` for streaming, e.g. with `tokio::io::copy`.
+    pub async fn open_reader(&self, path: &str) -> Result<AsyncFatFileReader<D>, SDError> {
+        let entry = self.stat(path).await?;
+        if entry.is_dir() {
+            return Err(SDError::IsADirectory(path.to_string()));
+        }
+        Ok(AsyncFatFileReader {
+            controller: self.clone(),
+            entry,
+            position: 0,
+            clusters: Vec::new(),
+            buffer: Vec::new(),
+            buffer_position: 0,
+            pending: None,
+        })
+    }
+}
+
+type Chunk = (Vec<u8>, Vec<u32>);
+
+/// Streams a file from an `AsyncSDController`. Each blocking task reads
+/// ahead at least `READ_AHEAD_BYTES`, and hands back the part of the
+/// cluster chain it followed so the next one carries on from there.
+pub struct AsyncFatFileReader<D: BlockDevice> {
+    controller: AsyncSDController<D>,
+    entry: DirEntry,
+    /// Offset in the file of the end of `buffer`.
+    position: u64,
+    clusters: Vec<u32>,
+    buffer: Vec<u8>,
+    buffer_position: usize,
+    pending: Option<JoinHandle<Result<Chunk, SDError>>>,
+}
+
+impl<D: BlockDevice> AsyncFatFileReader<D> {
+    pub fn len(&self) -> u64 {
+        self.entry.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry.size == 0
+    }
+}
+
+impl<D: BlockDevice + Send + 'static> AsyncFatFileReader<D> {
+    fn fetch(&mut self, wanted: usize) -> JoinHandle<Result<Chunk, SDError>> {
+        let inner = Arc::clone(&self.controller.inner);
+        let entry = self.entry.clone();
+        let clusters = std::mem::take(&mut self.clusters);
+        let position = self.position;
+        let length = (wanted.max(READ_AHEAD_BYTES) as u64).min(entry.size - position) as usize;
+        task::spawn_blocking(move || {
+            let mut controller = lock(&inner);
+            let mut reader = controller.resume_reader(&entry, clusters)?;
+            reader.set_read_ahead(length);
+            reader.seek(SeekFrom::Start(position))?;
+            let mut data = vec![0; length];
+            reader.read_exact(&mut data)?;
+            Ok((data, reader.into_clusters()))
+        })
+    }
+}
+
+impl<D: BlockDevice + Send + 'static> AsyncRead for AsyncFatFileReader<D> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let reader = &mut *self;
+            if reader.buffer_position < reader.buffer.len() {
+                let available = &reader.buffer[reader.buffer_position..];
+                let count = available.len().min(buf.remaining());
+                buf.put_slice(&available[..count]);
+                reader.buffer_position += count;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(pending) = reader.pending.as_mut() {
+                let result = match Pin::new(pending).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => result,
+                };
+                reader.pending = None;
+                let (data, clusters) = result
+                    .map_err(join_error)
+                    .and_then(|chunk| chunk)
+                    .map_err(io::Error::from)?;
+                reader.position += data.len() as u64;
+                reader.clusters = clusters;
+                reader.buffer = data;
+                reader.buffer_position = 0;
+                continue;
+            }
+            if reader.position >= reader.entry.size || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            reader.pending = Some(reader.fetch(buf.remaining()));
+        }
+    }
+}
+
+/// Locks the controller, carrying on after a panic in another task, which
+/// leaves the controller's own state usable.
+fn lock<D: BlockDevice>(inner: &Mutex<SDController<D>>) -> MutexGuard<'_, SDController<D>> {
+    inner
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Passes on a panic in a blocking task, and reports its cancellation
+/// when the runtime shuts down.
+fn join_error(error: task::JoinError) -> SDError {
+    match error.try_into_panic() {
+        Ok(panic) => std::panic::resume_unwind(panic),
+        Err(error) => SDError::IO(io::Error::other(error)),
+    }
+}
@@ -0,0 +1,196 @@
+use core::fmt;
+use core::ops::{BitAnd, BitOr, BitOrAssign, Not};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{
+    DirEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM,
+    ATTR_VOLUME_ID,
+};
+use crate::error::SDError;
+
+/// The attribute byte of a directory entry. exFAT keeps the same bits in
+/// the low byte of its attribute field.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Attributes(u8);
+
+impl Attributes {
+    pub const READ_ONLY: Attributes = Attributes(ATTR_READ_ONLY);
+    pub const HIDDEN: Attributes = Attributes(ATTR_HIDDEN);
+    pub const SYSTEM: Attributes = Attributes(ATTR_SYSTEM);
+    pub const VOLUME_ID: Attributes = Attributes(ATTR_VOLUME_ID);
+    pub const DIRECTORY: Attributes = Attributes(ATTR_DIRECTORY);
+    pub const ARCHIVE: Attributes = Attributes(ATTR_ARCHIVE);
+    /// The bits `set_attributes` may change; the others say what kind of
+    /// entry it is.
+    pub const SETTABLE: Attributes =
+        Attributes(ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE);
+
+    const NAMES: [(Attributes, &'static str); 6] = [
+        (Attributes::READ_ONLY, "READ_ONLY"),
+        (Attributes::HIDDEN, "HIDDEN"),
+        (Attributes::SYSTEM, "SYSTEM"),
+        (Attributes::VOLUME_ID, "VOLUME_ID"),
+        (Attributes::DIRECTORY, "DIRECTORY"),
+        (Attributes::ARCHIVE, "ARCHIVE"),
+    ];
+
+    /// The attributes in an on-disk attribute byte. The two reserved high
+    /// bits are kept, so writing the byte back does not lose them.
+    pub const fn from_bits(bits: u8) -> Self {
+        Attributes(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn empty() -> Self {
+        Attributes(0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every bit of `other` is set.
+    pub const fn contains(self, other: Attributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Attributes) {
+        self.0 &= !other.0;
+    }
+
+    /// Inserts or removes `other` as `value` says.
+    pub fn set(&mut self, other: Attributes, value: bool) {
+        if value {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+
+    pub const fn is_read_only(self) -> bool {
+        self.contains(Attributes::READ_ONLY)
+    }
+
+    pub const fn is_hidden(self) -> bool {
+        self.contains(Attributes::HIDDEN)
+    }
+
+    pub const fn is_system(self) -> bool {
+        self.contains(Attributes::SYSTEM)
+    }
+
+    pub const fn is_archive(self) -> bool {
+        self.contains(Attributes::ARCHIVE)
+    }
+
+    /// Whether a listing hides the entry unless asked to show everything,
+    /// as DOS `dir` does with hidden and system files.
+    pub const fn is_concealed(self) -> bool {
+        self.0 & (ATTR_HIDDEN | ATTR_SYSTEM) != 0
+    }
+}
+
+impl BitOr for Attributes {
+    type Output = Attributes;
+
+    fn bitor(self, other: Attributes) -> Attributes {
+        Attributes(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Attributes {
+    fn bitor_assign(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for Attributes {
+    type Output = Attributes;
+
+    fn bitand(self, other: Attributes) -> Attributes {
+        Attributes(self.0 & other.0)
+    }
+}
+
+impl Not for Attributes {
+    type Output = Attributes;
+
+    fn not(self) -> Attributes {
+        Attributes(!self.0)
+    }
+}
+
+/// Lists the names of the set bits, e.g. `Attributes(READ_ONLY | ARCHIVE)`.
+impl fmt::Debug for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Attributes(")?;
+        let mut first = true;
+        for (flag, name) in Attributes::NAMES {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(" | ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        let unnamed = self.0 & !ATTR_ALL;
+        if unnamed != 0 {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{unnamed:#04x}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+const ATTR_ALL: u8 =
+    ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID | ATTR_DIRECTORY | ATTR_ARCHIVE;
+
+/// The `attrib` letters of the settable bits, `-` where a bit is clear,
+/// e.g. `R--A`.
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (flag, letter) in [
+            (Attributes::READ_ONLY, 'R'),
+            (Attributes::HIDDEN, 'H'),
+            (Attributes::SYSTEM, 'S'),
+            (Attributes::ARCHIVE, 'A'),
+        ] {
+            fmt::Write::write_char(f, if self.contains(flag) { letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Replaces the read-only, hidden, system and archive bits of the file
+    /// or directory at `path` with those in `attributes`. The directory and
+    /// volume label bits, and the reserved ones, are kept as they are.
+    /// FAT12/16/32 only.
+    pub fn set_attributes(
+        &mut self,
+        path: &str,
+        attributes: Attributes,
+    ) -> Result<DirEntry, SDError> {
+        let layout = self.writable_layout()?;
+        let (_, found) = self.locate(&layout, path)?;
+        let mut entry = found.entry;
+        entry.attributes =
+            (entry.attributes & !Attributes::SETTABLE) | (attributes & Attributes::SETTABLE);
+        // Rewrite only the attribute byte, leaving the name as stored.
+        self.write_entry_at(found.sector, found.offset + 11, &[entry.attributes.bits()])?;
+        Ok(entry)
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::block::BlockDevice;
+use crate::capacity::splitmix64;
+use crate::error::SDError;
+use crate::partition::block_index;
+use crate::scan::LatencyStats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchPattern {
+    /// Consecutive requests from the start of the device, as when copying
+    /// large files.
+    Sequential,
+    /// Requests at random aligned offsets, as when loading many small files
+    /// or running applications from the card.
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchOptions {
+    pub pattern: BenchPattern,
+    /// Bytes per read request; a multiple of the device block size.
+    pub request_bytes: usize,
+    /// Requests kept in flight at once, each from its own handle on the
+    /// device.
+    pub queue_depth: usize,
+    /// How long to keep reading. A sequential test also stops at the end of
+    /// the device.
+    pub duration: Duration,
+    /// Seeds the offsets of a random test.
+    pub seed: u64,
+}
+
+impl BenchOptions {
+    /// The usual test for `pattern`: 1 MiB sequential reads or 4 KiB random
+    /// reads, one at a time, for ten seconds.
+    pub fn new(pattern: BenchPattern) -> Self {
+        BenchOptions {
+            pattern,
+            request_bytes: match pattern {
+                BenchPattern::Sequential => 1 << 20,
+                BenchPattern::Random => 4096,
+            },
+            queue_depth: 1,
+            duration: Duration::from_secs(10),
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchReport {
+    pub options: BenchOptions,
+    pub requests: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Time from issuing each request to its completion.
+    pub latency: LatencyStats,
+}
+
+impl BenchReport {
+    pub fn bytes_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.bytes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    pub fn iops(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.requests as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Measures read performance. `open` is called once per unit of queue
+/// depth, so that each in-flight request has a handle of its own; any
+/// `BlockDevice` works, including image files.
+///
+/// Reads of a device node or image go through the operating system's
+/// cache, so a region read shortly before can report speeds far above
+/// what the card can do. Benchmark a freshly inserted card, and prefer
+/// random tests over a device larger than the memory of the machine.
+pub fn bench<D, F>(mut open: F, options: &BenchOptions) -> Result<BenchReport, SDError>
+where
+    D: BlockDevice + Send,
+    F: FnMut() -> Result<D, SDError>,
+{
+    let devices = (0..options.queue_depth.max(1))
+        .map(|_| open())
+        .collect::<Result<Vec<_>, _>>()?;
+    let block_size = devices[0].block_size();
+    if options.request_bytes == 0 || !options.request_bytes.is_multiple_of(block_size) {
+        return Err(SDError::InvalidBlockSize);
+    }
+    let request_blocks = (options.request_bytes / block_size) as u64;
+    let slots = devices[0].num_blocks() / request_blocks;
+    if slots == 0 {
+        return Err(SDError::BlockOutOfRange(request_blocks - 1));
+    }
+
+    let next_slot = AtomicU64::new(0);
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let results = thread::scope(|scope| {
+        let workers: Vec<_> = devices
+            .into_iter()
+            .enumerate()
+            .map(|(worker, mut device)| {
+                let next_slot = &next_slot;
+                scope.spawn(move || -> Result<Vec<Duration>, SDError> {
+                    let mut buffer = vec![0u8; options.request_bytes];
+                    let mut state = options.seed ^ ((worker as u64) << 32);
+                    let mut latencies = Vec::new();
+                    while Instant::now() < deadline {
+                        let slot = match options.pattern {
+                            BenchPattern::Sequential => {
+                                let slot = next_slot.fetch_add(1, Ordering::Relaxed);
+                                if slot >= slots {
+                                    break;
+                                }
+                                slot
+                            }
+                            BenchPattern::Random => splitmix64(&mut state) % slots,
+                        };
+                        let request_started = Instant::now();
+                        device.read_blocks(block_index(slot * request_blocks)?, &mut buffer)?;
+                        latencies.push(request_started.elapsed());
+                    }
+                    Ok(latencies)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("benchmark worker panicked"))
+            .collect::<Vec<_>>()
+    });
+    let elapsed = started.elapsed();
+
+    let mut latencies = Vec::new();
+    for result in results {
+        latencies.extend(result?);
+    }
+    let requests = latencies.len() as u64;
+    Ok(BenchReport {
+        options: *options,
+        requests,
+        bytes: requests * options.request_bytes as u64,
+        elapsed,
+        latency: LatencyStats::from_latencies(latencies),
+    })
+}
@@ -0,0 +1,575 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+
+use crate::error::SDError;
+#[cfg(feature = "std")]
+use crate::log::trace;
+
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+    fn num_blocks(&self) -> u64;
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError>;
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError>;
+
+    /// Reads consecutive blocks starting at `start` into `buffer`, whose
+    /// length must be a multiple of the block size. Devices that can do this
+    /// in a single request should override the default, which reads one
+    /// block at a time.
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        let block_size = self.block_size();
+        if !buffer.len().is_multiple_of(block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        for (i, block) in buffer.chunks_mut(block_size).enumerate() {
+            self.read_block(start + i as u32, block)?;
+        }
+        Ok(())
+    }
+
+    /// Writes consecutive blocks starting at `start` from `data`, whose
+    /// length must be a multiple of the block size. The default writes one
+    /// block at a time.
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        let block_size = self.block_size();
+        if !data.len().is_multiple_of(block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        for (i, block) in data.chunks(block_size).enumerate() {
+            self.write_block(start + i as u32, block)?;
+        }
+        Ok(())
+    }
+
+    /// Pushes buffered writes down to the storage medium.
+    fn flush(&mut self) -> Result<(), SDError> {
+        Ok(())
+    }
+
+    /// Tells the device that `count` blocks from `start` no longer hold
+    /// anything, so that a card can erase the flash behind them ahead of
+    /// the next write. What they read as afterwards is up to the device.
+    /// The default fails with `Unsupported`.
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        let _ = (start, count);
+        Err(SDError::Unsupported("discarding blocks on this device"))
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (**self).num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        (**self).read_block(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        (**self).read_blocks(start, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        (**self).write_block(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        (**self).write_blocks(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        (**self).discard(start, count)
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Box<D> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (**self).num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        (**self).read_block(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        (**self).read_blocks(start, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        (**self).write_block(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        (**self).write_blocks(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        (**self).discard(start, count)
+    }
+}
+
+/// Passes reads through to another device and refuses every write and
+/// discard with `ReadOnly`, whatever the controller above it allows.
+///
+/// `SDController::read_only` builds a controller over one, so that the type
+/// of a controller handed to forensic code shows it cannot change the
+/// evidence: writes fail even after `enable_writes`, and there is no way
+/// back to the writable device short of `into_inner`.
+#[derive(Debug, Clone)]
+pub struct ReadOnlyDevice<D: BlockDevice> {
+    inner: D,
+}
+
+impl<D: BlockDevice> ReadOnlyDevice<D> {
+    pub fn new(inner: D) -> Self {
+        ReadOnlyDevice { inner }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for ReadOnlyDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.inner.read_block(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.inner.read_blocks(start, buffer)
+    }
+
+    fn write_block(&mut self, _block_index: u32, _data: &[u8]) -> Result<(), SDError> {
+        Err(SDError::ReadOnly)
+    }
+
+    fn write_blocks(&mut self, _start: u32, _data: &[u8]) -> Result<(), SDError> {
+        Err(SDError::ReadOnly)
+    }
+
+    /// Nothing was written, so there is nothing to push down.
+    fn flush(&mut self) -> Result<(), SDError> {
+        Ok(())
+    }
+
+    fn discard(&mut self, _start: u32, _count: u32) -> Result<(), SDError> {
+        Err(SDError::ReadOnly)
+    }
+}
+
+/// A block device held in memory, for tests and for building images before
+/// writing them out. Reads past the end fail like they do on a real device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemBlockDevice {
+    data: Vec<u8>,
+    block_size: usize,
+}
+
+impl MemBlockDevice {
+    /// A zero-filled device of `num_blocks` blocks.
+    pub fn new(block_size: usize, num_blocks: u64) -> Result<Self, SDError> {
+        MemBlockDevice::from_vec(vec![0; block_size * num_blocks as usize], block_size)
+    }
+
+    /// Wraps an image already in memory, whose length must be a multiple of
+    /// `block_size`.
+    pub fn from_vec(data: Vec<u8>, block_size: usize) -> Result<Self, SDError> {
+        if block_size < 512
+            || !block_size.is_power_of_two()
+            || !data.len().is_multiple_of(block_size)
+        {
+            return Err(SDError::InvalidBlockSize);
+        }
+        Ok(MemBlockDevice { data, block_size })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn range(&self, start: u32, len: usize) -> Result<Range<usize>, SDError> {
+        byte_range(self.data.len(), self.block_size, start, len)
+    }
+}
+
+/// The byte range of `len` bytes from block `start` of a device held in
+/// `total` bytes, if they all exist.
+pub(crate) fn byte_range(
+    total: usize,
+    block_size: usize,
+    start: u32,
+    len: usize,
+) -> Result<Range<usize>, SDError> {
+    let offset = start as usize * block_size;
+    if offset + len > total {
+        let blocks = len.div_ceil(block_size).max(1);
+        return Err(SDError::BlockOutOfRange(start as u64 + blocks as u64 - 1));
+    }
+    Ok(offset..offset + len)
+}
+
+impl BlockDevice for MemBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_blocks(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if !buffer.len().is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let range = self.range(start, buffer.len())?;
+        buffer.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.write_blocks(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !data.len().is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let range = self.range(start, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Zeros the blocks, as cards that report discarded blocks reading as
+    /// zeros do.
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        let range = self.range(start, count as usize * self.block_size)?;
+        self.data[range].fill(0);
+        Ok(())
+    }
+}
+
+/// A block device backed by a file: a raw device node or an image file.
+#[cfg(feature = "std")]
+pub struct FileDevice {
+    file: File,
+    block_size: usize,
+    num_blocks: u64,
+    writable: bool,
+    /// With direct I/O, transfers are staged here at the alignment the OS
+    /// requires of buffers it does not cache.
+    bounce: Option<Vec<u8>>,
+    /// Locked volume handles that must stay open as long as the device is.
+    #[cfg(windows)]
+    volume_locks: Vec<File>,
+}
+
+#[cfg(feature = "std")]
+impl FileDevice {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        FileDevice::from_file(File::open(path)?, 512, false)
+    }
+
+    pub fn open_rw<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        FileDevice::from_file(file, 512, true)
+    }
+
+    /// Wraps an open file. `block_size` must be a power of two of at least
+    /// 512 bytes.
+    pub fn from_file(mut file: File, block_size: usize, writable: bool) -> Result<Self, SDError> {
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let len = file.seek(SeekFrom::End(0))?;
+        FileDevice::with_len(file, block_size, len, writable)
+    }
+
+    /// Like `from_file`, for files whose size cannot be found by seeking,
+    /// such as Windows physical drives.
+    pub fn with_len(
+        file: File,
+        block_size: usize,
+        len: u64,
+        writable: bool,
+    ) -> Result<Self, SDError> {
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        Ok(FileDevice {
+            file,
+            block_size,
+            num_blocks: len / block_size as u64,
+            writable,
+            bounce: None,
+            #[cfg(windows)]
+            volume_locks: Vec::new(),
+        })
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn hold_volume_locks(&mut self, locks: Vec<File>) {
+        self.volume_locks.extend(locks);
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    #[cfg(any(target_os = "macos", all(feature = "io-uring", target_os = "linux")))]
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Stages every transfer through an aligned buffer, for a file opened
+    /// with the page cache turned off.
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    pub(crate) fn use_direct_io(&mut self) {
+        self.bounce = Some(Vec::new());
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.bounce.is_some()
+    }
+
+    /// Reads from the current position, until `buffer` is full or the file
+    /// ends.
+    fn read_in(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let Some(bounce) = &mut self.bounce else {
+            return read_full(&mut self.file, buffer);
+        };
+        let staged = aligned(bounce, buffer.len());
+        let bytes_read = read_full(&mut self.file, staged)?;
+        buffer[..bytes_read].copy_from_slice(&staged[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    fn write_out(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some(bounce) = &mut self.bounce else {
+            return self.file.write_all(data);
+        };
+        let staged = aligned(bounce, data.len());
+        staged.copy_from_slice(data);
+        self.file.write_all(staged)
+    }
+
+    fn seek_to(&mut self, block_index: u32) -> Result<(), SDError> {
+        let position = block_index as u64 * self.block_size as u64;
+        trace!(position, "seek");
+        self.file.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+}
+
+/// Alignment of direct I/O buffers: the page size, which satisfies every
+/// logical block size an SD card reader reports.
+#[cfg(feature = "std")]
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// The first `len` bytes of `buffer` from an aligned address, growing it
+/// as needed.
+#[cfg(feature = "std")]
+pub(crate) fn aligned(buffer: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    if buffer.len() < len + DIRECT_IO_ALIGNMENT {
+        buffer.resize(len + DIRECT_IO_ALIGNMENT, 0);
+    }
+    let start = buffer.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+    &mut buffer[start..start + len]
+}
+
+/// Reads until `buffer` is full or the end of the file is reached, returning
+/// the number of bytes read. A single `read` may legitimately return less
+/// than was asked for, in particular on device nodes and pipes.
+#[cfg(feature = "std")]
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(feature = "std")]
+impl BlockDevice for FileDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.seek_to(block_index)?;
+
+        let bytes_read = self.read_in(buffer)?;
+        if bytes_read != self.block_size {
+            return Err(SDError::ReadError {
+                expected: self.block_size,
+                actual: bytes_read,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if !buffer.len().is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.seek_to(start)?;
+
+        let bytes_read = self.read_in(buffer)?;
+        if bytes_read != buffer.len() {
+            return Err(SDError::ReadError {
+                expected: buffer.len(),
+                actual: bytes_read,
+            });
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if !self.writable {
+            return Err(SDError::ReadOnly);
+        }
+        if data.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if block_index as u64 >= self.num_blocks {
+            return Err(SDError::BlockOutOfRange(block_index as u64));
+        }
+        self.seek_to(block_index)?;
+        self.write_out(data)?;
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !self.writable {
+            return Err(SDError::ReadOnly);
+        }
+        if !data.len().is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let blocks = (data.len() / self.block_size) as u64;
+        if start as u64 + blocks > self.num_blocks {
+            return Err(SDError::BlockOutOfRange(start as u64 + blocks - 1));
+        }
+        self.seek_to(start)?;
+        self.write_out(data)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Issues `BLKDISCARD` for a device node, and punches a hole in an
+    /// image file, which then reads as zeros there. Linux only.
+    #[cfg(target_os = "linux")]
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::FileTypeExt;
+
+        if !self.writable {
+            return Err(SDError::ReadOnly);
+        }
+        if start as u64 + count as u64 > self.num_blocks {
+            return Err(SDError::BlockOutOfRange(start as u64 + count as u64 - 1));
+        }
+        let offset = start as u64 * self.block_size as u64;
+        let len = count as u64 * self.block_size as u64;
+        trace!(offset, len, "discard");
+        let fd = self.file.as_raw_fd();
+        // SAFETY: `fd` is open for as long as `self.file` is, and the range
+        // argument of `BLKDISCARD` is read during the call only.
+        let result = if self.file.metadata()?.file_type().is_block_device() {
+            let range = [offset, len];
+            unsafe { linux::ioctl(fd, linux::BLKDISCARD, range.as_ptr()) }
+        } else {
+            let mode = linux::FALLOC_FL_PUNCH_HOLE | linux::FALLOC_FL_KEEP_SIZE;
+            unsafe { linux::fallocate(fd, mode, offset as i64, len as i64) }
+        };
+        if result != 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Unsupported {
+                return Err(SDError::Unsupported("discard on this device"));
+            }
+            return Err(error.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod linux {
+    /// `_IO(0x12, 119)`: discards a byte range given as `[start, len]`.
+    pub const BLKDISCARD: core::ffi::c_ulong = 0x1277;
+    pub const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+    pub const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+
+    extern "C" {
+        pub fn ioctl(fd: i32, request: core::ffi::c_ulong, ...) -> i32;
+        pub fn fallocate(fd: i32, mode: i32, offset: i64, len: i64) -> i32;
+    }
+}
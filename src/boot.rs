@@ -0,0 +1,141 @@
+use alloc::vec::Vec;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::exfat::{self, ExFatBootSector};
+use crate::fat::FATBootSector;
+use crate::log::debug;
+
+/// Where FAT32 formatters put the backup boot sector. The primary says so
+/// too, but when the primary is damaged this is the only place to look.
+pub const BACKUP_BOOT_SECTOR: u32 = 6;
+
+/// Which copy of the boot sector a volume was read through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum BootSectorCopy {
+    /// Sector 0 of the volume.
+    #[default]
+    Primary,
+    /// The FAT32 backup at sector 6, used because sector 0 could not be
+    /// read or failed validation.
+    Backup,
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Which boot sector the last `read_boot_sector` returned, and so the
+    /// one the layout of the volume comes from.
+    pub fn boot_sector_copy(&self) -> BootSectorCopy {
+        self.boot_sector_copy
+    }
+
+    /// Reads the FAT32 backup boot sector. It has to describe a FAT32
+    /// volume whose backup is where it was found, or it is not taken for
+    /// one.
+    pub fn read_backup_boot_sector(&mut self) -> Result<FATBootSector, SDError> {
+        let block = self.partition_start() as u64 + BACKUP_BOOT_SECTOR as u64;
+        let boot_sector = FATBootSector::parse(&self.read_block(BACKUP_BOOT_SECTOR)?)
+            .map_err(|e| e.at_block(block))?;
+        if boot_sector.sectors_per_fat != 0
+            || boot_sector.backup_boot_sector as u32 != BACKUP_BOOT_SECTOR
+        {
+            return Err(
+                SDError::parse(50, "backup boot sector", "is not a FAT32 backup").at_block(block),
+            );
+        }
+        Ok(boot_sector)
+    }
+
+    /// Copies the backup boot sector over a damaged primary, so that other
+    /// systems can mount the volume again. Fails if there is no valid
+    /// backup; a primary that is already valid is left alone and `false`
+    /// returned.
+    pub fn restore_boot_sector(&mut self) -> Result<bool, SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let primary_ok = self
+            .read_block(0)
+            .is_ok_and(|data| FATBootSector::parse(&data).is_ok());
+        if primary_ok {
+            return Ok(false);
+        }
+        self.read_backup_boot_sector()?;
+        let backup = self.read_block(BACKUP_BOOT_SECTOR)?;
+        self.write_block(0, &backup)?;
+        self.flush()?;
+        self.boot_sector_copy = BootSectorCopy::Primary;
+        debug!("restored the boot sector from the backup");
+        Ok(true)
+    }
+
+    /// Reads the reserved region of the volume: every sector before the
+    /// first FAT. That is the boot sector, and on FAT32 the FSInfo sector
+    /// and the backup boot sector; on exFAT, the main and backup boot
+    /// regions. Saved before experimenting with repairs, it lets
+    /// `restore_reserved_region` undo them.
+    pub fn read_reserved_region(&mut self) -> Result<Vec<u8>, SDError> {
+        let layout = self.layout()?;
+        self.read_blocks(0, layout.fat_start)
+    }
+
+    /// Writes a reserved region saved by `read_reserved_region` back to
+    /// the start of the volume. The boot sector it begins with is checked
+    /// first: it has to be valid, use this device's block size, describe a
+    /// volume that fits, and have a reserved region as long as `data`.
+    /// What the volume holds now is not looked at, so this works on one
+    /// whose boot sector is destroyed.
+    pub fn restore_reserved_region(&mut self, data: &[u8]) -> Result<(), SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let block_size = self.block_size();
+        if data.len() < block_size || !data.len().is_multiple_of(block_size) {
+            return Err(SDError::InvalidImage(
+                "a reserved region is a whole number of blocks",
+            ));
+        }
+        let (bytes_per_sector, reserved, volume_sectors) = if exfat::is_exfat(data) {
+            let boot_sector = ExFatBootSector::parse(data)?;
+            if data.len() < 12 * block_size {
+                return Err(SDError::InvalidImage("the exFAT boot region is cut short"));
+            }
+            let expected = exfat::boot_region_checksum(&data[..11 * block_size]);
+            let stored = &data[11 * block_size..];
+            let actual = u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]);
+            if expected != actual {
+                return Err(SDError::ChecksumMismatch { expected, actual });
+            }
+            (
+                boot_sector.bytes_per_sector(),
+                boot_sector.fat_offset as u64,
+                boot_sector.volume_length,
+            )
+        } else {
+            let boot_sector = FATBootSector::parse(data)?;
+            (
+                boot_sector.bytes_per_sector as u32,
+                boot_sector.reserved_sectors as u64,
+                boot_sector.total_sectors() as u64,
+            )
+        };
+        self.check_sector_size(bytes_per_sector)?;
+        if reserved * block_size as u64 != data.len() as u64 {
+            return Err(SDError::InvalidImage(
+                "the boot sector gives its reserved region another size",
+            ));
+        }
+        if volume_sectors > self.num_blocks() {
+            return Err(SDError::InvalidImage(
+                "the saved volume does not fit on the device",
+            ));
+        }
+        self.write_blocks(0, data)?;
+        self.flush()?;
+        self.boot_sector_copy = BootSectorCopy::Primary;
+        debug!(blocks = reserved, "restored the reserved region");
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+//! Viewing and changing the BIOS parameter block of a FAT12/16/32 boot
+//! sector field by field, for volumes a bad format or a confused tool left
+//! with a wrong value.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use crate::block::BlockDevice;
+use crate::boot::BootSectorCopy;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::exfat;
+use crate::fat::{FATBootSector, FatVariant};
+use crate::layout::FATLayout;
+use crate::log::debug;
+
+/// A field of the BIOS parameter block, or of the extended boot record
+/// after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BpbField {
+    BytesPerSector,
+    SectorsPerCluster,
+    ReservedSectors,
+    NumberOfFats,
+    RootDirEntries,
+    TotalSectors16,
+    MediaDescriptor,
+    /// The FAT12/16 FAT size; zero on FAT32, which is how FAT32 is told
+    /// apart.
+    SectorsPerFat,
+    SectorsPerTrack,
+    NumberOfHeads,
+    /// Sectors before the volume on the device, the partition's start.
+    HiddenSectors,
+    TotalSectors32,
+    SectorsPerFat32,
+    RootCluster,
+    FsInfoSector,
+    BackupBootSector,
+    DriveNumber,
+    VolumeId,
+}
+
+impl BpbField {
+    pub const ALL: [BpbField; 18] = [
+        BpbField::BytesPerSector,
+        BpbField::SectorsPerCluster,
+        BpbField::ReservedSectors,
+        BpbField::NumberOfFats,
+        BpbField::RootDirEntries,
+        BpbField::TotalSectors16,
+        BpbField::MediaDescriptor,
+        BpbField::SectorsPerFat,
+        BpbField::SectorsPerTrack,
+        BpbField::NumberOfHeads,
+        BpbField::HiddenSectors,
+        BpbField::TotalSectors32,
+        BpbField::SectorsPerFat32,
+        BpbField::RootCluster,
+        BpbField::FsInfoSector,
+        BpbField::BackupBootSector,
+        BpbField::DriveNumber,
+        BpbField::VolumeId,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BpbField::BytesPerSector => "bytes_per_sector",
+            BpbField::SectorsPerCluster => "sectors_per_cluster",
+            BpbField::ReservedSectors => "reserved_sectors",
+            BpbField::NumberOfFats => "number_of_fats",
+            BpbField::RootDirEntries => "root_dir_entries",
+            BpbField::TotalSectors16 => "total_sectors_16",
+            BpbField::MediaDescriptor => "media_descriptor",
+            BpbField::SectorsPerFat => "sectors_per_fat",
+            BpbField::SectorsPerTrack => "sectors_per_track",
+            BpbField::NumberOfHeads => "number_of_heads",
+            BpbField::HiddenSectors => "hidden_sectors",
+            BpbField::TotalSectors32 => "total_sectors_32",
+            BpbField::SectorsPerFat32 => "sectors_per_fat_32",
+            BpbField::RootCluster => "root_cluster",
+            BpbField::FsInfoSector => "fs_info_sector",
+            BpbField::BackupBootSector => "backup_boot_sector",
+            BpbField::DriveNumber => "drive_number",
+            BpbField::VolumeId => "volume_id",
+        }
+    }
+
+    /// Where the field is in the boot sector, and how many bytes wide.
+    /// `None` for the FAT32 fields of a FAT12/16 boot sector.
+    pub fn location(self, fat32: bool) -> Option<(usize, usize)> {
+        let extended = if fat32 { 64 } else { 36 };
+        Some(match self {
+            BpbField::BytesPerSector => (11, 2),
+            BpbField::SectorsPerCluster => (13, 1),
+            BpbField::ReservedSectors => (14, 2),
+            BpbField::NumberOfFats => (16, 1),
+            BpbField::RootDirEntries => (17, 2),
+            BpbField::TotalSectors16 => (19, 2),
+            BpbField::MediaDescriptor => (21, 1),
+            BpbField::SectorsPerFat => (22, 2),
+            BpbField::SectorsPerTrack => (24, 2),
+            BpbField::NumberOfHeads => (26, 2),
+            BpbField::HiddenSectors => (28, 4),
+            BpbField::TotalSectors32 => (32, 4),
+            BpbField::SectorsPerFat32 if fat32 => (36, 4),
+            BpbField::RootCluster if fat32 => (44, 4),
+            BpbField::FsInfoSector if fat32 => (48, 2),
+            BpbField::BackupBootSector if fat32 => (50, 2),
+            BpbField::DriveNumber => (extended, 1),
+            BpbField::VolumeId => (extended + 3, 4),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for BpbField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for BpbField {
+    type Err = SDError;
+
+    /// Takes the names `name` gives, in any case and with `-` for `_`.
+    fn from_str(name: &str) -> Result<Self, SDError> {
+        let name = name.to_ascii_lowercase().replace('-', "_");
+        BpbField::ALL
+            .into_iter()
+            .find(|field| field.name() == name)
+            .ok_or(SDError::Unsupported("boot sector field"))
+    }
+}
+
+/// A field `Bpb::recompute` changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BpbChange {
+    pub field: BpbField,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// A boot sector being edited. Nothing is checked until `validate`, so
+/// that a damaged one can be looked at and put right.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bpb {
+    sector: Vec<u8>,
+}
+
+impl Bpb {
+    pub fn from_bytes(sector: Vec<u8>) -> Result<Self, SDError> {
+        if sector.len() < 512 {
+            return Err(SDError::parse(
+                sector.len(),
+                "boot sector",
+                "shorter than 512 bytes",
+            ));
+        }
+        if exfat::is_exfat(&sector) {
+            return Err(SDError::Unsupported("editing exFAT boot sectors"));
+        }
+        Ok(Bpb { sector })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.sector
+    }
+
+    /// Whether the fields are read as FAT32's, which they are when the
+    /// FAT12/16 FAT size is zero.
+    pub fn is_fat32(&self) -> bool {
+        self.sector[22..24] == [0, 0]
+    }
+
+    pub fn get(&self, field: BpbField) -> Option<u32> {
+        let (offset, width) = field.location(self.is_fat32())?;
+        let mut bytes = [0u8; 4];
+        bytes[..width].copy_from_slice(&self.sector[offset..offset + width]);
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// Sets a field. Fails if `value` does not fit it, or the field is one
+    /// of FAT32's and this is a FAT12/16 boot sector.
+    pub fn set(&mut self, field: BpbField, value: u32) -> Result<(), SDError> {
+        let (offset, width) = field
+            .location(self.is_fat32())
+            .ok_or(SDError::InvalidArgument(
+                "the field is only in FAT32 boot sectors",
+            ))?;
+        if width < 4 && value >> (width * 8) != 0 {
+            return Err(SDError::InvalidArgument(
+                "the value is too large for the field",
+            ));
+        }
+        self.sector[offset..offset + width].copy_from_slice(&value.to_le_bytes()[..width]);
+        Ok(())
+    }
+
+    /// Every field the boot sector has, with its value.
+    pub fn fields(&self) -> impl Iterator<Item = (BpbField, u32)> + '_ {
+        BpbField::ALL
+            .into_iter()
+            .filter_map(|field| Some((field, self.get(field)?)))
+    }
+
+    /// Parses the boot sector as the controller would when opening the
+    /// volume, failing on the first field that would stop it.
+    pub fn validate(&self) -> Result<FATBootSector, SDError> {
+        FATBootSector::parse(&self.sector)
+    }
+
+    /// The layout the fields give the volume, if they are valid.
+    pub fn layout(&self) -> Result<FATLayout, SDError> {
+        Ok(FATLayout::new(&self.validate()?))
+    }
+
+    /// Brings the fields that follow from others in line with them: the
+    /// hidden sectors with `hidden_sectors`, where the volume starts on the
+    /// device; the total sector count into the field its size calls for;
+    /// and the FAT size up to what is needed to cover every cluster, which
+    /// moves the data region. Returns what changed.
+    pub fn recompute(&mut self, hidden_sectors: u32) -> Vec<BpbChange> {
+        let mut changes = Vec::new();
+        let mut change = |bpb: &mut Bpb, field: BpbField, new: u32| {
+            let Some(old) = bpb.get(field) else {
+                return;
+            };
+            if old != new && bpb.set(field, new).is_ok() {
+                changes.push(BpbChange { field, old, new });
+            }
+        };
+        change(self, BpbField::HiddenSectors, hidden_sectors);
+
+        let fat32 = self.is_fat32();
+        let total = match self.get(BpbField::TotalSectors16) {
+            Some(0) | None => self.get(BpbField::TotalSectors32).unwrap_or(0),
+            Some(total) => total,
+        };
+        let small = !fat32 && total < 0x10000;
+        change(
+            self,
+            BpbField::TotalSectors16,
+            if small { total } else { 0 },
+        );
+        change(
+            self,
+            BpbField::TotalSectors32,
+            if small { 0 } else { total },
+        );
+
+        if let Some(fat_size) = self.needed_fat_size(total) {
+            let field = if fat32 {
+                BpbField::SectorsPerFat32
+            } else {
+                BpbField::SectorsPerFat
+            };
+            let current = self.get(field).unwrap_or(0);
+            if fat_size > current && (fat32 || fat_size <= u16::MAX as u32) {
+                change(self, field, fat_size);
+            }
+        }
+        changes
+    }
+
+    /// The smallest FAT that covers the clusters the other fields give the
+    /// volume. `None` if they leave no room for any.
+    fn needed_fat_size(&self, total: u32) -> Option<u32> {
+        let bytes_per_sector = self.get(BpbField::BytesPerSector)?;
+        let spc = self.get(BpbField::SectorsPerCluster)?;
+        let fats = self.get(BpbField::NumberOfFats)?;
+        if bytes_per_sector == 0 || spc == 0 || fats == 0 {
+            return None;
+        }
+        let root_dir_sectors =
+            (self.get(BpbField::RootDirEntries)? * 32).div_ceil(bytes_per_sector);
+        let before_fats = self.get(BpbField::ReservedSectors)?;
+        let mut fat_size = 1u32;
+        loop {
+            let metadata = before_fats + fats * fat_size + root_dir_sectors;
+            let clusters = total.checked_sub(metadata)? / spc;
+            let variant = if self.is_fat32() {
+                FatVariant::Fat32
+            } else {
+                FatVariant::from_cluster_count(clusters)
+            };
+            let needed = ((clusters as u64 + 2) * variant.entry_bytes() as u64)
+                .div_ceil(bytes_per_sector as u64);
+            if needed <= fat_size as u64 {
+                return Some(fat_size);
+            }
+            fat_size = u32::try_from(needed).ok()?;
+        }
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Reads the boot sector of the open volume for editing, whether or
+    /// not it is valid.
+    pub fn read_bpb(&mut self) -> Result<Bpb, SDError> {
+        Bpb::from_bytes(self.read_block(0)?)
+    }
+
+    /// Writes an edited boot sector to the volume, and to the FAT32 backup
+    /// boot sector it names so that the two agree. It has to be valid, use
+    /// this device's block size and describe a volume that fits.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn write_bpb(&mut self, bpb: &Bpb) -> Result<(), SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let boot_sector = bpb.validate()?;
+        self.check_sector_size(boot_sector.bytes_per_sector as u32)?;
+        if boot_sector.total_sectors() as u64 > self.num_blocks() {
+            return Err(SDError::InvalidArgument(
+                "the boot sector describes a volume larger than the device",
+            ));
+        }
+        let backup = boot_sector.backup_boot_sector;
+        let sectors: &[u32] = if boot_sector.sectors_per_fat == 0
+            && backup != 0
+            && backup != 0xFFFF
+            && backup < boot_sector.reserved_sectors
+        {
+            &[0, backup as u32]
+        } else {
+            &[0]
+        };
+        for &sector in sectors {
+            self.write_block(sector, bpb.as_bytes())?;
+        }
+        self.flush()?;
+        self.boot_sector_copy = BootSectorCopy::Primary;
+        debug!(copies = sectors.len(), "wrote the boot sector");
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::block::BlockDevice;
+use crate::error::SDError;
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Keeps the most recently used blocks of another device in memory.
+///
+/// Writes are held in the cache and only reach the underlying device when
+/// the block is evicted or on `flush`. Dropping the cache flushes it, but
+/// errors are lost at that point, so callers that write should flush
+/// explicitly.
+pub struct CachedDevice<D: BlockDevice> {
+    inner: D,
+    capacity: usize,
+    blocks: HashMap<u32, CachedBlock>,
+    /// Blocks keyed by their last use, oldest first.
+    recency: BTreeMap<u64, u32>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<D: BlockDevice> CachedDevice<D> {
+    /// Caches up to `capacity` blocks of `inner`; a capacity of zero is
+    /// treated as one.
+    pub fn new(inner: D, capacity: usize) -> Self {
+        CachedDevice {
+            inner,
+            capacity: capacity.max(1),
+            blocks: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Writes back dirty blocks and forgets everything cached.
+    pub fn clear(&mut self) -> Result<(), SDError> {
+        self.write_back()?;
+        self.blocks.clear();
+        self.recency.clear();
+        Ok(())
+    }
+
+    fn touch(&mut self, block_index: u32) {
+        self.clock += 1;
+        if let Some(block) = self.blocks.get_mut(&block_index) {
+            self.recency.remove(&block.last_used);
+            block.last_used = self.clock;
+            self.recency.insert(self.clock, block_index);
+        }
+    }
+
+    fn insert(&mut self, block_index: u32, data: Vec<u8>, dirty: bool) -> Result<(), SDError> {
+        while self.blocks.len() >= self.capacity {
+            self.evict_oldest()?;
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, block_index);
+        self.blocks.insert(
+            block_index,
+            CachedBlock {
+                data,
+                dirty,
+                last_used: self.clock,
+            },
+        );
+        Ok(())
+    }
+
+    fn evict_oldest(&mut self) -> Result<(), SDError> {
+        let Some((&last_used, &block_index)) = self.recency.iter().next() else {
+            return Ok(());
+        };
+        if let Some(block) = self.blocks.get(&block_index) {
+            if block.dirty {
+                self.inner.write_block(block_index, &block.data)?;
+            }
+        }
+        self.recency.remove(&last_used);
+        self.blocks.remove(&block_index);
+        Ok(())
+    }
+
+    /// Writes dirty blocks in ascending order, keeping them cached.
+    fn write_back(&mut self) -> Result<(), SDError> {
+        let mut dirty: Vec<u32> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(&block_index, _)| block_index)
+            .collect();
+        dirty.sort_unstable();
+        for block_index in dirty {
+            let block = self.blocks.get_mut(&block_index).unwrap();
+            self.inner.write_block(block_index, &block.data)?;
+            block.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for CachedDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if let Some(block) = self.blocks.get(&block_index) {
+            buffer.copy_from_slice(&block.data);
+            self.hits += 1;
+            self.touch(block_index);
+            return Ok(());
+        }
+
+        self.misses += 1;
+        self.inner.read_block(block_index, buffer)?;
+        self.insert(block_index, buffer.to_vec(), false)
+    }
+
+    /// Serves cached blocks from memory and reads each run of the others
+    /// from the device in one request, caching them as `read_block` does.
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        let block_size = self.block_size();
+        if !buffer.len().is_multiple_of(block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let count = buffer.len() / block_size;
+        let mut index = 0;
+        while index < count {
+            let block_index = start + index as u32;
+            let slot = &mut buffer[index * block_size..(index + 1) * block_size];
+            if let Some(block) = self.blocks.get(&block_index) {
+                slot.copy_from_slice(&block.data);
+                self.hits += 1;
+                self.touch(block_index);
+                index += 1;
+                continue;
+            }
+            let run_end = (index + 1..count)
+                .find(|&i| self.blocks.contains_key(&(start + i as u32)))
+                .unwrap_or(count);
+            let run = &mut buffer[index * block_size..run_end * block_size];
+            self.inner.read_blocks(block_index, run)?;
+            self.misses += (run_end - index) as u64;
+            for (i, data) in run.chunks(block_size).enumerate() {
+                self.insert(block_index + i as u32, data.to_vec(), false)?;
+            }
+            index = run_end;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if block_index as u64 >= self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(block_index as u64));
+        }
+        if let Some(block) = self.blocks.get_mut(&block_index) {
+            block.data.copy_from_slice(data);
+            block.dirty = true;
+            self.touch(block_index);
+            return Ok(());
+        }
+        self.insert(block_index, data.to_vec(), true)
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        self.write_back()?;
+        self.inner.flush()
+    }
+
+    /// Passes the discard on, then forgets the cached blocks in the range,
+    /// dirty or not. A device that refuses the discard keeps the range as
+    /// it was, so pending writes to it stay cached.
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        self.inner.discard(start, count)?;
+        let end = start as u64 + count as u64;
+        let cached: Vec<u32> = self
+            .blocks
+            .keys()
+            .copied()
+            .filter(|&block_index| block_index >= start && (block_index as u64) < end)
+            .collect();
+        for block_index in cached {
+            if let Some(block) = self.blocks.remove(&block_index) {
+                self.recency.remove(&block.last_used);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Drop for CachedDevice<D> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
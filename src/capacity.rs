@@ -0,0 +1,285 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink};
+
+/// Bytes moved per device request when testing every block.
+const CHUNK_BYTES: usize = 1 << 20;
+/// Most aliased blocks kept in a report.
+const MAX_ALIASES: usize = 16;
+/// Most runs of bad blocks kept in a report.
+const MAX_BAD_RANGES: usize = 1024;
+
+/// How `capacity_write` and `capacity_verify` cover the device. Both
+/// phases must be given the same settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityTest {
+    /// Seeds the test pattern, so that data left by an earlier run with a
+    /// different seed is not mistaken for good blocks.
+    pub seed: u64,
+    /// Test every `stride`th block only. A stride of 1 tests the whole
+    /// device; larger strides find fake capacity far faster, since such
+    /// cards fail everywhere past their real size, but can miss isolated
+    /// bad blocks.
+    pub stride: u64,
+}
+
+impl Default for CapacityTest {
+    fn default() -> Self {
+        CapacityTest {
+            seed: 0x5D_C0DE,
+            stride: 1,
+        }
+    }
+}
+
+/// A block whose contents turned out to be those written to another
+/// block: the card maps both addresses to the same flash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alias {
+    pub block: u64,
+    pub written_as: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapacityReport {
+    pub block_size: usize,
+    /// Blocks the device claims to have.
+    pub claimed_blocks: u64,
+    pub tested_blocks: u64,
+    pub good_blocks: u64,
+    /// Tested blocks that held something other than their own pattern,
+    /// including aliased ones.
+    pub bad_blocks: u64,
+    pub aliased_blocks: u64,
+    /// The first tested block that held neither its own pattern nor that of
+    /// another block.
+    pub first_bad_block: Option<u64>,
+    /// The shortest distance between an aliased block and the block whose
+    /// data it held. Cards that fake their capacity usually wrap addresses
+    /// around at their real size, which this then is.
+    pub wraparound_blocks: Option<u64>,
+    /// The first few aliased blocks found.
+    pub aliases: Vec<Alias>,
+    /// The first runs of consecutive bad blocks, aliased or not. With a
+    /// stride, each tested block that fails is a run of its own.
+    pub bad_ranges: Vec<Range<u64>>,
+    /// Time taken to read the pattern back.
+    pub elapsed: Duration,
+}
+
+impl CapacityReport {
+    pub fn claimed_bytes(&self) -> u64 {
+        self.claimed_blocks * self.block_size as u64
+    }
+
+    /// Bytes that can be trusted: everything before the first bad block or
+    /// the point where addresses wrap around, whichever comes first. With
+    /// wraparound the blocks at the start of the card read back as aliases,
+    /// having been overwritten by those written later, but the storage
+    /// behind them is real.
+    pub fn usable_bytes(&self) -> u64 {
+        let blocks = [self.first_bad_block, self.wraparound_blocks]
+            .into_iter()
+            .flatten()
+            .fold(self.claimed_blocks, u64::min);
+        blocks * self.block_size as u64
+    }
+
+    pub fn is_genuine(&self) -> bool {
+        self.bad_blocks == 0
+    }
+}
+
+/// The splitmix64 generator: fast, and good enough that a card cannot
+/// compress or deduplicate the pattern.
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Fills `buffer` with the pattern of `block`: the block number and seed,
+/// so that a misplaced block reveals where it was written, followed by
+/// pseudorandom data derived from both.
+fn fill_pattern(buffer: &mut [u8], block: u64, seed: u64) {
+    buffer[0..8].copy_from_slice(&block.to_le_bytes());
+    buffer[8..16].copy_from_slice(&seed.to_le_bytes());
+    let mut state = seed ^ block.wrapping_mul(0xD6E8_FEB8_6659_FD93);
+    for word in buffer[16..].chunks_mut(8) {
+        let value = splitmix64(&mut state).to_le_bytes();
+        word.copy_from_slice(&value[..word.len()]);
+    }
+}
+
+enum BlockCheck {
+    Good,
+    Bad,
+    Aliased(u64),
+}
+
+fn check_block(data: &[u8], block: u64, seed: u64, expected: &mut [u8]) -> BlockCheck {
+    fill_pattern(expected, block, seed);
+    if data == expected {
+        return BlockCheck::Good;
+    }
+    let written_as = u64::from_le_bytes(data[0..8].try_into().unwrap());
+    if data[8..16] == seed.to_le_bytes() && written_as != block {
+        fill_pattern(expected, written_as, seed);
+        if data == expected {
+            return BlockCheck::Aliased(written_as);
+        }
+    }
+    BlockCheck::Bad
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// The runs of blocks a test covers: whole chunks when every block is
+    /// tested, single blocks otherwise.
+    fn capacity_runs(&self, test: &CapacityTest) -> Vec<(u64, u64)> {
+        let total = self.num_blocks();
+        let stride = test.stride.max(1);
+        if stride == 1 {
+            let chunk = (CHUNK_BYTES / self.block_size()).max(1) as u64;
+            (0..total)
+                .step_by(chunk as usize)
+                .map(|start| (start, chunk.min(total - start)))
+                .collect()
+        } else {
+            (0..total)
+                .step_by(stride as usize)
+                .map(|block| (block, 1))
+                .collect()
+        }
+    }
+
+    /// First phase of a fake-capacity test in the manner of H2testw and f3:
+    /// fills the open partition, or the whole device, with a pattern unique
+    /// to each block. Everything on it is lost. Returns the number of blocks
+    /// written.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn capacity_write<P: ProgressSink>(
+        &mut self,
+        test: &CapacityTest,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        let block_size = self.block_size();
+        let runs = self.capacity_runs(test);
+        let total: u64 = runs.iter().map(|&(_, count)| count).sum();
+        let started = Instant::now();
+        let mut buffer = Vec::new();
+        let mut done = 0;
+        for (start, count) in runs {
+            buffer.resize(count as usize * block_size, 0);
+            for (index, block) in buffer.chunks_mut(block_size).enumerate() {
+                fill_pattern(block, start + index as u64, test.seed);
+            }
+            self.write_blocks(block_index(start)?, &buffer)?;
+            done += count;
+            progress.report(&Progress {
+                phase: Phase::Writing,
+                bytes_done: done * block_size as u64,
+                bytes_total: total * block_size as u64,
+                elapsed: started.elapsed(),
+            });
+        }
+        self.flush()?;
+        Ok(total)
+    }
+
+    /// Second phase of the test: reads every block `capacity_write` wrote
+    /// and reports which still hold their own pattern. A card with fake
+    /// capacity fails from its real size on, typically with blocks that
+    /// alias earlier ones.
+    ///
+    /// Reads can be answered from the operating system's cache of what was
+    /// just written. Closing and reopening a Linux block device drops that
+    /// cache, provided nothing else holds it open; safest of all is to
+    /// remove and reinsert the card between the phases.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn capacity_verify<P: ProgressSink>(
+        &mut self,
+        test: &CapacityTest,
+        progress: &mut P,
+    ) -> Result<CapacityReport, SDError> {
+        let block_size = self.block_size();
+        let runs = self.capacity_runs(test);
+        let total: u64 = runs.iter().map(|&(_, count)| count).sum();
+        let mut report = CapacityReport {
+            block_size,
+            claimed_blocks: self.num_blocks(),
+            tested_blocks: total,
+            good_blocks: 0,
+            bad_blocks: 0,
+            aliased_blocks: 0,
+            first_bad_block: None,
+            wraparound_blocks: None,
+            aliases: Vec::new(),
+            bad_ranges: Vec::new(),
+            elapsed: Duration::ZERO,
+        };
+        let started = Instant::now();
+        let mut expected = vec![0u8; block_size];
+        let mut done = 0;
+        for (start, count) in runs {
+            // A block that cannot be read at all counts as bad; the rest of
+            // the card is still worth looking at.
+            let data = match self.read_blocks(block_index(start)?, count as u32) {
+                Ok(data) => data,
+                Err(e) if matches!(e.root_cause(), SDError::IO(_)) => {
+                    vec![0; count as usize * block_size]
+                }
+                Err(e) => return Err(e),
+            };
+            for (index, block_data) in data.chunks(block_size).enumerate() {
+                let block = start + index as u64;
+                match check_block(block_data, block, test.seed, &mut expected) {
+                    BlockCheck::Good => {
+                        report.good_blocks += 1;
+                        continue;
+                    }
+                    BlockCheck::Aliased(written_as) => {
+                        report.aliased_blocks += 1;
+                        let distance = block.abs_diff(written_as);
+                        report.wraparound_blocks = Some(
+                            report
+                                .wraparound_blocks
+                                .map_or(distance, |shortest| shortest.min(distance)),
+                        );
+                        if report.aliases.len() < MAX_ALIASES {
+                            report.aliases.push(Alias { block, written_as });
+                        }
+                    }
+                    BlockCheck::Bad => {
+                        report.first_bad_block.get_or_insert(block);
+                    }
+                }
+                report.bad_blocks += 1;
+                let ranges = &mut report.bad_ranges;
+                match ranges.last_mut() {
+                    Some(last) if last.end == block => last.end += 1,
+                    _ => {
+                        if ranges.len() < MAX_BAD_RANGES {
+                            ranges.push(block..block + 1);
+                        }
+                    }
+                }
+            }
+            done += count;
+            progress.report(&Progress {
+                phase: Phase::Verifying,
+                bytes_done: done * block_size as u64,
+                bytes_total: total * block_size as u64,
+                elapsed: started.elapsed(),
+            });
+        }
+        report.elapsed = started.elapsed();
+        Ok(report)
+    }
+}
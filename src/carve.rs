@@ -0,0 +1,463 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes read per request while scanning and carving.
+const CHUNK_BYTES: u64 = 1 << 20;
+
+/// A file format `carve` recognizes by its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum CarveKind {
+    Jpeg,
+    Png,
+    /// MP4 and QuickTime video, and other ISO base media files.
+    Mp4,
+    Wav,
+    /// Zip archives, and formats built on them such as DOCX.
+    Zip,
+}
+
+impl CarveKind {
+    pub const ALL: [CarveKind; 5] = [
+        CarveKind::Jpeg,
+        CarveKind::Png,
+        CarveKind::Mp4,
+        CarveKind::Wav,
+        CarveKind::Zip,
+    ];
+
+    /// The usual file name extension.
+    pub fn extension(self) -> &'static str {
+        match self {
+            CarveKind::Jpeg => "jpg",
+            CarveKind::Png => "png",
+            CarveKind::Mp4 => "mp4",
+            CarveKind::Wav => "wav",
+            CarveKind::Zip => "zip",
+        }
+    }
+
+    /// Whether a block starting with `data` looks like the first block of
+    /// a file of this kind.
+    fn matches(self, data: &[u8]) -> bool {
+        match self {
+            CarveKind::Jpeg => {
+                data.starts_with(&[0xFF, 0xD8, 0xFF])
+                    && data.get(3).is_some_and(|&m| m & 0xF0 == 0xE0 || m == 0xDB)
+            }
+            CarveKind::Png => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+            CarveKind::Mp4 => {
+                data.len() >= 8
+                    && &data[4..8] == b"ftyp"
+                    && (8..=256).contains(&u32::from_be_bytes(data[..4].try_into().unwrap()))
+            }
+            CarveKind::Wav => {
+                data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WAVE"
+            }
+            CarveKind::Zip => data.starts_with(b"PK\x03\x04"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CarveOptions {
+    pub kinds: Vec<CarveKind>,
+    /// No file is carved longer than this. The default is the largest
+    /// file FAT32 can hold.
+    pub max_len: u64,
+}
+
+impl Default for CarveOptions {
+    fn default() -> Self {
+        CarveOptions {
+            kinds: CarveKind::ALL.to_vec(),
+            max_len: u32::MAX as u64,
+        }
+    }
+}
+
+/// A file found by its header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CarvedFile {
+    pub kind: CarveKind,
+    /// The block the header is in, relative to the open partition.
+    pub start_block: u64,
+    pub len: u64,
+    /// Whether the structure of the file could be followed to its end.
+    /// If not, `len` is as far as it made sense, and the file was most
+    /// likely fragmented or partly overwritten.
+    pub complete: bool,
+}
+
+/// Random access to the bytes from one block on, a chunk at a time.
+struct Window<'a, D: BlockDevice> {
+    controller: &'a mut SDController<D>,
+    base: u64,
+    /// Bytes that may be read from `base`.
+    limit: u64,
+    chunk_start: u64,
+    chunk: Vec<u8>,
+}
+
+impl<'a, D: BlockDevice> Window<'a, D> {
+    fn new(controller: &'a mut SDController<D>, base: u64, max_len: u64) -> Self {
+        let available = (controller.num_blocks() - base) * controller.block_size() as u64;
+        Window {
+            controller,
+            base,
+            limit: available.min(max_len),
+            chunk_start: 0,
+            chunk: Vec::new(),
+        }
+    }
+
+    fn byte(&mut self, at: u64) -> Result<Option<u8>, SDError> {
+        if at >= self.limit {
+            return Ok(None);
+        }
+        if at < self.chunk_start || at >= self.chunk_start + self.chunk.len() as u64 {
+            let block_size = self.controller.block_size() as u64;
+            let first = at / block_size;
+            let blocks = (CHUNK_BYTES / block_size)
+                .max(1)
+                .min(self.limit.div_ceil(block_size) - first);
+            self.chunk = self
+                .controller
+                .read_blocks(block_index(self.base + first)?, blocks as u32)?;
+            self.chunk_start = first * block_size;
+        }
+        Ok(Some(self.chunk[(at - self.chunk_start) as usize]))
+    }
+
+    fn bytes<const N: usize>(&mut self, at: u64) -> Result<Option<[u8; N]>, SDError> {
+        let mut bytes = [0; N];
+        for (offset, byte) in (at..).zip(bytes.iter_mut()) {
+            match self.byte(offset)? {
+                Some(value) => *byte = value,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(bytes))
+    }
+
+    fn u16_be(&mut self, at: u64) -> Result<Option<u16>, SDError> {
+        Ok(self.bytes(at)?.map(u16::from_be_bytes))
+    }
+
+    fn u16_le(&mut self, at: u64) -> Result<Option<u16>, SDError> {
+        Ok(self.bytes(at)?.map(u16::from_le_bytes))
+    }
+
+    fn u32_be(&mut self, at: u64) -> Result<Option<u32>, SDError> {
+        Ok(self.bytes(at)?.map(u32::from_be_bytes))
+    }
+
+    fn u32_le(&mut self, at: u64) -> Result<Option<u32>, SDError> {
+        Ok(self.bytes(at)?.map(u32::from_le_bytes))
+    }
+
+    fn u64_be(&mut self, at: u64) -> Result<Option<u64>, SDError> {
+        Ok(self.bytes(at)?.map(u64::from_be_bytes))
+    }
+
+    /// Follows the structure of a file of `kind` from its header, returning
+    /// how long it is and whether its end was found.
+    fn measure(&mut self, kind: CarveKind) -> Result<(u64, bool), SDError> {
+        match kind {
+            CarveKind::Jpeg => self.measure_jpeg(),
+            CarveKind::Png => self.measure_png(),
+            CarveKind::Mp4 => self.measure_mp4(),
+            CarveKind::Wav => {
+                let len = self.u32_le(4)?.unwrap_or(0) as u64 + 8;
+                Ok((len.min(self.limit), len <= self.limit))
+            }
+            CarveKind::Zip => self.measure_zip(),
+        }
+    }
+
+    /// Walks the marker segments, skipping entropy-coded data after each
+    /// start of scan, up to the end-of-image marker. Segment lengths carry
+    /// the walk over embedded thumbnails and their own end markers.
+    fn measure_jpeg(&mut self) -> Result<(u64, bool), SDError> {
+        let mut at = 2;
+        loop {
+            if self.byte(at)? != Some(0xFF) {
+                return Ok((at, false));
+            }
+            let Some(marker) = self.byte(at + 1)? else {
+                return Ok((at, false));
+            };
+            match marker {
+                0xD9 => return Ok((at + 2, true)),
+                // Fill bytes, and markers without a length.
+                0xFF => at += 1,
+                0x01 | 0xD0..=0xD7 => at += 2,
+                _ => {
+                    let Some(len) = self.u16_be(at + 2)? else {
+                        return Ok((at, false));
+                    };
+                    if len < 2 {
+                        return Ok((at, false));
+                    }
+                    at += 2 + len as u64;
+                    if marker == 0xDA {
+                        at = match self.skip_entropy_coded(at)? {
+                            Some(marker_at) => marker_at,
+                            None => return Ok((self.limit, false)),
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    /// The offset of the first marker from `at` other than a stuffed zero
+    /// or a restart marker.
+    fn skip_entropy_coded(&mut self, mut at: u64) -> Result<Option<u64>, SDError> {
+        loop {
+            match self.byte(at)? {
+                None => return Ok(None),
+                Some(0xFF) => match self.byte(at + 1)? {
+                    None => return Ok(None),
+                    Some(0x00 | 0xD0..=0xD7) => at += 2,
+                    Some(0xFF) => at += 1,
+                    Some(_) => return Ok(Some(at)),
+                },
+                Some(_) => at += 1,
+            }
+        }
+    }
+
+    /// Walks the chunks after the signature up to `IEND`.
+    fn measure_png(&mut self) -> Result<(u64, bool), SDError> {
+        let mut at = 8;
+        loop {
+            let (Some(len), Some(kind)) = (self.u32_be(at)?, self.bytes::<4>(at + 4)?) else {
+                return Ok((at.min(self.limit), false));
+            };
+            if !kind.iter().all(u8::is_ascii_alphabetic) {
+                return Ok((at, false));
+            }
+            // Length, type, data and CRC.
+            at += 12 + len as u64;
+            if &kind == b"IEND" {
+                return Ok((at.min(self.limit), at <= self.limit));
+            }
+        }
+    }
+
+    /// Walks the top-level boxes while they have known types. The file is
+    /// complete once both the movie header and the media data are seen.
+    fn measure_mp4(&mut self) -> Result<(u64, bool), SDError> {
+        const TOP_LEVEL: [&[u8; 4]; 13] = [
+            b"ftyp", b"moov", b"mdat", b"free", b"skip", b"wide", b"uuid", b"meta", b"pdin",
+            b"moof", b"mfra", b"styp", b"sidx",
+        ];
+        let mut at = 0;
+        let (mut movie, mut media) = (false, false);
+        while let (Some(size), Some(kind)) = (self.u32_be(at)?, self.bytes::<4>(at + 4)?) {
+            if !TOP_LEVEL.contains(&&kind) {
+                break;
+            }
+            let size = match size {
+                // Extends to the end of the file, which is not known.
+                0 => return Ok((self.limit, false)),
+                1 => match self.u64_be(at + 8)? {
+                    Some(large) if large >= 16 => large,
+                    _ => break,
+                },
+                size if size >= 8 => size as u64,
+                _ => break,
+            };
+            movie |= &kind == b"moov";
+            media |= &kind == b"mdat";
+            at += size;
+            if at > self.limit {
+                return Ok((self.limit, false));
+            }
+        }
+        Ok((at, movie && media))
+    }
+
+    /// Walks the local file headers and the central directory up to the
+    /// end-of-central-directory record and its comment.
+    fn measure_zip(&mut self) -> Result<(u64, bool), SDError> {
+        let mut at = 0;
+        loop {
+            let Some(signature) = self.u32_le(at)? else {
+                return Ok((at.min(self.limit), false));
+            };
+            let next = match signature {
+                // Local file header.
+                0x0403_4B50 => {
+                    let (Some(flags), Some(size), Some(name), Some(extra)) = (
+                        self.u16_le(at + 6)?,
+                        self.u32_le(at + 18)?,
+                        self.u16_le(at + 26)?,
+                        self.u16_le(at + 28)?,
+                    ) else {
+                        return Ok((self.limit, false));
+                    };
+                    let data = at + 30 + name as u64 + extra as u64;
+                    if flags & 0x08 == 0 {
+                        data + size as u64
+                    } else {
+                        // The sizes follow the data instead, in a descriptor
+                        // that usually starts with a signature.
+                        match self.find_signature(data, 0x0807_4B50)? {
+                            Some(descriptor) => descriptor + 16,
+                            None => return Ok((self.limit, false)),
+                        }
+                    }
+                }
+                // Central directory entry.
+                0x0201_4B50 => {
+                    let (Some(name), Some(extra), Some(comment)) = (
+                        self.u16_le(at + 28)?,
+                        self.u16_le(at + 30)?,
+                        self.u16_le(at + 32)?,
+                    ) else {
+                        return Ok((self.limit, false));
+                    };
+                    at + 46 + name as u64 + extra as u64 + comment as u64
+                }
+                // End of central directory.
+                0x0605_4B50 => {
+                    let Some(comment) = self.u16_le(at + 20)? else {
+                        return Ok((self.limit, false));
+                    };
+                    let end = at + 22 + comment as u64;
+                    return Ok((end.min(self.limit), end <= self.limit));
+                }
+                _ => return Ok((at, false)),
+            };
+            at = next;
+        }
+    }
+
+    fn find_signature(&mut self, mut at: u64, signature: u32) -> Result<Option<u64>, SDError> {
+        let first = signature.to_le_bytes()[0];
+        while let Some(byte) = self.byte(at)? {
+            if byte == first && self.u32_le(at)? == Some(signature) {
+                return Ok(Some(at));
+            }
+            at += 1;
+        }
+        Ok(None)
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Scans every block of the open partition, or of the whole device,
+    /// for the headers of `options.kinds`, and follows each file found to
+    /// its end. This needs no filesystem at all, so it finds files on a
+    /// card whose FAT or directories are destroyed, and deleted files
+    /// whose clusters were not reused.
+    ///
+    /// Files are taken to be contiguous, as they usually are on a camera
+    /// card filled from empty; a fragmented file comes out garbled or
+    /// incomplete. Blocks inside a complete file are not searched, so
+    /// pictures embedded in others are not found twice.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn carve<P: ProgressSink>(
+        &mut self,
+        options: &CarveOptions,
+        progress: &mut P,
+    ) -> Result<Vec<CarvedFile>, SDError> {
+        let block_size = self.block_size() as u64;
+        let total_blocks = self.num_blocks();
+        let chunk_blocks = (CHUNK_BYTES / block_size).max(1);
+        let stopwatch = Stopwatch::start();
+        let mut found = Vec::new();
+        let mut next = 0;
+        let mut block = 0;
+        while block < total_blocks {
+            let count = chunk_blocks.min(total_blocks - block);
+            let data = self.read_blocks(block_index(block)?, count as u32)?;
+            for (index, header) in data.chunks(block_size as usize).enumerate() {
+                let start_block = block + index as u64;
+                if start_block < next {
+                    continue;
+                }
+                let Some(&kind) = options.kinds.iter().find(|kind| kind.matches(header)) else {
+                    continue;
+                };
+                let (len, complete) =
+                    Window::new(self, start_block, options.max_len).measure(kind)?;
+                if complete {
+                    next = start_block + len.div_ceil(block_size);
+                }
+                found.push(CarvedFile {
+                    kind,
+                    start_block,
+                    len,
+                    complete,
+                });
+            }
+            block += count;
+            progress.report(&Progress {
+                phase: Phase::Scanning,
+                bytes_done: block * block_size,
+                bytes_total: total_blocks * block_size,
+                elapsed: stopwatch.elapsed(),
+            });
+        }
+        Ok(found)
+    }
+
+    /// Copies the bytes of a carved file to `out`.
+    pub fn read_carved<W: Write>(&mut self, file: &CarvedFile, out: &mut W) -> Result<(), SDError> {
+        let block_size = self.block_size() as u64;
+        let chunk_blocks = (CHUNK_BYTES / block_size).max(1);
+        let mut done = 0;
+        while done < file.len {
+            let block = file.start_block + done / block_size;
+            let count = chunk_blocks.min((file.len - done).div_ceil(block_size));
+            let data = self.read_blocks(block_index(block)?, count as u32)?;
+            let used = data.len().min((file.len - done) as usize);
+            out.write_all(&data[..used])?;
+            done += used as u64;
+        }
+        Ok(())
+    }
+
+    /// Carves the device and writes each file found into `dest`, named
+    /// after its first block and kind, e.g. `0000123456.jpg`. Incomplete
+    /// files are written only with `partial`. Returns each file written
+    /// with its path.
+    pub fn carve_to<P: ProgressSink>(
+        &mut self,
+        dest: &Path,
+        options: &CarveOptions,
+        partial: bool,
+        progress: &mut P,
+    ) -> Result<Vec<(CarvedFile, PathBuf)>, SDError> {
+        let found = self.carve(options, progress)?;
+        fs::create_dir_all(dest)?;
+        let mut written = Vec::new();
+        for file in found {
+            if !file.complete && !partial {
+                continue;
+            }
+            let target = dest.join(format!(
+                "{:010}.{}",
+                file.start_block,
+                file.kind.extension()
+            ));
+            let mut out = BufWriter::new(File::create(&target)?);
+            self.read_carved(&file, &mut out)?;
+            out.flush()?;
+            written.push((file, target));
+        }
+        Ok(written)
+    }
+}
@@ -0,0 +1,448 @@
+use std::fmt;
+
+use crate::block::BlockDevice;
+use crate::boot::BootSectorCopy;
+use crate::device::SDController;
+use crate::dir::{root_entry, DirEntry, DirIter, DirLocation, DIR_ENTRY_SIZE};
+use crate::error::SDError;
+use crate::fat::{FatEntry, FatTable, FatVariant};
+use crate::layout::FATLayout;
+
+/// A problem found by `check`. Paths are absolute within the volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsIssue {
+    /// The primary boot sector is unreadable or invalid, and the volume was
+    /// read through the FAT32 backup.
+    DamagedBootSector,
+    /// The volume was not unmounted cleanly, or a driver recorded hard
+    /// errors on it, so writes may have been cut off halfway.
+    Dirty { hard_error: bool },
+    /// A run of sectors in FAT copy `copy` differs from the first FAT.
+    /// `first_sector` is relative to the start of the FAT.
+    FatMismatch {
+        copy: u32,
+        first_sector: u32,
+        sectors: u32,
+    },
+    /// A directory entry whose first cluster lies outside the data area.
+    InvalidFirstCluster { path: String, cluster: u32 },
+    /// The chain leaves the data area or runs into a free, reserved or bad
+    /// cluster. `cluster` is the last valid cluster of the chain.
+    BrokenChain {
+        path: String,
+        cluster: u32,
+        entry: FatEntry,
+    },
+    /// The chain links back to one of its own clusters.
+    ChainLoop { path: String, cluster: u32 },
+    /// `cluster` belongs to the chains of both `path` and `other`.
+    CrossLinked {
+        path: String,
+        other: String,
+        cluster: u32,
+    },
+    /// The chain length does not match the file size.
+    SizeMismatch {
+        path: String,
+        size: u64,
+        clusters: u32,
+    },
+    /// Allocated clusters that no directory entry refers to.
+    LostChain { first_cluster: u32, clusters: u32 },
+}
+
+impl fmt::Display for FsIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsIssue::DamagedBootSector => f.write_str(
+                "the boot sector is damaged; the volume was read through the backup at sector 6",
+            ),
+            FsIssue::Dirty { hard_error: false } => {
+                f.write_str("the volume was not unmounted cleanly")
+            }
+            FsIssue::Dirty { hard_error: true } => {
+                f.write_str("the volume is marked as having had read or write errors")
+            }
+            FsIssue::FatMismatch {
+                copy,
+                first_sector,
+                sectors,
+            } => write!(
+                f,
+                "FAT copy {} differs from FAT 0 in {} sector(s) starting at sector {}",
+                copy, sectors, first_sector
+            ),
+            FsIssue::InvalidFirstCluster { path, cluster } => {
+                write!(
+                    f,
+                    "{}: first cluster {} is outside the data area",
+                    path, cluster
+                )
+            }
+            FsIssue::BrokenChain {
+                path,
+                cluster,
+                entry,
+            } => write!(
+                f,
+                "{}: chain is broken after cluster {} ({:?})",
+                path, cluster, entry
+            ),
+            FsIssue::ChainLoop { path, cluster } => {
+                write!(f, "{}: chain loops back to cluster {}", path, cluster)
+            }
+            FsIssue::CrossLinked {
+                path,
+                other,
+                cluster,
+            } => write!(
+                f,
+                "{}: cross-linked with {} at cluster {}",
+                path, other, cluster
+            ),
+            FsIssue::SizeMismatch {
+                path,
+                size,
+                clusters,
+            } => write!(
+                f,
+                "{}: size {} does not match chain of {} cluster(s)",
+                path, size, clusters
+            ),
+            FsIssue::LostChain {
+                first_cluster,
+                clusters,
+            } => write!(
+                f,
+                "lost chain of {} cluster(s) starting at cluster {}",
+                clusters, first_cluster
+            ),
+        }
+    }
+}
+
+const NO_OWNER: u32 = u32::MAX;
+
+/// The state of a check: which path owns each cluster, and what went wrong.
+pub(crate) struct Checker<'a> {
+    layout: &'a FATLayout,
+    table: &'a FatTable,
+    owners: Vec<u32>,
+    paths: Vec<String>,
+    pub(crate) issues: Vec<FsIssue>,
+}
+
+/// A chain as far as it could be followed.
+pub(crate) struct ChainWalk {
+    pub(crate) clusters: Vec<u32>,
+    pub(crate) end: ChainEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChainEnd {
+    /// The chain ended with a proper end-of-chain marker.
+    Complete,
+    /// The chain breaks or loops after its last cluster, and would be
+    /// complete if it ended there.
+    Broken,
+    /// The first cluster is invalid or the chain runs into another path.
+    Invalid,
+}
+
+impl ChainWalk {
+    pub(crate) fn is_complete(&self) -> bool {
+        self.end == ChainEnd::Complete
+    }
+}
+
+/// A directory entry visited by `check_tree`, with its chain.
+pub(crate) struct CheckedEntry {
+    pub(crate) path: String,
+    pub(crate) entry: DirEntry,
+    /// Sector and offset of the short entry; `None` for the FAT32 root.
+    pub(crate) slot: Option<(u32, usize)>,
+    pub(crate) walk: ChainWalk,
+}
+
+impl<'a> Checker<'a> {
+    pub(crate) fn new(layout: &'a FATLayout, table: &'a FatTable) -> Self {
+        Checker {
+            layout,
+            table,
+            owners: vec![NO_OWNER; layout.cluster_count as usize + 2],
+            paths: Vec::new(),
+            issues: Vec::new(),
+        }
+    }
+
+    /// Follows the chain of `path`, claiming its clusters.
+    pub(crate) fn follow(&mut self, path: &str, first_cluster: u32) -> ChainWalk {
+        let id = self.paths.len() as u32;
+        self.paths.push(path.to_string());
+
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+        if !self.layout.is_data_cluster(cluster) {
+            self.issues.push(FsIssue::InvalidFirstCluster {
+                path: path.to_string(),
+                cluster,
+            });
+            return ChainWalk {
+                clusters,
+                end: ChainEnd::Invalid,
+            };
+        }
+
+        let end = loop {
+            let owner = self.owners[cluster as usize];
+            if owner == id {
+                self.issues.push(FsIssue::ChainLoop {
+                    path: path.to_string(),
+                    cluster,
+                });
+                break ChainEnd::Broken;
+            }
+            if owner != NO_OWNER {
+                self.issues.push(FsIssue::CrossLinked {
+                    path: path.to_string(),
+                    other: self.paths[owner as usize].clone(),
+                    cluster,
+                });
+                break ChainEnd::Invalid;
+            }
+            self.owners[cluster as usize] = id;
+            clusters.push(cluster);
+
+            match self.table.fat_entry(cluster) {
+                FatEntry::EndOfChain => break ChainEnd::Complete,
+                FatEntry::Chain(next) if self.layout.is_data_cluster(next) => cluster = next,
+                entry => {
+                    self.issues.push(FsIssue::BrokenChain {
+                        path: path.to_string(),
+                        cluster,
+                        entry,
+                    });
+                    break ChainEnd::Broken;
+                }
+            }
+        };
+        ChainWalk { clusters, end }
+    }
+
+    /// Checks that a file's chain is as long as its size requires.
+    pub(crate) fn check_size(&mut self, path: &str, entry: &DirEntry, walk: &ChainWalk) {
+        let expected = entry.size.div_ceil(self.layout.cluster_size() as u64);
+        if walk.is_complete() && walk.clusters.len() as u64 != expected {
+            self.issues.push(FsIssue::SizeMismatch {
+                path: path.to_string(),
+                size: entry.size,
+                clusters: walk.clusters.len() as u32,
+            });
+        }
+    }
+
+    /// Groups allocated clusters that no path claimed into chains. Each
+    /// chain starts at a cluster no other lost cluster links to; clusters
+    /// left over after that form loops and are reported from their lowest
+    /// cluster.
+    pub(crate) fn lost_chains(&self) -> Vec<(u32, Vec<u32>)> {
+        let end = self.layout.cluster_count + 2;
+        let is_lost = |cluster: u32| {
+            self.owners[cluster as usize] == NO_OWNER
+                && matches!(
+                    self.table.fat_entry(cluster),
+                    FatEntry::Chain(_) | FatEntry::EndOfChain
+                )
+        };
+        let mut referenced = vec![false; end as usize];
+        for cluster in (2..end).filter(|&cluster| is_lost(cluster)) {
+            if let FatEntry::Chain(next) = self.table.fat_entry(cluster) {
+                if next < end {
+                    referenced[next as usize] = true;
+                }
+            }
+        }
+
+        let mut visited = vec![false; end as usize];
+        let mut chains = Vec::new();
+        let heads = (2..end)
+            .filter(|&cluster| is_lost(cluster) && !referenced[cluster as usize])
+            .chain((2..end).filter(|&cluster| is_lost(cluster)));
+        for head in heads {
+            if visited[head as usize] {
+                continue;
+            }
+            let mut chain = Vec::new();
+            let mut cluster = head;
+            while cluster < end && is_lost(cluster) && !visited[cluster as usize] {
+                visited[cluster as usize] = true;
+                chain.push(cluster);
+                match self.table.fat_entry(cluster) {
+                    FatEntry::Chain(next) => cluster = next,
+                    _ => break,
+                }
+            }
+            chains.push((head, chain));
+        }
+        chains
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Verifies the consistency of a FAT12/16/32 volume without modifying
+    /// it. An empty result means no problems were found.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn check(&mut self) -> Result<Vec<FsIssue>, SDError> {
+        let layout = self.layout()?;
+        if layout.variant == FatVariant::ExFat {
+            return Err(SDError::UnsupportedFilesystem);
+        }
+        let table = self.load_fat(&layout)?;
+        let mut issues = Vec::new();
+        if self.boot_sector_copy() == BootSectorCopy::Backup {
+            issues.push(FsIssue::DamagedBootSector);
+        }
+        if let Some(flags) = self.dirty_flags()?.filter(|flags| flags.is_dirty()) {
+            issues.push(FsIssue::Dirty {
+                hard_error: flags.hard_error,
+            });
+        }
+        issues.append(&mut self.compare_fat_copies(&layout, &table)?);
+
+        let mut checker = Checker::new(&layout, &table);
+        self.check_tree(&layout, &mut checker)?;
+        for (first_cluster, chain) in checker.lost_chains() {
+            checker.issues.push(FsIssue::LostChain {
+                first_cluster,
+                clusters: chain.len() as u32,
+            });
+        }
+        issues.append(&mut checker.issues);
+        Ok(issues)
+    }
+
+    pub(crate) fn compare_fat_copies(
+        &mut self,
+        layout: &FATLayout,
+        table: &FatTable,
+    ) -> Result<Vec<FsIssue>, SDError> {
+        let sector_size = layout.bytes_per_sector as usize;
+        let mut issues = Vec::new();
+        for copy in 1..layout.number_of_fats {
+            let other = self.load_fat_copy(layout, copy)?;
+            let differs: Vec<bool> = table
+                .bytes()
+                .chunks(sector_size)
+                .zip(other.bytes().chunks(sector_size))
+                .map(|(a, b)| a != b)
+                .collect();
+
+            let mut sector = 0;
+            while sector < differs.len() {
+                if !differs[sector] {
+                    sector += 1;
+                    continue;
+                }
+                let start = sector;
+                while sector < differs.len() && differs[sector] {
+                    sector += 1;
+                }
+                issues.push(FsIssue::FatMismatch {
+                    copy,
+                    first_sector: start as u32,
+                    sectors: (sector - start) as u32,
+                });
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Walks the directory tree using the in-memory FAT, so that a broken
+    /// chain only cuts off the affected directory. Returns every entry that
+    /// has a chain, directories included.
+    pub(crate) fn check_tree(
+        &mut self,
+        layout: &FATLayout,
+        checker: &mut Checker,
+    ) -> Result<Vec<CheckedEntry>, SDError> {
+        let mut checked = Vec::new();
+        let root = root_entry(layout);
+        let root_clusters = if layout.variant == FatVariant::Fat32 {
+            let walk = checker.follow("/", root.first_cluster);
+            let clusters = walk.clusters.clone();
+            checked.push(CheckedEntry {
+                path: "/".to_string(),
+                entry: root,
+                slot: None,
+                walk,
+            });
+            Some(clusters)
+        } else {
+            None
+        };
+        let mut pending = vec![(String::new(), root_clusters)];
+
+        while let Some((path, clusters)) = pending.pop() {
+            let (sectors, data) = match &clusters {
+                None => self.read_dir_region(layout, DirLocation::Root)?,
+                Some(clusters) => {
+                    let sectors = clusters
+                        .iter()
+                        .flat_map(|&cluster| {
+                            let first_sector = layout.cluster_to_sector(cluster);
+                            first_sector..first_sector + layout.sectors_per_cluster
+                        })
+                        .collect();
+                    (sectors, self.read_clusters(layout, clusters)?)
+                }
+            };
+            let bytes_per_sector = layout.bytes_per_sector as usize;
+            let mut entries = DirIter::new(data)
+                .with_codepage(self.codepage())
+                .without_dot_entries();
+            while let Some((slots, entry)) = entries.next_located() {
+                let child = format!("{}/{}", path, entry.full_name());
+                let offset = slots.end - DIR_ENTRY_SIZE;
+                let slot = Some((
+                    sectors[offset / bytes_per_sector],
+                    offset % bytes_per_sector,
+                ));
+                if entry.is_dir() {
+                    let walk = checker.follow(&child, entry.first_cluster);
+                    pending.push((child.clone(), Some(walk.clusters.clone())));
+                    checked.push(CheckedEntry {
+                        path: child,
+                        entry,
+                        slot,
+                        walk,
+                    });
+                } else if entry.first_cluster != 0 || entry.size != 0 {
+                    let walk = if entry.first_cluster == 0 {
+                        ChainWalk {
+                            clusters: Vec::new(),
+                            end: ChainEnd::Complete,
+                        }
+                    } else {
+                        checker.follow(&child, entry.first_cluster)
+                    };
+                    checker.check_size(&child, &entry, &walk);
+                    checked.push(CheckedEntry {
+                        path: child,
+                        entry,
+                        slot,
+                        walk,
+                    });
+                }
+            }
+        }
+        Ok(checked)
+    }
+
+    fn read_clusters(&mut self, layout: &FATLayout, clusters: &[u32]) -> Result<Vec<u8>, SDError> {
+        let mut data = Vec::with_capacity(clusters.len() * layout.cluster_size());
+        for &cluster in clusters {
+            data.extend_from_slice(&self.read_clusters_at(layout, cluster, 1)?);
+        }
+        Ok(data)
+    }
+}
@@ -0,0 +1,187 @@
+use std::iter;
+use std::ops::Range;
+
+use crate::block::BlockDevice;
+use crate::crc32::Crc32;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::fat::FatVariant;
+use crate::image::Verify;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes copied per request.
+const CHUNK_BYTES: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CloneOptions {
+    /// Copy only the blocks the volume uses: everything up to the first
+    /// data cluster, and the clusters the FAT, or the exFAT allocation
+    /// bitmap, marks as allocated. The destination's other blocks keep
+    /// whatever they held.
+    pub used_only: bool,
+    pub verify: Verify,
+}
+
+impl Default for CloneOptions {
+    fn default() -> Self {
+        CloneOptions {
+            used_only: false,
+            verify: Verify::ReadBack,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CloneReport {
+    pub block_size: usize,
+    pub copied_blocks: u64,
+    /// Free space left out with `used_only`.
+    pub skipped_blocks: u64,
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Copies this device, or its open partition, block for block onto
+    /// `dest`, or `dest`'s open partition, then verifies the copy as
+    /// `options.verify` says. `dest` must have writes enabled, the same
+    /// block size, and at least as many blocks; a larger destination keeps
+    /// its extra blocks as they were.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(used_only = options.used_only))
+    )]
+    pub fn clone_to<E: BlockDevice, P: ProgressSink>(
+        &mut self,
+        dest: &mut SDController<E>,
+        options: &CloneOptions,
+        progress: &mut P,
+    ) -> Result<CloneReport, SDError> {
+        let block_size = self.block_size();
+        if dest.block_size() != block_size {
+            return Err(SDError::Unsupported(
+                "cloning between devices with different block sizes",
+            ));
+        }
+        let size = self.num_blocks() * block_size as u64;
+        let capacity = dest.num_blocks() * block_size as u64;
+        if capacity < size {
+            return Err(SDError::DestinationTooSmall {
+                needed: size,
+                capacity,
+            });
+        }
+        if !dest.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let ranges = if options.used_only {
+            self.used_ranges()?
+        } else {
+            iter::once(0..self.num_blocks()).collect()
+        };
+        let copied_blocks: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+
+        let mut crc = Crc32::new();
+        self.copy_ranges(&ranges, Phase::Writing, progress, |start, data| {
+            dest.write_blocks(block_index(start)?, data)?;
+            crc.update(data);
+            Ok(())
+        })?;
+        dest.flush()?;
+
+        match options.verify {
+            Verify::None => {}
+            Verify::ReadBack => {
+                self.copy_ranges(&ranges, Phase::Verifying, progress, |start, data| {
+                    let copy =
+                        dest.read_blocks(block_index(start)?, (data.len() / block_size) as u32)?;
+                    match data.iter().zip(&copy).position(|(a, b)| a != b) {
+                        Some(at) => {
+                            Err(SDError::VerifyFailed(start * block_size as u64 + at as u64))
+                        }
+                        None => Ok(()),
+                    }
+                })?;
+            }
+            Verify::Checksum => {
+                let expected = crc.finish();
+                let mut copy_crc = Crc32::new();
+                dest.copy_ranges(&ranges, Phase::Verifying, progress, |_, data| {
+                    copy_crc.update(data);
+                    Ok(())
+                })?;
+                let actual = copy_crc.finish();
+                if actual != expected {
+                    return Err(SDError::ChecksumMismatch { expected, actual });
+                }
+            }
+        }
+        Ok(CloneReport {
+            block_size,
+            copied_blocks,
+            skipped_blocks: self.num_blocks() - copied_blocks,
+        })
+    }
+
+    /// Reads `ranges` a chunk at a time, passing each chunk to `handle`
+    /// with its first block.
+    pub(crate) fn copy_ranges<P: ProgressSink>(
+        &mut self,
+        ranges: &[Range<u64>],
+        phase: Phase,
+        progress: &mut P,
+        mut handle: impl FnMut(u64, &[u8]) -> Result<(), SDError>,
+    ) -> Result<(), SDError> {
+        let block_size = self.block_size() as u64;
+        let chunk_blocks = (CHUNK_BYTES / block_size).max(1);
+        let total = ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum::<u64>()
+            * block_size;
+        let stopwatch = Stopwatch::start();
+        let mut done = 0;
+        for range in ranges {
+            let mut block = range.start;
+            while block < range.end {
+                let count = chunk_blocks.min(range.end - block);
+                handle(block, &self.read_blocks(block_index(block)?, count as u32)?)?;
+                block += count;
+                done += count * block_size;
+                progress.report(&Progress {
+                    phase,
+                    bytes_done: done,
+                    bytes_total: total,
+                    elapsed: stopwatch.elapsed(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The blocks of the volume in use: the reserved sectors, FATs and
+    /// FAT12/16 root directory, then each run of allocated clusters.
+    pub(crate) fn used_ranges(&mut self) -> Result<Vec<Range<u64>>, SDError> {
+        let layout = self.layout()?;
+        let allocated: Box<dyn Fn(u32) -> bool> = if layout.variant == FatVariant::ExFat {
+            let bitmap = self.read_allocation_bitmap()?;
+            Box::new(move |cluster| bitmap.is_allocated(cluster))
+        } else {
+            let table = self.load_fat(&layout)?;
+            Box::new(move |cluster| !table.fat_entry(cluster).is_free())
+        };
+        let per_cluster = layout.sectors_per_cluster as u64;
+        let mut ranges: Vec<Range<u64>> = iter::once(0..layout.data_start as u64).collect();
+        for cluster in 2..layout.cluster_count + 2 {
+            if !allocated(cluster) {
+                continue;
+            }
+            let start = layout.cluster_to_sector(cluster) as u64;
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end += per_cluster,
+                _ => ranges.push(start..start + per_cluster),
+            }
+        }
+        Ok(ranges)
+    }
+}
@@ -0,0 +1,139 @@
+//! The OEM code pages short names are stored in. FAT keeps 8.3 names in
+//! whatever code page the system that wrote them used: 437 on US systems,
+//! 850 across Western Europe, and double-byte pages such as 932 on
+//! Japanese cameras. Long names are UTF-16 and need none of this.
+
+use alloc::string::String;
+use core::fmt;
+use core::str::FromStr;
+
+/// A code page for decoding short names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Codepage {
+    /// The original IBM PC code page, which Windows and Linux assume when
+    /// told nothing else.
+    #[default]
+    Cp437,
+    /// Multilingual Latin 1, the DOS code page across Western Europe.
+    Cp850,
+    /// Shift JIS as Windows extends it, for Japanese.
+    #[cfg(feature = "cjk")]
+    Cp932,
+    /// GBK, for Simplified Chinese.
+    #[cfg(feature = "cjk")]
+    Cp936,
+    /// Unified Hangul, for Korean.
+    #[cfg(feature = "cjk")]
+    Cp949,
+    /// Big5, for Traditional Chinese.
+    #[cfg(feature = "cjk")]
+    Cp950,
+}
+
+impl Codepage {
+    pub const ALL: &'static [Codepage] = &[
+        Codepage::Cp437,
+        Codepage::Cp850,
+        #[cfg(feature = "cjk")]
+        Codepage::Cp932,
+        #[cfg(feature = "cjk")]
+        Codepage::Cp936,
+        #[cfg(feature = "cjk")]
+        Codepage::Cp949,
+        #[cfg(feature = "cjk")]
+        Codepage::Cp950,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codepage::Cp437 => "cp437",
+            Codepage::Cp850 => "cp850",
+            #[cfg(feature = "cjk")]
+            Codepage::Cp932 => "cp932",
+            #[cfg(feature = "cjk")]
+            Codepage::Cp936 => "cp936",
+            #[cfg(feature = "cjk")]
+            Codepage::Cp949 => "cp949",
+            #[cfg(feature = "cjk")]
+            Codepage::Cp950 => "cp950",
+        }
+    }
+
+    /// Decodes a short name field. Bytes that are not valid in a
+    /// double-byte page come out as U+FFFD.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        let table = match self {
+            Codepage::Cp437 => &CP437,
+            Codepage::Cp850 => &CP850,
+            #[cfg(feature = "cjk")]
+            Codepage::Cp932 => return decode_multibyte(encoding_rs::SHIFT_JIS, bytes),
+            #[cfg(feature = "cjk")]
+            Codepage::Cp936 => return decode_multibyte(encoding_rs::GBK, bytes),
+            #[cfg(feature = "cjk")]
+            Codepage::Cp949 => return decode_multibyte(encoding_rs::EUC_KR, bytes),
+            #[cfg(feature = "cjk")]
+            Codepage::Cp950 => return decode_multibyte(encoding_rs::BIG5, bytes),
+        };
+        bytes
+            .iter()
+            .map(|&byte| match byte {
+                0..=0x7F => byte as char,
+                _ => table[byte as usize - 0x80],
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "cjk")]
+fn decode_multibyte(encoding: &'static encoding_rs::Encoding, bytes: &[u8]) -> String {
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    text.into_owned()
+}
+
+impl fmt::Display for Codepage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codepage {
+    type Err = &'static str;
+
+    /// Takes `cp850` or just `850`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim().to_ascii_lowercase();
+        let number = text.strip_prefix("cp").unwrap_or(&text);
+        Codepage::ALL
+            .iter()
+            .copied()
+            .find(|codepage| &codepage.name()[2..] == number)
+            .ok_or("unknown or unsupported code page")
+    }
+}
+
+/// Bytes 0x80 to 0xFF of the single-byte pages.
+#[rustfmt::skip]
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{00a0}',
+];
+
+#[rustfmt::skip]
+const CP850: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©', '╣', '║', '╗', '╝', '¢', '¥', '┐',
+    '└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤',
+    'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì', '▀',
+    'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´',
+    '\u{00ad}', '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{00a0}',
+];
@@ -0,0 +1,116 @@
+//! Settings read from a TOML file, by default
+//! `~/.config/sd_controller/config.toml`:
+//!
+//! ```toml
+//! block_size = 512
+//! extract_dir = "/srv/ingest"
+//! cache_blocks = 8192
+//! codepage = "cp850"
+//!
+//! [aliases]
+//! camera = "/dev/disk/by-id/usb-Generic_STORAGE_DEVICE-0:0"
+//!
+//! [safety]
+//! allow_write = ["/dev/mmcblk*", "/dev/disk/by-id/usb-Generic_*"]
+//! deny_write = ["/dev/nvme*"]
+//! ```
+//!
+//! Flags given on the command line win over the file.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::codepage::Codepage;
+use crate::error::SDError;
+use crate::filter::glob_matches;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Device block size, when `--block-size` is not given.
+    pub block_size: Option<usize>,
+    /// Where `extract` puts files when no destination is given.
+    pub extract_dir: Option<PathBuf>,
+    /// Blocks kept in memory by commands that cache, such as `mount`.
+    pub cache_blocks: Option<usize>,
+    /// Code page of short names, when `--codepage` is not given.
+    pub codepage: Option<Codepage>,
+    /// Short names that can be given wherever a device is expected.
+    pub aliases: BTreeMap<String, PathBuf>,
+    pub safety: Safety,
+}
+
+/// Overrides of the check that refuses to write to what looks like a
+/// fixed system disk. Patterns are globs over the whole device path, with
+/// `*`, `?` and `[...]`; `*` matches `/` too.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Safety {
+    /// Devices written without the check, as if `--force` were given.
+    pub allow_write: Vec<String>,
+    /// Devices never written, even with `--force`. These win over
+    /// `allow_write`.
+    pub deny_write: Vec<String>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/sd_controller/config.toml`, or under
+    /// `~/.config` without it; `%APPDATA%\sd_controller\config.toml` on
+    /// Windows.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = if cfg!(windows) {
+            std::env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            std::env::var_os("XDG_CONFIG_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        };
+        Some(base?.join("sd_controller").join("config.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SDError> {
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| SDError::InvalidConfig {
+            path: path.display().to_string(),
+            reason: e.message().to_string(),
+        })
+    }
+
+    /// The file at `default_path`, or the defaults if there is none.
+    pub fn load_default() -> Result<Self, SDError> {
+        match Config::default_path() {
+            Some(path) if path.exists() => Config::load(&path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    /// The device an alias names, or `device` itself.
+    pub fn resolve_device<'a>(&'a self, device: &'a Path) -> &'a Path {
+        device
+            .to_str()
+            .and_then(|name| self.aliases.get(name))
+            .map_or(device, PathBuf::as_path)
+    }
+
+    pub fn allows_write(&self, device: &Path) -> bool {
+        matches_any(&self.safety.allow_write, device)
+    }
+
+    pub fn denies_write(&self, device: &Path) -> bool {
+        matches_any(&self.safety.deny_write, device)
+    }
+}
+
+/// Whether `device`, or the node a link like `/dev/disk/by-id/...` points
+/// to, matches one of `patterns`.
+fn matches_any(patterns: &[String], device: &Path) -> bool {
+    let target = std::fs::canonicalize(device).ok();
+    let matches = |path: &Path| {
+        let path = path.to_string_lossy();
+        patterns.iter().any(|pattern| glob_matches(pattern, &path))
+    };
+    matches(device) || target.is_some_and(|target| matches(&target))
+}
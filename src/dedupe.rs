@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::hash::{Digest, HashAlgorithm};
+use crate::log::debug;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes hashed between progress updates.
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// What `find_duplicates` looks at.
+#[derive(Debug, Clone)]
+pub struct DedupeOptions {
+    /// Directory to search below.
+    pub root: String,
+    /// Files smaller than this are left out. Defaults to 1, so that empty
+    /// files are not all reported as copies of each other.
+    pub min_size: u64,
+    pub max_size: Option<u64>,
+    pub algorithm: HashAlgorithm,
+}
+
+impl Default for DedupeOptions {
+    fn default() -> Self {
+        DedupeOptions {
+            root: "/".to_string(),
+            min_size: 1,
+            max_size: None,
+            algorithm: HashAlgorithm::default(),
+        }
+    }
+}
+
+/// Files with the same contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DuplicateSet {
+    pub size: u64,
+    pub digest: Digest,
+    /// In the order the walk found them.
+    pub paths: Vec<String>,
+}
+
+impl DuplicateSet {
+    /// Space freed by keeping only one of the files.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DedupeReport {
+    /// Files within the size limits.
+    pub files: u64,
+    /// Files that shared their size with another and so were hashed.
+    pub hashed: u64,
+    /// Largest waste first.
+    pub duplicates: Vec<DuplicateSet>,
+}
+
+impl DedupeReport {
+    pub fn wasted_bytes(&self) -> u64 {
+        self.duplicates.iter().map(DuplicateSet::wasted_bytes).sum()
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Finds files below `options.root` with the same contents. Only files
+    /// of the same size are hashed, and progress counts the bytes of those.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(root = %options.root))
+    )]
+    pub fn find_duplicates<P: ProgressSink>(
+        &mut self,
+        options: &DedupeOptions,
+        progress: &mut P,
+    ) -> Result<DedupeReport, SDError> {
+        let mut by_size: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        let mut files = 0;
+        for item in self.walk_from(&options.root)? {
+            let item = item?;
+            let size = item.entry.size;
+            if item.entry.is_dir()
+                || size < options.min_size
+                || options.max_size.is_some_and(|max| size > max)
+            {
+                continue;
+            }
+            files += 1;
+            by_size.entry(size).or_default().push(item.path);
+        }
+        by_size.retain(|_, paths| paths.len() > 1);
+
+        let total = by_size
+            .iter()
+            .map(|(size, paths)| size * paths.len() as u64)
+            .sum();
+        let stopwatch = Stopwatch::start();
+        let mut done = 0;
+        let mut hashed = 0;
+        let mut buffer = vec![0u8; CHUNK_BYTES];
+        let mut duplicates = Vec::new();
+        for (size, paths) in by_size {
+            let mut by_digest: BTreeMap<Vec<u8>, DuplicateSet> = BTreeMap::new();
+            for path in paths {
+                let mut hasher = options.algorithm.hasher()?;
+                let mut reader = self.open_reader(&path)?;
+                loop {
+                    let read = reader.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..read]);
+                    done += read as u64;
+                    progress.report(&Progress {
+                        phase: Phase::Hashing,
+                        bytes_done: done,
+                        bytes_total: total,
+                        elapsed: stopwatch.elapsed(),
+                    });
+                }
+                hashed += 1;
+                let digest = Digest {
+                    algorithm: options.algorithm,
+                    bytes: hasher.finish(),
+                };
+                by_digest
+                    .entry(digest.bytes.clone())
+                    .or_insert_with(|| DuplicateSet {
+                        size,
+                        digest,
+                        paths: Vec::new(),
+                    })
+                    .paths
+                    .push(path);
+            }
+            duplicates.extend(by_digest.into_values().filter(|set| set.paths.len() > 1));
+        }
+        duplicates.sort_by_key(|set| std::cmp::Reverse(set.wasted_bytes()));
+        debug!(files, hashed, sets = duplicates.len(), "found duplicates");
+        Ok(DedupeReport {
+            files,
+            hashed,
+            duplicates,
+        })
+    }
+}
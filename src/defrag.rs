@@ -0,0 +1,418 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{set_first_cluster, DIR_ENTRY_SIZE};
+use crate::error::SDError;
+use crate::fat::FatTable;
+use crate::layout::FATLayout;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes copied per request.
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// First line of a journal, naming its format.
+const JOURNAL_HEADER: &str = "sd_controller defragment journal 1";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DefragReport {
+    /// Files whose clusters were in more than one run.
+    pub fragmented: usize,
+    /// Files moved into a single run of clusters.
+    pub moved: Vec<String>,
+    /// Fragmented files left where they were, for want of a free run long
+    /// enough to hold them.
+    pub skipped: Vec<String>,
+    pub moved_bytes: u64,
+}
+
+/// What an interrupted defragmentation was doing, as its journal records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum JournalState {
+    /// The copy was under way and the file still points at its old
+    /// clusters, which hold its data as before.
+    Copying,
+    /// The file points at its new clusters; the old ones were still to be
+    /// freed.
+    Switched,
+}
+
+/// The file an interrupted defragmentation was moving.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DefragJournal {
+    pub path: String,
+    pub state: JournalState,
+}
+
+/// The one move a journal records: a file, where its directory entry is,
+/// and its clusters before and after.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Move {
+    path: String,
+    /// The partition it was made on, as a check that the journal is resumed
+    /// against the same volume.
+    partition_start: u32,
+    sector: u32,
+    offset: usize,
+    old: Vec<u32>,
+    new_start: u32,
+}
+
+impl Move {
+    fn new_clusters(&self) -> impl Iterator<Item = u32> {
+        self.new_start..self.new_start + self.old.len() as u32
+    }
+
+    fn to_journal(&self) -> String {
+        let old: Vec<String> = self.old.iter().map(u32::to_string).collect();
+        format!(
+            "{}\npath {}\npartition {}\nentry {} {}\nold {}\nnew {} {}\n",
+            JOURNAL_HEADER,
+            self.path,
+            self.partition_start,
+            self.sector,
+            self.offset,
+            old.join(" "),
+            self.new_start,
+            self.old.len()
+        )
+    }
+
+    fn parse_journal(text: &str) -> Result<Move, SDError> {
+        let invalid = || SDError::InvalidJournal("a line is missing or malformed");
+        let mut lines = text.lines();
+        if lines.next() != Some(JOURNAL_HEADER) {
+            return Err(SDError::InvalidJournal("not a defragmentation journal"));
+        }
+        let mut field = |name: &str| {
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|rest| rest.strip_prefix(' '))
+                .ok_or_else(invalid)
+        };
+        let path = field("path")?.to_string();
+        let partition_start = field("partition")?.parse().map_err(|_| invalid())?;
+        let (sector, offset) = field("entry")?.split_once(' ').ok_or_else(invalid)?;
+        let old: Vec<u32> = field("old")?
+            .split(' ')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+        let (new_start, count) = field("new")?.split_once(' ').ok_or_else(invalid)?;
+        let parsed = Move {
+            path,
+            partition_start,
+            sector: sector.parse().map_err(|_| invalid())?,
+            offset: offset.parse().map_err(|_| invalid())?,
+            old,
+            new_start: new_start.parse().map_err(|_| invalid())?,
+        };
+        if count.parse() != Ok(parsed.old.len()) || !parsed.offset.is_multiple_of(DIR_ENTRY_SIZE) {
+            return Err(invalid());
+        }
+        Ok(parsed)
+    }
+}
+
+/// Whether `clusters` are not one ascending run.
+fn is_fragmented(clusters: &[u32]) -> bool {
+    clusters.windows(2).any(|pair| pair[1] != pair[0] + 1)
+}
+
+/// The first run of `count` free clusters.
+fn free_run(table: &FatTable, layout: &FATLayout, count: usize) -> Option<u32> {
+    let mut start = 2;
+    let mut run = 0;
+    for cluster in 2..layout.cluster_count + 2 {
+        if table.fat_entry(cluster).is_free() {
+            if run == 0 {
+                start = cluster;
+            }
+            run += 1;
+            if run == count {
+                return Some(start);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+/// Writes `contents` to `path` through a temporary file, so that a journal
+/// is either whole or absent.
+fn write_journal(path: &Path, contents: &str) -> Result<(), SDError> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let mut file = fs::File::create(&partial)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Moves every fragmented file of a FAT12/16/32 volume into a single
+    /// run of free clusters, first fit. Directories are left where they
+    /// are. Writes must be enabled, and nothing else may use the volume
+    /// meanwhile.
+    ///
+    /// Each file is copied before anything points at the copy, then its
+    /// directory entry is switched over in one sector write, and only then
+    /// are its old clusters freed. The move under way is recorded in the
+    /// file at `journal`, which is removed once it is done; if the run is
+    /// cut short, `resume_defragment` finishes the move and
+    /// `roll_back_defragment` undoes it. A run does not start while a
+    /// journal is left over.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn defragment<P: ProgressSink>(
+        &mut self,
+        journal: &Path,
+        progress: &mut P,
+    ) -> Result<DefragReport, SDError> {
+        if journal.exists() {
+            return Err(SDError::Interrupted(journal.display().to_string()));
+        }
+        let layout = self.writable_layout()?;
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let mut table = self.load_fat(&layout)?;
+        let mut fragmented = Vec::new();
+        for walked in self.walk()? {
+            let walked = walked?;
+            if walked.entry.is_dir() || walked.entry.first_cluster < 2 {
+                continue;
+            }
+            let clusters: Vec<u32> = table
+                .chain(walked.entry.first_cluster)
+                .collect::<Result<_, _>>()?;
+            if is_fragmented(&clusters) {
+                fragmented.push((walked.path, walked.entry, clusters));
+            }
+        }
+
+        let mut report = DefragReport {
+            fragmented: fragmented.len(),
+            ..DefragReport::default()
+        };
+        let total = fragmented
+            .iter()
+            .map(|(_, _, clusters)| clusters.len())
+            .sum::<usize>() as u64
+            * layout.cluster_size() as u64;
+        let mut tally = Tally {
+            done: 0,
+            total,
+            stopwatch: Stopwatch::start(),
+        };
+        for (path, entry, old) in fragmented {
+            let Some(new_start) = free_run(&table, &layout, old.len()) else {
+                tally.done += old.len() as u64 * layout.cluster_size() as u64;
+                report.skipped.push(path);
+                continue;
+            };
+            let (_, located) = self.locate(&layout, &path)?;
+            let planned = Move {
+                path,
+                partition_start: self.partition_start(),
+                sector: located.sector,
+                offset: located.offset,
+                old,
+                new_start,
+            };
+            write_journal(journal, &planned.to_journal())?;
+            self.copy_and_switch(&layout, &mut table, &planned, &mut tally, progress)?;
+            self.free_old_clusters(&layout, &mut table, &planned)?;
+            fs::remove_file(journal)?;
+            report.moved_bytes += entry.size;
+            report.moved.push(planned.path);
+        }
+        Ok(report)
+    }
+
+    /// Reads the journal an interrupted `defragment` left at `journal`,
+    /// checking it against the volume.
+    pub fn defragment_journal(&mut self, journal: &Path) -> Result<DefragJournal, SDError> {
+        let layout = self.writable_layout()?;
+        let planned = Move::parse_journal(&fs::read_to_string(journal)?)?;
+        let state = self.journal_state(&layout, &planned)?;
+        Ok(DefragJournal {
+            path: planned.path,
+            state,
+        })
+    }
+
+    /// Finishes the move an interrupted `defragment` recorded at `journal`,
+    /// then removes the journal. The rest of the volume is not looked at;
+    /// run `defragment` again for that. Returns the path of the file.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn resume_defragment(&mut self, journal: &Path) -> Result<String, SDError> {
+        let layout = self.writable_layout()?;
+        let planned = Move::parse_journal(&fs::read_to_string(journal)?)?;
+        let mut table = self.load_fat(&layout)?;
+        if self.journal_state(&layout, &planned)? == JournalState::Copying {
+            let mut tally = Tally {
+                done: 0,
+                total: planned.old.len() as u64 * layout.cluster_size() as u64,
+                stopwatch: Stopwatch::start(),
+            };
+            self.copy_and_switch(&layout, &mut table, &planned, &mut tally, &mut ())?;
+        }
+        self.free_old_clusters(&layout, &mut table, &planned)?;
+        fs::remove_file(journal)?;
+        Ok(planned.path)
+    }
+
+    /// Undoes the move an interrupted `defragment` recorded at `journal`,
+    /// leaving the file in its old clusters, then removes the journal. The
+    /// old clusters are never written during a move, so this holds even
+    /// after the switch to the new ones. Returns the path of the file.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn roll_back_defragment(&mut self, journal: &Path) -> Result<String, SDError> {
+        let layout = self.writable_layout()?;
+        let planned = Move::parse_journal(&fs::read_to_string(journal)?)?;
+        let mut table = self.load_fat(&layout)?;
+        if self.journal_state(&layout, &planned)? == JournalState::Switched {
+            link_chain(&mut table, planned.old.iter().copied());
+            self.store_fat(&layout, &mut table)?;
+            self.flush()?;
+            self.point_entry(&planned, planned.old[0])?;
+        }
+        for cluster in planned.new_clusters() {
+            table.set_entry(cluster, 0);
+        }
+        self.store_fat(&layout, &mut table)?;
+        self.flush()?;
+        fs::remove_file(journal)?;
+        Ok(planned.path)
+    }
+
+    /// Tells from the file's directory entry which side of the switch an
+    /// interrupted move stopped on.
+    fn journal_state(
+        &mut self,
+        layout: &FATLayout,
+        planned: &Move,
+    ) -> Result<JournalState, SDError> {
+        if planned.partition_start != self.partition_start() {
+            return Err(SDError::InvalidJournal(
+                "it was written for another partition",
+            ));
+        }
+        let in_range = |cluster: u32| layout.is_data_cluster(cluster);
+        if !planned.old.iter().all(|&c| in_range(c)) || !planned.new_clusters().all(in_range) {
+            return Err(SDError::InvalidJournal("it does not match the volume"));
+        }
+        let (_, located) = match self.locate(layout, &planned.path) {
+            Err(error) if matches!(error.root_cause(), SDError::NotFound(_)) => {
+                return Err(SDError::InvalidJournal("its file is gone"))
+            }
+            result => result?,
+        };
+        if located.sector != planned.sector || located.offset != planned.offset {
+            return Err(SDError::InvalidJournal("its file has moved since"));
+        }
+        let entry = located.entry;
+        if entry.first_cluster == planned.old[0] {
+            Ok(JournalState::Copying)
+        } else if entry.first_cluster == planned.new_start {
+            Ok(JournalState::Switched)
+        } else {
+            Err(SDError::InvalidJournal("its file has moved since"))
+        }
+    }
+
+    /// Copies the file's clusters into the new run, links the run in the
+    /// FAT, and switches the directory entry over, flushing between steps
+    /// so that they reach the card in this order.
+    fn copy_and_switch<P: ProgressSink>(
+        &mut self,
+        layout: &FATLayout,
+        table: &mut FatTable,
+        planned: &Move,
+        tally: &mut Tally,
+        progress: &mut P,
+    ) -> Result<(), SDError> {
+        let chunk_clusters = (CHUNK_BYTES / layout.cluster_size()).max(1);
+        let mut index = 0;
+        while index < planned.old.len() {
+            // A run of adjacent old clusters, at most a chunk long.
+            let first = planned.old[index];
+            let mut count = 1;
+            while count < chunk_clusters
+                && planned.old.get(index + count) == Some(&(first + count as u32))
+            {
+                count += 1;
+            }
+            let data = self.read_clusters_at(layout, first, count as u32)?;
+            let target = planned.new_start + index as u32;
+            self.write_clusters_at(layout, target, &data)?;
+            index += count;
+            tally.done += data.len() as u64;
+            tally.report(progress);
+        }
+        self.flush()?;
+        link_chain(table, planned.new_clusters());
+        self.store_fat(layout, table)?;
+        self.flush()?;
+        self.point_entry(planned, planned.new_start)
+    }
+
+    fn point_entry(&mut self, planned: &Move, cluster: u32) -> Result<(), SDError> {
+        let block = self.read_block(planned.sector)?;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw.copy_from_slice(&block[planned.offset..planned.offset + DIR_ENTRY_SIZE]);
+        set_first_cluster(&mut raw, cluster);
+        self.write_entry_at(planned.sector, planned.offset, &raw)?;
+        self.flush()
+    }
+
+    fn free_old_clusters(
+        &mut self,
+        layout: &FATLayout,
+        table: &mut FatTable,
+        planned: &Move,
+    ) -> Result<(), SDError> {
+        for &cluster in &planned.old {
+            table.set_entry(cluster, 0);
+        }
+        self.store_fat(layout, table)?;
+        self.flush()
+    }
+}
+
+/// Links `clusters` into a chain in `table`, in order.
+fn link_chain(table: &mut FatTable, clusters: impl Iterator<Item = u32>) {
+    let end_of_chain = table.variant().entry_mask();
+    let mut clusters = clusters.peekable();
+    while let Some(cluster) = clusters.next() {
+        let next = clusters.peek().copied().unwrap_or(end_of_chain);
+        table.set_entry(cluster, next);
+    }
+}
+
+struct Tally {
+    done: u64,
+    total: u64,
+    stopwatch: Stopwatch,
+}
+
+impl Tally {
+    fn report<P: ProgressSink>(&self, progress: &mut P) {
+        progress.report(&Progress {
+            phase: Phase::Writing,
+            bytes_done: self.done,
+            bytes_total: self.total,
+            elapsed: self.stopwatch.elapsed(),
+        });
+    }
+}
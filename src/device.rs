@@ -0,0 +1,583 @@
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use crate::block::FileDevice;
+use crate::block::{BlockDevice, ReadOnlyDevice};
+use crate::boot::BootSectorCopy;
+use crate::codepage::Codepage;
+use crate::dir::{root_entry, split_path, DirEntry, DirIter, DirLocation};
+use crate::error::{Operation, SDError};
+use crate::exfat;
+use crate::fat::{FATBootSector, FatVariant};
+use crate::layout::FATLayout;
+use crate::log::{debug, trace};
+
+#[cfg(feature = "std")]
+pub struct SDController<D: BlockDevice = FileDevice> {
+    pub(crate) device: D,
+    partition_start: u32,
+    partition_blocks: Option<u64>,
+    partition: Option<usize>,
+    writable: bool,
+    pub(crate) boot_sector_copy: BootSectorCopy,
+    codepage: Codepage,
+}
+
+/// Without `std` there is no `FileDevice` to default to.
+#[cfg(not(feature = "std"))]
+pub struct SDController<D: BlockDevice> {
+    pub(crate) device: D,
+    partition_start: u32,
+    partition_blocks: Option<u64>,
+    partition: Option<usize>,
+    writable: bool,
+    pub(crate) boot_sector_copy: BootSectorCopy,
+    codepage: Codepage,
+}
+
+/// A controller that cannot write to its device, whatever it is asked to do.
+pub type ReadOnlyController<D> = SDController<ReadOnlyDevice<D>>;
+
+impl<D: BlockDevice> ReadOnlyController<D> {
+    /// A controller that refuses to write to `device`, for workflows that
+    /// must leave it untouched. Every write fails with `ReadOnly` without
+    /// reaching the device, even after `enable_writes`.
+    pub fn read_only(device: D) -> Self {
+        SDController::from_device(ReadOnlyDevice::new(device))
+    }
+}
+
+#[cfg(feature = "std")]
+impl SDController<FileDevice> {
+    /// Opens a device node or a disk image file such as a `.img` dump
+    /// read-only. Blocks are addressed from the start of the file; call
+    /// `open_volume` to find the filesystem on partitioned media.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        Ok(SDController::from_device(FileDevice::open(path)?))
+    }
+
+    /// Opens the device read-only with a block size other than 512 bytes,
+    /// e.g. 4096 for 4Kn media. The filesystem's sector size must match.
+    pub fn with_block_size<P: AsRef<Path>>(path: P, block_size: usize) -> Result<Self, SDError> {
+        let file = std::fs::File::open(path)?;
+        Ok(SDController::from_device(FileDevice::from_file(
+            file, block_size, false,
+        )?))
+    }
+
+    /// Opens the device for reading and writing. Controllers are read-only
+    /// unless created through this constructor or `enable_writes`.
+    pub fn open_rw<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        let mut controller = SDController::from_device(FileDevice::open_rw(path)?);
+        controller.enable_writes();
+        Ok(controller)
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    pub fn from_device(device: D) -> Self {
+        SDController {
+            device,
+            partition_start: 0,
+            partition_blocks: None,
+            partition: None,
+            writable: false,
+            boot_sector_copy: BootSectorCopy::Primary,
+            codepage: Codepage::default(),
+        }
+    }
+
+    pub fn enable_writes(&mut self) {
+        self.writable = true;
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Sets the code page short names are decoded with. Long names do not
+    /// depend on it.
+    pub fn set_codepage(&mut self, codepage: Codepage) {
+        self.codepage = codepage;
+    }
+
+    pub fn codepage(&self) -> Codepage {
+        self.codepage
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Reads a block relative to the start of the open partition, or of the
+    /// whole device if no partition has been opened.
+    pub fn read_block(&mut self, block_index: u32) -> Result<Vec<u8>, SDError> {
+        let absolute = self.absolute_block(block_index)?;
+        self.read_device_block(absolute)
+    }
+
+    /// Reads a block at an absolute LBA, ignoring any open partition.
+    pub fn read_device_block(&mut self, block_index: u32) -> Result<Vec<u8>, SDError> {
+        trace!(block = block_index, "read block");
+        let mut buffer = vec![0; self.block_size()];
+        self.device
+            .read_block(block_index, &mut buffer)
+            .map_err(|e| self.located(e, Operation::Read, Some(block_index)))?;
+        Ok(buffer)
+    }
+
+    /// Reads `count` consecutive blocks relative to the start of the open
+    /// partition with a single device request.
+    pub fn read_blocks(&mut self, start: u32, count: u32) -> Result<Vec<u8>, SDError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let last = start
+            .checked_add(count - 1)
+            .ok_or(SDError::BlockOutOfRange(start as u64 + count as u64 - 1))?;
+        self.absolute_block(last)?;
+        let absolute = self.absolute_block(start)?;
+        self.read_device_blocks(absolute, count)
+    }
+
+    /// Reads `count` consecutive blocks at an absolute LBA.
+    pub fn read_device_blocks(&mut self, start: u32, count: u32) -> Result<Vec<u8>, SDError> {
+        trace!(block = start, count, "read blocks");
+        let mut buffer = vec![0; count as usize * self.block_size()];
+        self.device
+            .read_blocks(start, &mut buffer)
+            .map_err(|e| self.located(e, Operation::Read, Some(start)))?;
+        Ok(buffer)
+    }
+
+    /// Writes a block relative to the start of the open partition.
+    pub fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        let absolute = self.absolute_block(block_index)?;
+        self.write_device_block(absolute, data)
+    }
+
+    /// Writes a block at an absolute LBA, ignoring any open partition.
+    pub fn write_device_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if !self.writable {
+            return Err(SDError::ReadOnly);
+        }
+        if data.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if block_index as u64 >= self.device.num_blocks() {
+            return Err(SDError::BlockOutOfRange(block_index as u64));
+        }
+        trace!(block = block_index, "write block");
+        self.device
+            .write_block(block_index, data)
+            .map_err(|e| self.located(e, Operation::Write, Some(block_index)))
+    }
+
+    /// Writes consecutive blocks relative to the start of the open partition
+    /// with a single device request. `data` must be a whole number of
+    /// blocks.
+    pub fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !self.writable {
+            return Err(SDError::ReadOnly);
+        }
+        let block_size = self.block_size();
+        if !data.len().is_multiple_of(block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let count = (data.len() / block_size) as u64;
+        if count == 0 {
+            return Ok(());
+        }
+        let last = start as u64 + count - 1;
+        let last_absolute =
+            self.absolute_block(u32::try_from(last).map_err(|_| SDError::BlockOutOfRange(last))?)?;
+        if last_absolute as u64 >= self.device.num_blocks() {
+            return Err(SDError::BlockOutOfRange(last));
+        }
+        let absolute = self.absolute_block(start)?;
+        trace!(block = absolute, count, "write blocks");
+        self.device
+            .write_blocks(absolute, data)
+            .map_err(|e| self.located(e, Operation::Write, Some(absolute)))
+    }
+
+    pub fn flush(&mut self) -> Result<(), SDError> {
+        trace!("flush");
+        self.device
+            .flush()
+            .map_err(|e| self.located(e, Operation::Flush, None))
+    }
+
+    /// Discards `count` blocks from `start`, relative to the open
+    /// partition, on devices that support it. Writes must be enabled.
+    pub fn discard_blocks(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        if !self.writable {
+            return Err(SDError::ReadOnly);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let last = start as u64 + count as u64 - 1;
+        let last_absolute =
+            self.absolute_block(u32::try_from(last).map_err(|_| SDError::BlockOutOfRange(last))?)?;
+        if last_absolute as u64 >= self.device.num_blocks() {
+            return Err(SDError::BlockOutOfRange(last));
+        }
+        let absolute = self.absolute_block(start)?;
+        trace!(block = absolute, count, "discard blocks");
+        self.device
+            .discard(absolute, count)
+            .map_err(|e| self.located(e, Operation::Discard, Some(absolute)))
+    }
+
+    /// Adds the failed request and the open partition to a device error.
+    fn located(&self, error: SDError, operation: Operation, block: Option<u32>) -> SDError {
+        debug!(?operation, ?block, %error, "device request failed");
+        let error = error.during(operation, block.map(u64::from));
+        match self.partition {
+            Some(partition) => error.in_partition(partition),
+            None => error,
+        }
+    }
+
+    fn absolute_block(&self, block_index: u32) -> Result<u32, SDError> {
+        if self
+            .partition_blocks
+            .is_some_and(|blocks| block_index as u64 >= blocks)
+        {
+            return Err(SDError::BlockOutOfRange(block_index as u64));
+        }
+        self.partition_start
+            .checked_add(block_index)
+            .ok_or(SDError::BlockOutOfRange(block_index as u64))
+    }
+
+    pub(crate) fn set_partition(&mut self, index: usize, start: u32, blocks: u64) {
+        self.partition_start = start;
+        self.partition_blocks = Some(blocks);
+        self.partition = Some(index);
+        self.boot_sector_copy = BootSectorCopy::Primary;
+    }
+
+    /// Goes back to addressing the whole device.
+    pub fn close_partition(&mut self) {
+        self.partition_start = 0;
+        self.partition_blocks = None;
+        self.partition = None;
+        self.boot_sector_copy = BootSectorCopy::Primary;
+    }
+
+    /// Index of the open partition in the partition table.
+    pub fn partition(&self) -> Option<usize> {
+        self.partition
+    }
+
+    pub fn partition_start(&self) -> u32 {
+        self.partition_start
+    }
+
+    /// Number of blocks in the open partition, or on the whole device.
+    pub fn num_blocks(&self) -> u64 {
+        self.partition_blocks
+            .unwrap_or_else(|| self.device.num_blocks())
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    /// Reads and validates the boot sector. When sector 0 cannot be read
+    /// or is not a valid boot sector, the FAT32 backup is used instead, and
+    /// `boot_sector_copy` says so; the error is about the primary if the
+    /// backup is no good either.
+    pub fn read_boot_sector(&mut self) -> Result<FATBootSector, SDError> {
+        let primary = self.read_block(0).and_then(|data| {
+            FATBootSector::parse(&data).map_err(|e| e.at_block(self.partition_start as u64))
+        });
+        let (boot_sector, copy) = match primary {
+            Ok(boot_sector) => (boot_sector, BootSectorCopy::Primary),
+            Err(error) => match self.read_backup_boot_sector() {
+                Ok(backup) => {
+                    debug!(%error, "primary boot sector is damaged, using the backup");
+                    (backup, BootSectorCopy::Backup)
+                }
+                Err(_) => return Err(error),
+            },
+        };
+        self.boot_sector_copy = copy;
+        debug!(
+            bytes_per_sector = boot_sector.bytes_per_sector,
+            sectors_per_cluster = boot_sector.sectors_per_cluster,
+            reserved_sectors = boot_sector.reserved_sectors,
+            fats = boot_sector.number_of_fats,
+            "parsed boot sector"
+        );
+        Ok(boot_sector)
+    }
+
+    pub fn calculate_layout(&self, boot_sector: &FATBootSector) -> FATLayout {
+        FATLayout::new(boot_sector)
+    }
+
+    pub fn read_root_dir(&mut self) -> Result<DirIter, SDError> {
+        let layout = self.layout()?;
+
+        self.list_dir(&layout, &root_entry(&layout))
+    }
+
+    /// Looks up the entry at a slash-separated path such as
+    /// `/LOGS/2024/DATA01.CSV`. Names match case-insensitively against both
+    /// long and short names, and `.`/`..` are resolved lexically. `/` yields
+    /// a synthetic entry for the root.
+    pub fn stat(&mut self, path: &str) -> Result<DirEntry, SDError> {
+        let layout = self.layout()?;
+        let mut components: Vec<&str> = Vec::new();
+        for component in split_path(path) {
+            match component {
+                "." => {}
+                ".." => {
+                    components.pop();
+                }
+                component => components.push(component),
+            }
+        }
+
+        let mut current = root_entry(&layout);
+        for (depth, component) in components.iter().enumerate() {
+            if !current.is_dir() {
+                return Err(SDError::NotADirectory(components[..depth].join("/")));
+            }
+            trace!(component, cluster = current.first_cluster, "looking up");
+            current = self
+                .list_dir(&layout, &current)?
+                .find(|entry| !entry.is_dot_entry() && entry.matches_name(component))
+                .ok_or_else(|| SDError::NotFound(components[..=depth].join("/")))?;
+        }
+        Ok(current)
+    }
+
+    /// Lists the subdirectory whose chain starts at `cluster`; cluster 0
+    /// stands for the root. The `.` and `..` entries are skipped. exFAT
+    /// directories without a FAT chain need `open_dir` instead.
+    pub fn read_dir(&mut self, cluster: u32) -> Result<DirIter, SDError> {
+        let layout = self.layout()?;
+        let mut dir = root_entry(&layout);
+        if cluster != 0 {
+            dir.first_cluster = cluster;
+        }
+        Ok(self.list_dir(&layout, &dir)?.without_dot_entries())
+    }
+
+    /// Lists the directory described by `dir`, as returned by `stat` or a
+    /// previous listing.
+    pub fn open_dir(&mut self, dir: &DirEntry) -> Result<DirIter, SDError> {
+        if !dir.is_dir() {
+            return Err(SDError::NotADirectory(dir.full_name()));
+        }
+        let layout = self.layout()?;
+        Ok(self.list_dir(&layout, dir)?.without_dot_entries())
+    }
+
+    /// Reads the whole file at `path`.
+    pub fn open(&mut self, path: &str) -> Result<Vec<u8>, SDError> {
+        let entry = self.stat(path)?;
+        if entry.is_dir() {
+            return Err(SDError::IsADirectory(path.to_string()));
+        }
+        self.read_file(&entry).map_err(|e| e.in_file(path))
+    }
+
+    /// Iterates over the entries of the directory described by `dir`.
+    pub(crate) fn list_dir(
+        &mut self,
+        layout: &FATLayout,
+        dir: &DirEntry,
+    ) -> Result<DirIter, SDError> {
+        if layout.variant == FatVariant::ExFat {
+            let data = if dir.contiguous {
+                self.read_contiguous(layout, dir.first_cluster, dir.size as usize)?
+            } else {
+                self.read_cluster_chain(layout, dir.first_cluster, None)?
+            };
+            return Ok(DirIter::exfat(data));
+        }
+        let location = self.normalize_location(layout, DirLocation::of(dir));
+        let (_, data) = self.read_dir_region(layout, location)?;
+        Ok(DirIter::new(data).with_codepage(self.codepage))
+    }
+
+    pub fn read_file(&mut self, entry: &DirEntry) -> Result<Vec<u8>, SDError> {
+        let layout = self.layout()?;
+        let size = entry.size as usize;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut data = if entry.contiguous {
+            self.read_contiguous(&layout, entry.first_cluster, size)?
+        } else {
+            self.read_cluster_chain(&layout, entry.first_cluster, Some(size))?
+        };
+        if data.len() < size {
+            return Err(SDError::ReadError {
+                expected: size,
+                actual: data.len(),
+            });
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    pub(crate) fn layout(&mut self) -> Result<FATLayout, SDError> {
+        // A damaged block 0 may still have a FAT backup boot sector, so
+        // only a readable one is probed for exFAT.
+        if let Ok(block) = self.read_block(0) {
+            if exfat::is_exfat(&block) {
+                return Ok(self.read_exfat_boot_sector()?.layout());
+            }
+        }
+        let boot_sector = self.read_boot_sector()?;
+        self.check_sector_size(boot_sector.bytes_per_sector as u32)?;
+        Ok(self.calculate_layout(&boot_sector))
+    }
+
+    /// Sectors are addressed as device blocks, so the filesystem's sector
+    /// size has to equal the device's block size.
+    pub(crate) fn check_sector_size(&self, bytes_per_sector: u32) -> Result<(), SDError> {
+        if bytes_per_sector as usize != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        Ok(())
+    }
+
+    fn read_contiguous(
+        &mut self,
+        layout: &FATLayout,
+        first_cluster: u32,
+        size: usize,
+    ) -> Result<Vec<u8>, SDError> {
+        let clusters = size.div_ceil(layout.cluster_size()) as u32;
+        self.read_clusters_at(layout, first_cluster, clusters)
+    }
+
+    /// Reads data cluster `cluster` of the open volume. Clusters are
+    /// numbered from 2, as directory entries and the FAT number them.
+    pub fn read_cluster(&mut self, cluster: u32) -> Result<Vec<u8>, SDError> {
+        let layout = self.layout()?;
+        self.read_clusters_at(&layout, cluster, 1)
+    }
+
+    /// Overwrites data cluster `cluster` of the open volume with `data`,
+    /// which must be exactly one cluster long. The FAT is not touched, so
+    /// the cluster stays as allocated or free as it was.
+    pub fn write_cluster(&mut self, cluster: u32, data: &[u8]) -> Result<(), SDError> {
+        let layout = self.layout()?;
+        if data.len() != layout.cluster_size() {
+            return Err(SDError::InvalidArgument(
+                "cluster data must be exactly one cluster long",
+            ));
+        }
+        self.write_clusters_at(&layout, cluster, data)
+    }
+
+    /// Reads `count` consecutive clusters from `first` with a single
+    /// request, failing if any of them is outside the data region.
+    pub(crate) fn read_clusters_at(
+        &mut self,
+        layout: &FATLayout,
+        first: u32,
+        count: u32,
+    ) -> Result<Vec<u8>, SDError> {
+        check_cluster_run(layout, first, count)?;
+        self.read_blocks(
+            layout.cluster_to_sector(first),
+            count * layout.sectors_per_cluster,
+        )
+    }
+
+    /// Writes `data`, a whole number of clusters, to the clusters from
+    /// `first`.
+    pub(crate) fn write_clusters_at(
+        &mut self,
+        layout: &FATLayout,
+        first: u32,
+        data: &[u8],
+    ) -> Result<(), SDError> {
+        if !data.len().is_multiple_of(layout.cluster_size()) {
+            return Err(SDError::InvalidArgument(
+                "cluster data must be a whole number of clusters",
+            ));
+        }
+        check_cluster_run(layout, first, (data.len() / layout.cluster_size()) as u32)?;
+        self.write_blocks(layout.cluster_to_sector(first), data)
+    }
+
+    /// Concatenates the clusters of a chain. Stops at end-of-chain, or once
+    /// `limit` bytes have been read. Runs of adjacent clusters are fetched
+    /// with a single read.
+    pub(crate) fn read_cluster_chain(
+        &mut self,
+        layout: &FATLayout,
+        first_cluster: u32,
+        limit: Option<usize>,
+    ) -> Result<Vec<u8>, SDError> {
+        let wanted = limit.map_or(usize::MAX, |limit| limit.div_ceil(layout.cluster_size()));
+        let clusters: Vec<u32> = self
+            .chain_iter(layout, first_cluster)
+            .take(wanted)
+            .collect::<Result<_, _>>()?;
+
+        let mut data = Vec::new();
+        let mut run_start = first_cluster;
+        let mut run_length = 0;
+        for &cluster in &clusters {
+            if run_length > 0 && cluster != run_start + run_length {
+                self.read_run(layout, run_start, run_length, &mut data)?;
+                run_length = 0;
+            }
+            if run_length == 0 {
+                run_start = cluster;
+            }
+            run_length += 1;
+        }
+        if run_length > 0 {
+            self.read_run(layout, run_start, run_length, &mut data)?;
+        }
+        trace!(
+            first_cluster,
+            clusters = clusters.len(),
+            "read cluster chain"
+        );
+        Ok(data)
+    }
+
+    fn read_run(
+        &mut self,
+        layout: &FATLayout,
+        first_cluster: u32,
+        clusters: u32,
+        data: &mut Vec<u8>,
+    ) -> Result<(), SDError> {
+        let blocks = self.read_clusters_at(layout, first_cluster, clusters)?;
+        data.extend_from_slice(&blocks);
+        Ok(())
+    }
+}
+
+/// Fails unless the `count` clusters from `first` are all in the data
+/// region.
+fn check_cluster_run(layout: &FATLayout, first: u32, count: u32) -> Result<(), SDError> {
+    let last = first.checked_add(count.saturating_sub(1));
+    if !layout.is_data_cluster(first) || !last.is_some_and(|last| layout.is_data_cluster(last)) {
+        return Err(SDError::InvalidCluster(first));
+    }
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::Range;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes compared between progress updates.
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// How two devices differ block by block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BlockDiff {
+    pub block_size: usize,
+    /// Blocks on each side. Only the blocks both have are compared.
+    pub blocks: (u64, u64),
+    /// Runs of differing blocks, in order and coalesced.
+    pub differing: Vec<Range<u64>>,
+}
+
+impl BlockDiff {
+    pub fn differing_blocks(&self) -> u64 {
+        self.differing.iter().map(|run| run.end - run.start).sum()
+    }
+
+    /// Whether both sides are the same size and hold the same data.
+    pub fn is_identical(&self) -> bool {
+        self.blocks.0 == self.blocks.1 && self.differing.is_empty()
+    }
+}
+
+/// How a file or directory present on both sides differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FileChange {
+    /// A file on one side is a directory on the other.
+    Kind,
+    /// The file holds other data, or has another size.
+    Contents,
+    /// The data is the same, but not the attributes or modification time.
+    Metadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChangedFile {
+    pub path: String,
+    pub change: FileChange,
+}
+
+/// How two volumes differ file by file. Paths are compared as FAT
+/// compares names, ignoring case, and reported as the first volume has
+/// them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FileDiff {
+    /// Only on the second volume.
+    pub added: Vec<String>,
+    /// Only on the first volume.
+    pub removed: Vec<String>,
+    pub changed: Vec<ChangedFile>,
+}
+
+impl FileDiff {
+    pub fn is_identical(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Every file and directory of a volume, by upper-cased path.
+fn tree<D: BlockDevice>(
+    controller: &mut SDController<D>,
+) -> Result<BTreeMap<String, (String, DirEntry)>, SDError> {
+    let mut tree = BTreeMap::new();
+    for walked in controller.walk()? {
+        let walked = walked?;
+        tree.insert(walked.path.to_uppercase(), (walked.path, walked.entry));
+    }
+    Ok(tree)
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Compares every block of this device, or of the open partition, with
+    /// the same block of `other`. Both must have the same block size; if
+    /// one is larger, its extra blocks are left out and show only in
+    /// `BlockDiff::blocks`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn diff_blocks<E: BlockDevice, P: ProgressSink>(
+        &mut self,
+        other: &mut SDController<E>,
+        progress: &mut P,
+    ) -> Result<BlockDiff, SDError> {
+        let block_size = self.block_size();
+        if other.block_size() != block_size {
+            return Err(SDError::Unsupported(
+                "comparing devices with different block sizes",
+            ));
+        }
+        let blocks = (self.num_blocks(), other.num_blocks());
+        let common = blocks.0.min(blocks.1);
+        let chunk_blocks = (CHUNK_BYTES / block_size).max(1) as u64;
+        let stopwatch = Stopwatch::start();
+        let mut differing: Vec<Range<u64>> = Vec::new();
+        let mut block = 0;
+        while block < common {
+            let count = chunk_blocks.min(common - block);
+            let ours = self.read_blocks(block_index(block)?, count as u32)?;
+            let theirs = other.read_blocks(block_index(block)?, count as u32)?;
+            let pairs = ours.chunks(block_size).zip(theirs.chunks(block_size));
+            for (index, (a, b)) in pairs.enumerate() {
+                if a == b {
+                    continue;
+                }
+                let lba = block + index as u64;
+                match differing.last_mut() {
+                    Some(run) if run.end == lba => run.end += 1,
+                    _ => differing.push(lba..lba + 1),
+                }
+            }
+            block += count;
+            progress.report(&Progress {
+                phase: Phase::Comparing,
+                bytes_done: block * block_size as u64,
+                bytes_total: common * block_size as u64,
+                elapsed: stopwatch.elapsed(),
+            });
+        }
+        Ok(BlockDiff {
+            block_size,
+            blocks,
+            differing,
+        })
+    }
+
+    /// Compares the files and directories of this volume with those of
+    /// `other`. Files of the same size are read on both sides to compare
+    /// their contents; progress counts the bytes of those files.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn diff_files<E: BlockDevice, P: ProgressSink>(
+        &mut self,
+        other: &mut SDController<E>,
+        progress: &mut P,
+    ) -> Result<FileDiff, SDError> {
+        let ours = tree(self)?;
+        let mut theirs = tree(other)?;
+        let mut diff = FileDiff::default();
+        let mut pairs = Vec::new();
+        for (key, (path, entry)) in ours {
+            match theirs.remove(&key) {
+                Some((_, other_entry)) => pairs.push((path, entry, other_entry)),
+                None => diff.removed.push(path),
+            }
+        }
+        diff.added = theirs.into_values().map(|(path, _)| path).collect();
+
+        let total = pairs
+            .iter()
+            .filter(|(_, a, b)| !a.is_dir() && !b.is_dir() && a.size == b.size)
+            .map(|(_, a, _)| a.size)
+            .sum();
+        let stopwatch = Stopwatch::start();
+        let mut done = 0;
+        for (path, a, b) in pairs {
+            let change = if a.is_dir() != b.is_dir() {
+                Some(FileChange::Kind)
+            } else if a.is_dir() {
+                None
+            } else if a.size != b.size {
+                Some(FileChange::Contents)
+            } else {
+                let mut compared = done;
+                let same = same_contents(self, other, &path, |read| {
+                    compared += read;
+                    progress.report(&Progress {
+                        phase: Phase::Comparing,
+                        bytes_done: compared,
+                        bytes_total: total,
+                        elapsed: stopwatch.elapsed(),
+                    });
+                })?;
+                // Comparison stops at the first difference; count the rest
+                // as done all the same.
+                done += a.size;
+                if compared < done {
+                    progress.report(&Progress {
+                        phase: Phase::Comparing,
+                        bytes_done: done,
+                        bytes_total: total,
+                        elapsed: stopwatch.elapsed(),
+                    });
+                }
+                if !same {
+                    Some(FileChange::Contents)
+                } else if a.attributes != b.attributes
+                    || a.timestamps.modified_at() != b.timestamps.modified_at()
+                {
+                    Some(FileChange::Metadata)
+                } else {
+                    None
+                }
+            };
+            if let Some(change) = change {
+                diff.changed.push(ChangedFile { path, change });
+            }
+        }
+        Ok(diff)
+    }
+}
+
+/// Reads the file at `path` on both volumes a chunk at a time, stopping
+/// at the first difference. `advance` hears how many bytes each chunk was.
+fn same_contents<D: BlockDevice, E: BlockDevice>(
+    ours: &mut SDController<D>,
+    theirs: &mut SDController<E>,
+    path: &str,
+    mut advance: impl FnMut(u64),
+) -> Result<bool, SDError> {
+    let mut a = ours.open_reader(path)?;
+    let mut b = theirs.open_reader(path)?;
+    let mut buffer_a = vec![0u8; CHUNK_BYTES];
+    let mut buffer_b = vec![0u8; CHUNK_BYTES];
+    loop {
+        let read = read_full(&mut a, &mut buffer_a)?;
+        if read_full(&mut b, &mut buffer_b[..read.max(1)])? != read {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+        if buffer_a[..read] != buffer_b[..read] {
+            return Ok(false);
+        }
+        advance(read as u64);
+    }
+}
+
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, SDError> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
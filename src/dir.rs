@@ -0,0 +1,1073 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::attributes::Attributes;
+use crate::block::BlockDevice;
+use crate::codepage::Codepage;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::exfat;
+use crate::fat::FatVariant;
+use crate::layout::FATLayout;
+use crate::lfn::{long_name_entries, LongNameBuilder, LFN_MAX_UNITS};
+
+pub const DIR_ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+pub(crate) const ENTRY_END: u8 = 0x00;
+pub(crate) const ENTRY_DELETED: u8 = 0xE5;
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+/// Characters that names may not contain, besides control characters.
+const INVALID_NAME_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// Device names Windows reserves in every directory, with or without an
+/// extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Raw FAT date/time fields, still in their packed on-disk encoding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FatTimestamps {
+    pub created_tenths: u8,
+    pub created_time: u16,
+    pub created_date: u16,
+    pub accessed_date: u16,
+    pub modified_time: u16,
+    pub modified_date: u16,
+}
+
+impl FatTimestamps {
+    /// All timestamps set to `seconds` since the Unix epoch. FAT has no
+    /// notion of time zones; times are stored as UTC.
+    pub fn from_unix_time(seconds: u64) -> Self {
+        let (date, time, tenths) = encode_fat_datetime(seconds);
+        FatTimestamps {
+            created_tenths: tenths,
+            created_time: time,
+            created_date: date,
+            accessed_date: date,
+            modified_time: time,
+            modified_date: date,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn now() -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        FatTimestamps::from_unix_time(seconds)
+    }
+
+    /// Without `std` there is no clock to read, so new entries are dated at
+    /// the FAT epoch, 1980-01-01.
+    #[cfg(not(feature = "std"))]
+    pub fn now() -> Self {
+        FatTimestamps::from_unix_time(0)
+    }
+
+    /// Timestamps taken from a host file's times.
+    #[cfg(feature = "std")]
+    pub fn from_system_times(
+        created: SystemTime,
+        accessed: SystemTime,
+        modified: SystemTime,
+    ) -> Self {
+        let unix_time = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        };
+        let (created_date, created_time, created_tenths) = encode_fat_datetime(unix_time(created));
+        let (accessed_date, _, _) = encode_fat_datetime(unix_time(accessed));
+        let (modified_date, modified_time, _) = encode_fat_datetime(unix_time(modified));
+        FatTimestamps {
+            created_tenths,
+            created_time,
+            created_date,
+            accessed_date,
+            modified_time,
+            modified_date,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn created(&self) -> SystemTime {
+        let hundredths = self.created_tenths.min(199) as u64;
+        UNIX_EPOCH
+            + Duration::from_secs(decode_fat_datetime(self.created_date, self.created_time))
+            + Duration::from_millis(hundredths * 10)
+    }
+
+    /// The last access date; FAT does not record the time of day.
+    #[cfg(feature = "std")]
+    pub fn accessed(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(decode_fat_datetime(self.accessed_date, 0))
+    }
+
+    #[cfg(feature = "std")]
+    pub fn modified(&self) -> SystemTime {
+        UNIX_EPOCH
+            + Duration::from_secs(decode_fat_datetime(self.modified_date, self.modified_time))
+    }
+
+    /// The creation time, or `None` if it was never set or is not a valid
+    /// date. Unlike `created`, nothing is clamped.
+    pub fn created_at(&self) -> Option<FatDateTime> {
+        FatDateTime::decode(self.created_date, self.created_time, self.created_tenths)
+    }
+
+    /// The last access date, at midnight.
+    pub fn accessed_at(&self) -> Option<FatDateTime> {
+        FatDateTime::decode(self.accessed_date, 0, 0)
+    }
+
+    pub fn modified_at(&self) -> Option<FatDateTime> {
+        FatDateTime::decode(self.modified_date, self.modified_time, 0)
+    }
+}
+
+/// A FAT date and time unpacked into calendar fields. FAT records local
+/// time without a zone; this crate writes UTC and reads times back as UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FatDateTime {
+    /// 1980 to 2107.
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    /// Even on modification times, which have two-second resolution.
+    pub second: u8,
+    /// Only creation times have a finer resolution than seconds, in steps
+    /// of 10ms.
+    pub millisecond: u16,
+}
+
+impl FatDateTime {
+    /// Unpacks on-disk date and time fields with the creation time's 10ms
+    /// increments, which run to 199 to carry the odd second. Returns `None`
+    /// for a zero date, which marks a field that was never set, and for
+    /// fields out of range such as a 30th of February.
+    pub fn decode(date: u16, time: u16, hundredths: u8) -> Option<Self> {
+        let year = 1980 + (date >> 9);
+        let month = ((date >> 5) & 0x0F) as u8;
+        let day = (date & 0x1F) as u8;
+        let hour = (time >> 11) as u8;
+        let minute = ((time >> 5) & 0x3F) as u8;
+        let second = ((time & 0x1F) * 2) as u8 + hundredths / 100;
+        if !(1..=12).contains(&month)
+            || day == 0
+            || day > days_in_month(year, month)
+            || hour > 23
+            || minute > 59
+            || second > 59
+            || hundredths > 199
+        {
+            return None;
+        }
+        Some(FatDateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            millisecond: (hundredths % 100) as u16 * 10,
+        })
+    }
+
+    /// Parses the ISO 8601 form `Display` writes, with or without
+    /// milliseconds. `None` for anything else, and for times FAT cannot
+    /// hold.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (text, millisecond) = match text.split_once('.') {
+            Some((text, millis)) if millis.len() == 3 => (text, millis.parse().ok()?),
+            Some(_) => return None,
+            None => (text, 0),
+        };
+        let bytes = text.as_bytes();
+        if bytes.len() != 19
+            || [4, 7].iter().any(|&at| bytes[at] != b'-')
+            || bytes[10] != b'T'
+            || [13, 16].iter().any(|&at| bytes[at] != b':')
+        {
+            return None;
+        }
+        let field = |range: Range<usize>| -> Option<u16> {
+            let digits = text.get(range)?;
+            if !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            digits.parse().ok()
+        };
+        let time = FatDateTime {
+            year: field(0..4)?,
+            month: field(5..7)? as u8,
+            day: field(8..10)? as u8,
+            hour: field(11..13)? as u8,
+            minute: field(14..16)? as u8,
+            second: field(17..19)? as u8,
+            millisecond,
+        };
+        let valid = (1980..=2107).contains(&time.year)
+            && (1..=12).contains(&time.month)
+            && time.day != 0
+            && time.day <= days_in_month(time.year, time.month)
+            && time.hour <= 23
+            && time.minute <= 59
+            && time.second <= 59
+            && time.millisecond <= 999;
+        valid.then_some(time)
+    }
+
+    /// Seconds since the Unix epoch, ignoring milliseconds.
+    pub fn unix_time(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86_400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH
+            + Duration::from_secs(self.unix_time())
+            + Duration::from_millis(self.millisecond as u64)
+    }
+}
+
+/// ISO 8601, e.g. `2024-02-29T13:05:08`, with milliseconds when there are
+/// any.
+impl fmt::Display for FatDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )?;
+        if self.millisecond != 0 {
+            write!(f, ".{:03}", self.millisecond)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for FatDateTime {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for FatDateTime {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        FatDateTime::parse(&text).ok_or_else(|| serde::de::Error::custom("not a FAT date and time"))
+    }
+}
+
+#[cfg(feature = "time")]
+impl TryFrom<FatDateTime> for time::PrimitiveDateTime {
+    type Error = time::error::ComponentRange;
+
+    fn try_from(value: FatDateTime) -> Result<Self, Self::Error> {
+        let month = time::Month::try_from(value.month)?;
+        let date = time::Date::from_calendar_date(value.year as i32, month, value.day)?;
+        let time =
+            time::Time::from_hms_milli(value.hour, value.minute, value.second, value.millisecond)?;
+        Ok(time::PrimitiveDateTime::new(date, time))
+    }
+}
+
+/// Takes the time as UTC, as `FatDateTime` does.
+#[cfg(feature = "time")]
+impl TryFrom<FatDateTime> for time::OffsetDateTime {
+    type Error = time::error::ComponentRange;
+
+    fn try_from(value: FatDateTime) -> Result<Self, Self::Error> {
+        Ok(time::PrimitiveDateTime::try_from(value)?.assume_utc())
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since the Unix epoch of a proleptic Gregorian date, after Howard
+/// Hinnant's days-from-civil; the inverse of the conversion in
+/// `encode_fat_datetime`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Unpacks FAT date and time fields into a Unix time. Out-of-range fields,
+/// such as the all-zero date of entries that never had one, are clamped.
+#[cfg(feature = "std")]
+fn decode_fat_datetime(date: u16, time: u16) -> u64 {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0x0F).clamp(1, 12) as i64;
+    let day = (date & 0x1F).max(1) as i64;
+
+    let days = days_from_civil(year, month, day);
+
+    let hours = (time >> 11).min(23) as i64;
+    let minutes = ((time >> 5) & 0x3F).min(59) as i64;
+    let seconds = ((time & 0x1F) * 2).min(59) as i64;
+    (days * 86_400 + hours * 3600 + minutes * 60 + seconds) as u64
+}
+
+/// Packs a Unix time into FAT date, time and 10ms-increment fields. Dates
+/// before the FAT epoch (1980-01-01) are clamped to it.
+fn encode_fat_datetime(seconds: u64) -> (u16, u16, u8) {
+    const FAT_EPOCH: u64 = 315_532_800;
+    let seconds = seconds.max(FAT_EPOCH);
+    let days = (seconds / 86_400) as i64;
+    let secs_of_day = seconds % 86_400;
+
+    // Civil-from-days, after Howard Hinnant's algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let year = (year - 1980).clamp(0, 127) as u16;
+    let date = year << 9 | (month as u16) << 5 | day as u16;
+    let hours = (secs_of_day / 3600) as u16;
+    let minutes = (secs_of_day / 60 % 60) as u16;
+    let secs = (secs_of_day % 60) as u16;
+    let time = hours << 11 | minutes << 5 | (secs / 2);
+    (date, time, (secs % 2 * 100) as u8)
+}
+
+/// Where a directory's entries live: the fixed root region of FAT12/16 (or
+/// the root cluster chain of FAT32), or a cluster chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirLocation {
+    Root,
+    Cluster(u32),
+}
+
+impl DirLocation {
+    /// The location a directory entry points at. `..` entries use cluster 0
+    /// to refer to the root.
+    pub fn of(entry: &DirEntry) -> Self {
+        if entry.first_cluster == 0 {
+            DirLocation::Root
+        } else {
+            DirLocation::Cluster(entry.first_cluster)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DirEntry {
+    pub name: String,
+    pub ext: String,
+    /// VFAT long file name, when one precedes the short entry.
+    pub long_name: Option<String>,
+    pub attributes: Attributes,
+    pub size: u64,
+    pub first_cluster: u32,
+    /// The data occupies consecutive clusters and has no FAT chain (exFAT).
+    pub contiguous: bool,
+    pub timestamps: FatTimestamps,
+}
+
+impl DirEntry {
+    /// Parses a short entry, decoding its name with the default code page.
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        DirEntry::decode(raw, Codepage::default())
+    }
+
+    /// Parses a short entry, decoding its name with `codepage`.
+    pub fn decode(raw: &[u8], codepage: Codepage) -> Self {
+        let mut name_bytes = [0u8; 8];
+        name_bytes.copy_from_slice(&raw[0..8]);
+        if name_bytes[0] == ENTRY_KANJI_E5 {
+            name_bytes[0] = ENTRY_DELETED;
+        }
+
+        DirEntry {
+            name: decode_short_name(&name_bytes, codepage),
+            ext: decode_short_name(&raw[8..11], codepage),
+            long_name: None,
+            attributes: Attributes::from_bits(raw[11]),
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]) as u64,
+            first_cluster: (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16
+                | u16::from_le_bytes([raw[26], raw[27]]) as u32,
+            contiguous: false,
+            timestamps: FatTimestamps {
+                created_tenths: raw[13],
+                created_time: u16::from_le_bytes([raw[14], raw[15]]),
+                created_date: u16::from_le_bytes([raw[16], raw[17]]),
+                accessed_date: u16::from_le_bytes([raw[18], raw[19]]),
+                modified_time: u16::from_le_bytes([raw[22], raw[23]]),
+                modified_date: u16::from_le_bytes([raw[24], raw[25]]),
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<[u8; DIR_ENTRY_SIZE], SDError> {
+        let (name, ext) = encode_short_name(&self.short_name())?;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[0..8].copy_from_slice(&name);
+        raw[8..11].copy_from_slice(&ext);
+        raw[11] = self.attributes.bits();
+        raw[13] = self.timestamps.created_tenths;
+        raw[14..16].copy_from_slice(&self.timestamps.created_time.to_le_bytes());
+        raw[16..18].copy_from_slice(&self.timestamps.created_date.to_le_bytes());
+        raw[18..20].copy_from_slice(&self.timestamps.accessed_date.to_le_bytes());
+        set_first_cluster(&mut raw, self.first_cluster);
+        raw[22..24].copy_from_slice(&self.timestamps.modified_time.to_le_bytes());
+        raw[24..26].copy_from_slice(&self.timestamps.modified_date.to_le_bytes());
+        let size = u32::try_from(self.size).map_err(|_| SDError::FileTooLarge(self.size))?;
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        Ok(raw)
+    }
+
+    /// Whether `name` refers to this entry, by long or short name. FAT names
+    /// are case-insensitive.
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.short_name().eq_ignore_ascii_case(name)
+            || self
+                .long_name
+                .as_ref()
+                .is_some_and(|long_name| long_name.to_lowercase() == name.to_lowercase())
+    }
+
+    /// The long name if there is one, otherwise the 8.3 name.
+    pub fn full_name(&self) -> String {
+        match &self.long_name {
+            Some(long_name) => long_name.clone(),
+            None => self.short_name(),
+        }
+    }
+
+    /// The `NAME.EXT` short name.
+    pub fn short_name(&self) -> String {
+        if self.ext.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}.{}", self.name, self.ext)
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    pub fn is_volume_label(&self) -> bool {
+        self.attributes.contains(Attributes::VOLUME_ID)
+            && !self
+                .attributes
+                .contains(Attributes::from_bits(ATTR_LONG_NAME))
+    }
+
+    /// Whether this is the `.` or `..` entry of a subdirectory.
+    pub fn is_dot_entry(&self) -> bool {
+        self.is_dir() && self.ext.is_empty() && (self.name == "." || self.name == "..")
+    }
+}
+
+pub(crate) fn decode_short_name(bytes: &[u8], codepage: Codepage) -> String {
+    let len = bytes
+        .iter()
+        .rposition(|&b| b != b' ')
+        .map_or(0, |last| last + 1);
+    codepage.decode(&bytes[..len])
+}
+
+/// A synthetic entry standing for the root directory, which has no entry of
+/// its own.
+pub fn root_entry(layout: &FATLayout) -> DirEntry {
+    let first_cluster = match layout.variant {
+        FatVariant::Fat12 | FatVariant::Fat16 => 0,
+        FatVariant::Fat32 | FatVariant::ExFat => layout.root_cluster,
+    };
+    DirEntry {
+        name: String::new(),
+        ext: String::new(),
+        long_name: None,
+        attributes: Attributes::DIRECTORY,
+        size: 0,
+        first_cluster,
+        contiguous: false,
+        timestamps: FatTimestamps::default(),
+    }
+}
+
+/// Stores a cluster number in the split high/low fields of a raw entry.
+pub(crate) fn set_first_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+pub(crate) fn is_valid_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c) || c >= 0x80
+}
+
+/// Converts `NAME.EXT` into the space-padded 8.3 fields of a directory
+/// entry, upper-casing it. Names that do not fit 8.3 are rejected.
+pub fn encode_short_name(name: &str) -> Result<([u8; 8], [u8; 3]), SDError> {
+    let invalid = || SDError::InvalidName(name.to_string());
+    let upper = name.to_ascii_uppercase();
+    let (base, ext) = match upper.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (upper.as_str(), ""),
+    };
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !upper.is_ascii()
+        || !base
+            .bytes()
+            .chain(ext.bytes())
+            .all(is_valid_short_name_char)
+    {
+        return Err(invalid());
+    }
+
+    let mut name_field = [b' '; 8];
+    let mut ext_field = [b' '; 3];
+    name_field[..base.len()].copy_from_slice(base.as_bytes());
+    ext_field[..ext.len()].copy_from_slice(ext.as_bytes());
+    if name_field[0] == ENTRY_DELETED {
+        name_field[0] = ENTRY_KANJI_E5;
+    }
+    Ok((name_field, ext_field))
+}
+
+/// Converts a volume label into the 11-byte, space-padded form kept in the
+/// boot sector and the root directory, upper-casing it. Labels follow the
+/// short name rules, except that they may contain spaces but no dot.
+pub fn encode_volume_label(label: &str) -> Result<[u8; 11], SDError> {
+    let upper = label.to_ascii_uppercase();
+    if upper.is_empty()
+        || upper.len() > 11
+        || upper.starts_with(' ')
+        || !upper.is_ascii()
+        || !upper
+            .bytes()
+            .all(|c| c == b' ' || is_valid_short_name_char(c))
+    {
+        return Err(SDError::InvalidName(label.to_string()));
+    }
+    let mut field = [b' '; 11];
+    field[..upper.len()].copy_from_slice(upper.as_bytes());
+    Ok(field)
+}
+
+/// Whether `name` has to be stored as a long name: it does not fit 8.3, or
+/// its case would be lost by the upper-cased short name.
+pub(crate) fn needs_long_name(name: &str) -> bool {
+    encode_short_name(name).is_err() || name != name.to_ascii_uppercase()
+}
+
+/// Checks that a new file or directory may be called `name` on a card
+/// Windows will read too. `.` and `..`, names longer than a long name can
+/// hold, control characters and `"*/:<>?\|` are rejected, as are trailing
+/// dots and spaces, which Windows strips, and device names such as `CON`
+/// or `LPT1.TXT`, which it opens the device for instead.
+pub(crate) fn validate_name(name: &str) -> Result<(), SDError> {
+    let invalid = || SDError::InvalidName(name.to_string());
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > LFN_MAX_UNITS
+        || name
+            .chars()
+            .any(|c| c < ' ' || INVALID_NAME_CHARS.contains(&c))
+        || name.ends_with(['.', ' '])
+    {
+        return Err(invalid());
+    }
+    let device = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(device))
+    {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// A 16-bit hash of a long name, mixed into its short name once the first
+/// four numeric tails are taken.
+fn short_name_hash(long_name: &str) -> u16 {
+    let hash = long_name.encode_utf16().fold(0u32, |hash, unit| {
+        hash.wrapping_mul(0x25).wrapping_add(unit as u32)
+    });
+    (hash ^ (hash >> 16)) as u16
+}
+
+/// Derives a unique `NAME.EXT` short name for `long_name` the way Windows
+/// does: upper-cased, with spaces and leading dots dropped, other invalid
+/// characters replaced by `_`, cut down to 8.3, and given a `~N` tail when
+/// anything was lost or the plain form is `taken`. Like Windows, only
+/// `~1` to `~4` keep six characters of the base; after that the base is
+/// cut to two and a hash of the long name is added, so that a directory
+/// of similar names does not fill up one long run of tails. The same long
+/// name always gets the same short name in the same directory.
+pub(crate) fn generate_short_name(
+    long_name: &str,
+    taken: impl Fn(&str) -> bool,
+) -> Result<String, SDError> {
+    let mut lossy = false;
+    let mut convert = |part: &str| -> String {
+        part.chars()
+            .filter_map(|c| {
+                let upper = c.to_ascii_uppercase();
+                if upper.is_ascii() && is_valid_short_name_char(upper as u8) {
+                    return Some(upper);
+                }
+                lossy = true;
+                (c != ' ' && c != '.').then_some('_')
+            })
+            .collect()
+    };
+    let trimmed = long_name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) => (convert(base), convert(ext)),
+        None => (convert(trimmed), String::new()),
+    };
+    if base.is_empty() {
+        return Err(SDError::InvalidName(long_name.to_string()));
+    }
+    lossy |= base.len() > 8 || ext.len() > 3 || trimmed.len() != long_name.len();
+    let ext: String = ext.chars().take(3).collect();
+    let with_ext = |base: &str| {
+        if ext.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}.{}", base, ext)
+        }
+    };
+
+    if !lossy {
+        let candidate = with_ext(&base);
+        if !taken(&candidate) {
+            return Ok(candidate);
+        }
+    }
+    let numbered = |base: &str, number: u32| {
+        let tail = format!("~{}", number);
+        let kept: String = base.chars().take(8 - tail.len()).collect();
+        with_ext(&format!("{}{}", kept, tail))
+    };
+    let hashed = format!(
+        "{}{:04X}",
+        base.chars().take(2).collect::<String>(),
+        short_name_hash(long_name)
+    );
+    (1..=4)
+        .map(|number| numbered(&base, number))
+        .chain((1..1_000_000).map(|number| numbered(&hashed, number)))
+        .find(|candidate| !taken(candidate))
+        .ok_or(SDError::DirectoryFull)
+}
+
+/// Splits a slash-separated path into its components, ignoring empty ones.
+pub fn split_path(path: &str) -> Vec<&str> {
+    path.split(['/', '\\'])
+        .filter(|component| !component.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DirFormat {
+    Fat,
+    ExFat,
+}
+
+/// Iterates over the live entries of a raw directory region, skipping deleted
+/// slots, long-name fragments and the volume label.
+pub struct DirIter {
+    data: Vec<u8>,
+    position: usize,
+    format: DirFormat,
+    long_name: LongNameBuilder,
+    skip_dot_entries: bool,
+    codepage: Codepage,
+}
+
+impl DirIter {
+    pub fn new(data: Vec<u8>) -> Self {
+        DirIter {
+            data,
+            position: 0,
+            format: DirFormat::Fat,
+            long_name: LongNameBuilder::default(),
+            skip_dot_entries: false,
+            codepage: Codepage::default(),
+        }
+    }
+
+    pub fn exfat(data: Vec<u8>) -> Self {
+        DirIter {
+            data,
+            position: 0,
+            format: DirFormat::ExFat,
+            long_name: LongNameBuilder::default(),
+            skip_dot_entries: false,
+            codepage: Codepage::default(),
+        }
+    }
+
+    /// Decodes short names with `codepage` rather than the default.
+    pub fn with_codepage(mut self, codepage: Codepage) -> Self {
+        self.codepage = codepage;
+        self
+    }
+
+    /// Leaves out the `.` and `..` entries of subdirectories.
+    pub fn without_dot_entries(mut self) -> Self {
+        self.skip_dot_entries = true;
+        self
+    }
+
+    /// Like `next`, but also returns the byte range of the entry's slots —
+    /// its long-name entries followed by the short entry — within the
+    /// directory region.
+    pub(crate) fn next_located(&mut self) -> Option<(Range<usize>, DirEntry)> {
+        if self.format == DirFormat::ExFat {
+            return self.next_exfat();
+        }
+        while self.position + DIR_ENTRY_SIZE <= self.data.len() {
+            let offset = self.position;
+            let raw = &self.data[offset..offset + DIR_ENTRY_SIZE];
+            self.position += DIR_ENTRY_SIZE;
+
+            match raw[0] {
+                ENTRY_END => {
+                    self.position = self.data.len();
+                    return None;
+                }
+                ENTRY_DELETED => {
+                    self.long_name.reset();
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                self.long_name.push(offset, raw);
+                continue;
+            }
+
+            let start = self.long_name.start.take();
+            let mut entry = DirEntry::decode(raw, self.codepage);
+            entry.long_name = self.long_name.finish(&raw[0..11]);
+            if entry.is_volume_label() || (self.skip_dot_entries && entry.is_dot_entry()) {
+                continue;
+            }
+            let start = match entry.long_name {
+                Some(_) => start.unwrap_or(offset),
+                None => offset,
+            };
+            return Some((start..offset + DIR_ENTRY_SIZE, entry));
+        }
+        None
+    }
+
+    fn next_exfat(&mut self) -> Option<(Range<usize>, DirEntry)> {
+        while self.position + DIR_ENTRY_SIZE <= self.data.len() {
+            let offset = self.position;
+            if self.data[offset] == ENTRY_END {
+                self.position = self.data.len();
+                return None;
+            }
+            let (consumed, entry) = exfat::parse_entry_set(&self.data[offset..]);
+            self.position += consumed;
+            if let Some(entry) = entry {
+                return Some((offset..offset + consumed, entry));
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for DirIter {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        self.next_located().map(|(_, entry)| entry)
+    }
+}
+
+/// A directory entry together with the sector and byte offset of its slot.
+#[derive(Debug, Clone)]
+pub(crate) struct LocatedEntry {
+    pub entry: DirEntry,
+    pub sector: u32,
+    pub offset: usize,
+    /// Sector and offset of each long-name slot belonging to the entry.
+    pub long_name_slots: Vec<(u32, usize)>,
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// The sectors making up a FAT12/16/32 directory, in order.
+    pub(crate) fn dir_sectors(
+        &mut self,
+        layout: &FATLayout,
+        location: DirLocation,
+    ) -> Result<Vec<u32>, SDError> {
+        let first_cluster = match location {
+            DirLocation::Root if layout.variant == FatVariant::Fat32 => layout.root_cluster,
+            DirLocation::Root => return Ok((layout.root_dir_start..layout.data_start).collect()),
+            DirLocation::Cluster(cluster) => cluster,
+        };
+        let mut sectors = Vec::new();
+        for cluster in self.chain_clusters(layout, first_cluster)? {
+            let first_sector = layout.cluster_to_sector(cluster);
+            sectors.extend(first_sector..first_sector + layout.sectors_per_cluster);
+        }
+        Ok(sectors)
+    }
+
+    pub(crate) fn read_dir_region(
+        &mut self,
+        layout: &FATLayout,
+        location: DirLocation,
+    ) -> Result<(Vec<u32>, Vec<u8>), SDError> {
+        let sectors = self.dir_sectors(layout, location)?;
+        let mut data = Vec::with_capacity(sectors.len() * layout.bytes_per_sector as usize);
+        for &sector in &sectors {
+            data.extend_from_slice(&self.read_block(sector)?);
+        }
+        Ok((sectors, data))
+    }
+
+    pub(crate) fn find_entry(
+        &mut self,
+        layout: &FATLayout,
+        location: DirLocation,
+        name: &str,
+    ) -> Result<Option<LocatedEntry>, SDError> {
+        let (sectors, data) = self.read_dir_region(layout, location)?;
+        let bytes_per_sector = layout.bytes_per_sector as usize;
+        let mut entries = DirIter::new(data).with_codepage(self.codepage());
+
+        while let Some((slots, entry)) = entries.next_located() {
+            if entry.matches_name(name) {
+                let offset = slots.end - DIR_ENTRY_SIZE;
+                let long_name_slots = (slots.start..offset)
+                    .step_by(DIR_ENTRY_SIZE)
+                    .map(|slot| (sectors[slot / bytes_per_sector], slot % bytes_per_sector))
+                    .collect();
+                return Ok(Some(LocatedEntry {
+                    entry,
+                    sector: sectors[offset / bytes_per_sector],
+                    offset: offset % bytes_per_sector,
+                    long_name_slots,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Resolves a path to its entry and the directory that contains it.
+    pub(crate) fn locate(
+        &mut self,
+        layout: &FATLayout,
+        path: &str,
+    ) -> Result<(DirLocation, LocatedEntry), SDError> {
+        let components = split_path(path);
+        let (name, parents) = components
+            .split_last()
+            .ok_or(SDError::InvalidName(path.to_string()))?;
+        let parent = self.resolve_dir(layout, parents)?;
+        let found = self
+            .find_entry(layout, parent, name)?
+            .ok_or(SDError::NotFound(path.to_string()))?;
+        Ok((parent, found))
+    }
+
+    /// Walks `components` from the root and returns the directory they name.
+    pub(crate) fn resolve_dir(
+        &mut self,
+        layout: &FATLayout,
+        components: &[&str],
+    ) -> Result<DirLocation, SDError> {
+        let mut location = DirLocation::Root;
+        for (depth, component) in components.iter().enumerate() {
+            let path = components[..=depth].join("/");
+            let found = self
+                .find_entry(layout, location, component)?
+                .ok_or(SDError::NotFound(path.clone()))?;
+            if !found.entry.is_dir() {
+                return Err(SDError::NotADirectory(path));
+            }
+            location = self.normalize_location(layout, DirLocation::of(&found.entry));
+        }
+        Ok(location)
+    }
+
+    /// Some implementations point `..` at the FAT32 root cluster instead of
+    /// using 0; treat both as the root.
+    pub(crate) fn normalize_location(
+        &self,
+        layout: &FATLayout,
+        location: DirLocation,
+    ) -> DirLocation {
+        match location {
+            DirLocation::Cluster(cluster)
+                if layout.variant == FatVariant::Fat32 && cluster == layout.root_cluster =>
+            {
+                DirLocation::Root
+            }
+            location => location,
+        }
+    }
+
+    /// Stores a raw entry in the first free slot of a directory, growing
+    /// cluster-based directories by one zeroed cluster when they are full.
+    pub(crate) fn insert_entry(
+        &mut self,
+        layout: &FATLayout,
+        location: DirLocation,
+        raw: &[u8; DIR_ENTRY_SIZE],
+    ) -> Result<(u32, usize), SDError> {
+        self.insert_entries(layout, location, core::slice::from_ref(raw))
+    }
+
+    /// Stores `entry` with its long name, if it has one, as LFN slots
+    /// followed by the short entry. Returns the short entry's slot.
+    pub(crate) fn insert_dir_entry(
+        &mut self,
+        layout: &FATLayout,
+        location: DirLocation,
+        entry: &DirEntry,
+    ) -> Result<(u32, usize), SDError> {
+        let raw = entry.to_bytes()?;
+        let mut slots = match &entry.long_name {
+            Some(long_name) => long_name_entries(long_name, &raw[0..11])?,
+            None => Vec::new(),
+        };
+        slots.push(raw);
+        self.insert_entries(layout, location, &slots)
+    }
+
+    /// Stores raw entries in the first run of consecutive free slots long
+    /// enough to hold them all, growing cluster-based directories as
+    /// needed. Returns the slot of the last entry.
+    fn insert_entries(
+        &mut self,
+        layout: &FATLayout,
+        location: DirLocation,
+        slots: &[[u8; DIR_ENTRY_SIZE]],
+    ) -> Result<(u32, usize), SDError> {
+        let bytes_per_sector = layout.bytes_per_sector as usize;
+        loop {
+            let (sectors, data) = self.read_dir_region(layout, location)?;
+            let mut run = 0;
+            let first_free = data.chunks(DIR_ENTRY_SIZE).position(|slot| {
+                if slot[0] == ENTRY_END || slot[0] == ENTRY_DELETED {
+                    run += 1;
+                } else {
+                    run = 0;
+                }
+                run == slots.len()
+            });
+            let Some(last) = first_free else {
+                // A run at the end of the directory carries on into the
+                // new cluster.
+                self.grow_dir(layout, location)?;
+                continue;
+            };
+
+            let mut position = (0, 0);
+            for (index, raw) in slots.iter().enumerate() {
+                let offset = (last + 1 - slots.len() + index) * DIR_ENTRY_SIZE;
+                position = (
+                    sectors[offset / bytes_per_sector],
+                    offset % bytes_per_sector,
+                );
+                self.write_entry_at(position.0, position.1, raw)?;
+            }
+            return Ok(position);
+        }
+    }
+
+    /// Overwrites the start of an existing entry's slot with `raw`.
+    pub(crate) fn write_entry_at(
+        &mut self,
+        sector: u32,
+        offset: usize,
+        raw: &[u8],
+    ) -> Result<(), SDError> {
+        let mut block = self.read_block(sector)?;
+        block[offset..offset + raw.len()].copy_from_slice(raw);
+        self.write_block(sector, &block)
+    }
+
+    /// Marks an entry and its long-name slots as deleted.
+    pub(crate) fn mark_deleted(&mut self, entry: &LocatedEntry) -> Result<(), SDError> {
+        for &(sector, offset) in &entry.long_name_slots {
+            self.write_entry_at(sector, offset, &[ENTRY_DELETED])?;
+        }
+        self.write_entry_at(entry.sector, entry.offset, &[ENTRY_DELETED])
+    }
+
+    /// Appends a zeroed cluster to a directory and returns its first sector.
+    fn grow_dir(&mut self, layout: &FATLayout, location: DirLocation) -> Result<u32, SDError> {
+        let first_cluster = match location {
+            DirLocation::Root if layout.variant == FatVariant::Fat32 => layout.root_cluster,
+            DirLocation::Root => return Err(SDError::DirectoryFull),
+            DirLocation::Cluster(cluster) => cluster,
+        };
+        let last_cluster = *self
+            .chain_clusters(layout, first_cluster)?
+            .last()
+            .ok_or(SDError::InvalidCluster(first_cluster))?;
+
+        let mut table = self.load_fat(layout)?;
+        let cluster = self.allocate_chain(&mut table, 1)?[0];
+        self.zero_cluster(layout, cluster)?;
+        table.set_entry(last_cluster, cluster);
+        self.store_fat(layout, &mut table)?;
+        Ok(layout.cluster_to_sector(cluster))
+    }
+
+    pub(crate) fn zero_cluster(&mut self, layout: &FATLayout, cluster: u32) -> Result<(), SDError> {
+        self.write_clusters_at(layout, cluster, &vec![0u8; layout.cluster_size()])
+    }
+}
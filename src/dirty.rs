@@ -0,0 +1,152 @@
+use alloc::vec;
+
+use crate::block::BlockDevice;
+use crate::boot::{BootSectorCopy, BACKUP_BOOT_SECTOR};
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::fat::{FATBootSector, FatVariant};
+use crate::layout::FATLayout;
+use crate::log::debug;
+
+/// Bit 0 of the byte after the drive number in the extended boot record,
+/// which Linux sets while a volume is mounted read-write and Windows sets
+/// to ask for a check on the next boot.
+const BOOT_SECTOR_DIRTY: u8 = 0x01;
+
+/// What the volume records about how it was last left: the clean-shutdown
+/// and hard-error bits Windows keeps in FAT entry 1, and the dirty bit
+/// Linux keeps in the boot sector. A card pulled out while mounted has one
+/// of them showing, and its FAT and directories may be half written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DirtyFlags {
+    /// FAT entry 1 says the volume was unmounted cleanly.
+    pub clean_shutdown: bool,
+    /// FAT entry 1 says a driver hit a read or write error on the volume.
+    pub hard_error: bool,
+    /// The boot sector's dirty bit is set.
+    pub boot_sector_dirty: bool,
+}
+
+impl DirtyFlags {
+    /// Whether any flag says the metadata may not be trustworthy.
+    pub fn is_dirty(&self) -> bool {
+        !self.clean_shutdown || self.hard_error || self.boot_sector_dirty
+    }
+}
+
+/// The clean-shutdown and no-hard-error bits of FAT entry 1, which are set
+/// on a healthy volume.
+fn fat_flag_bits(variant: FatVariant) -> Option<(u32, u32)> {
+    match variant {
+        FatVariant::Fat16 => Some((0x8000, 0x4000)),
+        FatVariant::Fat32 => Some((0x0800_0000, 0x0400_0000)),
+        FatVariant::Fat12 | FatVariant::ExFat => None,
+    }
+}
+
+/// Offset of the boot sector's dirty byte.
+fn boot_flag_offset(variant: FatVariant) -> usize {
+    if variant == FatVariant::Fat32 {
+        65
+    } else {
+        37
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Reads the dirty flags of a FAT16 or FAT32 volume; FAT12 and exFAT
+    /// volumes give `None`, as FAT12 has no room for them and exFAT keeps
+    /// its own.
+    pub fn dirty_flags(&mut self) -> Result<Option<DirtyFlags>, SDError> {
+        let layout = self.layout()?;
+        let Some((clean, no_error)) = fat_flag_bits(layout.variant) else {
+            return Ok(None);
+        };
+        let entry = self.fat_value(&layout, 1)?;
+        let boot = self.read_block(self.boot_sector_block())?;
+        Ok(Some(DirtyFlags {
+            clean_shutdown: entry & clean != 0,
+            hard_error: entry & no_error == 0,
+            boot_sector_dirty: boot[boot_flag_offset(layout.variant)] & BOOT_SECTOR_DIRTY != 0,
+        }))
+    }
+
+    /// Marks the volume dirty, as a driver does when it mounts it, or
+    /// clean, as it does when it unmounts it and as `repair` does once the
+    /// volume is consistent again. Marking it clean also clears the hard
+    /// error bit. Every FAT copy is updated, and on FAT32 the backup boot
+    /// sector.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn set_dirty(&mut self, dirty: bool) -> Result<(), SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let layout = self.layout()?;
+        let Some((clean, no_error)) = fat_flag_bits(layout.variant) else {
+            return Err(SDError::Unsupported(
+                "only FAT16 and FAT32 volumes have dirty flags",
+            ));
+        };
+        self.update_fat_flags(&layout, |entry| {
+            if dirty {
+                entry & !clean
+            } else {
+                entry | clean | no_error
+            }
+        })?;
+
+        let boot_sector = self.read_boot_sector()?;
+        let offset = boot_flag_offset(layout.variant);
+        let mut sectors = vec![self.boot_sector_block()];
+        if layout.variant == FatVariant::Fat32 && boot_sector.backup_boot_sector != 0 {
+            sectors.push(boot_sector.backup_boot_sector as u32);
+        }
+        sectors.dedup();
+        for sector in sectors {
+            let mut data = self.read_block(sector)?;
+            if FATBootSector::parse(&data).is_err() {
+                continue;
+            }
+            if dirty {
+                data[offset] |= BOOT_SECTOR_DIRTY;
+            } else {
+                data[offset] &= !BOOT_SECTOR_DIRTY;
+            }
+            self.write_block(sector, &data)?;
+        }
+        self.flush()?;
+        debug!(dirty, "updated dirty flags");
+        Ok(())
+    }
+
+    /// Rewrites FAT entry 1 in every copy. It lies in the first sector of
+    /// each FAT whatever the variant.
+    fn update_fat_flags(
+        &mut self,
+        layout: &FATLayout,
+        update: impl Fn(u32) -> u32,
+    ) -> Result<(), SDError> {
+        let offset = layout.variant.entry_offset(1) as usize;
+        let width = layout.variant.entry_bytes();
+        for copy in 0..layout.number_of_fats {
+            let sector = layout.fat_start + copy * layout.fat_size;
+            let mut data = self.read_block(sector)?;
+            let slot = &mut data[offset..offset + width];
+            let mut bytes = [0u8; 4];
+            bytes[..width].copy_from_slice(slot);
+            let value = update(u32::from_le_bytes(bytes));
+            slot.copy_from_slice(&value.to_le_bytes()[..width]);
+            self.write_block(sector, &data)?;
+        }
+        Ok(())
+    }
+
+    /// The sector the boot sector in use was read from.
+    fn boot_sector_block(&self) -> u32 {
+        match self.boot_sector_copy() {
+            BootSectorCopy::Primary => 0,
+            BootSectorCopy::Backup => BACKUP_BOOT_SECTOR,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::SDError;
+use crate::raw::{device_name, is_partition_of, mounted_volumes};
+
+/// A whole-disk block device found by `discover`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Path to pass to `SDController::new`.
+    pub path: PathBuf,
+    /// Capacity in bytes.
+    pub size: u64,
+    pub model: String,
+    pub removable: bool,
+    /// Whether the device looks like an SD card or an SD card reader.
+    pub sd_like: bool,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+fn looks_like_sd(text: &str) -> bool {
+    let text = text.to_ascii_uppercase();
+    ["SD", "MMC", "CARD", "READER"].iter().any(|hint| {
+        text.split(|c: char| !c.is_ascii_alphanumeric())
+            .any(|word| word.starts_with(hint))
+    })
+}
+
+/// Lists the whole-disk block devices of the machine. Virtual devices such
+/// as loop and RAM disks are left out.
+pub fn discover() -> Result<Vec<DeviceInfo>, SDError> {
+    platform::discover()
+}
+
+/// Whether `path` is, or is a partition of, a disk that should not be
+/// overwritten by accident: one holding the root filesystem, or a fixed
+/// disk that does not look like an SD card. Image files never count.
+pub fn is_system_disk(path: &Path) -> Result<bool, SDError> {
+    let root = Path::new("/");
+    if mounted_volumes(path)?
+        .iter()
+        .any(|mount| mount.target == root)
+    {
+        return Ok(true);
+    }
+    let mut fixed = discover()?
+        .into_iter()
+        .filter(|device| !device.removable && !device.sd_like);
+    let is_system = match device_name(path) {
+        Some(name) => fixed
+            .filter_map(|device| device_name(&device.path))
+            .any(|base| is_partition_of(&base, &name)),
+        // Windows device paths such as \\.\PhysicalDrive0.
+        None => {
+            let path = path.to_string_lossy();
+            fixed.any(|device| device.path.to_string_lossy().eq_ignore_ascii_case(&path))
+        }
+    };
+    Ok(is_system)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{looks_like_sd, DeviceInfo};
+    use crate::error::SDError;
+
+    const VIRTUAL_PREFIXES: [&str; 6] = ["loop", "ram", "zram", "dm-", "md", "nbd"];
+
+    pub fn discover() -> Result<Vec<DeviceInfo>, SDError> {
+        discover_sysfs(Path::new("/sys/block"))
+    }
+
+    fn read_attribute(path: &Path) -> Option<String> {
+        fs::read_to_string(path)
+            .ok()
+            .map(|value| value.trim().to_string())
+    }
+
+    /// Walks `/sys/block`. Sizes there are counted in 512-byte units
+    /// regardless of the device's logical block size.
+    fn discover_sysfs(root: &Path) -> Result<Vec<DeviceInfo>, SDError> {
+        let mut devices = Vec::new();
+        for entry in fs::read_dir(root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if VIRTUAL_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+            {
+                continue;
+            }
+            let dir = entry.path();
+            let sectors: u64 = read_attribute(&dir.join("size"))
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0);
+            let removable = read_attribute(&dir.join("removable")).as_deref() == Some("1");
+
+            // SCSI and USB disks expose vendor/model, MMC devices a card name.
+            let model = [
+                read_attribute(&dir.join("device/vendor")),
+                read_attribute(&dir.join("device/model")),
+                read_attribute(&dir.join("device/name")),
+            ]
+            .into_iter()
+            .flatten()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+            // Soldered eMMC also shows up as mmcblkN, but with type "MMC".
+            let sd_card = match read_attribute(&dir.join("device/type")) {
+                Some(card_type) => card_type == "SD",
+                None => name.starts_with("mmcblk"),
+            };
+
+            devices.push(DeviceInfo {
+                path: PathBuf::from("/dev").join(&name),
+                size: sectors * 512,
+                sd_like: sd_card || (removable && looks_like_sd(&model)),
+                model,
+                removable: removable || sd_card,
+            });
+        }
+        devices.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(devices)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use super::{looks_like_sd, DeviceInfo};
+    use crate::error::SDError;
+
+    fn diskutil(args: &[&str]) -> Result<String, SDError> {
+        let output = Command::new("diskutil").args(args).output()?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Uses `diskutil` rather than IOKit directly. Raw `/dev/rdiskN` paths
+    /// are reported because they bypass the buffer cache and are much faster.
+    pub fn discover() -> Result<Vec<DeviceInfo>, SDError> {
+        let list = diskutil(&["list", "physical"])?;
+        let mut devices = Vec::new();
+        for line in list.lines() {
+            let Some(disk) = line
+                .strip_prefix("/dev/")
+                .and_then(|rest| rest.split_whitespace().next())
+            else {
+                continue;
+            };
+
+            let info = diskutil(&["info", disk])?;
+            let field = |name: &str| {
+                info.lines()
+                    .filter_map(|line| line.trim().split_once(':'))
+                    .find(|(key, _)| key.trim() == name)
+                    .map(|(_, value)| value.trim().to_string())
+                    .unwrap_or_default()
+            };
+            // "31.9 GB (31914983424 Bytes) (exactly 62333952 512-Byte-Units)"
+            let size = field("Disk Size")
+                .split('(')
+                .nth(1)
+                .and_then(|bytes| bytes.split_whitespace().next())
+                .and_then(|bytes| bytes.parse().ok())
+                .unwrap_or(0);
+            let model = field("Device / Media Name");
+            let removable = field("Removable Media") == "Removable" || field("Ejectable") == "Yes";
+            let sd_like =
+                field("Protocol") == "Secure Digital" || (removable && looks_like_sd(&model));
+
+            devices.push(DeviceInfo {
+                path: PathBuf::from(format!("/dev/r{}", disk)),
+                size,
+                model,
+                removable,
+                sd_like,
+            });
+        }
+        Ok(devices)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use super::{looks_like_sd, DeviceInfo};
+    use crate::error::SDError;
+
+    const QUERY: &str = "Get-CimInstance Win32_DiskDrive | \
+        Select-Object DeviceID,Model,Size,MediaType,InterfaceType | \
+        ConvertTo-Csv -NoTypeInformation";
+
+    /// Splits one line of PowerShell's CSV output, where every field is
+    /// double-quoted.
+    fn csv_fields(line: &str) -> Vec<String> {
+        line.trim()
+            .trim_matches('"')
+            .split("\",\"")
+            .map(|field| field.replace("\"\"", "\""))
+            .collect()
+    }
+
+    /// Queries the disk drives through CIM, which sits on top of the same
+    /// SetupAPI device information without needing FFI bindings.
+    pub fn discover() -> Result<Vec<DeviceInfo>, SDError> {
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", QUERY])
+            .output()?;
+        let text = String::from_utf8_lossy(&output.stdout);
+
+        let mut devices = Vec::new();
+        for line in text.lines().skip(1).filter(|line| !line.trim().is_empty()) {
+            let fields = csv_fields(line);
+            let [device_id, model, size, media_type, interface] = &fields[..] else {
+                continue;
+            };
+            let removable = media_type.starts_with("Removable");
+            devices.push(DeviceInfo {
+                path: PathBuf::from(device_id),
+                size: size.parse().unwrap_or(0),
+                sd_like: interface == "SD" || (removable && looks_like_sd(model)),
+                model: model.clone(),
+                removable,
+            });
+        }
+        Ok(devices)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use super::DeviceInfo;
+    use crate::error::SDError;
+
+    pub fn discover() -> Result<Vec<DeviceInfo>, SDError> {
+        Err(SDError::Unsupported("device discovery on this platform"))
+    }
+}
@@ -0,0 +1,429 @@
+//! Dry runs: writes recorded, and described in filesystem terms, instead
+//! of made.
+
+use std::fmt;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::diff::BlockDiff;
+use crate::error::SDError;
+use crate::exfat::{self, ExFatBootSector};
+use crate::fat::{FATBootSector, FatEntry, FatVariant};
+use crate::layout::FATLayout;
+use crate::log::debug;
+use crate::overlay::OverlayDevice;
+use crate::partition::{block_index, DiskLayout};
+
+/// Changes listed for one write before the rest are only counted.
+const MAX_CHANGES: usize = 16;
+
+/// Blocks compared at a time, so that discarding a whole card does not
+/// read it into memory at once.
+const CHUNK_BLOCKS: u32 = 2048;
+
+/// Blocks at the start of the device that may hold a partition table: the
+/// MBR, and the GPT header and entries behind it.
+const PARTITION_TABLE_BLOCKS: u64 = 34;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum WriteKind {
+    Write,
+    Discard,
+}
+
+/// A write a dry run held back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PlannedWrite {
+    pub kind: WriteKind,
+    /// First block, counted from the start of the device.
+    pub start: u64,
+    pub count: u64,
+    /// What the write would change, such as "update FAT entry 0x123 ->
+    /// EOC". Empty when the blocks already hold what would be written.
+    pub changes: Vec<String>,
+}
+
+impl fmt::Display for PlannedWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = match self.kind {
+            WriteKind::Write => "write",
+            WriteKind::Discard => "discard",
+        };
+        if self.count == 1 {
+            write!(f, "{verb} block {}", self.start)?;
+        } else {
+            write!(f, "{verb} {} blocks at {}", self.count, self.start)?;
+        }
+        if self.changes.is_empty() {
+            return write!(f, ": no change");
+        }
+        write!(f, ": {}", self.changes.join("; "))
+    }
+}
+
+type WriteCallback = Box<dyn FnMut(&PlannedWrite) + Send>;
+
+/// A FAT or exFAT volume found on the device, for naming what writes hit.
+struct Volume {
+    start: u64,
+    end: u64,
+    layout: FATLayout,
+    fs_info: Option<u64>,
+    backup_boot: Option<u64>,
+}
+
+/// Records every write and discard instead of passing it on, so that a
+/// command can be run against a card without changing it. Reads see the
+/// recorded writes, as an [`OverlayDevice`] kept in memory does, so that
+/// multi-step operations carry through as they would for real.
+///
+/// Each write is compared with what the blocks held and described by where
+/// it lands on the volumes the device holds: boot sectors, FAT entries,
+/// root directory entries or data clusters.
+pub struct DryRunDevice<D: BlockDevice> {
+    view: OverlayDevice<D>,
+    writes: Vec<PlannedWrite>,
+    /// Found again after a write to a partition table or boot sector.
+    volumes: Option<Vec<Volume>>,
+    on_write: Option<WriteCallback>,
+}
+
+impl<D: BlockDevice> DryRunDevice<D> {
+    pub fn new(base: D) -> Self {
+        DryRunDevice {
+            view: OverlayDevice::new(base),
+            writes: Vec::new(),
+            volumes: None,
+            on_write: None,
+        }
+    }
+
+    /// Calls `callback` with every write as it is recorded, for logging
+    /// them as a command goes.
+    pub fn on_write<F: FnMut(&PlannedWrite) + Send + 'static>(mut self, callback: F) -> Self {
+        self.on_write = Some(Box::new(callback));
+        self
+    }
+
+    /// The writes recorded so far, in the order they were made.
+    pub fn writes(&self) -> &[PlannedWrite] {
+        &self.writes
+    }
+
+    /// The blocks the recorded writes would change, against the base.
+    pub fn diff(&mut self) -> Result<BlockDiff, SDError> {
+        self.view.diff()
+    }
+
+    /// The base device, untouched.
+    pub fn into_base(self) -> D {
+        self.view.into_base()
+    }
+
+    /// Records a write of `data`, or with `None` a discard, of `count`
+    /// blocks from `start`.
+    fn record(
+        &mut self,
+        kind: WriteKind,
+        start: u32,
+        count: u32,
+        data: Option<&[u8]>,
+    ) -> Result<(), SDError> {
+        let block_size = self.block_size();
+        let mut changes = Vec::new();
+        let mut old = Vec::new();
+        let zeros = vec![0u8; count.min(CHUNK_BLOCKS) as usize * block_size];
+        for chunk in (0..count).step_by(CHUNK_BLOCKS as usize) {
+            let blocks = (count - chunk).min(CHUNK_BLOCKS) as usize;
+            let new = match data {
+                Some(data) => &data[chunk as usize * block_size..][..blocks * block_size],
+                None => &zeros[..blocks * block_size],
+            };
+            old.resize(new.len(), 0);
+            self.view.read_blocks(start + chunk, &mut old)?;
+            self.describe((start + chunk) as u64, &old, new, &mut changes);
+        }
+        if changes.len() > MAX_CHANGES {
+            let more = changes.len() - MAX_CHANGES;
+            changes.truncate(MAX_CHANGES);
+            changes.push(format!("and {more} more"));
+        }
+        let range = start as u64..start as u64 + count as u64;
+        let touches_boot_sector = self
+            .volumes
+            .as_ref()
+            .is_some_and(|volumes| volumes.iter().any(|volume| range.contains(&volume.start)));
+        if touches_boot_sector || range.start < PARTITION_TABLE_BLOCKS {
+            self.volumes = None;
+        }
+        let write = PlannedWrite {
+            kind,
+            start: range.start,
+            count: count as u64,
+            changes,
+        };
+        debug!(block = start, count, "dry run: {}", write);
+        if let Some(callback) = &mut self.on_write {
+            callback(&write);
+        }
+        self.writes.push(write);
+        Ok(())
+    }
+
+    /// Adds what writing `new` over `old` from block `start` changes.
+    fn describe(&mut self, start: u64, old: &[u8], new: &[u8], changes: &mut Vec<String>) {
+        let block_size = self.block_size();
+        if self.volumes.is_none() {
+            let volumes = find_volumes(&mut self.view);
+            debug!(volumes = volumes.len(), "dry run: found the volumes");
+            self.volumes = Some(volumes);
+        }
+        let volumes = self.volumes.as_deref().unwrap_or_default();
+        // Consecutive blocks of the same run of clusters make one change.
+        let mut clusters: Option<(u32, u32)> = None;
+        let mut outside = 0;
+        let blocks = old.chunks(block_size).zip(new.chunks(block_size));
+        for (i, (old, new)) in blocks.enumerate() {
+            if old == new {
+                continue;
+            }
+            let block = start + i as u64;
+            let Some(volume) = volumes
+                .iter()
+                .find(|volume| (volume.start..volume.end).contains(&block))
+            else {
+                if block == 0 {
+                    flush_clusters(&mut clusters, changes);
+                    changes.push("update the partition table".to_string());
+                } else {
+                    outside += 1;
+                }
+                continue;
+            };
+            let layout = &volume.layout;
+            let sector = (block - volume.start) as u32;
+            if sector >= layout.data_start {
+                let cluster = 2 + (sector - layout.data_start) / layout.sectors_per_cluster;
+                match &mut clusters {
+                    Some((_, last)) if cluster <= *last + 1 => *last = cluster,
+                    _ => {
+                        flush_clusters(&mut clusters, changes);
+                        clusters = Some((cluster, cluster));
+                    }
+                }
+                continue;
+            }
+            flush_clusters(&mut clusters, changes);
+            if sector >= layout.root_dir_start {
+                describe_root_dir(old, new, changes);
+            } else if sector >= layout.fat_start {
+                let fat = (sector - layout.fat_start) / layout.fat_size;
+                let offset =
+                    (sector - layout.fat_start - fat * layout.fat_size) * block_size as u32;
+                describe_fat(layout, fat, offset, old, new, changes);
+            } else if layout.variant == FatVariant::ExFat {
+                changes.push(match sector {
+                    0..=11 => "update the boot region".to_string(),
+                    12..=23 => "update the backup boot region".to_string(),
+                    _ => format!("update reserved sector {sector}"),
+                });
+            } else {
+                let sector = sector as u64;
+                changes.push(if sector == 0 {
+                    "update the boot sector".to_string()
+                } else if Some(sector) == volume.fs_info {
+                    "update the FSInfo sector".to_string()
+                } else if Some(sector) == volume.backup_boot {
+                    "update the backup boot sector".to_string()
+                } else {
+                    format!("update reserved sector {sector}")
+                });
+            }
+        }
+        flush_clusters(&mut clusters, changes);
+        match outside {
+            0 => {}
+            1 => changes.push("change a block outside any volume".to_string()),
+            _ => changes.push(format!("change {outside} blocks outside any volume")),
+        }
+    }
+}
+
+fn flush_clusters(clusters: &mut Option<(u32, u32)>, changes: &mut Vec<String>) {
+    match clusters.take() {
+        Some((first, last)) if first == last => changes.push(format!("write cluster {first:#X}")),
+        Some((first, last)) => changes.push(format!("write clusters {first:#X}-{last:#X}")),
+        None => {}
+    }
+}
+
+/// The volumes on `device`, as its partition table or lack of one has it.
+/// Volumes whose sectors are not the device's blocks are left out.
+fn find_volumes<D: BlockDevice>(device: &mut D) -> Vec<Volume> {
+    let block_size = device.block_size();
+    let blocks = device.num_blocks();
+    let mut controller = SDController::from_device(device);
+    let extents = match controller.detect_layout() {
+        Ok(DiskLayout::Superfloppy) => vec![(0, blocks)],
+        Ok(DiskLayout::Partitioned(table)) => table
+            .partitions
+            .iter()
+            .map(|partition| (partition.start_lba, partition.sector_count))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    extents
+        .into_iter()
+        .filter_map(|(start, length)| {
+            let boot = controller
+                .read_device_block(block_index(start).ok()?)
+                .ok()?;
+            let (layout, fs_info, backup_boot) = if exfat::is_exfat(&boot) {
+                (ExFatBootSector::parse(&boot).ok()?.layout(), None, None)
+            } else {
+                let boot_sector = FATBootSector::parse(&boot).ok()?;
+                let reserved = |sector: u16| (sector != 0).then_some(sector as u64);
+                (
+                    FATLayout::new(&boot_sector),
+                    reserved(boot_sector.fs_info_sector),
+                    reserved(boot_sector.backup_boot_sector),
+                )
+            };
+            let usable = layout.bytes_per_sector as usize == block_size
+                && layout.fat_size > 0
+                && layout.sectors_per_cluster > 0;
+            usable.then(|| Volume {
+                start,
+                end: start + length,
+                layout,
+                fs_info,
+                backup_boot,
+            })
+        })
+        .collect()
+}
+
+/// The FAT entries that changed in the block at byte `offset` of FAT
+/// number `fat`. A FAT12 entry straddling two blocks is left out.
+fn describe_fat(
+    layout: &FATLayout,
+    fat: u32,
+    offset: u32,
+    old: &[u8],
+    new: &[u8],
+    changes: &mut Vec<String>,
+) {
+    let variant = layout.variant;
+    let entry_bytes = variant.entry_bytes();
+    let first = match variant {
+        FatVariant::Fat12 => (offset * 2).div_ceil(3),
+        _ => offset / entry_bytes as u32,
+    };
+    let name = match fat {
+        0 => "FAT".to_string(),
+        _ => format!("FAT {}", fat + 1),
+    };
+    for cluster in first..layout.cluster_count + 2 {
+        let at = (variant.entry_offset(cluster) - offset) as usize;
+        if at + entry_bytes > old.len() {
+            break;
+        }
+        let value = |bytes: &[u8]| {
+            let mut raw = [0u8; 4];
+            raw[..entry_bytes].copy_from_slice(&bytes[at..at + entry_bytes]);
+            variant.decode_entry(cluster, u32::from_le_bytes(raw))
+        };
+        let (before, after) = (value(old), value(new));
+        if before != after {
+            let after = match FatEntry::from_value(variant, after) {
+                FatEntry::Free => "free".to_string(),
+                FatEntry::EndOfChain => "EOC".to_string(),
+                FatEntry::Bad => "bad".to_string(),
+                FatEntry::Chain(next) => format!("{next:#X}"),
+                FatEntry::Reserved => format!("{after:#X}"),
+            };
+            changes.push(format!("update {name} entry {cluster:#X} -> {after}"));
+        }
+    }
+}
+
+/// The short-name entries of a FAT12 or FAT16 root directory that were
+/// added, deleted or changed.
+fn describe_root_dir(old: &[u8], new: &[u8], changes: &mut Vec<String>) {
+    const ATTR_LONG_NAME: u8 = 0x0F;
+    let in_use = |entry: &[u8]| entry[0] != 0 && entry[0] != 0xE5 && entry[11] != ATTR_LONG_NAME;
+    for (old, new) in old.chunks_exact(32).zip(new.chunks_exact(32)) {
+        if old == new {
+            continue;
+        }
+        let change = match (in_use(old), in_use(new)) {
+            (false, true) => format!("add root directory entry {}", short_name(new)),
+            (true, false) => format!("delete root directory entry {}", short_name(old)),
+            (true, true) => format!("update root directory entry {}", short_name(new)),
+            (false, false) => continue,
+        };
+        changes.push(change);
+    }
+}
+
+fn short_name(entry: &[u8]) -> String {
+    let base = String::from_utf8_lossy(&entry[..8]).trim_end().to_string();
+    let extension = String::from_utf8_lossy(&entry[8..11])
+        .trim_end()
+        .to_string();
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{base}.{extension}")
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for DryRunDevice<D> {
+    fn block_size(&self) -> usize {
+        self.view.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.view.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.view.read_block(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.view.read_blocks(start, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        self.write_blocks(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !data.len().is_multiple_of(self.block_size()) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let count = (data.len() / self.block_size()) as u32;
+        let end = start as u64 + count as u64;
+        if count == 0 {
+            return Ok(());
+        }
+        if end > self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(end - 1));
+        }
+        self.record(WriteKind::Write, start, count, Some(data))?;
+        self.view.write_blocks(start, data)
+    }
+
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        let end = start as u64 + count as u64;
+        if end > self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(end - 1));
+        }
+        self.record(WriteKind::Discard, start, count, None)?;
+        self.view.discard(start, count)
+    }
+}
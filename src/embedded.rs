@@ -0,0 +1,397 @@
+//! A driver for SD cards wired to an SPI bus, for microcontrollers.
+//!
+//! `SpiSdCard` implements `BlockDevice`, so an `SDController` built on it
+//! reads and writes the card's filesystem with the same code that handles
+//! card readers and images on the desktop.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+use crate::block::BlockDevice;
+use crate::error::SDError;
+use crate::sdinfo::{Cid, Csd};
+
+const BLOCK_SIZE: usize = 512;
+
+const CMD0_GO_IDLE_STATE: u8 = 0;
+const CMD8_SEND_IF_COND: u8 = 8;
+const CMD9_SEND_CSD: u8 = 9;
+const CMD10_SEND_CID: u8 = 10;
+const CMD16_SET_BLOCKLEN: u8 = 16;
+const CMD17_READ_SINGLE_BLOCK: u8 = 17;
+const CMD24_WRITE_BLOCK: u8 = 24;
+const CMD55_APP_CMD: u8 = 55;
+const CMD58_READ_OCR: u8 = 58;
+const ACMD41_SD_SEND_OP_COND: u8 = 41;
+
+/// R1 response bits.
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+/// CMD8 argument: 2.7-3.6 V and a check pattern the card echoes back.
+const IF_COND_ARGUMENT: u32 = 0x1AA;
+/// ACMD41 and OCR bit announcing or reporting high capacity support.
+const HIGH_CAPACITY: u32 = 1 << 30;
+
+const DATA_START_TOKEN: u8 = 0xFE;
+const DATA_ACCEPTED: u8 = 0x05;
+
+/// Polls of the card made while waiting, each about `POLL_INTERVAL_US`
+/// apart: up to a second for initialisation and writes to finish, as the
+/// specification allows, and a tenth of that for read data to arrive.
+const POLL_INTERVAL_US: u32 = 10;
+const BUSY_POLLS: u32 = 100_000;
+const READ_POLLS: u32 = 10_000;
+const INIT_ATTEMPTS: u32 = 1000;
+
+/// CRC-7 of a command frame, shifted into place with the end bit set.
+fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        for bit in (0..8).rev() {
+            let feedback = ((byte >> bit) ^ (crc >> 6)) & 1;
+            crc = (crc << 1) & 0x7F;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc << 1 | 1
+}
+
+/// CRC-16/XMODEM, which protects data blocks.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// An SD card in SPI mode. The chip select line is driven separately from
+/// the bus, since a command, its response and any data must all happen
+/// within one selection, and initialisation needs clocks with the card
+/// deselected.
+pub struct SpiSdCard<SPI, CS, DELAY> {
+    spi: SPI,
+    cs: CS,
+    delay: DELAY,
+    /// SDHC and SDXC cards are addressed in blocks, SDSC cards in bytes.
+    high_capacity: bool,
+    num_blocks: u64,
+}
+
+impl<SPI, CS, DELAY> SpiSdCard<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    /// Initialises the card: CMD0 to enter SPI mode, CMD8 to tell SD 2.0
+    /// cards from older ones, ACMD41 until the card is ready, and CMD58 to
+    /// learn its addressing mode. The bus must run at 400 kHz or less until
+    /// this returns; most cards then take 25 MHz, to which `spi_mut` allows
+    /// switching.
+    pub fn new(spi: SPI, cs: CS, delay: DELAY) -> Result<Self, SDError> {
+        let mut card = SpiSdCard {
+            spi,
+            cs,
+            delay,
+            high_capacity: false,
+            num_blocks: 0,
+        };
+        card.initialise()?;
+        Ok(card)
+    }
+
+    pub fn spi_mut(&mut self) -> &mut SPI {
+        &mut self.spi
+    }
+
+    pub fn release(self) -> (SPI, CS, DELAY) {
+        (self.spi, self.cs, self.delay)
+    }
+
+    pub fn is_high_capacity(&self) -> bool {
+        self.high_capacity
+    }
+
+    pub fn read_csd(&mut self) -> Result<Csd, SDError> {
+        let raw = self.read_register(CMD9_SEND_CSD)?;
+        Ok(Csd::parse(&raw))
+    }
+
+    pub fn read_cid(&mut self) -> Result<Cid, SDError> {
+        let raw = self.read_register(CMD10_SEND_CID)?;
+        Ok(Cid::parse(&raw))
+    }
+
+    fn initialise(&mut self) -> Result<(), SDError> {
+        // At least 74 clocks with the card deselected put it in native
+        // mode, ready to be switched to SPI mode by CMD0.
+        self.deselect()?;
+        self.write(&[0xFF; 10])?;
+
+        let mut idle = false;
+        for _ in 0..10 {
+            if self.with_selected(|card| card.command(CMD0_GO_IDLE_STATE, 0))? == R1_IDLE {
+                idle = true;
+                break;
+            }
+            self.delay.delay_ms(1);
+        }
+        if !idle {
+            return Err(SDError::Card("no response to CMD0"));
+        }
+
+        let version_2 = self.with_selected(|card| {
+            let r1 = card.command(CMD8_SEND_IF_COND, IF_COND_ARGUMENT)?;
+            if r1 & R1_ILLEGAL_COMMAND != 0 {
+                return Ok(false);
+            }
+            let mut r7 = [0xFF; 4];
+            card.transfer(&mut r7)?;
+            if u32::from_be_bytes(r7) & 0xFFF != IF_COND_ARGUMENT {
+                return Err(SDError::Card("unsupported voltage range"));
+            }
+            Ok(true)
+        })?;
+
+        let argument = if version_2 { HIGH_CAPACITY } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_ATTEMPTS {
+            let r1 = self.with_selected(|card| {
+                card.command(CMD55_APP_CMD, 0)?;
+                card.command(ACMD41_SD_SEND_OP_COND, argument)
+            })?;
+            if r1 == 0 {
+                ready = true;
+                break;
+            }
+            if r1 & !R1_IDLE != 0 {
+                return Err(SDError::Card("ACMD41 rejected; not an SD card"));
+            }
+            self.delay.delay_ms(1);
+        }
+        if !ready {
+            return Err(SDError::Card(
+                "timed out waiting for the card to initialise",
+            ));
+        }
+
+        if version_2 {
+            let ocr = self.with_selected(|card| {
+                if card.command(CMD58_READ_OCR, 0)? != 0 {
+                    return Err(SDError::Card("CMD58 failed"));
+                }
+                let mut ocr = [0xFF; 4];
+                card.transfer(&mut ocr)?;
+                Ok(u32::from_be_bytes(ocr))
+            })?;
+            self.high_capacity = ocr & HIGH_CAPACITY != 0;
+        }
+        if !self.high_capacity {
+            let r1 =
+                self.with_selected(|card| card.command(CMD16_SET_BLOCKLEN, BLOCK_SIZE as u32))?;
+            if r1 != 0 {
+                return Err(SDError::Card("CMD16 failed"));
+            }
+        }
+
+        self.num_blocks = self.read_csd()?.capacity / BLOCK_SIZE as u64;
+        Ok(())
+    }
+
+    /// Reads the 16-byte register that `command` sends as a data block.
+    fn read_register(&mut self, command: u8) -> Result<[u8; 16], SDError> {
+        let mut raw = [0u8; 16];
+        self.with_selected(|card| {
+            if card.command(command, 0)? != 0 {
+                return Err(SDError::Card("register read rejected"));
+            }
+            card.read_data(&mut raw)
+        })?;
+        Ok(raw)
+    }
+
+    fn address(&self, block_index: u32) -> u32 {
+        if self.high_capacity {
+            block_index
+        } else {
+            block_index * BLOCK_SIZE as u32
+        }
+    }
+
+    /// Runs `f` with the card selected, deselecting it afterwards even if
+    /// `f` fails.
+    fn with_selected<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, SDError>,
+    ) -> Result<T, SDError> {
+        self.cs
+            .set_low()
+            .map_err(|_| SDError::Card("chip select pin error"))?;
+        let result = f(self);
+        let deselected = self.deselect();
+        // Eight more clocks make the card release its data line.
+        let released = self.write(&[0xFF]);
+        let value = result?;
+        deselected?;
+        released?;
+        Ok(value)
+    }
+
+    fn deselect(&mut self) -> Result<(), SDError> {
+        self.spi
+            .flush()
+            .map_err(|_| SDError::Card("SPI bus error"))?;
+        self.cs
+            .set_high()
+            .map_err(|_| SDError::Card("chip select pin error"))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SDError> {
+        self.spi
+            .write(bytes)
+            .map_err(|_| SDError::Card("SPI bus error"))
+    }
+
+    fn transfer(&mut self, bytes: &mut [u8]) -> Result<(), SDError> {
+        self.spi
+            .transfer_in_place(bytes)
+            .map_err(|_| SDError::Card("SPI bus error"))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, SDError> {
+        let mut byte = [0xFF];
+        self.transfer(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Waits for the card to stop holding its data line low, which it does
+    /// while busy programming.
+    fn wait_ready(&mut self) -> Result<(), SDError> {
+        for _ in 0..BUSY_POLLS {
+            if self.read_byte()? == 0xFF {
+                return Ok(());
+            }
+            self.delay.delay_us(POLL_INTERVAL_US);
+        }
+        Err(SDError::Card("timed out waiting for the card"))
+    }
+
+    /// Sends a command and returns its R1 response. The card must be
+    /// selected.
+    fn command(&mut self, command: u8, argument: u32) -> Result<u8, SDError> {
+        if command != CMD0_GO_IDLE_STATE {
+            self.wait_ready()?;
+        }
+        let mut frame = [0u8; 6];
+        frame[0] = 0x40 | command;
+        frame[1..5].copy_from_slice(&argument.to_be_bytes());
+        frame[5] = crc7(&frame[..5]);
+        self.write(&frame)?;
+        // The response follows within eight bytes, its top bit clear.
+        for _ in 0..8 {
+            let r1 = self.read_byte()?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(SDError::Card("no response to command"))
+    }
+
+    /// Receives a data block after a read command: the start token, the
+    /// data itself and its CRC.
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<(), SDError> {
+        let mut token = 0xFF;
+        for _ in 0..READ_POLLS {
+            token = self.read_byte()?;
+            if token != 0xFF {
+                break;
+            }
+            self.delay.delay_us(POLL_INTERVAL_US);
+        }
+        match token {
+            DATA_START_TOKEN => {}
+            0xFF => return Err(SDError::Card("timed out waiting for data")),
+            _ => return Err(SDError::Card("card reported a read error")),
+        }
+        buffer.fill(0xFF);
+        self.transfer(buffer)?;
+        let mut crc = [0xFF; 2];
+        self.transfer(&mut crc)?;
+        if u16::from_be_bytes(crc) != crc16(buffer) {
+            return Err(SDError::ChecksumMismatch {
+                expected: u16::from_be_bytes(crc) as u32,
+                actual: crc16(buffer) as u32,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<SPI, CS, DELAY> BlockDevice for SpiSdCard<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != BLOCK_SIZE {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if block_index as u64 >= self.num_blocks {
+            return Err(SDError::BlockOutOfRange(block_index as u64));
+        }
+        let address = self.address(block_index);
+        self.with_selected(|card| {
+            if card.command(CMD17_READ_SINGLE_BLOCK, address)? != 0 {
+                return Err(SDError::Card("CMD17 rejected"));
+            }
+            card.read_data(buffer)
+        })
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != BLOCK_SIZE {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if block_index as u64 >= self.num_blocks {
+            return Err(SDError::BlockOutOfRange(block_index as u64));
+        }
+        let address = self.address(block_index);
+        self.with_selected(|card| {
+            if card.command(CMD24_WRITE_BLOCK, address)? != 0 {
+                return Err(SDError::Card("CMD24 rejected"));
+            }
+            card.write(&[0xFF, DATA_START_TOKEN])?;
+            card.write(data)?;
+            card.write(&crc16(data).to_be_bytes())?;
+            if card.read_byte()? & 0x1F != DATA_ACCEPTED {
+                return Err(SDError::Card("card rejected the written data"));
+            }
+            card.wait_ready()
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        self.with_selected(|card| card.wait_ready())
+    }
+}
@@ -0,0 +1,305 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SDError {
+    #[cfg(feature = "std")]
+    #[error("IO error: {0}")]
+    IO(std::io::Error),
+    #[error("Device not found")]
+    DeviceNotFound,
+    #[error("Permission denied opening {0}; run as root or get read access to the device")]
+    PermissionDenied(String),
+    #[error("Device {device} is busy; unmount it first{}", mounted_at(.mounts))]
+    DeviceBusy { device: String, mounts: Vec<String> },
+    #[error("Invalid block size")]
+    InvalidBlockSize,
+    #[error("Read error: expected {expected} bytes got {actual}")]
+    ReadError { expected: usize, actual: usize },
+    #[error("Invalid cluster {0} in cluster chain")]
+    InvalidCluster(u32),
+    #[error("Not supported: {0}")]
+    Unsupported(&'static str),
+    /// A structure on the device failed validation. `offset` is the byte
+    /// offset of `field` within `block`, an absolute LBA once the error has
+    /// left the controller.
+    #[error("Invalid {field} at byte {offset} of block {block}: {reason}")]
+    Parse {
+        block: u64,
+        offset: usize,
+        field: &'static str,
+        reason: &'static str,
+    },
+    #[error("Unsupported or unrecognized filesystem")]
+    UnsupportedFilesystem,
+    #[error("Checksum mismatch: expected {expected:#010x} got {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[error("exFAT allocation bitmap not found")]
+    AllocationBitmapNotFound,
+    #[error("Invalid or missing partition table")]
+    InvalidPartitionTable,
+    #[error("Partition {0} not found")]
+    PartitionNotFound(usize),
+    #[error("Block {0} is out of range")]
+    BlockOutOfRange(u64),
+    #[error("Device was not opened for writing")]
+    ReadOnly,
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Not a directory: {0}")]
+    NotADirectory(String),
+    #[error("Is a directory: {0}")]
+    IsADirectory(String),
+    #[error("Cannot move a directory into itself: {0}")]
+    InvalidMove(String),
+    #[error("Invalid file name: {0}")]
+    InvalidName(String),
+    #[error("Directory is full")]
+    DirectoryFull,
+    #[error("No free clusters left on the volume")]
+    NoSpace,
+    #[error("File too large: {0} bytes")]
+    FileTooLarge(u64),
+    #[error("Image of {image} bytes does not fit on a device of {capacity} bytes")]
+    ImageTooLarge { image: u64, capacity: u64 },
+    #[error("Destination of {capacity} bytes is smaller than the {needed} bytes to copy")]
+    DestinationTooSmall { needed: u64, capacity: u64 },
+    #[error("Verification failed: the device differs from the image at byte {0}")]
+    VerifyFailed(u64),
+    #[error("Wipe verification failed: byte {0} does not hold what was written")]
+    WipeFailed(u64),
+    #[error("Invalid checksum manifest, line {line}: {reason}")]
+    InvalidManifest { line: usize, reason: &'static str },
+    #[error("Audit log line {line} does not match its signature")]
+    AuditLogTampered { line: usize },
+    #[error("Invalid journal: {0}")]
+    InvalidJournal(&'static str),
+    #[error("An interrupted operation is recorded in {0}; resume or roll it back first")]
+    Interrupted(String),
+    #[error("Invalid image: {0}")]
+    InvalidImage(&'static str),
+    #[error("Cannot format: {0}")]
+    InvalidFormat(&'static str),
+    #[error("Invalid card register: {0}")]
+    InvalidRegister(&'static str),
+    #[error("Invalid config file {path}: {reason}")]
+    InvalidConfig { path: String, reason: String },
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
+    #[error("Invalid search pattern: {0}")]
+    InvalidPattern(String),
+    #[error("Server replied {status}: {message}")]
+    Remote { status: u16, message: String },
+    #[error("Refusing to write to {0}: it looks like a fixed system disk")]
+    SystemDisk(String),
+    #[error("Refusing to write to {0}: the config file denies writes to it")]
+    WriteDenied(String),
+    #[error("SD card error: {0}")]
+    Card(&'static str),
+    /// `source` with where it happened. Contexts do not nest: a context
+    /// added later fills in what earlier ones left out.
+    #[error("{context}: {source}")]
+    Context {
+        context: ErrorContext,
+        #[source]
+        source: Box<SDError>,
+    },
+}
+
+/// The block device request an error came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Flush,
+    Discard,
+}
+
+/// Where an error happened, as far as it is known.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub operation: Option<Operation>,
+    /// The first block of the failed request, as an absolute LBA.
+    pub block: Option<u64>,
+    /// Index of the open partition in the partition table.
+    pub partition: Option<usize>,
+    /// The file or directory being processed.
+    pub path: Option<String>,
+    /// Byte offset in that file.
+    pub offset: Option<u64>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.operation, self.block) {
+            (Some(Operation::Read), Some(block)) => write!(f, "While reading block {block}")?,
+            (Some(Operation::Write), Some(block)) => write!(f, "While writing block {block}")?,
+            (Some(Operation::Flush), _) => f.write_str("While flushing the device")?,
+            (Some(Operation::Discard), Some(block)) => {
+                write!(f, "While discarding from block {block}")?
+            }
+            (_, Some(block)) => write!(f, "At block {block}")?,
+            (_, None) => f.write_str("In")?,
+        }
+        if let Some(partition) = self.partition {
+            write!(f, " of partition {partition}")?;
+        }
+        if self.block.is_some() && (self.path.is_some() || self.offset.is_some()) {
+            f.write_str(",")?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {offset} of")?;
+        }
+        match &self.path {
+            Some(path) => write!(f, " {path}"),
+            None if self.offset.is_some() => f.write_str(" the file"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SDError {
+    /// The error without any context around it, for matching on its kind.
+    pub fn root_cause(&self) -> &SDError {
+        match self {
+            SDError::Context { source, .. } => source,
+            error => error,
+        }
+    }
+
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            SDError::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The absolute block the error concerns, if known.
+    pub fn block(&self) -> Option<u64> {
+        match self.root_cause() {
+            SDError::Parse { block, .. } => Some(*block),
+            _ => self.context().and_then(|context| context.block),
+        }
+    }
+
+    /// The file or directory being processed when the error happened.
+    pub fn path(&self) -> Option<&str> {
+        self.context().and_then(|context| context.path.as_deref())
+    }
+
+    /// A `Parse` error in the first block of a buffer, for `at_block` to
+    /// place once the caller knows where the buffer came from.
+    pub(crate) fn parse(offset: usize, field: &'static str, reason: &'static str) -> Self {
+        SDError::Parse {
+            block: 0,
+            offset,
+            field,
+            reason,
+        }
+    }
+
+    fn with_context(self, update: impl FnOnce(&mut ErrorContext)) -> Self {
+        let (mut context, source) = match self {
+            SDError::Context { context, source } => (context, source),
+            error => (ErrorContext::default(), Box::new(error)),
+        };
+        update(&mut context);
+        SDError::Context { context, source }
+    }
+
+    /// Records the request that failed, and the first block it covered.
+    pub(crate) fn during(self, operation: Operation, block: Option<u64>) -> Self {
+        self.with_context(|context| {
+            context.operation.get_or_insert(operation);
+            if context.block.is_none() {
+                context.block = block;
+            }
+        })
+    }
+
+    /// Rebases a `Parse` error from a structure parsed out of a buffer
+    /// onto `block`, the LBA the buffer was read from.
+    pub(crate) fn at_block(self, block: u64) -> Self {
+        match self {
+            SDError::Parse {
+                block: within,
+                offset,
+                field,
+                reason,
+            } => SDError::Parse {
+                block: block + within,
+                offset,
+                field,
+                reason,
+            },
+            error => error.with_context(|context| {
+                context.block.get_or_insert(block);
+            }),
+        }
+    }
+
+    pub(crate) fn in_partition(self, partition: usize) -> Self {
+        self.with_context(|context| {
+            context.partition.get_or_insert(partition);
+        })
+    }
+
+    pub(crate) fn in_file(self, path: &str) -> Self {
+        self.with_context(|context| {
+            context.path.get_or_insert_with(|| path.to_string());
+        })
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn at_offset(self, offset: u64) -> Self {
+        self.with_context(|context| {
+            context.offset.get_or_insert(offset);
+        })
+    }
+}
+
+fn mounted_at(mounts: &[String]) -> String {
+    if mounts.is_empty() {
+        String::new()
+    } else {
+        format!(" (mounted at {})", mounts.join(", "))
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for SDError {
+    /// Unwraps an `SDError` that passed through an `io::Error`, such as one
+    /// from `FatFileReader` under `io::copy`, so its context survives.
+    fn from(error: std::io::Error) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<SDError>()) {
+            let inner = error.into_inner().expect("checked above");
+            return *inner.downcast::<SDError>().expect("checked above");
+        }
+        SDError::IO(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<SDError> for std::io::Error {
+    fn from(error: SDError) -> Self {
+        use std::io::{Error, ErrorKind};
+
+        let kind = match error.root_cause() {
+            SDError::IO(e) => e.kind(),
+            SDError::NotFound(_) => ErrorKind::NotFound,
+            SDError::ReadError { .. } => ErrorKind::UnexpectedEof,
+            _ => ErrorKind::Other,
+        };
+        match error {
+            SDError::IO(e) => e,
+            error => Error::new(kind, error),
+        }
+    }
+}
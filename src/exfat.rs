@@ -0,0 +1,317 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::attributes::Attributes;
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{DirEntry, FatTimestamps, DIR_ENTRY_SIZE};
+use crate::error::SDError;
+use crate::fat::FatVariant;
+use crate::layout::FATLayout;
+use crate::log::debug;
+
+pub const EXFAT_SIGNATURE: &[u8; 8] = b"EXFAT   ";
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_ALLOCATION_BITMAP: u8 = 0x81;
+const ENTRY_VOLUME_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM_EXTENSION: u8 = 0xC0;
+const ENTRY_FILE_NAME: u8 = 0xC1;
+
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+const NAME_CHARS_PER_ENTRY: usize = 15;
+
+/// The main boot sector of an exFAT volume.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ExFatBootSector {
+    pub partition_offset: u64,
+    pub volume_length: u64,
+    pub fat_offset: u32,
+    pub fat_length: u32,
+    pub cluster_heap_offset: u32,
+    pub cluster_count: u32,
+    pub root_dir_cluster: u32,
+    pub volume_serial_number: u32,
+    pub file_system_revision: u16,
+    pub volume_flags: u16,
+    pub bytes_per_sector_shift: u8,
+    pub sectors_per_cluster_shift: u8,
+    pub number_of_fats: u8,
+    pub percent_in_use: u8,
+}
+
+pub fn is_exfat(boot_sector: &[u8]) -> bool {
+    boot_sector.len() >= 11 && &boot_sector[3..11] == EXFAT_SIGNATURE
+}
+
+impl ExFatBootSector {
+    /// Parses an exFAT boot sector, checking the sector and cluster sizes:
+    /// 512 to 4096-byte sectors and clusters of at most 32 MiB, as the
+    /// specification allows. Anything else is corrupt, and would overflow
+    /// the sizes worked out from it.
+    pub fn parse(data: &[u8]) -> Result<Self, SDError> {
+        if data.len() < 512 {
+            return Err(SDError::parse(
+                data.len(),
+                "boot sector",
+                "shorter than 512 bytes",
+            ));
+        }
+        if !(9..=12).contains(&data[108]) {
+            return Err(SDError::parse(
+                108,
+                "BytesPerSectorShift",
+                "sectors must be 512 to 4096 bytes",
+            ));
+        }
+        if data[108] as u32 + data[109] as u32 > 25 {
+            return Err(SDError::parse(
+                109,
+                "SectorsPerClusterShift",
+                "clusters must be at most 32 MiB",
+            ));
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+        let u64_at = |offset: usize| u32_at(offset) as u64 | (u32_at(offset + 4) as u64) << 32;
+
+        Ok(ExFatBootSector {
+            partition_offset: u64_at(64),
+            volume_length: u64_at(72),
+            fat_offset: u32_at(80),
+            fat_length: u32_at(84),
+            cluster_heap_offset: u32_at(88),
+            cluster_count: u32_at(92),
+            root_dir_cluster: u32_at(96),
+            volume_serial_number: u32_at(100),
+            file_system_revision: u16_at(104),
+            volume_flags: u16_at(106),
+            bytes_per_sector_shift: data[108],
+            sectors_per_cluster_shift: data[109],
+            number_of_fats: data[110],
+            percent_in_use: data[112],
+        })
+    }
+
+    pub fn bytes_per_sector(&self) -> u32 {
+        1 << self.bytes_per_sector_shift
+    }
+
+    pub fn sectors_per_cluster(&self) -> u32 {
+        1 << self.sectors_per_cluster_shift
+    }
+
+    pub fn layout(&self) -> FATLayout {
+        FATLayout {
+            variant: FatVariant::ExFat,
+            fat_start: self.fat_offset,
+            fat_size: self.fat_length,
+            number_of_fats: self.number_of_fats as u32,
+            root_dir_start: self.cluster_heap_offset,
+            root_cluster: self.root_dir_cluster,
+            data_start: self.cluster_heap_offset,
+            cluster_count: self.cluster_count,
+            bytes_per_sector: self.bytes_per_sector(),
+            sectors_per_cluster: self.sectors_per_cluster(),
+        }
+    }
+}
+
+/// Checksum over the first eleven sectors of the boot region, skipping the
+/// VolumeFlags and PercentInUse fields, which may change without the
+/// checksum being rewritten.
+pub fn boot_region_checksum(sectors: &[u8]) -> u32 {
+    sectors
+        .iter()
+        .enumerate()
+        .fold(0u32, |checksum, (i, &byte)| {
+            if i == 106 || i == 107 || i == 112 {
+                checksum
+            } else {
+                checksum.rotate_right(1).wrapping_add(byte as u32)
+            }
+        })
+}
+
+fn entry_set_checksum(entries: &[u8]) -> u16 {
+    entries
+        .iter()
+        .enumerate()
+        .fold(0u16, |checksum, (i, &byte)| {
+            if i == 2 || i == 3 {
+                checksum
+            } else {
+                checksum.rotate_right(1).wrapping_add(byte as u16)
+            }
+        })
+}
+
+/// The cluster allocation bitmap; bit N covers cluster N + 2.
+pub struct AllocationBitmap {
+    bits: Vec<u8>,
+}
+
+impl AllocationBitmap {
+    pub fn new(bits: Vec<u8>) -> Self {
+        AllocationBitmap { bits }
+    }
+
+    pub fn is_allocated(&self, cluster: u32) -> bool {
+        let index = cluster.wrapping_sub(2) as usize;
+        self.bits
+            .get(index / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+
+    pub fn allocated_count(&self, cluster_count: u32) -> u32 {
+        (2..cluster_count + 2)
+            .filter(|&cluster| self.is_allocated(cluster))
+            .count() as u32
+    }
+}
+
+/// Finds the volume label entry among the entries of the root directory.
+pub(crate) fn volume_label(root: &[u8]) -> Option<String> {
+    let entry = root
+        .chunks(DIR_ENTRY_SIZE)
+        .take_while(|entry| entry[0] != ENTRY_END)
+        .find(|entry| entry[0] == ENTRY_VOLUME_LABEL)?;
+    let units: Vec<u16> = entry[2..24]
+        .chunks(2)
+        .take((entry[1] as usize).min(11))
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    Some(String::from_utf16_lossy(&units))
+}
+
+/// Parses the entry set starting at `data[0]`. Returns the number of bytes
+/// consumed and the decoded file, if the set is a valid file entry set.
+pub(crate) fn parse_entry_set(data: &[u8]) -> (usize, Option<DirEntry>) {
+    let entry_type = data[0];
+    if entry_type != ENTRY_FILE {
+        return (DIR_ENTRY_SIZE, None);
+    }
+
+    let secondary_count = data[1] as usize;
+    let set_len = (secondary_count + 1) * DIR_ENTRY_SIZE;
+    if secondary_count < 2 || set_len > data.len() {
+        return (DIR_ENTRY_SIZE, None);
+    }
+    let set = &data[..set_len];
+    let stored_checksum = u16::from_le_bytes([set[2], set[3]]);
+    if entry_set_checksum(set) != stored_checksum {
+        return (DIR_ENTRY_SIZE, None);
+    }
+
+    let stream = &set[DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE];
+    if stream[0] != ENTRY_STREAM_EXTENSION {
+        return (set_len, None);
+    }
+    let name_length = stream[3] as usize;
+    let mut name_units = Vec::with_capacity(name_length);
+    for entry in set[2 * DIR_ENTRY_SIZE..].chunks(DIR_ENTRY_SIZE) {
+        if entry[0] != ENTRY_FILE_NAME {
+            break;
+        }
+        for unit in entry[2..2 + 2 * NAME_CHARS_PER_ENTRY].chunks(2) {
+            name_units.push(u16::from_le_bytes([unit[0], unit[1]]));
+        }
+    }
+    name_units.truncate(name_length);
+
+    let u32_at = |entry: &[u8], offset: usize| {
+        u32::from_le_bytes([
+            entry[offset],
+            entry[offset + 1],
+            entry[offset + 2],
+            entry[offset + 3],
+        ])
+    };
+    let created = u32_at(set, 8);
+    let modified = u32_at(set, 12);
+    let accessed = u32_at(set, 16);
+
+    let entry = DirEntry {
+        name: String::from_utf16_lossy(&name_units),
+        ext: String::new(),
+        long_name: None,
+        attributes: Attributes::from_bits(set[4]),
+        size: u32_at(stream, 24) as u64 | (u32_at(stream, 28) as u64) << 32,
+        first_cluster: u32_at(stream, 20),
+        contiguous: stream[1] & FLAG_NO_FAT_CHAIN != 0,
+        timestamps: FatTimestamps {
+            created_tenths: set[20],
+            created_time: created as u16,
+            created_date: (created >> 16) as u16,
+            accessed_date: (accessed >> 16) as u16,
+            modified_time: modified as u16,
+            modified_date: (modified >> 16) as u16,
+        },
+    };
+    (set_len, Some(entry))
+}
+
+impl<D: BlockDevice> SDController<D> {
+    pub fn read_exfat_boot_sector(&mut self) -> Result<ExFatBootSector, SDError> {
+        let data = self.read_block(0)?;
+        if !is_exfat(&data) {
+            return Err(SDError::UnsupportedFilesystem);
+        }
+        let boot_sector = ExFatBootSector::parse(&data)?;
+        self.check_sector_size(boot_sector.bytes_per_sector())?;
+
+        let mut region = data;
+        for sector in 1..11 {
+            region.extend_from_slice(&self.read_block(sector)?);
+        }
+        let expected = boot_region_checksum(&region);
+        let checksum_sector = self.read_block(11)?;
+        let actual = u32::from_le_bytes([
+            checksum_sector[0],
+            checksum_sector[1],
+            checksum_sector[2],
+            checksum_sector[3],
+        ]);
+        if expected != actual {
+            return Err(SDError::ChecksumMismatch { expected, actual });
+        }
+        debug!(
+            bytes_per_sector = boot_sector.bytes_per_sector(),
+            sectors_per_cluster = boot_sector.sectors_per_cluster(),
+            cluster_count = boot_sector.cluster_count,
+            "parsed exFAT boot sector"
+        );
+        Ok(boot_sector)
+    }
+
+    pub fn read_allocation_bitmap(&mut self) -> Result<AllocationBitmap, SDError> {
+        let layout = self.read_exfat_boot_sector()?.layout();
+        let root = self.read_cluster_chain(&layout, layout.root_cluster, None)?;
+
+        for entry in root.chunks(DIR_ENTRY_SIZE) {
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_ALLOCATION_BITMAP => {
+                    let first_cluster =
+                        u32::from_le_bytes([entry[20], entry[21], entry[22], entry[23]]);
+                    let length = u32::from_le_bytes([entry[24], entry[25], entry[26], entry[27]]);
+                    let mut bits =
+                        self.read_cluster_chain(&layout, first_cluster, Some(length as usize))?;
+                    bits.truncate(length as usize);
+                    return Ok(AllocationBitmap::new(bits));
+                }
+                _ => {}
+            }
+        }
+        Err(SDError::AllocationBitmapNotFound)
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::HashSet;
+use std::fs::{self, File, FileTimes};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+#[cfg(target_os = "macos")]
+use std::os::macos::fs::FileTimesExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileTimesExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{split_path, DirEntry, FatTimestamps};
+use crate::error::SDError;
+use crate::filter::EntryFilter;
+use crate::layout::FATLayout;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes copied between progress updates.
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// Zero bytes skipped over, rather than written, when a run of them fills
+/// a whole aligned span of this many bytes of an extracted file. Logging
+/// firmware often allocates its files in full up front, and the host
+/// filesystem leaves the skipped spans unallocated.
+const HOLE_BYTES: usize = 64 << 10;
+
+/// An extracted file being written, with long runs of zeros left as holes.
+struct SparseFile {
+    file: File,
+    position: u64,
+}
+
+impl SparseFile {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(SparseFile {
+            file: File::create(path)?,
+            position: 0,
+        })
+    }
+
+    /// Appends `data`, seeking past each span of `HOLE_BYTES` aligned in
+    /// the file that it holds only zeros for.
+    fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let to_boundary = HOLE_BYTES - (self.position % HOLE_BYTES as u64) as usize;
+            let (span, rest) = data.split_at(to_boundary.min(data.len()));
+            if span.len() == HOLE_BYTES && span.iter().all(|&byte| byte == 0) {
+                self.file.seek(SeekFrom::Current(HOLE_BYTES as i64))?;
+            } else {
+                self.file.write_all(span)?;
+            }
+            self.position += span.len() as u64;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Sets the length to what was written, so that a hole at the end is
+    /// part of the file, and hands it back to have its times set.
+    fn finish(self) -> io::Result<File> {
+        self.file.set_len(self.position)?;
+        Ok(self.file)
+    }
+}
+
+/// One file handled by `extract_all`, successfully or not, as passed to
+/// `ProgressSink::file`.
+#[derive(Debug)]
+pub struct ExtractProgress<'a> {
+    /// Path of the file on the volume.
+    pub path: &'a str,
+    /// Where it was, or would have been, written.
+    pub target: &'a Path,
+    pub size: u64,
+    /// The number of this file, counting from 1, and how many files are
+    /// being extracted.
+    pub file: usize,
+    pub files_total: usize,
+    /// Why the file could not be extracted.
+    pub error: Option<&'a SDError>,
+}
+
+/// Byte progress of `extract_all` across all its files.
+struct Transfer<'a, P: ProgressSink> {
+    progress: &'a mut P,
+    done: u64,
+    total: u64,
+    stopwatch: Stopwatch,
+}
+
+impl<'a, P: ProgressSink> Transfer<'a, P> {
+    fn new(progress: &'a mut P, files: &[PlannedFile]) -> Self {
+        Transfer {
+            progress,
+            done: 0,
+            total: files.iter().map(|file| file.entry.size).sum(),
+            stopwatch: Stopwatch::start(),
+        }
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        self.done += bytes;
+        self.progress.report(&Progress {
+            phase: Phase::Extracting,
+            bytes_done: self.done,
+            bytes_total: self.total,
+            elapsed: self.stopwatch.elapsed(),
+        });
+    }
+
+    /// Reports a file as done, `reported` bytes of it having been counted
+    /// already. A file that failed is removed rather than left truncated,
+    /// and its remaining bytes still counted so that the total is reached.
+    fn finish_file(
+        &mut self,
+        file: &PlannedFile,
+        reported: u64,
+        number: usize,
+        files_total: usize,
+        result: &Result<(), SDError>,
+    ) {
+        if result.is_err() {
+            let _ = fs::remove_file(&file.target);
+        }
+        self.advance(file.entry.size.saturating_sub(reported));
+        self.progress.file(&ExtractProgress {
+            path: &file.path,
+            target: &file.target,
+            size: file.entry.size,
+            file: number,
+            files_total,
+            error: result.as_ref().err(),
+        });
+    }
+}
+
+/// A file found by listing the tree to extract.
+struct PlannedFile {
+    path: String,
+    target: PathBuf,
+    entry: DirEntry,
+}
+
+#[derive(Default)]
+struct ExtractPlan {
+    /// Directories in the order they were listed, parents first.
+    dirs: Vec<(PathBuf, DirEntry)>,
+    files: Vec<PlannedFile>,
+}
+
+impl ExtractPlan {
+    /// Children were listed after their parents; setting times in reverse
+    /// keeps writing a child from touching its parent again.
+    fn set_dir_times(&self) -> io::Result<()> {
+        for (target, dir) in self.dirs.iter().rev() {
+            set_dir_times(target, &dir.timestamps)?;
+        }
+        Ok(())
+    }
+}
+
+/// A file or directory that `extract_all` skipped.
+#[derive(Debug)]
+pub struct ExtractFailure {
+    pub path: String,
+    pub error: SDError,
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractSummary {
+    /// Files extracted in full.
+    pub files: usize,
+    pub directories: usize,
+    pub bytes: u64,
+    pub failures: Vec<ExtractFailure>,
+}
+
+impl ExtractSummary {
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn record(&mut self, file: &PlannedFile, result: Result<(), SDError>) {
+        match result {
+            Ok(()) => {
+                self.files += 1;
+                self.bytes += file.entry.size;
+            }
+            Err(error) => self.failures.push(ExtractFailure {
+                path: file.path.clone(),
+                error,
+            }),
+        }
+    }
+}
+
+/// Where the entry called `name` goes in the host directory `dir`. Names
+/// come from the card and may be anything a writer put there, so one that
+/// is empty, `.` or `..`, or holds a separator or a root, and would land
+/// outside `dir`, is refused with `InvalidName`.
+pub fn host_path(dir: &Path, name: &str) -> Result<PathBuf, SDError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(dir.join(name)),
+        _ => Err(SDError::InvalidName(name.to_string())),
+    }
+}
+
+/// Access and modification times, plus creation times where the host
+/// filesystem lets them be set. Times the entry leaves unset, or holds
+/// garbage in, are not changed.
+fn file_times(timestamps: &FatTimestamps) -> FileTimes {
+    let mut times = FileTimes::new();
+    if let Some(accessed) = timestamps.accessed_at() {
+        times = times.set_accessed(accessed.to_system_time());
+    }
+    if let Some(modified) = timestamps.modified_at() {
+        times = times.set_modified(modified.to_system_time());
+    }
+    #[cfg(any(windows, target_os = "macos"))]
+    if let Some(created) = timestamps.created_at() {
+        times = times.set_created(created.to_system_time());
+    }
+    times
+}
+
+/// Whether the entry has timestamps at all. The root directory has none,
+/// and neither do entries written by tools that leave them zeroed.
+fn has_times(timestamps: &FatTimestamps) -> bool {
+    timestamps.modified_at().is_some() || timestamps.accessed_at().is_some()
+}
+
+/// Sets the times of an extracted directory. Windows cannot open
+/// directories as files, so their times are left as they are there.
+fn set_dir_times(path: &Path, timestamps: &FatTimestamps) -> io::Result<()> {
+    #[cfg(unix)]
+    if has_times(timestamps) {
+        File::open(path)?.set_times(file_times(timestamps))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, timestamps);
+    Ok(())
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Copies the directory `src_dir` and everything below it into the host
+    /// directory `dest`, which is created if needed, keeping each entry's
+    /// timestamps. Existing files are overwritten. Long runs of zeros are
+    /// left as holes where the host filesystem supports sparse files.
+    ///
+    /// A file or directory that cannot be read is recorded in the summary's
+    /// failures and extraction carries on with the rest, so a card with a
+    /// few bad sectors gives up everything else it holds. A file that fails
+    /// part way is removed rather than left truncated. So is an entry whose
+    /// name would put it outside `dest`, such as a long name of `..`.
+    ///
+    /// Progress counts bytes of file data, and `progress.file` hears about
+    /// each file once it is done.
+    pub fn extract_all<P: ProgressSink>(
+        &mut self,
+        src_dir: &str,
+        dest: &Path,
+        progress: &mut P,
+    ) -> Result<ExtractSummary, SDError> {
+        self.extract_matching(src_dir, dest, &EntryFilter::default(), progress)
+    }
+
+    /// `extract_all` of only the files `filter` takes. Directories are
+    /// created only where they lead to one of those files.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(src_dir))
+    )]
+    pub fn extract_matching<P: ProgressSink>(
+        &mut self,
+        src_dir: &str,
+        dest: &Path,
+        filter: &EntryFilter,
+        progress: &mut P,
+    ) -> Result<ExtractSummary, SDError> {
+        let mut summary = ExtractSummary::default();
+        let plan = self.plan_extraction(src_dir, dest, filter, &mut summary)?;
+        let files_total = plan.files.len();
+        let mut transfer = Transfer::new(progress, &plan.files);
+        for (index, file) in plan.files.iter().enumerate() {
+            let before = transfer.done;
+            let result = self
+                .extract_file(&file.entry, &file.target, |bytes| transfer.advance(bytes))
+                .map(drop);
+            transfer.finish_file(
+                file,
+                transfer.done - before,
+                index + 1,
+                files_total,
+                &result,
+            );
+            summary.record(file, result);
+        }
+        plan.set_dir_times()?;
+        Ok(summary)
+    }
+
+    /// Lists the files below `src_dir` that `filter` takes and creates
+    /// their directories under `dest`, recording directories that cannot
+    /// be read in `summary`. The whole tree is listed first so that
+    /// progress can show a total.
+    fn plan_extraction(
+        &mut self,
+        src_dir: &str,
+        dest: &Path,
+        filter: &EntryFilter,
+        summary: &mut ExtractSummary,
+    ) -> Result<ExtractPlan, SDError> {
+        let root = self.stat(src_dir)?;
+        if !root.is_dir() {
+            return Err(SDError::NotADirectory(src_dir.to_string()));
+        }
+        let prefix: String = split_path(src_dir)
+            .iter()
+            .map(|component| format!("/{}", component))
+            .collect();
+        let mut pending = vec![(prefix, dest.to_path_buf(), root)];
+        let mut visited = HashSet::new();
+        let mut plan = ExtractPlan::default();
+        while let Some((path, target, dir)) = pending.pop() {
+            // A corrupted tree can link back to an ancestor.
+            if !visited.insert(dir.first_cluster) {
+                continue;
+            }
+            let entries = match self.open_dir(&dir) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    summary.failures.push(ExtractFailure { path, error });
+                    continue;
+                }
+            };
+            for entry in entries {
+                let child_path = format!("{}/{}", path, entry.full_name());
+                let child_target = match host_path(&target, &entry.full_name()) {
+                    Ok(child_target) => child_target,
+                    Err(error) => {
+                        summary.failures.push(ExtractFailure {
+                            path: child_path,
+                            error,
+                        });
+                        continue;
+                    }
+                };
+                if entry.is_dir() {
+                    pending.push((child_path, child_target, entry));
+                } else if filter.matches(&entry) {
+                    plan.files.push(PlannedFile {
+                        path: child_path,
+                        target: child_target,
+                        entry,
+                    });
+                }
+            }
+            plan.dirs.push((target, dir));
+        }
+        if !filter.is_empty() {
+            let mut wanted = HashSet::new();
+            for file in &plan.files {
+                let mut dir = file.target.parent();
+                while let Some(path) = dir.filter(|path| path.starts_with(dest)) {
+                    if !wanted.insert(path.to_path_buf()) {
+                        break;
+                    }
+                    dir = path.parent();
+                }
+            }
+            plan.dirs
+                .retain(|(target, _)| target == dest || wanted.contains(target));
+        }
+
+        for (target, _) in &plan.dirs {
+            fs::create_dir_all(target)?;
+        }
+        summary.directories = plan.dirs.len();
+        Ok(plan)
+    }
+
+    /// Copies the file `entry` to the host file `target`, overwriting it,
+    /// and returns the number of bytes copied. The host file gets the
+    /// entry's timestamps, and long runs of zeros are left as holes, as
+    /// `extract_all` does. `advance` is given the size of each chunk as it
+    /// is written.
+    pub fn extract_file(
+        &mut self,
+        entry: &DirEntry,
+        target: &Path,
+        advance: impl FnMut(u64),
+    ) -> Result<u64, SDError> {
+        let mut reader = self.file_reader(entry)?;
+        copy_sparse(&mut reader, entry, target, advance)
+    }
+}
+
+/// Writes what `reader` holds for `entry` to a new host file at `target`,
+/// as `extract_file` describes, calling `advance` with the size of each
+/// chunk.
+pub(crate) fn copy_sparse(
+    reader: &mut impl Read,
+    entry: &DirEntry,
+    target: &Path,
+    mut advance: impl FnMut(u64),
+) -> Result<u64, SDError> {
+    let mut file = SparseFile::create(target)?;
+    let mut buffer = vec![0u8; CHUNK_BYTES.min(entry.size as usize)];
+    let mut copied = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        file.write(&buffer[..read])?;
+        copied += read as u64;
+        advance(read as u64);
+    }
+    let file = file.finish()?;
+    if has_times(&entry.timestamps) {
+        file.set_times(file_times(&entry.timestamps))?;
+    }
+    Ok(copied)
+}
+
+/// Locks a mutex shared by extraction workers. A worker that panicked
+/// takes the whole extraction down with it, so poisoning is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl<D: BlockDevice + Send> SDController<D> {
+    /// `extract_all` with `threads` workers taking files off the list in
+    /// turn. The card is still read one request at a time, each worker
+    /// holding the controller while it reads a chunk of its file, but
+    /// creating, writing and timestamping host files goes on alongside,
+    /// which is where most of the time goes for trees of small files.
+    ///
+    /// Files are reported to `progress.file` as they finish, so not
+    /// necessarily in order; the summary lists failures in the order
+    /// `extract_all` would have.
+    pub fn extract_all_parallel<P: ProgressSink + Send>(
+        &mut self,
+        src_dir: &str,
+        dest: &Path,
+        threads: usize,
+        progress: &mut P,
+    ) -> Result<ExtractSummary, SDError> {
+        self.extract_matching_parallel(src_dir, dest, threads, &EntryFilter::default(), progress)
+    }
+
+    /// `extract_matching` with `threads` workers, as `extract_all_parallel`
+    /// has.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(src_dir, threads))
+    )]
+    pub fn extract_matching_parallel<P: ProgressSink + Send>(
+        &mut self,
+        src_dir: &str,
+        dest: &Path,
+        threads: usize,
+        filter: &EntryFilter,
+        progress: &mut P,
+    ) -> Result<ExtractSummary, SDError> {
+        let mut summary = ExtractSummary::default();
+        let plan = self.plan_extraction(src_dir, dest, filter, &mut summary)?;
+        let files_total = plan.files.len();
+        let layout = self.layout()?;
+        let controller = Mutex::new(self);
+        let transfer = Mutex::new(Transfer::new(progress, &plan.files));
+        let next = AtomicUsize::new(0);
+        let finished = AtomicUsize::new(0);
+        let results: Vec<_> = plan.files.iter().map(|_| Mutex::new(None)).collect();
+        thread::scope(|scope| {
+            for _ in 0..threads.clamp(1, files_total.max(1)) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = plan.files.get(index) else {
+                        break;
+                    };
+                    let mut reported = 0;
+                    let result =
+                        extract_shared(&controller, &layout, file, &transfer, &mut reported);
+                    let number = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                    lock(&transfer).finish_file(file, reported, number, files_total, &result);
+                    *lock(&results[index]) = Some(result);
+                });
+            }
+        });
+        for (file, result) in plan.files.iter().zip(results) {
+            if let Some(result) = result.into_inner().unwrap_or_else(|p| p.into_inner()) {
+                summary.record(file, result);
+            }
+        }
+        plan.set_dir_times()?;
+        Ok(summary)
+    }
+}
+
+/// Extracts one file for `extract_all_parallel`, counting the bytes it
+/// reports in `reported`.
+fn extract_shared<D: BlockDevice, P: ProgressSink>(
+    controller: &Mutex<&mut SDController<D>>,
+    layout: &FATLayout,
+    file: &PlannedFile,
+    transfer: &Mutex<Transfer<'_, P>>,
+    reported: &mut u64,
+) -> Result<(), SDError> {
+    let mut reader = SharedReader {
+        controller,
+        layout,
+        entry: &file.entry,
+        clusters: Vec::new(),
+        position: 0,
+    };
+    copy_sparse(&mut reader, &file.entry, &file.target, |bytes| {
+        *reported += bytes;
+        lock(transfer).advance(bytes);
+    })?;
+    Ok(())
+}
+
+/// Reads a file through a controller shared by extraction workers. The
+/// controller is locked for each read only, the chain followed so far
+/// being carried over from one to the next.
+struct SharedReader<'a, 'b, D: BlockDevice> {
+    controller: &'a Mutex<&'b mut SDController<D>>,
+    layout: &'a FATLayout,
+    entry: &'a DirEntry,
+    clusters: Vec<u32>,
+    position: u64,
+}
+
+impl<D: BlockDevice> Read for SharedReader<'_, '_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = buf.len().min((self.entry.size - self.position) as usize);
+        if length == 0 {
+            return Ok(0);
+        }
+        let mut controller = lock(self.controller);
+        let mut reader = controller.resume_reader_with(
+            self.layout.clone(),
+            self.entry,
+            mem::take(&mut self.clusters),
+        );
+        reader.set_read_ahead(length);
+        reader.seek(SeekFrom::Start(self.position))?;
+        reader.read_exact(&mut buf[..length])?;
+        self.clusters = reader.into_clusters();
+        self.position += length as u64;
+        Ok(length)
+    }
+}
@@ -0,0 +1,649 @@
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::layout::FATLayout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum FatVariant {
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+}
+
+impl FatVariant {
+    /// Determines the FAT type from the count of data clusters, which is the
+    /// only method the FAT specification considers authoritative.
+    pub fn from_cluster_count(cluster_count: u32) -> Self {
+        if cluster_count < 4085 {
+            FatVariant::Fat12
+        } else if cluster_count < 65525 {
+            FatVariant::Fat16
+        } else {
+            FatVariant::Fat32
+        }
+    }
+
+    pub fn entry_mask(self) -> u32 {
+        match self {
+            FatVariant::Fat12 => 0x0FFF,
+            FatVariant::Fat16 => 0xFFFF,
+            FatVariant::Fat32 => 0x0FFF_FFFF,
+            FatVariant::ExFat => 0xFFFF_FFFF,
+        }
+    }
+
+    pub fn bad_cluster(self) -> u32 {
+        match self {
+            FatVariant::Fat12 => 0x0FF7,
+            FatVariant::Fat16 => 0xFFF7,
+            FatVariant::Fat32 => 0x0FFF_FFF7,
+            FatVariant::ExFat => 0xFFFF_FFF7,
+        }
+    }
+
+    pub fn end_of_chain(self) -> u32 {
+        self.bad_cluster() + 1
+    }
+
+    /// Byte offset of a cluster's entry within the FAT. FAT12 packs two
+    /// 12-bit entries into every three bytes.
+    pub fn entry_offset(self, cluster: u32) -> u32 {
+        match self {
+            FatVariant::Fat12 => cluster + cluster / 2,
+            FatVariant::Fat16 => cluster * 2,
+            FatVariant::Fat32 | FatVariant::ExFat => cluster * 4,
+        }
+    }
+
+    /// Extracts an entry from the little-endian bytes starting at its
+    /// `entry_offset`.
+    pub fn decode_entry(self, cluster: u32, raw: u32) -> u32 {
+        let value = match self {
+            FatVariant::Fat12 if cluster % 2 == 1 => raw >> 4,
+            _ => raw,
+        };
+        value & self.entry_mask()
+    }
+
+    pub fn entry_bytes(self) -> usize {
+        match self {
+            FatVariant::Fat12 | FatVariant::Fat16 => 2,
+            FatVariant::Fat32 | FatVariant::ExFat => 4,
+        }
+    }
+}
+
+/// The meaning of a FAT entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatEntry {
+    Free,
+    /// Values the specification reserves, including entry 1.
+    Reserved,
+    /// The file continues in the given cluster.
+    Chain(u32),
+    Bad,
+    EndOfChain,
+}
+
+impl FatEntry {
+    pub fn from_value(variant: FatVariant, value: u32) -> Self {
+        let bad = variant.bad_cluster();
+        match value {
+            0 => FatEntry::Free,
+            1 => FatEntry::Reserved,
+            _ if value == bad => FatEntry::Bad,
+            _ if value > bad => FatEntry::EndOfChain,
+            // The seven values just below the bad-cluster marker are reserved.
+            _ if value >= bad - 7 => FatEntry::Reserved,
+            _ => FatEntry::Chain(value),
+        }
+    }
+
+    pub fn is_free(self) -> bool {
+        self == FatEntry::Free
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FATBootSector {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub number_of_fats: u8,
+    pub root_dir_entries: u16,
+    pub total_sectors_16: u16,
+    pub media_descriptor: u8,
+    pub sectors_per_fat: u16,
+    pub total_sectors_32: u32,
+    pub sectors_per_fat_32: u32,
+    pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+}
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+impl FATBootSector {
+    /// Parses and sanity-checks a FAT12/16/32 boot sector. Fields beyond the
+    /// common BPB are only read when the volume is FAT32, which is signalled
+    /// by a zero 16-bit sectors-per-FAT field.
+    pub fn parse(data: &[u8]) -> Result<Self, SDError> {
+        if data.len() < 512 {
+            return Err(SDError::parse(
+                data.len(),
+                "boot sector",
+                "shorter than 512 bytes",
+            ));
+        }
+        if data[510..512] != BOOT_SIGNATURE {
+            return Err(SDError::parse(
+                510,
+                "boot sector signature",
+                "0x55AA is missing",
+            ));
+        }
+
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+        let sectors_per_fat = u16_at(22);
+        let fat32 = sectors_per_fat == 0;
+
+        let boot_sector = FATBootSector {
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: data[13],
+            reserved_sectors: u16_at(14),
+            number_of_fats: data[16],
+            root_dir_entries: u16_at(17),
+            total_sectors_16: u16_at(19),
+            media_descriptor: data[21],
+            sectors_per_fat,
+            total_sectors_32: u32_at(32),
+            sectors_per_fat_32: if fat32 { u32_at(36) } else { 0 },
+            root_cluster: if fat32 { u32_at(44) } else { 0 },
+            fs_info_sector: if fat32 { u16_at(48) } else { 0 },
+            backup_boot_sector: if fat32 { u16_at(50) } else { 0 },
+        };
+        boot_sector.validate()?;
+        Ok(boot_sector)
+    }
+
+    fn validate(&self) -> Result<(), SDError> {
+        if !(512..=4096).contains(&self.bytes_per_sector)
+            || !self.bytes_per_sector.is_power_of_two()
+        {
+            return Err(SDError::parse(
+                11,
+                "bytes per sector",
+                "must be a power of two between 512 and 4096",
+            ));
+        }
+        if !self.sectors_per_cluster.is_power_of_two() {
+            return Err(SDError::parse(
+                13,
+                "sectors per cluster",
+                "must be a nonzero power of two",
+            ));
+        }
+        if self.reserved_sectors == 0 {
+            return Err(SDError::parse(14, "reserved sector count", "is zero"));
+        }
+        if self.number_of_fats == 0 {
+            return Err(SDError::parse(16, "number of FATs", "is zero"));
+        }
+        if self.fat_size() == 0 {
+            let offset = if self.sectors_per_fat == 0 { 36 } else { 22 };
+            return Err(SDError::parse(offset, "sectors per FAT", "is zero"));
+        }
+        if self.root_dir_entries == 0 && self.sectors_per_fat != 0 {
+            return Err(SDError::parse(
+                17,
+                "root directory entry count",
+                "is zero on a FAT12/16 volume",
+            ));
+        }
+
+        let root_dir_sectors =
+            (self.root_dir_entries as u32 * 32).div_ceil(self.bytes_per_sector as u32);
+        let metadata_sectors = self.reserved_sectors as u32
+            + self.number_of_fats as u32 * self.fat_size()
+            + root_dir_sectors;
+        if metadata_sectors >= self.total_sectors() {
+            let offset = if self.total_sectors_16 == 0 { 32 } else { 19 };
+            return Err(SDError::parse(
+                offset,
+                "total sector count",
+                "leaves no room for a data area",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn total_sectors(&self) -> u32 {
+        if self.total_sectors_16 > 0 {
+            self.total_sectors_16 as u32
+        } else {
+            self.total_sectors_32
+        }
+    }
+
+    pub fn fat_size(&self) -> u32 {
+        if self.sectors_per_fat > 0 {
+            self.sectors_per_fat as u32
+        } else {
+            self.sectors_per_fat_32
+        }
+    }
+}
+
+/// An in-memory copy of the first FAT. Modified sectors are tracked so that
+/// only they need to be written back to each FAT copy.
+pub struct FatTable {
+    variant: FatVariant,
+    bytes_per_sector: usize,
+    cluster_count: u32,
+    data: Vec<u8>,
+    dirty: BTreeSet<usize>,
+    /// Where `allocate_chain` starts looking for free clusters, as in the
+    /// FSInfo next-free field.
+    next_free: Option<u32>,
+}
+
+impl FatTable {
+    pub fn new(layout: &FATLayout, data: Vec<u8>) -> Self {
+        FatTable {
+            variant: layout.variant,
+            bytes_per_sector: layout.bytes_per_sector as usize,
+            cluster_count: layout.cluster_count,
+            data,
+            dirty: BTreeSet::new(),
+            next_free: None,
+        }
+    }
+
+    pub fn variant(&self) -> FatVariant {
+        self.variant
+    }
+
+    pub fn entry(&self, cluster: u32) -> u32 {
+        let offset = self.variant.entry_offset(cluster) as usize;
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes
+            .iter_mut()
+            .take(self.variant.entry_bytes())
+            .enumerate()
+        {
+            *byte = self.data.get(offset + i).copied().unwrap_or(0);
+        }
+        self.variant
+            .decode_entry(cluster, u32::from_le_bytes(bytes))
+    }
+
+    pub fn set_entry(&mut self, cluster: u32, value: u32) {
+        let offset = self.variant.entry_offset(cluster) as usize;
+        let len = self.variant.entry_bytes();
+        if offset + len > self.data.len() {
+            return;
+        }
+        let slot = &mut self.data[offset..offset + len];
+        match self.variant {
+            FatVariant::Fat12 => {
+                let current = u16::from_le_bytes([slot[0], slot[1]]);
+                let value = value as u16 & 0x0FFF;
+                let packed = if cluster % 2 == 1 {
+                    (current & 0x000F) | (value << 4)
+                } else {
+                    (current & 0xF000) | value
+                };
+                slot.copy_from_slice(&packed.to_le_bytes());
+            }
+            FatVariant::Fat16 => slot.copy_from_slice(&(value as u16).to_le_bytes()),
+            FatVariant::Fat32 => {
+                // The top four bits are reserved and must be preserved.
+                let current = u32::from_le_bytes([slot[0], slot[1], slot[2], slot[3]]);
+                let packed = (current & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                slot.copy_from_slice(&packed.to_le_bytes());
+            }
+            FatVariant::ExFat => slot.copy_from_slice(&value.to_le_bytes()),
+        }
+        self.dirty.insert(offset / self.bytes_per_sector);
+        self.dirty
+            .insert((offset + len - 1) / self.bytes_per_sector);
+    }
+
+    pub fn fat_entry(&self, cluster: u32) -> FatEntry {
+        FatEntry::from_value(self.variant, self.entry(cluster))
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// Iterates over the entries of all data clusters, in cluster order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, FatEntry)> + '_ {
+        (2..self.cluster_count + 2).map(|cluster| (cluster, self.fat_entry(cluster)))
+    }
+
+    /// Clusters whose entry is zero, in ascending order.
+    pub fn free_clusters(&self) -> impl Iterator<Item = u32> + '_ {
+        (2..self.cluster_count + 2).filter(|&cluster| self.entry(cluster) == 0)
+    }
+
+    /// The cluster after the last one allocated, where the next search for
+    /// free clusters begins.
+    pub(crate) fn next_free(&self) -> Option<u32> {
+        self.next_free
+    }
+
+    /// Free clusters in the order `allocate_chain` takes them: from the
+    /// next-free hint to the end, then from cluster 2 up to the hint.
+    fn free_clusters_from_hint(&self) -> impl Iterator<Item = u32> + '_ {
+        let end = self.cluster_count + 2;
+        let start = self
+            .next_free
+            .filter(|cluster| (2..end).contains(cluster))
+            .unwrap_or(2);
+        (start..end)
+            .chain(2..start)
+            .filter(|&cluster| self.entry(cluster) == 0)
+    }
+
+    /// Iterates over the clusters of the chain starting at `first_cluster`.
+    pub fn chain(&self, first_cluster: u32) -> TableChain<'_> {
+        TableChain {
+            table: self,
+            walker: ChainWalker::new(self.variant, self.cluster_count, first_cluster),
+        }
+    }
+
+    /// Marks every cluster of the chain starting at `first_cluster` as
+    /// free. A broken or looping chain is left as it is.
+    pub(crate) fn free_chain(&mut self, first_cluster: u32) -> Result<(), SDError> {
+        let clusters: Vec<u32> = self.chain(first_cluster).collect::<Result<_, _>>()?;
+        for cluster in clusters {
+            self.set_entry(cluster, 0);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Schedules every sector for writing, so that `store_fat` overwrites
+    /// all FAT copies with this table.
+    #[cfg(feature = "std")]
+    pub(crate) fn mark_all_dirty(&mut self) {
+        self.dirty = (0..self.data.len() / self.bytes_per_sector).collect();
+    }
+
+    fn sector(&self, index: usize) -> &[u8] {
+        &self.data[index * self.bytes_per_sector..(index + 1) * self.bytes_per_sector]
+    }
+}
+
+pub struct FatIter {
+    table: FatTable,
+    cluster: u32,
+}
+
+impl Iterator for FatIter {
+    type Item = (u32, FatEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cluster >= self.table.cluster_count + 2 {
+            return None;
+        }
+        let cluster = self.cluster;
+        self.cluster += 1;
+        Some((cluster, self.table.fat_entry(cluster)))
+    }
+}
+
+/// The state of a walk along a cluster chain, shared by chains read from
+/// the disk and from a `FatTable`.
+struct ChainWalker {
+    first: u32,
+    last: Option<u32>,
+    visited: u32,
+    done: bool,
+    end_of_chain: u32,
+    cluster_count: u32,
+}
+
+impl ChainWalker {
+    fn new(variant: FatVariant, cluster_count: u32, first: u32) -> Self {
+        ChainWalker {
+            first,
+            last: None,
+            visited: 0,
+            done: false,
+            end_of_chain: variant.end_of_chain(),
+            cluster_count,
+        }
+    }
+
+    /// Moves to the next cluster, with `lookup` reading the FAT entry of
+    /// the one before it.
+    fn step(
+        &mut self,
+        lookup: impl FnOnce(u32) -> Result<u32, SDError>,
+    ) -> Option<Result<u32, SDError>> {
+        if self.done {
+            return None;
+        }
+        let cluster = match self.last {
+            None => self.first,
+            Some(last) => match lookup(last) {
+                Ok(next) => next,
+                Err(error) => {
+                    self.done = true;
+                    return Some(Err(error));
+                }
+            },
+        };
+        if cluster >= self.end_of_chain {
+            self.done = true;
+            return None;
+        }
+        // No chain is longer than the volume has clusters, so one that
+        // gets that far has looped back on itself.
+        if cluster < 2 || cluster >= self.cluster_count + 2 || self.visited >= self.cluster_count {
+            self.done = true;
+            return Some(Err(SDError::InvalidCluster(cluster)));
+        }
+        self.visited += 1;
+        self.last = Some(cluster);
+        Some(Ok(cluster))
+    }
+}
+
+/// The clusters of a chain, read from the FAT one entry at a time as the
+/// iterator advances. A cluster outside the data area, or a chain that
+/// loops, ends the iteration with `SDError::InvalidCluster`.
+pub struct ClusterChain<'a, D: BlockDevice> {
+    controller: &'a mut SDController<D>,
+    layout: FATLayout,
+    walker: ChainWalker,
+}
+
+impl<D: BlockDevice> Iterator for ClusterChain<'_, D> {
+    type Item = Result<u32, SDError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (controller, layout) = (&mut *self.controller, &self.layout);
+        self.walker.step(|last| controller.fat_value(layout, last))
+    }
+}
+
+/// The clusters of a chain in a `FatTable`, with the same checks as
+/// `ClusterChain`.
+pub struct TableChain<'a> {
+    table: &'a FatTable,
+    walker: ChainWalker,
+}
+
+impl Iterator for TableChain<'_> {
+    type Item = Result<u32, SDError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let table = self.table;
+        self.walker.step(|last| Ok(table.entry(last)))
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Iterates over the clusters of the chain starting at `first_cluster`.
+    /// On exFAT this only holds for files whose chain is in the FAT, not
+    /// for contiguous ones.
+    pub fn cluster_chain(&mut self, first_cluster: u32) -> Result<ClusterChain<'_, D>, SDError> {
+        let layout = self.layout()?;
+        Ok(self.chain_iter(&layout, first_cluster))
+    }
+
+    pub(crate) fn chain_iter(
+        &mut self,
+        layout: &FATLayout,
+        first_cluster: u32,
+    ) -> ClusterChain<'_, D> {
+        ClusterChain {
+            walker: ChainWalker::new(layout.variant, layout.cluster_count, first_cluster),
+            layout: layout.clone(),
+            controller: self,
+        }
+    }
+
+    /// Reads the raw value of a cluster's FAT entry straight from the disk.
+    pub(crate) fn fat_value(&mut self, layout: &FATLayout, cluster: u32) -> Result<u32, SDError> {
+        let variant = layout.variant;
+        let offset = variant.entry_offset(cluster);
+        let mut sector = layout.fat_start + offset / layout.bytes_per_sector;
+        let mut index = (offset % layout.bytes_per_sector) as usize;
+
+        // A FAT12 entry can straddle two sectors.
+        let mut bytes = [0u8; 4];
+        let mut data = self.read_block(sector)?;
+        for byte in bytes.iter_mut().take(variant.entry_bytes()) {
+            if index == data.len() {
+                sector += 1;
+                index = 0;
+                data = self.read_block(sector)?;
+            }
+            *byte = data[index];
+            index += 1;
+        }
+        Ok(variant.decode_entry(cluster, u32::from_le_bytes(bytes)))
+    }
+
+    /// Lists the clusters of a chain, guarding against loops.
+    pub(crate) fn chain_clusters(
+        &mut self,
+        layout: &FATLayout,
+        first_cluster: u32,
+    ) -> Result<Vec<u32>, SDError> {
+        self.chain_iter(layout, first_cluster).collect()
+    }
+
+    pub(crate) fn load_fat(&mut self, layout: &FATLayout) -> Result<FatTable, SDError> {
+        self.load_fat_copy(layout, 0)
+    }
+
+    pub(crate) fn load_fat_copy(
+        &mut self,
+        layout: &FATLayout,
+        copy: u32,
+    ) -> Result<FatTable, SDError> {
+        let data = self.read_blocks(layout.fat_start + copy * layout.fat_size, layout.fat_size)?;
+        Ok(FatTable::new(layout, data))
+    }
+
+    /// Decodes the FAT entry of a single cluster.
+    pub fn read_fat_entry(&mut self, cluster: u32) -> Result<FatEntry, SDError> {
+        let layout = self.layout()?;
+        if cluster >= layout.cluster_count + 2 {
+            return Err(SDError::InvalidCluster(cluster));
+        }
+        let value = self.fat_value(&layout, cluster)?;
+        Ok(FatEntry::from_value(layout.variant, value))
+    }
+
+    /// Reads the whole first FAT into memory for inspection.
+    pub fn read_fat(&mut self) -> Result<FatTable, SDError> {
+        let layout = self.layout()?;
+        self.load_fat(&layout)
+    }
+
+    /// Iterates over the FAT entries of every data cluster. The FAT is read
+    /// into memory once, up front.
+    pub fn fat_iter(&mut self) -> Result<FatIter, SDError> {
+        Ok(FatIter {
+            table: self.read_fat()?,
+            cluster: 2,
+        })
+    }
+
+    /// Writes the modified sectors of `table` to every FAT copy. On FAT32
+    /// the FSInfo sector is brought up to date with them.
+    pub(crate) fn store_fat(
+        &mut self,
+        layout: &FATLayout,
+        table: &mut FatTable,
+    ) -> Result<(), SDError> {
+        if table.dirty.is_empty() {
+            return Ok(());
+        }
+        for &index in &table.dirty {
+            for copy in 0..layout.number_of_fats {
+                let sector = layout.fat_start + copy * layout.fat_size + index as u32;
+                self.write_block(sector, table.sector(index))?;
+            }
+        }
+        table.dirty.clear();
+        if layout.variant == FatVariant::Fat32 {
+            self.update_fs_info(table)?;
+        }
+        Ok(())
+    }
+
+    /// Reserves `count` free clusters and links them into a chain. The FAT
+    /// is only modified in memory; call `store_fat` to persist it.
+    ///
+    /// On FAT32 the search starts at the FSInfo next-free hint, so that
+    /// filling a large card does not rescan the used part of the FAT for
+    /// every file.
+    pub(crate) fn allocate_chain(
+        &mut self,
+        table: &mut FatTable,
+        count: usize,
+    ) -> Result<Vec<u32>, SDError> {
+        if table.next_free.is_none() && table.variant == FatVariant::Fat32 {
+            table.next_free = self.fsinfo().ok().and_then(|fs_info| fs_info.next_free);
+        }
+        let clusters: Vec<u32> = table.free_clusters_from_hint().take(count).collect();
+        if clusters.len() < count {
+            return Err(SDError::NoSpace);
+        }
+        if let Some(&last) = clusters.last() {
+            table.next_free = Some(last + 1);
+        }
+        let end_of_chain = table.variant().entry_mask();
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let next = clusters.get(i + 1).copied().unwrap_or(end_of_chain);
+            table.set_entry(cluster, next);
+        }
+        Ok(clusters)
+    }
+}
@@ -0,0 +1,334 @@
+//! A C interface to reading cards and images, for tools written in C or
+//! C++. `include/sd_controller.h` declares it; building with the `ffi`
+//! feature regenerates the header with cbindgen. Build a library to link
+//! against with
+//!
+//! ```text
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+//!
+//! or `--crate-type staticlib`.
+//!
+//! Every function returns an `SdctlStatus`, with `SDCTL_STATUS_OK` for success.
+//! On failure `sdctl_last_error` describes what went wrong on the calling
+//! thread. Memory the library hands out is freed with the matching
+//! `sdctl_free_*` function, never with `free`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+use crate::block::{BlockDevice, FileDevice};
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::image::open_image;
+
+/// An open card or image, from `sdctl_open`.
+pub struct SdctlController {
+    controller: SDController<Box<dyn BlockDevice + Send>>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdctlStatus {
+    Ok = 0,
+    /// A pointer was null, a string not UTF-8, or a buffer the wrong size.
+    InvalidArgument = 1,
+    NotFound = 2,
+    /// The host failed to read the device.
+    Io = 3,
+    /// No FAT or exFAT volume was found on the device.
+    NoFilesystem = 4,
+    /// The library panicked; the controller should not be used again.
+    Panic = 5,
+    /// Any other error, described by `sdctl_last_error`.
+    Failed = 6,
+}
+
+/// One entry of a directory listing. `name` is the long name if the entry
+/// has one, NUL-terminated.
+#[repr(C)]
+pub struct SdctlDirEntry {
+    pub name: *mut c_char,
+    pub size: u64,
+    pub first_cluster: u32,
+    /// The FAT attribute bits.
+    pub attributes: u8,
+    pub is_dir: bool,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn status_of(error: &SDError) -> SdctlStatus {
+    match error.root_cause() {
+        SDError::NotFound(_) | SDError::DeviceNotFound | SDError::PartitionNotFound(_) => {
+            SdctlStatus::NotFound
+        }
+        SDError::IO(_) | SDError::ReadError { .. } => SdctlStatus::Io,
+        SDError::UnsupportedFilesystem => SdctlStatus::NoFilesystem,
+        SDError::InvalidArgument(_) | SDError::InvalidBlockSize | SDError::BlockOutOfRange(_) => {
+            SdctlStatus::InvalidArgument
+        }
+        _ => SdctlStatus::Failed,
+    }
+}
+
+/// Runs `body`, turning its error or panic into a status and the message
+/// `sdctl_last_error` returns.
+fn guard(body: impl FnOnce() -> Result<(), SDError>) -> SdctlStatus {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => SdctlStatus::Ok,
+        Ok(Err(error)) => {
+            let status = status_of(&error);
+            set_last_error(error.to_string());
+            status
+        }
+        Err(_) => {
+            set_last_error("sd_controller panicked".to_string());
+            SdctlStatus::Panic
+        }
+    }
+}
+
+fn invalid(what: &'static str) -> SDError {
+    SDError::InvalidArgument(what)
+}
+
+/// # Safety
+///
+/// `string` must be null or point to a NUL-terminated string.
+unsafe fn str_arg<'a>(string: *const c_char) -> Result<&'a str, SDError> {
+    if string.is_null() {
+        return Err(invalid("a string argument is null"));
+    }
+    CStr::from_ptr(string)
+        .to_str()
+        .map_err(|_| invalid("a string argument is not UTF-8"))
+}
+
+/// # Safety
+///
+/// `controller` must be null or come from `sdctl_open` and not have been
+/// closed.
+unsafe fn controller_arg<'a>(
+    controller: *mut SdctlController,
+) -> Result<&'a mut SdctlController, SDError> {
+    controller
+        .as_mut()
+        .ok_or_else(|| invalid("the controller is null"))
+}
+
+/// Opens the device node or image file at `path` read-only and finds the
+/// volume on it: the first partition holding a filesystem, or the whole
+/// device. A device without a volume still opens, for `sdctl_read_block`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string and `out` point to writable
+/// memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_open(
+    path: *const c_char,
+    out: *mut *mut SdctlController,
+) -> SdctlStatus {
+    guard(|| {
+        if out.is_null() {
+            return Err(invalid("the output pointer is null"));
+        }
+        let path = Path::new(str_arg(path)?);
+        let device: Box<dyn BlockDevice + Send> = if path.is_file() {
+            open_image(path, false)?
+        } else {
+            Box::new(FileDevice::open(path)?)
+        };
+        let mut controller = SDController::from_device(device);
+        if controller.open_volume().is_err() {
+            controller.close_partition();
+        }
+        *out = Box::into_raw(Box::new(SdctlController { controller }));
+        Ok(())
+    })
+}
+
+/// Closes a controller from `sdctl_open`. Null is ignored.
+///
+/// # Safety
+///
+/// `controller` must be null or come from `sdctl_open`, and not be used
+/// again.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_close(controller: *mut SdctlController) {
+    if !controller.is_null() {
+        drop(Box::from_raw(controller));
+    }
+}
+
+/// The block size of the device, in bytes, or 0 for a null controller.
+///
+/// # Safety
+///
+/// `controller` must be null or come from `sdctl_open`.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_block_size(controller: *const SdctlController) -> usize {
+    controller
+        .as_ref()
+        .map_or(0, |controller| controller.controller.block_size())
+}
+
+/// The number of blocks on the device, or 0 for a null controller.
+///
+/// # Safety
+///
+/// `controller` must be null or come from `sdctl_open`.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_num_blocks(controller: *const SdctlController) -> u64 {
+    controller
+        .as_ref()
+        .map_or(0, |controller| controller.controller.device().num_blocks())
+}
+
+/// Reads the block at absolute LBA `block` into `buffer`, whose length
+/// `len` must be the block size.
+///
+/// # Safety
+///
+/// `controller` must come from `sdctl_open`, and `buffer` point to `len`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_read_block(
+    controller: *mut SdctlController,
+    block: u32,
+    buffer: *mut u8,
+    len: usize,
+) -> SdctlStatus {
+    guard(|| {
+        let controller = &mut controller_arg(controller)?.controller;
+        if buffer.is_null() || len != controller.block_size() {
+            return Err(invalid("the buffer is null or not one block long"));
+        }
+        let data = controller.read_device_block(block)?;
+        ptr::copy_nonoverlapping(data.as_ptr(), buffer, len);
+        Ok(())
+    })
+}
+
+/// Lists the directory at `path` on the volume, without `.`, `..` and the
+/// volume label. On success `*entries` points to `*count` entries, to be
+/// freed with `sdctl_free_dir`.
+///
+/// # Safety
+///
+/// `controller` must come from `sdctl_open`, `path` be a NUL-terminated
+/// string, and `entries` and `count` point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_list_dir(
+    controller: *mut SdctlController,
+    path: *const c_char,
+    entries: *mut *mut SdctlDirEntry,
+    count: *mut usize,
+) -> SdctlStatus {
+    guard(|| {
+        let controller = &mut controller_arg(controller)?.controller;
+        let path = str_arg(path)?;
+        if entries.is_null() || count.is_null() {
+            return Err(invalid("the output pointers are null"));
+        }
+        let dir = controller.stat(path)?;
+        let listing: Vec<SdctlDirEntry> = controller
+            .open_dir(&dir)?
+            .filter(|entry| !entry.is_volume_label())
+            .map(|entry| SdctlDirEntry {
+                name: CString::new(entry.full_name())
+                    .unwrap_or_default()
+                    .into_raw(),
+                size: entry.size,
+                first_cluster: entry.first_cluster,
+                attributes: entry.attributes.bits(),
+                is_dir: entry.is_dir(),
+            })
+            .collect();
+        let listing = listing.into_boxed_slice();
+        *count = listing.len();
+        *entries = Box::into_raw(listing).cast();
+        Ok(())
+    })
+}
+
+/// Frees a listing from `sdctl_list_dir`. Null is ignored.
+///
+/// # Safety
+///
+/// `entries` and `count` must be as `sdctl_list_dir` returned them, and the
+/// listing not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_free_dir(entries: *mut SdctlDirEntry, count: usize) {
+    if entries.is_null() {
+        return;
+    }
+    let listing = Box::from_raw(ptr::slice_from_raw_parts_mut(entries, count));
+    for entry in listing.iter() {
+        if !entry.name.is_null() {
+            drop(CString::from_raw(entry.name));
+        }
+    }
+}
+
+/// Reads the whole file at `path` on the volume. On success `*data` points
+/// to its `*len` bytes, to be freed with `sdctl_free_buffer`.
+///
+/// # Safety
+///
+/// `controller` must come from `sdctl_open`, `path` be a NUL-terminated
+/// string, and `data` and `len` point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_read_file(
+    controller: *mut SdctlController,
+    path: *const c_char,
+    data: *mut *mut u8,
+    len: *mut usize,
+) -> SdctlStatus {
+    guard(|| {
+        let controller = &mut controller_arg(controller)?.controller;
+        let path = str_arg(path)?;
+        if data.is_null() || len.is_null() {
+            return Err(invalid("the output pointers are null"));
+        }
+        let contents = controller.open(path)?.into_boxed_slice();
+        *len = contents.len();
+        *data = Box::into_raw(contents).cast();
+        Ok(())
+    })
+}
+
+/// Frees file contents from `sdctl_read_file`. Null is ignored.
+///
+/// # Safety
+///
+/// `data` and `len` must be as `sdctl_read_file` returned them, and the
+/// contents not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn sdctl_free_buffer(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// The message of the last error on the calling thread, or null if there
+/// has been none. The string stays valid until the next call that fails.
+#[no_mangle]
+pub extern "C" fn sdctl_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
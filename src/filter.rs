@@ -0,0 +1,120 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::dir::{DirEntry, FatDateTime};
+
+/// Which files a listing, extraction or manifest takes in. Every criterion
+/// set must hold; the default filter takes every file.
+///
+/// Names are compared ignoring case, as FAT looks them up. A file with no
+/// recorded modification time fails `newer_than` and `older_than`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryFilter {
+    /// Patterns with `*`, `?` and `[...]`, of which the long or short name
+    /// must match one, e.g. `IMG_*.JPG`.
+    pub globs: Vec<String>,
+    /// Extensions without the dot, of which the name must end in one.
+    pub extensions: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Modified at or after this time.
+    pub newer_than: Option<FatDateTime>,
+    /// Modified before this time.
+    pub older_than: Option<FatDateTime>,
+}
+
+impl EntryFilter {
+    /// Whether the filter takes every file.
+    pub fn is_empty(&self) -> bool {
+        *self == EntryFilter::default()
+    }
+
+    /// Whether the filter takes `entry`. Directories are tested like
+    /// files, so callers looking for files below a directory should walk
+    /// into directories whatever this says of them.
+    pub fn matches(&self, entry: &DirEntry) -> bool {
+        let names = [entry.full_name(), entry.short_name()];
+        if !self.globs.is_empty()
+            && !self.globs.iter().any(|glob| {
+                names
+                    .iter()
+                    .any(|name| glob_matches_ignoring_case(glob, name))
+            })
+        {
+            return false;
+        }
+        if !self.extensions.is_empty()
+            && !self.extensions.iter().any(|extension| {
+                names.iter().any(|name| {
+                    name.rsplit_once('.')
+                        .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case(extension))
+                })
+            })
+        {
+            return false;
+        }
+        if self.min_size.is_some_and(|min| entry.size < min)
+            || self.max_size.is_some_and(|max| entry.size > max)
+        {
+            return false;
+        }
+        if self.newer_than.is_some() || self.older_than.is_some() {
+            let Some(modified) = entry.timestamps.modified_at() else {
+                return false;
+            };
+            if self.newer_than.is_some_and(|time| modified < time)
+                || self.older_than.is_some_and(|time| modified >= time)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn glob_matches_ignoring_case(pattern: &str, text: &str) -> bool {
+    glob_matches(&pattern.to_uppercase(), &text.to_uppercase())
+}
+
+/// Whether `text` matches the shell pattern `pattern`: `*` for any run of
+/// characters, `?` for one, and `[...]` for one of a set such as `[0-9]`,
+/// or with `[!...]` one not in it.
+pub(crate) fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_chars(&pattern, &text)
+}
+
+fn glob_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| glob_chars(rest, &text[skip..])),
+        Some(('?', rest)) => !text.is_empty() && glob_chars(rest, &text[1..]),
+        Some(('[', rest)) => {
+            // A `]` straight after the opening bracket is part of the set.
+            let Some(end) = rest.iter().skip(1).position(|&c| c == ']').map(|i| i + 1) else {
+                return text.first() == Some(&'[') && glob_chars(rest, &text[1..]);
+            };
+            let Some((&c, text_rest)) = text.split_first() else {
+                return false;
+            };
+            let (negated, set) = match &rest[..end] {
+                ['!', set @ ..] => (true, set),
+                set => (false, set),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    matched |= (set[i]..=set[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= set[i] == c;
+                    i += 1;
+                }
+            }
+            matched != negated && glob_chars(&rest[end + 1..], text_rest)
+        }
+        Some((&c, rest)) => text.first() == Some(&c) && glob_chars(rest, &text[1..]),
+    }
+}
@@ -0,0 +1,441 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::encode_volume_label;
+use crate::error::SDError;
+use crate::fat::{FATBootSector, FatVariant};
+use crate::layout::FATLayout;
+use crate::log::debug;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Sectors zeroed per device request while formatting.
+const ZERO_CHUNK_SECTORS: u32 = 2048;
+const MEDIA_FIXED: u8 = 0xF8;
+const FAT16_ROOT_ENTRIES: u16 = 512;
+const FAT32_RESERVED_SECTORS: u16 = 32;
+const FAT32_FS_INFO_SECTOR: u16 = 1;
+const FAT32_BACKUP_BOOT_SECTOR: u16 = 6;
+/// Volumes from this size up get FAT32, as SDHC and SDXC cards do; smaller
+/// ones get FAT16, as SDSC cards do.
+pub(crate) const FAT32_THRESHOLD_BYTES: u64 = 2 << 30;
+
+/// Settings for `format`. Everything left as `None` is chosen from the size
+/// of the volume.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// `Fat16` or `Fat32`. By default a partition gets the one its type
+    /// byte names, and otherwise volumes under 2 GiB get FAT16 and larger
+    /// ones FAT32.
+    pub variant: Option<FatVariant>,
+    /// By default taken from the cluster size tables of the FAT
+    /// specification.
+    pub sectors_per_cluster: Option<u8>,
+    /// Up to 11 characters; the volume is left unlabelled without one.
+    pub label: Option<String>,
+    /// By default derived from the current time, as DOS does, or without
+    /// `std` from the size of the volume.
+    pub serial: Option<u32>,
+    /// Follow the SD Association's file system specification: the FAT
+    /// variant and cluster size it gives the card's capacity, unless set
+    /// above, and the FAT and data area aligned to the card's boundary
+    /// unit. Cards only reach their rated speed laid out this way.
+    pub sd_spec: bool,
+}
+
+/// What the SD file system specification puts on a card of `card_bytes`:
+/// the FAT variant, and the cluster size and boundary unit in bytes. The
+/// boundary unit stands in for the card's allocation unit, the block of
+/// flash it erases at once.
+fn sd_spec_profile(card_bytes: u64) -> Result<(FatVariant, u32, u32), SDError> {
+    const MIB: u64 = 1 << 20;
+    match card_bytes {
+        _ if card_bytes <= 64 * MIB => Err(SDError::Unsupported(
+            "the SD specification formats cards of 64 MiB or less as FAT12",
+        )),
+        _ if card_bytes <= 256 * MIB => Ok((FatVariant::Fat16, 16384, 32768)),
+        _ if card_bytes <= 1024 * MIB => Ok((FatVariant::Fat16, 16384, 65536)),
+        _ if card_bytes <= 2048 * MIB => Ok((FatVariant::Fat16, 32768, 65536)),
+        _ if card_bytes <= 32768 * MIB => Ok((FatVariant::Fat32, 32768, 4 << 20)),
+        _ => Err(SDError::Unsupported(
+            "the SD specification formats cards over 32 GiB as exFAT",
+        )),
+    }
+}
+
+/// The cluster size the FAT specification recommends for a volume of
+/// `sectors` 512-byte sectors, or `None` if the variant does not suit a
+/// volume of that size.
+fn default_cluster_bytes(variant: FatVariant, sectors: u64) -> Option<u32> {
+    let table: &[(u64, u32)] = match variant {
+        FatVariant::Fat16 => &[
+            (8_400, 0),
+            (32_680, 1024),
+            (262_144, 2048),
+            (524_288, 4096),
+            (1_048_576, 8192),
+            (2_097_152, 16384),
+            (4_194_304, 32768),
+        ],
+        FatVariant::Fat32 => &[
+            (66_600, 0),
+            (532_480, 512),
+            (16_777_216, 4096),
+            (33_554_432, 8192),
+            (67_108_864, 16384),
+            (u64::MAX, 32768),
+        ],
+        _ => return None,
+    };
+    table
+        .iter()
+        .find(|&&(limit, _)| sectors <= limit)
+        .map(|&(_, cluster_bytes)| cluster_bytes)
+        .filter(|&cluster_bytes| cluster_bytes > 0)
+}
+
+#[cfg(feature = "std")]
+fn default_serial(_sectors: u64) -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() as u32).wrapping_mul(0x9E37_79B9) ^ now.subsec_nanos()
+}
+
+/// Without a clock the serial number can only come from the volume itself.
+#[cfg(not(feature = "std"))]
+fn default_serial(sectors: u64) -> u32 {
+    (sectors as u32).wrapping_mul(0x9E37_79B9)
+}
+
+/// Works out the boot sector of a fresh volume: FAT size from the cluster
+/// count it has to cover, and reserved sectors padded so that the data area
+/// starts on a cluster boundary, which keeps clusters aligned with the
+/// card's flash pages.
+///
+/// With a `boundary` of `(hidden sectors, boundary unit)` in sectors, the
+/// reserved sectors are padded instead so that the FAT starts on a boundary
+/// of the device, and the FATs so that the data area does too.
+fn geometry(
+    variant: FatVariant,
+    total_sectors: u32,
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    boundary: Option<(u32, u32)>,
+) -> Result<FATBootSector, SDError> {
+    let fat32 = variant == FatVariant::Fat32;
+    let (mut reserved, root_dir_entries) = if fat32 {
+        (FAT32_RESERVED_SECTORS as u32, 0)
+    } else {
+        (1, FAT16_ROOT_ENTRIES)
+    };
+    let root_dir_sectors = (root_dir_entries as u32 * 32).div_ceil(bytes_per_sector as u32);
+    let spc = sectors_per_cluster as u32;
+    let entry_bytes = variant.entry_bytes() as u64;
+    let too_small = || SDError::InvalidFormat("the volume is too small");
+
+    // More FAT means fewer clusters to cover, so grow it until it fits.
+    let mut fat_size = 1u32;
+    loop {
+        let metadata = reserved + 2 * fat_size + root_dir_sectors;
+        let clusters = total_sectors.checked_sub(metadata).ok_or_else(too_small)? / spc;
+        let needed = ((clusters as u64 + 2) * entry_bytes).div_ceil(bytes_per_sector as u64);
+        if needed <= fat_size as u64 {
+            break;
+        }
+        fat_size = needed as u32;
+    }
+    match boundary {
+        Some((hidden, unit)) => {
+            reserved += (unit - (hidden + reserved) % unit) % unit;
+            // Both FATs grow at once, which ends on a boundary unit since
+            // units and the fixed root directory are an even number of
+            // sectors.
+            while !(2 * fat_size + root_dir_sectors).is_multiple_of(unit) {
+                fat_size += 1;
+            }
+            total_sectors
+                .checked_sub(reserved + 2 * fat_size + root_dir_sectors)
+                .ok_or_else(too_small)?;
+        }
+        None => reserved += (spc - (reserved + 2 * fat_size + root_dir_sectors) % spc) % spc,
+    }
+    let reserved = u16::try_from(reserved).map_err(|_| too_small())?;
+
+    let boot_sector = FATBootSector {
+        bytes_per_sector,
+        sectors_per_cluster,
+        reserved_sectors: reserved,
+        number_of_fats: 2,
+        root_dir_entries,
+        total_sectors_16: if !fat32 && total_sectors < 0x10000 {
+            total_sectors as u16
+        } else {
+            0
+        },
+        media_descriptor: MEDIA_FIXED,
+        sectors_per_fat: if fat32 {
+            0
+        } else {
+            u16::try_from(fat_size)
+                .map_err(|_| SDError::InvalidFormat("the volume has too many clusters for FAT16"))?
+        },
+        total_sectors_32: if !fat32 && total_sectors < 0x10000 {
+            0
+        } else {
+            total_sectors
+        },
+        sectors_per_fat_32: if fat32 { fat_size } else { 0 },
+        root_cluster: if fat32 { 2 } else { 0 },
+        fs_info_sector: if fat32 { FAT32_FS_INFO_SECTOR } else { 0 },
+        backup_boot_sector: if fat32 { FAT32_BACKUP_BOOT_SECTOR } else { 0 },
+    };
+
+    let cluster_count = FATLayout::new(&boot_sector).cluster_count;
+    if FatVariant::from_cluster_count(cluster_count) != variant {
+        return Err(SDError::InvalidFormat(match variant {
+            FatVariant::Fat16 if cluster_count >= 65525 => {
+                "the volume has too many clusters for FAT16; use larger clusters or FAT32"
+            }
+            FatVariant::Fat16 => "the volume has too few clusters for FAT16",
+            _ => "the volume has too few clusters for FAT32; use smaller clusters or FAT16",
+        }));
+    }
+    Ok(boot_sector)
+}
+
+/// Serializes a boot sector produced by `geometry`. The boot code area is
+/// left empty, so the volume does not boot.
+fn boot_sector_bytes(
+    boot_sector: &FATBootSector,
+    hidden_sectors: u32,
+    serial: u32,
+    label: &[u8; 11],
+) -> Vec<u8> {
+    let mut data = vec![0u8; boot_sector.bytes_per_sector as usize];
+    let fat32 = boot_sector.sectors_per_fat == 0;
+    data[0..3].copy_from_slice(if fat32 {
+        &[0xEB, 0x58, 0x90]
+    } else {
+        &[0xEB, 0x3C, 0x90]
+    });
+    // The OEM name most drivers are known to accept.
+    data[3..11].copy_from_slice(b"MSWIN4.1");
+    data[11..13].copy_from_slice(&boot_sector.bytes_per_sector.to_le_bytes());
+    data[13] = boot_sector.sectors_per_cluster;
+    data[14..16].copy_from_slice(&boot_sector.reserved_sectors.to_le_bytes());
+    data[16] = boot_sector.number_of_fats;
+    data[17..19].copy_from_slice(&boot_sector.root_dir_entries.to_le_bytes());
+    data[19..21].copy_from_slice(&boot_sector.total_sectors_16.to_le_bytes());
+    data[21] = boot_sector.media_descriptor;
+    data[22..24].copy_from_slice(&boot_sector.sectors_per_fat.to_le_bytes());
+    // Sectors per track and heads of the usual LBA translation.
+    data[24..26].copy_from_slice(&63u16.to_le_bytes());
+    data[26..28].copy_from_slice(&255u16.to_le_bytes());
+    data[28..32].copy_from_slice(&hidden_sectors.to_le_bytes());
+    data[32..36].copy_from_slice(&boot_sector.total_sectors_32.to_le_bytes());
+
+    let extended = if fat32 {
+        data[36..40].copy_from_slice(&boot_sector.sectors_per_fat_32.to_le_bytes());
+        data[44..48].copy_from_slice(&boot_sector.root_cluster.to_le_bytes());
+        data[48..50].copy_from_slice(&boot_sector.fs_info_sector.to_le_bytes());
+        data[50..52].copy_from_slice(&boot_sector.backup_boot_sector.to_le_bytes());
+        64
+    } else {
+        36
+    };
+    data[extended] = 0x80;
+    data[extended + 2] = 0x29;
+    data[extended + 3..extended + 7].copy_from_slice(&serial.to_le_bytes());
+    data[extended + 7..extended + 18].copy_from_slice(label);
+    data[extended + 18..extended + 26].copy_from_slice(if fat32 {
+        b"FAT32   "
+    } else {
+        b"FAT16   "
+    });
+    data[510] = 0x55;
+    data[511] = 0xAA;
+    data
+}
+
+fn fs_info_bytes(bytes_per_sector: usize, free_clusters: u32) -> Vec<u8> {
+    let mut data = vec![0u8; bytes_per_sector];
+    data[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    data[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    data[488..492].copy_from_slice(&free_clusters.to_le_bytes());
+    // Cluster 2 holds the root directory.
+    data[492..496].copy_from_slice(&3u32.to_le_bytes());
+    data[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+    data
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Writes an empty FAT16 or FAT32 filesystem over the open partition, or
+    /// the whole device: boot sector, FATs and root directory, plus the
+    /// FSInfo sector and backup boot sector on FAT32. Everything on the
+    /// volume is lost. The partition table, if any, is left alone, so its
+    /// type byte may need updating to match the new variant.
+    ///
+    /// Returns the layout of the new volume.
+    pub fn format(&mut self, options: &FormatOptions) -> Result<FATLayout, SDError> {
+        self.format_with_progress(options, &mut ())
+    }
+
+    /// `format`, reporting progress while the reserved sectors and FATs
+    /// are cleared, which is most of the work on a large card.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn format_with_progress<P: ProgressSink>(
+        &mut self,
+        options: &FormatOptions,
+        progress: &mut P,
+    ) -> Result<FATLayout, SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let bytes_per_sector = u16::try_from(self.block_size())
+            .ok()
+            .filter(|size| (512..=4096).contains(size) && size.is_power_of_two())
+            .ok_or(SDError::InvalidBlockSize)?;
+        let total_sectors = u32::try_from(self.num_blocks())
+            .map_err(|_| SDError::InvalidFormat("the volume is too large for FAT32"))?;
+        let volume_bytes = total_sectors as u64 * bytes_per_sector as u64;
+
+        let profile = if options.sd_spec {
+            let card_bytes = self.device.num_blocks() * bytes_per_sector as u64;
+            let (variant, cluster_bytes, unit_bytes) = sd_spec_profile(card_bytes)?;
+            let sectors = |bytes: u32| (bytes / bytes_per_sector as u32).max(1);
+            debug!(
+                ?variant,
+                cluster_bytes, unit_bytes, "SD specification profile"
+            );
+            Some((variant, sectors(cluster_bytes) as u8, sectors(unit_bytes)))
+        } else {
+            None
+        };
+        let variant = options
+            .variant
+            .or(profile.map(|(variant, _, _)| variant))
+            .or_else(|| self.partition_fat_variant())
+            .unwrap_or(if volume_bytes < FAT32_THRESHOLD_BYTES {
+                FatVariant::Fat16
+            } else {
+                FatVariant::Fat32
+            });
+        if !matches!(variant, FatVariant::Fat16 | FatVariant::Fat32) {
+            return Err(SDError::Unsupported(
+                "only FAT16 and FAT32 can be formatted",
+            ));
+        }
+        let sectors_per_cluster = match options.sectors_per_cluster {
+            Some(spc) if spc.is_power_of_two() => spc,
+            Some(_) => {
+                return Err(SDError::InvalidFormat(
+                    "sectors per cluster must be a nonzero power of two",
+                ))
+            }
+            None => match profile {
+                Some((_, spc, _)) => spc,
+                None => {
+                    let cluster_bytes = default_cluster_bytes(variant, volume_bytes / 512).ok_or(
+                        SDError::InvalidFormat("the volume size does not suit this FAT variant"),
+                    )?;
+                    (cluster_bytes / bytes_per_sector as u32).max(1) as u8
+                }
+            },
+        };
+        let label = match &options.label {
+            Some(label) => encode_volume_label(label)?,
+            None => *b"NO NAME    ",
+        };
+        let serial = options
+            .serial
+            .unwrap_or_else(|| default_serial(self.num_blocks()));
+
+        let hidden_sectors = self.partition_start();
+        let boot_sector = geometry(
+            variant,
+            total_sectors,
+            bytes_per_sector,
+            sectors_per_cluster,
+            profile.map(|(_, _, unit)| (hidden_sectors, unit)),
+        )?;
+        let layout = FATLayout::new(&boot_sector);
+        let sector_len = bytes_per_sector as usize;
+
+        // Clear the old boot sector first, so an interrupted format does
+        // not leave it describing FATs that have been wiped.
+        let mut clear = layout.data_start;
+        if variant == FatVariant::Fat32 {
+            clear += layout.sectors_per_cluster;
+        }
+        let stopwatch = Stopwatch::start();
+        self.zero_sectors(0, clear, |sectors| {
+            progress.report(&Progress {
+                phase: Phase::Formatting,
+                bytes_done: sectors as u64 * sector_len as u64,
+                bytes_total: clear as u64 * sector_len as u64,
+                elapsed: stopwatch.elapsed(),
+            })
+        })?;
+
+        // Entry 0 repeats the media descriptor and entry 1 is an end of
+        // chain marker, as is the entry of the FAT32 root directory.
+        let mut fat = vec![0u8; sector_len];
+        let media = 0xFFFF_FF00 | MEDIA_FIXED as u32;
+        let end_of_chain = variant.entry_mask();
+        let entries: &[u32] = if variant == FatVariant::Fat32 {
+            &[media, end_of_chain, end_of_chain]
+        } else {
+            &[media, end_of_chain]
+        };
+        let entry_bytes = variant.entry_bytes();
+        for (chunk, value) in fat.chunks_mut(entry_bytes).zip(entries) {
+            let value = value & variant.entry_mask();
+            chunk.copy_from_slice(&value.to_le_bytes()[..entry_bytes]);
+        }
+        for copy in 0..layout.number_of_fats {
+            self.write_block(layout.fat_start + copy * layout.fat_size, &fat)?;
+        }
+
+        let boot = boot_sector_bytes(&boot_sector, hidden_sectors, serial, &label);
+        if variant == FatVariant::Fat32 {
+            let fs_info = fs_info_bytes(sector_len, layout.cluster_count - 1);
+            let backup = boot_sector.backup_boot_sector as u32;
+            let fs_info_sector = boot_sector.fs_info_sector as u32;
+            self.write_block(backup, &boot)?;
+            self.write_block(backup + fs_info_sector, &fs_info)?;
+            self.write_block(fs_info_sector, &fs_info)?;
+        }
+        self.write_block(0, &boot)?;
+        // The boot sector already carries the label; this adds the root
+        // directory entry most systems read it from.
+        if let Some(label) = &options.label {
+            self.set_volume_label(Some(label))?;
+        }
+        self.flush()?;
+        Ok(layout)
+    }
+
+    /// Zeroes `count` sectors from `start`, passing `cleared` the number
+    /// zeroed so far after each request.
+    fn zero_sectors(
+        &mut self,
+        start: u32,
+        count: u32,
+        mut cleared: impl FnMut(u32),
+    ) -> Result<(), SDError> {
+        let zeros = vec![0u8; ZERO_CHUNK_SECTORS as usize * self.block_size()];
+        let mut sector = start;
+        while sector < start + count {
+            let chunk = ZERO_CHUNK_SECTORS.min(start + count - sector);
+            self.write_blocks(sector, &zeros[..chunk as usize * self.block_size()])?;
+            sector += chunk;
+            cleared(sector - start);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use fuser::{
+    Config, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo,
+    LockOwner, MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyStatfs, Request,
+};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+
+/// How long the kernel may cache names and attributes. Nothing changes
+/// under a read-only mount, so this can be long.
+const TTL: Duration = Duration::from_secs(60);
+
+fn errno(error: &SDError) -> Errno {
+    match error.root_cause() {
+        SDError::IO(e) => Errno::from_i32(e.raw_os_error().unwrap_or(0)),
+        SDError::NotFound(_) => Errno::ENOENT,
+        SDError::NotADirectory(_) => Errno::ENOTDIR,
+        SDError::IsADirectory(_) => Errno::EISDIR,
+        SDError::ReadOnly => Errno::EROFS,
+        _ => Errno::EIO,
+    }
+}
+
+struct Node {
+    entry: DirEntry,
+    /// Inodes of the entries of a directory, once it has been listed.
+    children: Option<Vec<u64>>,
+}
+
+struct Volume<D: BlockDevice> {
+    controller: SDController<D>,
+    /// Node `ino` is at index `ino - 1`; inode 1 is the root. FAT has no
+    /// inode numbers, so they are handed out as entries are first seen and
+    /// kept for the life of the mount.
+    nodes: Vec<Node>,
+    /// The clusters each open file's reader has followed so far, so that
+    /// sequential reads do not walk the chain from the start each time.
+    handles: HashMap<u64, Vec<u32>>,
+    next_handle: u64,
+    cluster_size: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    fn node(&self, ino: INodeNo) -> Result<&Node, Errno> {
+        (ino.0 as usize)
+            .checked_sub(1)
+            .and_then(|index| self.nodes.get(index))
+            .ok_or(Errno::ENOENT)
+    }
+
+    fn attr(&self, ino: u64, entry: &DirEntry) -> FileAttr {
+        let timestamps = &entry.timestamps;
+        let (kind, perm, nlink) = if entry.is_dir() {
+            (FileType::Directory, 0o555, 2)
+        } else {
+            (FileType::RegularFile, 0o444, 1)
+        };
+        FileAttr {
+            ino: INodeNo(ino),
+            size: entry.size,
+            blocks: entry.size.div_ceil(512),
+            atime: timestamps.accessed(),
+            mtime: timestamps.modified(),
+            ctime: timestamps.modified(),
+            crtime: timestamps.created(),
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: self.cluster_size,
+            flags: 0,
+        }
+    }
+
+    /// The inodes of a directory's entries, listing it on first use.
+    fn children(&mut self, ino: INodeNo) -> Result<Vec<u64>, Errno> {
+        let node = self.node(ino)?;
+        if let Some(children) = &node.children {
+            return Ok(children.clone());
+        }
+        if !node.entry.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+        let dir = node.entry.clone();
+        let entries: Vec<DirEntry> = self
+            .controller
+            .open_dir(&dir)
+            .map_err(|e| errno(&e))?
+            .collect();
+        let first = self.nodes.len() as u64 + 1;
+        let children: Vec<u64> = (first..first + entries.len() as u64).collect();
+        self.nodes.extend(entries.into_iter().map(|entry| Node {
+            entry,
+            children: None,
+        }));
+        self.nodes[ino.0 as usize - 1].children = Some(children.clone());
+        Ok(children)
+    }
+
+    fn read(&mut self, ino: INodeNo, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, SDError> {
+        let entry = self
+            .node(ino)
+            .map_err(|_| SDError::NotFound(format!("inode {}", ino.0)))?
+            .entry
+            .clone();
+        let clusters = self.handles.remove(&fh).unwrap_or_default();
+        let mut reader = self.controller.resume_reader(&entry, clusters)?;
+        // Fetch no more than the kernel asked for; it does its own
+        // read-ahead.
+        reader.set_read_ahead(size as usize);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(size as usize);
+        reader.by_ref().take(size as u64).read_to_end(&mut data)?;
+        self.handles.insert(fh, reader.into_clusters());
+        Ok(data)
+    }
+}
+
+/// A FAT or exFAT volume served read-only over FUSE.
+pub struct FuseVolume<D: BlockDevice> {
+    volume: Mutex<Volume<D>>,
+}
+
+impl<D: BlockDevice> FuseVolume<D> {
+    /// Serves the volume `controller` has open. Files and directories are
+    /// owned by `uid` and `gid`, since FAT records no owners.
+    pub fn new(mut controller: SDController<D>, uid: u32, gid: u32) -> Result<Self, SDError> {
+        let root = controller.stat("/")?;
+        let cluster_size = controller.layout()?.cluster_size() as u32;
+        Ok(FuseVolume {
+            volume: Mutex::new(Volume {
+                controller,
+                nodes: vec![Node {
+                    entry: root,
+                    children: None,
+                }],
+                handles: HashMap::new(),
+                next_handle: 1,
+                cluster_size,
+                uid,
+                gid,
+            }),
+        })
+    }
+
+    fn volume(&self) -> std::sync::MutexGuard<'_, Volume<D>> {
+        // A panic while serving one request leaves nothing half-updated
+        // that later requests could trip over.
+        self.volume
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<D: BlockDevice + Send + 'static> Filesystem for FuseVolume<D> {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        let mut volume = self.volume();
+        let children = match volume.children(parent) {
+            Ok(children) => children,
+            Err(e) => return reply.error(e),
+        };
+        let name = name.to_string_lossy();
+        let found = children.into_iter().find_map(|ino| {
+            let entry = &volume.nodes[ino as usize - 1].entry;
+            entry.matches_name(&name).then(|| volume.attr(ino, entry))
+        });
+        match found {
+            Some(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        let volume = self.volume();
+        match volume.node(ino) {
+            Ok(node) => reply.attr(&TTL, &volume.attr(ino.0, &node.entry)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+        let mut volume = self.volume();
+        match volume.node(ino) {
+            Ok(node) if node.entry.is_dir() => return reply.error(Errno::EISDIR),
+            Ok(_) => {}
+            Err(e) => return reply.error(e),
+        }
+        let fh = volume.next_handle;
+        volume.next_handle += 1;
+        volume.handles.insert(fh, Vec::new());
+        reply.opened(FileHandle(fh), FopenFlags::FOPEN_KEEP_CACHE);
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        match self.volume().read(ino, fh.0, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.volume().handles.remove(&fh.0);
+        reply.ok();
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        let mut volume = self.volume();
+        let children = match volume.children(ino) {
+            Ok(children) => children,
+            Err(e) => return reply.error(e),
+        };
+        // The parent is not tracked; the kernel resolves `..` itself.
+        let dots = [
+            (ino.0, FileType::Directory, ".".to_string()),
+            (ino.0, FileType::Directory, "..".to_string()),
+        ];
+        let entries = dots.into_iter().chain(children.into_iter().map(|child| {
+            let entry = &volume.nodes[child as usize - 1].entry;
+            let kind = if entry.is_dir() {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            (child, kind, entry.full_name())
+        }));
+        for (index, (child, kind, name)) in entries.enumerate().skip(offset as usize) {
+            if reply.add(INodeNo(child), index as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
+        let mut volume = self.volume();
+        match volume.controller.usage() {
+            Ok(usage) => reply.statfs(
+                usage.total_clusters as u64,
+                usage.free_clusters as u64,
+                usage.free_clusters as u64,
+                volume.nodes.len() as u64,
+                0,
+                usage.cluster_size as u32,
+                255,
+                usage.cluster_size as u32,
+            ),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+}
+
+/// Mounts the volume `controller` has open read-only at `mountpoint` and
+/// serves it until it is unmounted, e.g. with `fusermount -u`. Everything
+/// on the volume is owned by the owner of the mountpoint.
+pub fn mount<D: BlockDevice + Send + 'static>(
+    controller: SDController<D>,
+    mountpoint: &Path,
+) -> Result<(), SDError> {
+    let metadata = std::fs::metadata(mountpoint)?;
+    let filesystem = FuseVolume::new(controller, metadata.uid(), metadata.gid())?;
+    let mut config = Config::default();
+    config.mount_options = vec![
+        MountOption::RO,
+        MountOption::FSName("sd_controller".to_string()),
+        MountOption::Subtype("fat".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount(filesystem, mountpoint, &config)?;
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::block::BlockDevice;
+use crate::crc32::crc32;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::log::debug;
+use crate::partition::{block_index, PartitionEntry, PartitionType};
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: u32 = 92;
+const GPT_MIN_ENTRY_SIZE: u32 = 128;
+const GPT_ATTRIBUTE_LEGACY_BOOTABLE: u64 = 1 << 2;
+
+/// A GUID in its on-disk mixed-endian encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9,
+        0x3B,
+    ]);
+    pub const MICROSOFT_BASIC_DATA: Guid = Guid([
+        0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99,
+        0xC7,
+    ]);
+    pub const LINUX_FILESYSTEM: Guid = Guid([
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ]);
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for byte in &b[10..16] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Serialized in its usual text form, as `Display` writes it.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Guid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptHeader {
+    pub revision: u32,
+    pub header_size: u32,
+    pub my_lba: u64,
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    pub partition_entry_lba: u64,
+    pub num_partition_entries: u32,
+    pub partition_entry_size: u32,
+    pub partition_entries_crc32: u32,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u32_at(data, offset) as u64 | (u32_at(data, offset + 4) as u64) << 32
+}
+
+fn guid_at(data: &[u8], offset: usize) -> Guid {
+    let mut guid = [0u8; 16];
+    guid.copy_from_slice(&data[offset..offset + 16]);
+    Guid(guid)
+}
+
+impl GptHeader {
+    /// Parses and validates a header block, including its CRC32.
+    pub fn parse(block: &[u8]) -> Result<Self, SDError> {
+        if &block[0..8] != GPT_SIGNATURE {
+            return Err(SDError::parse(
+                0,
+                "GPT signature",
+                "\"EFI PART\" is missing",
+            ));
+        }
+        let header_size = u32_at(block, 12);
+        if header_size < GPT_MIN_HEADER_SIZE || header_size as usize > block.len() {
+            return Err(SDError::parse(12, "GPT header size", "out of range"));
+        }
+
+        let mut header = block[..header_size as usize].to_vec();
+        let expected = u32_at(&header, 16);
+        header[16..20].fill(0);
+        let actual = crc32(&header);
+        if expected != actual {
+            return Err(SDError::ChecksumMismatch { expected, actual });
+        }
+
+        let parsed = GptHeader {
+            revision: u32_at(block, 8),
+            header_size,
+            my_lba: u64_at(block, 24),
+            alternate_lba: u64_at(block, 32),
+            first_usable_lba: u64_at(block, 40),
+            last_usable_lba: u64_at(block, 48),
+            disk_guid: guid_at(block, 56),
+            partition_entry_lba: u64_at(block, 72),
+            num_partition_entries: u32_at(block, 80),
+            partition_entry_size: u32_at(block, 84),
+            partition_entries_crc32: u32_at(block, 88),
+        };
+        if parsed.partition_entry_size < GPT_MIN_ENTRY_SIZE
+            || !parsed.partition_entry_size.is_multiple_of(8)
+        {
+            return Err(SDError::parse(
+                84,
+                "GPT partition entry size",
+                "must be a multiple of 8 of at least 128",
+            ));
+        }
+        Ok(parsed)
+    }
+
+    fn entries_len(&self) -> usize {
+        self.num_partition_entries as usize * self.partition_entry_size as usize
+    }
+}
+
+fn parse_entry(raw: &[u8]) -> Option<PartitionEntry> {
+    let type_guid = guid_at(raw, 0);
+    if type_guid.is_zero() {
+        return None;
+    }
+    let first_lba = u64_at(raw, 32);
+    let last_lba = u64_at(raw, 40);
+    let attributes = u64_at(raw, 48);
+
+    let name_units: Vec<u16> = raw[56..128]
+        .chunks(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+
+    Some(PartitionEntry {
+        bootable: attributes & GPT_ATTRIBUTE_LEGACY_BOOTABLE != 0,
+        partition_type: PartitionType::Gpt(type_guid),
+        start_lba: first_lba,
+        sector_count: last_lba.saturating_sub(first_lba) + 1,
+        name: String::from_utf16_lossy(&name_units),
+        unique_guid: Some(guid_at(raw, 16)),
+    })
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Reads the GPT, falling back to the backup header at the end of the
+    /// device when the primary header or its entry array fails validation.
+    pub fn read_gpt(&mut self) -> Result<(GptHeader, Vec<PartitionEntry>), SDError> {
+        match self.read_gpt_at(1) {
+            Ok(gpt) => Ok(gpt),
+            Err(primary_error) => {
+                debug!(%primary_error, "primary GPT unusable, trying the backup");
+                let last_lba = self.device.num_blocks().saturating_sub(1);
+                match self.read_gpt_at(block_index(last_lba)?) {
+                    Ok(gpt) => Ok(gpt),
+                    Err(_) => Err(primary_error),
+                }
+            }
+        }
+    }
+
+    fn read_gpt_at(&mut self, lba: u32) -> Result<(GptHeader, Vec<PartitionEntry>), SDError> {
+        let header =
+            GptHeader::parse(&self.read_device_block(lba)?).map_err(|e| e.at_block(lba as u64))?;
+
+        let block_size = self.block_size();
+        let first_block = block_index(header.partition_entry_lba)?;
+        let block_count = header.entries_len().div_ceil(block_size) as u32;
+        let mut entries = Vec::with_capacity(block_count as usize * block_size);
+        for block in first_block..first_block + block_count {
+            entries.extend_from_slice(&self.read_device_block(block)?);
+        }
+        entries.truncate(header.entries_len());
+
+        let actual = crc32(&entries);
+        if actual != header.partition_entries_crc32 {
+            return Err(SDError::ChecksumMismatch {
+                expected: header.partition_entries_crc32,
+                actual,
+            }
+            .at_block(first_block as u64));
+        }
+
+        let partitions: Vec<_> = entries
+            .chunks(header.partition_entry_size as usize)
+            .filter_map(parse_entry)
+            .collect();
+        debug!(lba, partitions = partitions.len(), "parsed GPT");
+        Ok((header, partitions))
+    }
+}
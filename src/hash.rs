@@ -0,0 +1,306 @@
+use core::fmt;
+use core::ops::Range;
+use core::str::FromStr;
+use std::io::Read;
+
+use crate::block::BlockDevice;
+use crate::crc32::Crc32;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::log::debug;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes hashed between progress updates.
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// A checksum `hash_file` and `hash_device` can take. SHA-256 and BLAKE3
+/// need the `sha256` and `blake3` features; without them hashing fails
+/// with `Unsupported`, but manifests naming them still parse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum HashAlgorithm {
+    /// The IEEE CRC-32 of zip and gzip. Catches corruption, not tampering.
+    Crc32,
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 3] = [
+        HashAlgorithm::Crc32,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Blake3,
+    ];
+
+    /// The lowercase name, as `sha256sum` and `b3sum` manifests are
+    /// usually named after.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32 => "crc32",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Length of a digest in bytes.
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Crc32 => 4,
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+        }
+    }
+
+    pub(crate) fn hasher(self) -> Result<Hasher, SDError> {
+        match self {
+            HashAlgorithm::Crc32 => Ok(Hasher::Crc32(Crc32::new())),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => Ok(Hasher::Sha256(sha2::Digest::new())),
+            #[cfg(not(feature = "sha256"))]
+            HashAlgorithm::Sha256 => {
+                Err(SDError::Unsupported("SHA-256 without the `sha256` feature"))
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Ok(Hasher::Blake3(Box::new(blake3::Hasher::new()))),
+            #[cfg(not(feature = "blake3"))]
+            HashAlgorithm::Blake3 => {
+                Err(SDError::Unsupported("BLAKE3 without the `blake3` feature"))
+            }
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = SDError;
+
+    /// Takes the names `name` gives, in any case, and `sha-256` and
+    /// `crc-32` too.
+    fn from_str(name: &str) -> Result<Self, SDError> {
+        let name = name.to_ascii_lowercase().replace('-', "");
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+            .ok_or(SDError::Unsupported("hash algorithm"))
+    }
+}
+
+pub(crate) enum Hasher {
+    Crc32(Crc32),
+    #[cfg(feature = "sha256")]
+    Sha256(sha2::Sha256),
+    // Boxed for its large SIMD state.
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(crc) => crc.update(data),
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(sha) => sha2::Digest::update(sha, data),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(blake) => {
+                blake.update(data);
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        match self {
+            // Big-endian, as `crc32` tools print it.
+            Hasher::Crc32(crc) => crc.finish().to_be_bytes().to_vec(),
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(sha) => sha2::Digest::finalize(sha).to_vec(),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(blake) => blake.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// A checksum of a file or of a range of blocks. Displays as lowercase
+/// hex.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    pub(crate) algorithm: HashAlgorithm,
+    pub(crate) bytes: Vec<u8>,
+}
+
+impl Digest {
+    /// Parses a digest written in hex, in either case. `None` if it is not
+    /// hex or not the length `algorithm`'s digests are.
+    pub fn from_hex(algorithm: HashAlgorithm, hex: &str) -> Option<Digest> {
+        if !hex.is_ascii() || hex.len() != algorithm.digest_len() * 2 {
+            return None;
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Digest { algorithm, bytes })
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.bytes {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Digest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// One line of a checksum manifest in the format `sha256sum`, `b3sum` and
+/// the like write: the digest, two spaces, and the name it was taken of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumEntry {
+    pub digest: Digest,
+    pub name: String,
+}
+
+impl fmt::Display for ChecksumEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}  {}", self.digest, self.name)
+    }
+}
+
+/// Parses a checksum manifest of `algorithm` digests. Blank lines and
+/// lines starting with `#` are skipped, and a `*` before the name, which
+/// marks a file hashed in binary mode, is dropped.
+pub fn parse_checksums(
+    text: &str,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<ChecksumEntry>, SDError> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason| SDError::InvalidManifest {
+            line: index + 1,
+            reason,
+        };
+        let (hex, name) = line
+            .split_once(' ')
+            .ok_or(invalid("expected a digest and a name"))?;
+        let digest = Digest::from_hex(algorithm, hex)
+            .ok_or(invalid("not a digest of the chosen algorithm"))?;
+        let name = name.strip_prefix([' ', '*']).unwrap_or(name);
+        if name.is_empty() {
+            return Err(invalid("expected a digest and a name"));
+        }
+        entries.push(ChecksumEntry {
+            digest,
+            name: name.to_string(),
+        });
+    }
+    Ok(entries)
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Hashes the contents of the file at `path`, reporting progress in
+    /// bytes of the file.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path, %algorithm))
+    )]
+    pub fn hash_file<P: ProgressSink>(
+        &mut self,
+        path: &str,
+        algorithm: HashAlgorithm,
+        progress: &mut P,
+    ) -> Result<Digest, SDError> {
+        let mut hasher = algorithm.hasher()?;
+        let mut reader = self.open_reader(path)?;
+        let total = reader.len();
+        let stopwatch = Stopwatch::start();
+        let mut buffer = vec![0u8; CHUNK_BYTES];
+        let mut done = 0;
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            done += read as u64;
+            progress.report(&Progress {
+                phase: Phase::Hashing,
+                bytes_done: done,
+                bytes_total: total,
+                elapsed: stopwatch.elapsed(),
+            });
+        }
+        let digest = Digest {
+            algorithm,
+            bytes: hasher.finish(),
+        };
+        debug!(%digest, "hashed file");
+        Ok(digest)
+    }
+
+    /// Hashes the blocks in `range`, relative to the open partition, as an
+    /// image of them would hash. With no partition open and the whole
+    /// device as the range, the digest matches that of a `dump_image` of
+    /// the card.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(start = range.start, end = range.end, %algorithm))
+    )]
+    pub fn hash_device<P: ProgressSink>(
+        &mut self,
+        range: Range<u64>,
+        algorithm: HashAlgorithm,
+        progress: &mut P,
+    ) -> Result<Digest, SDError> {
+        if range.end > self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(range.end));
+        }
+        let mut hasher = algorithm.hasher()?;
+        let block_size = self.block_size() as u64;
+        let chunk_blocks = (CHUNK_BYTES as u64 / block_size).max(1);
+        let total = range.end.saturating_sub(range.start) * block_size;
+        let stopwatch = Stopwatch::start();
+        let mut block = range.start;
+        while block < range.end {
+            let count = chunk_blocks.min(range.end - block);
+            hasher.update(&self.read_blocks(block_index(block)?, count as u32)?);
+            block += count;
+            progress.report(&Progress {
+                phase: Phase::Hashing,
+                bytes_done: (block - range.start) * block_size,
+                bytes_total: total,
+                elapsed: stopwatch.elapsed(),
+            });
+        }
+        let digest = Digest {
+            algorithm,
+            bytes: hasher.finish(),
+        };
+        debug!(%digest, "hashed blocks");
+        Ok(digest)
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HexdumpOptions {
+    /// Bytes per line.
+    pub width: usize,
+    /// First byte of `data` to dump.
+    pub start: usize,
+    /// Number of bytes to dump; `None` dumps up to the end of `data`.
+    pub len: Option<usize>,
+    /// Added to the printed offsets, e.g. the byte address of a block on the
+    /// device.
+    pub base_offset: u64,
+}
+
+impl Default for HexdumpOptions {
+    fn default() -> Self {
+        HexdumpOptions {
+            width: 16,
+            start: 0,
+            len: None,
+            base_offset: 0,
+        }
+    }
+}
+
+/// Writes `data` in the layout of `hexdump -C`: offset, hex bytes split into
+/// groups of eight, and the printable ASCII characters.
+///
+/// ```text
+/// 00000000  eb 3c 90 4d 53 57 49 4e  34 2e 31 00 02 01 01 00  |.<.MSWIN4.1.....|
+/// ```
+pub fn write_hexdump<W: Write>(
+    writer: &mut W,
+    data: &[u8],
+    options: &HexdumpOptions,
+) -> io::Result<()> {
+    let width = options.width.max(1);
+    let start = options.start.min(data.len());
+    let end = options
+        .len
+        .map_or(data.len(), |len| start.saturating_add(len).min(data.len()));
+
+    for (line_index, line) in data[start..end].chunks(width).enumerate() {
+        let offset = options.base_offset + (start + line_index * width) as u64;
+        write!(writer, "{:08x} ", offset)?;
+        for column in 0..width {
+            if column % 8 == 0 {
+                write!(writer, " ")?;
+            }
+            match line.get(column) {
+                Some(byte) => write!(writer, "{:02x} ", byte)?,
+                None => write!(writer, "   ")?,
+            }
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(writer, " |{}|", ascii)?;
+    }
+    Ok(())
+}
+
+pub fn hexdump(data: &[u8], options: &HexdumpOptions) -> String {
+    let mut output = Vec::new();
+    write_hexdump(&mut output, data, options).expect("writing to a Vec cannot fail");
+    String::from_utf8(output).expect("hexdump output is ASCII")
+}
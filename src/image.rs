@@ -0,0 +1,313 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::block::{BlockDevice, FileDevice};
+use crate::crc32::Crc32;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::progress::{Phase, Progress, ProgressSink};
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+mod compressed;
+mod vhd;
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub use compressed::CompressedImage;
+pub use vhd::open_vhd;
+
+/// Bytes moved per device request when imaging. Large requests keep SD
+/// card readers near their sequential speed.
+const CHUNK_BYTES: u64 = 1 << 20;
+
+/// How `flash_image` checks what it wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verify {
+    None,
+    /// Read the device back and compare it with the image chunk by chunk,
+    /// which finds the first differing byte but reads the image twice.
+    ReadBack,
+    /// Compare a CRC-32 of the device contents with one taken of the image
+    /// while writing.
+    Checksum,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The container an image file is stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A plain copy of the device, such as `dd` or `dump_image` writes.
+    Raw,
+    /// A Microsoft Virtual PC disk.
+    Vhd,
+    Gzip,
+    Zstd,
+}
+
+impl ImageFormat {
+    /// Identifies an image file from its contents; the file name is not
+    /// looked at. Anything unrecognized is taken to be raw.
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<ImageFormat, SDError> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        let read = read_chunk(&mut file, &mut magic)?;
+        if magic[..read].starts_with(&ZSTD_MAGIC) {
+            return Ok(ImageFormat::Zstd);
+        }
+        if magic[..read].starts_with(&GZIP_MAGIC) {
+            return Ok(ImageFormat::Gzip);
+        }
+        if vhd::has_footer(&mut file)? {
+            return Ok(ImageFormat::Vhd);
+        }
+        Ok(ImageFormat::Raw)
+    }
+}
+
+/// Opens an image file in any format `ImageFormat::detect` recognizes.
+/// Compressed images can only be opened read-only, and only when the crate
+/// is built with the `gzip` or `zstd` feature.
+pub fn open_image<P: AsRef<Path>>(
+    path: P,
+    writable: bool,
+) -> Result<Box<dyn BlockDevice + Send>, SDError> {
+    let path = path.as_ref();
+    let format = ImageFormat::detect(path)?;
+    if writable && matches!(format, ImageFormat::Gzip | ImageFormat::Zstd) {
+        return Err(SDError::Unsupported("compressed images are read-only"));
+    }
+    Ok(match format {
+        ImageFormat::Raw if writable => Box::new(FileDevice::open_rw(path)?),
+        ImageFormat::Raw => Box::new(FileDevice::open(path)?),
+        ImageFormat::Vhd => Box::new(open_vhd(path, writable)?),
+        #[cfg(feature = "gzip")]
+        ImageFormat::Gzip => Box::new(CompressedImage::open_gzip(path)?),
+        #[cfg(not(feature = "gzip"))]
+        ImageFormat::Gzip => {
+            return Err(SDError::Unsupported("gzip images need the `gzip` feature"))
+        }
+        #[cfg(feature = "zstd")]
+        ImageFormat::Zstd => Box::new(CompressedImage::open_zstd(path)?),
+        #[cfg(not(feature = "zstd"))]
+        ImageFormat::Zstd => {
+            return Err(SDError::Unsupported("zstd images need the `zstd` feature"))
+        }
+    })
+}
+
+fn block_index(block: u64) -> Result<u32, SDError> {
+    u32::try_from(block).map_err(|_| SDError::BlockOutOfRange(block))
+}
+
+/// Fills `buffer` from `reader` as far as it can, returning the number of
+/// bytes read; less than the buffer only at the end of the input.
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, SDError> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Copies the open partition, or the whole device, into a new image
+    /// file at `dest`, which is synced to disk before returning. Returns the
+    /// number of bytes written.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn dump_image<P: ProgressSink>(
+        &mut self,
+        dest: &Path,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        let mut file = File::create(dest)?;
+        let written = self.dump_to(&mut file, progress)?;
+        file.sync_all()?;
+        Ok(written)
+    }
+
+    /// Images the FAT or exFAT volume on the open partition, or on an
+    /// unpartitioned card, reading only its reserved sectors, FATs, root
+    /// directory and allocated clusters. Free space is left as holes in a
+    /// sparse file at `dest`, which reads back as zeros, so the image is as
+    /// long as the volume and flashes like any raw one. Returns the number
+    /// of bytes read.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn dump_used_image<P: ProgressSink>(
+        &mut self,
+        dest: &Path,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        let ranges = self.used_ranges()?;
+        let block_size = self.block_size() as u64;
+        let mut file = File::create(dest)?;
+        let mut position = 0;
+        self.copy_ranges(&ranges, Phase::Reading, progress, |start, data| {
+            let offset = start * block_size;
+            if offset != position {
+                file.seek(SeekFrom::Start(offset))?;
+            }
+            file.write_all(data)?;
+            position = offset + data.len() as u64;
+            Ok(())
+        })?;
+        // Extends the file over free space at the end of the volume.
+        file.set_len(self.num_blocks() * block_size)?;
+        file.sync_all()?;
+        Ok(ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum::<u64>()
+            * block_size)
+    }
+
+    /// Streams the open partition, or the whole device, to `writer` in
+    /// chunks of 1 MiB, each starting at a multiple of the chunk size.
+    pub fn dump_to<W: Write, P: ProgressSink>(
+        &mut self,
+        writer: &mut W,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        let block_size = self.block_size() as u64;
+        let total_blocks = self.num_blocks();
+        let chunk_blocks = (CHUNK_BYTES / block_size).max(1);
+        let started = Instant::now();
+
+        let mut block = 0;
+        while block < total_blocks {
+            let count = chunk_blocks.min(total_blocks - block);
+            writer.write_all(&self.read_blocks(block_index(block)?, count as u32)?)?;
+            block += count;
+            progress.report(&Progress {
+                phase: Phase::Reading,
+                bytes_done: block * block_size,
+                bytes_total: total_blocks * block_size,
+                elapsed: started.elapsed(),
+            });
+        }
+        writer.flush()?;
+        Ok(total_blocks * block_size)
+    }
+
+    /// Writes the image file at `src` to the open partition, or the whole
+    /// device, and then verifies it as `verify` says. A final partial block
+    /// is padded with zeros. Returns the size of the image.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn flash_image<P: ProgressSink>(
+        &mut self,
+        src: &Path,
+        verify: Verify,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        let mut file = File::open(src)?;
+        let image_len = file.metadata()?.len();
+        let block_size = self.block_size() as u64;
+        let capacity = self.num_blocks() * block_size;
+        if image_len > capacity {
+            return Err(SDError::ImageTooLarge {
+                image: image_len,
+                capacity,
+            });
+        }
+
+        let chunk_len = CHUNK_BYTES.max(block_size) as usize;
+        let mut buffer = vec![0u8; chunk_len];
+        let mut crc = Crc32::new();
+        let started = Instant::now();
+        let mut done = 0u64;
+        while done < image_len {
+            let read = read_chunk(&mut file, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            crc.update(&buffer[..read]);
+            let padded = (read as u64).div_ceil(block_size) * block_size;
+            buffer[read..padded as usize].fill(0);
+            self.write_blocks(block_index(done / block_size)?, &buffer[..padded as usize])?;
+            done += read as u64;
+            progress.report(&Progress {
+                phase: Phase::Writing,
+                bytes_done: done,
+                bytes_total: image_len,
+                elapsed: started.elapsed(),
+            });
+        }
+        self.flush()?;
+
+        match verify {
+            Verify::None => {}
+            Verify::ReadBack => self.verify_read_back(src, image_len, progress)?,
+            Verify::Checksum => {
+                let expected = crc.finish();
+                let actual = self.device_crc(image_len, progress)?;
+                if actual != expected {
+                    return Err(SDError::ChecksumMismatch { expected, actual });
+                }
+            }
+        }
+        Ok(image_len)
+    }
+
+    /// Reads the first `len` bytes of the volume back in chunks, passing
+    /// each to `check` along with its offset.
+    fn read_back<P: ProgressSink>(
+        &mut self,
+        len: u64,
+        progress: &mut P,
+        mut check: impl FnMut(u64, &[u8]) -> Result<(), SDError>,
+    ) -> Result<(), SDError> {
+        let block_size = self.block_size() as u64;
+        let chunk_blocks = (CHUNK_BYTES / block_size).max(1);
+        let started = Instant::now();
+        let mut done = 0;
+        while done < len {
+            let blocks = chunk_blocks.min((len - done).div_ceil(block_size));
+            let data = self.read_blocks(block_index(done / block_size)?, blocks as u32)?;
+            let used = data.len().min((len - done) as usize);
+            check(done, &data[..used])?;
+            done += used as u64;
+            progress.report(&Progress {
+                phase: Phase::Verifying,
+                bytes_done: done,
+                bytes_total: len,
+                elapsed: started.elapsed(),
+            });
+        }
+        Ok(())
+    }
+
+    fn verify_read_back<P: ProgressSink>(
+        &mut self,
+        src: &Path,
+        len: u64,
+        progress: &mut P,
+    ) -> Result<(), SDError> {
+        let mut file = File::open(src)?;
+        let mut expected = Vec::new();
+        self.read_back(len, progress, |offset, data| {
+            expected.resize(data.len(), 0);
+            let read = read_chunk(&mut file, &mut expected)?;
+            match data[..read].iter().zip(&expected).position(|(a, b)| a != b) {
+                Some(index) => Err(SDError::VerifyFailed(offset + index as u64)),
+                None if read < data.len() => Err(SDError::VerifyFailed(offset + read as u64)),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn device_crc<P: ProgressSink>(&mut self, len: u64, progress: &mut P) -> Result<u32, SDError> {
+        let mut crc = Crc32::new();
+        self.read_back(len, progress, |_, data| {
+            crc.update(data);
+            Ok(())
+        })?;
+        Ok(crc.finish())
+    }
+}
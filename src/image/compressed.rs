@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::block::BlockDevice;
+use crate::error::SDError;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Codec {
+    /// Decodes the single gzip member or zstd frame at the start of `input`,
+    /// leaving `input` just past it.
+    fn member<'a, R: BufRead + 'a>(self, input: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            #[cfg(feature = "gzip")]
+            Codec::Gzip => Box::new(flate2::bufread::GzDecoder::new(input)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                Box::new(zstd::stream::read::Decoder::with_buffer(input)?.single_frame())
+            }
+        })
+    }
+
+    /// Decodes everything from the start of `input` to its end, across
+    /// member or frame boundaries.
+    fn stream<R: BufRead + Send + 'static>(self, input: R) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            #[cfg(feature = "gzip")]
+            Codec::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(input)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(input)?),
+        })
+    }
+}
+
+/// A place decoding can start from: the start of a gzip member or zstd
+/// frame, which does not depend on the data before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Checkpoint {
+    compressed: u64,
+    uncompressed: u64,
+}
+
+/// A read-only device backed by a compressed image, decoded on the fly.
+///
+/// Opening decodes the image once to build an index of its members (gzip)
+/// or frames (zstd). A read resumes the previous decoder when it lies
+/// ahead of it and otherwise restarts from the nearest indexed member, so
+/// images compressed in many independent pieces, as `bgzip`, `pigz -i` or
+/// `zstd -B` produce, seek quickly; images in a single piece decode from
+/// the start for every backward seek. Wrap the device in a `CachedDevice`
+/// to keep metadata from being decoded over and over.
+pub struct CompressedImage {
+    file: File,
+    codec: Codec,
+    index: Vec<Checkpoint>,
+    len: u64,
+    /// The decoder left by the last read and the uncompressed offset it
+    /// has reached.
+    stream: Option<(Box<dyn Read + Send>, u64)>,
+}
+
+impl CompressedImage {
+    /// Opens a gzip-compressed image such as `card.img.gz`.
+    #[cfg(feature = "gzip")]
+    pub fn open_gzip<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        CompressedImage::open(path.as_ref(), Codec::Gzip)
+    }
+
+    /// Opens a zstd-compressed image such as `card.img.zst`.
+    #[cfg(feature = "zstd")]
+    pub fn open_zstd<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        CompressedImage::open(path.as_ref(), Codec::Zstd)
+    }
+
+    fn open(path: &Path, codec: Codec) -> Result<Self, SDError> {
+        let file = File::open(path)?;
+        let mut input = BufReader::new(&file);
+        let mut index = Vec::new();
+        let mut len = 0;
+        while !input.fill_buf()?.is_empty() {
+            index.push(Checkpoint {
+                compressed: input.stream_position()?,
+                uncompressed: len,
+            });
+            len += io::copy(&mut codec.member(&mut input)?, &mut io::sink())?;
+        }
+        Ok(CompressedImage {
+            file,
+            codec,
+            index,
+            len,
+            stream: None,
+        })
+    }
+
+    /// Size of the decompressed image in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of points decoding can restart from.
+    pub fn checkpoints(&self) -> usize {
+        self.index.len()
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), SDError> {
+        let end = offset + buffer.len() as u64;
+        if end > self.len {
+            return Err(SDError::BlockOutOfRange((end - 1) / BLOCK_SIZE as u64));
+        }
+        let checkpoint = self.index[self.index.partition_point(|c| c.uncompressed <= offset) - 1];
+        let (mut stream, position) = match self.stream.take() {
+            Some((stream, position))
+                if position <= offset && position >= checkpoint.uncompressed =>
+            {
+                (stream, position)
+            }
+            _ => {
+                let mut file = self.file.try_clone()?;
+                file.seek(SeekFrom::Start(checkpoint.compressed))?;
+                (
+                    self.codec.stream(BufReader::new(file))?,
+                    checkpoint.uncompressed,
+                )
+            }
+        };
+
+        io::copy(
+            &mut stream.by_ref().take(offset - position),
+            &mut io::sink(),
+        )?;
+        stream.read_exact(buffer)?;
+        self.stream = Some((stream, end));
+        Ok(())
+    }
+}
+
+impl BlockDevice for CompressedImage {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.len / BLOCK_SIZE as u64
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != BLOCK_SIZE {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_at(block_index as u64 * BLOCK_SIZE as u64, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if !buffer.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_at(start as u64 * BLOCK_SIZE as u64, buffer)
+    }
+
+    fn write_block(&mut self, _block_index: u32, _data: &[u8]) -> Result<(), SDError> {
+        Err(SDError::ReadOnly)
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::block::FileDevice;
+use crate::error::SDError;
+
+/// VHD images end in a 512-byte footer; fixed-size ones hold the raw disk
+/// contents before it.
+const FOOTER_LEN: u64 = 512;
+const COOKIE: &[u8; 8] = b"conectix";
+const DISK_TYPE_FIXED: u32 = 2;
+
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads the last `FOOTER_LEN` bytes of `file`, or `None` if it is shorter.
+fn read_footer(file: &mut File) -> Result<Option<(Vec<u8>, u64)>, SDError> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < FOOTER_LEN {
+        return Ok(None);
+    }
+    let mut footer = vec![0; FOOTER_LEN as usize];
+    file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+    file.read_exact(&mut footer)?;
+    Ok(Some((footer, len)))
+}
+
+/// Whether `file` ends in a VHD footer of any disk type.
+pub(crate) fn has_footer(file: &mut File) -> Result<bool, SDError> {
+    Ok(read_footer(file)?.is_some_and(|(footer, _)| &footer[..8] == COOKIE))
+}
+
+/// Opens a fixed-size VHD image as a device covering the disk contents, with
+/// the footer out of reach of writes. Dynamic and differencing VHDs are not
+/// supported.
+pub fn open_vhd<P: AsRef<Path>>(path: P, writable: bool) -> Result<FileDevice, SDError> {
+    let mut file = OpenOptions::new().read(true).write(writable).open(path)?;
+    let (footer, len) = read_footer(&mut file)?
+        .filter(|(footer, _)| &footer[..8] == COOKIE)
+        .ok_or(SDError::InvalidImage("VHD footer is missing"))?;
+
+    // The checksum is the one's complement of the byte sum of the footer
+    // with the checksum field taken as zero.
+    let sum = footer
+        .iter()
+        .enumerate()
+        .filter(|&(offset, _)| !(64..68).contains(&offset))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(byte as u32));
+    if !sum != be_u32(&footer, 64) {
+        return Err(SDError::InvalidImage("VHD footer checksum is wrong"));
+    }
+    if be_u32(&footer, 60) != DISK_TYPE_FIXED {
+        return Err(SDError::Unsupported(
+            "only fixed-size VHD images can be opened",
+        ));
+    }
+    let data_len = len - FOOTER_LEN;
+    if be_u64(&footer, 48) != data_len {
+        return Err(SDError::InvalidImage(
+            "VHD disk size does not match the file size",
+        ));
+    }
+    FileDevice::with_len(file, 512, data_len, writable)
+}
@@ -0,0 +1,150 @@
+use std::fs::{self, File, Metadata};
+use std::io::Read;
+use std::path::Path;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{split_path, FatTimestamps};
+use crate::error::SDError;
+
+/// What `import` does with a file that already exists on the volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    /// Keep the file on the volume and record the host file as skipped.
+    #[default]
+    Skip,
+    Replace,
+    /// Replace the file only if the host copy was modified later.
+    ReplaceIfNewer,
+    /// Stop the import with `SDError::AlreadyExists`.
+    Fail,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    /// Files written to the volume, new or replacing an existing one.
+    pub files: usize,
+    /// Directories created; existing ones that were merged into are not
+    /// counted.
+    pub directories: usize,
+    pub bytes: u64,
+    /// Host paths left out: files kept by the overwrite policy, symbolic
+    /// links and special files.
+    pub skipped: Vec<String>,
+}
+
+/// The host times of `metadata` as FAT timestamps. Platforms that do not
+/// record creation or access times get the modification time instead.
+fn host_timestamps(metadata: &Metadata) -> Result<FatTimestamps, SDError> {
+    let modified = metadata.modified()?;
+    Ok(FatTimestamps::from_system_times(
+        metadata.created().unwrap_or(modified),
+        metadata.accessed().unwrap_or(modified),
+        modified,
+    ))
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Copies the host directory `src` and everything below it into the
+    /// directory `dest_dir` on the volume, keeping host timestamps. Missing
+    /// directories are created and existing ones merged into; files that
+    /// already exist are handled as `policy` says. Names that do not fit
+    /// 8.3 are stored as long names.
+    ///
+    /// Symbolic links are not followed. A host entry whose name is not valid
+    /// UTF-8, or whose type differs from the entry of the same name on the
+    /// volume, stops the import, as does running out of space.
+    pub fn import(
+        &mut self,
+        src: &Path,
+        dest_dir: &str,
+        policy: OverwritePolicy,
+    ) -> Result<ImportSummary, SDError> {
+        if !self.stat(dest_dir)?.is_dir() {
+            return Err(SDError::NotADirectory(dest_dir.to_string()));
+        }
+        if !fs::metadata(src)?.is_dir() {
+            return Err(SDError::NotADirectory(src.display().to_string()));
+        }
+        let prefix: String = split_path(dest_dir)
+            .iter()
+            .map(|component| format!("/{}", component))
+            .collect();
+        let mut summary = ImportSummary::default();
+        self.import_dir(src, &prefix, policy, &mut summary)?;
+        Ok(summary)
+    }
+
+    fn import_dir(
+        &mut self,
+        src: &Path,
+        dest: &str,
+        policy: OverwritePolicy,
+        summary: &mut ImportSummary,
+    ) -> Result<(), SDError> {
+        let mut children: Vec<_> = fs::read_dir(src)?.collect::<Result<_, _>>()?;
+        // Sorted so that generated short names do not depend on the order
+        // the host happens to list a directory in.
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            let host_path = child.path();
+            let name = child
+                .file_name()
+                .into_string()
+                .map_err(|name| SDError::InvalidName(name.to_string_lossy().into_owned()))?;
+            let path = format!("{}/{}", dest, name);
+            let metadata = fs::symlink_metadata(&host_path)?;
+            let existing = match self.stat(&path) {
+                Ok(entry) => Some(entry),
+                Err(SDError::NotFound(_)) => None,
+                Err(e) => return Err(e),
+            };
+
+            if metadata.is_dir() {
+                match existing {
+                    Some(entry) if !entry.is_dir() => return Err(SDError::NotADirectory(path)),
+                    Some(_) => {}
+                    None => {
+                        self.make_dir(&path, host_timestamps(&metadata)?)?;
+                        summary.directories += 1;
+                    }
+                }
+                self.import_dir(&host_path, &path, policy, summary)?;
+            } else if metadata.is_file() {
+                let timestamps = host_timestamps(&metadata)?;
+                if let Some(entry) = &existing {
+                    if entry.is_dir() {
+                        return Err(SDError::IsADirectory(path));
+                    }
+                    let replace = match policy {
+                        OverwritePolicy::Skip => false,
+                        OverwritePolicy::Replace => true,
+                        OverwritePolicy::ReplaceIfNewer => {
+                            timestamps.modified() > entry.timestamps.modified()
+                        }
+                        OverwritePolicy::Fail => return Err(SDError::AlreadyExists(path)),
+                    };
+                    if !replace {
+                        summary.skipped.push(host_path.display().to_string());
+                        continue;
+                    }
+                }
+                let mut file = File::open(&host_path)?;
+                let fill = |buffer: &mut [u8]| Ok(file.read_exact(buffer)?);
+                // An existing file keeps its entry, and its old contents if
+                // the new ones do not fit.
+                if existing.is_some() {
+                    self.replace_file(&path, metadata.len(), timestamps, fill)?;
+                } else {
+                    self.write_new_file(&path, metadata.len(), timestamps, fill)?;
+                }
+                summary.files += 1;
+                summary.bytes += metadata.len();
+            } else {
+                summary.skipped.push(host_path.display().to_string());
+            }
+        }
+        Ok(())
+    }
+}
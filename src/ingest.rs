@@ -0,0 +1,433 @@
+//! Topping up a backup from a card: only files an earlier run did not pull
+//! in are extracted.
+//!
+//! What was pulled in is kept in a database file, `.sd_ingest` in the
+//! destination unless given elsewhere. It holds a line per file with tab
+//! separated fields: the serial number of the volume it came from, its
+//! size, modification time and checksum, its path on the card, and where
+//! it went under the destination. A line is appended as each file is
+//! copied, so that a run cut short keeps what it finished, and the last
+//! line for a file wins.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{DirEntry, FatDateTime};
+use crate::error::SDError;
+use crate::extract::{copy_sparse, ExtractFailure, ExtractProgress};
+use crate::filter::EntryFilter;
+use crate::hash::{Digest, HashAlgorithm, Hasher};
+use crate::log::debug;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// The database's name in the destination directory.
+pub const DEFAULT_DATABASE: &str = ".sd_ingest";
+
+const HEADER: &str = "# sd_controller ingest database v1";
+
+/// A file pulled in from a card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IngestRecord {
+    /// Serial number of the volume, as eight hex digits, or empty for a
+    /// volume without one.
+    pub volume: String,
+    /// Path on the card.
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<FatDateTime>,
+    pub digest: Digest,
+    /// Where the copy went, relative to the destination.
+    pub target: PathBuf,
+}
+
+impl IngestRecord {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let volume = fields.next()?.to_string();
+        let size = fields.next()?.parse().ok()?;
+        let modified = match fields.next()? {
+            "-" => None,
+            time => Some(FatDateTime::parse(time)?),
+        };
+        let (algorithm, hex) = fields.next()?.split_once(':')?;
+        let digest = Digest::from_hex(algorithm.parse().ok()?, hex)?;
+        let path = fields.next()?.to_string();
+        let target = PathBuf::from(fields.next()?);
+        Some(IngestRecord {
+            volume,
+            path,
+            size,
+            modified,
+            digest,
+            target,
+        })
+    }
+
+    fn write_line<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let modified = self
+            .modified
+            .map_or_else(|| "-".to_string(), |time| time.to_string());
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}:{}\t{}\t{}",
+            self.volume,
+            self.size,
+            modified,
+            self.digest.algorithm(),
+            self.digest,
+            self.path,
+            self.target.display()
+        )
+    }
+
+    fn key(&self) -> (String, String) {
+        (self.volume.clone(), self.path.to_uppercase())
+    }
+}
+
+/// The files earlier runs pulled in, as recorded in a database file.
+pub struct IngestDatabase {
+    path: PathBuf,
+    records: HashMap<(String, String), IngestRecord>,
+    log: Option<File>,
+}
+
+impl IngestDatabase {
+    /// Reads the database at `path`, or starts an empty one if there is no
+    /// file there yet. Lines that cannot be read are skipped.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        let path = path.as_ref().to_path_buf();
+        let mut records = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.starts_with('#') || line.is_empty() {
+                        continue;
+                    }
+                    if let Some(record) = IngestRecord::parse(&line) {
+                        records.insert(record.key(), record);
+                    } else {
+                        debug!(line, "skipped an unreadable ingest record");
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(IngestDatabase {
+            path,
+            records,
+            log: None,
+        })
+    }
+
+    /// The record of the file at `path` on the volume with serial
+    /// `volume`, with the path compared ignoring case.
+    pub fn get(&self, volume: &str, path: &str) -> Option<&IngestRecord> {
+        self.records.get(&(volume.to_string(), path.to_uppercase()))
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &IngestRecord> {
+        self.records.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Adds or replaces a record, appending it to the file straight away.
+    pub fn insert(&mut self, record: IngestRecord) -> Result<(), SDError> {
+        if self.log.is_none() {
+            let exists = self.path.exists();
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            if !exists {
+                writeln!(file, "{HEADER}")?;
+            }
+            self.log = Some(file);
+        }
+        let log = self.log.as_mut().expect("opened above");
+        let mut line = Vec::new();
+        record.write_line(&mut line)?;
+        log.write_all(&line)?;
+        self.records.insert(record.key(), record);
+        Ok(())
+    }
+
+    /// Rewrites the file with one line per file, dropping the lines later
+    /// ones replaced.
+    pub fn compact(&mut self) -> Result<(), SDError> {
+        self.log = None;
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let mut records: Vec<&IngestRecord> = self.records.values().collect();
+        records.sort_by(|a, b| (&a.volume, &a.path).cmp(&(&b.volume, &b.path)));
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        writeln!(out, "{HEADER}")?;
+        for record in records {
+            record.write_line(&mut out)?;
+        }
+        out.into_inner().map_err(io::Error::from)?.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+/// What `ingest` pulls in and how it keeps track.
+#[derive(Debug, Clone)]
+pub struct IngestOptions {
+    /// Directory on the card to pull files from.
+    pub root: String,
+    pub filter: EntryFilter,
+    /// Checksum recorded for each file.
+    pub algorithm: HashAlgorithm,
+    /// The database; `DEFAULT_DATABASE` in the destination if `None`.
+    pub database: Option<PathBuf>,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        IngestOptions {
+            root: "/".to_string(),
+            filter: EntryFilter::default(),
+            algorithm: HashAlgorithm::default(),
+            database: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IngestSummary {
+    /// Files no earlier run pulled in.
+    pub new: usize,
+    /// Files pulled in before whose size or modification time has changed
+    /// since.
+    pub changed: usize,
+    /// Files left alone, being as recorded.
+    pub unchanged: usize,
+    /// Bytes copied.
+    pub bytes: u64,
+    pub failures: Vec<ExtractFailure>,
+}
+
+impl IngestSummary {
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A file to copy, and where to.
+struct Pending {
+    path: String,
+    entry: DirEntry,
+    target: PathBuf,
+    changed: bool,
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Copies the files below `options.root` into `dest` that the database
+    /// has no record of, or whose size or modification time differ from
+    /// the record, keeping their paths from the card. Files are told apart
+    /// by the serial number of their volume, so that several cards can be
+    /// ingested into one place.
+    ///
+    /// A changed file replaces the copy made of it before. A new file never
+    /// replaces anything: if its path is taken, by a file from another card
+    /// or one that was there already, a number is added to its name.
+    /// Files that cannot be read are recorded in the summary's failures and
+    /// picked up again by the next run.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(root = %options.root))
+    )]
+    pub fn ingest<P: ProgressSink>(
+        &mut self,
+        dest: &Path,
+        options: &IngestOptions,
+        progress: &mut P,
+    ) -> Result<IngestSummary, SDError> {
+        let volume = self
+            .volume_id()
+            .ok()
+            .flatten()
+            .map_or_else(String::new, |id| format!("{id:08X}"));
+        fs::create_dir_all(dest)?;
+        let database_path = options
+            .database
+            .clone()
+            .unwrap_or_else(|| dest.join(DEFAULT_DATABASE));
+        let mut database = IngestDatabase::open(&database_path)?;
+        let mut taken: HashSet<PathBuf> = database
+            .records()
+            .map(|record| record.target.clone())
+            .collect();
+
+        let mut summary = IngestSummary::default();
+        let mut pending = Vec::new();
+        for walked in self.walk_from(&options.root)? {
+            let walked = match walked {
+                Ok(walked) => walked,
+                Err(error) => {
+                    summary.failures.push(ExtractFailure {
+                        path: options.root.clone(),
+                        error,
+                    });
+                    continue;
+                }
+            };
+            if walked.entry.is_dir() || !options.filter.matches(&walked.entry) {
+                continue;
+            }
+            let modified = walked.entry.timestamps.modified_at();
+            let (target, changed) = match database.get(&volume, &walked.path) {
+                Some(record) if record.size == walked.entry.size && record.modified == modified => {
+                    summary.unchanged += 1;
+                    continue;
+                }
+                Some(record) => (record.target.clone(), true),
+                None => (free_target(dest, &walked.path, &mut taken), false),
+            };
+            pending.push(Pending {
+                path: walked.path,
+                entry: walked.entry,
+                target,
+                changed,
+            });
+        }
+
+        let total = pending.iter().map(|file| file.entry.size).sum();
+        let stopwatch = Stopwatch::start();
+        let mut done = 0;
+        let files_total = pending.len();
+        for (index, file) in pending.iter().enumerate() {
+            let host_path = dest.join(&file.target);
+            let before = done;
+            let result = self.ingest_file(file, &host_path, options.algorithm, |bytes| {
+                done += bytes;
+                progress.report(&Progress {
+                    phase: Phase::Extracting,
+                    bytes_done: done,
+                    bytes_total: total,
+                    elapsed: stopwatch.elapsed(),
+                });
+            });
+            let result = result.and_then(|digest| {
+                database.insert(IngestRecord {
+                    volume: volume.clone(),
+                    path: file.path.clone(),
+                    size: file.entry.size,
+                    modified: file.entry.timestamps.modified_at(),
+                    digest,
+                    target: file.target.clone(),
+                })
+            });
+            if result.is_err() {
+                let _ = fs::remove_file(&host_path);
+                done = before + file.entry.size;
+            }
+            progress.file(&ExtractProgress {
+                path: &file.path,
+                target: &host_path,
+                size: file.entry.size,
+                file: index + 1,
+                files_total,
+                error: result.as_ref().err(),
+            });
+            match result {
+                Ok(()) => {
+                    summary.bytes += file.entry.size;
+                    if file.changed {
+                        summary.changed += 1;
+                    } else {
+                        summary.new += 1;
+                    }
+                }
+                Err(error) => summary.failures.push(ExtractFailure {
+                    path: file.path.clone(),
+                    error,
+                }),
+            }
+        }
+        database.compact()?;
+        debug!(
+            new = summary.new,
+            changed = summary.changed,
+            unchanged = summary.unchanged,
+            "ingested"
+        );
+        Ok(summary)
+    }
+
+    fn ingest_file(
+        &mut self,
+        file: &Pending,
+        host_path: &Path,
+        algorithm: HashAlgorithm,
+        advance: impl FnMut(u64),
+    ) -> Result<Digest, SDError> {
+        if let Some(parent) = host_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut reader = HashingReader {
+            hasher: algorithm.hasher()?,
+            reader: self.file_reader(&file.entry)?,
+        };
+        copy_sparse(&mut reader, &file.entry, host_path, advance)?;
+        Ok(Digest {
+            algorithm,
+            bytes: reader.hasher.finish(),
+        })
+    }
+}
+
+/// Hashes what is read through it, for the database record of a file.
+struct HashingReader<R> {
+    hasher: Hasher,
+    reader: R,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Where under `dest` a new file from `path` on the card goes: the same
+/// path, or with `-1`, `-2` and so on added to the name if another file
+/// has it.
+fn free_target(dest: &Path, path: &str, taken: &mut HashSet<PathBuf>) -> PathBuf {
+    let relative: PathBuf = Path::new(path)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let stem = relative
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = relative
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let mut candidate = relative.clone();
+    let mut number = 0;
+    while taken.contains(&candidate) || dest.join(&candidate).exists() {
+        number += 1;
+        candidate.set_file_name(format!("{stem}-{number}{extension}"));
+    }
+    taken.insert(candidate.clone());
+    candidate
+}
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::block::BlockDevice;
+use crate::crc32::Crc32;
+use crate::error::SDError;
+
+const MAGIC: &[u8; 8] = b"SDJRNL01";
+/// Ends a journal, followed by the CRC-32 of everything before it.
+const COMMIT: &[u8; 8] = b"SDCOMMIT";
+
+/// Blocks a transaction may hold before `JournaledDevice` commits it of its
+/// own accord, by default.
+pub const DEFAULT_TRANSACTION_BLOCKS: usize = 16 * 1024;
+
+/// What `JournaledDevice::open` does with a transaction an earlier run
+/// committed to the journal but may not have finished writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryPolicy {
+    /// Write the transaction's blocks again, finishing it.
+    #[default]
+    Replay,
+    /// Write back what its blocks held before it began.
+    RollBack,
+}
+
+/// What opening a journal found left over from an earlier run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Recovery {
+    Replayed {
+        blocks: u64,
+    },
+    RolledBack {
+        blocks: u64,
+    },
+    /// The journal was still being written, so none of its transaction had
+    /// reached the device, and it was thrown away.
+    Discarded,
+}
+
+/// A block as a transaction found it and as it leaves it.
+struct Record {
+    block: u32,
+    old: Vec<u8>,
+    new: Vec<u8>,
+}
+
+/// Makes the writes to another device between one flush and the next a
+/// transaction, which reaches the device whole or not at all.
+///
+/// Writes are held in memory. `flush` first saves, in a journal file on
+/// the host, each written block as it was and as it is to be, then writes
+/// the blocks to the device and removes the journal. If the writing is cut
+/// short, by a crash or a card pulled out, the journal is still there when
+/// the device is next opened with it, and the transaction is finished or
+/// undone before anything else is read. That keeps a FAT, a directory and
+/// the data they describe in step: a file that was being created is there
+/// in full or not at all.
+///
+/// A transaction that grows past the limit set with
+/// `with_transaction_limit` is committed at that point, so that wiping or
+/// formatting a card does not hold it all in memory; such operations are
+/// only safe in parts.
+///
+/// Dropping the device commits what it holds, but errors are lost at that
+/// point, so callers should flush explicitly; `abort` forgets it instead.
+pub struct JournaledDevice<D: BlockDevice> {
+    inner: D,
+    path: PathBuf,
+    pending: BTreeMap<u32, Vec<u8>>,
+    limit: usize,
+    recovery: Option<Recovery>,
+}
+
+impl<D: BlockDevice> JournaledDevice<D> {
+    /// Journals writes to `inner` in the file at `path`, first recovering
+    /// as `policy` says from any transaction the file records.
+    pub fn open<P: AsRef<Path>>(
+        mut inner: D,
+        path: P,
+        policy: RecoveryPolicy,
+    ) -> Result<Self, SDError> {
+        let path = path.as_ref().to_path_buf();
+        let recovery = recover(&mut inner, &path, policy)?;
+        Ok(JournaledDevice {
+            inner,
+            path,
+            pending: BTreeMap::new(),
+            limit: DEFAULT_TRANSACTION_BLOCKS,
+            recovery,
+        })
+    }
+
+    /// Sets how many blocks a transaction may hold before it is committed
+    /// early; zero is treated as one.
+    pub fn with_transaction_limit(mut self, blocks: usize) -> Self {
+        self.limit = blocks.max(1);
+        self
+    }
+
+    /// What `open` recovered, if the journal recorded anything.
+    pub fn recovery(&self) -> Option<Recovery> {
+        self.recovery
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn journal_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Blocks written since the last commit.
+    pub fn pending_blocks(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Forgets the writes since the last commit.
+    pub fn abort(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Writes the transaction to the journal, then to the device, then
+    /// removes the journal. Fails with `Interrupted` while the journal
+    /// still records a transaction that did not finish, which reopening
+    /// the device recovers.
+    pub fn commit(&mut self) -> Result<(), SDError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.path.exists() {
+            return Err(SDError::Interrupted(self.path.display().to_string()));
+        }
+        let block_size = self.inner.block_size();
+        let mut records = Vec::with_capacity(self.pending.len());
+        for (&block, new) in &self.pending {
+            let mut old = vec![0u8; block_size];
+            self.inner.read_block(block, &mut old)?;
+            if old != *new {
+                records.push(Record {
+                    block,
+                    old,
+                    new: new.clone(),
+                });
+            }
+        }
+        if !records.is_empty() {
+            write_journal(&self.path, block_size, &records)?;
+            apply(&mut self.inner, &records, |record| &record.new)?;
+            fs::remove_file(&self.path)?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for JournaledDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        match self.pending.get(&block_index) {
+            Some(data) => {
+                buffer.copy_from_slice(data);
+                Ok(())
+            }
+            None => self.inner.read_block(block_index, buffer),
+        }
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        let block_size = self.block_size();
+        if !buffer.len().is_multiple_of(block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.inner.read_blocks(start, buffer)?;
+        let count = (buffer.len() / block_size) as u32;
+        for (&block, data) in self.pending.range(start..start.saturating_add(count)) {
+            let at = (block - start) as usize * block_size;
+            buffer[at..at + block_size].copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if block_index as u64 >= self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(block_index as u64));
+        }
+        self.pending.insert(block_index, data.to_vec());
+        if self.pending.len() >= self.limit {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        self.commit()?;
+        self.inner.flush()
+    }
+
+    /// Commits first: the blocks may still be in use until the transaction
+    /// that frees them is on the device.
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        self.commit()?;
+        self.inner.discard(start, count)
+    }
+}
+
+impl<D: BlockDevice> Drop for JournaledDevice<D> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// The file a journal is written to before it is renamed into place, so
+/// that a journal at `path` is always complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+fn write_journal(path: &Path, block_size: usize, records: &[Record]) -> Result<(), SDError> {
+    let mut bytes = Vec::with_capacity(24 + records.len() * (4 + 2 * block_size));
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(block_size as u32).to_le_bytes());
+    bytes.extend_from_slice(&(records.len() as u32).to_le_bytes());
+    for record in records {
+        bytes.extend_from_slice(&record.block.to_le_bytes());
+        bytes.extend_from_slice(&record.old);
+        bytes.extend_from_slice(&record.new);
+    }
+    let mut crc = Crc32::new();
+    crc.update(&bytes);
+    bytes.extend_from_slice(COMMIT);
+    bytes.extend_from_slice(&crc.finish().to_le_bytes());
+
+    let partial = partial_path(path);
+    let mut file = File::create(&partial)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn read_journal(path: &Path, block_size: usize) -> Result<Vec<Record>, SDError> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < 28 || &bytes[..8] != MAGIC {
+        return Err(SDError::InvalidJournal("not a write journal"));
+    }
+    let field =
+        |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+    if field(8) as usize != block_size {
+        return Err(SDError::InvalidJournal(
+            "the journal was written for another block size",
+        ));
+    }
+    let count = field(12) as usize;
+    let body = bytes.len() - 12;
+    if body != 16 + count * (4 + 2 * block_size) || &bytes[body..body + 8] != COMMIT {
+        return Err(SDError::InvalidJournal("the journal is damaged"));
+    }
+    let mut crc = Crc32::new();
+    crc.update(&bytes[..body]);
+    if crc.finish() != field(body + 8) {
+        return Err(SDError::InvalidJournal("the journal is damaged"));
+    }
+    let mut records = Vec::with_capacity(count);
+    let mut at = 16;
+    for _ in 0..count {
+        let block = field(at);
+        at += 4;
+        let old = bytes[at..at + block_size].to_vec();
+        let new = bytes[at + block_size..at + 2 * block_size].to_vec();
+        at += 2 * block_size;
+        records.push(Record { block, old, new });
+    }
+    Ok(records)
+}
+
+/// Writes the side of each record that `side` picks, then flushes.
+fn apply<D: BlockDevice>(
+    device: &mut D,
+    records: &[Record],
+    side: impl Fn(&Record) -> &Vec<u8>,
+) -> Result<(), SDError> {
+    for record in records {
+        device.write_block(record.block, side(record))?;
+    }
+    device.flush()
+}
+
+fn recover<D: BlockDevice>(
+    device: &mut D,
+    path: &Path,
+    policy: RecoveryPolicy,
+) -> Result<Option<Recovery>, SDError> {
+    let mut recovery = match fs::remove_file(partial_path(path)) {
+        Ok(()) => Some(Recovery::Discarded),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if path.exists() {
+        let records = read_journal(path, device.block_size())?;
+        let blocks = records.len() as u64;
+        recovery = Some(match policy {
+            RecoveryPolicy::Replay => {
+                apply(device, &records, |record| &record.new)?;
+                Recovery::Replayed { blocks }
+            }
+            RecoveryPolicy::RollBack => {
+                apply(device, &records, |record| &record.old)?;
+                Recovery::RolledBack { blocks }
+            }
+        });
+        fs::remove_file(path)?;
+    }
+    Ok(recovery)
+}
@@ -0,0 +1,151 @@
+use alloc::string::String;
+use alloc::vec;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{
+    decode_short_name, encode_volume_label, DirLocation, FatTimestamps, ATTR_LONG_NAME,
+    ATTR_VOLUME_ID, DIR_ENTRY_SIZE, ENTRY_DELETED, ENTRY_END,
+};
+use crate::error::SDError;
+use crate::exfat;
+use crate::fat::FatVariant;
+use crate::layout::FATLayout;
+
+/// The placeholder formatters put in the boot sector of unlabelled volumes.
+const NO_NAME: &[u8; 11] = b"NO NAME    ";
+/// Extended boot signatures: 0x29 is followed by the serial number, label
+/// and filesystem type, the older 0x28 by the serial number alone.
+const EXTENDED_BOOT_SIGNATURE: u8 = 0x29;
+const EXTENDED_BOOT_SIGNATURE_SERIAL_ONLY: u8 = 0x28;
+
+/// Offset of the extended boot signature, which the FAT32 BPB moves back to
+/// make room for its own fields.
+fn extended_boot_offset(variant: FatVariant) -> usize {
+    if variant == FatVariant::Fat32 {
+        66
+    } else {
+        38
+    }
+}
+
+/// The label field of a boot sector, if it has one.
+fn boot_sector_label(boot: &[u8], variant: FatVariant) -> Option<&[u8]> {
+    let offset = extended_boot_offset(variant);
+    (boot[offset] == EXTENDED_BOOT_SIGNATURE).then(|| &boot[offset + 5..offset + 16])
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// The volume label, or `None` if the volume has none. FAT volumes keep
+    /// the label twice, in the boot sector and as an entry in the root
+    /// directory; the entry is what Windows shows and what it updates, so
+    /// it wins when the two disagree.
+    pub fn volume_label(&mut self) -> Result<Option<String>, SDError> {
+        let layout = self.layout()?;
+        if layout.variant == FatVariant::ExFat {
+            let (_, root) =
+                self.read_dir_region(&layout, DirLocation::Cluster(layout.root_cluster))?;
+            return Ok(exfat::volume_label(&root));
+        }
+
+        if let Some((sector, offset)) = self.find_label_entry(&layout)? {
+            let block = self.read_block(sector)?;
+            return Ok(Some(decode_short_name(
+                &block[offset..offset + 11],
+                self.codepage(),
+            )));
+        }
+        let boot = self.read_block(0)?;
+        Ok(boot_sector_label(&boot, layout.variant)
+            .filter(|label| label != NO_NAME && label.iter().any(|&c| c != b' '))
+            .map(|label| decode_short_name(label, self.codepage())))
+    }
+
+    /// The volume serial number, which formatters derive from the time of
+    /// formatting. `None` for FAT volumes from before DOS 4, which have no
+    /// room for one.
+    pub fn volume_id(&mut self) -> Result<Option<u32>, SDError> {
+        let layout = self.layout()?;
+        if layout.variant == FatVariant::ExFat {
+            return Ok(Some(self.read_exfat_boot_sector()?.volume_serial_number));
+        }
+        let boot = self.read_block(0)?;
+        let offset = extended_boot_offset(layout.variant);
+        if !matches!(
+            boot[offset],
+            EXTENDED_BOOT_SIGNATURE | EXTENDED_BOOT_SIGNATURE_SERIAL_ONLY
+        ) {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes(
+            boot[offset + 1..offset + 5].try_into().unwrap(),
+        )))
+    }
+
+    /// Sets the volume label, or removes it when `label` is `None`, in both
+    /// the root directory and the boot sector (and its FAT32 backup). The
+    /// label is upper-cased and may be up to 11 characters, with the same
+    /// restrictions as short names except that spaces are allowed.
+    pub fn set_volume_label(&mut self, label: Option<&str>) -> Result<(), SDError> {
+        let layout = self.writable_layout()?;
+        let field = label.map(encode_volume_label).transpose()?;
+
+        match (self.find_label_entry(&layout)?, field) {
+            (Some((sector, offset)), Some(field)) => {
+                self.write_entry_at(sector, offset, &field)?;
+            }
+            (Some((sector, offset)), None) => {
+                self.write_entry_at(sector, offset, &[ENTRY_DELETED])?;
+            }
+            (None, Some(field)) => {
+                let timestamps = FatTimestamps::now();
+                let mut raw = [0u8; DIR_ENTRY_SIZE];
+                raw[0..11].copy_from_slice(&field);
+                raw[11] = ATTR_VOLUME_ID;
+                raw[22..24].copy_from_slice(&timestamps.modified_time.to_le_bytes());
+                raw[24..26].copy_from_slice(&timestamps.modified_date.to_le_bytes());
+                self.insert_entry(&layout, DirLocation::Root, &raw)?;
+            }
+            (None, None) => {}
+        }
+
+        let boot_field = field.unwrap_or(*NO_NAME);
+        let mut boot_sectors = vec![0];
+        if layout.variant == FatVariant::Fat32 {
+            let backup = self.read_boot_sector()?.backup_boot_sector as u32;
+            if backup != 0 {
+                boot_sectors.push(backup);
+            }
+        }
+        let offset = extended_boot_offset(layout.variant) + 5;
+        for sector in boot_sectors {
+            let boot = self.read_block(sector)?;
+            if boot_sector_label(&boot, layout.variant).is_some() {
+                self.write_entry_at(sector, offset, &boot_field)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The sector and offset of the volume label entry in the root
+    /// directory of a FAT12/16/32 volume.
+    fn find_label_entry(&mut self, layout: &FATLayout) -> Result<Option<(u32, usize)>, SDError> {
+        let (sectors, data) = self.read_dir_region(layout, DirLocation::Root)?;
+        let bytes_per_sector = layout.bytes_per_sector as usize;
+        let slot = data
+            .chunks(DIR_ENTRY_SIZE)
+            .take_while(|raw| raw[0] != ENTRY_END)
+            .position(|raw| {
+                raw[0] != ENTRY_DELETED
+                    && raw[11] & ATTR_LONG_NAME != ATTR_LONG_NAME
+                    && raw[11] & ATTR_VOLUME_ID != 0
+            });
+        Ok(slot.map(|slot| {
+            let position = slot * DIR_ENTRY_SIZE;
+            (
+                sectors[position / bytes_per_sector],
+                position % bytes_per_sector,
+            )
+        }))
+    }
+}
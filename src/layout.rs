@@ -0,0 +1,56 @@
+use crate::fat::{FATBootSector, FatVariant};
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FATLayout {
+    pub variant: FatVariant,
+    pub fat_start: u32,
+    pub fat_size: u32,
+    pub number_of_fats: u32,
+    pub root_dir_start: u32,
+    pub root_cluster: u32,
+    pub data_start: u32,
+    pub cluster_count: u32,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+}
+
+impl FATLayout {
+    pub fn new(boot_sector: &FATBootSector) -> Self {
+        let root_dir_sectors = (boot_sector.root_dir_entries as u32 * 32)
+            .div_ceil(boot_sector.bytes_per_sector as u32);
+        let fat_start = boot_sector.reserved_sectors as u32;
+        let fat_size = boot_sector.fat_size();
+        let root_dir_start = fat_start + (boot_sector.number_of_fats as u32 * fat_size);
+        let data_start = root_dir_start + root_dir_sectors;
+        let cluster_count = boot_sector.total_sectors().saturating_sub(data_start)
+            / boot_sector.sectors_per_cluster as u32;
+
+        FATLayout {
+            variant: FatVariant::from_cluster_count(cluster_count),
+            fat_start,
+            fat_size,
+            number_of_fats: boot_sector.number_of_fats as u32,
+            root_dir_start,
+            root_cluster: boot_sector.root_cluster,
+            data_start,
+            cluster_count,
+            bytes_per_sector: boot_sector.bytes_per_sector as u32,
+            sectors_per_cluster: boot_sector.sectors_per_cluster as u32,
+        }
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        (self.bytes_per_sector * self.sectors_per_cluster) as usize
+    }
+
+    /// First sector of a data cluster. Cluster numbering starts at 2.
+    pub fn cluster_to_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    /// Whether `cluster` addresses an existing cluster of the data area.
+    pub fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+}
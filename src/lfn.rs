@@ -0,0 +1,157 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::dir::{ATTR_LONG_NAME, DIR_ENTRY_SIZE};
+use crate::error::SDError;
+
+pub const LFN_CHARS_PER_ENTRY: usize = 13;
+pub const LFN_LAST_ENTRY: u8 = 0x40;
+pub const LFN_SEQUENCE_MASK: u8 = 0x1F;
+/// Longest long name, in UTF-16 code units.
+pub const LFN_MAX_UNITS: usize = 255;
+
+/// Byte offsets of the 13 UTF-16 code units stored in one LFN entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Checksum of an 11-byte short name, stored in each of its LFN entries.
+pub fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .take(11)
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn entry_chars(raw: &[u8]) -> [u16; LFN_CHARS_PER_ENTRY] {
+    let mut chars = [0u16; LFN_CHARS_PER_ENTRY];
+    for (char, &offset) in chars.iter_mut().zip(LFN_CHAR_OFFSETS.iter()) {
+        *char = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+    }
+    chars
+}
+
+/// Decodes the UTF-16 of a long name up to its 0x0000 terminator, or up to
+/// the 0xFFFF padding that some writers leave without one. Characters
+/// outside the BMP arrive as surrogate pairs; a surrogate without its other
+/// half comes out as U+FFFD.
+fn decode_long_name(units: impl Iterator<Item = u16>) -> String {
+    let units = units.take_while(|&unit| unit != 0x0000 && unit != 0xFFFF);
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Builds the LFN entries for `long_name` in on-disk order, last fragment
+/// first, tied by checksum to the 11-byte `short_name` that must follow
+/// them. The final fragment is terminated with 0x0000 and padded with
+/// 0xFFFF.
+pub(crate) fn long_name_entries(
+    long_name: &str,
+    short_name: &[u8],
+) -> Result<Vec<[u8; DIR_ENTRY_SIZE]>, SDError> {
+    let units: Vec<u16> = long_name.encode_utf16().collect();
+    if units.is_empty() || units.len() > LFN_MAX_UNITS {
+        return Err(SDError::InvalidName(long_name.to_string()));
+    }
+    let checksum = lfn_checksum(short_name);
+    let fragments = units.len().div_ceil(LFN_CHARS_PER_ENTRY);
+
+    let mut entries = Vec::with_capacity(fragments);
+    for index in (0..fragments).rev() {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[0] = (index + 1) as u8;
+        if index == fragments - 1 {
+            raw[0] |= LFN_LAST_ENTRY;
+        }
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        for (position, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            let unit = match (index * LFN_CHARS_PER_ENTRY + position).cmp(&units.len()) {
+                core::cmp::Ordering::Less => units[index * LFN_CHARS_PER_ENTRY + position],
+                core::cmp::Ordering::Equal => 0x0000,
+                core::cmp::Ordering::Greater => 0xFFFF,
+            };
+            raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.push(raw);
+    }
+    Ok(entries)
+}
+
+/// Collects the LFN entries preceding a short entry. They are stored last
+/// fragment first, each numbered, and all carry the short name's checksum.
+#[derive(Debug, Default)]
+pub(crate) struct LongNameBuilder {
+    fragments: Vec<[u16; LFN_CHARS_PER_ENTRY]>,
+    checksum: u8,
+    next_sequence: u8,
+    /// Byte offset of the first LFN entry within the directory region.
+    pub start: Option<usize>,
+}
+
+impl LongNameBuilder {
+    pub fn reset(&mut self) {
+        self.fragments.clear();
+        self.next_sequence = 0;
+        self.start = None;
+    }
+
+    pub fn push(&mut self, offset: usize, raw: &[u8]) {
+        let sequence = raw[0] & LFN_SEQUENCE_MASK;
+        let checksum = raw[13];
+
+        if raw[0] & LFN_LAST_ENTRY != 0 {
+            self.reset();
+            if sequence == 0 {
+                return;
+            }
+            self.checksum = checksum;
+            self.start = Some(offset);
+        } else if sequence == 0 || sequence != self.next_sequence || checksum != self.checksum {
+            self.reset();
+            return;
+        }
+        self.fragments.push(entry_chars(raw));
+        self.next_sequence = sequence - 1;
+    }
+
+    /// Returns the assembled name if a complete, matching sequence precedes
+    /// the short entry `short_name`. The builder is reset either way.
+    pub fn finish(&mut self, short_name: &[u8]) -> Option<String> {
+        let complete = !self.fragments.is_empty() && self.next_sequence == 0;
+        let name = if complete && lfn_checksum(short_name) == self.checksum {
+            Some(decode_long_name(
+                self.fragments.iter().rev().flatten().copied(),
+            ))
+        } else {
+            None
+        };
+        self.fragments.clear();
+        self.next_sequence = 0;
+        name
+    }
+}
+
+/// Reassembles the long name of a deleted file from its LFN entries, given
+/// in on-disk order (last fragment first), and the deleted short name.
+///
+/// Deletion overwrites the sequence number of each LFN entry and the first
+/// byte of the short name. The checksum determines that byte uniquely, so
+/// it is returned alongside the name; `None` means the entries do not share
+/// a checksum or do not belong to this short name.
+#[cfg(feature = "std")]
+pub(crate) fn deleted_long_name(entries: &[&[u8]], short_name: &[u8]) -> Option<(String, u8)> {
+    let checksum = entries.first()?[13];
+    if entries.iter().any(|raw| raw[13] != checksum) {
+        return None;
+    }
+    let mut candidate = [0u8; 11];
+    candidate.copy_from_slice(&short_name[..11]);
+    let first_byte = (0..=u8::MAX).find(|&byte| {
+        candidate[0] = byte;
+        lfn_checksum(&candidate) == checksum
+    })?;
+
+    let units = entries.iter().rev().flat_map(|raw| entry_chars(raw));
+    Some((decode_long_name(units), first_byte))
+}
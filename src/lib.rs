@@ -0,0 +1,239 @@
+//! Reads and writes FAT12/16/32 and exFAT volumes on SD cards and disk
+//! images.
+//!
+//! Without the default `std` feature the crate is `no_std` and needs only
+//! `alloc`: the `BlockDevice` trait and the filesystem code of
+//! `SDController` remain, and the `embedded` feature adds a driver for cards
+//! on an SPI bus. Everything that touches the host, such as device nodes,
+//! image files and host directories, needs `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "forensic")]
+pub mod acquire;
+pub mod allocation;
+#[cfg(feature = "tokio")]
+pub mod async_controller;
+pub mod attributes;
+#[cfg(feature = "std")]
+pub mod bench;
+pub mod block;
+pub mod boot;
+pub mod bpb;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
+pub mod capacity;
+#[cfg(feature = "std")]
+pub mod carve;
+#[cfg(feature = "std")]
+pub mod check;
+#[cfg(feature = "std")]
+pub mod clone;
+pub mod codepage;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod crc32;
+#[cfg(feature = "std")]
+pub mod dedupe;
+#[cfg(feature = "std")]
+pub mod defrag;
+pub mod device;
+#[cfg(feature = "std")]
+pub mod diff;
+pub mod dir;
+pub mod dirty;
+#[cfg(feature = "std")]
+pub mod discover;
+#[cfg(feature = "std")]
+pub mod dry_run;
+#[cfg(feature = "embedded")]
+pub mod embedded;
+pub mod error;
+pub mod exfat;
+#[cfg(feature = "std")]
+pub mod extract;
+pub mod fat;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod format;
+#[cfg(all(feature = "fuse", unix))]
+pub mod fuse;
+pub mod gpt;
+#[cfg(feature = "std")]
+pub mod hash;
+#[cfg(feature = "std")]
+pub mod hexdump;
+#[cfg(feature = "std")]
+pub mod image;
+#[cfg(feature = "std")]
+pub mod import;
+#[cfg(feature = "std")]
+pub mod ingest;
+#[cfg(feature = "std")]
+pub mod journal;
+pub mod label;
+pub mod layout;
+pub mod lfn;
+mod log;
+#[cfg(feature = "std")]
+pub mod manifest;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "std")]
+pub mod nbd;
+#[cfg(feature = "std")]
+pub mod overlay;
+pub mod owner;
+pub mod partition;
+pub mod progress;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod raw;
+#[cfg(feature = "std")]
+pub mod reader;
+#[cfg(feature = "std")]
+pub mod recover;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "std")]
+pub mod repair;
+#[cfg(feature = "std")]
+pub mod report;
+#[cfg(feature = "std")]
+pub mod scan;
+pub mod sdinfo;
+#[cfg(feature = "std")]
+pub mod search;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod throttle;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod usage;
+pub mod walk;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "std")]
+pub mod watch;
+#[cfg(all(feature = "std", windows))]
+mod windows;
+#[cfg(feature = "std")]
+pub mod wipe;
+pub mod write;
+
+#[cfg(feature = "forensic")]
+pub use acquire::{verify_audit_log, AcquireOptions, AcquisitionReport, AuditLog};
+pub use allocation::{AllocationMap, ClusterState, MapOptions};
+#[cfg(feature = "tokio")]
+pub use async_controller::{AsyncFatFileReader, AsyncSDController};
+pub use attributes::Attributes;
+#[cfg(feature = "std")]
+pub use bench::{bench, BenchOptions, BenchPattern, BenchReport};
+#[cfg(feature = "std")]
+pub use block::FileDevice;
+pub use block::{BlockDevice, MemBlockDevice, ReadOnlyDevice};
+pub use boot::BootSectorCopy;
+pub use bpb::{Bpb, BpbChange, BpbField};
+#[cfg(feature = "std")]
+pub use cache::CachedDevice;
+#[cfg(feature = "std")]
+pub use capacity::{Alias, CapacityReport, CapacityTest};
+#[cfg(feature = "std")]
+pub use carve::{CarveKind, CarveOptions, CarvedFile};
+#[cfg(feature = "std")]
+pub use check::FsIssue;
+#[cfg(feature = "std")]
+pub use clone::{CloneOptions, CloneReport};
+pub use codepage::Codepage;
+#[cfg(feature = "config")]
+pub use config::{Config, Safety};
+#[cfg(feature = "std")]
+pub use dedupe::{DedupeOptions, DedupeReport, DuplicateSet};
+#[cfg(feature = "std")]
+pub use defrag::{DefragJournal, DefragReport, JournalState};
+pub use device::{ReadOnlyController, SDController};
+#[cfg(feature = "std")]
+pub use diff::{BlockDiff, ChangedFile, FileChange, FileDiff};
+pub use dir::{DirEntry, DirIter, DirLocation, FatDateTime, FatTimestamps};
+pub use dirty::DirtyFlags;
+#[cfg(feature = "std")]
+pub use discover::{discover, is_system_disk, DeviceInfo};
+#[cfg(feature = "std")]
+pub use dry_run::{DryRunDevice, PlannedWrite, WriteKind};
+#[cfg(feature = "embedded")]
+pub use embedded::SpiSdCard;
+pub use error::{ErrorContext, Operation, SDError};
+pub use exfat::ExFatBootSector;
+#[cfg(feature = "std")]
+pub use extract::{host_path, ExtractFailure, ExtractProgress, ExtractSummary};
+pub use fat::{ClusterChain, FATBootSector, FatEntry, FatIter, FatTable, FatVariant, TableChain};
+pub use filter::EntryFilter;
+pub use format::FormatOptions;
+pub use gpt::Guid;
+#[cfg(feature = "std")]
+pub use hash::{parse_checksums, ChecksumEntry, Digest, HashAlgorithm};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub use image::CompressedImage;
+#[cfg(feature = "std")]
+pub use image::{open_image, open_vhd, ImageFormat, Verify};
+#[cfg(feature = "std")]
+pub use import::{ImportSummary, OverwritePolicy};
+#[cfg(feature = "std")]
+pub use ingest::{IngestDatabase, IngestOptions, IngestRecord, IngestSummary};
+#[cfg(feature = "std")]
+pub use journal::{JournaledDevice, Recovery, RecoveryPolicy};
+pub use layout::FATLayout;
+#[cfg(feature = "std")]
+pub use manifest::{Manifest, ManifestEntry, ManifestMismatch, ManifestProblem, ManifestReport};
+#[cfg(feature = "mmap")]
+pub use mmap::MmapDevice;
+#[cfg(feature = "std")]
+pub use nbd::{NbdOptions, NbdStats};
+#[cfg(feature = "std")]
+pub use overlay::OverlayDevice;
+pub use owner::{BlockOwner, BlockOwners};
+pub use partition::{
+    DiskLayout, NewPartition, PartitionEntry, PartitionScheme, PartitionTable, PartitionType,
+};
+#[cfg(feature = "std")]
+pub use progress::TerminalProgress;
+pub use progress::{Phase, Progress, ProgressSink};
+#[cfg(feature = "std")]
+pub use raw::{open_raw_device, MountPoint, RawOptions};
+#[cfg(feature = "std")]
+pub use reader::FatFileReader;
+#[cfg(feature = "std")]
+pub use recover::{DeletedEntry, Recoverability};
+#[cfg(feature = "remote")]
+pub use remote::{RemoteDevice, ServeOptions, Server};
+#[cfg(feature = "std")]
+pub use repair::{RepairAction, RepairOptions};
+#[cfg(feature = "std")]
+pub use report::{Finding, Report, Severity};
+#[cfg(feature = "std")]
+pub use scan::{BlockLatency, LatencyStats, RecoveredBlock, ScanOptions, ScanReport};
+#[cfg(feature = "std")]
+pub use sdinfo::read_sd_info;
+pub use sdinfo::{Cid, Csd, Scr, SdInfo, SpeedRatings};
+#[cfg(feature = "std")]
+pub use search::{SearchHit, SearchOptions, SearchPattern};
+#[cfg(feature = "std")]
+pub use testing::{FatImageBuilder, Fault, FaultyDevice};
+#[cfg(feature = "std")]
+pub use throttle::ThrottledDevice;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use uring::UringDevice;
+pub use usage::{FsInfo, Usage};
+pub use walk::{Walk, WalkEntry};
+#[cfg(feature = "std")]
+pub use watch::{DeviceEvent, DeviceWatcher};
+#[cfg(feature = "std")]
+pub use wipe::{WipeOptions, WipePass};
@@ -0,0 +1,23 @@
+//! Diagnostics through `tracing`. With the `tracing` feature off these
+//! macros expand to nothing, so the arguments must not be the only use of a
+//! variable.
+//!
+//! Every device request is a `trace` event and every structure parsed off
+//! the card a `debug` event; the CLI prints them according to `RUST_LOG`,
+//! e.g. `RUST_LOG=sd_controller=trace`.
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)*);
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+pub(crate) use {debug, trace};
@@ -1,154 +1,3309 @@
-use std::alloc::Layout;
 use std::fs::File;
-use std::path::Path;
-use std::io::{Read, Seek, SeekFrom};
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum SDError {
-    #[error("IO error: {0}")]
-    IO(#[from] std::io::Error),
-    #[error("Device not found")]
-    DeviceNotFound,
-    #[error("Invalid block size")]
-    InvalidBlockSize,
-    #[error("Read error: expected {expected} bytes got {actual}")]
-    ReadError { expected: usize, actual: usize},
-}
-
-#[derive(Debug)]
-pub struct FATBootSector {
-    bytes_per_sector: u16,
-    sectors_per_cluster: u8,
-    reserved_sectors: u16,
-    number_of_fats: u8,
-    root_dir_entries: u16,
-    total_sectors_16: u16,
-    media_descriptor: u8,
-    sectors_per_fat: u16,
-    total_sectors_32: u32,
-}
-
-#[derive(Debug)]
-pub struct FATLayout {
-    fat_start: u32,
-    root_dir_start: u32,
-    data_start: u32,
-}
-
-pub struct SDController{
-    device: File,
-    block_size: usize,
-}
-
-impl SDController{
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, SDError>{
-        let device = File::open(path)?;
-        Ok(SDController { device, block_size: 512})
-    }
-    pub fn read_block(&mut self, block_index: u32) -> Result<Vec<u8>, SDError> {
-        let mut buffer = vec![0; self.block_size];
-
-        let position = block_index as u64 * self.block_size as u64;
-
-        self.device.seek(SeekFrom::Start(position))?;
-
-        let bytes_read = self.device.read(&mut buffer)?;
-        if bytes_read != self.block_size {
-            return Err(SDError::ReadError {
-                expected: self.block_size,
-                actual: bytes_read,
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use sd_controller::{
+    bench, boot, discover, exfat,
+    hexdump::{write_hexdump, HexdumpOptions},
+    host_path, is_system_disk, open_image, open_raw_device, parse_checksums,
+    progress::format_size,
+    read_sd_info, remote, verify_audit_log, AcquireOptions, Attributes, AuditLog, BenchOptions,
+    BenchPattern, BlockDevice, BlockDiff, BlockOwner, BlockOwners, BootSectorCopy, Bpb, BpbField,
+    CapacityTest, CarveKind, CarveOptions, CloneOptions, ClusterState, Codepage, Config,
+    DedupeOptions, DeviceEvent, DeviceInfo, DeviceWatcher, DirEntry, DirtyFlags, DiskLayout,
+    DryRunDevice, EntryFilter, ExFatBootSector, FATBootSector, FATLayout, FatDateTime,
+    FatTimestamps, FatVariant, FileChange, FileDevice, FileDiff, FormatOptions, FsInfo,
+    HashAlgorithm, ImageFormat, IngestOptions, JournaledDevice, Manifest, ManifestProblem,
+    MapOptions, MmapDevice, NbdOptions, NewPartition, OverlayDevice, OverwritePolicy,
+    PartitionTable, RawOptions, ReadOnlyController, ReadOnlyDevice, Recoverability, Recovery,
+    RecoveryPolicy, RemoteDevice, RepairOptions, Report, SDController, SDError, ScanOptions,
+    SearchOptions, SearchPattern, ServeOptions, Server, TerminalProgress, ThrottledDevice, Verify,
+    WipeOptions, WipePass,
+};
+use serde::Serialize;
+use tracing_subscriber::EnvFilter;
+
+/// Controllers over device nodes as well as raw, compressed and VHD images.
+type Controller = SDController<Box<dyn BlockDevice + Send>>;
+
+#[derive(Parser)]
+#[command(version, about = "Inspect FAT and exFAT SD cards and disk images")]
+struct Cli {
+    /// Partition to open, as listed by `info`. Defaults to the first
+    /// partition when block 0 does not hold a filesystem.
+    #[arg(long, short, global = true)]
+    partition: Option<usize>,
+
+    /// Device block size in bytes. Defaults to 512.
+    #[arg(long, global = true)]
+    block_size: Option<usize>,
+
+    /// Code page the short names were written in, e.g. `cp850` or `cp932`.
+    /// Defaults to cp437.
+    #[arg(long, global = true)]
+    codepage: Option<Codepage>,
+
+    /// Unmount the device's volumes before opening it.
+    #[arg(long, global = true)]
+    unmount: bool,
+
+    /// Bypass the OS page cache, so that benchmarks and capacity tests
+    /// measure the card rather than host memory.
+    #[arg(long, global = true)]
+    direct: bool,
+
+    /// Read and write through io_uring, keeping several requests in flight
+    /// for the card reader.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[arg(long, global = true)]
+    io_uring: bool,
+
+    /// Open the device read-only and keep every write in this file
+    /// instead, created if need be, so that a command can be tried out
+    /// first. `overlay diff` shows what it changed and `overlay commit`
+    /// writes it to the device.
+    #[arg(long, global = true, value_name = "FILE")]
+    overlay: Option<PathBuf>,
+
+    /// Journal writes in this file, so that each step of a command reaches
+    /// the device whole or not at all. A step left unfinished by a crash is
+    /// finished the next time the device is opened with the same journal.
+    #[arg(long, global = true, value_name = "FILE")]
+    write_journal: Option<PathBuf>,
+
+    /// Read and write the device no faster than this, such as `10MB/s`,
+    /// so that a long image, scan or wipe leaves bandwidth for the other
+    /// devices on a shared USB hub.
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_rate)]
+    limit: Option<u64>,
+
+    /// Change nothing: log each write a command would make, and what it
+    /// would change on the filesystem, instead of making it.
+    #[arg(long, global = true, conflicts_with = "overlay")]
+    dry_run: bool,
+
+    /// Read the device through the `serve` command running at this URL,
+    /// such as `http://pi.local:7878`, naming it as the server does.
+    #[arg(long, global = true, value_name = "URL")]
+    remote: Option<String>,
+
+    /// File holding the token `serve` and `--remote` authenticate with.
+    /// Defaults to the SDCTL_TOKEN environment variable.
+    #[arg(long, global = true, value_name = "FILE")]
+    token_file: Option<PathBuf>,
+
+    /// Read settings from this file instead of
+    /// ~/.config/sd_controller/config.toml.
+    #[arg(long = "config", global = true, value_name = "FILE")]
+    config_file: Option<PathBuf>,
+
+    /// Loaded from `config_file` once the arguments are parsed.
+    #[arg(skip)]
+    config: Config,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+impl Cli {
+    fn block_size(&self) -> usize {
+        self.block_size.or(self.config.block_size).unwrap_or(512)
+    }
+
+    fn codepage(&self) -> Codepage {
+        self.codepage.or(self.config.codepage).unwrap_or_default()
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the partition table, boot sector and filesystem layout.
+    Info {
+        device: PathBuf,
+        /// Print JSON instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the filesystem for consistency without modifying it. Exits
+    /// with status 1 if problems were found.
+    Check {
+        device: PathBuf,
+        /// Print a machine-readable report instead of text.
+        #[arg(long, value_enum)]
+        report: Option<ReportFormat>,
+    },
+    /// Repair FAT copy mismatches, broken chains, lost clusters and wrong
+    /// file sizes.
+    Repair {
+        device: PathBuf,
+        /// Print what would change without writing anything.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List deleted files and how much of each survives, or recover them
+    /// into a local directory.
+    Recover {
+        device: PathBuf,
+        /// Directory to recover the files into.
+        dest: Option<PathBuf>,
+    },
+    /// Find files by their headers alone, for cards whose filesystem is
+    /// destroyed. Scans the whole device, or the partition given with
+    /// `--partition`.
+    Carve {
+        device: PathBuf,
+        /// Directory to write the files found into; they are only listed
+        /// without it.
+        dest: Option<PathBuf>,
+        /// Kinds of files to look for; all by default.
+        #[arg(long = "kind", value_enum)]
+        kinds: Vec<CarveType>,
+        /// Also write files whose end was not found.
+        #[arg(long)]
+        partial: bool,
+    },
+    /// Show which file or directory, or which part of the filesystem,
+    /// blocks belong to. Blocks are counted as `scan` counts them: from the
+    /// start of the device, or of the partition given with `--partition`.
+    Owner {
+        device: PathBuf,
+        #[arg(required = true)]
+        blocks: Vec<u64>,
+        /// Print JSON instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Search every block of the device for a string, hex bytes or a
+    /// regular expression, like `grep` for the raw card. Hits are listed by
+    /// block and byte offset, with the file they lie in when the volume can
+    /// be read.
+    Search {
+        device: PathBuf,
+        pattern: String,
+        /// Take the pattern as hex bytes, e.g. `FFD8FFE1`.
+        #[arg(long, conflicts_with = "regex")]
+        hex: bool,
+        /// Take the pattern as a regular expression over bytes; start it
+        /// with `(?-u)` to match binary data.
+        #[arg(long, short = 'E')]
+        regex: bool,
+        /// Stop after this many hits.
+        #[arg(long, short = 'm')]
+        max_hits: Option<usize>,
+        /// Longest regular expression match found across chunk boundaries.
+        #[arg(long, value_parser = parse_size, default_value = "4K")]
+        overlap: u64,
+        /// Print JSON instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show total, used and free space.
+    Df { device: PathBuf },
+    /// Draw which clusters are used, free and bad, as text or as a PNG
+    /// image, to show fragmentation and where data lies on the card.
+    Map {
+        device: PathBuf,
+        /// Write a PNG image here instead of printing the map.
+        #[arg(long)]
+        png: Option<PathBuf>,
+        /// Cells per row.
+        #[arg(long, default_value_t = 64)]
+        columns: usize,
+        /// Clusters per cell. Defaults to fitting the map in 32 rows.
+        #[arg(long)]
+        per_cell: Option<usize>,
+        /// Pixels per side of a cell in the PNG image.
+        #[arg(long, default_value_t = 8)]
+        scale: u32,
+    },
+    /// List a directory.
+    Ls {
+        device: PathBuf,
+        #[arg(default_value = "/")]
+        path: String,
+        /// Print JSON instead of text.
+        #[arg(long)]
+        json: bool,
+        /// Include hidden and system files.
+        #[arg(long, short)]
+        all: bool,
+        /// Directories are listed whatever the filter says.
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Write a file to standard output.
+    Cat { device: PathBuf, path: String },
+    /// Dump a raw device block.
+    Hexdump {
+        device: PathBuf,
+        block: u32,
+        /// Number of blocks to dump.
+        #[arg(long, short = 'n', default_value_t = 1)]
+        count: u32,
+        /// Bytes per line.
+        #[arg(long, short, default_value_t = 16)]
+        width: usize,
+        /// Byte offset into the dumped blocks to start at.
+        #[arg(long, default_value_t = 0)]
+        skip: usize,
+        /// Number of bytes to dump.
+        #[arg(long)]
+        length: Option<usize>,
+    },
+    /// Copy the whole device, or the partition given with `--partition`, to
+    /// an image file.
+    Dump {
+        device: PathBuf,
+        dest: PathBuf,
+        /// Read only the blocks the filesystem uses and leave free space as
+        /// holes in a sparse image. Images only the volume: the partition
+        /// holding it, unless the card is unpartitioned.
+        #[arg(long)]
+        used_only: bool,
+    },
+    /// Image the whole device for evidence: read-only, hashed while it is
+    /// read, with every step recorded in a signed audit log and a report
+    /// written at the end. Exits with status 1 if the verifying read hashes
+    /// differently.
+    Acquire {
+        device: PathBuf,
+        image: PathBuf,
+        /// The audit log, in JSON Lines. Defaults to the image's name with
+        /// `.audit.jsonl` added.
+        #[arg(long)]
+        log: Option<PathBuf>,
+        /// The acquisition report, in JSON. Defaults to the image's name
+        /// with `.report.json` added.
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// Digests to take, and repeat to take several. crc32, sha256 or
+        /// blake3.
+        #[arg(long = "hash", default_value = "sha256")]
+        algorithms: Vec<HashAlgorithm>,
+        /// File holding the key the log is signed with.
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+        #[arg(long)]
+        case: Option<String>,
+        #[arg(long)]
+        examiner: Option<String>,
+        /// Skip reading the device a second time to check it.
+        #[arg(long)]
+        no_verify: bool,
+    },
+    /// Check the signatures of an audit log written by `acquire`.
+    Audit {
+        log: PathBuf,
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// Export devices read-only over HTTP, for `--remote` on another
+    /// machine. Runs until interrupted.
+    Serve {
+        #[arg(required = true)]
+        devices: Vec<PathBuf>,
+        /// Address to listen on; use 0.0.0.0 to accept other machines.
+        #[arg(long, default_value = remote::DEFAULT_ADDRESS)]
+        listen: String,
+    },
+    /// Export the device, or the partition given with `--partition`, over
+    /// the network block device protocol, serving one client at a time
+    /// until interrupted. NBD has no authentication, so listen only where
+    /// trusted hosts can connect.
+    Nbd {
+        device: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:10809")]
+        listen: String,
+        /// The export name clients ask for.
+        #[arg(long, default_value = "sd")]
+        name: String,
+        /// Let clients write to the device.
+        #[arg(long)]
+        writable: bool,
+        /// Export for writing even if the device looks like a fixed system
+        /// disk.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write an image file to the device, or to the partition given with
+    /// `--partition`, and verify it.
+    Flash {
+        device: PathBuf,
+        image: PathBuf,
+        #[arg(long, value_enum, default_value_t = VerifyMode::ReadBack)]
+        verify: VerifyMode,
+        /// Write even if the device looks like a fixed system disk.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print checksums of files on the card, or of the whole device (or
+    /// the partition given with `--partition`) if no path is given, in the
+    /// format `sha256sum` writes.
+    Hash {
+        device: PathBuf,
+        paths: Vec<String>,
+        /// crc32, sha256 or blake3.
+        #[arg(long, default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+    },
+    /// Hash the files on the volume and list those with the same contents
+    /// under different paths, largest waste first.
+    DedupeScan {
+        device: PathBuf,
+        #[arg(default_value = "/")]
+        root: String,
+        /// Leave out files smaller than this many bytes; K, M and G
+        /// suffixes count in 1024s.
+        #[arg(long, value_parser = parse_size, default_value = "1")]
+        min_size: u64,
+        /// Leave out files larger than this.
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+        #[arg(long, default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+        /// Print JSON instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the card against a checksum manifest as `hash` or `sha256sum`
+    /// writes it. Names starting with `/` are files on the card; any other
+    /// name, such as that of the image a card was flashed from, stands for
+    /// the whole device, or the partition given with `--partition`.
+    Verify {
+        device: PathBuf,
+        manifest: PathBuf,
+        #[arg(long, default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+    },
+    /// Compare two devices or images block by block, or with `--files`
+    /// file by file. With `--partition`, the same partition of each is
+    /// compared. Exits with status 1 if they differ.
+    Diff {
+        first: PathBuf,
+        second: PathBuf,
+        #[arg(long)]
+        files: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show or write out the changes kept in a file by `--overlay`.
+    Overlay {
+        #[command(subcommand)]
+        action: OverlayAction,
+    },
+    /// Record the size, modification time and checksum of every file in a
+    /// JSON manifest, or check the card against one.
+    Manifest {
+        #[command(subcommand)]
+        action: ManifestAction,
+    },
+    /// Show the fields of the boot sector's BIOS parameter block, or change
+    /// them.
+    Bpb {
+        #[command(subcommand)]
+        action: BpbAction,
+    },
+    /// Save the reserved region of the volume (the boot sector, FSInfo and
+    /// backup boot sector) to a file, or write a saved one back.
+    Reserved {
+        #[command(subcommand)]
+        action: ReservedAction,
+    },
+    /// Copy one device onto another and verify the copy. With
+    /// `--partition`, the same partition of each is copied.
+    Clone {
+        source: PathBuf,
+        dest: PathBuf,
+        /// Copy only the blocks the filesystem uses, leaving free space.
+        #[arg(long)]
+        used_only: bool,
+        #[arg(long, value_enum, default_value_t = VerifyMode::ReadBack)]
+        verify: VerifyMode,
+        /// Write even if the destination looks like a fixed system disk.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Overwrite the device, the partition given with `--partition`, or
+    /// the free space of its volume. Asks for the device path to be typed
+    /// back unless `--yes` is given.
+    Wipe {
+        device: PathBuf,
+        #[arg(long, value_enum, default_value_t = WipeScheme::Zeros)]
+        pattern: WipeScheme,
+        /// Wipe only the clusters no file uses, keeping the files.
+        #[arg(long)]
+        free_space: bool,
+        /// Check that the last pass reads back as written.
+        #[arg(long)]
+        verify: bool,
+        /// Discard the free space once wiped, as `trim` does.
+        #[arg(long, requires = "free_space")]
+        discard: bool,
+        /// Skip the confirmation prompt.
+        #[arg(long)]
+        yes: bool,
+        /// Wipe even if the device looks like a fixed system disk.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Tell the card which clusters of the volume are free, so that it can
+    /// erase them ahead of the next write (TRIM). Needs a device node whose
+    /// reader passes discards on; image files get holes punched instead.
+    Trim { device: PathBuf },
+    /// Move each fragmented file into one run of free clusters. The move
+    /// under way is kept in a journal, so that an interrupted run can be
+    /// finished with `--resume` or undone with `--roll-back`.
+    Defrag {
+        device: PathBuf,
+        #[arg(long, default_value = "defrag.journal")]
+        journal: PathBuf,
+        /// Finish the move an interrupted run left in the journal.
+        #[arg(long, conflicts_with = "roll_back")]
+        resume: bool,
+        /// Undo the move an interrupted run left in the journal.
+        #[arg(long)]
+        roll_back: bool,
+    },
+    /// Finish the writes an interrupted run left in a `--write-journal`
+    /// journal, or with `--roll-back` undo them.
+    Journal {
+        device: PathBuf,
+        journal: PathBuf,
+        #[arg(long)]
+        roll_back: bool,
+    },
+    /// Create an empty FAT16 or FAT32 filesystem on the device, or on the
+    /// partition given with `--partition`. Everything on it is lost.
+    Format {
+        device: PathBuf,
+        /// Defaults to FAT16 below 2 GiB and FAT32 from there.
+        #[arg(long, value_enum)]
+        fat: Option<FatType>,
+        #[arg(long)]
+        label: Option<String>,
+        /// Volume serial number in hex, e.g. `1234-ABCD`.
+        #[arg(long, value_parser = parse_serial)]
+        serial: Option<u32>,
+        /// Cluster size in bytes; chosen from the volume size by default.
+        #[arg(long)]
+        cluster_size: Option<u32>,
+        /// Lay the volume out as the SD Association's formatter does, with
+        /// the FAT variant and cluster size it gives the card's capacity and
+        /// the FAT and data area on its allocation unit boundaries.
+        #[arg(long)]
+        sd_spec: bool,
+        /// Format even if the device looks like a fixed system disk.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write a new partition table, or list the partitions of the device.
+    Partition {
+        #[command(subcommand)]
+        action: PartitionAction,
+    },
+    /// Show the volume label and serial number, or change the label.
+    Label {
+        device: PathBuf,
+        /// New label, up to 11 characters.
+        #[arg(conflicts_with = "clear")]
+        label: Option<String>,
+        /// Remove the label.
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Show the attributes of a file or directory, or change them.
+    Attrib {
+        device: PathBuf,
+        path: String,
+        /// Attributes to set, as letters: r (read-only), h (hidden),
+        /// s (system) and a (archive), e.g. `rh`.
+        #[arg(long, value_parser = parse_attributes)]
+        set: Option<Attributes>,
+        /// Attributes to clear, as letters like `--set`.
+        #[arg(long, value_parser = parse_attributes)]
+        clear: Option<Attributes>,
+    },
+    /// Decode the CID, CSD and SCR registers of a card in a native SD slot
+    /// and point out signs of a counterfeit.
+    Sdinfo { device: PathBuf },
+    /// Check for fake capacity by filling the device, or the partition
+    /// given with `--partition`, with a test pattern and reading it back.
+    /// Everything on it is lost. Exits with status 1 if any block fails.
+    CapacityTest {
+        device: PathBuf,
+        /// Test every Nth block only, which is much faster and still finds
+        /// where fake capacity begins.
+        #[arg(long, default_value_t = 1)]
+        stride: u64,
+        /// Seed for the test pattern; give the same one to `--verify-only`.
+        #[arg(long, default_value_t = CapacityTest::default().seed)]
+        seed: u64,
+        /// Only write the pattern, so the card can be reinserted before
+        /// verifying.
+        #[arg(long, conflicts_with = "verify_only")]
+        write_only: bool,
+        /// Only read back a pattern written earlier.
+        #[arg(long)]
+        verify_only: bool,
+        /// Write even if the device looks like a fixed system disk.
+        #[arg(long)]
+        force: bool,
+        /// Print a machine-readable report instead of text.
+        #[arg(long, value_enum)]
+        report: Option<ReportFormat>,
+    },
+    /// Read the device, or the partition given with `--partition`, and list
+    /// the blocks that cannot be read. Exits with status 1 if there are any.
+    Scan {
+        device: PathBuf,
+        /// First block to read.
+        #[arg(long, default_value_t = 0)]
+        start: u64,
+        /// Number of blocks to read; defaults to the rest of the device.
+        #[arg(long)]
+        count: Option<u64>,
+        /// Read every Nth block only.
+        #[arg(long, default_value_t = 1)]
+        stride: u64,
+        /// Further attempts at each block that fails to read.
+        #[arg(long, default_value_t = ScanOptions::default().retries)]
+        retries: u32,
+        /// Stop at the first unreadable block.
+        #[arg(long)]
+        stop_on_error: bool,
+        /// Print a machine-readable report instead of text.
+        #[arg(long, value_enum)]
+        report: Option<ReportFormat>,
+    },
+    /// Measure sequential and random read speed.
+    Bench {
+        device: PathBuf,
+        /// Run only this test.
+        #[arg(long, value_enum)]
+        test: Option<BenchTest>,
+        /// Bytes per request; by default 1 MiB sequential and 4 KiB random.
+        #[arg(long)]
+        request_size: Option<usize>,
+        /// Requests kept in flight at once.
+        #[arg(long, default_value_t = 1)]
+        queue_depth: usize,
+        /// Seconds to run each test for.
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+    },
+    /// List attached block devices, marking the ones that look like SD cards.
+    ListDevices,
+    /// Wait for cards to be inserted and removed, running ACTION on each
+    /// card inserted until interrupted. ACTION is a command of this tool,
+    /// with `{device}` standing for the card, `{name}` for its device name
+    /// and `{time}` for the time it was inserted, such as
+    /// `watch -- extract {device} / /ingest/{name}-{time}`. Only removable
+    /// disks are watched.
+    Watch {
+        /// Ignore removable disks that do not look like SD cards.
+        #[arg(long)]
+        sd_only: bool,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        action: Vec<String>,
+    },
+    /// Copy a file, or a directory and everything below it, from the card
+    /// to the local filesystem. Exits with status 1 if anything could not
+    /// be read.
+    Extract {
+        device: PathBuf,
+        path: String,
+        /// Defaults to `extract_dir` from the config file.
+        dest: Option<PathBuf>,
+        /// Extract a directory's files on this many threads.
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Copy the files under a directory on the card that earlier runs into
+    /// the same destination have not, keeping track in a database there.
+    /// Files whose size or modification time changed are copied again.
+    /// Exits with status 1 if anything could not be read.
+    Ingest {
+        device: PathBuf,
+        /// Defaults to `extract_dir` from the config file.
+        dest: Option<PathBuf>,
+        #[arg(long, default_value = "/")]
+        root: String,
+        /// Database of files already copied. Defaults to `.sd_ingest` in
+        /// the destination.
+        #[arg(long)]
+        database: Option<PathBuf>,
+        #[arg(long, default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Copy a local directory and everything below it onto the card.
+    Import {
+        device: PathBuf,
+        src: PathBuf,
+        #[arg(default_value = "/")]
+        dest: String,
+        /// What to do with files that already exist on the card.
+        #[arg(long, value_enum, default_value_t = OverwriteMode::Skip)]
+        overwrite: OverwriteMode,
+    },
+    /// Mount the volume read-only with FUSE until it is unmounted.
+    #[cfg(all(feature = "fuse", unix))]
+    Mount {
+        device: PathBuf,
+        mountpoint: PathBuf,
+    },
+    /// Browse the partitions, files and cluster map in the terminal.
+    #[cfg(feature = "tui")]
+    Browse {
+        device: PathBuf,
+        /// Where extracted files and hexdumps go.
+        #[arg(default_value = ".")]
+        dest: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ManifestAction {
+    /// Write a manifest of the files below a directory.
+    Create {
+        device: PathBuf,
+        output: PathBuf,
+        #[arg(long, default_value = "/")]
+        root: String,
+        #[arg(long, default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Check the files of a manifest, telling corrupted files from ones
+    /// written since it was made. Give the filter the manifest was made
+    /// with, if any, or the files it left out show up as added.
+    Verify {
+        device: PathBuf,
+        manifest: PathBuf,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum BpbAction {
+    /// List every field with its offset and value, and the layout they
+    /// give the volume.
+    Show {
+        device: PathBuf,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Change fields and write the boot sector back, to its FAT32 backup
+    /// too. Without `--set` or `--recompute`, asks for each field in turn.
+    Edit {
+        device: PathBuf,
+        /// A field and its new value, e.g. `sectors_per_cluster=64`; may be
+        /// given more than once. Values may be hex with `0x`.
+        #[arg(long = "set", value_parser = parse_field_value)]
+        set: Vec<(BpbField, u32)>,
+        /// Then bring the hidden sectors, total sector fields and FAT size
+        /// in line with the other fields.
+        #[arg(long)]
+        recompute: bool,
+        /// Write without asking, even to what looks like a fixed system
+        /// disk.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum PartitionAction {
+    /// Replace the partition table with a new MBR. Partitions start on
+    /// 4 MiB boundaries and are left for `format`.
+    Create {
+        device: PathBuf,
+        /// `fat16` or `fat32` and a size, e.g. `fat32:1G`; either may be
+        /// left out, and a partition without a size takes the rest of the
+        /// device. Defaults to one partition over the whole device.
+        #[arg(value_parser = parse_partition_spec)]
+        partitions: Vec<NewPartition>,
+        /// Mark this partition, counting from 0, as the boot partition.
+        #[arg(long)]
+        bootable: Option<usize>,
+        /// Write even if the device looks like a fixed system disk.
+        #[arg(long)]
+        force: bool,
+    },
+    /// List the partitions with their type and extent.
+    List {
+        device: PathBuf,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReservedAction {
+    /// Write the reserved region to a file.
+    Backup { device: PathBuf, output: PathBuf },
+    /// Write a saved reserved region back to the volume, replacing its boot
+    /// sector.
+    Restore {
+        device: PathBuf,
+        backup: PathBuf,
+        /// Write even if the device looks like a fixed system disk, or the
+        /// backup like one of another volume.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum OverlayAction {
+    /// Compare the device with the device as the overlay changes it, block
+    /// by block or with `--files` file by file. Exits with status 1 if the
+    /// overlay changes anything.
+    Diff {
+        device: PathBuf,
+        overlay: PathBuf,
+        #[arg(long)]
+        files: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write the overlay's changes to the device and empty the overlay.
+    Commit {
+        device: PathBuf,
+        overlay: PathBuf,
+        /// Write even if the device looks like a fixed system disk.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Json,
+    /// One row per finding and affected block range.
+    Csv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BenchTest {
+    Sequential,
+    Random,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum VerifyMode {
+    None,
+    /// Compare the device with the image byte for byte.
+    ReadBack,
+    /// Compare CRC-32s of the device and the image.
+    Checksum,
+}
+
+impl From<VerifyMode> for Verify {
+    fn from(mode: VerifyMode) -> Verify {
+        match mode {
+            VerifyMode::None => Verify::None,
+            VerifyMode::ReadBack => Verify::ReadBack,
+            VerifyMode::Checksum => Verify::Checksum,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CarveType {
+    Jpeg,
+    Png,
+    Mp4,
+    Wav,
+    Zip,
+}
+
+impl From<CarveType> for CarveKind {
+    fn from(kind: CarveType) -> CarveKind {
+        match kind {
+            CarveType::Jpeg => CarveKind::Jpeg,
+            CarveType::Png => CarveKind::Png,
+            CarveType::Mp4 => CarveKind::Mp4,
+            CarveType::Wav => CarveKind::Wav,
+            CarveType::Zip => CarveKind::Zip,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WipeScheme {
+    Zeros,
+    /// 0xFF bytes.
+    Ones,
+    Random,
+    /// Zeros, then ones, then random data.
+    ThreePass,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OverwriteMode {
+    Skip,
+    Replace,
+    /// Replace files the local copy is newer than.
+    IfNewer,
+    Fail,
+}
+
+/// Which files a command takes in; every option given must hold.
+#[derive(Args)]
+struct FilterArgs {
+    /// Take only names matching this pattern, such as 'IMG_*.JPG', ignoring
+    /// case. May be given more than once.
+    #[arg(long = "glob", value_name = "PATTERN")]
+    globs: Vec<String>,
+    /// Take only names with this extension, such as CR2. May be given more
+    /// than once.
+    #[arg(long = "ext", value_name = "EXTENSION")]
+    extensions: Vec<String>,
+    /// Take only files of at least this size; K, M and G suffixes count in
+    /// 1024s.
+    #[arg(long = "min-size", value_parser = parse_size, value_name = "SIZE")]
+    min_size: Option<u64>,
+    #[arg(long = "max-size", value_parser = parse_size, value_name = "SIZE")]
+    max_size: Option<u64>,
+    /// Take only files modified at or after this date, as 2024-01-01 or
+    /// 2024-01-01T12:00:00.
+    #[arg(long, value_parser = parse_date, value_name = "DATE")]
+    newer_than: Option<FatDateTime>,
+    /// Take only files modified before this date.
+    #[arg(long, value_parser = parse_date, value_name = "DATE")]
+    older_than: Option<FatDateTime>,
+}
+
+impl FilterArgs {
+    fn to_filter(&self) -> EntryFilter {
+        EntryFilter {
+            globs: self.globs.clone(),
+            extensions: self
+                .extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_string())
+                .collect(),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            newer_than: self.newer_than,
+            older_than: self.older_than,
+        }
+    }
+}
+
+fn main() {
+    // Diagnostics from the library, e.g. RUST_LOG=sd_controller=trace for
+    // every device request; only errors are shown without RUST_LOG.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+    let mut cli = Cli::parse();
+    let result = load_config(cli.config_file.as_deref()).and_then(|config| {
+        cli.config = config;
+        run(&cli)
+    });
+    match result {
+        Ok(()) if cli.dry_run => eprintln!("dry run: nothing was written"),
+        Ok(()) => {}
+        // Output piped into e.g. `head` that exited early.
+        Err(SDError::IO(e)) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), SDError> {
+    match &cli.command {
+        Command::Format {
+            device,
+            fat,
+            label,
+            serial,
+            cluster_size,
+            sd_spec,
+            force,
+        } => {
+            check_write_target(cli, device, *force, "format it")?;
+            let mut controller = open(cli, device, true)?;
+            if let Some(index) = cli.partition {
+                controller.open_partition(index)?;
+            }
+            let sectors_per_cluster = match cluster_size {
+                Some(bytes) => Some(
+                    u8::try_from(bytes / cli.block_size() as u32)
+                        .ok()
+                        .filter(|_| bytes % cli.block_size() as u32 == 0)
+                        .ok_or(SDError::InvalidFormat(
+                            "the cluster size must be a multiple of the block size, \
+                             up to 128 blocks",
+                        ))?,
+                ),
+                None => None,
+            };
+            let options = FormatOptions {
+                variant: fat.map(|fat| match fat {
+                    FatType::Fat16 => FatVariant::Fat16,
+                    FatType::Fat32 => FatVariant::Fat32,
+                }),
+                sectors_per_cluster,
+                label: label.clone(),
+                serial: *serial,
+                sd_spec: *sd_spec,
+            };
+            let layout = controller.format_with_progress(&options, &mut TerminalProgress::new())?;
+            println!(
+                "Formatted {} as {:?}: {} clusters of {}",
+                device.display(),
+                layout.variant,
+                layout.cluster_count,
+                format_size(layout.cluster_size() as u64)
+            );
+            Ok(())
+        }
+        Command::Partition {
+            action:
+                PartitionAction::Create {
+                    device,
+                    partitions,
+                    bootable,
+                    force,
+                },
+        } => {
+            check_write_target(cli, device, *force, "repartition it")?;
+            let mut controller = open(cli, device, true)?;
+            let mut partitions = partitions.clone();
+            if partitions.is_empty() {
+                partitions.push(NewPartition::default());
+            }
+            if let Some(index) = *bootable {
+                partitions
+                    .get_mut(index)
+                    .ok_or(SDError::PartitionNotFound(index))?
+                    .bootable = true;
+            }
+            let table = controller.create_partition_table(&partitions)?;
+            println!("Wrote a new partition table to {}", device.display());
+            print_partitions(&table, controller.block_size());
+            Ok(())
+        }
+        Command::Partition {
+            action: PartitionAction::List { device, json },
+        } => {
+            let mut controller = open(cli, device, false)?;
+            let table = controller.read_partition_table()?;
+            if *json {
+                return print_json(&table);
+            }
+            print_partitions(&table, controller.block_size());
+            Ok(())
+        }
+        Command::Label {
+            device,
+            label,
+            clear,
+        } => {
+            let change = label.is_some() || *clear;
+            let mut controller = open_volume(cli, device, change)?;
+            if change {
+                controller.set_volume_label(label.as_deref())?;
+                controller.flush()?;
+            }
+            match controller.volume_label()? {
+                Some(label) => println!("Label: {}", label),
+                None => println!("Label: (none)"),
+            }
+            if let Some(serial) = controller.volume_id()? {
+                println!(
+                    "Serial number: {:04X}-{:04X}",
+                    serial >> 16,
+                    serial & 0xFFFF
+                );
+            }
+            Ok(())
+        }
+        Command::Attrib {
+            device,
+            path,
+            set,
+            clear,
+        } => {
+            let change = set.is_some() || clear.is_some();
+            let mut controller = open_volume(cli, device, change)?;
+            let mut entry = controller.stat(path)?;
+            if change {
+                let mut attributes = entry.attributes;
+                attributes.insert(set.unwrap_or_default());
+                attributes.remove(clear.unwrap_or_default());
+                entry = controller.set_attributes(path, attributes)?;
+                controller.flush()?;
+            }
+            println!("{}  {}", entry.attributes, path);
+            Ok(())
+        }
+        Command::Sdinfo { device } => {
+            let info = read_sd_info(device)?;
+            let cid = &info.cid;
+            println!(
+                "Manufacturer: {} ({:#04x})",
+                cid.manufacturer().unwrap_or("unknown"),
+                cid.manufacturer_id
+            );
+            println!("OEM ID: {}", cid.oem_id);
+            println!(
+                "Product: {} rev {}.{}",
+                cid.product_name, cid.revision.0, cid.revision.1
+            );
+            println!("Serial number: {:08x}", cid.serial);
+            println!(
+                "Manufactured: {}-{:02}",
+                cid.manufacture_year, cid.manufacture_month
+            );
+            let csd = &info.csd;
+            println!(
+                "Capacity: {} ({}, CSD version {})",
+                format_size(csd.capacity),
+                csd.capacity_class(),
+                csd.version + 1
+            );
+            println!(
+                "Max transfer rate: {} Mbit/s per line",
+                csd.max_transfer_rate / 1000
+            );
+            if csd.write_protected {
+                println!("Write protected");
+            }
+            if let Some(scr) = &info.scr {
+                println!("Specification: {}", scr.spec_version);
+            }
+            if let Some(speed) = &info.speed {
+                let mut ratings = vec![format!("class {}", speed.speed_class)];
+                if speed.uhs_speed_grade > 0 {
+                    ratings.push(format!("U{}", speed.uhs_speed_grade));
+                }
+                if speed.video_speed_class > 0 {
+                    ratings.push(format!("V{}", speed.video_speed_class));
+                }
+                if speed.app_performance_class > 0 {
+                    ratings.push(format!("A{}", speed.app_performance_class));
+                }
+                println!("Speed ratings: {}", ratings.join(", "));
+            }
+            let warnings = info.warnings();
+            if warnings.is_empty() {
+                println!("No signs of a counterfeit in the registers");
+            }
+            for warning in &warnings {
+                println!("Warning: {}", warning);
+            }
+            Ok(())
+        }
+        Command::CapacityTest {
+            device,
+            stride,
+            seed,
+            write_only,
+            verify_only,
+            force,
+            report: report_format,
+        } => {
+            let test = CapacityTest {
+                seed: *seed,
+                stride: *stride,
+            };
+            let open_target = |writable| -> Result<Controller, SDError> {
+                let mut controller = open(cli, device, writable)?;
+                if let Some(index) = cli.partition {
+                    controller.open_partition(index)?;
+                }
+                Ok(controller)
+            };
+            let mut report = TerminalProgress::new();
+            if !verify_only {
+                check_write_target(cli, device, *force, "overwrite it")?;
+                let mut controller = open_target(true)?;
+                controller.capacity_write(&test, &mut report)?;
+                if *write_only {
+                    println!(
+                        "Wrote the test pattern; reinsert the card and run with \
+                         --verify-only --seed {}",
+                        seed
+                    );
+                    return Ok(());
+                }
+            }
+            // Reopening drops the kernel's cache of what was just written.
+            let mut controller = open_target(false)?;
+            let result = controller.capacity_verify(&test, &mut report)?;
+            if let Some(format) = report_format {
+                let mut report = Report::from_capacity(&result);
+                report.device = Some(device.display().to_string());
+                report.partition_start = controller.partition_start() as u64;
+                print_report(&report, *format)?;
+                if !result.is_genuine() {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            println!(
+                "Claimed capacity: {} ({} blocks of {} bytes)",
+                format_size(result.claimed_bytes()),
+                result.claimed_blocks,
+                result.block_size
+            );
+            println!(
+                "Tested {} blocks: {} good, {} bad, {} aliased",
+                result.tested_blocks, result.good_blocks, result.bad_blocks, result.aliased_blocks
+            );
+            for alias in &result.aliases {
+                println!(
+                    "  block {} holds the data written to block {}",
+                    alias.block, alias.written_as
+                );
+            }
+            if result.is_genuine() {
+                println!("No problems found");
+                return Ok(());
+            }
+            if let Some(blocks) = result.wraparound_blocks {
+                println!("Addresses wrap around every {} blocks", blocks);
+            }
+            if let Some(block) = result.first_bad_block {
+                println!("First bad block: {}", block);
+            }
+            println!("Usable capacity: {}", format_size(result.usable_bytes()));
+            std::process::exit(1);
+        }
+        Command::Scan {
+            device,
+            start,
+            count,
+            stride,
+            retries,
+            stop_on_error,
+            report: report_format,
+        } => {
+            let mut controller = open(cli, device, false)?;
+            if let Some(index) = cli.partition {
+                controller.open_partition(index)?;
+            }
+            let end = match count {
+                Some(count) => start.saturating_add(*count),
+                None => controller.num_blocks(),
+            };
+            let options = ScanOptions {
+                stride: *stride,
+                retries: *retries,
+                stop_on_error: *stop_on_error,
+                ..ScanOptions::default()
+            };
+            let mut report = TerminalProgress::new();
+            let result = controller.scan_bad_blocks(*start..end, &options, &mut report)?;
+            if let Some(format) = report_format {
+                let mut report = Report::from_scan(&result);
+                report.device = Some(device.display().to_string());
+                report.partition_start = controller.partition_start() as u64;
+                print_report(&report, *format)?;
+                if !result.is_clean() {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            println!(
+                "Scanned {} blocks in {:.1} s",
+                result.scanned_blocks,
+                result.elapsed.as_secs_f64()
+            );
+            let latency = &result.latency;
+            println!(
+                "Read latency: min {:?}, median {:?}, p99 {:?}, max {:?}",
+                latency.min, latency.median, latency.p99, latency.max
+            );
+            if let Some(slowest) = result.slowest.first() {
+                println!(
+                    "Slowest read: {} blocks from {} in {:?}",
+                    slowest.blocks, slowest.block, slowest.latency
+                );
+            }
+            for recovered in &result.recovered {
+                println!(
+                    "Block {} read after {} failed attempts",
+                    recovered.block, recovered.failures
+                );
+            }
+            if result.is_clean() {
+                println!("No unreadable blocks");
+                return Ok(());
+            }
+            println!("{} unreadable blocks:", result.unreadable.len());
+            // The scan has already found the damage; a volume too damaged
+            // to walk only leaves the blocks unexplained.
+            let owners = volume_owners(cli, &mut controller).ok();
+            for &block in &result.unreadable {
+                match &owners {
+                    Some((owners, start)) => {
+                        println!("  {:<12} {}", block, describe_owner(owners, *start, block))
+                    }
+                    None => println!("  {}", block),
+                }
+            }
+            std::process::exit(1);
+        }
+        Command::Bench {
+            device,
+            test,
+            request_size,
+            queue_depth,
+            seconds,
+        } => {
+            let patterns = match test {
+                Some(BenchTest::Sequential) => vec![BenchPattern::Sequential],
+                Some(BenchTest::Random) => vec![BenchPattern::Random],
+                None => vec![BenchPattern::Sequential, BenchPattern::Random],
+            };
+            for pattern in patterns {
+                let mut options = BenchOptions::new(pattern);
+                if let Some(bytes) = request_size {
+                    options.request_bytes = *bytes;
+                }
+                options.queue_depth = *queue_depth;
+                options.duration = Duration::from_secs(*seconds);
+                let result = bench(|| Ok(open(cli, device, false)?.into_inner()), &options)?;
+                let latency = &result.latency;
+                println!(
+                    "{:?} reads of {}, queue depth {}: {:.1} MB/s, {:.0} IOPS",
+                    pattern,
+                    format_size(options.request_bytes as u64),
+                    options.queue_depth,
+                    result.bytes_per_second() / 1_000_000.0,
+                    result.iops()
+                );
+                println!(
+                    "  latency min {:?}, median {:?}, p99 {:?}, max {:?}",
+                    latency.min, latency.median, latency.p99, latency.max
+                );
+            }
+            Ok(())
+        }
+        Command::ListDevices => {
+            for device in discover()? {
+                let flags = match (device.sd_like, device.removable) {
+                    (true, _) => "SD",
+                    (false, true) => "removable",
+                    (false, false) => "",
+                };
+                println!(
+                    "{:<24} {:>10} {:<10} {}",
+                    device.path.display(),
+                    format_size(device.size),
+                    flags,
+                    device.model
+                );
+            }
+            Ok(())
+        }
+        Command::Watch { sd_only, action } => {
+            let mut watcher = DeviceWatcher::new(*sd_only)?;
+            for device in watcher.present() {
+                eprintln!("Already present: {}", device.path.display());
+            }
+            eprintln!("Waiting for cards...");
+            loop {
+                let device = match watcher.next_event()? {
+                    DeviceEvent::Removed(device) => {
+                        println!("Removed {}", device.path.display());
+                        continue;
+                    }
+                    DeviceEvent::Inserted(device) => device,
+                };
+                println!(
+                    "Inserted {} ({}, {})",
+                    device.path.display(),
+                    format_size(device.size),
+                    device.model
+                );
+                if action.is_empty() {
+                    continue;
+                }
+                // Failures are reported and the next card waited for.
+                let args = action_args(action, &device);
+                match std::process::Command::new(std::env::current_exe()?)
+                    .args(&args)
+                    .status()
+                {
+                    Ok(status) if status.success() => {
+                        println!("Finished with {}", device.path.display())
+                    }
+                    Ok(status) => eprintln!("{} failed: {status}", args.join(" ")),
+                    Err(e) => eprintln!("{} failed: {e}", args.join(" ")),
+                }
+            }
+        }
+        Command::Info { device, json } => {
+            let mut controller = open(cli, device, false)?;
+            let info = read_info(&mut controller, cli.partition)?;
+            if *json {
+                return print_json(&info);
+            }
+            print_info(&info);
+            Ok(())
+        }
+        Command::Check { device, report } => {
+            let mut controller = open_volume(cli, device, false)?;
+            if let Some(format) = report {
+                let mut report = controller.check_report()?;
+                report.device = Some(device.display().to_string());
+                print_report(&report, *format)?;
+                if !report.findings.is_empty() {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            let issues = controller.check()?;
+            if issues.is_empty() {
+                println!("No problems found");
+                return Ok(());
+            }
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            println!("{} problem(s) found", issues.len());
+            std::process::exit(1);
+        }
+        Command::Repair { device, dry_run } => {
+            let mut controller = open_volume(cli, device, !dry_run)?;
+            let actions = controller.repair(&RepairOptions {
+                dry_run: *dry_run,
+                ..RepairOptions::default()
+            })?;
+            if actions.is_empty() {
+                println!("Nothing to repair");
+                return Ok(());
+            }
+            for action in &actions {
+                println!("{}", action);
+            }
+            if *dry_run {
+                println!("{} change(s) would be made", actions.len());
+            } else {
+                println!("{} change(s) made", actions.len());
+            }
+            Ok(())
+        }
+        Command::Recover { device, dest } => {
+            let mut controller = open_volume(cli, device, false)?;
+            if let Some(dest) = dest {
+                let recovered = controller.recover_all(dest)?;
+                for (deleted, target) in &recovered {
+                    println!("{} -> {}", deleted.path, target.display());
+                }
+                println!("Recovered {} file(s)", recovered.len());
+                return Ok(());
+            }
+            for deleted in controller.deleted_entries()? {
+                let state = match deleted.recoverability {
+                    Recoverability::Contiguous => "contiguous".to_string(),
+                    Recoverability::Fragmented { skipped } => {
+                        format!("fragmented, skips {} used cluster(s)", skipped)
+                    }
+                    Recoverability::Lost => "lost".to_string(),
+                };
+                let kind = if deleted.entry.is_dir() { "<DIR>" } else { "" };
+                println!(
+                    "{:<32} {:>5} {:>10}  {}",
+                    deleted.path, kind, deleted.entry.size, state
+                );
+            }
+            Ok(())
+        }
+        Command::Owner {
+            device,
+            blocks,
+            json,
+        } => {
+            let mut controller = open(cli, device, false)?;
+            let (owners, start) = volume_owners(cli, &mut controller)?;
+            if *json {
+                let owners: Vec<_> = blocks
+                    .iter()
+                    .map(|&block| {
+                        let owner = block
+                            .checked_sub(start)
+                            .and_then(|block| owners.owner(block).ok());
+                        BlockOwnerJson { block, owner }
+                    })
+                    .collect();
+                return print_json(&owners);
+            }
+            for &block in blocks {
+                println!("{:<12} {}", block, describe_owner(&owners, start, block));
+            }
+            Ok(())
+        }
+        Command::Search {
+            device,
+            pattern,
+            hex,
+            regex,
+            max_hits,
+            overlap,
+            json,
+        } => {
+            let pattern = if *regex {
+                SearchPattern::regex(pattern)?
+            } else if *hex {
+                SearchPattern::Bytes(parse_hex(pattern).map_err(SDError::InvalidPattern)?)
+            } else {
+                SearchPattern::Bytes(pattern.as_bytes().to_vec())
+            };
+            let mut controller = open(cli, device, false)?;
+            // Hits are still found on a card whose volume cannot be read,
+            // only without files to go with them.
+            if let Err(error) = select_volume(cli, &mut controller) {
+                if cli.partition.is_some() {
+                    return Err(error);
+                }
+            }
+            let options = SearchOptions {
+                max_hits: *max_hits,
+                overlap: *overlap as usize,
+                ..SearchOptions::new(pattern)
+            };
+            let hits = controller.search(&options, &mut TerminalProgress::new())?;
+            if *json {
+                return print_json(&hits);
+            }
+            for hit in &hits {
+                let shown: Vec<u8> = hit.bytes.iter().copied().take(64).collect();
+                println!(
+                    "{:>12} +{:<4} {}  {}{}",
+                    hit.block,
+                    hit.offset,
+                    hit.owner.as_deref().unwrap_or("-"),
+                    shown.escape_ascii(),
+                    if hit.bytes.len() > shown.len() {
+                        "..."
+                    } else {
+                        ""
+                    }
+                );
+            }
+            println!("{} hits", hits.len());
+            Ok(())
+        }
+        Command::Carve {
+            device,
+            dest,
+            kinds,
+            partial,
+        } => {
+            let mut controller = open(cli, device, false)?;
+            if let Some(index) = cli.partition {
+                controller.open_partition(index)?;
+            }
+            let mut options = CarveOptions::default();
+            if !kinds.is_empty() {
+                options.kinds = kinds.iter().map(|&kind| kind.into()).collect();
+            }
+            let mut progress = TerminalProgress::new();
+            let Some(dest) = dest else {
+                for file in controller.carve(&options, &mut progress)? {
+                    println!(
+                        "{:>12} {:<5} {:>10}  {}",
+                        file.start_block,
+                        file.kind.extension(),
+                        file.len,
+                        if file.complete {
+                            "complete"
+                        } else {
+                            "incomplete"
+                        }
+                    );
+                }
+                return Ok(());
+            };
+            let written = controller.carve_to(dest, &options, *partial, &mut progress)?;
+            for (file, target) in &written {
+                println!("{:>10}  {}", file.len, target.display());
+            }
+            println!("Carved {} file(s)", written.len());
+            Ok(())
+        }
+        Command::Df { device } => {
+            let mut controller = open_volume(cli, device, false)?;
+            let usage = controller.usage()?;
+            let percent = if usage.total_clusters == 0 {
+                0
+            } else {
+                (usage.total_clusters - usage.free_clusters) as u64 * 100
+                    / usage.total_clusters as u64
+            };
+            println!(
+                "{:>10} {:>10} {:>10} {:>5}",
+                "Size", "Used", "Avail", "Use%"
+            );
+            println!(
+                "{:>10} {:>10} {:>10} {:>4}%",
+                format_size(usage.total_bytes()),
+                format_size(usage.used_bytes()),
+                format_size(usage.free_bytes()),
+                percent
+            );
+            println!(
+                "\n{:?}, {} clusters of {} bytes, {} free",
+                usage.variant, usage.total_clusters, usage.cluster_size, usage.free_clusters
+            );
+            println!(
+                "Largest free extent: {} clusters ({})",
+                usage.largest_free_extent,
+                format_size(usage.largest_free_extent_bytes())
+            );
+            if let Some(free) = usage.fs_info.and_then(|fs_info| fs_info.free_clusters) {
+                if free != usage.free_clusters {
+                    println!("FSInfo free count {} is stale", free);
+                }
+            }
+            Ok(())
+        }
+        Command::Map {
+            device,
+            png,
+            columns,
+            per_cell,
+            scale,
+        } => {
+            let mut controller = open_volume(cli, device, false)?;
+            let map = controller.alloc_map()?;
+            let mut options = MapOptions::fit(map.clusters.len(), *columns, 32);
+            if let Some(per_cell) = per_cell {
+                options.clusters_per_cell = *per_cell;
+            }
+            options.scale = *scale;
+            match png {
+                Some(png) => {
+                    let mut out = io::BufWriter::new(File::create(png)?);
+                    map.write_png(&mut out, &options)?;
+                    out.flush()?;
+                }
+                None => map.write_text(&mut io::stdout().lock(), &options)?,
+            }
+            println!(
+                "{} clusters, {} used, {} free, {} bad; {} per cell",
+                map.clusters.len(),
+                map.count(ClusterState::Used),
+                map.count(ClusterState::Free),
+                map.count(ClusterState::Bad),
+                options.clusters_per_cell
+            );
+            Ok(())
+        }
+        Command::Ls {
+            device,
+            path,
+            json,
+            all,
+            filter,
+        } => {
+            let filter = filter.to_filter();
+            let mut controller = open_volume(cli, device, false)?;
+            let entry = controller.stat(path)?;
+            let entries: Vec<_> = if entry.is_dir() {
+                controller
+                    .open_dir(&entry)?
+                    .filter(|entry| *all || !entry.attributes.is_concealed())
+                    .filter(|entry| entry.is_dir() || filter.matches(entry))
+                    .collect()
+            } else {
+                vec![entry]
+            };
+            if *json {
+                let listing: Vec<_> = entries.iter().map(ListedEntry::new).collect();
+                return print_json(&listing);
+            }
+            for entry in entries {
+                let kind = if entry.is_dir() { "<DIR>" } else { "" };
+                let modified = entry
+                    .timestamps
+                    .modified_at()
+                    .map_or(String::new(), |time| time.to_string().replace('T', " "));
+                println!(
+                    "{:<12} {:>5} {:>10}  {}  {}",
+                    entry.full_name(),
+                    kind,
+                    entry.size,
+                    entry.attributes,
+                    modified
+                );
+            }
+            Ok(())
+        }
+        Command::Cat { device, path } => {
+            let mut controller = open_volume(cli, device, false)?;
+            let mut reader = controller.open_reader(path)?;
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            io::copy(&mut reader, &mut stdout)?;
+            stdout.flush()?;
+            Ok(())
+        }
+        Command::Hexdump {
+            device,
+            block,
+            count,
+            width,
+            skip,
+            length,
+        } => {
+            let mut controller = open(cli, device, false)?;
+            if let Some(index) = cli.partition {
+                controller.open_partition(index)?;
+            }
+            let data = controller.read_blocks(*block, *count)?;
+            let options = HexdumpOptions {
+                width: *width,
+                start: *skip,
+                len: *length,
+                base_offset: *block as u64 * controller.block_size() as u64,
+            };
+            write_hexdump(&mut io::stdout().lock(), &data, &options)?;
+            Ok(())
+        }
+        Command::Acquire {
+            device,
+            image,
+            log,
+            report,
+            algorithms,
+            key_file,
+            case,
+            examiner,
+            no_verify,
+        } => {
+            let key = key_file.as_deref().map(std::fs::read).transpose()?;
+            let log_path = log
+                .clone()
+                .unwrap_or_else(|| with_suffix(image, ".audit.jsonl"));
+            let report_path = report
+                .clone()
+                .unwrap_or_else(|| with_suffix(image, ".report.json"));
+            let mut audit = AuditLog::new(File::create(&log_path)?, key.as_deref());
+            let mut controller = ReadOnlyController::read_only(open_device(cli, device, false)?);
+            let mut out = File::create(image)?;
+            let options = AcquireOptions {
+                algorithms: algorithms.clone(),
+                verify: !no_verify,
+                device: Some(device.display().to_string()),
+                case: case.clone(),
+                examiner: examiner.clone(),
+            };
+            let acquisition =
+                controller.acquire(&mut out, &mut audit, &options, &mut TerminalProgress::new())?;
+            out.sync_all()?;
+            acquisition.write_json(File::create(&report_path)?)?;
+            for (name, digest) in &acquisition.digests {
+                println!("{name}  {digest}");
+            }
+            println!(
+                "Imaged {} to {}; log in {}, report in {}",
+                format_size(acquisition.bytes),
+                image.display(),
+                log_path.display(),
+                report_path.display()
+            );
+            if acquisition.verified == Some(false) {
+                eprintln!("The second read of the device hashed differently.");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Audit { log, key_file } => {
+            let key = key_file.as_deref().map(std::fs::read).transpose()?;
+            let entries = verify_audit_log(io::BufReader::new(File::open(log)?), key.as_deref())?;
+            println!("All {entries} entries of {} are intact", log.display());
+            Ok(())
+        }
+        Command::Nbd {
+            device,
+            listen,
+            name,
+            writable,
+            force,
+        } => {
+            if *writable {
+                check_write_target(cli, device, *force, "export it for writing")?;
+            }
+            let mut controller = open(cli, device, *writable)?;
+            if let Some(index) = cli.partition {
+                controller.open_partition(index)?;
+            }
+            let listener = std::net::TcpListener::bind(listen.as_str())?;
+            let options = NbdOptions { name: name.clone() };
+            eprintln!(
+                "Exporting {} as {name:?} on nbd://{}/{name}{}",
+                device.display(),
+                listener.local_addr()?,
+                if *writable { "" } else { ", read-only" }
+            );
+            for stream in listener.incoming() {
+                let stream = stream?;
+                let peer = stream.peer_addr()?;
+                eprintln!("{peer} connected");
+                // One client failing should not stop the export.
+                match controller.serve_nbd(stream, &options) {
+                    Ok(stats) => eprintln!(
+                        "{peer} disconnected after {} requests: read {}, wrote {}, {} failed",
+                        stats.requests,
+                        format_size(stats.bytes_read),
+                        format_size(stats.bytes_written),
+                        stats.errors
+                    ),
+                    Err(e) => eprintln!("{peer}: {e}"),
+                }
+            }
+            Ok(())
+        }
+        Command::Serve { devices, listen } => {
+            let options = ServeOptions {
+                devices: devices.clone(),
+                token: read_token(cli)?,
+            };
+            let server = Server::bind(listen.as_str(), options)?;
+            if let Some(address) = server.local_addr() {
+                eprintln!("Serving {} devices on http://{address}", devices.len());
+            }
+            server.run();
+            Ok(())
+        }
+        Command::Dump {
+            device,
+            dest,
+            used_only,
+        } => {
+            let mut controller = open(cli, device, false)?;
+            let mut report = TerminalProgress::new();
+            if *used_only {
+                select_volume(cli, &mut controller)?;
+                let read = controller.dump_used_image(dest, &mut report)?;
+                let size = controller.num_blocks() * controller.block_size() as u64;
+                println!(
+                    "Wrote {} of {} to {}",
+                    format_size(read),
+                    format_size(size),
+                    dest.display()
+                );
+                return Ok(());
+            }
+            if let Some(index) = cli.partition {
+                controller.open_partition(index)?;
+            }
+            let written = controller.dump_image(dest, &mut report)?;
+            println!("Wrote {} to {}", format_size(written), dest.display());
+            Ok(())
+        }
+        Command::Flash {
+            device,
+            image,
+            verify,
+            force,
+        } => {
+            check_write_target(cli, device, *force, "overwrite it")?;
+            let mut controller = open(cli, device, true)?;
+            if let Some(index) = cli.partition {
+                controller.open_partition(index)?;
+            }
+            let mut report = TerminalProgress::new();
+            let written = controller.flash_image(image, (*verify).into(), &mut report)?;
+            println!("Wrote {} to {}", format_size(written), device.display());
+            Ok(())
+        }
+        Command::Hash {
+            device,
+            paths,
+            algorithm,
+        } => {
+            let mut controller = open(cli, device, false)?;
+            let mut progress = TerminalProgress::new();
+            if paths.is_empty() {
+                if let Some(index) = cli.partition {
+                    controller.open_partition(index)?;
+                }
+                let blocks = controller.num_blocks();
+                let digest = controller.hash_device(0..blocks, *algorithm, &mut progress)?;
+                println!("{}  {}", digest, device.display());
+                return Ok(());
+            }
+            select_volume(cli, &mut controller)?;
+            for path in paths {
+                let digest = controller.hash_file(path, *algorithm, &mut progress)?;
+                println!("{}  {}", digest, path);
+            }
+            Ok(())
+        }
+        Command::DedupeScan {
+            device,
+            root,
+            min_size,
+            max_size,
+            algorithm,
+            json,
+        } => {
+            let mut controller = open_volume(cli, device, false)?;
+            let options = DedupeOptions {
+                root: root.clone(),
+                min_size: *min_size,
+                max_size: *max_size,
+                algorithm: *algorithm,
+            };
+            let report = controller.find_duplicates(&options, &mut TerminalProgress::new())?;
+            if *json {
+                return print_json(&report);
+            }
+            for set in &report.duplicates {
+                println!(
+                    "{} each, {} copies ({} wasted)  {}",
+                    format_size(set.size),
+                    set.paths.len(),
+                    format_size(set.wasted_bytes()),
+                    set.digest
+                );
+                for path in &set.paths {
+                    println!("  {}", path);
+                }
+            }
+            println!(
+                "{} duplicate sets among {} files, {} wasted",
+                report.duplicates.len(),
+                report.files,
+                format_size(report.wasted_bytes())
+            );
+            Ok(())
+        }
+        Command::Verify {
+            device,
+            manifest,
+            algorithm,
+        } => verify_manifest(cli, device, manifest, *algorithm),
+        Command::Diff {
+            first,
+            second,
+            files,
+            json,
+        } => {
+            let mut progress = TerminalProgress::new();
+            let identical = if *files {
+                let mut ours = open_volume(cli, first, false)?;
+                let mut theirs = open_volume(cli, second, false)?;
+                let diff = ours.diff_files(&mut theirs, &mut progress)?;
+                if *json {
+                    print_json(&diff)?;
+                } else {
+                    print_file_diff(&diff);
+                }
+                diff.is_identical()
+            } else {
+                let mut ours = open(cli, first, false)?;
+                let mut theirs = open(cli, second, false)?;
+                if let Some(index) = cli.partition {
+                    ours.open_partition(index)?;
+                    theirs.open_partition(index)?;
+                }
+                let diff = ours.diff_blocks(&mut theirs, &mut progress)?;
+                if *json {
+                    print_json(&diff)?;
+                } else {
+                    print_block_diff(&diff, first, second);
+                }
+                diff.is_identical()
+            };
+            if !identical {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Overlay {
+            action:
+                OverlayAction::Diff {
+                    device,
+                    overlay,
+                    files,
+                    json,
+                },
+        } => {
+            let identical = if *files {
+                let mut base = SDController::from_device(open_device(cli, device, false)?);
+                let mut changed = SDController::from_device(OverlayDevice::with_file(
+                    open_device(cli, device, false)?,
+                    overlay,
+                )?);
+                base.set_codepage(cli.codepage());
+                changed.set_codepage(cli.codepage());
+                select_volume(cli, &mut base)?;
+                select_volume(cli, &mut changed)?;
+                let diff = base.diff_files(&mut changed, &mut TerminalProgress::new())?;
+                if *json {
+                    print_json(&diff)?;
+                } else {
+                    print_file_diff(&diff);
+                }
+                diff.is_identical()
+            } else {
+                let mut changed =
+                    OverlayDevice::with_file(open_device(cli, device, false)?, overlay)?;
+                let diff = changed.diff()?;
+                if *json {
+                    print_json(&diff)?;
+                } else {
+                    print_block_diff(&diff, device, overlay);
+                }
+                diff.is_identical()
+            };
+            if !identical {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Overlay {
+            action:
+                OverlayAction::Commit {
+                    device,
+                    overlay,
+                    force,
+                },
+        } => {
+            check_write_target(cli, device, *force, "overwrite it")?;
+            let mut changed = OverlayDevice::with_file(open_device(cli, device, true)?, overlay)?;
+            if cli.dry_run {
+                // Committing empties the overlay file.
+                let diff = changed.diff()?;
+                for run in &diff.differing {
+                    eprintln!(
+                        "dry run: write {} blocks at {}",
+                        run.end - run.start,
+                        run.start
+                    );
+                }
+                return Ok(());
+            }
+            let blocks = changed.commit()?;
+            println!(
+                "Wrote {} blocks ({}) to {}",
+                blocks,
+                format_size(blocks * changed.block_size() as u64),
+                device.display()
+            );
+            Ok(())
+        }
+        Command::Manifest {
+            action:
+                ManifestAction::Create {
+                    device,
+                    output,
+                    root,
+                    algorithm,
+                    filter,
+                },
+        } => {
+            let mut controller = open_volume(cli, device, false)?;
+            let manifest = controller.create_manifest_matching(
+                root,
+                *algorithm,
+                &filter.to_filter(),
+                &mut TerminalProgress::new(),
+            )?;
+            manifest.write_json(File::create(output)?)?;
+            println!(
+                "Recorded {} files in {}",
+                manifest.files.len(),
+                output.display()
+            );
+            Ok(())
+        }
+        Command::Manifest {
+            action:
+                ManifestAction::Verify {
+                    device,
+                    manifest,
+                    filter,
+                },
+        } => {
+            let manifest = Manifest::read_json(File::open(manifest)?)?;
+            let mut controller = open_volume(cli, device, false)?;
+            let report = controller.verify_manifest_matching(
+                &manifest,
+                &filter.to_filter(),
+                &mut TerminalProgress::new(),
+            )?;
+            for mismatch in &report.mismatches {
+                match &mismatch.problem {
+                    ManifestProblem::Corrupted => println!("{}: CORRUPTED", mismatch.path),
+                    ManifestProblem::Changed => println!("{}: changed", mismatch.path),
+                    ManifestProblem::Missing => println!("{}: missing", mismatch.path),
+                    ManifestProblem::Unreadable(error) => {
+                        println!("{}: unreadable ({})", mismatch.path, error)
+                    }
+                }
+            }
+            for path in &report.added {
+                println!("{}: added", path);
+            }
+            println!(
+                "{} of {} files as recorded",
+                report.verified,
+                manifest.files.len()
+            );
+            if !report.is_intact() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Bpb {
+            action: BpbAction::Show { device, json },
+        } => {
+            let mut controller = open(cli, device, false)?;
+            open_boot_sector(cli, &mut controller);
+            let bpb = controller.read_bpb()?;
+            if *json {
+                let fields: Vec<_> = bpb
+                    .fields()
+                    .map(|(field, value)| BpbFieldJson {
+                        field,
+                        offset: field
+                            .location(bpb.is_fat32())
+                            .map_or(0, |(offset, _)| offset),
+                        value,
+                    })
+                    .collect();
+                return print_json(&fields);
+            }
+            print_bpb(&bpb);
+            Ok(())
+        }
+        Command::Bpb {
+            action:
+                BpbAction::Edit {
+                    device,
+                    set,
+                    recompute,
+                    force,
+                },
+        } => {
+            check_write_target(cli, device, *force, "edit its boot sector")?;
+            let mut controller = open(cli, device, true)?;
+            open_boot_sector(cli, &mut controller);
+            let original = controller.read_bpb()?;
+            let mut bpb = original.clone();
+            let interactive = set.is_empty() && !recompute;
+            if interactive {
+                edit_bpb_interactively(&mut bpb)?;
+            }
+            for &(field, value) in set {
+                bpb.set(field, value)?;
+            }
+            if *recompute {
+                bpb.recompute(controller.partition_start());
+            }
+            let mut changed = false;
+            for (field, old) in original.fields() {
+                let new = bpb.get(field);
+                if new != Some(old) {
+                    changed = true;
+                    match new {
+                        Some(new) => println!("{field}: {old} -> {new}"),
+                        None => println!("{field}: {old} -> (not a FAT32 boot sector)"),
+                    }
+                }
+            }
+            if !changed {
+                println!("Nothing to change");
+                return Ok(());
+            }
+            if let Err(error) = bpb.validate() {
+                eprintln!("Not writing a boot sector that would not mount: {error}");
+                std::process::exit(1);
+            }
+            if interactive && !*force && !confirm("The boot sector will be rewritten.", device)? {
+                println!("Nothing written");
+                return Ok(());
+            }
+            controller.write_bpb(&bpb)?;
+            println!("Wrote the boot sector of {}", device.display());
+            Ok(())
+        }
+        Command::Reserved {
+            action: ReservedAction::Backup { device, output },
+        } => {
+            let mut controller = open_volume(cli, device, false)?;
+            let region = controller.read_reserved_region()?;
+            std::fs::write(output, &region)?;
+            println!(
+                "Saved {} reserved blocks to {}",
+                region.len() / controller.block_size(),
+                output.display()
+            );
+            Ok(())
+        }
+        Command::Reserved {
+            action:
+                ReservedAction::Restore {
+                    device,
+                    backup,
+                    force,
+                },
+        } => {
+            check_write_target(cli, device, *force, "overwrite it")?;
+            let region = std::fs::read(backup)?;
+            let mut controller = open(cli, device, true)?;
+            // Block 0 of a card without a partition table is the boot
+            // sector, which may be what is being restored.
+            if select_volume(cli, &mut controller).is_err() && cli.partition.is_none() {
+                controller.close_partition();
+            }
+            let current = controller.read_reserved_region();
+            let matches = current.as_ref().map_or(true, |current| {
+                current.len() == region.len()
+                    && exfat::is_exfat(current) == exfat::is_exfat(&region)
             });
+            if !force && !matches {
+                eprintln!(
+                    "The backup does not look like one of this volume. \
+                     Pass --force to restore it all the same."
+                );
+                std::process::exit(1);
+            }
+            controller.restore_reserved_region(&region)?;
+            println!(
+                "Restored {} reserved blocks to {}",
+                region.len() / controller.block_size(),
+                device.display()
+            );
+            Ok(())
+        }
+        Command::Clone {
+            source,
+            dest,
+            used_only,
+            verify,
+            force,
+        } => {
+            check_write_target(cli, dest, *force, "overwrite it")?;
+            let mut from = open(cli, source, false)?;
+            let mut to = open(cli, dest, true)?;
+            if let Some(index) = cli.partition {
+                from.open_partition(index)?;
+                to.open_partition(index)?;
+            }
+            let options = CloneOptions {
+                used_only: *used_only,
+                verify: (*verify).into(),
+            };
+            let report = from.clone_to(&mut to, &options, &mut TerminalProgress::new())?;
+            let block_size = report.block_size as u64;
+            println!(
+                "Copied {} to {}",
+                format_size(report.copied_blocks * block_size),
+                dest.display()
+            );
+            if report.skipped_blocks > 0 {
+                println!(
+                    "Left out {} of free space",
+                    format_size(report.skipped_blocks * block_size)
+                );
+            }
+            Ok(())
+        }
+        Command::Wipe {
+            device,
+            pattern,
+            free_space,
+            verify,
+            discard,
+            yes,
+            force,
+        } => {
+            check_write_target(cli, device, *force, "wipe it")?;
+            let what = if *free_space {
+                "the free space of"
+            } else {
+                "everything on"
+            };
+            if !yes
+                && !confirm(
+                    &format!("This overwrites {what} {}.", device.display()),
+                    device,
+                )?
+            {
+                eprintln!("Not confirmed; nothing was written.");
+                std::process::exit(1);
+            }
+            let mut controller = open(cli, device, true)?;
+            let mut options = match pattern {
+                WipeScheme::Zeros => WipeOptions::zeros(),
+                WipeScheme::Ones => WipeOptions::with_passes(&[WipePass::Ones]),
+                WipeScheme::Random => WipeOptions::with_passes(&[WipePass::Random]),
+                WipeScheme::ThreePass => WipeOptions::three_pass(),
+            };
+            options.verify = *verify;
+            let mut progress = TerminalProgress::new();
+            let blocks = if *free_space {
+                select_volume(cli, &mut controller)?;
+                controller.wipe_free_space(&options, &mut progress)?
+            } else {
+                if let Some(index) = cli.partition {
+                    controller.open_partition(index)?;
+                }
+                let blocks = controller.num_blocks();
+                controller.wipe(0..blocks, &options, &mut progress)?
+            };
+            println!(
+                "Wiped {} of {} in {} pass(es)",
+                format_size(blocks * controller.block_size() as u64),
+                device.display(),
+                options.passes.len()
+            );
+            if *discard {
+                let blocks = controller.discard_free_space()?;
+                println!(
+                    "Discarded {}",
+                    format_size(blocks * controller.block_size() as u64)
+                );
+            }
+            Ok(())
+        }
+        Command::Trim { device } => {
+            let mut controller = open(cli, device, true)?;
+            select_volume(cli, &mut controller)?;
+            let blocks = controller.discard_free_space()?;
+            println!(
+                "Discarded {} of free space on {}",
+                format_size(blocks * controller.block_size() as u64),
+                device.display()
+            );
+            Ok(())
+        }
+        Command::Journal {
+            device,
+            journal,
+            roll_back,
+        } => {
+            let policy = if *roll_back {
+                RecoveryPolicy::RollBack
+            } else {
+                RecoveryPolicy::Replay
+            };
+            let journaled =
+                JournaledDevice::open(open_device(cli, device, true)?, journal, policy)?;
+            match journaled.recovery() {
+                Some(recovery) => print_recovery(recovery),
+                None => println!("{} records nothing to recover", journal.display()),
+            }
+            Ok(())
+        }
+        Command::Defrag {
+            device,
+            journal,
+            resume,
+            roll_back,
+        } => {
+            let mut controller = open(cli, device, true)?;
+            select_volume(cli, &mut controller)?;
+            if *resume {
+                let path = controller.resume_defragment(journal)?;
+                println!("Finished moving {}", path);
+                return Ok(());
+            }
+            if *roll_back {
+                let path = controller.roll_back_defragment(journal)?;
+                println!("Moved {} back to where it was", path);
+                return Ok(());
+            }
+            let report = controller.defragment(journal, &mut TerminalProgress::new())?;
+            for path in &report.skipped {
+                println!("No free run large enough for {}", path);
+            }
+            println!(
+                "Moved {} of {} fragmented files ({})",
+                report.moved.len(),
+                report.fragmented,
+                format_size(report.moved_bytes)
+            );
+            Ok(())
+        }
+        Command::Extract {
+            device,
+            path,
+            dest,
+            jobs,
+            filter,
+        } => {
+            let filter = filter.to_filter();
+            let dest = dest.as_ref().or(cli.config.extract_dir.as_ref()).ok_or(
+                SDError::InvalidArgument(
+                    "give a destination or set extract_dir in the config file",
+                ),
+            )?;
+            let mut controller = open_volume(cli, device, false)?;
+            let entry = controller.stat(path)?;
+            let dest = if dest.is_dir() {
+                host_path(dest, &entry.full_name())?
+            } else {
+                dest.clone()
+            };
+            if entry.is_dir() {
+                return extract_tree(&mut controller, path, &dest, *jobs, &filter);
+            }
+            if !filter.matches(&entry) {
+                println!("{} does not match the filter", path);
+                return Ok(());
+            }
+            let written = controller.extract_file(&entry, &dest, |_| {})?;
+            println!("Extracted {} bytes to {}", written, dest.display());
+            Ok(())
+        }
+        Command::Ingest {
+            device,
+            dest,
+            root,
+            database,
+            algorithm,
+            filter,
+        } => {
+            let dest = dest.as_ref().or(cli.config.extract_dir.as_ref()).ok_or(
+                SDError::InvalidArgument(
+                    "give a destination or set extract_dir in the config file",
+                ),
+            )?;
+            let options = IngestOptions {
+                root: root.clone(),
+                filter: filter.to_filter(),
+                algorithm: *algorithm,
+                database: database.clone(),
+            };
+            let mut controller = open_volume(cli, device, false)?;
+            let summary = controller.ingest(dest, &options, &mut TerminalProgress::new())?;
+            println!(
+                "Ingested {} new and {} changed files ({}) to {}; {} already there",
+                summary.new,
+                summary.changed,
+                format_size(summary.bytes),
+                dest.display(),
+                summary.unchanged
+            );
+            if summary.is_complete() {
+                return Ok(());
+            }
+            println!("{} entries could not be read:", summary.failures.len());
+            for failure in &summary.failures {
+                println!("  {}: {}", failure.path, failure.error);
+            }
+            std::process::exit(1);
+        }
+        Command::Import {
+            device,
+            src,
+            dest,
+            overwrite,
+        } => {
+            let mut controller = open_volume(cli, device, true)?;
+            let policy = match overwrite {
+                OverwriteMode::Skip => OverwritePolicy::Skip,
+                OverwriteMode::Replace => OverwritePolicy::Replace,
+                OverwriteMode::IfNewer => OverwritePolicy::ReplaceIfNewer,
+                OverwriteMode::Fail => OverwritePolicy::Fail,
+            };
+            let summary = controller.import(src, dest, policy)?;
+            controller.flush()?;
+            for skipped in &summary.skipped {
+                println!("Skipped {}", skipped);
+            }
+            println!(
+                "Imported {} files and {} directories ({}) to {}",
+                summary.files,
+                summary.directories,
+                format_size(summary.bytes),
+                dest
+            );
+            Ok(())
         }
-        Ok(buffer)
+        #[cfg(all(feature = "fuse", unix))]
+        Command::Mount { device, mountpoint } => {
+            // Keep directories and the FAT in memory instead of reading
+            // them again for every lookup.
+            let inner = open(cli, device, false)?.into_inner();
+            let mut controller = SDController::from_device(sd_controller::CachedDevice::new(
+                inner,
+                cli.config.cache_blocks.unwrap_or(4096),
+            ));
+            controller.set_codepage(cli.codepage());
+            select_volume(cli, &mut controller)?;
+            sd_controller::fuse::mount(controller, mountpoint)
+        }
+        #[cfg(feature = "tui")]
+        Command::Browse { device, dest } => {
+            let controller = open_volume(cli, device, false)?;
+            sd_controller::tui::browse(controller, dest)
+        }
+    }
+}
+
+fn extract_tree(
+    controller: &mut Controller,
+    path: &str,
+    dest: &Path,
+    jobs: usize,
+    filter: &EntryFilter,
+) -> Result<(), SDError> {
+    let mut progress = TerminalProgress::new();
+    let summary = if jobs > 1 {
+        controller.extract_matching_parallel(path, dest, jobs, filter, &mut progress)?
+    } else {
+        controller.extract_matching(path, dest, filter, &mut progress)?
+    };
+    println!(
+        "Extracted {} files and {} directories ({}) to {}",
+        summary.files,
+        summary.directories,
+        format_size(summary.bytes),
+        dest.display()
+    );
+    if summary.is_complete() {
+        return Ok(());
+    }
+    println!("{} entries could not be read:", summary.failures.len());
+    for failure in &summary.failures {
+        println!("  {}: {}", failure.path, failure.error);
+    }
+    std::process::exit(1);
+}
+
+/// Checks every entry of a checksum manifest, printing `OK` or `FAILED`
+/// for each as `sha256sum --check` does, and exits with status 1 if any
+/// failed. The whole device is hashed at most once, before the volume is
+/// opened for the files.
+fn verify_manifest(
+    cli: &Cli,
+    device: &Path,
+    manifest: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<(), SDError> {
+    let entries = parse_checksums(&std::fs::read_to_string(manifest)?, algorithm)?;
+    let mut controller = open(cli, device, false)?;
+    let mut progress = TerminalProgress::new();
+    let mut failed = 0;
+    let mut check = |name: &str, result: Result<bool, &SDError>| match result {
+        Ok(true) => println!("{}: OK", name),
+        Ok(false) => {
+            println!("{}: FAILED", name);
+            failed += 1;
+        }
+        Err(error) => {
+            println!("{}: FAILED ({})", name, error);
+            failed += 1;
+        }
+    };
 
+    let (files, images): (Vec<_>, Vec<_>) = entries
+        .iter()
+        .partition(|entry| entry.name.starts_with('/'));
+    if !images.is_empty() {
+        if let Some(index) = cli.partition {
+            controller.open_partition(index)?;
+        }
+        let blocks = controller.num_blocks();
+        let digest = controller.hash_device(0..blocks, algorithm, &mut progress);
+        for entry in images {
+            check(&entry.name, digest.as_ref().map(|d| *d == entry.digest));
+        }
+    }
+    if !files.is_empty() {
+        select_volume(cli, &mut controller)?;
+        for entry in files {
+            let digest = controller.hash_file(&entry.name, algorithm, &mut progress);
+            check(&entry.name, digest.as_ref().map(|d| *d == entry.digest));
+        }
     }
+    if failed == 0 {
+        return Ok(());
+    }
+    eprintln!("{} of {} checksums did not match", failed, entries.len());
+    std::process::exit(1);
+}
 
-    pub fn block_size(&self) -> usize {
-        self.block_size
+/// Parses attribute letters as `attrib` takes them, in any case and order.
+fn parse_attributes(text: &str) -> Result<Attributes, String> {
+    let mut attributes = Attributes::empty();
+    for letter in text.chars() {
+        attributes.insert(match letter.to_ascii_lowercase() {
+            'r' => Attributes::READ_ONLY,
+            'h' => Attributes::HIDDEN,
+            's' => Attributes::SYSTEM,
+            'a' => Attributes::ARCHIVE,
+            _ => {
+                return Err(format!(
+                    "unknown attribute '{letter}', expected r, h, s or a"
+                ))
+            }
+        });
     }
+    Ok(attributes)
+}
 
-    pub fn read_boot_sector(&mut self) -> Result<FATBootSector, SDError> {
-        let data = self.read_block(0)?;
+/// Parses a volume serial number written as eight hex digits, optionally
+/// split in two by a dash as DOS prints it.
+fn parse_serial(text: &str) -> Result<u32, String> {
+    let digits: String = text.chars().filter(|&c| c != '-').collect();
+    if digits.len() != 8 {
+        return Err("expected eight hex digits, e.g. 1234-ABCD".to_string());
+    }
+    u32::from_str_radix(&digits, 16).map_err(|e| e.to_string())
+}
 
-        Ok(FATBootSector{
-            bytes_per_sector: u16::from_le_bytes([data[11], data[12]]),
-            sectors_per_cluster: data[13],
-            reserved_sectors: u16::from_le_bytes([data[14], data[15]]),
-            number_of_fats: data[16],
-            root_dir_entries: u16::from_le_bytes([data[17], data[18]]),
-            total_sectors_16: u16::from_le_bytes([data[19], data[20]]),
-            media_descriptor: data[21],
-            sectors_per_fat: u16::from_le_bytes([data[22], data[23]]),
-            total_sectors_32: u32::from_le_bytes([data[32], data[33], data[34], data[36]]),
-        })
+/// What the volume's blocks belong to, and the block the volume starts at
+/// as `scan` and `owner` count blocks.
+fn volume_owners(cli: &Cli, controller: &mut Controller) -> Result<(BlockOwners, u64), SDError> {
+    let start = match cli.partition {
+        Some(index) => {
+            controller.open_partition(index)?;
+            0
+        }
+        None => {
+            controller.open_volume()?;
+            controller.partition_start() as u64
+        }
+    };
+    Ok((controller.block_owners()?, start))
+}
+
+fn describe_owner(owners: &BlockOwners, start: u64, block: u64) -> String {
+    let owner = block
+        .checked_sub(start)
+        .and_then(|block| owners.owner(block).ok());
+    match owner {
+        None => "outside the volume".to_string(),
+        Some(BlockOwner::Reserved) => "reserved sectors".to_string(),
+        Some(BlockOwner::Fat { copy }) => format!("FAT {}", copy + 1),
+        Some(BlockOwner::RootDirectory) => "root directory".to_string(),
+        Some(BlockOwner::Entry {
+            path,
+            directory,
+            size,
+            cluster,
+            offset,
+        }) => {
+            if !directory && offset >= size {
+                format!("{path} (cluster {cluster}, slack past its {size} bytes)")
+            } else {
+                format!("{path} (cluster {cluster}, from byte {offset})")
+            }
+        }
+        Some(BlockOwner::Unowned { cluster, state }) => match state {
+            ClusterState::Free => format!("free cluster {cluster}"),
+            ClusterState::Used => format!("lost cluster {cluster}, used but in no file"),
+            ClusterState::Bad => format!("bad cluster {cluster}"),
+        },
+        Some(BlockOwner::Tail) => "sectors after the last cluster".to_string(),
+    }
+}
 
+/// Opens the partition holding the volume, or falls back on block 0 for a
+/// card without a partition table, whose boot sector may be too damaged to
+/// open the volume by.
+fn open_boot_sector(cli: &Cli, controller: &mut Controller) {
+    if select_volume(cli, controller).is_err() && cli.partition.is_none() {
+        controller.close_partition();
     }
+}
 
-    pub fn calculate_layout(&self, boot_sector: &FATBootSector) -> FATLayout{
-        let root_dir_sectors = ((boot_sector.root_dir_entries as u32 * 32) +
-                                (boot_sector.bytes_per_sector as u32 -1)) /
-                                boot_sector.bytes_per_sector as u32;
-        let fat_start = boot_sector.reserved_sectors as u32;
-        let root_dir_start = fat_start +
-                                (boot_sector.number_of_fats as u32 * boot_sector.sectors_per_fat as u32);
-        let data_start = root_dir_start + root_dir_sectors;
+fn print_bpb(bpb: &Bpb) {
+    for (field, value) in bpb.fields() {
+        let (offset, width) = field.location(bpb.is_fat32()).unwrap_or_default();
+        println!(
+            "{:<22} {:>3}  {:>10}  {:#0w$x}",
+            field.name(),
+            offset,
+            value,
+            value,
+            w = width * 2 + 2
+        );
+    }
+    match bpb.layout() {
+        Ok(layout) => println!(
+            "\n{:?} with {} clusters of {}, FATs from sector {}, data from sector {}",
+            layout.variant,
+            layout.cluster_count,
+            format_size(layout.cluster_size() as u64),
+            layout.fat_start,
+            layout.data_start
+        ),
+        Err(error) => println!("\nThe volume cannot be opened: {error}"),
+    }
+}
 
-        FATLayout {
-            fat_start,
-            root_dir_start,
-            data_start,
+/// Asks for a new value for each field, keeping the old one on an empty
+/// answer.
+fn edit_bpb_interactively(bpb: &mut Bpb) -> Result<(), SDError> {
+    if !io::stdin().is_terminal() {
+        return Err(SDError::InvalidArgument(
+            "give fields with --set when not at a terminal",
+        ));
+    }
+    for field in BpbField::ALL {
+        while let Some(value) = bpb.get(field) {
+            eprint!("{field} [{value}]: ");
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            let answer = answer.trim();
+            if answer.is_empty() {
+                break;
+            }
+            match parse_number(answer)
+                .and_then(|value| bpb.set(field, value).map_err(|error| error.to_string()))
+            {
+                Ok(()) => break,
+                Err(error) => eprintln!("{error}"),
+            }
         }
+    }
+    Ok(())
+}
+
+/// `field=value`, as `bpb edit --set` takes it.
+fn parse_field_value(text: &str) -> Result<(BpbField, u32), String> {
+    let (field, value) = text
+        .split_once('=')
+        .ok_or_else(|| format!("expected field=value, got {text}"))?;
+    let field = field.trim().parse().map_err(|e: SDError| e.to_string())?;
+    Ok((field, parse_number(value)?))
+}
 
+/// A number in decimal, or in hex after `0x`.
+fn parse_number(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
     }
+    .map_err(|_| format!("expected a number, got {text}"))
+}
 
+/// Bytes written as hex digits, with any spaces between them ignored.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(format!("expected an even number of hex digits, got {text}"));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("{text} is not hex"))
+        })
+        .collect()
+}
 
+/// A byte count, with an optional K, M or G suffix in powers of 1024.
+fn parse_size(text: &str) -> Result<u64, String> {
+    let upper = text.trim().to_ascii_uppercase();
+    let digits = upper.trim_end_matches(['B', 'I']);
+    let (digits, shift) = match digits.as_bytes().last() {
+        Some(b'K') => (&digits[..digits.len() - 1], 10),
+        Some(b'M') => (&digits[..digits.len() - 1], 20),
+        Some(b'G') => (&digits[..digits.len() - 1], 30),
+        _ => (digits, 0),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a size such as 4096 or 10M, got {text}"))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("{text} is too large"))
 }
 
-fn main() -> Result<(), SDError>{
+/// A transfer rate for `--limit`: a size per second, as in `10MB/s`, or
+/// just the size.
+fn parse_rate(text: &str) -> Result<u64, String> {
+    let trimmed = text.trim();
+    let size = trimmed
+        .strip_suffix("/s")
+        .or_else(|| trimmed.strip_suffix("/S"))
+        .unwrap_or(trimmed);
+    match parse_size(size)? {
+        0 => Err("the limit must be above zero".to_string()),
+        rate => Ok(rate),
+    }
+}
 
-    println!("Device path selected /dev/disk4");
-    let mut controller = SDController::new("/dev/rdisk4s1")?;
-    println!("Succesfully opened SD Card");
+/// A partition for `partition create`: `fat16` or `fat32`, a size, or
+/// both as in `fat32:1G`.
+fn parse_partition_spec(text: &str) -> Result<NewPartition, String> {
+    let (variant, size) = match text.split_once(':') {
+        Some((variant, size)) => (Some(variant), Some(size)),
+        None if text.starts_with(|c: char| c.is_ascii_digit()) => (None, Some(text)),
+        None => (Some(text), None),
+    };
+    let variant = match variant.map(str::to_ascii_lowercase).as_deref() {
+        None => None,
+        Some("fat16") => Some(FatVariant::Fat16),
+        Some("fat32") => Some(FatVariant::Fat32),
+        Some(other) => return Err(format!("expected fat16 or fat32, got {other}")),
+    };
+    Ok(NewPartition {
+        variant,
+        size: size.map(parse_size).transpose()?,
+        bootable: false,
+    })
+}
 
-    match controller.read_block(0){
-        Ok(data) => {
-            println!("Succesfully read first block:");
-            for (i, byte) in data.iter().take(16).enumerate(){
-                if i % 16 == 0 {
-                    print!("\n{:04x}: ", i);
-                }
-                print!("{:02x} ", byte);
+/// A date, or a date and time, as `FatDateTime` writes them.
+fn parse_date(text: &str) -> Result<FatDateTime, String> {
+    let text = text.trim().replace(' ', "T");
+    let text = if text.len() == 10 {
+        format!("{text}T00:00:00")
+    } else {
+        text
+    };
+    FatDateTime::parse(&text).ok_or_else(|| {
+        format!("expected a date such as 2024-01-01 between 1980 and 2107, got {text}")
+    })
+}
+
+/// Opens a device node, or an image file in any supported format. Raw
+/// images are memory-mapped unless `--direct` asks for uncached access.
+fn open(cli: &Cli, device: &Path, writable: bool) -> Result<Controller, SDError> {
+    let inner: Box<dyn BlockDevice + Send> = match &cli.overlay {
+        Some(overlay) => Box::new(OverlayDevice::with_file(
+            open_device(cli, device, false)?,
+            overlay,
+        )?),
+        None => open_device(cli, device, writable)?,
+    };
+    let inner: Box<dyn BlockDevice + Send> = match &cli.write_journal {
+        Some(journal) if writable && !cli.dry_run => {
+            let journaled = JournaledDevice::open(inner, journal, RecoveryPolicy::Replay)?;
+            if let Some(recovery) = journaled.recovery() {
+                eprint!("{}: ", journal.display());
+                print_recovery(recovery);
             }
+            Box::new(journaled)
+        }
+        _ => inner,
+    };
+    let mut controller = SDController::from_device(inner);
+    controller.set_codepage(cli.codepage());
+    if writable {
+        controller.enable_writes();
+    }
+    Ok(controller)
+}
+
+/// The device or image at `device`, held to `--limit` if one was given.
+fn open_device(
+    cli: &Cli,
+    device: &Path,
+    writable: bool,
+) -> Result<Box<dyn BlockDevice + Send>, SDError> {
+    let inner = open_backend(cli, device, writable)?;
+    Ok(match cli.limit {
+        Some(rate) => Box::new(ThrottledDevice::new(inner, rate)),
+        None => inner,
+    })
+}
+
+/// The device or image at `device`, in whichever backend suits it.
+fn open_backend(
+    cli: &Cli,
+    device: &Path,
+    writable: bool,
+) -> Result<Box<dyn BlockDevice + Send>, SDError> {
+    if writable && cli.dry_run {
+        let base = open_backend(cli, device, false)?;
+        let recorder = DryRunDevice::new(base).on_write(|write| eprintln!("dry run: {write}"));
+        return Ok(Box::new(recorder));
+    }
+    if let Some(url) = &cli.remote {
+        if writable {
+            return Err(SDError::Unsupported("writing to a remote device"));
+        }
+        let remote = RemoteDevice::open(url, &device.to_string_lossy(), &read_token(cli)?)?;
+        return Ok(Box::new(ReadOnlyDevice::new(remote)));
+    }
+    let device = cli.config.resolve_device(device);
+    let format = if device.is_file() {
+        Some(ImageFormat::detect(device)?)
+    } else {
+        None
+    };
+    let inner: Box<dyn BlockDevice + Send> =
+        if format.is_some_and(|format| format != ImageFormat::Raw) {
+            open_image(device, writable)?
+        } else if format.is_some() && !cli.direct && !uses_io_uring(cli) {
+            let file = File::options().read(true).write(writable).open(device)?;
+            Box::new(MmapDevice::from_file(&file, cli.block_size(), writable)?)
+        } else {
+            let raw = open_raw_device(
+                device,
+                RawOptions {
+                    writable,
+                    unmount: cli.unmount,
+                    block_size: cli.block_size(),
+                    direct: cli.direct,
+                },
+            )?;
+            raw_backend(cli, raw.into_inner())?
+        };
+    if !writable {
+        return Ok(Box::new(ReadOnlyDevice::new(inner)));
+    }
+    Ok(inner)
+}
+
+/// The file given with `--config`, or the default one if it exists.
+fn load_config(path: Option<&Path>) -> Result<Config, SDError> {
+    match path {
+        Some(path) => Config::load(path),
+        None => Config::load_default(),
+    }
+}
+
+/// Refuses to write to `device` if the config file denies it, or if it
+/// looks like a system disk and neither `force` nor the config file allows
+/// it. `what` completes "Pass --force if you really mean to".
+fn check_write_target(cli: &Cli, device: &Path, force: bool, what: &str) -> Result<(), SDError> {
+    if cli.dry_run {
+        return Ok(());
+    }
+    let device = cli.config.resolve_device(device);
+    if cli.config.denies_write(device) {
+        return Err(SDError::WriteDenied(device.display().to_string()));
+    }
+    if !force && !cli.config.allows_write(device) && is_system_disk(device)? {
+        eprintln!("Pass --force if you really mean to {what}.");
+        return Err(SDError::SystemDisk(device.display().to_string()));
+    }
+    Ok(())
+}
+
+/// `watch`'s action with the placeholders filled in for `device`.
+fn action_args(action: &[String], device: &DeviceInfo) -> Vec<String> {
+    let time = FatTimestamps::now()
+        .modified_at()
+        .map(|time| {
+            format!(
+                "{:04}{:02}{:02}-{:02}{:02}{:02}",
+                time.year, time.month, time.day, time.hour, time.minute, time.second
+            )
+        })
+        .unwrap_or_default();
+    let name = device
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    action
+        .iter()
+        .map(|arg| {
+            arg.replace("{device}", &device.path.to_string_lossy())
+                .replace("{name}", &name)
+                .replace("{time}", &time)
+        })
+        .collect()
+}
+
+/// The token from `--token-file`, or else from SDCTL_TOKEN.
+fn read_token(cli: &Cli) -> Result<String, SDError> {
+    let token = match &cli.token_file {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => std::env::var("SDCTL_TOKEN").unwrap_or_default(),
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(SDError::InvalidArgument(
+            "a token is needed, from --token-file or SDCTL_TOKEN",
+        ));
+    }
+    Ok(token.to_string())
+}
+
+/// A raw device as opened, or driven through io_uring with `--io-uring`.
+fn raw_backend(cli: &Cli, device: FileDevice) -> Result<Box<dyn BlockDevice + Send>, SDError> {
+    if uses_io_uring(cli) {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        return Ok(Box::new(sd_controller::UringDevice::new(device)?));
+    }
+    Ok(Box::new(device))
+}
+
+fn uses_io_uring(cli: &Cli) -> bool {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    return cli.io_uring;
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    {
+        let _ = cli;
+        false
+    }
+}
+
+/// Opens the device and selects the volume to work on: the partition given
+/// with `--partition`, or else the one `open_volume` finds.
+fn open_volume(cli: &Cli, device: &Path, writable: bool) -> Result<Controller, SDError> {
+    let mut controller = open(cli, device, writable)?;
+    select_volume(cli, &mut controller)?;
+    if let Ok(Some(flags)) = controller.dirty_flags() {
+        if flags.is_dirty() {
+            eprintln!(
+                "Warning: {} was not unmounted cleanly, so its FAT and directories may be \
+                 half written; run `check` or `repair` before trusting them.",
+                device.display()
+            );
+        }
+    }
+    Ok(controller)
+}
+
+/// `path` with `suffix` added to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn print_recovery(recovery: Recovery) {
+    match recovery {
+        Recovery::Replayed { blocks } => {
+            println!("Finished an interrupted write of {blocks} blocks")
+        }
+        Recovery::RolledBack { blocks } => {
+            println!("Undid an interrupted write of {blocks} blocks")
+        }
+        Recovery::Discarded => println!("Dropped a write that had not begun"),
+    }
+}
+
+fn print_file_diff(diff: &FileDiff) {
+    for path in &diff.added {
+        println!("+ {}", path);
+    }
+    for path in &diff.removed {
+        println!("- {}", path);
+    }
+    for changed in &diff.changed {
+        let what = match changed.change {
+            FileChange::Kind => "file or directory",
+            FileChange::Contents => "contents",
+            FileChange::Metadata => "attributes or time",
+        };
+        println!("M {} ({})", changed.path, what);
+    }
+}
+
+fn print_block_diff(diff: &BlockDiff, first: &Path, second: &Path) {
+    for run in &diff.differing {
+        println!("blocks {}..{} differ", run.start, run.end);
+    }
+    if diff.blocks.0 != diff.blocks.1 {
+        println!(
+            "{} has {} blocks, {} has {}",
+            first.display(),
+            diff.blocks.0,
+            second.display(),
+            diff.blocks.1
+        );
+    }
+    println!(
+        "{} of {} blocks differ",
+        diff.differing_blocks(),
+        diff.blocks.0.min(diff.blocks.1)
+    );
+}
+
+/// Prints `warning` and asks for `device` to be typed back. Refuses
+/// without asking when standard input is not a terminal.
+fn confirm(warning: &str, device: &Path) -> Result<bool, SDError> {
+    eprintln!("{warning}");
+    if !io::stdin().is_terminal() {
+        eprintln!("Pass --yes to confirm without a terminal.");
+        return Ok(false);
+    }
+    eprint!("Type {} to continue: ", device.display());
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(Path::new(answer.trim()) == device)
+}
+
+fn select_volume<D: BlockDevice>(
+    cli: &Cli,
+    controller: &mut SDController<D>,
+) -> Result<(), SDError> {
+    match cli.partition {
+        Some(index) => {
+            controller.open_partition(index)?;
+        }
+        None => {
+            controller.open_volume()?;
         }
-        Err(e) => println!("Failed to read block: {}", e),
     }
+    Ok(())
+}
+
+/// What `info` shows, gathered before it is printed.
+#[derive(Serialize)]
+struct VolumeInfo {
+    device_blocks: u64,
+    partition_table: Option<PartitionTable>,
+    /// The partition holding the volume, if the device is partitioned.
+    partition: Option<usize>,
+    boot_sector: Option<FATBootSector>,
+    /// Whether `boot_sector` is the primary or the FAT32 backup.
+    boot_sector_copy: BootSectorCopy,
+    exfat_boot_sector: Option<ExFatBootSector>,
+    /// The FSInfo hints of a FAT32 volume, checked against its layout.
+    fs_info: Option<FsInfo>,
+    dirty_flags: Option<DirtyFlags>,
+    layout: FATLayout,
+}
+
+fn print_partitions(table: &PartitionTable, block_size: usize) {
+    for (i, partition) in table.partitions.iter().enumerate() {
+        println!(
+            "{i}: {} start {} blocks {} ({}){}",
+            partition.partition_type,
+            partition.start_lba,
+            partition.sector_count,
+            format_size(partition.sector_count * block_size as u64),
+            if partition.bootable { ", bootable" } else { "" }
+        );
+    }
+}
+
+fn read_info(controller: &mut Controller, partition: Option<usize>) -> Result<VolumeInfo, SDError> {
+    let device_blocks = controller.device().num_blocks();
+    let layout = match partition {
+        Some(_) => controller
+            .read_partition_table()
+            .map(DiskLayout::Partitioned),
+        None => controller.detect_layout(),
+    };
+    let (partition_table, partition) = match layout {
+        Ok(DiskLayout::Partitioned(table)) if !table.partitions.is_empty() => {
+            let index = match partition {
+                Some(index) => index,
+                None => controller.open_volume()?.unwrap_or(0),
+            };
+            controller.open_partition(index)?;
+            (Some(table), Some(index))
+        }
+        _ => (None, None),
+    };
+
+    let is_exfat = controller
+        .read_block(0)
+        .map(|data| exfat::is_exfat(&data))
+        .unwrap_or(false);
+    let (boot_sector, exfat_boot_sector, layout) = if is_exfat {
+        let boot_sector = controller.read_exfat_boot_sector()?;
+        let layout = boot_sector.layout();
+        (None, Some(boot_sector), layout)
+    } else {
+        let boot_sector = controller.read_boot_sector()?;
+        let layout = controller.calculate_layout(&boot_sector);
+        (Some(boot_sector), None, layout)
+    };
+    let fs_info = if layout.variant == FatVariant::Fat32 {
+        controller.fsinfo().ok()
+    } else {
+        None
+    };
+    Ok(VolumeInfo {
+        device_blocks,
+        partition_table,
+        partition,
+        boot_sector,
+        boot_sector_copy: controller.boot_sector_copy(),
+        exfat_boot_sector,
+        fs_info,
+        dirty_flags: controller.dirty_flags().ok().flatten(),
+        layout,
+    })
+}
 
-    match controller.read_boot_sector() {
-        Ok(boot_sector) => {
-            println!("\nFAT16 Boot Sector Information:");
-            println!("Bytes per sector: {}", boot_sector.bytes_per_sector);
-            println!("Sectors per cluster {}", boot_sector.sectors_per_cluster);
-            println!("Reserved sectors {}", boot_sector.reserved_sectors);
-            println!("Number of FATs: {}", boot_sector.number_of_fats);
-            println!("Root directory entries: {}", boot_sector.root_dir_entries);
-            println!("Total sectors: {}",
-                if boot_sector.total_sectors_16 > 0{
-                    boot_sector.total_sectors_16 as u32
+fn print_info(info: &VolumeInfo) {
+    println!("Device blocks: {}", info.device_blocks);
+    match (&info.partition_table, info.partition) {
+        (Some(table), Some(index)) => {
+            println!("\nPartition table:");
+            for (i, partition) in table.partitions.iter().enumerate() {
+                let name = if partition.name.is_empty() {
+                    String::new()
                 } else {
-                    boot_sector.total_sectors_32
-                });
-            println!("Sectors per FAT: {}", boot_sector.sectors_per_fat);
+                    format!(" \"{}\"", partition.name)
+                };
+                println!(
+                    "{}: type {} start {} sectors {}{}{}",
+                    i,
+                    partition.partition_type,
+                    partition.start_lba,
+                    partition.sector_count,
+                    name,
+                    if partition.bootable {
+                        " (bootable)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            println!(
+                "Opened partition {} at LBA {}",
+                index, table.partitions[index].start_lba
+            );
+        }
+        _ => println!("\nNo partition table, treating device as a single volume"),
+    }
+
+    let layout = &info.layout;
+    if let Some(boot_sector) = &info.exfat_boot_sector {
+        println!("\nexFAT Boot Sector Information:");
+        println!("Bytes per sector: {}", boot_sector.bytes_per_sector());
+        println!("Sectors per cluster: {}", boot_sector.sectors_per_cluster());
+        println!("Number of FATs: {}", boot_sector.number_of_fats);
+        println!("Volume length: {}", boot_sector.volume_length);
+        println!("Cluster count: {}", boot_sector.cluster_count);
+        println!("Root directory cluster: {}", boot_sector.root_dir_cluster);
+        println!(
+            "Volume serial number: {:08X}",
+            boot_sector.volume_serial_number
+        );
+
+        println!("\nFilesystem layout (ExFat):");
+        println!("FAT starts at sector: {}", boot_sector.fat_offset);
+        println!(
+            "Cluster heap starts at sector: {}",
+            boot_sector.cluster_heap_offset
+        );
+    } else if let Some(boot_sector) = &info.boot_sector {
+        println!("\nFAT Boot Sector Information:");
+        if info.boot_sector_copy == BootSectorCopy::Backup {
+            println!(
+                "Read from the backup at sector {}: the primary is damaged",
+                boot::BACKUP_BOOT_SECTOR
+            );
+        }
+        println!("Bytes per sector: {}", boot_sector.bytes_per_sector);
+        println!("Sectors per cluster {}", boot_sector.sectors_per_cluster);
+        println!("Reserved sectors {}", boot_sector.reserved_sectors);
+        println!("Number of FATs: {}", boot_sector.number_of_fats);
+        println!("Root directory entries: {}", boot_sector.root_dir_entries);
+        println!("Total sectors: {}", boot_sector.total_sectors());
+        println!("Sectors per FAT: {}", boot_sector.fat_size());
+        if let Some(flags) = &info.dirty_flags {
+            println!(
+                "State: {}",
+                match (flags.is_dirty(), flags.hard_error) {
+                    (false, _) => "clean",
+                    (true, false) => "dirty, not unmounted cleanly",
+                    (true, true) => "dirty, with read or write errors recorded",
+                }
+            );
+        }
+
+        if layout.variant == FatVariant::Fat32 {
+            println!("Root directory cluster: {}", boot_sector.root_cluster);
+            println!("FSInfo sector: {}", boot_sector.fs_info_sector);
+            println!("Backup boot sector: {}", boot_sector.backup_boot_sector);
+            if let Some(fs_info) = &info.fs_info {
+                let hint =
+                    |value: Option<u32>| value.map_or("unknown".to_string(), |v| v.to_string());
+                println!("FSInfo free clusters: {}", hint(fs_info.free_clusters));
+                println!("FSInfo next free cluster: {}", hint(fs_info.next_free));
+            }
+        }
 
-            let layout = controller.calculate_layout(&boot_sector);
-            println!("\nFilesystem layout:");
-            println!("FAT starts at sector: {}", layout.fat_start);
-            println!("Root directory starts at sector: {}", layout.root_dir_start);
-            println!("Data area starts at sector: {}", layout.data_start);
+        println!("\nFilesystem layout ({:?}):", layout.variant);
+        println!("FAT starts at sector: {}", layout.fat_start);
+        println!("Root directory starts at sector: {}", layout.root_dir_start);
+        println!("Data area starts at sector: {}", layout.data_start);
+        println!("Cluster count: {}", layout.cluster_count);
+    }
+}
+
+/// An entry as `ls --json` prints it: the raw directory entry, plus the
+/// name and times decoded.
+#[derive(Serialize)]
+struct ListedEntry<'a> {
+    full_name: String,
+    is_dir: bool,
+    /// Seconds since the Unix epoch, or `None` when the entry leaves it
+    /// unset.
+    modified: Option<u64>,
+    created_at: Option<FatDateTime>,
+    accessed_at: Option<FatDateTime>,
+    modified_at: Option<FatDateTime>,
+    #[serde(flatten)]
+    entry: &'a DirEntry,
+}
 
+impl<'a> ListedEntry<'a> {
+    fn new(entry: &'a DirEntry) -> Self {
+        let timestamps = &entry.timestamps;
+        let modified_at = timestamps.modified_at();
+        ListedEntry {
+            full_name: entry.full_name(),
+            is_dir: entry.is_dir(),
+            modified: modified_at.map(|time| time.unix_time()),
+            created_at: timestamps.created_at(),
+            accessed_at: timestamps.accessed_at(),
+            modified_at,
+            entry,
         }
-        Err(e) => println!("Failed to read boot sector: {}", e),
     }
+}
+
+fn print_report(report: &Report, format: ReportFormat) -> Result<(), SDError> {
+    match format {
+        ReportFormat::Json => print_json(report),
+        ReportFormat::Csv => Ok(report.write_csv(io::stdout().lock())?),
+    }
+}
+
+/// Writes `value` to standard output as pretty-printed JSON.
+/// A field `bpb show --json` lists.
+#[derive(Serialize)]
+struct BpbFieldJson {
+    field: BpbField,
+    offset: usize,
+    value: u32,
+}
+
+/// A block `owner --json` looked up; `owner` is null outside the volume.
+#[derive(Serialize)]
+struct BlockOwnerJson {
+    block: u64,
+    owner: Option<BlockOwner>,
+}
 
+fn print_json<T: Serialize>(value: &T) -> Result<(), SDError> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, value).map_err(io::Error::from)?;
+    writeln!(stdout)?;
     Ok(())
 }
@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+#[cfg(feature = "json")]
+use std::io::{self, Read, Write};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::FatDateTime;
+use crate::error::SDError;
+use crate::filter::EntryFilter;
+use crate::hash::HashAlgorithm;
+use crate::progress::{Progress, ProgressSink, Stopwatch};
+
+/// A record of every file below a directory of the volume, to check the
+/// card against later. Stored as JSON with the `json` feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Manifest {
+    pub algorithm: HashAlgorithm,
+    /// The directory the manifest was taken of; paths of files include it.
+    pub root: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<FatDateTime>,
+    /// The digest in lowercase hex.
+    pub hash: String,
+}
+
+#[cfg(feature = "json")]
+impl Manifest {
+    pub fn write_json<W: Write>(&self, out: W) -> Result<(), SDError> {
+        serde_json::to_writer_pretty(out, self).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    pub fn read_json<R: Read>(input: R) -> Result<Manifest, SDError> {
+        Ok(serde_json::from_reader(input).map_err(io::Error::from)?)
+    }
+}
+
+/// What is wrong with one file of a manifest.
+#[derive(Debug)]
+pub enum ManifestProblem {
+    /// The size and modification time are as recorded but the contents
+    /// are not. Nothing wrote the file through the filesystem; the card
+    /// lost or changed data on its own.
+    Corrupted,
+    /// The file was written since, having another size or modification
+    /// time.
+    Changed,
+    Missing,
+    Unreadable(SDError),
+}
+
+#[derive(Debug)]
+pub struct ManifestMismatch {
+    pub path: String,
+    pub problem: ManifestProblem,
+}
+
+#[derive(Debug, Default)]
+pub struct ManifestReport {
+    /// Files found as recorded.
+    pub verified: usize,
+    pub mismatches: Vec<ManifestMismatch>,
+    /// Files below the manifest's root that it does not list.
+    pub added: Vec<String>,
+}
+
+impl ManifestReport {
+    /// Whether every file of the manifest is on the card as recorded.
+    /// Files added since do not count against it.
+    pub fn is_intact(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Passes on the progress of hashing one file as progress through all the
+/// files of a manifest.
+struct Overall<'a, P: ProgressSink> {
+    progress: &'a mut P,
+    before: u64,
+    total: u64,
+    stopwatch: Stopwatch,
+}
+
+impl<P: ProgressSink> ProgressSink for Overall<'_, P> {
+    fn report(&mut self, progress: &Progress) {
+        self.progress.report(&Progress {
+            bytes_done: self.before + progress.bytes_done,
+            bytes_total: self.total,
+            elapsed: self.stopwatch.elapsed(),
+            ..*progress
+        });
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Hashes every file below the directory at `root`. Any file or
+    /// directory that cannot be read fails the whole manifest, which would
+    /// otherwise not cover the card.
+    pub fn create_manifest<P: ProgressSink>(
+        &mut self,
+        root: &str,
+        algorithm: HashAlgorithm,
+        progress: &mut P,
+    ) -> Result<Manifest, SDError> {
+        self.create_manifest_matching(root, algorithm, &EntryFilter::default(), progress)
+    }
+
+    /// `create_manifest` of only the files `filter` takes. Verify it with
+    /// the same filter, or the files left out show up as added.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(root, %algorithm))
+    )]
+    pub fn create_manifest_matching<P: ProgressSink>(
+        &mut self,
+        root: &str,
+        algorithm: HashAlgorithm,
+        filter: &EntryFilter,
+        progress: &mut P,
+    ) -> Result<Manifest, SDError> {
+        let mut files = Vec::new();
+        for walked in self.walk_from(root)? {
+            let walked = walked?;
+            if !walked.entry.is_dir() && filter.matches(&walked.entry) {
+                files.push(ManifestEntry {
+                    path: walked.path,
+                    size: walked.entry.size,
+                    modified: walked.entry.timestamps.modified_at(),
+                    hash: String::new(),
+                });
+            }
+        }
+        let mut overall = Overall {
+            progress,
+            before: 0,
+            total: files.iter().map(|file| file.size).sum(),
+            stopwatch: Stopwatch::start(),
+        };
+        for file in &mut files {
+            file.hash = self
+                .hash_file(&file.path, algorithm, &mut overall)?
+                .to_string();
+            overall.before += file.size;
+        }
+        Ok(Manifest {
+            algorithm,
+            root: root.to_string(),
+            files,
+        })
+    }
+
+    /// Checks every file of `manifest` against the card, and lists the
+    /// files below its root that have been added since. Only files whose
+    /// size and modification time are as recorded are hashed.
+    pub fn verify_manifest<P: ProgressSink>(
+        &mut self,
+        manifest: &Manifest,
+        progress: &mut P,
+    ) -> Result<ManifestReport, SDError> {
+        self.verify_manifest_matching(manifest, &EntryFilter::default(), progress)
+    }
+
+    /// `verify_manifest` for a manifest made with `filter`: added files
+    /// the filter leaves out are not listed.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(root = manifest.root))
+    )]
+    pub fn verify_manifest_matching<P: ProgressSink>(
+        &mut self,
+        manifest: &Manifest,
+        filter: &EntryFilter,
+        progress: &mut P,
+    ) -> Result<ManifestReport, SDError> {
+        let mut report = ManifestReport::default();
+        let mut overall = Overall {
+            progress,
+            before: 0,
+            total: manifest.files.iter().map(|file| file.size).sum(),
+            stopwatch: Stopwatch::start(),
+        };
+        for file in &manifest.files {
+            if let Some(problem) = self.check_manifest_entry(manifest, file, &mut overall) {
+                report.mismatches.push(ManifestMismatch {
+                    path: file.path.clone(),
+                    problem,
+                });
+            } else {
+                report.verified += 1;
+            }
+            overall.before += file.size;
+        }
+
+        // A directory that cannot be read shows up above as its files
+        // being unreadable.
+        let listed: HashSet<String> = manifest
+            .files
+            .iter()
+            .map(|file| file.path.to_ascii_uppercase())
+            .collect();
+        report.added = self
+            .walk_from(&manifest.root)?
+            .filter_map(Result::ok)
+            .filter(|walked| !walked.entry.is_dir() && filter.matches(&walked.entry))
+            .map(|walked| walked.path)
+            .filter(|path| !listed.contains(&path.to_ascii_uppercase()))
+            .collect();
+        Ok(report)
+    }
+
+    fn check_manifest_entry<P: ProgressSink>(
+        &mut self,
+        manifest: &Manifest,
+        file: &ManifestEntry,
+        progress: &mut P,
+    ) -> Option<ManifestProblem> {
+        let entry = match self.stat(&file.path) {
+            Ok(entry) if !entry.is_dir() => entry,
+            Ok(_) => return Some(ManifestProblem::Changed),
+            Err(error) if matches!(error.root_cause(), SDError::NotFound(_)) => {
+                return Some(ManifestProblem::Missing)
+            }
+            Err(error) => return Some(ManifestProblem::Unreadable(error)),
+        };
+        if entry.size != file.size || entry.timestamps.modified_at() != file.modified {
+            return Some(ManifestProblem::Changed);
+        }
+        match self.hash_file(&file.path, manifest.algorithm, progress) {
+            Ok(digest) if digest.to_string() == file.hash.to_ascii_lowercase() => None,
+            Ok(_) => Some(ManifestProblem::Corrupted),
+            Err(error) => Some(ManifestProblem::Unreadable(error)),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+use memmap2::UncheckedAdvice;
+use memmap2::{Mmap, MmapMut};
+
+use crate::block::{byte_range, BlockDevice};
+use crate::error::SDError;
+use crate::log::trace;
+
+enum Map {
+    ReadOnly(Mmap),
+    Writable(MmapMut),
+}
+
+/// A disk image file accessed through a memory map. Reads and writes are
+/// copies to and from the mapping, with no system call per request, which
+/// makes whole-volume passes such as `check` or `deleted_entries`
+/// much faster over large images than with a `FileDevice`.
+///
+/// Only for regular files: device nodes cannot be mapped on every system,
+/// and a card pulled while mapped would crash the process rather than
+/// fail a read. Another process truncating the image does the same.
+pub struct MmapDevice {
+    map: Map,
+    block_size: usize,
+}
+
+impl MmapDevice {
+    /// Maps an image read-only, with 512-byte blocks.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        MmapDevice::from_file(&File::open(path)?, 512, false)
+    }
+
+    pub fn open_rw<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        MmapDevice::from_file(&file, 512, true)
+    }
+
+    /// Maps an open image file, which has to have been opened for writing
+    /// if `writable` is set. A partial block at the end of the file is left
+    /// out.
+    pub fn from_file(file: &File, block_size: usize, writable: bool) -> Result<Self, SDError> {
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        if !file.metadata()?.is_file() {
+            return Err(SDError::Unsupported(
+                "memory-mapping anything but an image file",
+            ));
+        }
+        // SAFETY: the map is only sound while no one else changes the file
+        // under it. That cannot be enforced; the type's documentation says
+        // what happens otherwise.
+        let map = unsafe {
+            if writable {
+                Map::Writable(MmapMut::map_mut(file)?)
+            } else {
+                Map::ReadOnly(Mmap::map(file)?)
+            }
+        };
+        Ok(MmapDevice { map, block_size })
+    }
+
+    pub fn is_writable(&self) -> bool {
+        matches!(self.map, Map::Writable(_))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        let bytes: &[u8] = match &self.map {
+            Map::ReadOnly(map) => map,
+            Map::Writable(map) => map,
+        };
+        &bytes[..bytes.len() - bytes.len() % self.block_size]
+    }
+}
+
+impl BlockDevice for MmapDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.bytes().len() / self.block_size) as u64
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_blocks(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if !buffer.len().is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let bytes = self.bytes();
+        let range = byte_range(bytes.len(), self.block_size, start, buffer.len())?;
+        buffer.copy_from_slice(&bytes[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.write_blocks(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !data.len().is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let total = self.bytes().len();
+        let Map::Writable(map) = &mut self.map else {
+            return Err(SDError::ReadOnly);
+        };
+        let range = byte_range(total, self.block_size, start, data.len())?;
+        map[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Writes the modified pages back to the image file.
+    fn flush(&mut self) -> Result<(), SDError> {
+        if let Map::Writable(map) = &self.map {
+            trace!("flushing memory map");
+            map.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Zeros the blocks. On Linux the whole pages among them are punched
+    /// out of the image file, where its file system allows.
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        let total = self.bytes().len();
+        let Map::Writable(map) = &mut self.map else {
+            return Err(SDError::ReadOnly);
+        };
+        let range = byte_range(
+            total,
+            self.block_size,
+            start,
+            count as usize * self.block_size,
+        )?;
+        #[cfg(target_os = "linux")]
+        let range = {
+            let page = page_size();
+            let first = range.start.next_multiple_of(page);
+            let last = range.end / page * page;
+            // SAFETY: the pages are within the map and hold no references;
+            // they read as zeros once removed, as the file does there.
+            let removed = first < last
+                && unsafe {
+                    map.unchecked_advise_range(UncheckedAdvice::Remove, first, last - first)
+                }
+                .is_ok();
+            if removed {
+                map[range.start..first].fill(0);
+                last..range.end
+            } else {
+                range
+            }
+        };
+        map[range].fill(0);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn page_size() -> usize {
+    const SC_PAGESIZE: i32 = 30;
+    extern "C" {
+        fn sysconf(name: i32) -> core::ffi::c_long;
+    }
+    // SAFETY: `sysconf` only reads system configuration.
+    unsafe { sysconf(SC_PAGESIZE) as usize }
+}
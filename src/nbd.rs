@@ -0,0 +1,396 @@
+//! Exporting a device over the network block device protocol, so that a
+//! virtual machine or another host's kernel can attach it as a disk:
+//!
+//! ```text
+//! nbd-client -N sd pi.local 10809 /dev/nbd0
+//! qemu-system-x86_64 -drive file=nbd://pi.local:10809/sd,format=raw
+//! ```
+//!
+//! Only the fixed newstyle handshake and simple replies are spoken. NBD has
+//! no authentication without TLS, which is not offered, so a server should
+//! listen where only trusted hosts can reach it.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::partition::block_index;
+
+/// The port registered for NBD.
+pub const DEFAULT_PORT: u16 = 10809;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const TRANSMIT_HAS_FLAGS: u16 = 1 << 0;
+const TRANSMIT_READ_ONLY: u16 = 1 << 1;
+const TRANSMIT_SEND_FLUSH: u16 = 1 << 2;
+const TRANSMIT_SEND_FUA: u16 = 1 << 3;
+const TRANSMIT_SEND_TRIM: u16 = 1 << 5;
+
+const CMD_FLAG_FUA: u16 = 1 << 0;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+
+/// Longest option a client may send during the handshake.
+const MAX_OPTION_BYTES: u32 = 64 << 10;
+
+/// Longest read or write a client may ask for.
+pub const MAX_REQUEST_BYTES: u32 = 32 << 20;
+
+#[derive(Debug, Clone)]
+pub struct NbdOptions {
+    /// The export name clients ask for. The empty name, which asks for the
+    /// default export, is accepted too.
+    pub name: String,
+}
+
+impl Default for NbdOptions {
+    fn default() -> Self {
+        NbdOptions {
+            name: "sd".to_string(),
+        }
+    }
+}
+
+/// What a client did over one connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NbdStats {
+    pub requests: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Requests answered with an error.
+    pub errors: u64,
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    input.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn option_reply<W: Write>(out: &mut W, option: u32, reply: u32, data: &[u8]) -> io::Result<()> {
+    out.write_all(&OPTION_REPLY_MAGIC.to_be_bytes())?;
+    out.write_all(&option.to_be_bytes())?;
+    out.write_all(&reply.to_be_bytes())?;
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(data)?;
+    out.flush()
+}
+
+fn simple_reply<W: Write>(out: &mut W, error: u32, handle: u64, data: &[u8]) -> io::Result<()> {
+    out.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+    out.write_all(&error.to_be_bytes())?;
+    out.write_all(&handle.to_be_bytes())?;
+    out.write_all(data)?;
+    out.flush()
+}
+
+fn protocol_error(reason: &'static str) -> SDError {
+    SDError::IO(io::Error::new(ErrorKind::InvalidData, reason))
+}
+
+/// The errno an NBD client is told for `error`.
+fn errno(error: &SDError) -> u32 {
+    match error.root_cause() {
+        SDError::ReadOnly => EPERM,
+        SDError::BlockOutOfRange(_) | SDError::InvalidArgument(_) => EINVAL,
+        _ => EIO,
+    }
+}
+
+/// The name asked for by `NBD_OPT_INFO` or `NBD_OPT_GO`, or `None` if the
+/// option is malformed.
+fn requested_name(data: &[u8]) -> Option<&[u8]> {
+    let length = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let name = data.get(4..4 + length)?;
+    let requests = u16::from_be_bytes(data.get(4 + length..6 + length)?.try_into().ok()?);
+    (data.len() == 6 + length + 2 * requests as usize).then_some(name)
+}
+
+/// The byte just past a request's range. The offset and length come from
+/// the client, so a sum that overflows is refused rather than trusted.
+fn nbd_end(offset: u64, length: u32) -> Result<u64, SDError> {
+    offset
+        .checked_add(length as u64)
+        .ok_or(SDError::InvalidArgument(
+            "NBD request past the end of the device",
+        ))
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Serves the open partition, or the whole device, to one NBD client
+    /// until it disconnects. The export is read-only unless writes are
+    /// enabled. Failed requests are answered with an errno and counted;
+    /// only a broken connection or protocol ends the session with an
+    /// error.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn serve_nbd<S: Read + Write>(
+        &mut self,
+        mut stream: S,
+        options: &NbdOptions,
+    ) -> Result<NbdStats, SDError> {
+        let mut stats = NbdStats::default();
+        if !self.nbd_handshake(&mut stream, options)? {
+            return Ok(stats);
+        }
+        loop {
+            let magic = match read_u32(&mut stream) {
+                Ok(magic) => magic,
+                // Clients may hang up without NBD_CMD_DISC.
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            if magic != REQUEST_MAGIC {
+                return Err(protocol_error("bad NBD request magic"));
+            }
+            let flags = read_u16(&mut stream)?;
+            let command = read_u16(&mut stream)?;
+            let handle = read_u64(&mut stream)?;
+            let offset = read_u64(&mut stream)?;
+            let length = read_u32(&mut stream)?;
+            stats.requests += 1;
+            let result = match command {
+                CMD_READ => match self.nbd_read(offset, length) {
+                    Ok(data) => {
+                        stats.bytes_read += data.len() as u64;
+                        simple_reply(&mut stream, 0, handle, &data)?;
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                },
+                CMD_WRITE => {
+                    if length > MAX_REQUEST_BYTES {
+                        return Err(protocol_error("NBD write too long"));
+                    }
+                    let mut data = vec![0; length as usize];
+                    stream.read_exact(&mut data)?;
+                    self.nbd_write(offset, &data).and_then(|()| {
+                        stats.bytes_written += data.len() as u64;
+                        if flags & CMD_FLAG_FUA != 0 {
+                            self.flush()?;
+                        }
+                        Ok(())
+                    })
+                }
+                CMD_DISC => {
+                    if self.is_writable() {
+                        self.flush()?;
+                    }
+                    break;
+                }
+                CMD_FLUSH => self.flush(),
+                CMD_TRIM => self.nbd_trim(offset, length),
+                _ => Err(SDError::InvalidArgument("unknown NBD command")),
+            };
+            let error = match &result {
+                Ok(()) => 0,
+                Err(e) => {
+                    stats.errors += 1;
+                    match (command, e.root_cause()) {
+                        (CMD_WRITE, SDError::BlockOutOfRange(_)) => ENOSPC,
+                        _ => errno(e),
+                    }
+                }
+            };
+            simple_reply(&mut stream, error, handle, &[])?;
+        }
+        Ok(stats)
+    }
+
+    /// Runs the handshake and option haggling. Returns whether the client
+    /// went on to transmission rather than aborting.
+    fn nbd_handshake<S: Read + Write>(
+        &mut self,
+        stream: &mut S,
+        options: &NbdOptions,
+    ) -> Result<bool, SDError> {
+        stream.write_all(&NBDMAGIC.to_be_bytes())?;
+        stream.write_all(&IHAVEOPT.to_be_bytes())?;
+        stream.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+        stream.flush()?;
+        let client_flags = read_u32(stream)?;
+        if client_flags & FLAG_FIXED_NEWSTYLE as u32 == 0 {
+            return Err(protocol_error("NBD client does not speak fixed newstyle"));
+        }
+        let no_zeroes = client_flags & FLAG_NO_ZEROES as u32 != 0;
+
+        let size = self.num_blocks() * self.block_size() as u64;
+        let mut flags = TRANSMIT_HAS_FLAGS | TRANSMIT_SEND_FLUSH;
+        if self.is_writable() {
+            flags |= TRANSMIT_SEND_FUA | TRANSMIT_SEND_TRIM;
+        } else {
+            flags |= TRANSMIT_READ_ONLY;
+        }
+        let known = |name: &[u8]| name.is_empty() || name == options.name.as_bytes();
+        loop {
+            if read_u64(stream)? != IHAVEOPT {
+                return Err(protocol_error("bad NBD option magic"));
+            }
+            let option = read_u32(stream)?;
+            let length = read_u32(stream)?;
+            if length > MAX_OPTION_BYTES {
+                return Err(protocol_error("NBD option too long"));
+            }
+            let mut data = vec![0; length as usize];
+            stream.read_exact(&mut data)?;
+            match option {
+                OPT_EXPORT_NAME => {
+                    if !known(&data) {
+                        // This option has no error reply; hanging up is it.
+                        return Err(protocol_error("NBD client asked for an unknown export"));
+                    }
+                    stream.write_all(&size.to_be_bytes())?;
+                    stream.write_all(&flags.to_be_bytes())?;
+                    if !no_zeroes {
+                        stream.write_all(&[0; 124])?;
+                    }
+                    stream.flush()?;
+                    return Ok(true);
+                }
+                OPT_ABORT => {
+                    option_reply(stream, option, REP_ACK, &[])?;
+                    return Ok(false);
+                }
+                OPT_LIST => {
+                    let mut server = (options.name.len() as u32).to_be_bytes().to_vec();
+                    server.extend_from_slice(options.name.as_bytes());
+                    option_reply(stream, option, REP_SERVER, &server)?;
+                    option_reply(stream, option, REP_ACK, &[])?;
+                }
+                OPT_INFO | OPT_GO => {
+                    let Some(name) = requested_name(&data) else {
+                        option_reply(stream, option, REP_ERR_INVALID, &[])?;
+                        continue;
+                    };
+                    if !known(name) {
+                        option_reply(stream, option, REP_ERR_UNKNOWN, &[])?;
+                        continue;
+                    }
+                    let mut export = INFO_EXPORT.to_be_bytes().to_vec();
+                    export.extend_from_slice(&size.to_be_bytes());
+                    export.extend_from_slice(&flags.to_be_bytes());
+                    option_reply(stream, option, REP_INFO, &export)?;
+                    let block_size = self.block_size() as u32;
+                    let mut sizes = INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                    for size in [block_size, block_size, MAX_REQUEST_BYTES] {
+                        sizes.extend_from_slice(&size.to_be_bytes());
+                    }
+                    option_reply(stream, option, REP_INFO, &sizes)?;
+                    option_reply(stream, option, REP_ACK, &[])?;
+                    if option == OPT_GO {
+                        return Ok(true);
+                    }
+                }
+                _ => option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    /// The blocks covering `length` bytes from byte `offset`, as the first
+    /// block and the count.
+    fn nbd_blocks(&self, offset: u64, length: u32) -> Result<(u32, u32), SDError> {
+        let block_size = self.block_size() as u64;
+        let end = nbd_end(offset, length)?;
+        if length > MAX_REQUEST_BYTES {
+            return Err(SDError::InvalidArgument("NBD request too long"));
+        }
+        if end > self.num_blocks() * block_size {
+            return Err(SDError::BlockOutOfRange(end.div_ceil(block_size) - 1));
+        }
+        let first = offset / block_size;
+        let count = end.div_ceil(block_size) - first;
+        Ok((block_index(first)?, count as u32))
+    }
+
+    fn nbd_read(&mut self, offset: u64, length: u32) -> Result<Vec<u8>, SDError> {
+        let (first, count) = self.nbd_blocks(offset, length)?;
+        let data = self.read_blocks(first, count)?;
+        let skip = (offset % self.block_size() as u64) as usize;
+        Ok(data[skip..skip + length as usize].to_vec())
+    }
+
+    /// Writes `data` at byte `offset`, reading back the blocks it only
+    /// partly covers.
+    fn nbd_write(&mut self, offset: u64, data: &[u8]) -> Result<(), SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let (first, count) = self.nbd_blocks(offset, data.len() as u32)?;
+        let block_size = self.block_size();
+        let skip = (offset % block_size as u64) as usize;
+        if skip == 0 && data.len().is_multiple_of(block_size) {
+            return self.write_blocks(first, data);
+        }
+        let mut blocks = self.read_blocks(first, count)?;
+        blocks[skip..skip + data.len()].copy_from_slice(data);
+        self.write_blocks(first, &blocks)
+    }
+
+    /// Discards the whole blocks inside the range. Trimming is only a hint,
+    /// so a device that cannot discard still succeeds.
+    fn nbd_trim(&mut self, offset: u64, length: u32) -> Result<(), SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let block_size = self.block_size() as u64;
+        let end = nbd_end(offset, length)?;
+        if end > self.num_blocks() * block_size {
+            return Err(SDError::BlockOutOfRange(end.div_ceil(block_size) - 1));
+        }
+        let first = offset.div_ceil(block_size);
+        let last = end / block_size;
+        if last <= first {
+            return Ok(());
+        }
+        match self.discard_blocks(block_index(first)?, (last - first) as u32) {
+            Err(e) if matches!(e.root_cause(), SDError::Unsupported(_)) => Ok(()),
+            result => result,
+        }
+    }
+}
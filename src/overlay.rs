@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::block::BlockDevice;
+use crate::diff::BlockDiff;
+use crate::error::SDError;
+
+/// First bytes of an overlay file; the block size follows.
+const MAGIC: &[u8; 8] = b"SDOVRLY1";
+const HEADER_LEN: u64 = 16;
+
+/// Follows the block number in a record of an overlay file.
+const RECORD_DATA: u32 = 0;
+const RECORD_ZEROS: u32 = 1;
+
+/// Where the overlay keeps a block written over the base.
+#[derive(Debug, Clone)]
+enum Slot {
+    Memory(Box<[u8]>),
+    /// Offset of the block's data in the overlay file.
+    File(u64),
+    /// Discarded, and reading as zeros.
+    Zeros,
+}
+
+/// Lays a copy-on-write store over another device, which is only ever
+/// read: writes land in the store, and reads see them in place of the
+/// base's blocks. Repairs, formats, defragmentation and the like can then
+/// be run in full and the outcome inspected, with `diff` or by reading the
+/// volume, before `commit` writes it to the card or `discard_changes`
+/// forgets it.
+///
+/// The store is kept in memory, or with `with_file` in a file that a later
+/// run can open again to pick up where this one left off.
+pub struct OverlayDevice<D: BlockDevice> {
+    base: D,
+    blocks: BTreeMap<u32, Slot>,
+    file: Option<File>,
+}
+
+impl<D: BlockDevice> OverlayDevice<D> {
+    /// An overlay that keeps written blocks in memory.
+    pub fn new(base: D) -> Self {
+        OverlayDevice {
+            base,
+            blocks: BTreeMap::new(),
+            file: None,
+        }
+    }
+
+    /// An overlay that keeps written blocks in the file at `path`, created
+    /// if it does not exist. An existing file brings back the blocks it
+    /// holds; it must have been made for a device of the same block size.
+    pub fn with_file<P: AsRef<Path>>(base: D, path: P) -> Result<Self, SDError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let block_size = base.block_size();
+        let mut blocks = BTreeMap::new();
+        if file.metadata()?.len() == 0 {
+            let mut header = [0u8; HEADER_LEN as usize];
+            header[..8].copy_from_slice(MAGIC);
+            header[8..12].copy_from_slice(&(block_size as u32).to_le_bytes());
+            file.write_all(&header)?;
+        } else {
+            let mut header = [0u8; HEADER_LEN as usize];
+            file.read_exact(&mut header)?;
+            if &header[..8] != MAGIC {
+                return Err(SDError::InvalidImage("not an overlay file"));
+            }
+            if u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize
+                != block_size
+            {
+                return Err(SDError::InvalidImage(
+                    "the overlay was made for another block size",
+                ));
+            }
+            // Later records of a block take the place of earlier ones. A
+            // record cut short, as by a crash, is left out.
+            let len = file.metadata()?.len();
+            let mut position = HEADER_LEN;
+            let mut record = [0u8; 8];
+            while position + 8 <= len {
+                file.read_exact(&mut record)?;
+                let block = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+                let kind = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+                position += 8;
+                match kind {
+                    RECORD_ZEROS => {
+                        blocks.insert(block, Slot::Zeros);
+                    }
+                    RECORD_DATA if position + block_size as u64 <= len => {
+                        blocks.insert(block, Slot::File(position));
+                        position += block_size as u64;
+                        file.seek(SeekFrom::Start(position))?;
+                    }
+                    RECORD_DATA => break,
+                    _ => return Err(SDError::InvalidImage("corrupt overlay record")),
+                }
+            }
+            file.set_len(position)?;
+        }
+        Ok(OverlayDevice {
+            base,
+            blocks,
+            file: Some(file),
+        })
+    }
+
+    pub fn base(&self) -> &D {
+        &self.base
+    }
+
+    /// The base device, without the changes.
+    pub fn into_base(self) -> D {
+        self.base
+    }
+
+    /// Runs of blocks written over the base, in order and coalesced. Blocks
+    /// written with what the base already holds are included; `diff` leaves
+    /// them out.
+    pub fn changed_blocks(&self) -> Vec<Range<u64>> {
+        let mut runs: Vec<Range<u64>> = Vec::new();
+        for &block in self.blocks.keys() {
+            let block = block as u64;
+            match runs.last_mut() {
+                Some(run) if run.end == block => run.end += 1,
+                _ => runs.push(block..block + 1),
+            }
+        }
+        runs
+    }
+
+    /// Compares each block written over the base with the base's own.
+    pub fn diff(&mut self) -> Result<BlockDiff, SDError> {
+        let block_size = self.block_size();
+        let mut ours = vec![0u8; block_size];
+        let mut theirs = vec![0u8; block_size];
+        let mut differing: Vec<Range<u64>> = Vec::new();
+        let written: Vec<u32> = self.blocks.keys().copied().collect();
+        for block in written {
+            self.overlay_block(block, &mut ours)?;
+            self.base.read_block(block, &mut theirs)?;
+            if ours == theirs {
+                continue;
+            }
+            let block = block as u64;
+            match differing.last_mut() {
+                Some(run) if run.end == block => run.end += 1,
+                _ => differing.push(block..block + 1),
+            }
+        }
+        let blocks = self.num_blocks();
+        Ok(BlockDiff {
+            block_size,
+            blocks: (blocks, blocks),
+            differing,
+        })
+    }
+
+    /// Writes every changed block to the base, flushes it, and empties the
+    /// overlay. The base must accept writes. Returns the number of blocks
+    /// written.
+    pub fn commit(&mut self) -> Result<u64, SDError> {
+        let mut buffer = vec![0u8; self.block_size()];
+        let written: Vec<u32> = self.blocks.keys().copied().collect();
+        for &block in &written {
+            self.overlay_block(block, &mut buffer)?;
+            self.base.write_block(block, &buffer)?;
+        }
+        self.base.flush()?;
+        self.discard_changes()?;
+        Ok(written.len() as u64)
+    }
+
+    /// Forgets every change, so that the device reads as the base again.
+    pub fn discard_changes(&mut self) -> Result<(), SDError> {
+        self.blocks.clear();
+        if let Some(file) = &mut self.file {
+            file.set_len(HEADER_LEN)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Reads a block the overlay holds into `buffer`.
+    fn overlay_block(&mut self, block: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        match self.blocks.get(&block) {
+            Some(Slot::Memory(data)) => buffer.copy_from_slice(data),
+            Some(Slot::File(offset)) => {
+                let file = self.file.as_mut().expect("file slots come from a file");
+                file.seek(SeekFrom::Start(*offset))?;
+                file.read_exact(buffer)?;
+            }
+            Some(Slot::Zeros) => buffer.fill(0),
+            None => self.base.read_block(block, buffer)?,
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, start: u32, count: u64) -> Result<(), SDError> {
+        let end = start as u64 + count;
+        if end > self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(end - 1));
+        }
+        Ok(())
+    }
+
+    /// Appends a record for `block` to the overlay file, or overwrites the
+    /// block's data in place if the file already holds it.
+    fn store(&mut self, block: u32, data: Option<&[u8]>) -> Result<(), SDError> {
+        let Some(file) = &mut self.file else {
+            let slot = match data {
+                Some(data) => Slot::Memory(data.into()),
+                None => Slot::Zeros,
+            };
+            self.blocks.insert(block, slot);
+            return Ok(());
+        };
+        if let (Some(data), Some(Slot::File(offset))) = (data, self.blocks.get(&block)) {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(data)?;
+            return Ok(());
+        }
+        let end = file.seek(SeekFrom::End(0))?;
+        let kind = if data.is_some() {
+            RECORD_DATA
+        } else {
+            RECORD_ZEROS
+        };
+        let mut record = Vec::with_capacity(8 + data.map_or(0, <[u8]>::len));
+        record.extend_from_slice(&block.to_le_bytes());
+        record.extend_from_slice(&kind.to_le_bytes());
+        record.extend_from_slice(data.unwrap_or_default());
+        file.write_all(&record)?;
+        let slot = match data {
+            Some(_) => Slot::File(end + 8),
+            None => Slot::Zeros,
+        };
+        self.blocks.insert(block, slot);
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for OverlayDevice<D> {
+    fn block_size(&self) -> usize {
+        self.base.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.base.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.overlay_block(block_index, buffer)
+    }
+
+    /// Reads the range from the base in one request, then lays the
+    /// overlay's blocks over it.
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        let block_size = self.block_size();
+        if !buffer.len().is_multiple_of(block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let count = (buffer.len() / block_size) as u32;
+        self.base.read_blocks(start, buffer)?;
+        let written: Vec<u32> = self
+            .blocks
+            .range(start..start.saturating_add(count))
+            .map(|(&block, _)| block)
+            .collect();
+        for block in written {
+            let at = (block - start) as usize * block_size;
+            self.overlay_block(block, &mut buffer[at..at + block_size])?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.check_range(block_index, 1)?;
+        self.store(block_index, Some(data))
+    }
+
+    /// Syncs the overlay file; the base is left alone.
+    fn flush(&mut self) -> Result<(), SDError> {
+        if let Some(file) = &mut self.file {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Records the blocks as reading zeros.
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        self.check_range(start, count as u64)?;
+        for block in start..start + count {
+            self.store(block, None)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::allocation::{AllocationMap, ClusterState};
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::fat::FatVariant;
+use crate::layout::FATLayout;
+use crate::log::debug;
+
+/// What a block of a volume is part of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+pub enum BlockOwner {
+    /// The boot sector, or another of the sectors before the first FAT.
+    Reserved,
+    /// A sector of a FAT, `copy` counting from 0.
+    Fat { copy: u32 },
+    /// The fixed-size root directory of FAT12 and FAT16.
+    RootDirectory,
+    /// A cluster of a file or directory. `offset` is the byte of its data
+    /// the block holds, which may be past `size` in its last cluster.
+    Entry {
+        path: String,
+        directory: bool,
+        size: u64,
+        cluster: u32,
+        offset: u64,
+    },
+    /// A cluster no directory entry leads to. `Used` ones are lost: the
+    /// FAT or the allocation bitmap has them in use all the same.
+    Unowned { cluster: u32, state: ClusterState },
+    /// One of the sectors after the last cluster, too few to make another.
+    Tail,
+}
+
+impl BlockOwner {
+    /// The path of the file or directory, for an `Entry`.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            BlockOwner::Entry { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// A run of consecutive clusters of one entry.
+struct Run {
+    first: u32,
+    count: u32,
+    entry: usize,
+    /// Clusters of the entry before the run.
+    position: u32,
+}
+
+/// Which file or directory each cluster of a volume belongs to, read once
+/// so that many blocks can be looked up quickly.
+pub struct BlockOwners {
+    layout: FATLayout,
+    blocks: u64,
+    /// `(path, is a directory, size)`.
+    entries: Vec<(String, bool, u64)>,
+    /// Sorted by first cluster.
+    runs: Vec<Run>,
+    allocation: AllocationMap,
+}
+
+impl BlockOwners {
+    /// What `block`, counted from the start of the volume, is part of.
+    pub fn owner(&self, block: u64) -> Result<BlockOwner, SDError> {
+        let layout = &self.layout;
+        let sector = u32::try_from(block)
+            .ok()
+            .filter(|_| block < self.blocks)
+            .ok_or(SDError::BlockOutOfRange(block))?;
+        if sector < layout.fat_start {
+            return Ok(BlockOwner::Reserved);
+        }
+        let fats_end = layout.fat_start + layout.fat_size * layout.number_of_fats;
+        if sector < fats_end {
+            return Ok(BlockOwner::Fat {
+                copy: (sector - layout.fat_start) / layout.fat_size,
+            });
+        }
+        if sector < layout.data_start {
+            return Ok(if sector >= layout.root_dir_start {
+                BlockOwner::RootDirectory
+            } else {
+                BlockOwner::Reserved
+            });
+        }
+        let within = sector - layout.data_start;
+        let cluster = within / layout.sectors_per_cluster + 2;
+        if !layout.is_data_cluster(cluster) {
+            return Ok(BlockOwner::Tail);
+        }
+        let index = self.runs.partition_point(|run| run.first <= cluster);
+        if let Some(run) = index
+            .checked_sub(1)
+            .map(|index| &self.runs[index])
+            .filter(|run| cluster < run.first + run.count)
+        {
+            let (path, directory, size) = &self.entries[run.entry];
+            let clusters_before = (run.position + cluster - run.first) as u64;
+            let sectors_before = clusters_before * layout.sectors_per_cluster as u64
+                + (within % layout.sectors_per_cluster) as u64;
+            return Ok(BlockOwner::Entry {
+                path: path.clone(),
+                directory: *directory,
+                size: *size,
+                cluster,
+                offset: sectors_before * layout.bytes_per_sector as u64,
+            });
+        }
+        Ok(BlockOwner::Unowned {
+            cluster,
+            state: self.allocation.state(cluster).unwrap_or(ClusterState::Free),
+        })
+    }
+
+    fn add(&mut self, entry: usize, position: u32, cluster: u32) {
+        match self.runs.last_mut() {
+            Some(run) if run.entry == entry && run.first + run.count == cluster => run.count += 1,
+            _ => self.runs.push(Run {
+                first: cluster,
+                count: 1,
+                entry,
+                position,
+            }),
+        }
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Works out what `block` of the open volume, counted from its start
+    /// as `read_block` counts, is part of: which file or directory for a
+    /// block of the data region. The whole tree is walked to find out, so
+    /// use `block_owners` to look up more than a few blocks.
+    pub fn owner_of(&mut self, block: u64) -> Result<BlockOwner, SDError> {
+        self.block_owners()?.owner(block)
+    }
+
+    /// Walks the volume and follows every cluster chain, to look up which
+    /// entry blocks belong to. A cluster that two chains cross-link is put
+    /// down to one of them.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn block_owners(&mut self) -> Result<BlockOwners, SDError> {
+        let layout = self.layout()?;
+        let table = self.load_fat(&layout)?;
+        let mut owners = BlockOwners {
+            layout: layout.clone(),
+            blocks: self.num_blocks(),
+            entries: Vec::new(),
+            runs: Vec::new(),
+            allocation: self.alloc_map()?,
+        };
+        if matches!(layout.variant, FatVariant::Fat32 | FatVariant::ExFat) {
+            owners.entries.push(("/".to_string(), true, 0));
+            for (position, cluster) in (0..).zip(table.chain(layout.root_cluster)) {
+                let Ok(cluster) = cluster else {
+                    break;
+                };
+                owners.add(0, position, cluster);
+            }
+        }
+        for item in self.walk()? {
+            let Ok(item) = item else {
+                continue;
+            };
+            let entry = &item.entry;
+            if !layout.is_data_cluster(entry.first_cluster) {
+                continue;
+            }
+            let index = owners.entries.len();
+            owners.entries.push((item.path, entry.is_dir(), entry.size));
+            if entry.contiguous {
+                let clusters = entry.size.div_ceil(layout.cluster_size() as u64).max(1);
+                owners.runs.push(Run {
+                    first: entry.first_cluster,
+                    count: clusters as u32,
+                    entry: index,
+                    position: 0,
+                });
+                continue;
+            }
+            for (position, cluster) in (0..).zip(table.chain(entry.first_cluster)) {
+                let Ok(cluster) = cluster else {
+                    break;
+                };
+                owners.add(index, position, cluster);
+            }
+        }
+        owners.runs.sort_by_key(|run| run.first);
+        debug!(
+            entries = owners.entries.len(),
+            runs = owners.runs.len(),
+            "mapped cluster owners"
+        );
+        Ok(owners)
+    }
+}
@@ -0,0 +1,474 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::exfat;
+use crate::fat::{FATBootSector, FatVariant};
+use crate::format::FAT32_THRESHOLD_BYTES;
+use crate::gpt::Guid;
+use crate::log::{debug, trace};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_PRIMARY_ENTRIES: usize = 4;
+
+pub const PARTITION_TYPE_EMPTY: u8 = 0x00;
+pub const PARTITION_TYPE_FAT16_SMALL: u8 = 0x04;
+pub const PARTITION_TYPE_FAT16: u8 = 0x06;
+pub const PARTITION_TYPE_EXTENDED_CHS: u8 = 0x05;
+/// FAT32 addressed by CHS, for partitions that end within CHS reach.
+pub const PARTITION_TYPE_FAT32_CHS: u8 = 0x0B;
+/// FAT32 addressed by LBA only.
+pub const PARTITION_TYPE_FAT32_LBA: u8 = 0x0C;
+pub const PARTITION_TYPE_FAT16_LBA: u8 = 0x0E;
+pub const PARTITION_TYPE_EXTENDED_LBA: u8 = 0x0F;
+pub const PARTITION_TYPE_EXTENDED_LINUX: u8 = 0x85;
+pub const PARTITION_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionType::Mbr(partition_type) => write!(f, "{:#04x}", partition_type),
+            PartitionType::Gpt(guid) => match *guid {
+                Guid::EFI_SYSTEM => write!(f, "EFI System"),
+                Guid::MICROSOFT_BASIC_DATA => write!(f, "Microsoft Basic Data"),
+                Guid::LINUX_FILESYSTEM => write!(f, "Linux filesystem"),
+                _ => write!(f, "{}", guid),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PartitionScheme {
+    Mbr,
+    Gpt { disk_guid: Guid },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PartitionEntry {
+    pub bootable: bool,
+    pub partition_type: PartitionType,
+    pub start_lba: u64,
+    pub sector_count: u64,
+    /// Partition name; only GPT stores one.
+    pub name: String,
+    pub unique_guid: Option<Guid>,
+}
+
+impl PartitionEntry {
+    fn parse(raw: &[u8]) -> Self {
+        PartitionEntry {
+            bootable: raw[0] == 0x80,
+            partition_type: PartitionType::Mbr(raw[4]),
+            start_lba: u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]) as u64,
+            sector_count: u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]) as u64,
+            name: String::new(),
+            unique_guid: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partition_type == PartitionType::Mbr(PARTITION_TYPE_EMPTY)
+    }
+
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self.partition_type,
+            PartitionType::Mbr(
+                PARTITION_TYPE_EXTENDED_CHS
+                    | PARTITION_TYPE_EXTENDED_LBA
+                    | PARTITION_TYPE_EXTENDED_LINUX
+            )
+        )
+    }
+
+    pub fn end_lba(&self) -> u64 {
+        self.start_lba + self.sector_count
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PartitionTable {
+    pub scheme: PartitionScheme,
+    pub partitions: Vec<PartitionEntry>,
+}
+
+/// How a device or disk image is organised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum DiskLayout {
+    /// A filesystem starting at block 0 with no partition table, as written
+    /// by some cameras and by `dd` of a single partition.
+    Superfloppy,
+    Partitioned(PartitionTable),
+}
+
+/// Whether `block` is the boot sector of a FAT or exFAT filesystem. A FAT
+/// boot sector carries the same 0x55AA signature as an MBR, so this has to
+/// be ruled out before reading block 0 as a partition table.
+fn holds_filesystem(block: &[u8]) -> bool {
+    exfat::is_exfat(block) || FATBootSector::parse(block).is_ok()
+}
+
+fn parse_mbr_entries(block: &[u8]) -> Result<Vec<PartitionEntry>, SDError> {
+    if block[510..512] != MBR_SIGNATURE {
+        return Err(SDError::parse(510, "MBR signature", "0x55AA is missing"));
+    }
+    Ok((0..MBR_PRIMARY_ENTRIES)
+        .map(|i| {
+            let offset = MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE;
+            PartitionEntry::parse(&block[offset..offset + MBR_ENTRY_SIZE])
+        })
+        .collect())
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Parses the partition table of the whole device. An MBR holding a
+    /// protective 0xEE entry defers to the GPT; otherwise MBR partitions are
+    /// returned, including logical partitions inside an extended partition.
+    /// Partitions are listed in on-disk order and empty slots are skipped.
+    pub fn read_partition_table(&mut self) -> Result<PartitionTable, SDError> {
+        let mbr = self.read_device_block(0)?;
+        let entries = parse_mbr_entries(&mbr).map_err(|e| e.at_block(0))?;
+
+        let protective = PartitionType::Mbr(PARTITION_TYPE_GPT_PROTECTIVE);
+        if entries
+            .iter()
+            .any(|entry| entry.partition_type == protective)
+        {
+            debug!("protective MBR, reading the GPT");
+            let (header, partitions) = self.read_gpt()?;
+            return Ok(PartitionTable {
+                scheme: PartitionScheme::Gpt {
+                    disk_guid: header.disk_guid,
+                },
+                partitions,
+            });
+        }
+
+        let mut partitions = Vec::new();
+        for entry in entries {
+            if entry.is_empty() {
+                continue;
+            }
+            if entry.is_extended() {
+                self.read_logical_partitions(entry.start_lba, &mut partitions)?;
+            } else {
+                partitions.push(entry);
+            }
+        }
+        debug!(partitions = partitions.len(), "parsed MBR");
+        Ok(PartitionTable {
+            scheme: PartitionScheme::Mbr,
+            partitions,
+        })
+    }
+
+    /// Follows the chain of extended boot records. Each EBR holds one logical
+    /// partition relative to itself and a link relative to the extended
+    /// partition's start.
+    fn read_logical_partitions(
+        &mut self,
+        extended_start: u64,
+        partitions: &mut Vec<PartitionEntry>,
+    ) -> Result<(), SDError> {
+        let mut ebr_lba = extended_start;
+        let max_links = self.device.num_blocks();
+
+        for _ in 0..max_links {
+            let ebr = self.read_device_block(block_index(ebr_lba)?)?;
+            let entries = parse_mbr_entries(&ebr).map_err(|e| e.at_block(ebr_lba))?;
+            trace!(lba = ebr_lba, "parsed extended boot record");
+
+            let mut logical = entries[0].clone();
+            if !logical.is_empty() {
+                logical.start_lba += ebr_lba;
+                partitions.push(logical);
+            }
+
+            let link = &entries[1];
+            if !link.is_extended() || link.start_lba == 0 {
+                return Ok(());
+            }
+            ebr_lba = extended_start + link.start_lba;
+        }
+        Err(SDError::InvalidPartitionTable)
+    }
+
+    /// Restricts all subsequent block reads to the partition at `index` of
+    /// the partition table.
+    pub fn open_partition(&mut self, index: usize) -> Result<PartitionEntry, SDError> {
+        let table = self.read_partition_table()?;
+        let partition = table
+            .partitions
+            .get(index)
+            .cloned()
+            .ok_or(SDError::PartitionNotFound(index))?;
+
+        debug!(
+            index,
+            start = partition.start_lba,
+            blocks = partition.sector_count,
+            "opened partition"
+        );
+        self.set_partition(
+            index,
+            block_index(partition.start_lba)?,
+            partition.sector_count,
+        );
+        Ok(partition)
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Tells a superfloppy from a partitioned device by looking for a boot
+    /// sector in block 0, or a FAT32 backup boot sector in block 6 when
+    /// block 0 holds no partition table either.
+    pub fn detect_layout(&mut self) -> Result<DiskLayout, SDError> {
+        if holds_filesystem(&self.read_device_block(0)?) {
+            debug!("block 0 holds a filesystem, no partition table");
+            return Ok(DiskLayout::Superfloppy);
+        }
+        match self.read_partition_table() {
+            Ok(table) => Ok(DiskLayout::Partitioned(table)),
+            // Neither a boot sector nor a partition table: a FAT32 volume
+            // whose boot sector is damaged still has its backup.
+            Err(error) => match self.read_backup_boot_sector() {
+                Ok(_) => {
+                    debug!("block 0 is damaged, block 6 holds a FAT32 backup boot sector");
+                    Ok(DiskLayout::Superfloppy)
+                }
+                Err(_) => Err(error),
+            },
+        }
+    }
+
+    /// Opens the volume that holds the filesystem: the whole device for a
+    /// superfloppy, otherwise the first partition with a FAT or exFAT boot
+    /// sector, or failing that the first partition. Works the same on raw
+    /// devices and on images dumped from them. Returns the index of the
+    /// opened partition, or `None` for a superfloppy.
+    pub fn open_volume(&mut self) -> Result<Option<usize>, SDError> {
+        self.close_partition();
+        let DiskLayout::Partitioned(table) = self.detect_layout()? else {
+            return Ok(None);
+        };
+        for index in 0..table.partitions.len() {
+            self.open_partition(index)?;
+            if self
+                .read_block(0)
+                .is_ok_and(|block| holds_filesystem(&block))
+            {
+                return Ok(Some(index));
+            }
+        }
+        self.open_partition(0)?;
+        Ok(Some(0))
+    }
+}
+
+/// Where `create_partition_table` starts partitions and rounds their sizes
+/// to, so that clusters line up with the erase blocks of flash media.
+pub const PARTITION_ALIGNMENT_BYTES: u64 = 4 << 20;
+
+/// The geometry CHS addresses are given in, as every tool since the
+/// 8 GB limit assumes.
+const CHS_HEADS: u64 = 255;
+const CHS_SECTORS: u64 = 63;
+const CHS_MAX_CYLINDER: u64 = 1023;
+
+/// A partition for `create_partition_table` to lay out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NewPartition {
+    /// `Fat16` or `Fat32`. By default partitions under 2 GiB get FAT16,
+    /// as `format` would choose.
+    pub variant: Option<FatVariant>,
+    /// Size in bytes, rounded up to the alignment. `None` takes the rest
+    /// of the device, and only the last partition may leave it out.
+    pub size: Option<u64>,
+    pub bootable: bool,
+}
+
+/// The CHS address of `lba`, or the largest one there is past the 1024
+/// cylinders CHS can reach.
+fn chs_address(lba: u64) -> [u8; 3] {
+    let cylinder = lba / (CHS_HEADS * CHS_SECTORS);
+    if cylinder > CHS_MAX_CYLINDER {
+        return [0xFE, 0xFF, 0xFF];
+    }
+    let head = (lba / CHS_SECTORS) % CHS_HEADS;
+    let sector = lba % CHS_SECTORS + 1;
+    [
+        head as u8,
+        sector as u8 | ((cylinder >> 2) & 0xC0) as u8,
+        cylinder as u8,
+    ]
+}
+
+/// Blocks cleared at the start of each new partition: the reserved region
+/// of a FAT32 volume, so that neither its boot sector nor the backup at
+/// block 6 lets an old volume be mounted again.
+const CLEARED_BLOCKS: u64 = 32;
+
+/// The partition type byte for a FAT partition of `sector_count` blocks
+/// ending before `end_lba`. Partitions CHS cannot reach the end of get the
+/// LBA types, and FAT16 ones small enough for the 16-bit sector count of
+/// the BPB the original small FAT16 type.
+fn fat_partition_type(variant: FatVariant, sector_count: u64, end_lba: u64) -> Result<u8, SDError> {
+    let within_chs = end_lba <= (CHS_MAX_CYLINDER + 1) * CHS_HEADS * CHS_SECTORS;
+    match variant {
+        FatVariant::Fat16 if !within_chs => Ok(PARTITION_TYPE_FAT16_LBA),
+        FatVariant::Fat16 if sector_count <= u16::MAX as u64 => Ok(PARTITION_TYPE_FAT16_SMALL),
+        FatVariant::Fat16 => Ok(PARTITION_TYPE_FAT16),
+        FatVariant::Fat32 if within_chs => Ok(PARTITION_TYPE_FAT32_CHS),
+        FatVariant::Fat32 => Ok(PARTITION_TYPE_FAT32_LBA),
+        _ => Err(SDError::Unsupported(
+            "only FAT16 and FAT32 partitions can be created",
+        )),
+    }
+}
+
+fn encode_mbr_entry(entry: &PartitionEntry, raw: &mut [u8]) -> Result<(), SDError> {
+    let PartitionType::Mbr(partition_type) = entry.partition_type else {
+        return Err(SDError::Unsupported("GPT entries cannot go in an MBR"));
+    };
+    raw[0] = if entry.bootable { 0x80 } else { 0x00 };
+    raw[1..4].copy_from_slice(&chs_address(entry.start_lba));
+    raw[4] = partition_type;
+    raw[5..8].copy_from_slice(&chs_address(entry.end_lba() - 1));
+    raw[8..12].copy_from_slice(&block_index(entry.start_lba)?.to_le_bytes());
+    raw[12..16].copy_from_slice(&block_index(entry.sector_count)?.to_le_bytes());
+    Ok(())
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// The FAT variant the type byte of the open MBR partition names.
+    pub(crate) fn partition_fat_variant(&mut self) -> Option<FatVariant> {
+        let index = self.partition()?;
+        let table = self.read_partition_table().ok()?;
+        match table.partitions.get(index)?.partition_type {
+            PartitionType::Mbr(
+                PARTITION_TYPE_FAT16_SMALL | PARTITION_TYPE_FAT16 | PARTITION_TYPE_FAT16_LBA,
+            ) => Some(FatVariant::Fat16),
+            PartitionType::Mbr(PARTITION_TYPE_FAT32_CHS | PARTITION_TYPE_FAT32_LBA) => {
+                Some(FatVariant::Fat32)
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes a new MBR holding `partitions` one after the other, each
+    /// starting on a 4 MiB boundary, and closes any open partition. The
+    /// boot code of an old MBR is kept; everything else in the table is
+    /// replaced. The first 32 blocks of each partition are zeroed so that
+    /// a boot sector, or FAT32 backup boot sector, left there by an earlier
+    /// layout is not taken for a volume; `format` the partitions next.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn create_partition_table(
+        &mut self,
+        partitions: &[NewPartition],
+    ) -> Result<PartitionTable, SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        if partitions.is_empty() || partitions.len() > MBR_PRIMARY_ENTRIES {
+            return Err(SDError::InvalidArgument(
+                "an MBR holds from one to four partitions",
+            ));
+        }
+        if partitions[..partitions.len() - 1]
+            .iter()
+            .any(|partition| partition.size.is_none())
+        {
+            return Err(SDError::InvalidArgument(
+                "only the last partition may take the rest of the device",
+            ));
+        }
+        self.close_partition();
+        let block_size = self.block_size() as u64;
+        let alignment = (PARTITION_ALIGNMENT_BYTES / block_size).max(1);
+        let device_blocks = self.device.num_blocks().min(u32::MAX as u64 + 1);
+
+        let mut entries = Vec::with_capacity(partitions.len());
+        let mut start = alignment;
+        for partition in partitions {
+            let sector_count = match partition.size {
+                Some(bytes) => bytes.div_ceil(block_size).div_ceil(alignment) * alignment,
+                None => device_blocks.saturating_sub(start),
+            };
+            if sector_count == 0 || start + sector_count > device_blocks {
+                return Err(SDError::InvalidArgument(
+                    "the partitions do not fit on the device",
+                ));
+            }
+            let variant =
+                partition
+                    .variant
+                    .unwrap_or(if sector_count * block_size < FAT32_THRESHOLD_BYTES {
+                        FatVariant::Fat16
+                    } else {
+                        FatVariant::Fat32
+                    });
+            entries.push(PartitionEntry {
+                bootable: partition.bootable,
+                partition_type: PartitionType::Mbr(fat_partition_type(
+                    variant,
+                    sector_count,
+                    start + sector_count,
+                )?),
+                start_lba: start,
+                sector_count,
+                name: String::new(),
+                unique_guid: None,
+            });
+            start += sector_count;
+        }
+
+        // Keep the boot code and disk signature of an MBR, but not the
+        // code of a boot sector that held the whole device.
+        let mut mbr = self.read_device_block(0)?;
+        if mbr[510..512] != MBR_SIGNATURE || holds_filesystem(&mbr) {
+            mbr.fill(0);
+        }
+        mbr[MBR_TABLE_OFFSET..510].fill(0);
+        for (i, entry) in entries.iter().enumerate() {
+            let offset = MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE;
+            encode_mbr_entry(entry, &mut mbr[offset..offset + MBR_ENTRY_SIZE])?;
+        }
+        mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+
+        let zeros = vec![0; block_size as usize];
+        for entry in &entries {
+            for block in entry.start_lba..entry.start_lba + entry.sector_count.min(CLEARED_BLOCKS) {
+                self.write_device_block(block_index(block)?, &zeros)?;
+            }
+        }
+        self.write_device_block(0, &mbr)?;
+        self.flush()?;
+        debug!(partitions = entries.len(), "wrote MBR");
+        Ok(PartitionTable {
+            scheme: PartitionScheme::Mbr,
+            partitions: entries,
+        })
+    }
+}
+
+pub(crate) fn block_index(lba: u64) -> Result<u32, SDError> {
+    u32::try_from(lba).map_err(|_| SDError::BlockOutOfRange(lba))
+}
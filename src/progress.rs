@@ -0,0 +1,209 @@
+use core::time::Duration;
+
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+#[cfg(feature = "std")]
+use std::sync::mpsc;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use crate::extract::ExtractProgress;
+
+/// What a long operation is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Reading,
+    Writing,
+    Verifying,
+    /// Reading every block to find the bad ones.
+    Scanning,
+    /// Clearing the reserved sectors and FATs of a new volume.
+    Formatting,
+    /// Copying files off the volume; counts the bytes of file data.
+    Extracting,
+    /// Checksumming a file or a range of blocks.
+    Hashing,
+    /// Reading two devices or volumes side by side.
+    Comparing,
+}
+
+/// How far a long operation has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    /// Time since the phase started. Always zero without `std`, which has
+    /// no clock.
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn percent(&self) -> f64 {
+        if self.bytes_total == 0 {
+            100.0
+        } else {
+            self.bytes_done as f64 * 100.0 / self.bytes_total as f64
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.bytes_done as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Time left at the average rate so far; `None` until a rate is known.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.bytes_per_second();
+        (rate > 0.0).then(|| {
+            Duration::from_secs_f64(self.bytes_total.saturating_sub(self.bytes_done) as f64 / rate)
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.bytes_done >= self.bytes_total
+    }
+}
+
+/// Receives updates from long operations: imaging, formatting, scanning,
+/// capacity tests and extraction all report through it, so a GUI can show
+/// one progress bar for any of them.
+///
+/// `report` is called once per device request. Closures taking a
+/// `&Progress` implement the trait, `()` ignores all updates, a
+/// `TerminalProgress` draws a bar on standard error, and an
+/// `mpsc::Sender<Progress>` forwards updates to another thread.
+pub trait ProgressSink {
+    fn report(&mut self, progress: &Progress);
+
+    /// Called by `extract_all` after each file, successfully extracted or
+    /// not. Does nothing unless overridden.
+    #[cfg(feature = "std")]
+    fn file(&mut self, _file: &ExtractProgress<'_>) {}
+}
+
+impl ProgressSink for () {
+    fn report(&mut self, _progress: &Progress) {}
+}
+
+impl<F: FnMut(&Progress)> ProgressSink for F {
+    fn report(&mut self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Sends every update down the channel. Once the receiver is gone updates
+/// are dropped and the operation carries on.
+#[cfg(feature = "std")]
+impl ProgressSink for mpsc::Sender<Progress> {
+    fn report(&mut self, progress: &Progress) {
+        let _ = self.send(*progress);
+    }
+}
+
+/// Measures `Progress::elapsed`, reading zero where there is no clock.
+pub(crate) struct Stopwatch {
+    #[cfg(feature = "std")]
+    started: Instant,
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Stopwatch {
+            #[cfg(feature = "std")]
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(feature = "std")]
+        return self.started.elapsed();
+        #[cfg(not(feature = "std"))]
+        Duration::ZERO
+    }
+}
+
+/// Width of the bar `TerminalProgress` draws, in characters.
+#[cfg(feature = "std")]
+const BAR_WIDTH: usize = 24;
+
+/// Draws a progress bar on standard error, redrawn at most twice a second
+/// and on a new line for each phase. Files that could not be extracted are
+/// listed above the bar.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct TerminalProgress {
+    last: Option<(Phase, Instant)>,
+}
+
+#[cfg(feature = "std")]
+impl TerminalProgress {
+    pub fn new() -> Self {
+        TerminalProgress::default()
+    }
+}
+
+#[cfg(feature = "std")]
+impl ProgressSink for TerminalProgress {
+    fn report(&mut self, progress: &Progress) {
+        match self.last {
+            Some((phase, _)) if phase != progress.phase => eprintln!(),
+            Some((_, last)) if !progress.is_finished() && last.elapsed().as_millis() < 500 => {
+                return
+            }
+            _ => {}
+        }
+        self.last = Some((progress.phase, Instant::now()));
+        let filled = ((progress.percent() / 100.0 * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+        let eta = progress.eta().map_or("--:--".to_string(), |eta| {
+            format!("{}:{:02}", eta.as_secs() / 60, eta.as_secs() % 60)
+        });
+        let mut stderr = io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r{:<10} [{}{}] {:5.1}% {:>10} of {:>10} {:6.1} MB/s ETA {} ",
+            format!("{:?}", progress.phase),
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            progress.percent(),
+            format_size(progress.bytes_done),
+            format_size(progress.bytes_total),
+            progress.bytes_per_second() / 1_000_000.0,
+            eta
+        );
+        if progress.is_finished() {
+            let _ = writeln!(stderr);
+            self.last = None;
+        }
+    }
+
+    fn file(&mut self, file: &ExtractProgress<'_>) {
+        if let Some(error) = file.error {
+            // Clear the bar line so the message does not run into it.
+            eprintln!("\r\x1b[K{}: {}", file.path, error);
+            self.last = None;
+        }
+    }
+}
+
+/// Formats a byte count in decimal units, as card capacities are quoted.
+#[cfg(feature = "std")]
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
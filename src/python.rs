@@ -0,0 +1,227 @@
+//! A Python module, `sd_controller`, for reading cards and images from
+//! scripts and notebooks:
+//!
+//! ```python
+//! import pandas, sd_controller
+//!
+//! card = sd_controller.SDController("/dev/sdb")
+//! for path, entry in card.walk("/LOGS"):
+//!     if path.endswith(".CSV"):
+//!         frame = pandas.read_csv(card.open(path))
+//! ```
+//!
+//! `pyproject.toml` builds it with maturin; `pip install .` or, while
+//! working on it, `maturin develop --release` installs it into the active
+//! environment.
+
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyFileNotFoundError, PyIsADirectoryError, PyNotADirectoryError, PyOSError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::block::{BlockDevice, FileDevice};
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+use crate::image::open_image;
+
+create_exception!(
+    sd_controller,
+    Error,
+    PyOSError,
+    "Raised for errors reading the card or its filesystem."
+);
+
+fn to_py_err(error: SDError) -> PyErr {
+    let message = error.to_string();
+    match error.root_cause() {
+        SDError::NotFound(_) => PyFileNotFoundError::new_err(message),
+        SDError::IsADirectory(_) => PyIsADirectoryError::new_err(message),
+        SDError::NotADirectory(_) => PyNotADirectoryError::new_err(message),
+        _ => Error::new_err(message),
+    }
+}
+
+/// A card or image opened read-only, with its volume found: the partition
+/// given, or else the first holding a filesystem, or the whole device.
+#[pyclass(name = "SDController", module = "sd_controller")]
+pub struct PyController {
+    controller: Mutex<SDController<Box<dyn BlockDevice + Send>>>,
+}
+
+impl PyController {
+    fn lock(&self) -> MutexGuard<'_, SDController<Box<dyn BlockDevice + Send>>> {
+        // A panic while reading leaves nothing half-updated worth refusing.
+        self.controller
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[pymethods]
+impl PyController {
+    #[new]
+    #[pyo3(signature = (path, partition = None))]
+    fn new(path: PathBuf, partition: Option<usize>) -> PyResult<Self> {
+        let open = || -> Result<_, SDError> {
+            let device: Box<dyn BlockDevice + Send> = if path.is_file() {
+                open_image(&path, false)?
+            } else {
+                Box::new(FileDevice::open(&path)?)
+            };
+            let mut controller = SDController::from_device(device);
+            if let Some(index) = partition {
+                controller.open_partition(index)?;
+            } else {
+                controller.open_volume()?;
+            }
+            Ok(controller)
+        };
+        Ok(PyController {
+            controller: Mutex::new(open().map_err(to_py_err)?),
+        })
+    }
+
+    #[getter]
+    fn block_size(&self) -> usize {
+        self.lock().block_size()
+    }
+
+    /// Blocks in the volume.
+    #[getter]
+    fn num_blocks(&self) -> u64 {
+        self.lock().num_blocks()
+    }
+
+    fn stat(&self, path: &str) -> PyResult<PyDirEntry> {
+        let entry = self.lock().stat(path).map_err(to_py_err)?;
+        Ok(PyDirEntry::from(&entry))
+    }
+
+    /// The entries of the directory at `path`, without `.` and `..`.
+    #[pyo3(signature = (path = "/"))]
+    fn listdir(&self, path: &str) -> PyResult<Vec<PyDirEntry>> {
+        let mut controller = self.lock();
+        let dir = controller.stat(path).map_err(to_py_err)?;
+        let entries = controller.open_dir(&dir).map_err(to_py_err)?;
+        Ok(entries
+            .filter(|entry| !entry.is_volume_label())
+            .map(|entry| PyDirEntry::from(&entry))
+            .collect())
+    }
+
+    /// Every file and directory below `path` as `(path, entry)` pairs,
+    /// parents before their children.
+    #[pyo3(signature = (path = "/"))]
+    fn walk(&self, path: &str) -> PyResult<Vec<(String, PyDirEntry)>> {
+        let mut controller = self.lock();
+        controller
+            .walk_from(path)
+            .map_err(to_py_err)?
+            .map(|walked| {
+                walked
+                    .map(|walked| (walked.path, PyDirEntry::from(&walked.entry)))
+                    .map_err(to_py_err)
+            })
+            .collect()
+    }
+
+    /// The whole contents of the file at `path`.
+    fn read<'py>(&self, py: Python<'py>, path: &str) -> PyResult<Bound<'py, PyBytes>> {
+        let data = py
+            .allow_threads(|| self.lock().open(path))
+            .map_err(to_py_err)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// The file at `path` as a binary file object, an `io.BytesIO` of its
+    /// contents, for anything that reads from one, such as
+    /// `pandas.read_csv`.
+    fn open<'py>(&self, py: Python<'py>, path: &str) -> PyResult<Bound<'py, PyAny>> {
+        let data = self.read(py, path)?;
+        py.import("io")?.getattr("BytesIO")?.call1((data,))
+    }
+
+    /// Copies the file or directory at `path`, and everything below a
+    /// directory, to `dest` on the host, keeping modification times.
+    /// Returns the number of files written; raises if any could not be
+    /// read, after copying the rest.
+    fn extract(&self, py: Python<'_>, path: &str, dest: PathBuf) -> PyResult<usize> {
+        let summary = py
+            .allow_threads(|| {
+                let mut controller = self.lock();
+                let entry = controller.stat(path)?;
+                if !entry.is_dir() {
+                    let mut reader = controller.file_reader(&entry)?;
+                    std::io::copy(&mut reader, &mut std::fs::File::create(&dest)?)?;
+                    return Ok(None);
+                }
+                controller.extract_all(path, &dest, &mut ()).map(Some)
+            })
+            .map_err(to_py_err)?;
+        let Some(summary) = summary else {
+            return Ok(1);
+        };
+        match summary.failures.first() {
+            None => Ok(summary.files),
+            Some(failure) => Err(Error::new_err(format!(
+                "{} of the files could not be read, {} among them: {}",
+                summary.failures.len(),
+                failure.path,
+                failure.error
+            ))),
+        }
+    }
+}
+
+/// A file or directory on the card.
+#[pyclass(name = "DirEntry", module = "sd_controller", frozen, get_all)]
+#[derive(Clone)]
+pub struct PyDirEntry {
+    /// The long name if there is one.
+    name: String,
+    size: u64,
+    is_dir: bool,
+    /// The FAT attribute bits.
+    attributes: u8,
+    first_cluster: u32,
+    /// Seconds since the Unix epoch, or `None` if unset.
+    modified: Option<u64>,
+}
+
+impl From<&DirEntry> for PyDirEntry {
+    fn from(entry: &DirEntry) -> Self {
+        PyDirEntry {
+            name: entry.full_name(),
+            size: entry.size,
+            is_dir: entry.is_dir(),
+            attributes: entry.attributes.bits(),
+            first_cluster: entry.first_cluster,
+            modified: entry.timestamps.modified_at().map(|time| time.unix_time()),
+        }
+    }
+}
+
+#[pymethods]
+impl PyDirEntry {
+    fn __repr__(&self) -> String {
+        format!(
+            "DirEntry(name={:?}, size={}, is_dir={})",
+            self.name,
+            self.size,
+            if self.is_dir { "True" } else { "False" }
+        )
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "sd_controller")]
+fn python_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyController>()?;
+    m.add_class::<PyDirEntry>()?;
+    m.add("Error", m.py().get_type::<Error>())?;
+    Ok(())
+}
@@ -0,0 +1,269 @@
+#[cfg(not(windows))]
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(not(windows))]
+use std::process::Command;
+
+use crate::block::FileDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawOptions {
+    pub writable: bool,
+    /// Unmount every volume of the device before opening it.
+    pub unmount: bool,
+    pub block_size: usize,
+    /// Bypass the OS page cache, so that reads and writes reach the card
+    /// rather than host memory: `O_DIRECT` on Linux, `F_NOCACHE` on macOS
+    /// and `FILE_FLAG_NO_BUFFERING` on Windows. Image files on a file system
+    /// without direct I/O then fail to open.
+    pub direct: bool,
+}
+
+impl Default for RawOptions {
+    fn default() -> Self {
+        RawOptions {
+            writable: false,
+            unmount: false,
+            block_size: 512,
+            direct: false,
+        }
+    }
+}
+
+/// A mounted filesystem that lives on a device being opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountPoint {
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
+
+/// Opens a raw device such as `/dev/sdb`, `/dev/rdisk4` or
+/// `\\.\PhysicalDrive2`, turning the usual failures into errors that say
+/// what to do about them.
+///
+/// Opening for writing while any of the device's volumes is mounted fails
+/// with `DeviceBusy` unless `options.unmount` is set, since the mounted
+/// filesystem would overwrite our changes. Read-only access is allowed, but
+/// may see stale data for volumes with unflushed writes.
+pub fn open_raw_device<P: AsRef<Path>>(
+    path: P,
+    options: RawOptions,
+) -> Result<SDController, SDError> {
+    #[cfg(windows)]
+    use crate::windows::open_device;
+    open_device(path.as_ref(), options)
+}
+
+#[cfg(not(windows))]
+fn open_device(path: &Path, options: RawOptions) -> Result<SDController, SDError> {
+    let mounts = mounted_volumes(path)?;
+    if !mounts.is_empty() {
+        if options.unmount {
+            unmount(path, &mounts)?;
+        } else if options.writable {
+            return Err(busy(path, &mounts));
+        }
+    }
+
+    let mut open_options = OpenOptions::new();
+    open_options.read(true).write(options.writable);
+    #[cfg(target_os = "linux")]
+    if options.direct {
+        use std::os::unix::fs::OpenOptionsExt;
+        open_options.custom_flags(O_DIRECT);
+    }
+    let file = open_options
+        .open(path)
+        .map_err(|e| direct_open_error(path, e, options.direct))?;
+    #[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(unused_mut))]
+    let mut device = FileDevice::from_file(file, options.block_size, options.writable)?;
+    if options.direct {
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        return Err(SDError::Unsupported("direct I/O on this platform"));
+        #[cfg(target_os = "macos")]
+        set_no_cache(device.file())?;
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        device.use_direct_io();
+    }
+    let mut controller = SDController::from_device(device);
+    if options.writable {
+        controller.enable_writes();
+    }
+    Ok(controller)
+}
+
+/// `O_DIRECT` differs between architectures.
+#[cfg(target_os = "linux")]
+const O_DIRECT: i32 = if cfg!(any(target_arch = "arm", target_arch = "aarch64")) {
+    0o200000
+} else if cfg!(any(target_arch = "powerpc", target_arch = "powerpc64")) {
+    0o400000
+} else if cfg!(any(target_arch = "mips", target_arch = "mips64")) {
+    0o100000
+} else {
+    0o40000
+};
+
+#[cfg(target_os = "macos")]
+const F_NOCACHE: i32 = 48;
+
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn fcntl(fd: i32, command: i32, ...) -> i32;
+}
+
+/// Turns off caching for an open file; macOS has no open flag for it.
+#[cfg(target_os = "macos")]
+fn set_no_cache(file: &std::fs::File) -> Result<(), SDError> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor is valid for the lifetime of `file`, and
+    // F_NOCACHE takes a single int argument.
+    if unsafe { fcntl(file.as_raw_fd(), F_NOCACHE, 1) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Like `open_error`, except that the file system refusing direct I/O is
+/// reported as such instead of as an invalid argument.
+pub(crate) fn direct_open_error(path: &Path, error: io::Error, direct: bool) -> SDError {
+    if direct && error.kind() == io::ErrorKind::InvalidInput {
+        return SDError::Unsupported("direct I/O on this file system");
+    }
+    open_error(path, error)
+}
+
+#[cfg(not(windows))]
+fn busy(path: &Path, mounts: &[MountPoint]) -> SDError {
+    SDError::DeviceBusy {
+        device: path.display().to_string(),
+        mounts: mounts
+            .iter()
+            .map(|mount| mount.target.display().to_string())
+            .collect(),
+    }
+}
+
+pub(crate) fn open_error(path: &Path, error: io::Error) -> SDError {
+    match error.kind() {
+        io::ErrorKind::NotFound => SDError::DeviceNotFound,
+        io::ErrorKind::PermissionDenied => SDError::PermissionDenied(path.display().to_string()),
+        io::ErrorKind::ResourceBusy => SDError::DeviceBusy {
+            device: path.display().to_string(),
+            mounts: Vec::new(),
+        },
+        _ => SDError::IO(error),
+    }
+}
+
+/// Whether `candidate` names `base` itself or one of its partitions:
+/// `sdb` → `sdb1`, `mmcblk0` → `mmcblk0p1`, `disk4` → `disk4s1`.
+pub(crate) fn is_partition_of(base: &str, candidate: &str) -> bool {
+    let Some(rest) = candidate.strip_prefix(base) else {
+        return false;
+    };
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    rest.is_empty()
+        || (digits(rest) && !base.ends_with(|c: char| c.is_ascii_digit()))
+        || rest.strip_prefix('p').is_some_and(digits)
+        || rest.strip_prefix('s').is_some_and(digits)
+}
+
+/// The device's base name with symlinks resolved and, on macOS, the `r` of
+/// raw device nodes dropped, so that `/dev/rdisk4` matches `/dev/disk4s1`.
+pub(crate) fn device_name(path: &Path) -> Option<String> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if !path.starts_with("/dev") {
+        return None;
+    }
+    let name = path.file_name()?.to_string_lossy().into_owned();
+    Some(match name.strip_prefix("rdisk") {
+        Some(number) => format!("disk{}", number),
+        None => name,
+    })
+}
+
+/// Lists the mounted volumes that live on `device`. Disk images and
+/// anything outside `/dev` never count as mounted.
+pub fn mounted_volumes(device: &Path) -> Result<Vec<MountPoint>, SDError> {
+    let Some(base) = device_name(device) else {
+        return Ok(Vec::new());
+    };
+    Ok(system_mounts()?
+        .into_iter()
+        .filter(|mount| {
+            mount.source.starts_with("/dev")
+                && device_name(&mount.source).is_some_and(|name| is_partition_of(&base, &name))
+        })
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+fn system_mounts() -> Result<Vec<MountPoint>, SDError> {
+    // Fields are space-separated, with spaces inside paths escaped as \040.
+    let unescape = |field: &str| PathBuf::from(field.replace("\\040", " "));
+    Ok(std::fs::read_to_string("/proc/self/mounts")?
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            Some(MountPoint {
+                source: unescape(fields.next()?),
+                target: unescape(fields.next()?),
+            })
+        })
+        .collect())
+}
+
+#[cfg(target_os = "macos")]
+fn system_mounts() -> Result<Vec<MountPoint>, SDError> {
+    // "/dev/disk4s1 on /Volumes/NO NAME (msdos, local, nodev, nosuid)"
+    let output = Command::new("mount").output()?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (source, rest) = line.split_once(" on ")?;
+            let (target, _) = rest.rsplit_once(" (")?;
+            Some(MountPoint {
+                source: PathBuf::from(source),
+                target: PathBuf::from(target),
+            })
+        })
+        .collect())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn system_mounts() -> Result<Vec<MountPoint>, SDError> {
+    Ok(Vec::new())
+}
+
+#[cfg(not(windows))]
+fn run(command: &mut Command) -> Result<bool, SDError> {
+    Ok(command.status()?.success())
+}
+
+#[cfg(not(windows))]
+fn unmount(device: &Path, mounts: &[MountPoint]) -> Result<(), SDError> {
+    let unmounted = if cfg!(target_os = "macos") {
+        run(Command::new("diskutil").arg("unmountDisk").arg(device))?
+    } else {
+        let mut unmounted = true;
+        for mount in mounts {
+            unmounted &= run(Command::new("umount").arg(&mount.target))?;
+        }
+        unmounted
+    };
+    if !unmounted {
+        return Err(busy(device, &mounted_volumes(device)?));
+    }
+    Ok(())
+}
+
+impl SDController<FileDevice> {
+    /// Shorthand for `open_raw_device` with default options.
+    pub fn open_raw<P: AsRef<Path>>(path: P) -> Result<Self, SDError> {
+        open_raw_device(path, RawOptions::default())
+    }
+}
@@ -0,0 +1,293 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+use crate::layout::FATLayout;
+
+/// How much `FatFileReader` fetches ahead of sequential reads by default.
+pub const DEFAULT_READ_AHEAD: usize = 1 << 20;
+
+/// Streams the contents of a file. The cluster chain is followed lazily and
+/// the clusters seen so far are remembered, so seeking backwards does not
+/// walk the FAT again.
+///
+/// While reads follow on from each other, each device request fetches the
+/// next physically adjacent clusters too, up to the read-ahead size, and
+/// later reads are served from them; small reads such as `io::copy` makes
+/// then cost one request per megabyte rather than one per cluster. After a
+/// seek, reads fetch only the sectors they touch until they are sequential
+/// again.
+pub struct FatFileReader<'a, D: BlockDevice> {
+    controller: &'a mut SDController<D>,
+    layout: FATLayout,
+    first_cluster: u32,
+    contiguous: bool,
+    size: u64,
+    position: u64,
+    clusters: Vec<u32>,
+    /// Named in errors, when the reader was opened by path.
+    path: Option<String>,
+    read_ahead: usize,
+    /// Data fetched ahead, and the file offset it starts at.
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    /// Where the last read ended, to tell sequential reads from seeks.
+    last_end: u64,
+    /// The FAT sector the chain was last followed through, as neighbouring
+    /// clusters mostly have their entries in the same sector.
+    fat_sector: Option<(u32, Vec<u8>)>,
+}
+
+impl<'a, D: BlockDevice> FatFileReader<'a, D> {
+    fn new(controller: &'a mut SDController<D>, layout: FATLayout, entry: &DirEntry) -> Self {
+        FatFileReader {
+            controller,
+            layout,
+            first_cluster: entry.first_cluster,
+            contiguous: entry.contiguous,
+            size: entry.size,
+            position: 0,
+            clusters: Vec::new(),
+            path: None,
+            read_ahead: DEFAULT_READ_AHEAD,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            last_end: 0,
+            fat_sector: None,
+        }
+    }
+
+    /// Sets how many bytes sequential reads fetch at once, rounded down to
+    /// whole clusters. Zero turns read-ahead off.
+    pub fn set_read_ahead(&mut self, bytes: usize) {
+        self.read_ahead = bytes;
+        self.buffer.clear();
+    }
+
+    /// The clusters of the chain followed so far, which `resume_reader`
+    /// takes to carry on without walking the FAT from the start again.
+    pub fn into_clusters(self) -> Vec<u32> {
+        self.clusters
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The FAT entry of `cluster`, read through the last FAT sector used.
+    fn fat_value(&mut self, cluster: u32) -> Result<u32, SDError> {
+        let variant = self.layout.variant;
+        let bytes_per_sector = self.layout.bytes_per_sector;
+        let offset = variant.entry_offset(cluster);
+        let within = (offset % bytes_per_sector) as usize;
+        // A FAT12 entry can straddle two sectors.
+        if within + variant.entry_bytes() > bytes_per_sector as usize {
+            return self.controller.fat_value(&self.layout, cluster);
+        }
+        let sector = self.layout.fat_start + offset / bytes_per_sector;
+        if self
+            .fat_sector
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != sector)
+        {
+            self.fat_sector = Some((sector, self.controller.read_block(sector)?));
+        }
+        let data = &self.fat_sector.as_ref().expect("just read").1;
+        let mut bytes = [0u8; 4];
+        bytes[..variant.entry_bytes()]
+            .copy_from_slice(&data[within..within + variant.entry_bytes()]);
+        Ok(variant.decode_entry(cluster, u32::from_le_bytes(bytes)))
+    }
+
+    /// Returns the cluster holding the `index`-th cluster-sized chunk of the
+    /// file.
+    fn cluster(&mut self, index: usize) -> Result<u32, SDError> {
+        if self.contiguous {
+            let cluster = self.first_cluster + index as u32;
+            if !self.layout.is_data_cluster(cluster) {
+                return Err(SDError::InvalidCluster(cluster));
+            }
+            return Ok(cluster);
+        }
+
+        while self.clusters.len() <= index {
+            let next = match self.clusters.last() {
+                Some(&last) => self.fat_value(last)?,
+                None => self.first_cluster,
+            };
+            if next >= self.layout.variant.end_of_chain() {
+                let cluster_size = self.layout.cluster_size();
+                return Err(SDError::ReadError {
+                    expected: self.size as usize,
+                    actual: self.clusters.len() * cluster_size,
+                });
+            }
+            if !self.layout.is_data_cluster(next)
+                || self.clusters.len() as u32 >= self.layout.cluster_count
+            {
+                return Err(SDError::InvalidCluster(next));
+            }
+            self.clusters.push(next);
+        }
+        Ok(self.clusters[index])
+    }
+}
+
+impl<D: BlockDevice> FatFileReader<'_, D> {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, SDError> {
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        let sequential = self.position == self.last_end;
+        if !(self.buffer_start..buffer_end).contains(&self.position) && sequential {
+            self.fill_buffer()?;
+        }
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        let count = if (self.buffer_start..buffer_end).contains(&self.position) {
+            let offset = (self.position - self.buffer_start) as usize;
+            let count = buf.len().min(self.buffer.len() - offset);
+            buf[..count].copy_from_slice(&self.buffer[offset..offset + count]);
+            count
+        } else {
+            self.read_sectors(buf)?
+        };
+        self.position += count as u64;
+        self.last_end = self.position;
+        Ok(count)
+    }
+
+    /// Reads the part of `buf` that lies in the cluster at the current
+    /// position, fetching only the sectors it touches.
+    fn read_sectors(&mut self, buf: &mut [u8]) -> Result<usize, SDError> {
+        let cluster_size = self.layout.cluster_size() as u64;
+        let bytes_per_sector = self.layout.bytes_per_sector as u64;
+        let cluster = self.cluster((self.position / cluster_size) as usize)?;
+        let within_cluster = self.position % cluster_size;
+        let count = (buf.len() as u64)
+            .min(cluster_size - within_cluster)
+            .min(self.size - self.position) as usize;
+
+        // Fetch every sector the request touches within this cluster at once.
+        let first_sector = within_cluster / bytes_per_sector;
+        let last_sector = (within_cluster + count as u64 - 1) / bytes_per_sector;
+        let data = self.controller.read_blocks(
+            self.layout.cluster_to_sector(cluster) + first_sector as u32,
+            (last_sector - first_sector + 1) as u32,
+        )?;
+        let offset = (within_cluster % bytes_per_sector) as usize;
+        buf[..count].copy_from_slice(&data[offset..offset + count]);
+        Ok(count)
+    }
+
+    /// Fetches the cluster at the current position and the physically
+    /// adjacent ones after it, up to the read-ahead size and the end of the
+    /// file. Leaves the buffer empty when read-ahead is off.
+    fn fill_buffer(&mut self) -> Result<(), SDError> {
+        self.buffer.clear();
+        let cluster_size = self.layout.cluster_size() as u64;
+        let wanted = (self.read_ahead as u64 / cluster_size) as usize;
+        if wanted == 0 {
+            return Ok(());
+        }
+        let index = (self.position / cluster_size) as usize;
+        let file_clusters = self.size.div_ceil(cluster_size) as usize;
+        let first = self.cluster(index)?;
+        let mut run = 1;
+        // A chain that breaks further on is reported when the read gets
+        // there, not while reading ahead.
+        while run < wanted
+            && index + run < file_clusters
+            && self
+                .cluster(index + run)
+                .is_ok_and(|cluster| cluster == first + run as u32)
+        {
+            run += 1;
+        }
+
+        // Of the last cluster, only the sectors holding the file's tail.
+        self.buffer_start = index as u64 * cluster_size;
+        let bytes = (run as u64 * cluster_size).min(self.size - self.buffer_start);
+        let sectors = bytes.div_ceil(self.layout.bytes_per_sector as u64) as u32;
+        self.buffer = self
+            .controller
+            .read_blocks(self.layout.cluster_to_sector(first), sectors)?;
+        self.buffer.truncate(bytes as usize);
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Read for FatFileReader<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        self.read_chunk(buf).map_err(|e| {
+            let e = e.at_offset(self.position);
+            match &self.path {
+                Some(path) => e.in_file(path).into(),
+                None => e.into(),
+            }
+        })
+    }
+}
+
+impl<D: BlockDevice> Seek for FatFileReader<'_, D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Opens the file at `path` for streaming.
+    pub fn open_reader(&mut self, path: &str) -> Result<FatFileReader<'_, D>, SDError> {
+        let entry = self.stat(path)?;
+        if entry.is_dir() {
+            return Err(SDError::IsADirectory(path.to_string()));
+        }
+        let mut reader = self.file_reader(&entry)?;
+        reader.path = Some(path.to_string());
+        Ok(reader)
+    }
+
+    pub fn file_reader(&mut self, entry: &DirEntry) -> Result<FatFileReader<'_, D>, SDError> {
+        let layout = self.layout()?;
+        Ok(FatFileReader::new(self, layout, entry))
+    }
+
+    /// Opens a reader for `entry` that already knows the first clusters of
+    /// its chain, as returned by `into_clusters` of an earlier reader.
+    pub fn resume_reader(
+        &mut self,
+        entry: &DirEntry,
+        clusters: Vec<u32>,
+    ) -> Result<FatFileReader<'_, D>, SDError> {
+        let layout = self.layout()?;
+        Ok(self.resume_reader_with(layout, entry, clusters))
+    }
+
+    /// `resume_reader` with the `layout` of the open volume already known,
+    /// for callers that open many readers and would otherwise have the boot
+    /// sector read for each.
+    pub fn resume_reader_with(
+        &mut self,
+        layout: FATLayout,
+        entry: &DirEntry,
+        clusters: Vec<u32>,
+    ) -> FatFileReader<'_, D> {
+        let mut reader = FatFileReader::new(self, layout, entry);
+        reader.clusters = clusters;
+        reader
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::block::BlockDevice;
+use crate::codepage::Codepage;
+use crate::device::SDController;
+use crate::dir::{
+    is_valid_short_name_char, split_path, DirEntry, DirLocation, ATTR_LONG_NAME, DIR_ENTRY_SIZE,
+    ENTRY_DELETED, ENTRY_END,
+};
+use crate::error::SDError;
+use crate::fat::{FatTable, FatVariant};
+use crate::layout::FATLayout;
+use crate::lfn::deleted_long_name;
+
+/// How likely a deleted file's data is to be intact. Deletion frees the
+/// whole chain, so the chain is rebuilt from the first cluster onwards by
+/// taking free clusters in order, which is how first-fit allocators lay
+/// files out. Clusters where another deleted entry starts are passed over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recoverability {
+    /// The file fits in free, consecutive clusters.
+    Contiguous,
+    /// `skipped` clusters lie between the free clusters making up the
+    /// file. The data is right if those clusters already belonged to
+    /// other files when it was written, and garbled if they were
+    /// allocated after the deletion.
+    Fragmented { skipped: u32 },
+    /// The first cluster is in use again or invalid, or there are not
+    /// enough free clusters after it.
+    Lost,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeletedEntry {
+    /// Absolute path. Deletion erases the first character of the short
+    /// name; it is recovered from the long-name checksum when the long name
+    /// survives, and shown as `_` otherwise.
+    pub path: String,
+    pub entry: DirEntry,
+    pub recoverability: Recoverability,
+    /// The rebuilt chain; empty if the entry is lost or holds no data.
+    pub clusters: Vec<u32>,
+}
+
+/// Finds the deleted short entries of a directory region, with whatever
+/// can be restored of their names.
+fn scan_deleted(data: &[u8], codepage: Codepage) -> Vec<DirEntry> {
+    let mut found = Vec::new();
+    let mut fragments: Vec<&[u8]> = Vec::new();
+    for raw in data.chunks_exact(DIR_ENTRY_SIZE) {
+        if raw[0] == ENTRY_END {
+            break;
+        }
+        if raw[0] != ENTRY_DELETED {
+            fragments.clear();
+            continue;
+        }
+        if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            fragments.push(raw);
+            continue;
+        }
+
+        let restored = deleted_long_name(&fragments, &raw[0..11])
+            .filter(|&(_, first_byte)| is_valid_short_name_char(first_byte));
+        fragments.clear();
+        // The first byte is restored before decoding, as in a double-byte
+        // code page it may begin a two-byte character.
+        let mut short = [0u8; DIR_ENTRY_SIZE];
+        short.copy_from_slice(raw);
+        short[0] = restored
+            .as_ref()
+            .map_or(b'_', |&(_, first_byte)| first_byte);
+        let mut entry = DirEntry::decode(&short, codepage);
+        if entry.is_volume_label() {
+            continue;
+        }
+        entry.long_name = restored.map(|(long_name, _)| long_name);
+        found.push(entry);
+    }
+    found
+}
+
+/// Rebuilds the chain of a deleted entry: the first `n` usable clusters from
+/// its first cluster on, and how many clusters were skipped. A cluster is
+/// usable if it is free and not in `starts`, the first clusters of the other
+/// deleted entries. Directories are assumed to occupy a single cluster.
+fn rebuild_chain(
+    layout: &FATLayout,
+    table: &FatTable,
+    entry: &DirEntry,
+    starts: &HashSet<u32>,
+) -> Option<(Vec<u32>, u32)> {
+    let needed = if entry.first_cluster == 0 {
+        0
+    } else if entry.is_dir() {
+        1
+    } else {
+        entry.size.div_ceil(layout.cluster_size() as u64) as usize
+    };
+    if needed == 0 {
+        return (entry.size == 0).then_some((Vec::new(), 0));
+    }
+    if !layout.is_data_cluster(entry.first_cluster)
+        || !table.fat_entry(entry.first_cluster).is_free()
+    {
+        return None;
+    }
+
+    let end = layout.cluster_count + 2;
+    let mut clusters = Vec::with_capacity(needed);
+    let mut skipped = 0;
+    for cluster in entry.first_cluster..end {
+        if clusters.len() == needed {
+            break;
+        }
+        let usable = cluster == entry.first_cluster || !starts.contains(&cluster);
+        if usable && table.fat_entry(cluster).is_free() {
+            clusters.push(cluster);
+        } else {
+            skipped += 1;
+        }
+    }
+    (clusters.len() == needed).then_some((clusters, skipped))
+}
+
+/// `path` below `dest`, with a numeric suffix if something is already there.
+/// Deleted names can hold any bytes, so only the plain components of the
+/// path are kept, and `..` or a root cannot lead outside `dest`.
+fn unused_path(dest: &Path, path: &str) -> PathBuf {
+    let target: PathBuf = split_path(path)
+        .iter()
+        .flat_map(|component| Path::new(component).components())
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .fold(dest.to_path_buf(), |target, component| {
+            target.join(component)
+        });
+    if !target.exists() {
+        return target;
+    }
+    (1..)
+        .map(|n| {
+            let mut name = target.as_os_str().to_owned();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("some numeric suffix is unused")
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Lists the deleted entries of every directory, including deleted
+    /// directories whose first cluster is still intact. Only FAT12/16/32 is
+    /// supported.
+    pub fn deleted_entries(&mut self) -> Result<Vec<DeletedEntry>, SDError> {
+        let layout = self.layout()?;
+        if layout.variant == FatVariant::ExFat {
+            return Err(SDError::UnsupportedFilesystem);
+        }
+        let table = self.load_fat(&layout)?;
+
+        let mut dirs = vec![(String::new(), DirLocation::Root)];
+        for walked in self.walk()? {
+            let walked = walked?;
+            if walked.entry.is_dir() {
+                dirs.push((walked.path, DirLocation::of(&walked.entry)));
+            }
+        }
+        let mut pending = Vec::new();
+        for (path, location) in dirs {
+            pending.push((path, self.read_dir_region(&layout, location)?.1));
+        }
+
+        let mut found = Vec::new();
+        while let Some((path, data)) = pending.pop() {
+            for entry in scan_deleted(&data, self.codepage()) {
+                let path = format!("{}/{}", path, entry.full_name());
+                let first = entry.first_cluster;
+                if entry.is_dir()
+                    && layout.is_data_cluster(first)
+                    && table.fat_entry(first).is_free()
+                {
+                    let data = self.read_clusters_at(&layout, first, 1)?;
+                    // A directory cluster that has not been reused still
+                    // starts with its `.` entry.
+                    let dot = DirEntry::decode(&data[..DIR_ENTRY_SIZE], self.codepage());
+                    if dot.is_dot_entry() && dot.first_cluster == first {
+                        pending.push((path.clone(), data));
+                    }
+                }
+                found.push((path, entry));
+            }
+        }
+
+        let starts: HashSet<u32> = found.iter().map(|(_, entry)| entry.first_cluster).collect();
+        let mut deleted: Vec<DeletedEntry> = found
+            .into_iter()
+            .map(|(path, entry)| {
+                let (recoverability, clusters) =
+                    match rebuild_chain(&layout, &table, &entry, &starts) {
+                        None => (Recoverability::Lost, Vec::new()),
+                        Some((clusters, 0)) => (Recoverability::Contiguous, clusters),
+                        Some((clusters, skipped)) => {
+                            (Recoverability::Fragmented { skipped }, clusters)
+                        }
+                    };
+                DeletedEntry {
+                    path,
+                    entry,
+                    recoverability,
+                    clusters,
+                }
+            })
+            .collect();
+        deleted.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(deleted)
+    }
+
+    /// Reads the data of a deleted file from its rebuilt chain.
+    pub fn recover_file(&mut self, deleted: &DeletedEntry) -> Result<Vec<u8>, SDError> {
+        if deleted.entry.is_dir() {
+            return Err(SDError::IsADirectory(deleted.path.clone()));
+        }
+        if deleted.recoverability == Recoverability::Lost {
+            return Err(SDError::NotFound(deleted.path.clone()));
+        }
+        let layout = self.layout()?;
+        let mut data = Vec::with_capacity(deleted.clusters.len() * layout.cluster_size());
+        for &cluster in &deleted.clusters {
+            data.extend_from_slice(&self.read_clusters_at(&layout, cluster, 1)?);
+        }
+        data.truncate(deleted.entry.size as usize);
+        Ok(data)
+    }
+
+    /// Recovers every deleted file that is not lost into `dest`, mirroring
+    /// the volume's directory structure. Existing files are never
+    /// overwritten; a numeric suffix is added instead. Returns each
+    /// recovered entry with the path it was written to.
+    pub fn recover_all(&mut self, dest: &Path) -> Result<Vec<(DeletedEntry, PathBuf)>, SDError> {
+        let mut recovered = Vec::new();
+        for deleted in self.deleted_entries()? {
+            if deleted.entry.is_dir() || deleted.recoverability == Recoverability::Lost {
+                continue;
+            }
+            let data = self.recover_file(&deleted)?;
+            let target = unused_path(dest, &deleted.path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&target, data)?;
+            recovered.push((deleted, target));
+        }
+        Ok(recovered)
+    }
+}
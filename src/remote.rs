@@ -0,0 +1,428 @@
+//! Reading devices over HTTP: `Server` exports devices attached to one
+//! machine, such as a Raspberry Pi with a card reader, and `RemoteDevice`
+//! reads one of them from another as if it were local.
+//!
+//! Every request carries the shared token as `Authorization: Bearer`.
+//! Requests are plain HTTP; across an untrusted network, put the server
+//! behind a TLS proxy or an SSH tunnel. The service is read-only.
+//!
+//! | Endpoint | Parameters | Reply |
+//! |---|---|---|
+//! | `GET /v1/devices` | | the exported device paths |
+//! | `GET /v1/info` | `device` | `{ "block_size", "num_blocks" }` |
+//! | `GET /v1/blocks` | `device`, `start`, `count` | the blocks' bytes |
+//! | `GET /v1/list` | `device`, `path`, `partition` | the directory's entries |
+//! | `GET /v1/file` | `device`, `path`, `partition` | the file, streamed |
+//!
+//! Errors are replied with a status and `{ "error": message }`.
+
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use crate::block::{BlockDevice, FileDevice};
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+use crate::image::open_image;
+use crate::partition::block_index;
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+
+/// Most bytes one `/v1/blocks` request may ask for.
+pub const MAX_REQUEST_BYTES: usize = 4 << 20;
+
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// The device nodes and image files that may be read, named in
+    /// requests exactly as given here.
+    pub devices: Vec<PathBuf>,
+    /// The secret clients must present.
+    pub token: String,
+}
+
+/// An HTTP server exporting devices read-only.
+pub struct Server {
+    http: tiny_http::Server,
+    options: ServeOptions,
+}
+
+/// A request that could not be served.
+struct Failure {
+    status: u16,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Failure {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<SDError> for Failure {
+    fn from(error: SDError) -> Self {
+        let status = match error.root_cause() {
+            SDError::NotFound(_) | SDError::PartitionNotFound(_) => 404,
+            SDError::IsADirectory(_)
+            | SDError::NotADirectory(_)
+            | SDError::BlockOutOfRange(_)
+            | SDError::UnsupportedFilesystem => 400,
+            _ => 500,
+        };
+        Failure::new(status, error.to_string())
+    }
+}
+
+enum Reply {
+    Json(Value),
+    Bytes(Vec<u8>),
+    File {
+        controller: SDController<Box<dyn BlockDevice + Send>>,
+        entry: DirEntry,
+    },
+}
+
+impl Server {
+    /// Listens on `address`, such as `DEFAULT_ADDRESS` or `0.0.0.0:7878`.
+    pub fn bind<A: ToSocketAddrs>(address: A, options: ServeOptions) -> Result<Self, SDError> {
+        let http = tiny_http::Server::http(address).map_err(io::Error::other)?;
+        Ok(Server { http, options })
+    }
+
+    /// The address the server listens on, with the port chosen if `bind`
+    /// was given port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Serves requests until the process ends, each on its own thread.
+    pub fn run(&self) {
+        thread::scope(|scope| {
+            for request in self.http.incoming_requests() {
+                scope.spawn(move || self.handle(request));
+            }
+        });
+    }
+
+    fn handle(&self, request: Request) {
+        let reply = self.route(&request);
+        // The client hanging up is not the server's problem.
+        let _ = match reply {
+            Ok(Reply::Json(value)) => request.respond(json_response(200, &value)),
+            Ok(Reply::Bytes(bytes)) => {
+                request.respond(Response::from_data(bytes).with_header(octet_stream()))
+            }
+            Ok(Reply::File {
+                mut controller,
+                entry,
+            }) => match controller.file_reader(&entry) {
+                Ok(reader) => request.respond(Response::new(
+                    StatusCode(200),
+                    vec![octet_stream()],
+                    reader,
+                    usize::try_from(entry.size).ok(),
+                    None,
+                )),
+                Err(error) => request.respond(failure_response(&Failure::from(error))),
+            },
+            Err(failure) => request.respond(failure_response(&failure)),
+        };
+    }
+
+    fn route(&self, request: &Request) -> Result<Reply, Failure> {
+        if !self.authorized(request) {
+            return Err(Failure::new(401, "missing or wrong token"));
+        }
+        if *request.method() != Method::Get {
+            return Err(Failure::new(405, "only GET is served"));
+        }
+        let (endpoint, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+        let query = Query::parse(query);
+        match endpoint {
+            "/v1/devices" => Ok(Reply::Json(json!(self.options.devices))),
+            "/v1/info" => {
+                let device = self.open(&query)?;
+                Ok(Reply::Json(json!({
+                    "block_size": device.block_size(),
+                    "num_blocks": device.num_blocks(),
+                })))
+            }
+            "/v1/blocks" => {
+                let mut device = self.open(&query)?;
+                let start: u64 = query.number("start")?;
+                let count: usize = query.number("count")?;
+                if count.saturating_mul(device.block_size()) > MAX_REQUEST_BYTES {
+                    return Err(Failure::new(400, "too many blocks in one request"));
+                }
+                let mut buffer = vec![0; count * device.block_size()];
+                device.read_blocks(block_index(start)?, &mut buffer)?;
+                Ok(Reply::Bytes(buffer))
+            }
+            "/v1/list" => {
+                let mut controller = self.open_volume(&query)?;
+                let dir = controller.stat(query.get("path").unwrap_or("/"))?;
+                let entries: Vec<DirEntry> = controller
+                    .open_dir(&dir)?
+                    .filter(|entry| !entry.is_volume_label())
+                    .collect();
+                Ok(Reply::Json(json!(entries)))
+            }
+            "/v1/file" => {
+                let mut controller = self.open_volume(&query)?;
+                let path = query
+                    .get("path")
+                    .ok_or(Failure::new(400, "no path given"))?;
+                let entry = controller.stat(path)?;
+                if entry.is_dir() {
+                    return Err(SDError::IsADirectory(path.to_string()).into());
+                }
+                Ok(Reply::File { controller, entry })
+            }
+            _ => Err(Failure::new(404, format!("no endpoint {endpoint}"))),
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let expected = format!("Bearer {}", self.options.token);
+        request
+            .headers()
+            .iter()
+            .filter(|header| header.field.equiv("Authorization"))
+            .any(|header| constant_time_eq(header.value.as_bytes(), expected.as_bytes()))
+    }
+
+    /// Opens the device a request names, if it is exported. Devices are
+    /// opened afresh for every request, so that a card swapped in the
+    /// reader is picked up.
+    fn open(&self, query: &Query) -> Result<Box<dyn BlockDevice + Send>, Failure> {
+        let name = query
+            .get("device")
+            .ok_or(Failure::new(400, "no device given"))?;
+        let path = self
+            .options
+            .devices
+            .iter()
+            .find(|device| device.as_path() == Path::new(name))
+            .ok_or_else(|| Failure::new(404, format!("{name} is not exported")))?;
+        let device: Box<dyn BlockDevice + Send> = if path.is_file() {
+            open_image(path, false)?
+        } else {
+            Box::new(FileDevice::open(path)?)
+        };
+        Ok(device)
+    }
+
+    fn open_volume(
+        &self,
+        query: &Query,
+    ) -> Result<SDController<Box<dyn BlockDevice + Send>>, Failure> {
+        let mut controller = SDController::from_device(self.open(query)?);
+        if query.get("partition").is_some() {
+            controller.open_partition(query.number("partition")?)?;
+        } else {
+            controller.open_volume()?;
+        }
+        Ok(controller)
+    }
+}
+
+/// Compares without stopping at the first difference, so that the time
+/// taken does not tell how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn octet_stream() -> Header {
+    header("Content-Type", "application/octet-stream")
+}
+
+fn json_response(status: u16, value: &Value) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_data(value.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn failure_response(failure: &Failure) -> Response<io::Cursor<Vec<u8>>> {
+    json_response(failure.status, &json!({ "error": failure.message }))
+}
+
+/// The decoded parameters of a query string.
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn parse(query: &str) -> Self {
+        Query(
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (percent_decode(key), percent_decode(value))
+                })
+                .collect(),
+        )
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn number<T: std::str::FromStr>(&self, key: &str) -> Result<T, Failure> {
+        self.get(key)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| Failure::new(400, format!("{key} is missing or not a number")))
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => match rest
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(decoded) => {
+                    bytes.push(decoded);
+                    rest = &rest[2..];
+                }
+                None => bytes.push(b'%'),
+            },
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// A device exported by a `Server`, read over HTTP. Writes fail with
+/// `ReadOnly`.
+pub struct RemoteDevice {
+    agent: ureq::Agent,
+    url: String,
+    device: String,
+    token: String,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl RemoteDevice {
+    /// Connects to the server at `url`, such as `http://pi.local:7878`, and
+    /// opens `device` there.
+    pub fn open(url: &str, device: &str, token: &str) -> Result<Self, SDError> {
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        let mut remote = RemoteDevice {
+            agent,
+            url: url.trim_end_matches('/').to_string(),
+            device: device.to_string(),
+            token: token.to_string(),
+            block_size: 0,
+            num_blocks: 0,
+        };
+        let info: Value =
+            serde_json::from_reader(remote.get("info", &[])?).map_err(|e| SDError::IO(e.into()))?;
+        let (Some(block_size), Some(num_blocks)) =
+            (info["block_size"].as_u64(), info["num_blocks"].as_u64())
+        else {
+            return Err(SDError::Remote {
+                status: 200,
+                message: "the server did not describe the device".to_string(),
+            });
+        };
+        // A block size the reads below cannot divide into requests would
+        // fail or panic later, so it is refused here.
+        let block_size = usize::try_from(block_size)
+            .ok()
+            .filter(|&size| size.is_power_of_two() && size <= MAX_REQUEST_BYTES)
+            .ok_or_else(|| SDError::Remote {
+                status: 200,
+                message: format!("the server gave an unusable block size of {block_size}"),
+            })?;
+        remote.block_size = block_size;
+        remote.num_blocks = num_blocks;
+        Ok(remote)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn get(&self, endpoint: &str, params: &[(&str, String)]) -> Result<impl Read, SDError> {
+        let mut request = self
+            .agent
+            .get(format!("{}/v1/{endpoint}", self.url))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .query("device", &self.device);
+        for (key, value) in params {
+            request = request.query(*key, value);
+        }
+        let response = request.call().map_err(io::Error::other)?;
+        let status = response.status().as_u16();
+        let mut body = response.into_body().into_reader();
+        if status != 200 {
+            let mut text = String::new();
+            body.read_to_string(&mut text)?;
+            let message = serde_json::from_str::<Value>(&text)
+                .ok()
+                .and_then(|reply| reply["error"].as_str().map(str::to_string))
+                .unwrap_or(text);
+            return Err(SDError::Remote { status, message });
+        }
+        Ok(body)
+    }
+}
+
+impl BlockDevice for RemoteDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_blocks(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if !buffer.len().is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let per_request = (MAX_REQUEST_BYTES / self.block_size).max(1) * self.block_size;
+        for (i, chunk) in buffer.chunks_mut(per_request).enumerate() {
+            let first = start as u64 + (i * per_request / self.block_size) as u64;
+            let count = chunk.len() / self.block_size;
+            let params = [("start", first.to_string()), ("count", count.to_string())];
+            self.get("blocks", &params)?.read_exact(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, _block_index: u32, _data: &[u8]) -> Result<(), SDError> {
+        Err(SDError::ReadOnly)
+    }
+}
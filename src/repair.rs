@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::attributes::Attributes;
+use crate::block::BlockDevice;
+use crate::boot::BootSectorCopy;
+use crate::check::{ChainEnd, Checker, FsIssue};
+use crate::device::SDController;
+use crate::dir::{DirEntry, DirIter, DirLocation, FatTimestamps};
+use crate::error::SDError;
+use crate::fat::{FatEntry, FatVariant};
+use crate::layout::FATLayout;
+
+/// Selects the repairs `repair` performs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepairOptions {
+    /// Work out the repairs without writing anything.
+    pub dry_run: bool,
+    /// Overwrite the other FAT copies with the first FAT.
+    pub sync_fats: bool,
+    /// End chains that break or loop at their last valid cluster.
+    pub truncate_chains: bool,
+    /// Save lost cluster chains as files in a new `FOUND.nnn` directory.
+    pub reclaim_lost: bool,
+    /// Make file sizes and chain lengths agree.
+    pub fix_sizes: bool,
+    /// Rewrite a damaged FAT32 boot sector from its backup.
+    pub restore_boot_sector: bool,
+    /// Mark the volume clean once everything else is written, as `chkdsk`
+    /// and `fsck.fat` do.
+    pub clear_dirty: bool,
+}
+
+impl Default for RepairOptions {
+    fn default() -> Self {
+        RepairOptions {
+            dry_run: false,
+            sync_fats: true,
+            truncate_chains: true,
+            reclaim_lost: true,
+            fix_sizes: true,
+            restore_boot_sector: true,
+            clear_dirty: true,
+        }
+    }
+}
+
+/// A change made, or in a dry run one that would be made, by `repair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairAction {
+    /// The primary boot sector is overwritten with the backup.
+    RestoreBootSector,
+    /// FAT copy `copy` is overwritten with the first FAT.
+    SyncFat { copy: u32 },
+    /// The chain of `path` now ends at `cluster`, and the `freed` clusters
+    /// that followed it are released.
+    TruncateChain {
+        path: String,
+        cluster: u32,
+        freed: u32,
+    },
+    /// The size of `path` is changed to cover its chain.
+    FixSize {
+        path: String,
+        old_size: u64,
+        new_size: u64,
+    },
+    /// A lost chain is saved as the file `path`.
+    ReclaimChain {
+        first_cluster: u32,
+        clusters: u32,
+        path: String,
+    },
+    /// The clean-shutdown flag is set again, and the hard error flag and
+    /// the boot sector's dirty bit cleared.
+    ClearDirty,
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepairAction::RestoreBootSector => {
+                f.write_str("copy the backup boot sector over the damaged primary")
+            }
+            RepairAction::SyncFat { copy } => write!(f, "copy FAT 0 over FAT copy {}", copy),
+            RepairAction::TruncateChain {
+                path,
+                cluster,
+                freed,
+            } => write!(
+                f,
+                "{}: end chain at cluster {}, freeing {} cluster(s)",
+                path, cluster, freed
+            ),
+            RepairAction::FixSize {
+                path,
+                old_size,
+                new_size,
+            } => write!(f, "{}: change size from {} to {}", path, old_size, new_size),
+            RepairAction::ReclaimChain {
+                first_cluster,
+                clusters,
+                path,
+            } => write!(
+                f,
+                "save lost chain of {} cluster(s) starting at cluster {} as {}",
+                clusters, first_cluster, path
+            ),
+            RepairAction::ClearDirty => f.write_str("mark the volume as cleanly unmounted"),
+        }
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Repairs the common problems that `check` reports on FAT12/16/32
+    /// volumes and returns what was changed. Cross-linked chains and invalid
+    /// first clusters are left alone. With `options.dry_run` nothing is
+    /// written and the result lists what would change.
+    ///
+    /// All changes are planned from a single pass over the volume before
+    /// any are written, with the first FAT taken as authoritative.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn repair(&mut self, options: &RepairOptions) -> Result<Vec<RepairAction>, SDError> {
+        let layout = self.layout()?;
+        if layout.variant == FatVariant::ExFat {
+            return Err(SDError::UnsupportedFilesystem);
+        }
+        if !options.dry_run && !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let mut table = self.load_fat(&layout)?;
+        let mut actions = Vec::new();
+
+        let restore_boot_sector =
+            options.restore_boot_sector && self.boot_sector_copy() == BootSectorCopy::Backup;
+        if restore_boot_sector {
+            actions.push(RepairAction::RestoreBootSector);
+        }
+
+        if options.sync_fats {
+            let mut copies: Vec<u32> = self
+                .compare_fat_copies(&layout, &table)?
+                .into_iter()
+                .filter_map(|issue| match issue {
+                    FsIssue::FatMismatch { copy, .. } => Some(copy),
+                    _ => None,
+                })
+                .collect();
+            copies.dedup();
+            actions.extend(
+                copies
+                    .into_iter()
+                    .map(|copy| RepairAction::SyncFat { copy }),
+            );
+        }
+
+        let (checked, lost, shared) = {
+            let mut checker = Checker::new(&layout, &table);
+            let checked = self.check_tree(&layout, &mut checker)?;
+            // Where two chains meet, everything from that cluster on
+            // belongs to both.
+            let shared: HashSet<u32> = checker
+                .issues
+                .iter()
+                .filter_map(|issue| match issue {
+                    FsIssue::CrossLinked { cluster, .. } => Some(*cluster),
+                    _ => None,
+                })
+                .collect();
+            (checked, checker.lost_chains(), shared)
+        };
+
+        let cluster_size = layout.cluster_size() as u64;
+        let end_of_chain = layout.variant.entry_mask();
+        let mut size_fixes = Vec::new();
+        for checked in &checked {
+            let clusters = &checked.walk.clusters;
+            let mut complete = checked.walk.is_complete();
+            if options.truncate_chains && checked.walk.end == ChainEnd::Broken {
+                let last = clusters[clusters.len() - 1];
+                table.set_entry(last, end_of_chain);
+                actions.push(RepairAction::TruncateChain {
+                    path: checked.path.clone(),
+                    cluster: last,
+                    freed: 0,
+                });
+                complete = true;
+            }
+
+            let Some(slot) = checked.slot else {
+                continue;
+            };
+            if !options.fix_sizes || !complete || checked.entry.is_dir() {
+                continue;
+            }
+            let size = checked.entry.size;
+            let capacity = (clusters.len() as u64 * cluster_size).min(u32::MAX as u64);
+            let needed = size.div_ceil(cluster_size) as usize;
+            // A file claiming more data than its chain holds, or none at
+            // all, is sized to the chain so that no data is lost.
+            if size > capacity || (needed == 0 && !clusters.is_empty()) {
+                size_fixes.push((slot, capacity as u32));
+                actions.push(RepairAction::FixSize {
+                    path: checked.path.clone(),
+                    old_size: size,
+                    new_size: capacity,
+                });
+            } else if needed < clusters.len() {
+                let (kept, tail) = clusters.split_at(needed);
+                table.set_entry(kept[needed - 1], end_of_chain);
+                let freed: Vec<u32> = tail
+                    .iter()
+                    .copied()
+                    .take_while(|cluster| !shared.contains(cluster))
+                    .collect();
+                for &cluster in &freed {
+                    table.set_entry(cluster, 0);
+                }
+                actions.push(RepairAction::TruncateChain {
+                    path: checked.path.clone(),
+                    cluster: kept[needed - 1],
+                    freed: freed.len() as u32,
+                });
+            }
+        }
+
+        let mut reclaimed = Vec::new();
+        let found_dir = if options.reclaim_lost && !lost.is_empty() {
+            Some(self.found_dir_name(&layout)?)
+        } else {
+            None
+        };
+        if let Some(dir) = &found_dir {
+            for (index, (first_cluster, chain)) in lost.iter().enumerate() {
+                let last = chain[chain.len() - 1];
+                if table.fat_entry(last) != FatEntry::EndOfChain {
+                    table.set_entry(last, end_of_chain);
+                }
+                let name = format!("FILE{:04}", index);
+                actions.push(RepairAction::ReclaimChain {
+                    first_cluster: *first_cluster,
+                    clusters: chain.len() as u32,
+                    path: format!("/{}/{}.CHK", dir, name),
+                });
+                reclaimed.push(DirEntry {
+                    name,
+                    ext: "CHK".to_string(),
+                    long_name: None,
+                    attributes: Attributes::ARCHIVE,
+                    size: (chain.len() as u64 * cluster_size).min(u32::MAX as u64),
+                    first_cluster: *first_cluster,
+                    contiguous: false,
+                    timestamps: FatTimestamps::now(),
+                });
+            }
+        }
+
+        let clear_dirty =
+            options.clear_dirty && self.dirty_flags()?.is_some_and(|flags| flags.is_dirty());
+        if clear_dirty {
+            actions.push(RepairAction::ClearDirty);
+        }
+
+        if options.dry_run {
+            return Ok(actions);
+        }
+
+        if restore_boot_sector {
+            self.restore_boot_sector()?;
+        }
+
+        if options.sync_fats {
+            table.mark_all_dirty();
+        }
+        self.store_fat(&layout, &mut table)?;
+        for ((sector, offset), size) in size_fixes {
+            self.write_entry_at(sector, offset + 28, &size.to_le_bytes())?;
+        }
+        if let Some(dir) = found_dir {
+            let dir = self.create_dir(&dir)?;
+            for entry in reclaimed {
+                self.insert_entry(
+                    &layout,
+                    DirLocation::Cluster(dir.first_cluster),
+                    &entry.to_bytes()?,
+                )?;
+            }
+        }
+        if clear_dirty {
+            self.set_dirty(false)?;
+        }
+        self.flush()?;
+        Ok(actions)
+    }
+
+    /// The first of `FOUND.000` to `FOUND.999` not already in the root.
+    fn found_dir_name(&mut self, layout: &FATLayout) -> Result<String, SDError> {
+        let root: Vec<DirEntry> = DirIter::new(self.read_dir_region(layout, DirLocation::Root)?.1)
+            .with_codepage(self.codepage())
+            .collect();
+        (0..1000)
+            .map(|number| format!("FOUND.{:03}", number))
+            .find(|name| !root.iter().any(|entry| entry.matches_name(name)))
+            .ok_or(SDError::DirectoryFull)
+    }
+}
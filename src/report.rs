@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::block::BlockDevice;
+use crate::capacity::CapacityReport;
+use crate::check::FsIssue;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::layout::FATLayout;
+use crate::scan::ScanReport;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    /// Worth knowing, but nothing is wrong.
+    Info,
+    /// Something is off without data being at risk, e.g. wasted space or a
+    /// block that needed retries.
+    Warning,
+    /// Data is lost or at risk.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// One problem in a `Report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Finding {
+    pub severity: Severity,
+    /// A stable identifier for the kind of problem, such as
+    /// `unreadable_block` or `cross_linked`, for tools to match on.
+    pub kind: &'static str,
+    pub message: String,
+    /// The blocks affected, relative to the start of the volume or
+    /// partition; `Report::partition_start` makes them absolute.
+    pub lbas: Vec<Range<u64>>,
+    /// The file or directory affected.
+    pub path: Option<String>,
+}
+
+impl Finding {
+    pub fn new(severity: Severity, kind: &'static str, message: String) -> Self {
+        Finding {
+            severity,
+            kind,
+            message,
+            lbas: Vec::new(),
+            path: None,
+        }
+    }
+
+    fn with_lbas(mut self, lbas: impl IntoIterator<Item = Range<u64>>) -> Self {
+        self.lbas.extend(lbas);
+        self
+    }
+
+    fn with_range(mut self, range: Range<u64>) -> Self {
+        self.lbas.push(range);
+        self
+    }
+
+    fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+}
+
+/// The outcome of a check, scan or capacity test in a form other programs
+/// can read, with `write_json` and `write_csv`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Report {
+    /// What produced the report: `check`, `scan` or `capacity-test`.
+    pub operation: &'static str,
+    /// The device or image, when the caller knows it.
+    pub device: Option<String>,
+    /// Absolute LBA of the volume or partition the LBAs of the findings
+    /// are relative to.
+    pub partition_start: u64,
+    /// Seconds since the Unix epoch.
+    pub finished_at: u64,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "as_seconds"))]
+    pub elapsed: Duration,
+    /// Counts and measurements, e.g. `scanned_blocks`; durations are in
+    /// microseconds.
+    pub metrics: BTreeMap<&'static str, u64>,
+    pub findings: Vec<Finding>,
+}
+
+#[cfg(feature = "serde")]
+fn as_seconds<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+impl Report {
+    pub fn new(operation: &'static str, elapsed: Duration) -> Self {
+        Report {
+            operation,
+            device: None,
+            partition_start: 0,
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            elapsed,
+            metrics: BTreeMap::new(),
+            findings: Vec::new(),
+        }
+    }
+
+    /// The most severe finding, or `None` if there are none.
+    pub fn severity(&self) -> Option<Severity> {
+        self.findings.iter().map(|finding| finding.severity).max()
+    }
+
+    /// Whether nothing worse than a warning was found.
+    pub fn is_ok(&self) -> bool {
+        self.severity() < Some(Severity::Error)
+    }
+
+    pub fn from_check(issues: &[FsIssue], layout: &FATLayout, elapsed: Duration) -> Self {
+        let mut report = Report::new("check", elapsed);
+        report.metrics.insert("issues", issues.len() as u64);
+        report
+            .metrics
+            .insert("clusters", layout.cluster_count as u64);
+        report.findings = issues
+            .iter()
+            .map(|issue| issue_finding(issue, layout))
+            .collect();
+        report
+    }
+
+    pub fn from_scan(scan: &ScanReport) -> Self {
+        let mut report = Report::new("scan", scan.elapsed);
+        let metrics = &mut report.metrics;
+        metrics.insert("block_size", scan.block_size as u64);
+        metrics.insert("first_block", scan.range.start);
+        metrics.insert("scanned_blocks", scan.scanned_blocks);
+        metrics.insert("unreadable_blocks", scan.unreadable.len() as u64);
+        metrics.insert("recovered_blocks", scan.recovered.len() as u64);
+        metrics.insert("requests", scan.latency.requests);
+        metrics.insert("latency_min_us", micros(scan.latency.min));
+        metrics.insert("latency_median_us", micros(scan.latency.median));
+        metrics.insert("latency_p99_us", micros(scan.latency.p99));
+        metrics.insert("latency_max_us", micros(scan.latency.max));
+
+        for range in coalesce(scan.unreadable.iter().copied()) {
+            let message = format!(
+                "{} block(s) from {} cannot be read",
+                range.end - range.start,
+                range.start
+            );
+            report
+                .findings
+                .push(Finding::new(Severity::Error, "unreadable_block", message).with_range(range));
+        }
+        for recovered in &scan.recovered {
+            let message = format!(
+                "block {} read after {} failed attempt(s)",
+                recovered.block, recovered.failures
+            );
+            report.findings.push(
+                Finding::new(Severity::Warning, "recovered_block", message)
+                    .with_range(recovered.block..recovered.block + 1),
+            );
+        }
+        report
+    }
+
+    pub fn from_capacity(capacity: &CapacityReport) -> Self {
+        let mut report = Report::new("capacity-test", capacity.elapsed);
+        let metrics = &mut report.metrics;
+        metrics.insert("block_size", capacity.block_size as u64);
+        metrics.insert("claimed_blocks", capacity.claimed_blocks);
+        metrics.insert("tested_blocks", capacity.tested_blocks);
+        metrics.insert("good_blocks", capacity.good_blocks);
+        metrics.insert("bad_blocks", capacity.bad_blocks);
+        metrics.insert("aliased_blocks", capacity.aliased_blocks);
+        metrics.insert("usable_bytes", capacity.usable_bytes());
+        if let Some(blocks) = capacity.wraparound_blocks {
+            metrics.insert("wraparound_blocks", blocks);
+        }
+
+        if !capacity.is_genuine() {
+            let message = format!(
+                "{} of {} tested blocks do not hold what was written; {} of the \
+                 claimed {} bytes are usable",
+                capacity.bad_blocks,
+                capacity.tested_blocks,
+                capacity.usable_bytes(),
+                capacity.claimed_bytes()
+            );
+            report.findings.push(
+                Finding::new(Severity::Error, "bad_blocks", message)
+                    .with_lbas(capacity.bad_ranges.clone()),
+            );
+        }
+        for alias in &capacity.aliases {
+            let message = format!(
+                "block {} holds the data written to block {}",
+                alias.block, alias.written_as
+            );
+            report.findings.push(
+                Finding::new(Severity::Error, "aliased_block", message)
+                    .with_range(alias.block..alias.block + 1),
+            );
+        }
+        report
+    }
+
+    #[cfg(feature = "json")]
+    pub fn write_json<W: Write>(&self, out: W) -> Result<(), SDError> {
+        serde_json::to_writer_pretty(out, self).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    /// Writes one row per affected range of each finding, and one row for
+    /// a finding without LBAs. `end_lba` is exclusive. Metrics are left out;
+    /// they are in the JSON form.
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(
+            out,
+            "operation,severity,kind,start_lba,end_lba,path,message"
+        )?;
+        for finding in &self.findings {
+            let path = finding.path.as_deref().unwrap_or("");
+            let mut row = |lbas: Option<&Range<u64>>| {
+                let (start, end) = match lbas {
+                    Some(range) => (range.start.to_string(), range.end.to_string()),
+                    None => (String::new(), String::new()),
+                };
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{}",
+                    self.operation,
+                    finding.severity,
+                    finding.kind,
+                    start,
+                    end,
+                    csv_field(path),
+                    csv_field(&finding.message)
+                )
+            };
+            if finding.lbas.is_empty() {
+                row(None)?;
+            }
+            for range in &finding.lbas {
+                row(Some(range))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Runs `check` and reports its findings as a `Report`.
+    pub fn check_report(&mut self) -> Result<Report, SDError> {
+        let started = Instant::now();
+        let issues = self.check()?;
+        let mut report = Report::from_check(&issues, &self.layout()?, started.elapsed());
+        report.partition_start = self.partition_start() as u64;
+        Ok(report)
+    }
+}
+
+fn issue_finding(issue: &FsIssue, layout: &FATLayout) -> Finding {
+    let cluster_lbas = |cluster: u32| -> Option<Range<u64>> {
+        if !layout.is_data_cluster(cluster) {
+            return None;
+        }
+        let start = layout.cluster_to_sector(cluster) as u64;
+        Some(start..start + layout.sectors_per_cluster as u64)
+    };
+    let message = issue.to_string();
+    match issue {
+        FsIssue::DamagedBootSector => {
+            Finding::new(Severity::Error, "damaged_boot_sector", message).with_range(0..1)
+        }
+        FsIssue::Dirty { .. } => Finding::new(Severity::Warning, "dirty", message),
+        FsIssue::FatMismatch {
+            copy,
+            first_sector,
+            sectors,
+        } => {
+            let start = (layout.fat_start + copy * layout.fat_size + first_sector) as u64;
+            Finding::new(Severity::Error, "fat_mismatch", message)
+                .with_range(start..start + *sectors as u64)
+        }
+        FsIssue::InvalidFirstCluster { path, .. } => {
+            Finding::new(Severity::Error, "invalid_first_cluster", message).with_path(path)
+        }
+        FsIssue::BrokenChain { path, cluster, .. } => {
+            Finding::new(Severity::Error, "broken_chain", message)
+                .with_path(path)
+                .with_lbas(cluster_lbas(*cluster))
+        }
+        FsIssue::ChainLoop { path, cluster } => {
+            Finding::new(Severity::Error, "chain_loop", message)
+                .with_path(path)
+                .with_lbas(cluster_lbas(*cluster))
+        }
+        FsIssue::CrossLinked { path, cluster, .. } => {
+            Finding::new(Severity::Error, "cross_linked", message)
+                .with_path(path)
+                .with_lbas(cluster_lbas(*cluster))
+        }
+        FsIssue::SizeMismatch { path, .. } => {
+            Finding::new(Severity::Error, "size_mismatch", message).with_path(path)
+        }
+        FsIssue::LostChain { first_cluster, .. } => {
+            Finding::new(Severity::Warning, "lost_chain", message)
+                .with_lbas(cluster_lbas(*first_cluster))
+        }
+    }
+}
+
+/// Merges sorted block numbers into runs of consecutive blocks.
+fn coalesce(blocks: impl IntoIterator<Item = u64>) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for block in blocks {
+        match ranges.last_mut() {
+            Some(last) if last.end == block => last.end += 1,
+            _ => ranges.push(block..block + 1),
+        }
+    }
+    ranges
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+/// Quotes a CSV field if it needs it, as RFC 4180 describes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,248 @@
+use std::ops::Range;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::log::debug;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink};
+
+/// Bytes read per device request when scanning every block.
+const CHUNK_BYTES: usize = 1 << 20;
+/// Most entries kept in `ScanReport::slowest`.
+const MAX_SLOWEST: usize = 10;
+
+/// Controls how `scan_bad_blocks` reads the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Read every `stride`th block only, for a quick survey of a large card.
+    pub stride: u64,
+    /// Further attempts at a block that fails to read, each after twice the
+    /// delay of the one before.
+    pub retries: u32,
+    /// Delay before the first retry.
+    pub retry_delay: Duration,
+    /// Stop with the error at the first block that stays unreadable instead
+    /// of recording it and moving on.
+    pub stop_on_error: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            stride: 1,
+            retries: 2,
+            retry_delay: Duration::from_millis(50),
+            stop_on_error: false,
+        }
+    }
+}
+
+/// A block that read back only after retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveredBlock {
+    pub block: u64,
+    /// Failed attempts before the one that succeeded.
+    pub failures: u32,
+}
+
+/// The time taken by one read request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLatency {
+    /// First block of the request.
+    pub block: u64,
+    pub blocks: u64,
+    pub latency: Duration,
+}
+
+/// Distribution of read request latencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencyStats {
+    pub requests: u64,
+    pub min: Duration,
+    pub median: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    pub(crate) fn from_latencies(mut latencies: Vec<Duration>) -> Self {
+        if latencies.is_empty() {
+            return LatencyStats::default();
+        }
+        latencies.sort_unstable();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        LatencyStats {
+            requests: latencies.len() as u64,
+            min: latencies[0],
+            median: percentile(50),
+            p99: percentile(99),
+            max: latencies[latencies.len() - 1],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanReport {
+    pub block_size: usize,
+    pub range: Range<u64>,
+    pub scanned_blocks: u64,
+    /// Blocks that could not be read even after retrying.
+    pub unreadable: Vec<u64>,
+    pub recovered: Vec<RecoveredBlock>,
+    /// Latencies of successful read requests; a block range that is much
+    /// slower than the rest often fails soon after.
+    pub latency: LatencyStats,
+    /// The slowest successful requests, slowest first.
+    pub slowest: Vec<BlockLatency>,
+    pub elapsed: Duration,
+}
+
+impl ScanReport {
+    pub fn is_clean(&self) -> bool {
+        self.unreadable.is_empty()
+    }
+}
+
+/// Whether an error means the medium could not be read, as opposed to a
+/// mistake in the request.
+fn is_read_failure(error: &SDError) -> bool {
+    matches!(
+        error.root_cause(),
+        SDError::IO(_) | SDError::ReadError { .. }
+    )
+}
+
+struct Scan {
+    options: ScanOptions,
+    report: ScanReport,
+    latencies: Vec<Duration>,
+}
+
+impl Scan {
+    fn record_latency(&mut self, block: u64, blocks: u64, latency: Duration) {
+        self.latencies.push(latency);
+        let slowest = &mut self.report.slowest;
+        let position = slowest
+            .iter()
+            .position(|entry| entry.latency < latency)
+            .unwrap_or(slowest.len());
+        if position < MAX_SLOWEST {
+            slowest.insert(
+                position,
+                BlockLatency {
+                    block,
+                    blocks,
+                    latency,
+                },
+            );
+            slowest.truncate(MAX_SLOWEST);
+        }
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Reads the blocks in `range`, relative to the open partition, and
+    /// reports the ones that cannot be read along with read latencies. The
+    /// whole range is read in large requests; a request that fails is
+    /// retried block by block so that only the blocks actually at fault
+    /// are reported.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(start = range.start, end = range.end)))]
+    pub fn scan_bad_blocks<P: ProgressSink>(
+        &mut self,
+        range: Range<u64>,
+        options: &ScanOptions,
+        progress: &mut P,
+    ) -> Result<ScanReport, SDError> {
+        if range.end > self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(range.end));
+        }
+        let block_size = self.block_size();
+        let stride = options.stride.max(1);
+        let chunk = if stride == 1 {
+            (CHUNK_BYTES / block_size).max(1) as u64
+        } else {
+            1
+        };
+        let total = range.clone().step_by(stride as usize).count() as u64;
+        let started = Instant::now();
+        let mut scan = Scan {
+            options: *options,
+            report: ScanReport {
+                block_size,
+                range: range.clone(),
+                scanned_blocks: 0,
+                unreadable: Vec::new(),
+                recovered: Vec::new(),
+                latency: LatencyStats::default(),
+                slowest: Vec::new(),
+                elapsed: Duration::ZERO,
+            },
+            latencies: Vec::new(),
+        };
+
+        let mut block = range.start;
+        while block < range.end {
+            let count = chunk.min(range.end - block);
+            let request_started = Instant::now();
+            match self.read_blocks(block_index(block)?, count as u32) {
+                Ok(_) => scan.record_latency(block, count, request_started.elapsed()),
+                Err(e) if is_read_failure(&e) => {
+                    for single in block..block + count {
+                        self.scan_block(single, &mut scan)?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            scan.report.scanned_blocks += count;
+            progress.report(&Progress {
+                phase: Phase::Scanning,
+                bytes_done: scan.report.scanned_blocks * block_size as u64,
+                bytes_total: total * block_size as u64,
+                elapsed: started.elapsed(),
+            });
+            block += count.max(stride);
+        }
+
+        scan.report.latency = LatencyStats::from_latencies(scan.latencies);
+        scan.report.elapsed = started.elapsed();
+        Ok(scan.report)
+    }
+
+    /// Reads a single block, retrying with exponential backoff.
+    fn scan_block(&mut self, block: u64, scan: &mut Scan) -> Result<(), SDError> {
+        let mut delay = scan.options.retry_delay;
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            match self.read_block(block_index(block)?) {
+                Ok(_) => {
+                    scan.record_latency(block, 1, started.elapsed());
+                    if failures > 0 {
+                        scan.report
+                            .recovered
+                            .push(RecoveredBlock { block, failures });
+                    }
+                    return Ok(());
+                }
+                Err(e) if is_read_failure(&e) => {
+                    failures += 1;
+                    if failures > scan.options.retries {
+                        if scan.options.stop_on_error {
+                            return Err(e);
+                        }
+                        debug!(block, failures, "block unreadable");
+                        scan.report.unreadable.push(block);
+                        return Ok(());
+                    }
+                    debug!(block, failures, error = %e, ?delay, "read failed, retrying");
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
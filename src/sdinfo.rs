@@ -0,0 +1,364 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use crate::dir::FatTimestamps;
+use crate::error::SDError;
+
+/// Extracts bits `high..=low` of a register stored most significant byte
+/// first, numbering bits from the least significant end as the SD
+/// specification does.
+fn bits(register: &[u8], high: usize, low: usize) -> u64 {
+    let width = register.len() * 8;
+    (low..=high).rev().fold(0, |value, bit| {
+        let index = width - 1 - bit;
+        value << 1 | ((register[index / 8] >> (7 - index % 8)) & 1) as u64
+    })
+}
+
+/// Parses a register printed as hex digits, as Linux shows them in sysfs.
+fn parse_hex(text: &str, len: usize) -> Result<Vec<u8>, SDError> {
+    let text = text.trim();
+    if text.len() != len * 2 || !text.is_ascii() {
+        return Err(SDError::InvalidRegister("wrong number of hex digits"));
+    }
+    (0..len)
+        .map(|i| {
+            u8::from_str_radix(&text[2 * i..2 * i + 2], 16)
+                .map_err(|_| SDError::InvalidRegister("not a hex number"))
+        })
+        .collect()
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Manufacturer IDs assigned by the SD Association, as far as they are
+/// publicly known.
+const MANUFACTURERS: &[(u8, &str)] = &[
+    (0x01, "Panasonic"),
+    (0x02, "Toshiba"),
+    (0x03, "SanDisk"),
+    (0x1B, "Samsung"),
+    (0x1D, "ADATA"),
+    (0x27, "Phison"),
+    (0x28, "Lexar"),
+    (0x31, "Silicon Power"),
+    (0x41, "Kingston"),
+    (0x74, "Transcend"),
+    (0x76, "Patriot"),
+    (0x82, "Sony"),
+];
+
+/// The Card Identification register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cid {
+    pub manufacturer_id: u8,
+    /// Two ASCII characters naming the OEM or application.
+    pub oem_id: String,
+    pub product_name: String,
+    /// Product revision as major and minor numbers.
+    pub revision: (u8, u8),
+    pub serial: u32,
+    pub manufacture_year: u16,
+    pub manufacture_month: u8,
+}
+
+impl Cid {
+    pub fn parse(raw: &[u8; 16]) -> Self {
+        Cid {
+            manufacturer_id: raw[0],
+            oem_id: ascii(&raw[1..3]),
+            product_name: ascii(&raw[3..8]).trim_end().to_string(),
+            revision: (raw[8] >> 4, raw[8] & 0x0F),
+            serial: u32::from_be_bytes([raw[9], raw[10], raw[11], raw[12]]),
+            manufacture_year: 2000 + bits(raw, 19, 12) as u16,
+            manufacture_month: bits(raw, 11, 8) as u8,
+        }
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, SDError> {
+        let raw = parse_hex(text, 16)?;
+        Ok(Cid::parse(raw[..].try_into().unwrap()))
+    }
+
+    /// The manufacturer's name, if the ID is a known one.
+    pub fn manufacturer(&self) -> Option<&'static str> {
+        MANUFACTURERS
+            .iter()
+            .find(|&&(id, _)| id == self.manufacturer_id)
+            .map(|&(_, name)| name)
+    }
+}
+
+/// The Card-Specific Data register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csd {
+    /// 0 for standard capacity cards (SDSC), 1 for SDHC and SDXC, 2 for
+    /// SDUC.
+    pub version: u8,
+    /// Capacity in bytes as the card reports it.
+    pub capacity: u64,
+    /// Maximum transfer rate per data line, in kbit/s.
+    pub max_transfer_rate: u32,
+    /// Bit N set when the card supports command class N.
+    pub command_classes: u16,
+    pub write_protected: bool,
+}
+
+impl Csd {
+    pub fn parse(raw: &[u8; 16]) -> Self {
+        let version = bits(raw, 127, 126) as u8;
+        let capacity = match version {
+            0 => {
+                let c_size = bits(raw, 73, 62);
+                let c_size_mult = bits(raw, 49, 47);
+                let read_bl_len = bits(raw, 83, 80);
+                (c_size + 1) << (c_size_mult + 2 + read_bl_len)
+            }
+            1 => (bits(raw, 69, 48) + 1) * 512 * 1024,
+            _ => (bits(raw, 75, 48) + 1) * 512 * 1024,
+        };
+
+        // TRAN_SPEED: a mantissa in tenths times a power of ten kbit/s.
+        const MANTISSAS: [u32; 16] = [
+            0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+        ];
+        let tran_speed = raw[3];
+        let unit = [100, 1_000, 10_000, 100_000]
+            .get((tran_speed & 0x07) as usize)
+            .copied()
+            .unwrap_or(0);
+        Csd {
+            version,
+            capacity,
+            max_transfer_rate: MANTISSAS[(tran_speed >> 3 & 0x0F) as usize] * unit / 10,
+            command_classes: bits(raw, 95, 84) as u16,
+            write_protected: bits(raw, 13, 12) != 0,
+        }
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, SDError> {
+        let raw = parse_hex(text, 16)?;
+        Ok(Csd::parse(raw[..].try_into().unwrap()))
+    }
+
+    /// The capacity class the register claims: SDSC, SDHC, SDXC or SDUC.
+    pub fn capacity_class(&self) -> &'static str {
+        match self.version {
+            0 => "SDSC",
+            1 if self.capacity <= 32 << 30 => "SDHC",
+            1 => "SDXC",
+            _ => "SDUC",
+        }
+    }
+}
+
+/// The SD Configuration register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scr {
+    /// Version of the physical layer specification the card complies with,
+    /// such as "3.0x".
+    pub spec_version: &'static str,
+    pub bus_width_4: bool,
+    /// Security version: 2 for CPRM on SDSC cards, 3 for SDHC, 4 for SDXC.
+    pub security: u8,
+}
+
+impl Scr {
+    pub fn parse(raw: &[u8; 8]) -> Self {
+        let spec = bits(raw, 59, 56);
+        let spec3 = bits(raw, 47, 47);
+        let spec4 = bits(raw, 42, 42);
+        let specx = bits(raw, 41, 38);
+        let spec_version = match (spec, spec3, spec4, specx) {
+            (0, _, _, _) => "1.0",
+            (1, _, _, _) => "1.10",
+            (2, 0, _, _) => "2.00",
+            (2, 1, 0, 0) => "3.0x",
+            (2, 1, 1, 0) => "4.xx",
+            (2, 1, _, 1) => "5.xx",
+            (2, 1, _, 2) => "6.xx",
+            (2, 1, _, 3) => "7.xx",
+            (2, 1, _, 4) => "8.xx",
+            (2, 1, _, 5) => "9.xx",
+            _ => "unknown",
+        };
+        Scr {
+            spec_version,
+            bus_width_4: bits(raw, 50, 50) != 0,
+            security: bits(raw, 54, 52) as u8,
+        }
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, SDError> {
+        let raw = parse_hex(text, 8)?;
+        Ok(Scr::parse(raw[..].try_into().unwrap()))
+    }
+}
+
+/// The speed ratings from the SD Status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpeedRatings {
+    /// Speed class 2, 4, 6 or 10, or 0 for class 0 cards.
+    pub speed_class: u8,
+    /// UHS speed grade 1 or 3, or 0 if the card has none.
+    pub uhs_speed_grade: u8,
+    /// Video speed class such as 30 for V30, or 0 if the card has none.
+    pub video_speed_class: u8,
+    /// Application performance class 1 or 2 (A1, A2), or 0 if none.
+    pub app_performance_class: u8,
+}
+
+impl SpeedRatings {
+    /// Parses the 512-bit SD Status register.
+    pub fn parse(raw: &[u8; 64]) -> Self {
+        SpeedRatings {
+            speed_class: match bits(raw, 447, 440) {
+                1 => 2,
+                2 => 4,
+                3 => 6,
+                4 => 10,
+                _ => 0,
+            },
+            uhs_speed_grade: bits(raw, 399, 396) as u8,
+            video_speed_class: bits(raw, 391, 384) as u8,
+            app_performance_class: bits(raw, 339, 336) as u8,
+        }
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, SDError> {
+        let raw = parse_hex(text, 64)?;
+        Ok(SpeedRatings::parse(raw[..].try_into().unwrap()))
+    }
+}
+
+/// The registers of an SD card in a native SD slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdInfo {
+    pub cid: Cid,
+    pub csd: Csd,
+    /// Missing on kernels or cards that do not expose it.
+    pub scr: Option<Scr>,
+    /// Only exposed by recent kernels.
+    pub speed: Option<SpeedRatings>,
+}
+
+impl SdInfo {
+    /// Reads the registers from an MMC device directory in sysfs, such as
+    /// `/sys/block/mmcblk0/device`.
+    #[cfg(feature = "std")]
+    pub fn from_sysfs(dir: &Path) -> Result<Self, SDError> {
+        let read = |name: &str| std::fs::read_to_string(dir.join(name));
+        let cid = read("cid").map_err(|_| {
+            SDError::Unsupported("card registers of devices other than cards in a native SD slot")
+        })?;
+        Ok(SdInfo {
+            cid: Cid::from_hex(&cid)?,
+            csd: Csd::from_hex(&read("csd")?)?,
+            scr: read("scr")
+                .ok()
+                .map(|scr| Scr::from_hex(&scr))
+                .transpose()?,
+            speed: read("ssr")
+                .ok()
+                .map(|ssr| SpeedRatings::from_hex(&ssr))
+                .transpose()?,
+        })
+    }
+
+    /// Signs in the registers that the card is not what it claims to be.
+    /// Counterfeit cards often carry made-up identification, but a clean
+    /// result proves nothing: only writing the whole card and reading it
+    /// back shows its real capacity.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let cid = &self.cid;
+        if cid.manufacturer().is_none() {
+            warnings.push(format!(
+                "unknown manufacturer ID {:#04x}",
+                cid.manufacturer_id
+            ));
+        }
+        let printable = |text: &str| text.bytes().all(|b| b.is_ascii_graphic() || b == b' ');
+        if !printable(&cid.oem_id) || !printable(&cid.product_name) {
+            warnings.push("OEM ID or product name is not printable text".to_string());
+        }
+        if cid.serial == 0 || cid.serial == u32::MAX {
+            warnings.push(format!("placeholder serial number {:08x}", cid.serial));
+        }
+        // Without `std` there is no clock to tell a date in the future.
+        #[cfg(feature = "std")]
+        let in_future = cid.manufacture_year > 1980 + (FatTimestamps::now().modified_date >> 9);
+        #[cfg(not(feature = "std"))]
+        let in_future = false;
+        if !(1..=12).contains(&cid.manufacture_month) || in_future {
+            warnings.push(format!(
+                "impossible manufacture date {}-{:02}",
+                cid.manufacture_year, cid.manufacture_month
+            ));
+        }
+        let csd = &self.csd;
+        if csd.version == 0 && csd.capacity > 2 << 30 {
+            warnings.push(format!(
+                "standard capacity register layout claims {} bytes, more than SDSC allows",
+                csd.capacity
+            ));
+        }
+        if csd.version == 1 && csd.capacity > 2 << 40 {
+            warnings.push(format!(
+                "claims {} bytes, more than SDXC allows",
+                csd.capacity
+            ));
+        }
+        warnings
+    }
+}
+
+/// Reads the registers of the SD card behind `device`, such as
+/// `/dev/mmcblk0` or one of its partitions. Linux only exposes them for
+/// cards in a native SD or MMC slot; USB card readers hide them.
+#[cfg(feature = "std")]
+pub fn read_sd_info(device: &Path) -> Result<SdInfo, SDError> {
+    platform::read_sd_info(device)
+}
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod platform {
+    use std::path::{Path, PathBuf};
+
+    use super::SdInfo;
+    use crate::error::SDError;
+    use crate::raw::device_name;
+
+    pub fn read_sd_info(device: &Path) -> Result<SdInfo, SDError> {
+        let name = device_name(device).ok_or(SDError::DeviceNotFound)?;
+        let mut dir = PathBuf::from("/sys/class/block").join(name);
+        if !dir.exists() {
+            return Err(SDError::DeviceNotFound);
+        }
+        // Partitions sit below their disk.
+        if dir.join("partition").exists() {
+            dir = dir.join("..");
+        }
+        SdInfo::from_sysfs(&dir.join("device"))
+    }
+}
+
+#[cfg(all(feature = "std", not(target_os = "linux")))]
+mod platform {
+    use std::path::Path;
+
+    use super::SdInfo;
+    use crate::error::SDError;
+
+    pub fn read_sd_info(_device: &Path) -> Result<SdInfo, SDError> {
+        Err(SDError::Unsupported(
+            "reading SD card registers on this platform",
+        ))
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::log::debug;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes read per request while searching.
+const CHUNK_BYTES: u64 = 1 << 20;
+
+/// What `search` looks for.
+#[derive(Clone)]
+pub enum SearchPattern {
+    Bytes(Vec<u8>),
+    /// A regular expression over raw bytes. Unicode classes such as `.`
+    /// only match valid UTF-8; turn them off with `(?-u)` to match any
+    /// byte.
+    #[cfg(feature = "regex")]
+    Regex(regex::bytes::Regex),
+}
+
+impl SearchPattern {
+    /// Compiles a regular expression.
+    #[cfg(feature = "regex")]
+    pub fn regex(pattern: &str) -> Result<Self, SDError> {
+        regex::bytes::Regex::new(pattern)
+            .map(SearchPattern::Regex)
+            .map_err(|e| SDError::InvalidPattern(e.to_string()))
+    }
+
+    /// The first match starting at or after `from` in `haystack`, as a
+    /// range of it.
+    fn find_at(&self, haystack: &[u8], from: usize) -> Option<(usize, usize)> {
+        match self {
+            SearchPattern::Bytes(needle) => {
+                let (&first, _) = needle.split_first()?;
+                let last_start = haystack.len().checked_sub(needle.len())?;
+                (from..=last_start)
+                    .filter(|&at| haystack[at] == first)
+                    .find(|&at| haystack[at..].starts_with(needle))
+                    .map(|at| (at, at + needle.len()))
+            }
+            #[cfg(feature = "regex")]
+            SearchPattern::Regex(regex) => regex
+                .find_at(haystack, from)
+                .map(|found| (found.start(), found.end())),
+        }
+    }
+}
+
+impl fmt::Debug for SearchPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchPattern::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            #[cfg(feature = "regex")]
+            SearchPattern::Regex(regex) => f.debug_tuple("Regex").field(&regex.as_str()).finish(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub pattern: SearchPattern,
+    /// Stop after this many hits.
+    pub max_hits: Option<usize>,
+    /// Bytes of each chunk searched again with the next, so that matches
+    /// across chunk boundaries are found. Byte patterns need no more than
+    /// their own length and ignore this; a regular expression finds
+    /// matches this long or shorter wherever they lie.
+    pub overlap: usize,
+    /// Name the file or directory each hit lies in, if the device holds a
+    /// volume `open_volume` or `open_partition` opened.
+    pub owners: bool,
+}
+
+impl SearchOptions {
+    pub fn new(pattern: SearchPattern) -> Self {
+        SearchOptions {
+            pattern,
+            max_hits: None,
+            overlap: 4096,
+            owners: true,
+        }
+    }
+}
+
+/// A match found by `search`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SearchHit {
+    /// The block the match starts in, counted from the start of the
+    /// device whether or not a partition is open.
+    pub block: u64,
+    /// Byte offset of the match in that block.
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    /// Path of the file or directory whose cluster holds the match.
+    pub owner: Option<String>,
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Searches every block of the device for `options.pattern`, from
+    /// block 0 whatever partition is open, so that the partition table,
+    /// free space and the gaps between partitions are searched too. Hits
+    /// are listed in the order they lie on the device, and do not overlap.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn search<P: ProgressSink>(
+        &mut self,
+        options: &SearchOptions,
+        progress: &mut P,
+    ) -> Result<Vec<SearchHit>, SDError> {
+        let owners = if options.owners {
+            self.block_owners().ok()
+        } else {
+            None
+        };
+        let volume_start = self.partition_start() as u64;
+        let overlap = match &options.pattern {
+            SearchPattern::Bytes(needle) => needle.len().saturating_sub(1),
+            #[cfg(feature = "regex")]
+            SearchPattern::Regex(_) => options.overlap,
+        };
+        let block_size = self.block_size() as u64;
+        let total_blocks = self.device.num_blocks();
+        let chunk_blocks = (CHUNK_BYTES / block_size).max(1);
+        let stopwatch = Stopwatch::start();
+        let mut hits = Vec::new();
+        // The bytes searched, which start at `window_start` on the device,
+        // and the first byte a match may start at.
+        let mut window = Vec::new();
+        let mut window_start = 0u64;
+        let mut resume = 0u64;
+        let mut block = 0;
+        while block < total_blocks {
+            let count = chunk_blocks.min(total_blocks - block);
+            window.extend(self.read_device_blocks(block_index(block)?, count as u32)?);
+            block += count;
+            let last = block == total_blocks;
+            // Matches starting in the tail are left for the next window,
+            // which will hold them in full.
+            let cut = if last {
+                window.len()
+            } else {
+                window.len().saturating_sub(overlap)
+            };
+            let mut from = (resume - window_start) as usize;
+            while let Some((start, end)) = options.pattern.find_at(&window, from) {
+                if start >= cut {
+                    break;
+                }
+                let position = window_start + start as u64;
+                let hit_block = position / block_size;
+                hits.push(SearchHit {
+                    block: hit_block,
+                    offset: (position % block_size) as usize,
+                    bytes: window[start..end].to_vec(),
+                    owner: owners.as_ref().and_then(|owners| {
+                        let block = hit_block.checked_sub(volume_start)?;
+                        Some(owners.owner(block).ok()?.path()?.to_string())
+                    }),
+                });
+                if options.max_hits.is_some_and(|max| hits.len() >= max) {
+                    return Ok(hits);
+                }
+                // An empty match must not be found again.
+                from = end.max(start + 1);
+                resume = window_start + from as u64;
+            }
+            resume = resume.max(window_start + cut as u64);
+            window.drain(..cut);
+            window_start += cut as u64;
+            progress.report(&Progress {
+                phase: Phase::Scanning,
+                bytes_done: block * block_size,
+                bytes_total: total_blocks * block_size,
+                elapsed: stopwatch.elapsed(),
+            });
+        }
+        debug!(hits = hits.len(), "searched device");
+        Ok(hits)
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::block::{BlockDevice, MemBlockDevice};
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::fat::FatVariant;
+use crate::format::FormatOptions;
+
+/// What a `FaultyDevice` does when a read touches a faulty block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Every read fails with an I/O error, as on an unreadable sector.
+    IoError,
+    /// Reads come back with only this many bytes of the request, from the
+    /// start of the faulty block.
+    ShortRead(usize),
+    /// Reads succeed after this delay, as on a card retrying internally.
+    Latency(Duration),
+    /// The next `n` reads fail with an I/O error and later reads succeed.
+    Transient(u32),
+}
+
+/// Wraps a device and makes reads of chosen blocks fail, come back short or
+/// take longer, to exercise error handling without a damaged card. Writes
+/// pass straight through.
+///
+/// A multi-block read fails as a whole if any block in it is faulty, the
+/// way a card reports an error for the whole transfer.
+#[derive(Debug, Clone)]
+pub struct FaultyDevice<D: BlockDevice> {
+    inner: D,
+    faults: BTreeMap<u64, Fault>,
+}
+
+impl<D: BlockDevice> FaultyDevice<D> {
+    pub fn new(inner: D) -> Self {
+        FaultyDevice {
+            inner,
+            faults: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_fault(mut self, block: u64, fault: Fault) -> Self {
+        self.add_fault(block, fault);
+        self
+    }
+
+    /// Sets the fault of `block`, replacing any it had.
+    pub fn add_fault(&mut self, block: u64, fault: Fault) {
+        self.faults.insert(block, fault);
+    }
+
+    pub fn remove_fault(&mut self, block: u64) -> Option<Fault> {
+        self.faults.remove(&block)
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Applies the faults of the blocks from `start` covered by a read of
+    /// `len` bytes, and returns how many bytes the read delivers.
+    fn inject(&mut self, start: u32, len: usize) -> Result<usize, SDError> {
+        let block_size = self.inner.block_size() as u64;
+        let first = start as u64;
+        let end = first + (len as u64).div_ceil(block_size);
+        let mut delay = Duration::ZERO;
+        let mut failed = None;
+        let mut delivered = len;
+        for (&block, fault) in self.faults.range_mut(first..end) {
+            match fault {
+                Fault::IoError => failed = failed.or(Some(block)),
+                Fault::ShortRead(bytes) => {
+                    let offset = ((block - first) * block_size) as usize;
+                    delivered = delivered.min(offset + *bytes);
+                }
+                Fault::Latency(extra) => delay += *extra,
+                Fault::Transient(0) => {}
+                Fault::Transient(remaining) => {
+                    *remaining -= 1;
+                    failed = failed.or(Some(block));
+                }
+            }
+        }
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        if let Some(block) = failed {
+            return Err(SDError::IO(io::Error::other(format!(
+                "injected read error at block {block}"
+            ))));
+        }
+        Ok(delivered)
+    }
+
+    fn read_checked(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        let delivered = self.inject(start, buffer.len())?;
+        if delivered < buffer.len() {
+            return Err(SDError::ReadError {
+                expected: buffer.len(),
+                actual: delivered,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for FaultyDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.read_checked(block_index, buffer)?;
+        self.inner.read_block(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.read_checked(start, buffer)?;
+        self.inner.read_blocks(start, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        self.inner.write_block(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        self.inner.write_blocks(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        self.inner.flush()
+    }
+
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        self.inner.discard(start, count)
+    }
+}
+
+/// Builds a small formatted volume in memory, optionally holding some
+/// directories and files, for tests.
+#[derive(Debug, Clone)]
+pub struct FatImageBuilder {
+    variant: FatVariant,
+    bytes: u64,
+    options: FormatOptions,
+    dirs: Vec<String>,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl FatImageBuilder {
+    /// A 16 MiB FAT16 volume with 2 KiB clusters.
+    pub fn fat16() -> Self {
+        FatImageBuilder::new(FatVariant::Fat16, 16 << 20)
+    }
+
+    /// A 40 MiB FAT32 volume with 512-byte clusters, about the smallest
+    /// volume FAT32 allows.
+    pub fn fat32() -> Self {
+        FatImageBuilder::new(FatVariant::Fat32, 40 << 20)
+    }
+
+    fn new(variant: FatVariant, bytes: u64) -> Self {
+        FatImageBuilder {
+            variant,
+            bytes,
+            options: FormatOptions {
+                variant: Some(variant),
+                serial: Some(0x1234_5678),
+                ..FormatOptions::default()
+            },
+            dirs: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// The size of the volume, rounded down to whole sectors.
+    pub fn size(mut self, bytes: u64) -> Self {
+        self.bytes = bytes;
+        self
+    }
+
+    pub fn sectors_per_cluster(mut self, sectors: u8) -> Self {
+        self.options.sectors_per_cluster = Some(sectors);
+        self
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        self.options.label = Some(label.to_string());
+        self
+    }
+
+    pub fn serial(mut self, serial: u32) -> Self {
+        self.options.serial = Some(serial);
+        self
+    }
+
+    /// Creates the directory at `path`, whose parent must already be added.
+    pub fn dir(mut self, path: &str) -> Self {
+        self.dirs.push(path.to_string());
+        self
+    }
+
+    /// Creates a file at `path`, whose directory must already be added.
+    /// Files are written after every directory.
+    pub fn file(mut self, path: &str, data: &[u8]) -> Self {
+        self.files.push((path.to_string(), data.to_vec()));
+        self
+    }
+
+    pub fn variant(&self) -> FatVariant {
+        self.variant
+    }
+
+    /// The formatted, populated image.
+    pub fn build(self) -> Result<MemBlockDevice, SDError> {
+        Ok(self.build_controller()?.into_inner())
+    }
+
+    /// The image, opened in a writable controller.
+    pub fn build_controller(self) -> Result<SDController<MemBlockDevice>, SDError> {
+        let device = MemBlockDevice::new(512, self.bytes / 512)?;
+        let mut controller = SDController::from_device(device);
+        controller.enable_writes();
+        controller.format(&self.options)?;
+        for dir in &self.dirs {
+            controller.create_dir(dir)?;
+        }
+        for (path, data) in &self.files {
+            controller.create_file(path, data)?;
+        }
+        controller.flush()?;
+        Ok(controller)
+    }
+}
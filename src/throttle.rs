@@ -0,0 +1,99 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::block::BlockDevice;
+use crate::error::SDError;
+
+/// How far ahead of the limit requests may run after the device has been
+/// idle, so that a pause does not buy a burst at full speed.
+const BURST: Duration = Duration::from_millis(100);
+
+/// Caps the bytes read from and written to another device per second, by
+/// sleeping before a request until the bytes already moved would have
+/// taken that long at the limit.
+///
+/// A long image, scan or wipe over a card reader on a shared USB hub can
+/// otherwise take the whole bus from the devices beside it. Requests are
+/// passed on whole, so the limit holds on average over a few requests
+/// rather than within each one.
+pub struct ThrottledDevice<D: BlockDevice> {
+    inner: D,
+    bytes_per_second: u64,
+    /// When the bytes moved so far are done at the limit.
+    ready_at: Instant,
+}
+
+impl<D: BlockDevice> ThrottledDevice<D> {
+    /// Limits `inner` to `bytes_per_second`, which is treated as at least
+    /// one.
+    pub fn new(inner: D, bytes_per_second: u64) -> Self {
+        ThrottledDevice {
+            inner,
+            bytes_per_second: bytes_per_second.max(1),
+            ready_at: Instant::now(),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Waits until `bytes` more may be moved.
+    fn pace(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let earliest = now.checked_sub(BURST).unwrap_or(now);
+        let start = self.ready_at.max(earliest);
+        if start > now {
+            thread::sleep(start - now);
+        }
+        let nanos = bytes as u128 * 1_000_000_000 / self.bytes_per_second as u128;
+        self.ready_at = start + Duration::from_nanos(nanos.min(u64::MAX as u128) as u64);
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for ThrottledDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.pace(buffer.len());
+        self.inner.read_block(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.pace(buffer.len());
+        self.inner.read_blocks(start, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        self.pace(data.len());
+        self.inner.write_block(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        self.pace(data.len());
+        self.inner.write_blocks(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        self.inner.flush()
+    }
+
+    /// Discards move no data over the bus, so they are not held back.
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        self.inner.discard(start, count)
+    }
+}
@@ -0,0 +1,527 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::allocation::{AllocationMap, ClusterState};
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+use crate::extract::host_path;
+use crate::hexdump::{write_hexdump, HexdumpOptions};
+use crate::partition::DiskLayout;
+
+/// How much of the selected file the hex pane shows.
+const PREVIEW_BYTES: u64 = 64 << 10;
+
+/// Bytes hexdumped to a file at a time; a multiple of every line width.
+const HEXDUMP_CHUNK: usize = 1 << 20;
+
+const HELP: &str = "↑↓ move  Enter open  ⌫ up  Tab switch pane  m hex/map  \
+                    PgUp/PgDn scroll  x extract  d hexdump  q quit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Volumes,
+    Files,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    Hex,
+    Map,
+}
+
+/// A volume to pick: the whole device, or a partition of its table.
+struct Volume {
+    label: String,
+    partition: Option<usize>,
+}
+
+enum Item {
+    Parent,
+    Entry(DirEntry),
+}
+
+struct Browser<D: BlockDevice> {
+    controller: SDController<D>,
+    dest: PathBuf,
+    volumes: Vec<Volume>,
+    volume_state: ListState,
+    path: String,
+    items: Vec<Item>,
+    item_state: ListState,
+    focus: Focus,
+    view: View,
+    preview: Vec<u8>,
+    scroll: u16,
+    map: Option<AllocationMap>,
+    /// The clusters of the selected file or directory.
+    selected_clusters: Vec<u32>,
+    status: String,
+}
+
+/// Browses the device `controller` reads in the terminal until `q` is
+/// pressed: its partitions, the directories of the open volume, a hex
+/// preview of the selected file and a map of the allocated clusters.
+/// Files and directories are extracted, and hexdumps written, into `dest`.
+pub fn browse<D: BlockDevice>(controller: SDController<D>, dest: &Path) -> Result<(), SDError> {
+    let mut browser = Browser::new(controller, dest)?;
+    let mut terminal = ratatui::init();
+    let result = browser.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl<D: BlockDevice> Browser<D> {
+    fn new(mut controller: SDController<D>, dest: &Path) -> Result<Self, SDError> {
+        let open = controller.partition();
+        controller.close_partition();
+        let volumes = match controller.detect_layout() {
+            Ok(DiskLayout::Partitioned(table)) => table
+                .partitions
+                .iter()
+                .enumerate()
+                .map(|(index, partition)| Volume {
+                    label: format!(
+                        "{}: {} at {}, {} blocks",
+                        index,
+                        partition.partition_type,
+                        partition.start_lba,
+                        partition.sector_count
+                    ),
+                    partition: Some(index),
+                })
+                .collect(),
+            _ => vec![Volume {
+                label: format!("Whole device, {} blocks", controller.num_blocks()),
+                partition: None,
+            }],
+        };
+        if let Some(index) = open {
+            controller.open_partition(index)?;
+        }
+        let selected = volumes
+            .iter()
+            .position(|volume| volume.partition == open)
+            .unwrap_or(0);
+        let mut browser = Browser {
+            controller,
+            dest: dest.to_path_buf(),
+            volumes,
+            volume_state: ListState::default().with_selected(Some(selected)),
+            path: "/".to_string(),
+            items: Vec::new(),
+            item_state: ListState::default(),
+            focus: Focus::Files,
+            view: View::Hex,
+            preview: Vec::new(),
+            scroll: 0,
+            map: None,
+            selected_clusters: Vec::new(),
+            status: String::new(),
+        };
+        browser.list("/".to_string());
+        Ok(browser)
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), SDError> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            self.status.clear();
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Tab | KeyCode::BackTab => {
+                    self.focus = match self.focus {
+                        Focus::Volumes => Focus::Files,
+                        Focus::Files => Focus::Volumes,
+                    }
+                }
+                KeyCode::Up | KeyCode::Char('k') => self.step(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.step(1),
+                KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => self.enter(),
+                KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => self.leave(),
+                KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(16),
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_add(16),
+                KeyCode::Char('m') => {
+                    self.view = match self.view {
+                        View::Hex => View::Map,
+                        View::Map => View::Hex,
+                    };
+                    self.select_item();
+                }
+                KeyCode::Char('x') => self.status = outcome(self.extract()),
+                KeyCode::Char('d') => self.status = outcome(self.hexdump()),
+                _ => {}
+            }
+        }
+    }
+
+    fn step(&mut self, by: isize) {
+        let (state, len) = match self.focus {
+            Focus::Volumes => (&mut self.volume_state, self.volumes.len()),
+            Focus::Files => (&mut self.item_state, self.items.len()),
+        };
+        if len == 0 {
+            return;
+        }
+        let current = state.selected().unwrap_or(0) as isize;
+        state.select(Some((current + by).clamp(0, len as isize - 1) as usize));
+        if self.focus == Focus::Files {
+            self.select_item();
+        }
+    }
+
+    fn enter(&mut self) {
+        match self.focus {
+            Focus::Volumes => {
+                let Some(volume) = self.volume_state.selected().map(|i| &self.volumes[i]) else {
+                    return;
+                };
+                match volume.partition {
+                    Some(index) => {
+                        if let Err(error) = self.controller.open_partition(index) {
+                            self.status = error.to_string();
+                            return;
+                        }
+                    }
+                    None => self.controller.close_partition(),
+                }
+                self.map = None;
+                self.focus = Focus::Files;
+                self.list("/".to_string());
+            }
+            Focus::Files => match self.selected_item() {
+                Some(Item::Parent) => self.leave(),
+                Some(Item::Entry(entry)) if entry.is_dir() => {
+                    let path = self.child_path(entry);
+                    self.list(path);
+                }
+                _ => {}
+            },
+        }
+    }
+
+    fn leave(&mut self) {
+        if self.focus != Focus::Files || self.path == "/" {
+            return;
+        }
+        let parent = match self.path.rfind('/') {
+            Some(0) | None => "/".to_string(),
+            Some(at) => self.path[..at].to_string(),
+        };
+        self.list(parent);
+    }
+
+    /// Lists the directory at `path`, directories first.
+    fn list(&mut self, path: String) {
+        let listing = self
+            .controller
+            .stat(&path)
+            .and_then(|dir| Ok(self.controller.open_dir(&dir)?.collect::<Vec<_>>()));
+        let mut entries = match listing {
+            Ok(entries) => entries,
+            Err(error) => {
+                self.status = error.to_string();
+                Vec::new()
+            }
+        };
+        entries.sort_by_key(|entry| (!entry.is_dir(), entry.full_name().to_lowercase()));
+        self.items = Vec::new();
+        if path != "/" {
+            self.items.push(Item::Parent);
+        }
+        self.items.extend(entries.into_iter().map(Item::Entry));
+        self.path = path;
+        self.item_state
+            .select((!self.items.is_empty()).then_some(0));
+        self.select_item();
+    }
+
+    fn selected_item(&self) -> Option<&Item> {
+        self.item_state.selected().and_then(|i| self.items.get(i))
+    }
+
+    fn selected_entry(&self) -> Option<DirEntry> {
+        match self.selected_item() {
+            Some(Item::Entry(entry)) => Some(entry.clone()),
+            _ => None,
+        }
+    }
+
+    fn child_path(&self, entry: &DirEntry) -> String {
+        format!("{}/{}", self.path.trim_end_matches('/'), entry.full_name())
+    }
+
+    /// Loads what the right-hand pane shows of the selected entry.
+    fn select_item(&mut self) {
+        self.scroll = 0;
+        self.preview.clear();
+        let entry = self.selected_entry();
+        let result = match self.view {
+            View::Hex => match &entry {
+                Some(entry) if !entry.is_dir() => self.load_preview(entry),
+                _ => Ok(()),
+            },
+            View::Map => self.load_map(entry.as_ref()),
+        };
+        if let Err(error) = result {
+            self.status = error.to_string();
+        }
+    }
+
+    fn load_preview(&mut self, entry: &DirEntry) -> Result<(), SDError> {
+        self.controller
+            .file_reader(entry)?
+            .take(PREVIEW_BYTES)
+            .read_to_end(&mut self.preview)?;
+        Ok(())
+    }
+
+    fn load_map(&mut self, entry: Option<&DirEntry>) -> Result<(), SDError> {
+        let layout = self.controller.layout()?;
+        if self.map.is_none() {
+            self.map = Some(self.controller.alloc_map()?);
+        }
+        self.selected_clusters = match entry {
+            Some(entry) if entry.first_cluster >= 2 => {
+                if entry.contiguous {
+                    let clusters = entry.size.div_ceil(layout.cluster_size() as u64).max(1);
+                    (entry.first_cluster..entry.first_cluster + clusters as u32).collect()
+                } else {
+                    self.controller
+                        .chain_clusters(&layout, entry.first_cluster)?
+                }
+            }
+            _ => Vec::new(),
+        };
+        Ok(())
+    }
+
+    /// Copies the selected file, or directory and everything below it, into
+    /// `dest`.
+    fn extract(&mut self) -> Result<String, SDError> {
+        let entry = self.selected_entry().ok_or(SDError::Unsupported(
+            "extracting without a file or directory selected",
+        ))?;
+        let path = self.child_path(&entry);
+        let target = host_path(&self.dest, &entry.full_name())?;
+        if entry.is_dir() {
+            let summary = self.controller.extract_all(&path, &target, &mut ())?;
+            return Ok(format!(
+                "Extracted {} files to {} ({} failed)",
+                summary.files,
+                target.display(),
+                summary.failures.len()
+            ));
+        }
+        let mut reader = self.controller.file_reader(&entry)?;
+        let written = io::copy(&mut reader, &mut File::create(&target)?)?;
+        Ok(format!(
+            "Extracted {} bytes to {}",
+            written,
+            target.display()
+        ))
+    }
+
+    /// Writes a hexdump of the whole selected file into `dest`, as the
+    /// file's name with `.hex` added.
+    fn hexdump(&mut self) -> Result<String, SDError> {
+        let entry = match self.selected_entry() {
+            Some(entry) if !entry.is_dir() => entry,
+            _ => return Err(SDError::Unsupported("hexdumping anything but a file")),
+        };
+        let target = host_path(&self.dest, &format!("{}.hex", entry.full_name()))?;
+        let mut out = BufWriter::new(File::create(&target)?);
+        let mut reader = self.controller.file_reader(&entry)?;
+        let mut buffer = vec![0u8; HEXDUMP_CHUNK];
+        let mut offset = 0;
+        loop {
+            let mut filled = 0;
+            while filled < buffer.len() {
+                match reader.read(&mut buffer[filled..])? {
+                    0 => break,
+                    read => filled += read,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            let options = HexdumpOptions {
+                base_offset: offset,
+                ..HexdumpOptions::default()
+            };
+            write_hexdump(&mut out, &buffer[..filled], &options)?;
+            offset += filled as u64;
+        }
+        out.flush()?;
+        Ok(format!(
+            "Wrote a hexdump of {} bytes to {}",
+            offset,
+            target.display()
+        ))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status, help] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main);
+        let volumes_height = self.volumes.len() as u16 + 2;
+        let [volumes, files] = Layout::vertical([
+            Constraint::Length(volumes_height.min(8)),
+            Constraint::Min(0),
+        ])
+        .areas(left);
+
+        let highlight = Style::default().add_modifier(Modifier::REVERSED);
+        let volume_list = List::new(self.volumes.iter().map(|volume| volume.label.as_str()))
+            .block(pane("Partitions", self.focus == Focus::Volumes))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(volume_list, volumes, &mut self.volume_state);
+
+        // Sizes are right-aligned against the border.
+        let name_width = (files.width as usize).saturating_sub(14);
+        let items = self.items.iter().map(|item| match item {
+            Item::Parent => ListItem::new("../"),
+            Item::Entry(entry) if entry.is_dir() => {
+                ListItem::new(format!("{}/", entry.full_name()))
+                    .style(Style::default().fg(Color::Blue))
+            }
+            Item::Entry(entry) => ListItem::new(format!(
+                "{:<name_width$} {:>10}",
+                entry.full_name(),
+                entry.size
+            )),
+        });
+        let file_list = List::new(items)
+            .block(pane(&self.path, self.focus == Focus::Files))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(file_list, files, &mut self.item_state);
+
+        match self.view {
+            View::Hex => self.draw_hex(frame, right),
+            View::Map => self.draw_map(frame, right),
+        }
+        frame.render_widget(
+            Paragraph::new(self.status.as_str()).style(Style::default().fg(Color::Yellow)),
+            status,
+        );
+        frame.render_widget(
+            Paragraph::new(HELP).style(Style::default().add_modifier(Modifier::DIM)),
+            help,
+        );
+    }
+
+    fn draw_hex(&self, frame: &mut Frame, area: Rect) {
+        // A line of 16 bytes takes 78 columns, one of 8 takes 44.
+        let width = if area.width >= 80 { 16 } else { 8 };
+        let options = HexdumpOptions {
+            width,
+            ..HexdumpOptions::default()
+        };
+        let text = crate::hexdump::hexdump(&self.preview, &options);
+        let title = match self.selected_entry() {
+            Some(entry) if !entry.is_dir() && entry.size > PREVIEW_BYTES => format!(
+                "{} (first {} of {} bytes)",
+                entry.full_name(),
+                PREVIEW_BYTES,
+                entry.size
+            ),
+            Some(entry) => entry.full_name(),
+            None => String::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(text)
+                .block(pane(&title, false))
+                .scroll((self.scroll, 0)),
+            area,
+        );
+    }
+
+    /// Draws each cell of the pane for a run of clusters: full when all of
+    /// them are used, shaded when some are, red when one is bad, and
+    /// highlighted when the selected entry has clusters among them.
+    fn draw_map(&self, frame: &mut Frame, area: Rect) {
+        let Some(map) = &self.map else {
+            frame.render_widget(Block::bordered().title(" Allocation "), area);
+            return;
+        };
+        let cells =
+            (area.width.saturating_sub(2) as usize * area.height.saturating_sub(2) as usize).max(1);
+        let per_cell = map.clusters.len().div_ceil(cells).max(1);
+        let mut selected = vec![false; map.clusters.len().div_ceil(per_cell)];
+        for &cluster in &self.selected_clusters {
+            if let Some(cell) = selected.get_mut(cluster.saturating_sub(2) as usize / per_cell) {
+                *cell = true;
+            }
+        }
+        let spans: Vec<Span> = map
+            .clusters
+            .chunks(per_cell)
+            .zip(&selected)
+            .map(|(run, &selected)| {
+                let used = run.iter().filter(|&&s| s == ClusterState::Used).count();
+                let symbol = match used {
+                    0 => "·",
+                    used if used == run.len() => "█",
+                    _ => "▒",
+                };
+                let color = if selected {
+                    Color::Yellow
+                } else if run.contains(&ClusterState::Bad) {
+                    Color::Red
+                } else {
+                    Color::Green
+                };
+                Span::styled(symbol, Style::default().fg(color))
+            })
+            .collect();
+        let line_width = area.width.saturating_sub(2).max(1) as usize;
+        let lines: Vec<Line> = spans
+            .chunks(line_width)
+            .map(|chunk| Line::from(chunk.to_vec()))
+            .collect();
+        let title = format!(
+            "Allocation: {} of {} clusters used, {} bad, {} per cell",
+            map.count(ClusterState::Used),
+            map.clusters.len(),
+            map.count(ClusterState::Bad),
+            per_cell
+        );
+        frame.render_widget(Paragraph::new(lines).block(pane(&title, false)), area);
+    }
+}
+
+fn pane(title: &str, focused: bool) -> Block<'static> {
+    let style = if focused {
+        Style::default().fg(Color::Cyan)
+    } else {
+        Style::default()
+    };
+    Block::bordered()
+        .title(format!(" {} ", title))
+        .border_style(style)
+}
+
+fn outcome(result: Result<String, SDError>) -> String {
+    result.unwrap_or_else(|error| error.to_string())
+}
@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::block::{aligned, BlockDevice, FileDevice};
+use crate::error::SDError;
+use crate::log::trace;
+
+/// Requests kept in flight at once by `UringDevice::new`.
+pub const DEFAULT_QUEUE_DEPTH: u32 = 32;
+
+/// Size of each request a transfer is split into. Card readers rarely take
+/// more than this in one command, so larger requests would only be split
+/// again by the kernel, one after the other.
+const REQUEST_BYTES: usize = 128 << 10;
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// A `FileDevice` whose multi-block transfers go through io_uring, split
+/// into requests that are all submitted together. A reader on a USB3 or
+/// UAS bridge then has a queue of commands to work through instead of one
+/// at a time, which is what bulk passes over the card need: scanning,
+/// imaging and extraction all read a megabyte per call.
+///
+/// Linux only, with the `io-uring` feature. Kernels that forbid io_uring,
+/// as some containers do, fail `new`; use the `FileDevice` itself then.
+pub struct UringDevice {
+    device: FileDevice,
+    ring: IoUring,
+    queue_depth: usize,
+    /// Aligned buffer for a device opened with direct I/O.
+    staging: Vec<u8>,
+}
+
+impl UringDevice {
+    pub fn new(device: FileDevice) -> Result<Self, SDError> {
+        UringDevice::with_queue_depth(device, DEFAULT_QUEUE_DEPTH)
+    }
+
+    /// `queue_depth` is how many requests may be in flight at once. The
+    /// kernel refuses zero and depths beyond its limit, 32768 today.
+    pub fn with_queue_depth(device: FileDevice, queue_depth: u32) -> Result<Self, SDError> {
+        let ring = IoUring::new(queue_depth)?;
+        Ok(UringDevice {
+            device,
+            ring,
+            queue_depth: queue_depth as usize,
+            staging: Vec::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> FileDevice {
+        self.device
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.device.is_writable()
+    }
+
+    fn check_range(&self, start: u32, len: usize) -> Result<(), SDError> {
+        let blocks = (len / self.device.block_size()) as u64;
+        if start as u64 + blocks > self.device.num_blocks() {
+            return Err(SDError::BlockOutOfRange(start as u64 + blocks - 1));
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.check_range(start, buffer.len())?;
+        let offset = start as u64 * self.device.block_size() as u64;
+        if !self.device.is_direct() {
+            return Ok(self.transfer(
+                offset,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                Direction::Read,
+            )?);
+        }
+        let mut staging = std::mem::take(&mut self.staging);
+        let staged = aligned(&mut staging, buffer.len());
+        let result = self.transfer(offset, staged.as_mut_ptr(), staged.len(), Direction::Read);
+        buffer.copy_from_slice(staged);
+        self.staging = staging;
+        Ok(result?)
+    }
+
+    fn write_at(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !self.device.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        self.check_range(start, data.len())?;
+        let offset = start as u64 * self.device.block_size() as u64;
+        let mut staging = std::mem::take(&mut self.staging);
+        // The ring only reads from a write's buffer, so a pointer to the
+        // caller's data is only made mutable to share `transfer`.
+        let buffer = if self.device.is_direct() {
+            let staged = aligned(&mut staging, data.len());
+            staged.copy_from_slice(data);
+            staged.as_mut_ptr()
+        } else {
+            data.as_ptr().cast_mut()
+        };
+        let result = self.transfer(offset, buffer, data.len(), Direction::Write);
+        self.staging = staging;
+        Ok(result?)
+    }
+
+    /// Moves `len` bytes between `buffer` and the file from `offset`, as up
+    /// to `queue_depth` requests at a time. Short transfers are resubmitted
+    /// for the rest. Every request submitted has completed by the time this
+    /// returns, even on an error, so `buffer` is not used after that.
+    fn transfer(
+        &mut self,
+        offset: u64,
+        buffer: *mut u8,
+        len: usize,
+        direction: Direction,
+    ) -> io::Result<()> {
+        let fd = types::Fd(self.device.file().as_raw_fd());
+        let mut pending: VecDeque<(usize, usize)> = (0..len)
+            .step_by(REQUEST_BYTES)
+            .map(|at| (at, REQUEST_BYTES.min(len - at)))
+            .collect();
+        trace!(offset, len, requests = pending.len(), "io_uring transfer");
+        let mut failure = None;
+        while failure.is_none() && !pending.is_empty() {
+            let batch: Vec<(usize, usize)> = pending
+                .drain(..pending.len().min(self.queue_depth))
+                .collect();
+            for (index, &(at, count)) in batch.iter().enumerate() {
+                // SAFETY: `at + count` is within the `len` bytes of `buffer`.
+                let pointer = unsafe { buffer.add(at) };
+                let entry = match direction {
+                    Direction::Read => opcode::Read::new(fd, pointer, count as u32)
+                        .offset(offset + at as u64)
+                        .build(),
+                    Direction::Write => opcode::Write::new(fd, pointer, count as u32)
+                        .offset(offset + at as u64)
+                        .build(),
+                };
+                // SAFETY: the buffer outlives the request, which is waited
+                // for below before anything else is done with it.
+                unsafe { self.ring.submission().push(&entry.user_data(index as u64)) }
+                    .expect("batches are no longer than the queue");
+            }
+            let mut completed = 0;
+            while completed < batch.len() {
+                // Other than for these, the kernel fails the call on checks
+                // made before it takes any entry, so none is in flight.
+                match self.ring.submit_and_wait(batch.len() - completed) {
+                    Ok(_) => {}
+                    Err(e) if is_transient(&e) || e.kind() == io::ErrorKind::ResourceBusy => {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                }
+                for completion in self.ring.completion() {
+                    completed += 1;
+                    let (at, count) = batch[completion.user_data() as usize];
+                    let result = completion.result();
+                    if result < 0 {
+                        let error = io::Error::from_raw_os_error(-result);
+                        if is_transient(&error) {
+                            pending.push_back((at, count));
+                        } else {
+                            failure.get_or_insert(error);
+                        }
+                    } else if result == 0 {
+                        failure.get_or_insert(match direction {
+                            Direction::Read => io::ErrorKind::UnexpectedEof.into(),
+                            Direction::Write => io::ErrorKind::WriteZero.into(),
+                        });
+                    } else if (result as usize) < count {
+                        let done = result as usize;
+                        pending.push_back((at + done, count - done));
+                    }
+                }
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+impl BlockDevice for UringDevice {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_at(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if !buffer.len().is_multiple_of(self.block_size()) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_at(start, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.write_at(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !data.len().is_multiple_of(self.block_size()) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.write_at(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        self.device.flush()
+    }
+
+    fn discard(&mut self, start: u32, count: u32) -> Result<(), SDError> {
+        self.device.discard(start, count)
+    }
+}
@@ -0,0 +1,223 @@
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::fat::{FatTable, FatVariant};
+use crate::log::{debug, trace};
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The FAT32 FSInfo sector. Both fields are hints that the filesystem keeps
+/// for speed; they can be stale after an unclean unmount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FsInfo {
+    pub free_clusters: Option<u32>,
+    pub next_free: Option<u32>,
+}
+
+impl FsInfo {
+    pub fn parse(data: &[u8]) -> Result<Self, SDError> {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+        if data.len() < 512 {
+            return Err(SDError::parse(
+                data.len(),
+                "FSInfo sector",
+                "shorter than 512 bytes",
+            ));
+        }
+        for (offset, signature) in [
+            (0, FSINFO_LEAD_SIGNATURE),
+            (484, FSINFO_STRUCT_SIGNATURE),
+            (508, FSINFO_TRAIL_SIGNATURE),
+        ] {
+            if u32_at(offset) != signature {
+                return Err(SDError::parse(offset, "FSInfo signature", "is missing"));
+            }
+        }
+        let known = |value: u32| (value != FSINFO_UNKNOWN).then_some(value);
+        Ok(FsInfo {
+            free_clusters: known(u32_at(488)),
+            next_free: known(u32_at(492)),
+        })
+    }
+
+    /// Stores both fields into an FSInfo sector, leaving its signatures and
+    /// reserved bytes alone.
+    pub fn write_to(&self, data: &mut [u8]) {
+        let unknown = |value: Option<u32>| value.unwrap_or(FSINFO_UNKNOWN).to_le_bytes();
+        data[488..492].copy_from_slice(&unknown(self.free_clusters));
+        data[492..496].copy_from_slice(&unknown(self.next_free));
+    }
+
+    /// Drops the hints no volume of `cluster_count` clusters can have: a
+    /// free count above the cluster count, or a next-free cluster outside
+    /// the data area.
+    fn validated(self, cluster_count: u32) -> Self {
+        FsInfo {
+            free_clusters: self.free_clusters.filter(|&free| free <= cluster_count),
+            next_free: self
+                .next_free
+                .filter(|&cluster| cluster >= 2 && cluster < cluster_count + 2),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub variant: FatVariant,
+    pub cluster_size: u64,
+    pub total_clusters: u32,
+    pub free_clusters: u32,
+    /// Length in clusters of the longest run of free clusters.
+    pub largest_free_extent: u32,
+    /// The FSInfo sector of a FAT32 volume, if it is valid.
+    pub fs_info: Option<FsInfo>,
+}
+
+impl Usage {
+    pub fn total_bytes(&self) -> u64 {
+        self.total_clusters as u64 * self.cluster_size
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_clusters as u64 * self.cluster_size
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.total_bytes() - self.free_bytes()
+    }
+
+    pub fn largest_free_extent_bytes(&self) -> u64 {
+        self.largest_free_extent as u64 * self.cluster_size
+    }
+}
+
+/// Counts free clusters and the longest free run from a per-cluster
+/// predicate over clusters 2..total+2.
+fn scan_free(total_clusters: u32, is_free: impl Fn(u32) -> bool) -> (u32, u32) {
+    let mut free = 0;
+    let mut run = 0;
+    let mut largest = 0;
+    for cluster in 2..total_clusters + 2 {
+        if is_free(cluster) {
+            free += 1;
+            run += 1;
+            largest = largest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    (free, largest)
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Computes volume usage by scanning the FAT, or the allocation bitmap on
+    /// exFAT where the FAT does not record contiguous files.
+    pub fn usage(&mut self) -> Result<Usage, SDError> {
+        let layout = self.layout()?;
+        let total_clusters = layout.cluster_count;
+
+        let (free_clusters, largest_free_extent, fs_info) = if layout.variant == FatVariant::ExFat {
+            let bitmap = self.read_allocation_bitmap()?;
+            let (free, largest) =
+                scan_free(total_clusters, |cluster| !bitmap.is_allocated(cluster));
+            (free, largest, None)
+        } else {
+            let table = self.load_fat(&layout)?;
+            let (free, largest) =
+                scan_free(total_clusters, |cluster| table.fat_entry(cluster).is_free());
+            let fs_info = if layout.variant == FatVariant::Fat32 {
+                self.read_fs_info().ok()
+            } else {
+                None
+            };
+            (free, largest, fs_info)
+        };
+
+        Ok(Usage {
+            variant: layout.variant,
+            cluster_size: layout.cluster_size() as u64,
+            total_clusters,
+            free_clusters,
+            largest_free_extent,
+            fs_info,
+        })
+    }
+
+    /// The free cluster count of the volume: from the FSInfo sector of a
+    /// FAT32 volume when it has one, which is one read instead of the whole
+    /// FAT, and otherwise counted as `usage` does.
+    pub fn free_clusters(&mut self) -> Result<u32, SDError> {
+        let layout = self.layout()?;
+        if layout.variant == FatVariant::Fat32 {
+            if let Some(free) = self.fsinfo().ok().and_then(|fs_info| fs_info.free_clusters) {
+                return Ok(free);
+            }
+        }
+        Ok(self.usage()?.free_clusters)
+    }
+
+    /// The FSInfo sector of a FAT32 volume, with hints that cannot be right
+    /// for this volume reported as unknown. `read_fs_info` returns the
+    /// fields as stored.
+    pub fn fsinfo(&mut self) -> Result<FsInfo, SDError> {
+        let layout = self.layout()?;
+        Ok(self.read_fs_info()?.validated(layout.cluster_count))
+    }
+
+    pub fn read_fs_info(&mut self) -> Result<FsInfo, SDError> {
+        let boot_sector = self.read_boot_sector()?;
+        if boot_sector.sectors_per_fat != 0 || boot_sector.fs_info_sector == 0 {
+            return Err(SDError::UnsupportedFilesystem);
+        }
+        let sector = boot_sector.fs_info_sector as u32;
+        let fs_info = FsInfo::parse(&self.read_block(sector)?)
+            .map_err(|e| e.at_block(self.partition_start() as u64 + sector as u64))?;
+        debug!(
+            free_clusters = ?fs_info.free_clusters,
+            next_free = ?fs_info.next_free,
+            "parsed FSInfo"
+        );
+        Ok(fs_info)
+    }
+
+    /// Rewrites the FSInfo sector from `table`, which holds every change to
+    /// the FAT: the free count is recounted and the next-free hint is the
+    /// one allocation left. A volume without a valid FSInfo sector is left
+    /// without one.
+    pub(crate) fn update_fs_info(&mut self, table: &FatTable) -> Result<(), SDError> {
+        let boot_sector = self.read_boot_sector()?;
+        let sector = boot_sector.fs_info_sector as u32;
+        if sector == 0 {
+            return Ok(());
+        }
+        let mut data = self.read_block(sector)?;
+        let Ok(stored) = FsInfo::parse(&data) else {
+            return Ok(());
+        };
+        let fs_info = FsInfo {
+            free_clusters: Some(table.free_clusters().count() as u32),
+            next_free: table.next_free().or(stored.next_free),
+        };
+        if fs_info != stored {
+            fs_info.write_to(&mut data);
+            self.write_block(sector, &data)?;
+            trace!(
+                free_clusters = ?fs_info.free_clusters,
+                next_free = ?fs_info.next_free,
+                "updated FSInfo"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{DirEntry, DirIter};
+use crate::error::SDError;
+
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// Absolute path, e.g. `/DOCS/SUB/DEEP.TXT`.
+    pub path: String,
+    pub entry: DirEntry,
+}
+
+/// Depth-first, pre-order traversal of a directory tree. Each directory is
+/// entered at most once, so a corrupted tree that links back to an ancestor
+/// cannot loop forever.
+pub struct Walk<'a, D: BlockDevice> {
+    controller: &'a mut SDController<D>,
+    stack: Vec<(String, DirIter)>,
+    visited: BTreeSet<u32>,
+}
+
+impl<'a, D: BlockDevice> Walk<'a, D> {
+    fn new(
+        controller: &'a mut SDController<D>,
+        path: &str,
+        dir: DirEntry,
+    ) -> Result<Self, SDError> {
+        let entries = controller.open_dir(&dir)?;
+        let prefix = path.trim_end_matches('/').to_string();
+        Ok(Walk {
+            controller,
+            stack: vec![(prefix, entries)],
+            visited: BTreeSet::from([dir.first_cluster]),
+        })
+    }
+}
+
+impl<D: BlockDevice> Iterator for Walk<'_, D> {
+    type Item = Result<WalkEntry, SDError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (prefix, entries) = self.stack.last_mut()?;
+            let Some(entry) = entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            let path = format!("{}/{}", prefix, entry.full_name());
+
+            if entry.is_dir() && self.visited.insert(entry.first_cluster) {
+                match self.controller.open_dir(&entry) {
+                    Ok(children) => self.stack.push((path.clone(), children)),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            return Some(Ok(WalkEntry { path, entry }));
+        }
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Recursively walks the whole volume.
+    pub fn walk(&mut self) -> Result<Walk<'_, D>, SDError> {
+        self.walk_from("/")
+    }
+
+    /// Recursively walks the tree below the directory at `path`.
+    pub fn walk_from(&mut self, path: &str) -> Result<Walk<'_, D>, SDError> {
+        let dir = self.stat(path)?;
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        Walk::new(self, &path, dir)
+    }
+}
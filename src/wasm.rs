@@ -0,0 +1,380 @@
+//! Reading cards and images in a browser, built for `wasm32-unknown-unknown`
+//! with wasm-bindgen:
+//!
+//! ```text
+//! cargo rustc --release --lib --target wasm32-unknown-unknown \
+//!     --no-default-features --features wasm --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg \
+//!     target/wasm32-unknown-unknown/release/sd_controller.wasm
+//! ```
+//!
+//! `Card.fromBytes` opens an image the page already holds, such as the
+//! `arrayBuffer()` of a dropped `File`. Anything else is reached through
+//! `Card.fromDevice`, which takes an object reading blocks synchronously:
+//! in a worker, a `FileSystemSyncAccessHandle` from the File System Access
+//! API can back it directly, and a WebUSB mass-storage driver can by
+//! waiting on its transfers with `Atomics.wait`.
+
+use std::io;
+
+use js_sys::{Array, Date, Object, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use crate::block::{byte_range, BlockDevice};
+use crate::device::SDController;
+use crate::dir::DirEntry;
+use crate::error::SDError;
+
+/// An image in a JavaScript `Uint8Array`, read and written in place: a view
+/// over an `ArrayBuffer` sees every write the controller makes.
+pub struct ArrayBufferDevice {
+    bytes: Uint8Array,
+    block_size: usize,
+}
+
+impl ArrayBufferDevice {
+    /// Wraps `bytes`, whose length must be a multiple of `block_size`.
+    pub fn new(bytes: Uint8Array, block_size: usize) -> Result<Self, SDError> {
+        if block_size < 512
+            || !block_size.is_power_of_two()
+            || !(bytes.length() as usize).is_multiple_of(block_size)
+        {
+            return Err(SDError::InvalidBlockSize);
+        }
+        Ok(ArrayBufferDevice { bytes, block_size })
+    }
+
+    pub fn bytes(&self) -> &Uint8Array {
+        &self.bytes
+    }
+
+    fn view(&self, start: u32, len: usize) -> Result<Uint8Array, SDError> {
+        if !len.is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let range = byte_range(self.bytes.length() as usize, self.block_size, start, len)?;
+        Ok(self.bytes.subarray(range.start as u32, range.end as u32))
+    }
+}
+
+impl BlockDevice for ArrayBufferDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.bytes.length() as u64 / self.block_size as u64
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_blocks(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.view(start, buffer.len())?.copy_to(buffer);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.write_blocks(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        self.view(start, data.len())?.copy_from(data);
+        Ok(())
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const BLOCK_DEVICE_TS: &str = r#"
+/** A block device implemented in JavaScript, for `Card.fromDevice`. */
+export interface SdBlockDevice {
+    readonly blockSize: number;
+    readonly numBlocks: number;
+    /** Fills `buffer`, a whole number of blocks, from block `start`. */
+    readBlocks(start: number, buffer: Uint8Array): void;
+    writeBlocks?(start: number, data: Uint8Array): void;
+    flush?(): void;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    /// A JavaScript object implementing `SdBlockDevice`.
+    #[wasm_bindgen(typescript_type = "SdBlockDevice")]
+    pub type JsBlockDevice;
+
+    #[wasm_bindgen(method, getter, js_name = blockSize)]
+    fn js_block_size(this: &JsBlockDevice) -> u32;
+
+    #[wasm_bindgen(method, getter, js_name = numBlocks)]
+    fn js_num_blocks(this: &JsBlockDevice) -> f64;
+
+    #[wasm_bindgen(method, catch, js_name = readBlocks)]
+    fn js_read_blocks(this: &JsBlockDevice, start: u32, buffer: &mut [u8]) -> Result<(), JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = writeBlocks)]
+    fn js_write_blocks(this: &JsBlockDevice, start: u32, data: &[u8]) -> Result<(), JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = flush)]
+    fn js_flush(this: &JsBlockDevice) -> Result<(), JsValue>;
+}
+
+/// A `JsBlockDevice` as a `BlockDevice`. Exceptions it throws become `IO`
+/// errors carrying their message; one without `writeBlocks` is read-only.
+pub struct JsDevice {
+    device: JsBlockDevice,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl JsDevice {
+    pub fn new(device: JsBlockDevice) -> Result<Self, SDError> {
+        let block_size = device.js_block_size() as usize;
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(SDError::InvalidBlockSize);
+        }
+        let num_blocks = device.js_num_blocks() as u64;
+        Ok(JsDevice {
+            device,
+            block_size,
+            num_blocks,
+        })
+    }
+
+    fn has_method(&self, name: &str) -> bool {
+        Reflect::get(&self.device, &JsValue::from_str(name))
+            .is_ok_and(|method| method.is_function())
+    }
+
+    fn check(&self, start: u32, len: usize) -> Result<(), SDError> {
+        if !len.is_multiple_of(self.block_size) {
+            return Err(SDError::InvalidBlockSize);
+        }
+        // Counted in blocks: a card's size in bytes overflows a wasm32 usize.
+        let blocks = (len / self.block_size).max(1) as u64;
+        if start as u64 + blocks > self.num_blocks {
+            return Err(SDError::BlockOutOfRange(start as u64 + blocks - 1));
+        }
+        Ok(())
+    }
+}
+
+fn js_error(error: JsValue) -> SDError {
+    let message = match error.dyn_ref::<js_sys::Error>() {
+        Some(error) => String::from(error.message()),
+        None => error
+            .as_string()
+            .unwrap_or_else(|| "the JavaScript device failed".to_string()),
+    };
+    SDError::IO(io::Error::other(message))
+}
+
+impl BlockDevice for JsDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        if buffer.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.read_blocks(block_index, buffer)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.check(start, buffer.len())?;
+        self.device.js_read_blocks(start, buffer).map_err(js_error)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if data.len() != self.block_size {
+            return Err(SDError::InvalidBlockSize);
+        }
+        self.write_blocks(block_index, data)
+    }
+
+    fn write_blocks(&mut self, start: u32, data: &[u8]) -> Result<(), SDError> {
+        if !self.has_method("writeBlocks") {
+            return Err(SDError::ReadOnly);
+        }
+        self.check(start, data.len())?;
+        self.device.js_write_blocks(start, data).map_err(js_error)
+    }
+
+    fn flush(&mut self) -> Result<(), SDError> {
+        if !self.has_method("flush") {
+            return Ok(());
+        }
+        self.device.js_flush().map_err(js_error)
+    }
+}
+
+fn to_js_error(error: SDError) -> JsError {
+    JsError::new(&error.to_string())
+}
+
+fn object(fields: &[(&str, JsValue)]) -> Object {
+    let object = Object::new();
+    for (key, value) in fields {
+        // Setting a property on a plain object cannot fail.
+        let _ = Reflect::set(&object, &JsValue::from_str(key), value);
+    }
+    object
+}
+
+fn entry_object(entry: &DirEntry) -> Object {
+    let modified = entry
+        .timestamps
+        .modified_at()
+        .map_or(JsValue::NULL, |time| {
+            Date::new(&JsValue::from_f64(time.unix_time() as f64 * 1000.0)).into()
+        });
+    object(&[
+        ("name", entry.full_name().into()),
+        ("size", (entry.size as f64).into()),
+        ("isDir", entry.is_dir().into()),
+        ("attributes", entry.attributes.bits().into()),
+        ("firstCluster", entry.first_cluster.into()),
+        ("modified", modified),
+    ])
+}
+
+/// A card or image opened in the browser. A volume is looked for when it
+/// opens: the first partition holding a filesystem, or the whole device.
+/// Without one, the partition table and raw blocks can still be read.
+#[wasm_bindgen]
+pub struct Card {
+    controller: SDController<Box<dyn BlockDevice>>,
+}
+
+impl Card {
+    fn open(device: Box<dyn BlockDevice>) -> Card {
+        let mut controller = SDController::from_device(device);
+        if controller.open_volume().is_err() {
+            controller.close_partition();
+        }
+        Card { controller }
+    }
+}
+
+#[wasm_bindgen]
+impl Card {
+    /// Opens an image held in `bytes`, with 512-byte blocks.
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: Uint8Array) -> Result<Card, JsError> {
+        let device = ArrayBufferDevice::new(bytes, 512).map_err(to_js_error)?;
+        Ok(Card::open(Box::new(device)))
+    }
+
+    #[wasm_bindgen(js_name = fromDevice)]
+    pub fn from_device(device: JsBlockDevice) -> Result<Card, JsError> {
+        let device = JsDevice::new(device).map_err(to_js_error)?;
+        Ok(Card::open(Box::new(device)))
+    }
+
+    #[wasm_bindgen(getter, js_name = blockSize)]
+    pub fn block_size(&self) -> usize {
+        self.controller.block_size()
+    }
+
+    /// Blocks on the whole device.
+    #[wasm_bindgen(getter, js_name = numBlocks)]
+    pub fn num_blocks(&self) -> f64 {
+        self.controller.device().num_blocks() as f64
+    }
+
+    /// Index of the open partition, or `undefined` for the whole device.
+    #[wasm_bindgen(getter)]
+    pub fn partition(&self) -> Option<usize> {
+        self.controller.partition()
+    }
+
+    /// The partitions in the partition table, as `{ index, type, startLba,
+    /// sectorCount, bootable, name }`.
+    pub fn partitions(&mut self) -> Result<Array, JsError> {
+        let table = self
+            .controller
+            .read_partition_table()
+            .map_err(to_js_error)?;
+        Ok(table
+            .partitions
+            .iter()
+            .enumerate()
+            .map(|(index, partition)| {
+                object(&[
+                    ("index", index.into()),
+                    ("type", partition.partition_type.to_string().into()),
+                    ("startLba", (partition.start_lba as f64).into()),
+                    ("sectorCount", (partition.sector_count as f64).into()),
+                    ("bootable", partition.bootable.into()),
+                    ("name", partition.name.clone().into()),
+                ])
+            })
+            .collect())
+    }
+
+    #[wasm_bindgen(js_name = openPartition)]
+    pub fn open_partition(&mut self, index: usize) -> Result<(), JsError> {
+        self.controller
+            .open_partition(index)
+            .map(drop)
+            .map_err(to_js_error)
+    }
+
+    /// The file or directory at `path`, as `{ name, size, isDir,
+    /// attributes, firstCluster, modified }`.
+    pub fn stat(&mut self, path: &str) -> Result<Object, JsError> {
+        let entry = self.controller.stat(path).map_err(to_js_error)?;
+        Ok(entry_object(&entry))
+    }
+
+    /// The entries of the directory at `path`, without `.`, `..` and the
+    /// volume label.
+    #[wasm_bindgen(js_name = listDir)]
+    pub fn list_dir(&mut self, path: &str) -> Result<Array, JsError> {
+        let dir = self.controller.stat(path).map_err(to_js_error)?;
+        let entries = self.controller.open_dir(&dir).map_err(to_js_error)?;
+        Ok(entries
+            .filter(|entry| !entry.is_volume_label())
+            .map(|entry| entry_object(&entry))
+            .collect())
+    }
+
+    /// Every file and directory below `path`, parents first, each with its
+    /// absolute `path` added.
+    pub fn walk(&mut self, path: &str) -> Result<Array, JsError> {
+        let walked = Array::new();
+        for item in self.controller.walk_from(path).map_err(to_js_error)? {
+            let item = item.map_err(to_js_error)?;
+            let entry = entry_object(&item.entry);
+            let _ = Reflect::set(&entry, &JsValue::from_str("path"), &item.path.into());
+            walked.push(&entry);
+        }
+        Ok(walked)
+    }
+
+    /// The whole contents of the file at `path`.
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, JsError> {
+        self.controller.open(path).map_err(to_js_error)
+    }
+
+    /// The block at absolute LBA `block`.
+    #[wasm_bindgen(js_name = readBlock)]
+    pub fn read_block(&mut self, block: u32) -> Result<Vec<u8>, JsError> {
+        self.controller
+            .read_device_block(block)
+            .map_err(to_js_error)
+    }
+}
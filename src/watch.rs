@@ -0,0 +1,330 @@
+//! Noticing cards as they are inserted and removed.
+//!
+//! Changes are found by comparing what `discover` lists before and after.
+//! On Linux the kernel's uevents, the ones udev listens to, say when to
+//! look again; on macOS the DiskArbitration events `diskutil activity`
+//! prints do. Elsewhere, and as a fallback, the disks are listed again
+//! every poll interval.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use crate::discover::{discover, DeviceInfo};
+use crate::error::SDError;
+
+/// How long to wait after a notification before listing the disks, so
+/// that the kernel has read the new card's size and partitions.
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// A card inserted or removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Inserted(DeviceInfo),
+    Removed(DeviceInfo),
+}
+
+impl DeviceEvent {
+    pub fn device(&self) -> &DeviceInfo {
+        match self {
+            DeviceEvent::Inserted(device) | DeviceEvent::Removed(device) => device,
+        }
+    }
+}
+
+/// Reports removable disks, or with `sd_only` only cards and card readers,
+/// as they come and go. A card reader counts as present while it holds a
+/// card. Fixed disks are never reported.
+pub struct DeviceWatcher {
+    present: BTreeMap<PathBuf, DeviceInfo>,
+    pending: VecDeque<DeviceEvent>,
+    notifier: platform::Notifier,
+    poll_interval: Duration,
+    sd_only: bool,
+}
+
+impl DeviceWatcher {
+    /// Starts watching. Disks already present are not reported as
+    /// inserted; `present` lists them.
+    pub fn new(sd_only: bool) -> Result<Self, SDError> {
+        let mut watcher = DeviceWatcher {
+            present: BTreeMap::new(),
+            pending: VecDeque::new(),
+            notifier: platform::Notifier::new(),
+            poll_interval: Duration::from_secs(2),
+            sd_only,
+        };
+        watcher.present = watcher.scan()?;
+        Ok(watcher)
+    }
+
+    /// Lists the disks again this often even without a notification.
+    /// Defaults to 2 seconds.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn present(&self) -> impl Iterator<Item = &DeviceInfo> {
+        self.present.values()
+    }
+
+    /// Waits for the next insertion or removal.
+    pub fn next_event(&mut self) -> Result<DeviceEvent, SDError> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            if self.notifier.wait(self.poll_interval) {
+                thread::sleep(SETTLE);
+                self.notifier.drain();
+            }
+            let now = self.scan()?;
+            for (path, device) in &self.present {
+                if now.get(path) != Some(device) {
+                    self.pending.push_back(DeviceEvent::Removed(device.clone()));
+                }
+            }
+            // A disk whose size or model changed is another card in the
+            // same reader, so it is removed above and inserted here.
+            for (path, device) in &now {
+                if self.present.get(path) != Some(device) {
+                    self.pending
+                        .push_back(DeviceEvent::Inserted(device.clone()));
+                }
+            }
+            self.present = now;
+        }
+    }
+
+    fn scan(&self) -> Result<BTreeMap<PathBuf, DeviceInfo>, SDError> {
+        Ok(discover()?
+            .into_iter()
+            .filter(|device| device.size > 0 && device.removable)
+            .filter(|device| !self.sd_only || device.sd_like)
+            .map(|device| (device.path.clone(), device))
+            .collect())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::os::raw::{c_ulong, c_void};
+    use std::time::{Duration, Instant};
+
+    const AF_NETLINK: i32 = 16;
+    const SOCK_DGRAM: i32 = 2;
+    const SOCK_CLOEXEC: i32 = 0o2000000;
+    const NETLINK_KOBJECT_UEVENT: i32 = 15;
+    /// The multicast group of uevents straight from the kernel.
+    const KERNEL_EVENTS: u32 = 1;
+    const POLLIN: i16 = 1;
+
+    #[repr(C)]
+    struct SockaddrNl {
+        family: u16,
+        pad: u16,
+        pid: u32,
+        groups: u32,
+    }
+
+    #[repr(C)]
+    struct PollFd {
+        fd: i32,
+        events: i16,
+        revents: i16,
+    }
+
+    extern "C" {
+        fn socket(domain: i32, kind: i32, protocol: i32) -> i32;
+        fn bind(fd: i32, address: *const c_void, length: u32) -> i32;
+        fn poll(fds: *mut PollFd, count: c_ulong, timeout: i32) -> i32;
+    }
+
+    /// A socket receiving the kernel's uevents, or `None` where none can be
+    /// opened, as in some containers.
+    pub struct Notifier {
+        socket: Option<File>,
+    }
+
+    impl Notifier {
+        pub fn new() -> Self {
+            Notifier {
+                socket: open_uevent_socket(),
+            }
+        }
+
+        /// Waits up to `timeout` for a uevent about a block device, and
+        /// returns whether one came.
+        pub fn wait(&mut self, timeout: Duration) -> bool {
+            let Some(socket) = &mut self.socket else {
+                std::thread::sleep(timeout);
+                return false;
+            };
+            let mut message = vec![0; 8192];
+            let deadline = Instant::now() + timeout;
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if !readable(socket, left.as_millis().min(i32::MAX as u128) as i32) {
+                    return false;
+                }
+                let Ok(length) = socket.read(&mut message) else {
+                    return false;
+                };
+                // "ACTION@DEVPATH", then NUL-separated KEY=value pairs.
+                if message[..length]
+                    .split(|&byte| byte == 0)
+                    .any(|field| field == b"SUBSYSTEM=block")
+                {
+                    return true;
+                }
+            }
+        }
+
+        pub fn drain(&mut self) {
+            if let Some(socket) = &mut self.socket {
+                let mut message = vec![0; 8192];
+                while readable(socket, 0) && socket.read(&mut message).is_ok() {}
+            }
+        }
+    }
+
+    fn readable(socket: &File, timeout_ms: i32) -> bool {
+        use std::os::fd::AsRawFd;
+        let mut fd = PollFd {
+            fd: socket.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `fd` is one valid pollfd for the duration of the call.
+        unsafe { poll(&mut fd, 1, timeout_ms) > 0 }
+    }
+
+    fn open_uevent_socket() -> Option<File> {
+        // SAFETY: plain system calls; the descriptor is owned from here on.
+        unsafe {
+            let fd = socket(
+                AF_NETLINK,
+                SOCK_DGRAM | SOCK_CLOEXEC,
+                NETLINK_KOBJECT_UEVENT,
+            );
+            if fd < 0 {
+                return None;
+            }
+            let socket = OwnedFd::from_raw_fd(fd);
+            let address = SockaddrNl {
+                family: AF_NETLINK as u16,
+                pad: 0,
+                pid: 0,
+                groups: KERNEL_EVENTS,
+            };
+            let length = std::mem::size_of::<SockaddrNl>() as u32;
+            if bind(fd, (&address as *const SockaddrNl).cast(), length) != 0 {
+                return None;
+            }
+            Some(File::from(socket))
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+    use std::thread;
+    use std::time::Duration;
+
+    /// `diskutil activity`, relaying DiskArbitration's events for as long
+    /// as it runs.
+    pub struct Notifier {
+        child: Option<Child>,
+        events: Option<Receiver<()>>,
+    }
+
+    impl Notifier {
+        pub fn new() -> Self {
+            let Ok(mut child) = Command::new("diskutil")
+                .arg("activity")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            else {
+                return Notifier {
+                    child: None,
+                    events: None,
+                };
+            };
+            let stdout = child.stdout.take().expect("piped");
+            let (sender, events) = mpsc::channel();
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                    // "***DiskAppeared ('disk4', ...)", "***DiskDisappeared ..."
+                    if line.starts_with("***Disk") && sender.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+            Notifier {
+                child: Some(child),
+                events: Some(events),
+            }
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> bool {
+            let Some(events) = &self.events else {
+                thread::sleep(timeout);
+                return false;
+            };
+            match events.recv_timeout(timeout) {
+                Ok(()) => true,
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.events = None;
+                    false
+                }
+            }
+        }
+
+        pub fn drain(&mut self) {
+            if let Some(events) = &self.events {
+                while events.try_recv().is_ok() {}
+            }
+        }
+    }
+
+    impl Drop for Notifier {
+        fn drop(&mut self) {
+            if let Some(child) = &mut self.child {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod platform {
+    use std::time::Duration;
+
+    /// Nothing to listen to: every wait times out and the disks are listed
+    /// again.
+    pub struct Notifier;
+
+    impl Notifier {
+        pub fn new() -> Self {
+            Notifier
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> bool {
+            std::thread::sleep(timeout);
+            false
+        }
+
+        pub fn drain(&mut self) {}
+    }
+}
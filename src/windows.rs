@@ -0,0 +1,192 @@
+use std::ffi::c_void;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+
+use crate::block::FileDevice;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::raw::{direct_open_error, RawOptions};
+
+const FILE_SHARE_READ: u32 = 0x0000_0001;
+const FILE_SHARE_WRITE: u32 = 0x0000_0002;
+const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+
+const IOCTL_DISK_GET_DRIVE_GEOMETRY_EX: u32 = 0x0007_00A0;
+const IOCTL_DISK_GET_LENGTH_INFO: u32 = 0x0007_405C;
+const IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS: u32 = 0x0056_0000;
+const FSCTL_LOCK_VOLUME: u32 = 0x0009_0018;
+const FSCTL_DISMOUNT_VOLUME: u32 = 0x0009_0020;
+
+/// Offset of `BytesPerSector` in `DISK_GEOMETRY_EX`.
+const GEOMETRY_BYTES_PER_SECTOR: usize = 20;
+/// Size of one `DISK_EXTENT` and the offset of the first one in
+/// `VOLUME_DISK_EXTENTS`.
+const DISK_EXTENT_SIZE: usize = 24;
+const DISK_EXTENTS_OFFSET: usize = 8;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn DeviceIoControl(
+        device: *mut c_void,
+        control_code: u32,
+        in_buffer: *const c_void,
+        in_buffer_size: u32,
+        out_buffer: *mut c_void,
+        out_buffer_size: u32,
+        bytes_returned: *mut u32,
+        overlapped: *mut c_void,
+    ) -> i32;
+}
+
+fn ioctl(file: &File, control_code: u32, out: &mut [u8]) -> io::Result<usize> {
+    let mut bytes_returned = 0u32;
+    // SAFETY: the handle is valid for the lifetime of `file`, and the output
+    // buffer pointer and length describe `out`.
+    let ok = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            control_code,
+            std::ptr::null(),
+            0,
+            out.as_mut_ptr() as *mut c_void,
+            out.len() as u32,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(bytes_returned as usize)
+}
+
+enum Target {
+    PhysicalDrive(u32),
+    Volume(char),
+}
+
+fn parse_target(path: &Path) -> Option<Target> {
+    let path = path.to_str()?;
+    let name = path.strip_prefix(r"\\.\")?;
+    let lower = name.to_ascii_lowercase();
+    if let Some(number) = lower.strip_prefix("physicaldrive") {
+        return number.parse().ok().map(Target::PhysicalDrive);
+    }
+    match name.as_bytes() {
+        [letter, b':'] if letter.is_ascii_alphabetic() => {
+            Some(Target::Volume(letter.to_ascii_uppercase() as char))
+        }
+        _ => None,
+    }
+}
+
+fn open_shared(path: &Path, write: bool, direct: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+        .custom_flags(if direct { FILE_FLAG_NO_BUFFERING } else { 0 })
+        .open(path)
+}
+
+fn device_len(file: &File) -> io::Result<u64> {
+    let mut length = [0u8; 8];
+    ioctl(file, IOCTL_DISK_GET_LENGTH_INFO, &mut length)?;
+    Ok(u64::from_le_bytes(length))
+}
+
+fn sector_size(file: &File) -> Option<usize> {
+    let mut geometry = [0u8; 64];
+    ioctl(file, IOCTL_DISK_GET_DRIVE_GEOMETRY_EX, &mut geometry).ok()?;
+    let offset = GEOMETRY_BYTES_PER_SECTOR;
+    let bytes = u32::from_le_bytes(geometry[offset..offset + 4].try_into().ok()?);
+    Some(bytes as usize).filter(|&bytes| bytes > 0)
+}
+
+fn lock_and_dismount(volume: &File, device: &Path, name: &str) -> Result<(), SDError> {
+    ioctl(volume, FSCTL_LOCK_VOLUME, &mut [])
+        .and_then(|_| ioctl(volume, FSCTL_DISMOUNT_VOLUME, &mut []))
+        .map_err(|_| SDError::DeviceBusy {
+            device: device.display().to_string(),
+            mounts: vec![name.to_string()],
+        })?;
+    Ok(())
+}
+
+/// Whether any extent of the volume lies on physical drive `disk`.
+fn volume_on_disk(volume: &File, disk: u32) -> bool {
+    let mut extents = [0u8; DISK_EXTENTS_OFFSET + 8 * DISK_EXTENT_SIZE];
+    if ioctl(volume, IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS, &mut extents).is_err() {
+        return false;
+    }
+    let count = u32::from_le_bytes(extents[0..4].try_into().unwrap()) as usize;
+    extents[DISK_EXTENTS_OFFSET..]
+        .chunks_exact(DISK_EXTENT_SIZE)
+        .take(count)
+        .any(|extent| u32::from_le_bytes(extent[0..4].try_into().unwrap()) == disk)
+}
+
+/// Locks and dismounts every lettered volume on physical drive `disk`.
+fn lock_disk_volumes(disk: u32, device: &Path) -> Result<Vec<File>, SDError> {
+    let mut locks = Vec::new();
+    for letter in 'A'..='Z' {
+        let name = format!("{}:", letter);
+        let Ok(volume) = open_shared(Path::new(&format!(r"\\.\{}", name)), true, false) else {
+            continue;
+        };
+        if volume_on_disk(&volume, disk) {
+            lock_and_dismount(&volume, device, &name)?;
+            locks.push(volume);
+        }
+    }
+    Ok(locks)
+}
+
+/// Opens `\\.\PhysicalDriveN` for whole disks, `\\.\X:` for single
+/// volumes, or an image file.
+///
+/// Windows does not report the size of a device through seeking and refuses
+/// reads that are not a multiple of the sector size. It also refuses writes
+/// to sectors that belong to a mounted volume, so volumes are locked and
+/// dismounted first; the locks are released when the device is dropped.
+pub(crate) fn open_device(path: &Path, options: RawOptions) -> Result<SDController, SDError> {
+    let target = parse_target(path);
+    let lock = options.writable || options.unmount;
+
+    // A volume can only be locked through a handle with write access, and
+    // locking fails while other handles are open, so that handle is ours.
+    let volume_locks = match target {
+        Some(Target::PhysicalDrive(disk)) if lock => lock_disk_volumes(disk, path)?,
+        _ => Vec::new(),
+    };
+    let write = options.writable || (lock && matches!(target, Some(Target::Volume(_))));
+    let file = open_shared(path, write, options.direct)
+        .map_err(|e| direct_open_error(path, e, options.direct))?;
+    if let (Some(Target::Volume(letter)), true) = (&target, lock) {
+        lock_and_dismount(&file, path, &format!("{}:", letter))?;
+    }
+
+    let len = match target {
+        Some(_) => {
+            if sector_size(&file).is_some_and(|sector| !options.block_size.is_multiple_of(sector)) {
+                return Err(SDError::InvalidBlockSize);
+            }
+            device_len(&file)?
+        }
+        None => file.metadata()?.len(),
+    };
+    let mut device = FileDevice::with_len(file, options.block_size, len, options.writable)?;
+    device.hold_volume_locks(volume_locks);
+    if options.direct {
+        device.use_direct_io();
+    }
+
+    let mut controller = SDController::from_device(device);
+    if options.writable {
+        controller.enable_writes();
+    }
+    Ok(controller)
+}
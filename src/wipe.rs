@@ -0,0 +1,210 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::ops::Range;
+
+use crate::block::BlockDevice;
+use crate::capacity::splitmix64;
+use crate::device::SDController;
+use crate::error::SDError;
+use crate::partition::block_index;
+use crate::progress::{Phase, Progress, ProgressSink, Stopwatch};
+
+/// Bytes written per request.
+const CHUNK_BYTES: u64 = 1 << 20;
+
+/// What one pass of a wipe writes over every block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WipePass {
+    Zeros,
+    /// 0xFF, what an erased flash block reads as.
+    Ones,
+    Byte(u8),
+    /// Pseudorandom data from `WipeOptions::seed`.
+    Random,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WipeOptions {
+    /// Written one after the other, each over the whole range.
+    pub passes: Vec<WipePass>,
+    /// Seeds the `Random` passes. The default is drawn afresh each time,
+    /// so that the data cannot be told from what a card might hold.
+    pub seed: u64,
+    /// Read the range back after the last pass and check that it holds
+    /// what was written.
+    pub verify: bool,
+}
+
+impl WipeOptions {
+    /// A single pass of zeros.
+    pub fn zeros() -> Self {
+        WipeOptions::with_passes(&[WipePass::Zeros])
+    }
+
+    /// Zeros, then ones, then random data, as DoD 5220.22-M describes.
+    pub fn three_pass() -> Self {
+        WipeOptions::with_passes(&[WipePass::Zeros, WipePass::Ones, WipePass::Random])
+    }
+
+    pub fn with_passes(passes: &[WipePass]) -> Self {
+        WipeOptions {
+            passes: passes.to_vec(),
+            seed: RandomState::new().hash_one(0u64),
+            verify: false,
+        }
+    }
+}
+
+impl Default for WipeOptions {
+    fn default() -> Self {
+        WipeOptions::zeros()
+    }
+}
+
+/// Fills `buffer`, which starts at `block`, with what `pass` writes there.
+/// Random data is derived from the block number, so a verify pass can
+/// regenerate it a chunk at a time.
+fn fill_pass(buffer: &mut [u8], block_size: usize, block: u64, pass: WipePass, seed: u64) {
+    match pass {
+        WipePass::Zeros => buffer.fill(0),
+        WipePass::Ones => buffer.fill(0xFF),
+        WipePass::Byte(byte) => buffer.fill(byte),
+        WipePass::Random => {
+            for (index, data) in buffer.chunks_mut(block_size).enumerate() {
+                let mut state = seed ^ (block + index as u64).wrapping_mul(0xD6E8_FEB8_6659_FD93);
+                for word in data.chunks_mut(8) {
+                    let value = splitmix64(&mut state).to_le_bytes();
+                    word.copy_from_slice(&value[..word.len()]);
+                }
+            }
+        }
+    }
+}
+
+impl<D: BlockDevice> SDController<D> {
+    /// Overwrites the blocks in `range`, relative to the open partition,
+    /// with each of `options.passes` in turn. Writes must be enabled.
+    ///
+    /// A card remaps worn blocks behind the host's back, so data that was
+    /// in a retired block is out of reach of any overwrite; only the card's
+    /// own erase commands clear those.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(start = range.start, end = range.end))
+    )]
+    pub fn wipe<P: ProgressSink>(
+        &mut self,
+        range: Range<u64>,
+        options: &WipeOptions,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        if range.end > self.num_blocks() {
+            return Err(SDError::BlockOutOfRange(range.end));
+        }
+        self.wipe_ranges(std::slice::from_ref(&range), options, progress)
+    }
+
+    /// Wipes the clusters the volume does not use, leaving its files and
+    /// directories as they are. The directory entries of deleted files keep
+    /// their names; only the data they pointed to is overwritten. Returns
+    /// the number of blocks wiped.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn wipe_free_space<P: ProgressSink>(
+        &mut self,
+        options: &WipeOptions,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        let free = self.free_ranges()?;
+        self.wipe_ranges(&free, options, progress)
+    }
+
+    /// Discards the clusters the volume does not use, so that the card can
+    /// erase them in the background and write them faster later. Worth
+    /// doing after deleting files or wiping free space. Fails with
+    /// `Unsupported` on devices without discard, such as card readers that
+    /// do not pass it on; on Linux, an image file has holes punched into it
+    /// instead. Returns the number of blocks discarded.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn discard_free_space(&mut self) -> Result<u64, SDError> {
+        let free = self.free_ranges()?;
+        let mut discarded = 0;
+        for range in free {
+            let mut block = range.start;
+            while block < range.end {
+                let count = (range.end - block).min(u32::MAX as u64) as u32;
+                self.discard_blocks(block_index(block)?, count)?;
+                block += count as u64;
+            }
+            discarded += range.end - range.start;
+        }
+        Ok(discarded)
+    }
+
+    /// The runs of free clusters of the volume, as blocks.
+    fn free_ranges(&mut self) -> Result<Vec<Range<u64>>, SDError> {
+        let layout = self.layout()?;
+        let data_end = layout.cluster_to_sector(layout.cluster_count + 2) as u64;
+        let mut free = Vec::new();
+        let mut next = layout.data_start as u64;
+        for used in self.used_ranges()? {
+            if used.start > next {
+                free.push(next..used.start);
+            }
+            next = next.max(used.end);
+        }
+        if next < data_end {
+            free.push(next..data_end);
+        }
+        Ok(free)
+    }
+
+    fn wipe_ranges<P: ProgressSink>(
+        &mut self,
+        ranges: &[Range<u64>],
+        options: &WipeOptions,
+        progress: &mut P,
+    ) -> Result<u64, SDError> {
+        if !self.is_writable() {
+            return Err(SDError::ReadOnly);
+        }
+        let block_size = self.block_size();
+        let chunk_blocks = (CHUNK_BYTES / block_size as u64).max(1);
+        let blocks: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+        let total = blocks * block_size as u64 * options.passes.len() as u64;
+        let stopwatch = Stopwatch::start();
+        let mut buffer = vec![0u8; chunk_blocks as usize * block_size];
+        let mut done = 0;
+        for &pass in &options.passes {
+            for range in ranges {
+                let mut block = range.start;
+                while block < range.end {
+                    let count = chunk_blocks.min(range.end - block);
+                    let chunk = &mut buffer[..count as usize * block_size];
+                    fill_pass(chunk, block_size, block, pass, options.seed);
+                    self.write_blocks(block_index(block)?, chunk)?;
+                    block += count;
+                    done += chunk.len() as u64;
+                    progress.report(&Progress {
+                        phase: Phase::Writing,
+                        bytes_done: done,
+                        bytes_total: total,
+                        elapsed: stopwatch.elapsed(),
+                    });
+                }
+            }
+        }
+        self.flush()?;
+
+        if let (true, Some(&last)) = (options.verify, options.passes.last()) {
+            self.copy_ranges(ranges, Phase::Verifying, progress, |start, data| {
+                let expected = &mut buffer[..data.len()];
+                fill_pass(expected, block_size, start, last, options.seed);
+                match data.iter().zip(expected.iter()).position(|(a, b)| a != b) {
+                    Some(at) => Err(SDError::WipeFailed(start * block_size as u64 + at as u64)),
+                    None => Ok(()),
+                }
+            })?;
+        }
+        Ok(blocks)
+    }
+}
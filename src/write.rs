@@ -0,0 +1,351 @@
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::Read;
+
+use crate::attributes::Attributes;
+use crate::block::BlockDevice;
+use crate::device::SDController;
+use crate::dir::{
+    encode_short_name, generate_short_name, needs_long_name, set_first_cluster, split_path,
+    validate_name, DirEntry, DirIter, DirLocation, FatTimestamps, DIR_ENTRY_SIZE, ENTRY_DELETED,
+};
+use crate::error::SDError;
+use crate::fat::FatVariant;
+use crate::layout::FATLayout;
+
+impl<D: BlockDevice> SDController<D> {
+    /// Creates a file at `path` holding `data`. The parent directory must
+    /// exist. Names that do not fit 8.3 get a long name and a generated
+    /// short name. Names Windows would refuse, such as `CON` or ones with a
+    /// trailing dot, are rejected. Clusters are allocated first-fit and
+    /// recorded in every FAT copy.
+    pub fn create_file(&mut self, path: &str, data: &[u8]) -> Result<DirEntry, SDError> {
+        let mut rest = data;
+        self.write_new_file(path, data.len() as u64, FatTimestamps::now(), |buffer| {
+            let (head, tail) = rest.split_at(buffer.len());
+            buffer.copy_from_slice(head);
+            rest = tail;
+            Ok(())
+        })
+    }
+
+    /// Like `create_file`, streaming the `len` bytes of the file from
+    /// `reader` one cluster at a time.
+    #[cfg(feature = "std")]
+    pub fn create_file_from<R: Read>(
+        &mut self,
+        path: &str,
+        reader: &mut R,
+        len: u64,
+    ) -> Result<DirEntry, SDError> {
+        self.write_new_file(path, len, FatTimestamps::now(), |buffer| {
+            Ok(reader.read_exact(buffer)?)
+        })
+    }
+
+    /// Creates a file of `len` bytes, calling `fill` to get each cluster's
+    /// worth of its contents in turn.
+    pub(crate) fn write_new_file<F>(
+        &mut self,
+        path: &str,
+        len: u64,
+        timestamps: FatTimestamps,
+        mut fill: F,
+    ) -> Result<DirEntry, SDError>
+    where
+        F: FnMut(&mut [u8]) -> Result<(), SDError>,
+    {
+        let layout = self.writable_layout()?;
+        if len > u32::MAX as u64 {
+            return Err(SDError::FileTooLarge(len));
+        }
+
+        let (parent, mut entry) = self.new_entry(&layout, path, Attributes::ARCHIVE, len)?;
+        entry.timestamps = timestamps;
+        entry.first_cluster = self.write_chain(&layout, len, &mut fill)?;
+        self.insert_dir_entry(&layout, parent, &entry)?;
+        Ok(entry)
+    }
+
+    /// Replaces the contents of the file at `path` with `len` bytes from
+    /// `fill`, keeping its entry and names. The new clusters are written
+    /// and claimed before the entry is pointed at them and the old ones
+    /// are freed, so a source that fails part way, or a volume without
+    /// room for both copies, leaves the file as it was.
+    #[cfg(feature = "std")]
+    pub(crate) fn replace_file<F>(
+        &mut self,
+        path: &str,
+        len: u64,
+        timestamps: FatTimestamps,
+        mut fill: F,
+    ) -> Result<(), SDError>
+    where
+        F: FnMut(&mut [u8]) -> Result<(), SDError>,
+    {
+        let layout = self.writable_layout()?;
+        let size = u32::try_from(len).map_err(|_| SDError::FileTooLarge(len))?;
+        let (_, found) = self.locate(&layout, path)?;
+        if found.entry.is_dir() {
+            return Err(SDError::IsADirectory(path.to_string()));
+        }
+        let first_cluster = self.write_chain(&layout, len, &mut fill)?;
+
+        let block = self.read_block(found.sector)?;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw.copy_from_slice(&block[found.offset..found.offset + DIR_ENTRY_SIZE]);
+        raw[11] |= Attributes::ARCHIVE.bits();
+        raw[13] = timestamps.created_tenths;
+        raw[14..16].copy_from_slice(&timestamps.created_time.to_le_bytes());
+        raw[16..18].copy_from_slice(&timestamps.created_date.to_le_bytes());
+        raw[18..20].copy_from_slice(&timestamps.accessed_date.to_le_bytes());
+        set_first_cluster(&mut raw, first_cluster);
+        raw[22..24].copy_from_slice(&timestamps.modified_time.to_le_bytes());
+        raw[24..26].copy_from_slice(&timestamps.modified_date.to_le_bytes());
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        self.write_entry_at(found.sector, found.offset, &raw)?;
+
+        if found.entry.first_cluster != 0 {
+            let mut table = self.load_fat(&layout)?;
+            table.free_chain(found.entry.first_cluster)?;
+            self.store_fat(&layout, &mut table)?;
+        }
+        Ok(())
+    }
+
+    /// Allocates and fills the clusters of a `len`-byte file, returning the
+    /// first of them, or 0 for an empty file. The clusters are only claimed
+    /// once the FAT is stored, so a source that fails part way, like a
+    /// volume without room, leaves nothing behind.
+    fn write_chain<F>(&mut self, layout: &FATLayout, len: u64, fill: &mut F) -> Result<u32, SDError>
+    where
+        F: FnMut(&mut [u8]) -> Result<(), SDError>,
+    {
+        let cluster_size = layout.cluster_size();
+        let mut table = self.load_fat(layout)?;
+        let clusters =
+            self.allocate_chain(&mut table, len.div_ceil(cluster_size as u64) as usize)?;
+        let mut buffer = vec![0u8; cluster_size];
+        let mut remaining = len;
+        for &cluster in &clusters {
+            let wanted = remaining.min(cluster_size as u64) as usize;
+            fill(&mut buffer[..wanted])?;
+            buffer[wanted..].fill(0);
+            self.write_clusters_at(layout, cluster, &buffer)?;
+            remaining -= wanted as u64;
+        }
+        self.store_fat(layout, &mut table)?;
+        Ok(clusters.first().copied().unwrap_or(0))
+    }
+
+    /// Creates an empty directory at `path`, holding only its `.` and `..`
+    /// entries. The parent directory must exist.
+    pub fn create_dir(&mut self, path: &str) -> Result<DirEntry, SDError> {
+        self.make_dir(path, FatTimestamps::now())
+    }
+
+    pub(crate) fn make_dir(
+        &mut self,
+        path: &str,
+        timestamps: FatTimestamps,
+    ) -> Result<DirEntry, SDError> {
+        let layout = self.writable_layout()?;
+        let (parent, mut entry) = self.new_entry(&layout, path, Attributes::DIRECTORY, 0)?;
+        entry.timestamps = timestamps;
+
+        let mut table = self.load_fat(&layout)?;
+        let cluster = self.allocate_chain(&mut table, 1)?[0];
+        entry.first_cluster = cluster;
+        let parent_cluster = match parent {
+            DirLocation::Root => 0,
+            DirLocation::Cluster(parent_cluster) => parent_cluster,
+        };
+
+        self.zero_cluster(&layout, cluster)?;
+        let mut block = vec![0u8; layout.bytes_per_sector as usize];
+        let links = [(b".          ", cluster), (b"..         ", parent_cluster)];
+        for (slot, (name, target)) in block.chunks_mut(DIR_ENTRY_SIZE).zip(links) {
+            let mut raw = entry.to_bytes()?;
+            raw[0..11].copy_from_slice(name);
+            set_first_cluster(&mut raw, target);
+            slot.copy_from_slice(&raw);
+        }
+        self.write_block(layout.cluster_to_sector(cluster), &block)?;
+
+        self.store_fat(&layout, &mut table)?;
+        self.insert_dir_entry(&layout, parent, &entry)?;
+        Ok(entry)
+    }
+
+    /// Deletes a file: its directory entry is marked free (0xE5) and its
+    /// cluster chain is released in every FAT copy.
+    pub fn delete_file(&mut self, path: &str) -> Result<(), SDError> {
+        let layout = self.writable_layout()?;
+        let (_, found) = self.locate(&layout, path)?;
+        if found.entry.is_dir() {
+            return Err(SDError::IsADirectory(path.to_string()));
+        }
+
+        // Drop the entry before the clusters, so an interruption leaves lost
+        // clusters rather than an entry pointing at free space.
+        self.mark_deleted(&found)?;
+        if found.entry.first_cluster != 0 {
+            let mut table = self.load_fat(&layout)?;
+            table.free_chain(found.entry.first_cluster)?;
+            self.store_fat(&layout, &mut table)?;
+        }
+        Ok(())
+    }
+
+    /// Renames a file or directory. `new_path` may name a different parent
+    /// directory, in which case the entry is moved there.
+    pub fn rename_file(&mut self, old_path: &str, new_path: &str) -> Result<DirEntry, SDError> {
+        let layout = self.writable_layout()?;
+        let (old_parent, found) = self.locate(&layout, old_path)?;
+
+        let components = split_path(new_path);
+        let (new_name, new_parents) = components
+            .split_last()
+            .ok_or(SDError::InvalidName(new_path.to_string()))?;
+        validate_name(new_name)?;
+        let (name_field, ext_field) = encode_short_name(new_name)?;
+        let new_parent = self.resolve_dir(&layout, new_parents)?;
+
+        if let Some(existing) = self.find_entry(&layout, new_parent, new_name)? {
+            let same_slot = existing.sector == found.sector && existing.offset == found.offset;
+            if !same_slot {
+                return Err(SDError::AlreadyExists(new_path.to_string()));
+            }
+        }
+        if found.entry.is_dir() && self.is_within(&layout, new_parent, found.entry.first_cluster)? {
+            return Err(SDError::InvalidMove(new_path.to_string()));
+        }
+
+        let block = self.read_block(found.sector)?;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw.copy_from_slice(&block[found.offset..found.offset + DIR_ENTRY_SIZE]);
+        raw[0..8].copy_from_slice(&name_field);
+        raw[8..11].copy_from_slice(&ext_field);
+
+        // The new name is a plain 8.3 name, so any long name is dropped.
+        if new_parent == old_parent {
+            for &(sector, offset) in &found.long_name_slots {
+                self.write_entry_at(sector, offset, &[ENTRY_DELETED])?;
+            }
+            self.write_entry_at(found.sector, found.offset, &raw)?;
+        } else {
+            self.insert_entry(&layout, new_parent, &raw)?;
+            self.mark_deleted(&found)?;
+            if found.entry.is_dir() {
+                self.set_parent_link(&layout, found.entry.first_cluster, new_parent)?;
+            }
+        }
+        Ok(DirEntry::decode(&raw, self.codepage()))
+    }
+
+    /// Resolves the parent of a new entry at `path` and builds the entry,
+    /// with no clusters yet. Fails if the name is taken. A name that does
+    /// not fit 8.3 becomes the long name, with a short name generated to
+    /// go with it.
+    fn new_entry(
+        &mut self,
+        layout: &FATLayout,
+        path: &str,
+        attributes: Attributes,
+        size: u64,
+    ) -> Result<(DirLocation, DirEntry), SDError> {
+        let components = split_path(path);
+        let (name, parents) = components
+            .split_last()
+            .ok_or(SDError::InvalidName(path.to_string()))?;
+        validate_name(name)?;
+        let parent = self.resolve_dir(layout, parents)?;
+        if self.find_entry(layout, parent, name)?.is_some() {
+            return Err(SDError::AlreadyExists(path.to_string()));
+        }
+
+        let (short_name, long_name) = if needs_long_name(name) {
+            let siblings: Vec<DirEntry> = DirIter::new(self.read_dir_region(layout, parent)?.1)
+                .with_codepage(self.codepage())
+                .collect();
+            let short_name = generate_short_name(name, |candidate| {
+                siblings
+                    .iter()
+                    .any(|sibling| sibling.matches_name(candidate))
+            })?;
+            (short_name, Some(name.to_string()))
+        } else {
+            (name.to_ascii_uppercase(), None)
+        };
+        let (base, ext) = short_name.rsplit_once('.').unwrap_or((&short_name, ""));
+        let entry = DirEntry {
+            name: base.to_string(),
+            ext: ext.to_string(),
+            long_name,
+            attributes,
+            size,
+            first_cluster: 0,
+            contiguous: false,
+            timestamps: FatTimestamps::now(),
+        };
+        // Validate the name before touching the FAT.
+        entry.to_bytes()?;
+        Ok((parent, entry))
+    }
+
+    pub(crate) fn writable_layout(&mut self) -> Result<FATLayout, SDError> {
+        let layout = self.layout()?;
+        if layout.variant == FatVariant::ExFat {
+            return Err(SDError::UnsupportedFilesystem);
+        }
+        Ok(layout)
+    }
+
+    /// Whether `location` is the directory starting at `cluster` or one of
+    /// its descendants, found by following `..` links up to the root.
+    fn is_within(
+        &mut self,
+        layout: &FATLayout,
+        mut location: DirLocation,
+        cluster: u32,
+    ) -> Result<bool, SDError> {
+        for _ in 0..layout.cluster_count {
+            match location {
+                DirLocation::Root => return Ok(false),
+                DirLocation::Cluster(current) if current == cluster => return Ok(true),
+                DirLocation::Cluster(_) => {
+                    location = match self.find_entry(layout, location, "..")? {
+                        Some(parent) => {
+                            self.normalize_location(layout, DirLocation::of(&parent.entry))
+                        }
+                        None => return Ok(false),
+                    };
+                }
+            }
+        }
+        Err(SDError::InvalidCluster(cluster))
+    }
+
+    /// Points the `..` entry of the directory at `cluster` to `parent`.
+    fn set_parent_link(
+        &mut self,
+        layout: &FATLayout,
+        cluster: u32,
+        parent: DirLocation,
+    ) -> Result<(), SDError> {
+        let Some(link) = self.find_entry(layout, DirLocation::Cluster(cluster), "..")? else {
+            return Ok(());
+        };
+        let parent_cluster = match parent {
+            DirLocation::Root => 0,
+            DirLocation::Cluster(parent_cluster) => parent_cluster,
+        };
+        let block = self.read_block(link.sector)?;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw.copy_from_slice(&block[link.offset..link.offset + DIR_ENTRY_SIZE]);
+        set_first_cluster(&mut raw, parent_cluster);
+        self.write_entry_at(link.sector, link.offset, &raw)
+    }
+}
@@ -0,0 +1,312 @@
+#![cfg(feature = "std")]
+
+use std::io::Read;
+use std::time::Duration;
+
+use sd_controller::error::{ErrorContext, Operation};
+use sd_controller::testing::{FatImageBuilder, Fault, FaultyDevice};
+use sd_controller::{
+    BlockDevice, BootSectorCopy, MemBlockDevice, SDController, SDError, ScanOptions,
+};
+
+fn faulty_sample() -> (FaultyDevice<MemBlockDevice>, u64) {
+    let data = vec![0x42; 8192];
+    let mut controller = FatImageBuilder::fat16()
+        .file("/DATA.BIN", &data)
+        .build_controller()
+        .unwrap();
+    let entry = controller.stat("/DATA.BIN").unwrap();
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let layout = controller.calculate_layout(&boot_sector);
+    let first_block = layout.data_start as u64
+        + (entry.first_cluster as u64 - 2) * layout.sectors_per_cluster as u64;
+    (FaultyDevice::new(controller.into_inner()), first_block)
+}
+
+#[test]
+fn mem_device_checks_sizes_and_ranges() {
+    assert!(matches!(
+        MemBlockDevice::from_vec(vec![0; 1000], 512),
+        Err(SDError::InvalidBlockSize)
+    ));
+    let mut device = MemBlockDevice::new(512, 4).unwrap();
+    assert_eq!(device.num_blocks(), 4);
+    device.write_block(3, &[7; 512]).unwrap();
+    let mut buffer = [0; 1024];
+    device.read_blocks(2, &mut buffer).unwrap();
+    assert!(buffer[..512].iter().all(|&b| b == 0));
+    assert!(buffer[512..].iter().all(|&b| b == 7));
+    assert!(matches!(
+        device.read_blocks(3, &mut buffer),
+        Err(SDError::BlockOutOfRange(4))
+    ));
+    assert!(matches!(
+        device.write_block(0, &[0; 100]),
+        Err(SDError::InvalidBlockSize)
+    ));
+}
+
+#[test]
+fn io_errors_reach_file_reads() {
+    let (device, block) = faulty_sample();
+    let mut controller = SDController::from_device(device.with_fault(block + 1, Fault::IoError));
+    let error = controller.open("/DATA.BIN").unwrap_err();
+    assert!(matches!(error.root_cause(), SDError::IO(_)));
+    assert_eq!(error.path(), Some("/DATA.BIN"));
+
+    let mut device = controller.into_inner();
+    device.clear_faults();
+    let mut controller = SDController::from_device(device);
+    assert_eq!(controller.open("/DATA.BIN").unwrap(), vec![0x42; 8192]);
+}
+
+#[test]
+fn short_reads_are_reported() {
+    let (device, block) = faulty_sample();
+    let mut device = device.with_fault(block, Fault::ShortRead(100));
+    let mut buffer = [0; 1024];
+    assert!(matches!(
+        device.read_blocks(block as u32 - 1, &mut buffer),
+        Err(SDError::ReadError {
+            expected: 1024,
+            actual: 612
+        })
+    ));
+    let mut controller = SDController::from_device(device);
+    assert!(controller.open("/DATA.BIN").is_err());
+}
+
+#[test]
+fn scan_finds_injected_bad_blocks() {
+    let (device, _) = faulty_sample();
+    let device = device
+        .with_fault(5000, Fault::IoError)
+        .with_fault(9000, Fault::ShortRead(0))
+        // One failure is taken by the large request covering the block.
+        .with_fault(7000, Fault::Transient(2));
+    let mut controller = SDController::from_device(device);
+    let options = ScanOptions {
+        retry_delay: Duration::ZERO,
+        ..ScanOptions::default()
+    };
+    let report = controller
+        .scan_bad_blocks(0..controller.num_blocks(), &options, &mut ())
+        .unwrap();
+    assert!(!report.is_clean());
+    assert_eq!(report.unreadable, [5000, 9000]);
+    assert_eq!(report.recovered.len(), 1);
+    assert_eq!(report.recovered[0].block, 7000);
+    assert_eq!(report.scanned_blocks, controller.num_blocks());
+}
+
+#[test]
+fn unreadable_blocks_are_traced_to_their_files() {
+    use sd_controller::{BlockOwner, ClusterState};
+
+    let (device, first_block) = faulty_sample();
+    let device = device.with_fault(first_block + 5, Fault::IoError);
+    let mut controller = SDController::from_device(device);
+    let options = ScanOptions {
+        retry_delay: Duration::ZERO,
+        ..ScanOptions::default()
+    };
+    let report = controller
+        .scan_bad_blocks(0..controller.num_blocks(), &options, &mut ())
+        .unwrap();
+    assert_eq!(report.unreadable, [first_block + 5]);
+
+    let owners = controller.block_owners().unwrap();
+    assert_eq!(
+        owners.owner(report.unreadable[0]).unwrap(),
+        BlockOwner::Entry {
+            path: "/DATA.BIN".to_string(),
+            directory: false,
+            size: 8192,
+            cluster: 3,
+            offset: 5 * 512,
+        }
+    );
+    assert_eq!(owners.owner(0).unwrap(), BlockOwner::Reserved);
+    assert_eq!(
+        owners.owner(first_block - 1).unwrap(),
+        BlockOwner::RootDirectory
+    );
+    assert_eq!(
+        controller.owner_of(first_block + 16).unwrap(),
+        BlockOwner::Unowned {
+            cluster: 6,
+            state: ClusterState::Free
+        }
+    );
+    assert!(owners.owner(controller.num_blocks()).is_err());
+}
+
+#[test]
+fn scan_can_stop_at_first_error() {
+    let (device, _) = faulty_sample();
+    let mut controller = SDController::from_device(device.with_fault(300, Fault::IoError));
+    let options = ScanOptions {
+        retries: 0,
+        stop_on_error: true,
+        ..ScanOptions::default()
+    };
+    assert!(controller
+        .scan_bad_blocks(0..controller.num_blocks(), &options, &mut ())
+        .is_err());
+}
+
+#[test]
+fn latency_slows_reads_without_failing_them() {
+    let (device, _) = faulty_sample();
+    let mut device = device.with_fault(10, Fault::Latency(Duration::from_millis(30)));
+    let mut buffer = [0; 512];
+    let started = std::time::Instant::now();
+    device.read_block(10, &mut buffer).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(30));
+}
+
+#[test]
+fn transient_faults_clear_after_retries() {
+    let (device, block) = faulty_sample();
+    let mut controller = SDController::from_device(device.with_fault(block, Fault::Transient(2)));
+    assert!(controller.open("/DATA.BIN").is_err());
+    assert!(controller.open("/DATA.BIN").is_err());
+    assert_eq!(controller.open("/DATA.BIN").unwrap(), vec![0x42; 8192]);
+}
+
+#[test]
+fn errors_say_where_they_happened() {
+    let (device, block) = faulty_sample();
+    let mut controller = SDController::from_device(device.with_fault(block + 2, Fault::IoError));
+    let mut reader = controller.open_reader("/DATA.BIN").unwrap();
+    // One read covers the first cluster, and fails as a whole.
+    let error = SDError::from(reader.read(&mut [0; 4096]).unwrap_err());
+    assert!(matches!(error.root_cause(), SDError::IO(_)));
+    assert_eq!(
+        error.context(),
+        Some(&ErrorContext {
+            operation: Some(Operation::Read),
+            block: Some(block),
+            partition: None,
+            path: Some("/DATA.BIN".to_string()),
+            offset: Some(0),
+        })
+    );
+    assert!(error.to_string().starts_with(&format!(
+        "While reading block {block}, at byte 0 of /DATA.BIN: IO error"
+    )));
+}
+
+#[test]
+fn unreadable_block_zero_falls_back_to_the_backup_boot_sector() {
+    let device = FatImageBuilder::fat32()
+        .file("/DATA.BIN", b"still here")
+        .build()
+        .unwrap();
+    let device = FaultyDevice::new(device).with_fault(0, Fault::IoError);
+    let mut controller = SDController::from_device(device);
+    assert_eq!(controller.open("/DATA.BIN").unwrap(), b"still here");
+    assert_eq!(controller.boot_sector_copy(), BootSectorCopy::Backup);
+}
+
+#[test]
+fn parse_errors_name_the_field() {
+    let mut device = FatImageBuilder::fat16().build().unwrap();
+    device.as_bytes_mut()[13] = 3;
+    let mut controller = SDController::from_device(device);
+    let error = controller.read_boot_sector().unwrap_err();
+    assert!(matches!(
+        error,
+        SDError::Parse {
+            block: 0,
+            offset: 13,
+            field: "sectors per cluster",
+            ..
+        }
+    ));
+    assert!(controller.open("/ANY.TXT").is_err());
+}
+
+/// Takes a set number of writes, then fails the rest, like a card pulled
+/// out part way through.
+#[derive(Clone)]
+struct Unplugged {
+    inner: MemBlockDevice,
+    writes_left: usize,
+}
+
+impl BlockDevice for Unplugged {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.inner.read_block(block_index, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        if self.writes_left == 0 {
+            return Err(SDError::DeviceNotFound);
+        }
+        self.writes_left -= 1;
+        self.inner.write_block(block_index, data)
+    }
+}
+
+#[test]
+fn journaled_writes_cut_short_are_finished_or_undone() {
+    use sd_controller::{JournaledDevice, Recovery, RecoveryPolicy};
+
+    let image = FatImageBuilder::fat16()
+        .file("/OLD.TXT", b"old")
+        .build()
+        .unwrap();
+    let original = image.as_bytes().to_vec();
+    let journal = std::env::temp_dir().join(format!("sd-journal-{}", std::process::id()));
+    let _ = std::fs::remove_file(&journal);
+
+    let device = Unplugged {
+        inner: image,
+        writes_left: 1,
+    };
+    let mut controller = SDController::from_device(
+        JournaledDevice::open(device, &journal, RecoveryPolicy::Replay).unwrap(),
+    );
+    controller.enable_writes();
+    let data = vec![0x5A; 5000];
+    controller.create_file("/NEW.BIN", &data).unwrap();
+    assert!(controller.device().pending_blocks() > 1);
+    assert!(controller.flush().is_err());
+    let torn = controller.device().inner().inner.clone();
+    drop(controller);
+    assert!(!SDController::from_device(torn.clone())
+        .check()
+        .unwrap()
+        .is_empty());
+    let saved = std::fs::read(&journal).unwrap();
+
+    let undone = JournaledDevice::open(torn.clone(), &journal, RecoveryPolicy::RollBack).unwrap();
+    assert!(matches!(
+        undone.recovery(),
+        Some(Recovery::RolledBack { .. })
+    ));
+    assert_eq!(undone.inner().as_bytes(), original);
+    assert!(!journal.exists());
+    drop(undone);
+
+    std::fs::write(&journal, saved).unwrap();
+    let mut finished = SDController::from_device(
+        JournaledDevice::open(torn, &journal, RecoveryPolicy::Replay).unwrap(),
+    );
+    assert!(matches!(
+        finished.device().recovery(),
+        Some(Recovery::Replayed { .. })
+    ));
+    assert_eq!(finished.open("/NEW.BIN").unwrap(), data);
+    assert_eq!(finished.open("/OLD.TXT").unwrap(), b"old");
+    assert!(finished.check().unwrap().is_empty());
+}
@@ -0,0 +1,288 @@
+#![cfg(feature = "std")]
+
+use sd_controller::testing::FatImageBuilder;
+use sd_controller::{
+    BootSectorCopy, FatVariant, FileDevice, FormatOptions, FsIssue, MemBlockDevice, NewPartition,
+    PartitionType, RepairAction, RepairOptions, SDController, SDError,
+};
+
+#[test]
+fn formatted_volumes_are_empty_and_clean() {
+    for builder in [FatImageBuilder::fat16(), FatImageBuilder::fat32()] {
+        let variant = builder.variant();
+        let mut controller = builder.build_controller().unwrap();
+        let usage = controller.usage().unwrap();
+        assert_eq!(usage.variant, variant);
+        assert_eq!(
+            usage.free_clusters,
+            usage.total_clusters - (variant == FatVariant::Fat32) as u32
+        );
+        assert!(controller.check().unwrap().is_empty());
+        assert_eq!(controller.read_root_dir().unwrap().count(), 0);
+    }
+}
+
+#[test]
+fn fat32_gets_fs_info() {
+    let mut controller = FatImageBuilder::fat32().build_controller().unwrap();
+    let usage = controller.usage().unwrap();
+    let fs_info = controller.read_fs_info().unwrap();
+    assert_eq!(fs_info.free_clusters, Some(usage.free_clusters));
+}
+
+#[test]
+fn label_and_serial_are_written() {
+    let mut controller = FatImageBuilder::fat16()
+        .label("CAMERA")
+        .serial(0xCAFE_F00D)
+        .build_controller()
+        .unwrap();
+    assert_eq!(
+        controller.volume_label().unwrap().as_deref(),
+        Some("CAMERA")
+    );
+    assert_eq!(controller.volume_id().unwrap(), Some(0xCAFE_F00D));
+
+    controller.set_volume_label(Some("HOLIDAY")).unwrap();
+    assert_eq!(
+        controller.volume_label().unwrap().as_deref(),
+        Some("HOLIDAY")
+    );
+    assert!(controller.check().unwrap().is_empty());
+}
+
+#[test]
+fn cluster_size_can_be_chosen() {
+    let mut controller = FatImageBuilder::fat16()
+        .sectors_per_cluster(8)
+        .build_controller()
+        .unwrap();
+    assert_eq!(controller.usage().unwrap().cluster_size, 4096);
+}
+
+#[test]
+fn too_small_for_fat32_is_refused() {
+    let result = FatImageBuilder::fat32().size(8 << 20).build_controller();
+    assert!(result.is_err());
+}
+
+#[test]
+fn format_needs_a_writable_controller() {
+    let mut controller = SDController::from_device(MemBlockDevice::new(512, 32768).unwrap());
+    assert!(matches!(
+        controller.format(&FormatOptions::default()),
+        Err(SDError::ReadOnly)
+    ));
+}
+
+#[test]
+fn new_partitions_are_aligned_and_can_be_formatted() {
+    let mut controller = SDController::from_device(MemBlockDevice::new(512, 131072).unwrap());
+    controller.enable_writes();
+    // Where the backup boot sector of an old FAT32 volume would lie.
+    controller
+        .write_device_block(8192 + 6, &[0xAA; 512])
+        .unwrap();
+    let table = controller
+        .create_partition_table(&[
+            NewPartition {
+                variant: Some(FatVariant::Fat16),
+                size: Some(18 << 20),
+                bootable: true,
+            },
+            NewPartition {
+                variant: Some(FatVariant::Fat32),
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+    assert_eq!(controller.read_partition_table().unwrap(), table);
+    let extents: Vec<_> = table
+        .partitions
+        .iter()
+        .map(|partition| {
+            (
+                partition.partition_type,
+                partition.start_lba,
+                partition.sector_count,
+            )
+        })
+        .collect();
+    assert_eq!(
+        extents,
+        [
+            (PartitionType::Mbr(0x04), 8192, 40960),
+            (PartitionType::Mbr(0x0B), 49152, 81920),
+        ]
+    );
+    let mbr = controller.read_device_block(0).unwrap();
+    assert_eq!(mbr[446], 0x80);
+    assert_eq!(mbr[447..450], [130, 3, 0]);
+    assert_eq!(controller.read_device_block(8192 + 6).unwrap(), [0; 512]);
+
+    controller.open_partition(1).unwrap();
+    controller
+        .format(&FormatOptions {
+            sectors_per_cluster: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+    controller.close_partition();
+    assert_eq!(controller.open_volume().unwrap(), Some(1));
+    assert_eq!(controller.usage().unwrap().variant, FatVariant::Fat32);
+}
+
+#[test]
+fn partitions_past_chs_reach_get_lba_types() {
+    let path = std::env::temp_dir().join(format!("sd-lba-{}.img", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(9 << 30)
+        .unwrap();
+    let mut controller = SDController::from_device(FileDevice::open_rw(&path).unwrap());
+    controller.enable_writes();
+    let table = controller
+        .create_partition_table(&[
+            NewPartition {
+                variant: Some(FatVariant::Fat16),
+                size: Some(1 << 30),
+                ..Default::default()
+            },
+            NewPartition {
+                variant: Some(FatVariant::Fat32),
+                size: Some(7 << 30),
+                ..Default::default()
+            },
+            NewPartition {
+                variant: Some(FatVariant::Fat16),
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let types: Vec<_> = table
+        .partitions
+        .iter()
+        .map(|partition| partition.partition_type)
+        .collect();
+    assert_eq!(
+        types,
+        [
+            PartitionType::Mbr(0x06),
+            PartitionType::Mbr(0x0C),
+            PartitionType::Mbr(0x0E)
+        ]
+    );
+}
+
+#[test]
+fn sd_spec_format_aligns_the_fat_and_data_area_to_the_boundary_unit() {
+    let mut controller = SDController::from_device(MemBlockDevice::new(512, 262144).unwrap());
+    controller.enable_writes();
+    controller
+        .create_partition_table(&[NewPartition::default()])
+        .unwrap();
+    controller.open_partition(0).unwrap();
+    let layout = controller
+        .format(&FormatOptions {
+            sd_spec: true,
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(layout.variant, FatVariant::Fat16);
+    assert_eq!(layout.cluster_size(), 16384);
+    // 32 KiB boundary units of 64 sectors, counted from the start of the
+    // card.
+    let start = controller.partition_start();
+    assert_eq!((start + layout.fat_start) % 64, 0);
+    assert_eq!((start + layout.data_start) % 64, 0);
+    assert!(controller.check().unwrap().is_empty());
+
+    let mut small = SDController::from_device(MemBlockDevice::new(512, 65536).unwrap());
+    small.enable_writes();
+    let options = FormatOptions {
+        sd_spec: true,
+        ..Default::default()
+    };
+    assert!(matches!(
+        small.format(&options),
+        Err(SDError::Unsupported(_))
+    ));
+}
+
+#[test]
+fn repair_clears_the_dirty_flags_a_driver_left() {
+    for builder in [FatImageBuilder::fat16(), FatImageBuilder::fat32()] {
+        let mut controller = builder.build_controller().unwrap();
+        assert!(!controller.dirty_flags().unwrap().unwrap().is_dirty());
+
+        controller.set_dirty(true).unwrap();
+        let flags = controller.dirty_flags().unwrap().unwrap();
+        assert!(!flags.clean_shutdown && flags.boot_sector_dirty && !flags.hard_error);
+        assert_eq!(
+            controller.check().unwrap(),
+            [FsIssue::Dirty { hard_error: false }]
+        );
+
+        let actions = controller.repair(&RepairOptions::default()).unwrap();
+        assert_eq!(actions, [RepairAction::ClearDirty]);
+        assert!(!controller.dirty_flags().unwrap().unwrap().is_dirty());
+        assert!(controller.check().unwrap().is_empty());
+    }
+}
+
+#[test]
+fn damaged_fat32_boot_sector_falls_back_to_the_backup() {
+    let mut controller = FatImageBuilder::fat32()
+        .file("/KEEP.TXT", b"kept")
+        .build_controller()
+        .unwrap();
+    controller.enable_writes();
+    controller.write_block(0, &[0; 512]).unwrap();
+
+    assert_eq!(controller.open("/KEEP.TXT").unwrap(), b"kept");
+    assert_eq!(controller.boot_sector_copy(), BootSectorCopy::Backup);
+    assert_eq!(controller.check().unwrap(), [FsIssue::DamagedBootSector]);
+
+    let actions = controller.repair(&RepairOptions::default()).unwrap();
+    assert_eq!(actions, [RepairAction::RestoreBootSector]);
+    assert_eq!(
+        controller.read_block(0).unwrap(),
+        controller.read_block(6).unwrap()
+    );
+    controller.read_boot_sector().unwrap();
+    assert_eq!(controller.boot_sector_copy(), BootSectorCopy::Primary);
+    assert!(!controller.restore_boot_sector().unwrap());
+}
+
+#[test]
+fn saved_reserved_regions_restore_a_wrecked_volume() {
+    let mut controller = FatImageBuilder::fat32()
+        .file("/KEEP.TXT", b"kept")
+        .build_controller()
+        .unwrap();
+    let region = controller.read_reserved_region().unwrap();
+    let reserved = region.len() / 512;
+    assert_eq!(
+        reserved,
+        controller.read_boot_sector().unwrap().reserved_sectors as usize
+    );
+    controller
+        .write_blocks(0, &vec![0xAB; region.len()])
+        .unwrap();
+    assert!(controller.open("/KEEP.TXT").is_err());
+
+    assert!(matches!(
+        controller.restore_reserved_region(&region[..region.len() - 512]),
+        Err(SDError::InvalidImage(_))
+    ));
+    controller.restore_reserved_region(&region).unwrap();
+    assert_eq!(controller.open("/KEEP.TXT").unwrap(), b"kept");
+    assert!(controller.check().unwrap().is_empty());
+
+    let mut read_only = SDController::from_device(controller.into_inner());
+    assert!(matches!(
+        read_only.restore_reserved_region(&region),
+        Err(SDError::ReadOnly)
+    ));
+}
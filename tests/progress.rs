@@ -0,0 +1,185 @@
+#![cfg(feature = "std")]
+
+use std::sync::mpsc;
+
+use sd_controller::testing::FatImageBuilder;
+use sd_controller::{
+    ExtractProgress, FormatOptions, IngestOptions, MemBlockDevice, Phase, Progress, ProgressSink,
+    SDController,
+};
+
+#[derive(Default)]
+struct Recorder {
+    updates: Vec<Progress>,
+    files: Vec<(String, bool)>,
+}
+
+impl ProgressSink for Recorder {
+    fn report(&mut self, progress: &Progress) {
+        self.updates.push(*progress);
+    }
+
+    fn file(&mut self, file: &ExtractProgress<'_>) {
+        self.files
+            .push((file.path.to_string(), file.error.is_none()));
+    }
+}
+
+#[test]
+fn extraction_reports_bytes_and_files() {
+    let big = vec![7u8; 3 << 20];
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DIR")
+        .file("/DIR/BIG.BIN", &big)
+        .file("/DIR/SMALL.TXT", b"small")
+        .build_controller()
+        .unwrap();
+    let dest = std::env::temp_dir().join(format!("sd-progress-{}", std::process::id()));
+    let mut recorder = Recorder::default();
+    let summary = controller
+        .extract_all("/DIR", &dest, &mut recorder)
+        .unwrap();
+    std::fs::remove_dir_all(&dest).unwrap();
+
+    assert_eq!(summary.files, 2);
+    let mut files = recorder.files.clone();
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            ("/DIR/BIG.BIN".to_string(), true),
+            ("/DIR/SMALL.TXT".to_string(), true)
+        ]
+    );
+    // The large file is reported chunk by chunk, not only once done.
+    assert!(recorder.updates.len() > 2);
+    assert!(recorder
+        .updates
+        .windows(2)
+        .all(|pair| pair[0].bytes_done < pair[1].bytes_done));
+    let last = recorder.updates.last().unwrap();
+    assert_eq!(last.phase, Phase::Extracting);
+    assert_eq!(last.bytes_total, big.len() as u64 + 5);
+    assert!(last.is_finished());
+}
+
+#[test]
+fn format_progress_reaches_the_total() {
+    let mut controller = SDController::from_device(MemBlockDevice::new(512, 80 << 11).unwrap());
+    controller.enable_writes();
+    let mut updates = Vec::new();
+    controller
+        .format_with_progress(&FormatOptions::default(), &mut |progress: &Progress| {
+            updates.push(*progress)
+        })
+        .unwrap();
+    let last = updates.last().unwrap();
+    assert!(updates.iter().all(|p| p.phase == Phase::Formatting));
+    assert!(last.bytes_total > 0);
+    assert!(last.is_finished());
+}
+
+#[test]
+fn progress_can_go_to_another_thread() {
+    let mut controller = FatImageBuilder::fat16().build_controller().unwrap();
+    let blocks = controller.num_blocks();
+    let (mut sender, receiver) = mpsc::channel();
+    let watcher = std::thread::spawn(move || receiver.iter().last());
+    controller
+        .scan_bad_blocks(0..blocks, &Default::default(), &mut sender)
+        .unwrap();
+    drop(sender);
+    let last = watcher.join().unwrap().unwrap();
+    assert_eq!(last.phase, Phase::Scanning);
+    assert_eq!(last.bytes_done, blocks * 512);
+}
+
+#[test]
+fn parallel_extraction_matches_the_volume() {
+    let mut builder = FatImageBuilder::fat16().dir("/DIR").dir("/DIR/SUB");
+    let mut expected = Vec::new();
+    for index in 0..40 {
+        let data = vec![index as u8; 700 * index + 1];
+        let path = if index % 3 == 0 {
+            format!("/DIR/SUB/F{index}.BIN")
+        } else {
+            format!("/DIR/F{index}.BIN")
+        };
+        builder = builder.file(&path, &data);
+        expected.push((path, data));
+    }
+    let mut controller = builder.build_controller().unwrap();
+    let dest = std::env::temp_dir().join(format!("sd-parallel-{}", std::process::id()));
+    let mut recorder = Recorder::default();
+    let summary = controller
+        .extract_all_parallel("/DIR", &dest, 4, &mut recorder)
+        .unwrap();
+
+    assert!(summary.is_complete());
+    assert_eq!(summary.files, 40);
+    assert_eq!(summary.directories, 2);
+    for (path, data) in &expected {
+        let host = dest.join(path.trim_start_matches("/DIR/"));
+        assert_eq!(&std::fs::read(host).unwrap(), data, "{path}");
+    }
+    std::fs::remove_dir_all(&dest).unwrap();
+    assert_eq!(recorder.files.len(), 40);
+    let last = recorder.updates.last().unwrap();
+    assert_eq!(last.bytes_done, summary.bytes);
+    assert!(last.is_finished());
+}
+
+#[test]
+fn zero_runs_are_extracted_as_holes() {
+    let mut data = vec![1u8; 10_000];
+    data.resize(3 << 20, 0);
+    data.extend_from_slice(b"end of the written part");
+    data.resize(5 << 20, 0);
+    let mut controller = FatImageBuilder::fat32()
+        .dir("/LOGS")
+        .file("/LOGS/DATA.LOG", &data)
+        .build_controller()
+        .unwrap();
+    let assert_sparse = |host: &std::path::Path| {
+        assert!(std::fs::read(host).unwrap() == data, "{}", host.display());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let allocated = std::fs::metadata(host).unwrap().blocks() * 512;
+            assert!(allocated < 1 << 20, "{allocated} bytes allocated");
+        }
+    };
+    let dest = std::env::temp_dir().join(format!("sd-sparse-{}", std::process::id()));
+
+    for threads in [1, 4] {
+        let target = dest.join(format!("tree-{threads}"));
+        let summary = if threads == 1 {
+            controller.extract_all("/LOGS", &target, &mut Recorder::default())
+        } else {
+            controller.extract_all_parallel("/LOGS", &target, threads, &mut Recorder::default())
+        }
+        .unwrap();
+        assert!(summary.is_complete());
+        assert_sparse(&target.join("DATA.LOG"));
+    }
+
+    let entry = controller.stat("/LOGS/DATA.LOG").unwrap();
+    let single = dest.join("single.log");
+    assert_eq!(
+        controller.extract_file(&entry, &single, |_| {}).unwrap(),
+        data.len() as u64
+    );
+    assert_sparse(&single);
+    let modified = entry.timestamps.modified_at().unwrap().to_system_time();
+    assert_eq!(
+        std::fs::metadata(&single).unwrap().modified().unwrap(),
+        modified
+    );
+
+    let ingested = dest.join("ingest");
+    controller
+        .ingest(&ingested, &IngestOptions::default(), &mut ())
+        .unwrap();
+    assert_sparse(&ingested.join("LOGS/DATA.LOG"));
+    std::fs::remove_dir_all(&dest).unwrap();
+}
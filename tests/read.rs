@@ -0,0 +1,1097 @@
+#![cfg(feature = "std")]
+
+use std::cell::Cell;
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sd_controller::testing::FatImageBuilder;
+use sd_controller::{
+    BlockDevice, FatDateTime, FatVariant, MemBlockDevice, SDController, SDError, ThrottledDevice,
+};
+
+fn sample(builder: FatImageBuilder) -> SDController<MemBlockDevice> {
+    builder
+        .dir("/DOCS")
+        .dir("/DOCS/Meeting notes")
+        .file("/HELLO.TXT", b"Hello, world!\n")
+        .file("/DOCS/README.MD", b"# Readme\n")
+        .file("/DOCS/Meeting notes/Quarterly review.txt", b"All good.")
+        .build_controller()
+        .unwrap()
+}
+
+fn read_sample(builder: FatImageBuilder) {
+    let variant = builder.variant();
+    let mut controller = sample(builder);
+    assert_eq!(controller.open_volume().unwrap(), None);
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let layout = controller.calculate_layout(&boot_sector);
+    assert_eq!(layout.variant, variant);
+
+    assert_eq!(controller.open("/HELLO.TXT").unwrap(), b"Hello, world!\n");
+    assert_eq!(controller.open("/hello.txt").unwrap(), b"Hello, world!\n");
+    assert_eq!(
+        controller
+            .open("/DOCS/Meeting notes/Quarterly review.txt")
+            .unwrap(),
+        b"All good."
+    );
+
+    let mut paths: Vec<String> = controller
+        .walk()
+        .unwrap()
+        .map(|entry| entry.unwrap().path)
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "/DOCS",
+            "/DOCS/Meeting notes",
+            "/DOCS/Meeting notes/Quarterly review.txt",
+            "/DOCS/README.MD",
+            "/HELLO.TXT",
+        ]
+    );
+}
+
+#[test]
+fn reads_fat16_tree() {
+    read_sample(FatImageBuilder::fat16());
+}
+
+#[test]
+fn reads_fat32_tree() {
+    read_sample(FatImageBuilder::fat32());
+}
+
+#[test]
+fn long_names_keep_case_and_get_short_aliases() {
+    let mut controller = sample(FatImageBuilder::fat16());
+    let entry = controller.stat("/DOCS/Meeting notes").unwrap();
+    assert!(entry.is_dir());
+    assert_eq!(entry.long_name.as_deref(), Some("Meeting notes"));
+    assert_eq!(entry.full_name(), "Meeting notes");
+    assert!(entry.name.len() <= 8 && entry.name.chars().all(|c| !c.is_lowercase()));
+}
+
+#[test]
+fn missing_paths_are_reported() {
+    let mut controller = sample(FatImageBuilder::fat16());
+    assert!(matches!(
+        controller.open("/NOPE.TXT"),
+        Err(SDError::NotFound(_))
+    ));
+    assert!(matches!(
+        controller.open("/DOCS/NOPE/FILE.TXT"),
+        Err(SDError::NotFound(_))
+    ));
+}
+
+#[test]
+fn reader_streams_across_clusters() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut controller = FatImageBuilder::fat32()
+        .file("/BIG.BIN", &data)
+        .build_controller()
+        .unwrap();
+    let mut reader = controller.open_reader("/BIG.BIN").unwrap();
+    assert_eq!(reader.len(), data.len() as u64);
+
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, data);
+
+    reader.seek(SeekFrom::Start(60_001)).unwrap();
+    let mut chunk = [0u8; 1000];
+    reader.read_exact(&mut chunk).unwrap();
+    assert_eq!(chunk[..], data[60_001..61_001]);
+}
+
+#[test]
+fn variants_follow_builder() {
+    let device = FatImageBuilder::fat32().build().unwrap();
+    let mut controller = SDController::from_device(device);
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let layout = controller.calculate_layout(&boot_sector);
+    assert_eq!(layout.variant, FatVariant::Fat32);
+    assert_eq!(layout.sectors_per_cluster, 1);
+}
+
+#[test]
+fn timestamps_decode_into_calendar_fields() {
+    let mut controller = sample(FatImageBuilder::fat16());
+    let entry = controller.stat("/DOCS/README.MD").unwrap();
+    let modified = entry.timestamps.modified_at().unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    // Modification times have two-second resolution.
+    assert!(now.abs_diff(modified.unix_time()) <= 2);
+    assert_eq!(modified.to_system_time(), entry.timestamps.modified());
+
+    // 2024-02-29 13:05:08.450, and the 30th of February that FAT can encode.
+    let date = (2024 - 1980) << 9 | 2 << 5 | 29;
+    let time = 13 << 11 | 5 << 5 | 4;
+    let decoded = FatDateTime::decode(date, time, 45).unwrap();
+    assert_eq!(decoded.to_string(), "2024-02-29T13:05:08.450");
+    assert_eq!(decoded.unix_time(), 1_709_211_908);
+    #[cfg(feature = "time")]
+    assert_eq!(
+        time::OffsetDateTime::try_from(decoded)
+            .unwrap()
+            .unix_timestamp(),
+        1_709_211_908
+    );
+    assert_eq!(FatDateTime::decode(date + 1, time, 0), None);
+    assert_eq!(FatDateTime::decode(0, 0, 0), None);
+}
+
+#[test]
+fn looping_chains_end_in_an_error() {
+    let mut controller = FatImageBuilder::fat16()
+        .sectors_per_cluster(1)
+        .file("/LOOP.BIN", &[9; 1500])
+        .build_controller()
+        .unwrap();
+    let first = controller.stat("/LOOP.BIN").unwrap().first_cluster;
+    let chain: Vec<u32> = controller
+        .cluster_chain(first)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(chain, [first, first + 1, first + 2]);
+
+    // Point the last cluster back at the first, in the first FAT only.
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let layout = controller.calculate_layout(&boot_sector);
+    let offset = (first + 2) as usize * 2;
+    let sector = layout.fat_start + (offset / 512) as u32;
+    let mut block = controller.read_block(sector).unwrap();
+    block[offset % 512..offset % 512 + 2].copy_from_slice(&(first as u16).to_le_bytes());
+    controller.write_block(sector, &block).unwrap();
+
+    let mut on_disk = controller.cluster_chain(first).unwrap();
+    let looped = on_disk.by_ref().find_map(Result::err);
+    assert!(matches!(looped, Some(SDError::InvalidCluster(_))));
+    assert!(on_disk.next().is_none());
+    let table = controller.read_fat().unwrap();
+    assert_eq!(
+        table.chain(first).filter(Result::is_ok).count() as u32,
+        table.cluster_count()
+    );
+    // Reading the file stops at its size, before the loop.
+    assert_eq!(controller.open("/LOOP.BIN").unwrap(), [9; 1500]);
+}
+
+/// Counts the read requests that reach the device.
+struct CountingDevice {
+    inner: MemBlockDevice,
+    reads: Rc<Cell<usize>>,
+}
+
+impl BlockDevice for CountingDevice {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.reads.set(self.reads.get() + 1);
+        self.inner.read_block(block_index, buffer)
+    }
+
+    fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+        self.inner.write_block(block_index, data)
+    }
+
+    fn read_blocks(&mut self, start: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+        self.reads.set(self.reads.get() + 1);
+        self.inner.read_blocks(start, buffer)
+    }
+}
+
+#[test]
+fn cached_multi_block_reads_fetch_runs_of_misses_together() {
+    use sd_controller::CachedDevice;
+
+    let data: Vec<u8> = (0..16 * 512).map(|i| (i / 512) as u8).collect();
+    let reads = Rc::new(Cell::new(0));
+    let mut cache = CachedDevice::new(
+        CountingDevice {
+            inner: MemBlockDevice::from_vec(data.clone(), 512).unwrap(),
+            reads: reads.clone(),
+        },
+        64,
+    );
+    let mut block = [0u8; 512];
+    cache.read_block(2, &mut block).unwrap();
+    cache.write_block(5, &[0xEE; 512]).unwrap();
+    reads.take();
+
+    // Blocks 2 and 5 come from the cache, leaving three runs to read.
+    let mut buffer = vec![0u8; 10 * 512];
+    cache.read_blocks(0, &mut buffer).unwrap();
+    assert_eq!(reads.take(), 3);
+    let mut expected = data[..10 * 512].to_vec();
+    expected[5 * 512..6 * 512].fill(0xEE);
+    assert_eq!(buffer, expected);
+    assert_eq!((cache.hits(), cache.misses()), (2, 9));
+
+    cache.read_blocks(0, &mut buffer).unwrap();
+    assert_eq!(reads.take(), 0);
+    assert_eq!(buffer, expected);
+}
+
+#[test]
+fn sequential_reads_are_fetched_ahead() {
+    let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 253) as u8).collect();
+    let inner = FatImageBuilder::fat32()
+        .size(64 << 20)
+        .file("/VIDEO.MP4", &data)
+        .build()
+        .unwrap();
+    let reads = Rc::new(Cell::new(0));
+    let mut controller = SDController::from_device(CountingDevice {
+        inner,
+        reads: reads.clone(),
+    });
+
+    let mut read_with = |read_ahead: usize| {
+        let mut reader = controller.open_reader("/VIDEO.MP4").unwrap();
+        reader.set_read_ahead(read_ahead);
+        let mut contents = Vec::new();
+        let mut chunk = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            contents.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(contents, data);
+        // A seek lands outside the read-ahead data and is still right.
+        reader.seek(SeekFrom::Start(42)).unwrap();
+        reader.read_exact(&mut chunk[..10]).unwrap();
+        assert_eq!(chunk[..10], data[42..52]);
+        reads.take()
+    };
+    let without = read_with(0);
+    let with = read_with(1 << 20);
+    assert!(
+        with * 20 < without,
+        "{with} requests with read-ahead, {without} without"
+    );
+}
+
+#[test]
+fn files_and_blocks_hash_as_their_contents() {
+    use sd_controller::{parse_checksums, HashAlgorithm};
+
+    let mut controller = FatImageBuilder::fat16()
+        .file("/ABC.TXT", b"abc")
+        .file("/DIGITS.TXT", b"123456789")
+        .build_controller()
+        .unwrap();
+    let crc = controller
+        .hash_file("/DIGITS.TXT", HashAlgorithm::Crc32, &mut ())
+        .unwrap();
+    assert_eq!(crc.to_string(), "cbf43926");
+
+    let mut zeros = SDController::from_device(MemBlockDevice::new(512, 4).unwrap());
+    let crc = zeros
+        .hash_device(1..2, HashAlgorithm::Crc32, &mut ())
+        .unwrap();
+    assert_eq!(crc.to_string(), "b2aa7578");
+    let past_end = zeros.hash_device(0..5, HashAlgorithm::Crc32, &mut ());
+    assert!(matches!(past_end, Err(SDError::BlockOutOfRange(5))));
+
+    let manifest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  /ABC.TXT\n\
+                    # comment\n\
+                    076A27C79E5ACE2A3D47F9DD2E83E4FF6EA8872B3C2218F66C92B89B55F36560 *zeros.img\n";
+    let entries = parse_checksums(manifest, HashAlgorithm::Sha256).unwrap();
+    assert_eq!(entries[1].name, "zeros.img");
+    assert!(parse_checksums("cbf43926  /DIGITS.TXT", HashAlgorithm::Sha256).is_err());
+    if cfg!(feature = "sha256") {
+        let sha = controller
+            .hash_file("/ABC.TXT", HashAlgorithm::Sha256, &mut ())
+            .unwrap();
+        assert_eq!(sha, entries[0].digest);
+        let sha = zeros
+            .hash_device(0..1, HashAlgorithm::Sha256, &mut ())
+            .unwrap();
+        assert_eq!(sha, entries[1].digest);
+    }
+}
+
+#[test]
+fn manifests_tell_corruption_from_changes() {
+    use sd_controller::{FATLayout, HashAlgorithm, ManifestProblem};
+
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/KEEP")
+        .file("/KEEP/SAFE.TXT", b"untouched")
+        .file("/KEEP/ROT.TXT", b"precious data")
+        .file("/KEEP/EDIT.TXT", b"first draft")
+        .file("/KEEP/GONE.TXT", b"deleted later")
+        .build_controller()
+        .unwrap();
+    let manifest = controller
+        .create_manifest("/KEEP", HashAlgorithm::Crc32, &mut ())
+        .unwrap();
+    assert_eq!(manifest.files.len(), 4);
+
+    controller.enable_writes();
+    let layout = FATLayout::new(&controller.read_boot_sector().unwrap());
+    let rotten = controller.stat("/KEEP/ROT.TXT").unwrap();
+    let sector = layout.cluster_to_sector(rotten.first_cluster);
+    let mut block = controller.read_block(sector).unwrap();
+    block[0] ^= 0x01;
+    controller.write_block(sector, &block).unwrap();
+    controller.delete_file("/KEEP/EDIT.TXT").unwrap();
+    controller
+        .create_file("/KEEP/EDIT.TXT", b"second draft")
+        .unwrap();
+    controller.delete_file("/KEEP/GONE.TXT").unwrap();
+    controller.create_file("/KEEP/NEW.TXT", b"new").unwrap();
+
+    let report = controller.verify_manifest(&manifest, &mut ()).unwrap();
+    assert!(!report.is_intact());
+    assert_eq!(report.verified, 1);
+    let problems: Vec<_> = report
+        .mismatches
+        .iter()
+        .map(|mismatch| (mismatch.path.as_str(), &mismatch.problem))
+        .collect();
+    assert!(matches!(
+        problems[..],
+        [
+            ("/KEEP/ROT.TXT", ManifestProblem::Corrupted),
+            ("/KEEP/EDIT.TXT", ManifestProblem::Changed),
+            ("/KEEP/GONE.TXT", ManifestProblem::Missing),
+        ]
+    ));
+    assert_eq!(report.added, ["/KEEP/NEW.TXT"]);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn manifests_round_trip_through_json() {
+    use sd_controller::{HashAlgorithm, Manifest};
+
+    let mut controller = FatImageBuilder::fat32()
+        .file("/A.TXT", b"a")
+        .build_controller()
+        .unwrap();
+    let manifest = controller
+        .create_manifest("/", HashAlgorithm::Crc32, &mut ())
+        .unwrap();
+    let mut json = Vec::new();
+    manifest.write_json(&mut json).unwrap();
+    assert_eq!(Manifest::read_json(&json[..]).unwrap(), manifest);
+    assert!(Manifest::read_json(&b"{}"[..]).is_err());
+}
+
+#[test]
+fn diffs_show_changed_blocks_and_files() {
+    use sd_controller::{Attributes, ChangedFile, FileChange};
+
+    let builder = FatImageBuilder::fat16()
+        .dir("/DIR")
+        .file("/DIR/SAME.TXT", b"same")
+        .file("/DIR/EDIT.TXT", b"before")
+        .file("/DIR/ATTR.TXT", b"attributes")
+        .file("/OLD.TXT", b"old");
+    let mut original = builder.clone().build_controller().unwrap();
+    let mut copy = builder.build_controller().unwrap();
+    let identical = original.diff_blocks(&mut copy, &mut ()).unwrap();
+    assert!(identical.is_identical());
+
+    copy.enable_writes();
+    copy.delete_file("/DIR/EDIT.TXT").unwrap();
+    copy.create_file("/DIR/EDIT.TXT", b"after!").unwrap();
+    copy.set_attributes("/DIR/ATTR.TXT", Attributes::READ_ONLY)
+        .unwrap();
+    copy.delete_file("/OLD.TXT").unwrap();
+    copy.create_file("/NEW.TXT", b"new").unwrap();
+
+    let blocks = original.diff_blocks(&mut copy, &mut ()).unwrap();
+    assert!(!blocks.is_identical());
+    assert_eq!(blocks.blocks.0, blocks.blocks.1);
+    assert!(blocks.differing.windows(2).all(|w| w[0].end < w[1].start));
+
+    let files = original.diff_files(&mut copy, &mut ()).unwrap();
+    assert_eq!(files.added, ["/NEW.TXT"]);
+    assert_eq!(files.removed, ["/OLD.TXT"]);
+    let change = |path: &str, change| ChangedFile {
+        path: path.to_string(),
+        change,
+    };
+    assert_eq!(
+        files.changed,
+        [
+            change("/DIR/ATTR.TXT", FileChange::Metadata),
+            change("/DIR/EDIT.TXT", FileChange::Contents),
+        ]
+    );
+}
+
+#[test]
+fn used_only_images_hold_the_whole_volume() {
+    use sd_controller::FileDevice;
+
+    let mut controller = FatImageBuilder::fat32()
+        .dir("/DIR")
+        .file("/DIR/DATA.BIN", &vec![0x5A; 200_000])
+        .build_controller()
+        .unwrap();
+    let size = controller.num_blocks() * 512;
+    let path = std::env::temp_dir().join(format!("sd-used-{}.img", std::process::id()));
+    let read = controller.dump_used_image(&path, &mut ()).unwrap();
+    assert!(read >= 200_000 && read < size / 4);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+
+    let mut image = SDController::from_device(FileDevice::open(&path).unwrap());
+    let diff = controller.diff_blocks(&mut image, &mut ()).unwrap();
+    drop(image);
+    std::fs::remove_file(&path).unwrap();
+    assert!(diff.is_identical());
+}
+
+#[test]
+fn carving_finds_files_without_a_filesystem() {
+    use sd_controller::{CarveKind, CarveOptions};
+
+    // A JPEG whose EXIF segment holds a thumbnail with its own end marker,
+    // and whose scan data has stuffed bytes and a restart marker.
+    let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x0A];
+    jpeg.extend_from_slice(&[0xFF, 0xD8, 0xFF, 0xD9, 0x00, 0x00, 0x00, 0x00]);
+    jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x04, 0x01, 0x02]);
+    jpeg.extend(std::iter::repeat_n([0x12, 0xFF, 0x00, 0x34], 2_000).flatten());
+    jpeg.extend_from_slice(&[0xFF, 0xD3, 0x56, 0xFF, 0xD9]);
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, len) in [(b"IHDR", 13), (b"IDAT", 3_000), (b"IEND", 0)] {
+        png.extend_from_slice(&(len as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend(vec![0x5A; len + 4]);
+    }
+    let mut mp4 = Vec::new();
+    for (kind, len) in [(b"ftyp", 24), (b"moov", 600), (b"mdat", 5_000)] {
+        mp4.extend_from_slice(&(len as u32).to_be_bytes());
+        mp4.extend_from_slice(kind);
+        mp4.extend(vec![0x61; len - 8]);
+    }
+    let mut zip = b"PK\x03\x04".to_vec();
+    zip.extend_from_slice(&[0u8; 14]);
+    zip.extend_from_slice(&5u32.to_le_bytes());
+    zip.extend_from_slice(&[0u8; 4]);
+    zip.extend_from_slice(&1u16.to_le_bytes());
+    zip.extend_from_slice(&[0u8; 2]);
+    zip.extend_from_slice(b"Ahello");
+    zip.extend_from_slice(b"PK\x01\x02");
+    zip.extend_from_slice(&[0u8; 24]);
+    zip.extend_from_slice(&1u16.to_le_bytes());
+    zip.extend_from_slice(&[0u8; 16]);
+    zip.extend_from_slice(b"A");
+    zip.extend_from_slice(b"PK\x05\x06");
+    zip.extend_from_slice(&[0u8; 16]);
+    zip.extend_from_slice(&2u16.to_le_bytes());
+    zip.extend_from_slice(b"hi");
+
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DCIM")
+        .file("/DCIM/IMG_0001.JPG", &jpeg)
+        .file("/IMAGE.PNG", &png)
+        .file("/CLIP.MP4", &mp4)
+        .file("/FILES.ZIP", &zip)
+        .build_controller()
+        .unwrap();
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let data_start = controller.calculate_layout(&boot_sector).data_start;
+    controller
+        .write_blocks(0, &vec![0; data_start as usize * 512])
+        .unwrap();
+    assert!(controller.stat("/IMAGE.PNG").is_err());
+
+    let found = controller.carve(&CarveOptions::default(), &mut ()).unwrap();
+    let mut carved: Vec<(CarveKind, Vec<u8>)> = found
+        .iter()
+        .inspect(|file| assert!(file.complete, "{file:?}"))
+        .map(|file| {
+            let mut data = Vec::new();
+            controller.read_carved(file, &mut data).unwrap();
+            (file.kind, data)
+        })
+        .collect();
+    carved.sort_by_key(|(kind, _)| *kind as u8);
+    assert_eq!(
+        carved,
+        [
+            (CarveKind::Jpeg, jpeg),
+            (CarveKind::Png, png),
+            (CarveKind::Mp4, mp4),
+            (CarveKind::Zip, zip),
+        ]
+    );
+}
+
+#[test]
+fn allocation_maps_show_used_free_and_bad_clusters() {
+    use sd_controller::{ClusterState, MapOptions};
+
+    let mut controller = FatImageBuilder::fat16()
+        .file("/DATA.BIN", &vec![7; 5000])
+        .build_controller()
+        .unwrap();
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let layout = controller.calculate_layout(&boot_sector);
+    let usage = controller.usage().unwrap();
+
+    // Mark cluster 100 bad in the first FAT: its entry is at byte 200.
+    controller.enable_writes();
+    let mut fat = controller.read_block(layout.fat_start).unwrap();
+    fat[200..202].copy_from_slice(&0xFFF7u16.to_le_bytes());
+    controller.write_block(layout.fat_start, &fat).unwrap();
+
+    let map = controller.alloc_map().unwrap();
+    assert_eq!(map.clusters.len(), usage.total_clusters as usize);
+    assert_eq!(map.state(100), Some(ClusterState::Bad));
+    assert_eq!(map.state(2), Some(ClusterState::Used));
+    assert_eq!(
+        map.count(ClusterState::Free),
+        usage.free_clusters as usize - 1
+    );
+
+    let options = MapOptions {
+        columns: 50,
+        ..MapOptions::default()
+    };
+    let mut text = Vec::new();
+    map.write_text(&mut text, &options).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    let rows: Vec<&str> = text.lines().collect();
+    assert!(rows[0].starts_with("        2  #"));
+    assert_eq!(rows[1], format!("       52  {}X.", ".".repeat(48)));
+
+    let mut png = Vec::new();
+    map.write_png(&mut png, &options).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(png[16..20], (50u32 * 8).to_be_bytes());
+    assert_eq!(png[20..24], (rows.len() as u32 * 8).to_be_bytes());
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn the_c_interface_lists_and_reads_files() {
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    use sd_controller::ffi::*;
+
+    let image = FatImageBuilder::fat16()
+        .dir("/DIR")
+        .file("/DIR/NOTE.TXT", b"from C")
+        .build()
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("sd-ffi-{}.img", std::process::id()));
+    std::fs::write(&path, image.as_bytes()).unwrap();
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+    unsafe {
+        let mut controller = ptr::null_mut();
+        assert_eq!(
+            sdctl_open(c_path.as_ptr(), &mut controller),
+            SdctlStatus::Ok
+        );
+        assert_eq!(sdctl_num_blocks(controller), image.num_blocks());
+        let mut block = [0u8; 512];
+        assert_eq!(
+            sdctl_read_block(controller, 0, block.as_mut_ptr(), block.len()),
+            SdctlStatus::Ok
+        );
+        assert_eq!(block, image.as_bytes()[..512]);
+
+        let (mut entries, mut count) = (ptr::null_mut(), 0);
+        let dir = CString::new("/DIR").unwrap();
+        assert_eq!(
+            sdctl_list_dir(controller, dir.as_ptr(), &mut entries, &mut count),
+            SdctlStatus::Ok
+        );
+        let listing = std::slice::from_raw_parts(entries, count);
+        assert_eq!(listing.len(), 1);
+        assert_eq!(CStr::from_ptr(listing[0].name).to_str(), Ok("NOTE.TXT"));
+        assert_eq!((listing[0].size, listing[0].is_dir), (6, false));
+        sdctl_free_dir(entries, count);
+
+        let (mut data, mut len) = (ptr::null_mut(), 0);
+        let file = CString::new("/DIR/NOTE.TXT").unwrap();
+        assert_eq!(
+            sdctl_read_file(controller, file.as_ptr(), &mut data, &mut len),
+            SdctlStatus::Ok
+        );
+        assert_eq!(std::slice::from_raw_parts(data, len), b"from C");
+        sdctl_free_buffer(data, len);
+
+        let missing = CString::new("/NOPE").unwrap();
+        assert_eq!(
+            sdctl_read_file(controller, missing.as_ptr(), &mut data, &mut len),
+            SdctlStatus::NotFound
+        );
+        assert!(CStr::from_ptr(sdctl_last_error())
+            .to_str()
+            .unwrap()
+            .contains("NOPE"));
+        assert_eq!(
+            sdctl_read_block(controller, 0, block.as_mut_ptr(), 100),
+            SdctlStatus::InvalidArgument
+        );
+        sdctl_close(controller);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "remote")]
+#[test]
+fn remote_devices_read_like_local_ones() {
+    use sd_controller::{RemoteDevice, ServeOptions, Server};
+
+    let image = FatImageBuilder::fat32()
+        .file("/LOG.CSV", &b"t,temp\n".repeat(1000))
+        .build()
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("sd-remote-{}.img", std::process::id()));
+    std::fs::write(&path, image.as_bytes()).unwrap();
+    let options = ServeOptions {
+        devices: vec![path.clone()],
+        token: "secret".to_string(),
+    };
+    let server = Server::bind("127.0.0.1:0", options).unwrap();
+    let url = format!("http://{}", server.local_addr().unwrap());
+    std::thread::spawn(move || server.run());
+    let name = path.to_str().unwrap();
+
+    let remote = RemoteDevice::open(&url, name, "secret").unwrap();
+    assert_eq!(remote.num_blocks(), image.num_blocks());
+    let mut controller = SDController::from_device(remote);
+    controller.open_volume().unwrap();
+    assert_eq!(
+        controller.open("/LOG.CSV").unwrap(),
+        b"t,temp\n".repeat(1000)
+    );
+
+    let wrong = RemoteDevice::open(&url, name, "guess").err().unwrap();
+    assert!(matches!(wrong, SDError::Remote { status: 401, .. }));
+    let unexported = RemoteDevice::open(&url, "/etc/passwd", "secret")
+        .err()
+        .unwrap();
+    assert!(matches!(unexported, SDError::Remote { status: 404, .. }));
+    std::fs::remove_file(path).unwrap();
+
+    // A server describing a block size no reads can be made of is refused.
+    let bogus = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", bogus.local_addr().unwrap());
+    let block_sizes = [0, 768, 8 << 20];
+    std::thread::spawn(move || {
+        use std::io::{BufRead, BufReader, Write};
+        for block_size in block_sizes {
+            let (mut stream, _) = bogus.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let body = format!(r#"{{"block_size":{block_size},"num_blocks":64}}"#);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+    });
+    for _ in block_sizes {
+        let refused = RemoteDevice::open(&url, "card", "secret").err().unwrap();
+        assert!(
+            matches!(refused, SDError::Remote { status: 200, .. }),
+            "{refused}"
+        );
+    }
+}
+
+#[test]
+fn duplicates_are_found_by_contents() {
+    use sd_controller::DedupeOptions;
+
+    let burst = b"IMG".repeat(3000);
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DCIM")
+        .file("/DCIM/IMG_0001.JPG", &burst)
+        .file("/DCIM/IMG_0002.JPG", &burst)
+        .file("/COPY.JPG", &burst)
+        .file("/OTHER.JPG", &b"IMH".repeat(3000))
+        .file("/A.TXT", b"a")
+        .file("/B.TXT", b"a")
+        .file("/EMPTY1", b"")
+        .file("/EMPTY2", b"")
+        .build_controller()
+        .unwrap();
+    controller.open_volume().unwrap();
+
+    let report = controller
+        .find_duplicates(&DedupeOptions::default(), &mut ())
+        .unwrap();
+    assert_eq!(report.files, 6);
+    assert_eq!(report.hashed, 6);
+    let sets: Vec<Vec<&str>> = report
+        .duplicates
+        .iter()
+        .map(|set| set.paths.iter().map(String::as_str).collect())
+        .collect();
+    assert_eq!(
+        sets,
+        [
+            vec!["/DCIM/IMG_0001.JPG", "/DCIM/IMG_0002.JPG", "/COPY.JPG"],
+            vec!["/A.TXT", "/B.TXT"],
+        ]
+    );
+    assert_eq!(report.wasted_bytes(), 2 * 9000 + 1);
+
+    let options = DedupeOptions {
+        min_size: 2,
+        max_size: Some(9000),
+        root: "/DCIM".to_string(),
+        ..DedupeOptions::default()
+    };
+    let report = controller.find_duplicates(&options, &mut ()).unwrap();
+    assert_eq!(report.duplicates.len(), 1);
+    assert_eq!(report.duplicates[0].paths.len(), 2);
+}
+
+#[test]
+fn filters_pick_files_by_name_size_and_date() {
+    use sd_controller::{EntryFilter, HashAlgorithm};
+
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DCIM")
+        .dir("/DCIM/100CANON")
+        .file("/DCIM/100CANON/IMG_0001.CR2", &[1; 3000])
+        .file("/DCIM/100CANON/IMG_0001.JPG", &[2; 1000])
+        .file("/DCIM/100CANON/Long raw name.cr2", &[3; 10])
+        .dir("/MISC")
+        .file("/MISC/NOTES.TXT", b"notes")
+        .build_controller()
+        .unwrap();
+    controller.open_volume().unwrap();
+    let jpg = controller.stat("/DCIM/100CANON/IMG_0001.JPG").unwrap();
+
+    let by_glob = EntryFilter {
+        globs: vec!["img_*.[cj]??".to_string()],
+        ..EntryFilter::default()
+    };
+    assert!(by_glob.matches(&jpg));
+    let raw_and_large = EntryFilter {
+        extensions: vec!["cr2".to_string()],
+        min_size: Some(100),
+        ..EntryFilter::default()
+    };
+    assert!(!raw_and_large.matches(&jpg));
+    let newer = EntryFilter {
+        newer_than: FatDateTime::parse("2020-01-01T00:00:00"),
+        ..EntryFilter::default()
+    };
+    assert!(newer.matches(&jpg));
+    let older = EntryFilter {
+        older_than: newer.newer_than,
+        ..EntryFilter::default()
+    };
+    assert!(!older.matches(&jpg));
+
+    let dest = std::env::temp_dir().join(format!("sd-filter-{}", std::process::id()));
+    let summary = controller
+        .extract_matching("/", &dest, &raw_and_large, &mut ())
+        .unwrap();
+    assert_eq!(summary.files, 1);
+    assert!(dest.join("DCIM/100CANON/IMG_0001.CR2").is_file());
+    assert!(!dest.join("DCIM/100CANON/IMG_0001.JPG").exists());
+    assert!(!dest.join("MISC").exists());
+
+    let manifest = controller
+        .create_manifest_matching("/", HashAlgorithm::Crc32, &raw_and_large, &mut ())
+        .unwrap();
+    assert_eq!(manifest.files.len(), 1);
+    let report = controller
+        .verify_manifest_matching(&manifest, &raw_and_large, &mut ())
+        .unwrap();
+    assert!(report.added.is_empty());
+    std::fs::remove_dir_all(dest).unwrap();
+}
+
+#[test]
+fn ingest_only_pulls_files_it_has_not_seen() {
+    use sd_controller::IngestOptions;
+
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DCIM")
+        .dir("/DCIM/100CANON")
+        .file("/DCIM/100CANON/IMG_0001.JPG", &[1; 3000])
+        .file("/DCIM/100CANON/IMG_0002.JPG", &[2; 1000])
+        .build_controller()
+        .unwrap();
+    controller.open_volume().unwrap();
+    let dest = std::env::temp_dir().join(format!("sd-ingest-{}", std::process::id()));
+    let folder = dest.join("DCIM/100CANON");
+    std::fs::create_dir_all(&folder).unwrap();
+    // Already there from elsewhere, so the card's file must not replace it.
+    std::fs::write(folder.join("IMG_0002.JPG"), b"mine").unwrap();
+    let options = IngestOptions::default();
+
+    let first = controller.ingest(&dest, &options, &mut ()).unwrap();
+    assert_eq!((first.new, first.changed, first.unchanged), (2, 0, 0));
+    assert_eq!(first.bytes, 4000);
+    assert_eq!(std::fs::read(folder.join("IMG_0002.JPG")).unwrap(), b"mine");
+    assert_eq!(
+        std::fs::read(folder.join("IMG_0002-1.JPG")).unwrap(),
+        [2; 1000]
+    );
+
+    let second = controller.ingest(&dest, &options, &mut ()).unwrap();
+    assert_eq!((second.new, second.changed, second.unchanged), (0, 0, 2));
+    assert_eq!(second.bytes, 0);
+
+    controller
+        .delete_file("/DCIM/100CANON/IMG_0002.JPG")
+        .unwrap();
+    controller
+        .create_file("/DCIM/100CANON/IMG_0002.JPG", &[3; 1500])
+        .unwrap();
+    controller
+        .create_file("/DCIM/100CANON/IMG_0003.JPG", &[4; 10])
+        .unwrap();
+    let third = controller.ingest(&dest, &options, &mut ()).unwrap();
+    assert_eq!((third.new, third.changed, third.unchanged), (1, 1, 1));
+    assert_eq!(
+        std::fs::read(folder.join("IMG_0002-1.JPG")).unwrap(),
+        [3; 1500]
+    );
+    assert!(folder.join("IMG_0003.JPG").is_file());
+
+    let database = std::fs::read_to_string(dest.join(".sd_ingest")).unwrap();
+    assert_eq!(database.lines().count(), 4, "not compacted:\n{database}");
+    std::fs::remove_dir_all(dest).unwrap();
+}
+
+#[test]
+fn search_finds_bytes_across_chunks_and_names_their_files() {
+    use sd_controller::{SearchOptions, SearchPattern};
+
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DOCS")
+        .file("/DOCS/NOTES.TXT", b"the secret word is swordfish")
+        .file("/OTHER.TXT", b"swordfish again")
+        .build_controller()
+        .unwrap();
+    // Straddles the first 1 MiB read, in clusters no file uses.
+    let mut blocks = controller.read_blocks(2047, 2).unwrap();
+    blocks[509..515].copy_from_slice(b"NEEDLE");
+    controller.write_blocks(2047, &blocks).unwrap();
+    controller.open_volume().unwrap();
+
+    let search = |controller: &mut SDController<MemBlockDevice>, pattern| {
+        controller
+            .search(&SearchOptions::new(pattern), &mut ())
+            .unwrap()
+    };
+    let hits = search(&mut controller, SearchPattern::Bytes(b"NEEDLE".to_vec()));
+    assert_eq!(hits.len(), 1);
+    assert_eq!((hits[0].block, hits[0].offset), (2047, 509));
+    assert_eq!(hits[0].owner, None);
+
+    let hits = search(&mut controller, SearchPattern::Bytes(b"swordfish".to_vec()));
+    let owners: Vec<_> = hits.iter().map(|hit| hit.owner.as_deref()).collect();
+    assert_eq!(owners, [Some("/DOCS/NOTES.TXT"), Some("/OTHER.TXT")]);
+    assert_eq!(hits[0].offset, 19);
+
+    let first = controller
+        .search(
+            &SearchOptions {
+                max_hits: Some(1),
+                ..SearchOptions::new(SearchPattern::Bytes(b"swordfish".to_vec()))
+            },
+            &mut (),
+        )
+        .unwrap();
+    assert_eq!(first, hits[..1]);
+
+    #[cfg(feature = "regex")]
+    {
+        let hits = search(&mut controller, SearchPattern::regex(r"NEE\w+").unwrap());
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].bytes, b"NEEDLE");
+    }
+}
+
+/// Overwrites the 11-byte short name `from` with `to` wherever it is.
+fn patch_short_name(controller: &mut SDController<MemBlockDevice>, from: &[u8], to: &[u8]) {
+    patch_bytes(controller, &from[..11], &to[..11]);
+}
+
+/// Replaces the first occurrence of `from` on the volume with `to`, of the
+/// same length, within one block.
+fn patch_bytes(controller: &mut SDController<MemBlockDevice>, from: &[u8], to: &[u8]) {
+    let (block, offset) = (0..controller.num_blocks() as u32)
+        .find_map(|block| {
+            let data = controller.read_block(block).unwrap();
+            data.windows(from.len())
+                .position(|bytes| bytes == from)
+                .map(|offset| (block, offset))
+        })
+        .unwrap();
+    let mut data = controller.read_block(block).unwrap();
+    data[offset..offset + to.len()].copy_from_slice(to);
+    controller.write_block(block, &data).unwrap();
+}
+
+#[test]
+fn long_names_cannot_lead_extraction_out_of_the_destination() {
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DIR")
+        .file("/DIR/aa_evil", b"payload")
+        .file("/DIR/KEPT.TXT", b"kept")
+        .build_controller()
+        .unwrap();
+    // The first five characters of the long name, as UTF-16 in its entry;
+    // the checksum covers the short name only, so it still matches.
+    patch_bytes(&mut controller, b"a\0a\0_\0e\0v\0", b".\0.\0/\0e\0v\0");
+    let dir = controller.stat("/DIR").unwrap();
+    assert!(controller
+        .open_dir(&dir)
+        .unwrap()
+        .any(|entry| entry.full_name() == "../evil"));
+
+    let root = std::env::temp_dir().join(format!("sd-traversal-{}", std::process::id()));
+    let dest = root.join("out");
+    let summary = controller.extract_all("/DIR", &dest, &mut ()).unwrap();
+    assert_eq!(summary.files, 1);
+    assert_eq!(summary.failures.len(), 1);
+    assert!(matches!(summary.failures[0].error, SDError::InvalidName(_)));
+    assert!(!root.join("evil").exists());
+    assert_eq!(std::fs::read(dest.join("KEPT.TXT")).unwrap(), b"kept");
+    std::fs::remove_dir_all(&root).unwrap();
+
+    for name in ["..", ".", "", "a/b", "a\\b", "/etc/x"] {
+        assert!(sd_controller::host_path(&dest, name).is_err(), "{name}");
+    }
+    assert_eq!(
+        sd_controller::host_path(&dest, "..notes").unwrap(),
+        dest.join("..notes")
+    );
+}
+
+#[test]
+fn recovered_files_stay_inside_the_destination() {
+    let mut controller = FatImageBuilder::fat16()
+        .file("/aa_evil", b"payload")
+        .build_controller()
+        .unwrap();
+    controller.delete_file("/aa_evil").unwrap();
+    patch_bytes(&mut controller, b"a\0a\0_\0e\0v\0", b".\0.\0/\0e\0v\0");
+
+    let root = std::env::temp_dir().join(format!("sd-recover-{}", std::process::id()));
+    let dest = root.join("out");
+    let recovered = controller.recover_all(&dest).unwrap();
+    assert_eq!(recovered.len(), 1);
+    assert_eq!(recovered[0].0.path, "/../evil");
+    assert!(recovered[0].1.starts_with(&dest), "{:?}", recovered[0].1);
+    assert_eq!(std::fs::read(&recovered[0].1).unwrap(), b"payload");
+    assert!(!root.join("evil").exists());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn short_names_are_decoded_with_the_chosen_code_page() {
+    let mut controller = FatImageBuilder::fat16()
+        .file("/CAFE.TXT", b"au lait")
+        .file("/PHOTO.JPG", b"jpeg")
+        .file("/\u{1F4F7} shot.jpg", b"jpeg")
+        .build_controller()
+        .unwrap();
+    // 0x9B is ¢ in cp437 and ø in cp850; 0x8E 0xCA 0x90 0x5E is 写真 in
+    // cp932.
+    patch_short_name(&mut controller, b"CAFE    TXT", b"CAF\x9B    TXT");
+    patch_short_name(&mut controller, b"PHOTO   JPG", b"\x8E\xCA\x90\x5E    JPG");
+    let names = |controller: &mut SDController<MemBlockDevice>| -> Vec<String> {
+        controller
+            .read_root_dir()
+            .unwrap()
+            .map(|entry| entry.full_name())
+            .collect()
+    };
+    assert_eq!(
+        names(&mut controller),
+        ["CAF¢.TXT", "Ä╩É^.JPG", "\u{1F4F7} shot.jpg"]
+    );
+
+    controller.set_codepage("850".parse().unwrap());
+    assert_eq!(names(&mut controller)[0], "CAFø.TXT");
+    assert_eq!(controller.open("/CAFø.TXT").unwrap(), b"au lait");
+    #[cfg(feature = "cjk")]
+    {
+        controller.set_codepage(sd_controller::Codepage::Cp932);
+        assert_eq!(names(&mut controller)[1], "写真.JPG");
+        assert_eq!(controller.open("/写真.JPG").unwrap(), b"jpeg");
+    }
+}
+
+#[test]
+fn throttled_devices_hold_reads_to_the_limit() {
+    let data: Vec<u8> = (0..2 << 20).map(|i| (i % 251) as u8).collect();
+    let inner = MemBlockDevice::from_vec(data.clone(), 512).unwrap();
+    let mut device = ThrottledDevice::new(inner, 8 << 20);
+    let mut buffer = vec![0u8; 64 << 10];
+    let started = Instant::now();
+    for (index, chunk) in data.chunks(buffer.len()).enumerate() {
+        device
+            .read_blocks((index * buffer.len() / 512) as u32, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, chunk);
+    }
+    // 2 MiB at 8 MiB/s is 250ms, less the burst allowed from idle.
+    assert!(started.elapsed() >= Duration::from_millis(140));
+}
+
+#[test]
+fn exfat_boot_sectors_with_impossible_sizes_are_refused() {
+    use sd_controller::ExFatBootSector;
+
+    let boot_sector = |bytes_shift: u8, cluster_shift: u8| {
+        let mut data = vec![0u8; 512];
+        data[3..11].copy_from_slice(b"EXFAT   ");
+        data[108] = bytes_shift;
+        data[109] = cluster_shift;
+        data[510..512].copy_from_slice(&[0x55, 0xAA]);
+        data
+    };
+    let sector = ExFatBootSector::parse(&boot_sector(9, 3)).unwrap();
+    assert_eq!(sector.layout().cluster_size(), 4096);
+    for (bytes_shift, cluster_shift) in [(40, 0), (8, 0), (13, 0), (9, 23), (12, 14)] {
+        assert!(
+            matches!(
+                ExFatBootSector::parse(&boot_sector(bytes_shift, cluster_shift)),
+                Err(SDError::Parse { .. })
+            ),
+            "{bytes_shift} {cluster_shift}"
+        );
+    }
+
+    let mut data = boot_sector(40, 0);
+    data.resize(64 * 512, 0);
+    let mut controller = SDController::from_device(MemBlockDevice::from_vec(data, 512).unwrap());
+    assert!(controller.read_exfat_boot_sector().is_err());
+    assert!(controller.read_root_dir().is_err());
+}
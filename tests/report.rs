@@ -0,0 +1,173 @@
+#![cfg(feature = "std")]
+
+use std::time::Duration;
+
+use sd_controller::testing::{FatImageBuilder, Fault, FaultyDevice};
+use sd_controller::{CapacityTest, Report, SDController, ScanOptions, Severity};
+
+#[test]
+fn clean_check_has_no_findings() {
+    let mut controller = FatImageBuilder::fat16()
+        .file("/A.TXT", b"a")
+        .build_controller()
+        .unwrap();
+    let report = controller.check_report().unwrap();
+    assert_eq!(report.operation, "check");
+    assert!(report.findings.is_empty());
+    assert_eq!(report.severity(), None);
+    assert!(report.is_ok());
+}
+
+#[test]
+fn check_findings_point_at_the_damage() {
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/DIR")
+        .build_controller()
+        .unwrap();
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let layout = controller.calculate_layout(&boot_sector);
+    // Allocate cluster 100 in the first FAT only: a lost chain, and a
+    // mismatch between the copies. Its entry is in the first FAT sector.
+    let fat_sector = layout.fat_start;
+    let mut block = controller.read_block(fat_sector).unwrap();
+    block[200..202].copy_from_slice(&0xFFFFu16.to_le_bytes());
+    controller.write_block(fat_sector, &block).unwrap();
+
+    let report = controller.check_report().unwrap();
+    let kinds: Vec<_> = report.findings.iter().map(|f| f.kind).collect();
+    assert_eq!(kinds, ["fat_mismatch", "lost_chain"]);
+    assert_eq!(report.severity(), Some(Severity::Error));
+    let backup = (fat_sector + layout.fat_size) as u64;
+    let mismatched = backup..backup + 1;
+    assert_eq!(report.findings[0].lbas, [mismatched]);
+    let cluster = layout.cluster_to_sector(100) as u64;
+    let lost = cluster..cluster + layout.sectors_per_cluster as u64;
+    assert_eq!(report.findings[1].severity, Severity::Warning);
+    assert_eq!(report.findings[1].lbas, [lost]);
+}
+
+#[test]
+fn scan_report_merges_unreadable_runs() {
+    let device = FatImageBuilder::fat16().build().unwrap();
+    let device = FaultyDevice::new(device)
+        .with_fault(5000, Fault::IoError)
+        .with_fault(5001, Fault::IoError)
+        .with_fault(9000, Fault::Transient(2));
+    let mut controller = SDController::from_device(device);
+    let options = ScanOptions {
+        retry_delay: Duration::ZERO,
+        ..ScanOptions::default()
+    };
+    let scan = controller
+        .scan_bad_blocks(0..controller.num_blocks(), &options, &mut ())
+        .unwrap();
+    let report = Report::from_scan(&scan);
+    assert_eq!(report.metrics["unreadable_blocks"], 2);
+    assert_eq!(report.metrics["scanned_blocks"], controller.num_blocks());
+    assert_eq!(report.findings.len(), 2);
+    assert_eq!(report.findings[0].kind, "unreadable_block");
+    let unreadable = 5000..5002;
+    assert_eq!(report.findings[0].lbas, [unreadable]);
+    assert_eq!(report.findings[1].kind, "recovered_block");
+    assert_eq!(report.findings[1].severity, Severity::Warning);
+
+    let mut csv = Vec::new();
+    report.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(
+        lines[0],
+        "operation,severity,kind,start_lba,end_lba,path,message"
+    );
+    assert_eq!(
+        lines[1],
+        "scan,error,unreadable_block,5000,5002,,2 block(s) from 5000 cannot be read"
+    );
+    assert_eq!(lines.len(), 3);
+}
+
+#[test]
+fn capacity_report_of_a_genuine_device_is_clean() {
+    let mut controller = FatImageBuilder::fat16()
+        .size(5 << 20)
+        .build_controller()
+        .unwrap();
+    let test = CapacityTest::default();
+    controller.capacity_write(&test, &mut ()).unwrap();
+    let capacity = controller.capacity_verify(&test, &mut ()).unwrap();
+    let report = Report::from_capacity(&capacity);
+    assert!(report.findings.is_empty());
+    assert_eq!(report.metrics["usable_bytes"], 5 << 20);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_report_lists_findings() {
+    let mut controller = FatImageBuilder::fat16()
+        .file("/A.TXT", b"a")
+        .build_controller()
+        .unwrap();
+    let mut report = controller.check_report().unwrap();
+    report.device = Some("card.img".to_string());
+    let mut json = Vec::new();
+    report.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains("\"operation\": \"check\""));
+    assert!(json.contains("\"device\": \"card.img\""));
+    assert!(json.contains("\"findings\": []"));
+}
+
+#[cfg(feature = "forensic")]
+#[test]
+fn acquisitions_hash_the_image_and_sign_the_log() {
+    use sd_controller::{
+        verify_audit_log, AcquireOptions, AuditLog, BlockDevice, HashAlgorithm, SDError,
+    };
+
+    let image = FatImageBuilder::fat16()
+        .file("/IMG_0001.JPG", &[0xFF; 3000])
+        .build()
+        .unwrap();
+    let expected = SDController::from_device(image.clone())
+        .hash_device(0..image.num_blocks(), HashAlgorithm::Sha256, &mut ())
+        .unwrap();
+    let mut controller = SDController::read_only(image.clone());
+    let mut copy = Vec::new();
+    let mut log = AuditLog::new(Vec::new(), Some(b"case key"));
+    let options = AcquireOptions {
+        case: Some("2026-114".to_string()),
+        ..AcquireOptions::default()
+    };
+    let report = controller
+        .acquire(&mut copy, &mut log, &options, &mut ())
+        .unwrap();
+    assert_eq!(copy, image.as_bytes());
+    assert_eq!(report.digests["sha256"], expected);
+    assert_eq!(report.verified, Some(true));
+    assert_eq!(report.audit_signature, log.last_signature());
+
+    let log = String::from_utf8(log.into_inner()).unwrap();
+    assert!(log
+        .lines()
+        .next()
+        .unwrap()
+        .contains("\"case\":\"2026-114\""));
+    assert_eq!(
+        verify_audit_log(log.as_bytes(), Some(b"case key")).unwrap(),
+        4
+    );
+    assert!(matches!(
+        verify_audit_log(log.as_bytes(), Some(b"another key")),
+        Err(SDError::AuditLogTampered { line: 1 })
+    ));
+    let dropped: String = log
+        .lines()
+        .enumerate()
+        .filter(|&(index, _)| index != 1)
+        .map(|(_, line)| format!("{line}\n"))
+        .collect();
+    assert!(matches!(
+        verify_audit_log(dropped.as_bytes(), Some(b"case key")),
+        Err(SDError::AuditLogTampered { line: 2 })
+    ));
+}
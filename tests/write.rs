@@ -0,0 +1,890 @@
+#![cfg(feature = "std")]
+
+use sd_controller::testing::FatImageBuilder;
+use sd_controller::{Attributes, BlockDevice, SDController, SDError};
+
+fn assert_clean<D: BlockDevice>(controller: &mut SDController<D>) {
+    let issues = controller.check().unwrap();
+    assert!(issues.is_empty(), "unexpected issues: {issues:?}");
+}
+
+fn write_and_check(builder: FatImageBuilder) {
+    let mut controller = builder.build_controller().unwrap();
+    let free_before = controller.usage().unwrap().free_bytes();
+
+    let data = vec![0xA5; 70_000];
+    controller.create_dir("/PHOTOS").unwrap();
+    controller
+        .create_file("/PHOTOS/IMG_0001.JPG", &data)
+        .unwrap();
+    controller
+        .create_file("/PHOTOS/a longer name.jpeg", b"x")
+        .unwrap();
+    controller.create_file("/EMPTY", b"").unwrap();
+    assert_clean(&mut controller);
+
+    assert_eq!(controller.open("/PHOTOS/IMG_0001.JPG").unwrap(), data);
+    assert_eq!(controller.open("/PHOTOS/a longer name.jpeg").unwrap(), b"x");
+    assert!(controller.open("/EMPTY").unwrap().is_empty());
+    assert!(controller.usage().unwrap().free_bytes() < free_before);
+
+    controller
+        .rename_file("/PHOTOS/IMG_0001.JPG", "/PHOTOS/BEACH.JPG")
+        .unwrap();
+    assert!(matches!(
+        controller.stat("/PHOTOS/IMG_0001.JPG"),
+        Err(SDError::NotFound(_))
+    ));
+    assert_eq!(controller.open("/PHOTOS/BEACH.JPG").unwrap(), data);
+
+    controller.delete_file("/PHOTOS/BEACH.JPG").unwrap();
+    controller
+        .delete_file("/PHOTOS/a longer name.jpeg")
+        .unwrap();
+    controller.delete_file("/EMPTY").unwrap();
+    assert_clean(&mut controller);
+    // The directory keeps its one cluster.
+    let cluster_size = controller.usage().unwrap().cluster_size;
+    assert_eq!(
+        controller.usage().unwrap().free_bytes(),
+        free_before - cluster_size
+    );
+}
+
+#[test]
+fn fat16_writes_stay_consistent() {
+    write_and_check(FatImageBuilder::fat16());
+}
+
+#[test]
+fn fat32_writes_stay_consistent() {
+    write_and_check(FatImageBuilder::fat32());
+}
+
+#[test]
+fn many_files_grow_a_directory() {
+    let mut controller = FatImageBuilder::fat32()
+        .dir("/LOGS")
+        .build_controller()
+        .unwrap();
+    for i in 0..200 {
+        controller
+            .create_file(
+                &format!("/LOGS/log file {i:03}.txt"),
+                i.to_string().as_bytes(),
+            )
+            .unwrap();
+    }
+    assert_clean(&mut controller);
+    let dir = controller.stat("/LOGS").unwrap();
+    assert_eq!(
+        controller
+            .open_dir(&dir)
+            .unwrap()
+            .filter(|e| !e.is_dir())
+            .count(),
+        200
+    );
+    assert_eq!(controller.open("/LOGS/log file 123.txt").unwrap(), b"123");
+}
+
+#[test]
+fn existing_names_are_refused() {
+    let mut controller = FatImageBuilder::fat16()
+        .file("/A.TXT", b"a")
+        .build_controller()
+        .unwrap();
+    assert!(controller.create_file("/a.txt", b"b").is_err());
+    assert_eq!(controller.open("/A.TXT").unwrap(), b"a");
+}
+
+#[test]
+fn new_names_get_unique_short_names_and_reserved_ones_are_refused() {
+    let mut controller = FatImageBuilder::fat16().build_controller().unwrap();
+    let short_names: Vec<String> = (1..=6)
+        .map(|i| {
+            controller
+                .create_file(&format!("/Holiday photo {i}.jpeg"), b"x")
+                .unwrap()
+                .short_name()
+        })
+        .collect();
+    assert_eq!(
+        short_names[..4],
+        [
+            "HOLIDA~1.JPE",
+            "HOLIDA~2.JPE",
+            "HOLIDA~3.JPE",
+            "HOLIDA~4.JPE"
+        ]
+    );
+    // Past ~4 the base is cut to two characters and a hash added.
+    let hashed = &short_names[4][..6];
+    assert!(hashed.starts_with("HO"), "{}", short_names[4]);
+    assert_ne!(short_names[4], short_names[5]);
+    for name in &short_names {
+        assert_eq!(controller.open(&format!("/{name}")).unwrap(), b"x");
+    }
+    assert_clean(&mut controller);
+
+    for name in [
+        "/CON",
+        "/nul.txt",
+        "/Lpt1.log",
+        "/trailing.",
+        "/space ",
+        "/a?b",
+        "/tab\tname",
+        "/..",
+    ] {
+        assert!(
+            matches!(
+                controller.create_file(name, b""),
+                Err(SDError::InvalidName(_))
+            ),
+            "{name} was accepted"
+        );
+    }
+    assert!(matches!(
+        controller.rename_file("/HOLIDA~1.JPE", "/AUX.JPG"),
+        Err(SDError::InvalidName(_))
+    ));
+    controller.create_file("/console.txt", b"").unwrap();
+}
+
+#[test]
+fn read_only_controllers_refuse_writes() {
+    let device = FatImageBuilder::fat16().build().unwrap();
+    let mut controller = SDController::from_device(device);
+    assert!(matches!(
+        controller.create_file("/NEW.TXT", b"data"),
+        Err(SDError::ReadOnly)
+    ));
+}
+
+#[test]
+fn filling_the_volume_reports_no_space() {
+    let mut controller = FatImageBuilder::fat16()
+        .size(5 << 20)
+        .build_controller()
+        .unwrap();
+    let free = controller.usage().unwrap().free_bytes() as usize;
+    assert!(controller
+        .create_file("/HUGE.BIN", &vec![0; free + 1])
+        .is_err());
+    assert_clean(&mut controller);
+    controller.create_file("/FITS.BIN", &vec![1; free]).unwrap();
+    assert_eq!(controller.usage().unwrap().free_bytes(), 0);
+    assert_clean(&mut controller);
+}
+
+#[test]
+fn attributes_toggle_without_touching_the_entry_kind() {
+    let mut controller = FatImageBuilder::fat16()
+        .dir("/FIRMWARE")
+        .file("/FIRMWARE/a long firmware name.bin", b"fw")
+        .build_controller()
+        .unwrap();
+    let path = "/FIRMWARE/a long firmware name.bin";
+    let entry = controller.stat(path).unwrap();
+    assert_eq!(entry.attributes, Attributes::ARCHIVE);
+
+    let updated = controller
+        .set_attributes(path, Attributes::READ_ONLY | Attributes::HIDDEN)
+        .unwrap();
+    assert!(updated.attributes.is_read_only() && updated.attributes.is_hidden());
+    assert!(!updated.attributes.is_archive());
+    let reread = controller.stat(path).unwrap();
+    assert_eq!(reread.attributes, updated.attributes);
+    assert_eq!(
+        reread.long_name.as_deref(),
+        Some("a long firmware name.bin")
+    );
+    assert_eq!(reread.attributes.to_string(), "RH--");
+
+    // The directory bit is not one of the settable ones.
+    let dir = controller
+        .set_attributes("/FIRMWARE", Attributes::SYSTEM)
+        .unwrap();
+    assert!(dir.is_dir() && dir.attributes.is_system());
+    assert_clean(&mut controller);
+}
+
+#[test]
+fn fat32_fs_info_follows_writes() {
+    let mut controller = FatImageBuilder::fat32().build_controller().unwrap();
+    let fresh = controller.fsinfo().unwrap();
+    controller.create_file("/A.BIN", &[1; 5000]).unwrap();
+    controller.create_file("/B.BIN", &[2; 5000]).unwrap();
+    let after_writes = controller.fsinfo().unwrap();
+    assert_eq!(
+        after_writes.free_clusters,
+        Some(controller.usage().unwrap().free_clusters)
+    );
+    assert!(after_writes.next_free > fresh.next_free);
+
+    // Freed clusters count again, but allocation carries on past them
+    // rather than returning to the start of the FAT.
+    let a = controller.stat("/A.BIN").unwrap();
+    controller.delete_file("/A.BIN").unwrap();
+    assert_eq!(
+        controller.free_clusters().unwrap(),
+        controller.usage().unwrap().free_clusters
+    );
+    let c = controller.create_file("/C.BIN", b"c").unwrap();
+    assert!(c.first_cluster > a.first_cluster);
+    assert_eq!(
+        controller.fsinfo().unwrap().next_free,
+        Some(c.first_cluster + 1)
+    );
+    assert_clean(&mut controller);
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn direct_io_reads_and_writes_through_aligned_buffers() {
+    use sd_controller::{open_raw_device, RawOptions};
+
+    let image = FatImageBuilder::fat16()
+        .file("/A.TXT", b"a")
+        .build()
+        .unwrap();
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("direct.img");
+    std::fs::write(&path, image.as_bytes()).unwrap();
+
+    let options = RawOptions {
+        writable: true,
+        direct: true,
+        ..RawOptions::default()
+    };
+    let mut controller = match open_raw_device(&path, options) {
+        Err(SDError::Unsupported(_)) => return,
+        result => result.unwrap(),
+    };
+    assert!(controller.device().is_direct());
+    // An odd offset into a vector is never page aligned.
+    let unaligned = vec![0x5A; 2 * 512 + 1];
+    controller.write_blocks(5000, &unaligned[1..]).unwrap();
+    assert_eq!(controller.read_blocks(5000, 2).unwrap(), unaligned[1..]);
+    controller.create_file("/B.TXT", b"direct").unwrap();
+    assert_eq!(controller.open("/B.TXT").unwrap(), b"direct");
+    assert_clean(&mut controller);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[test]
+fn io_uring_transfers_span_many_requests() {
+    use sd_controller::{FileDevice, UringDevice};
+
+    let image = FatImageBuilder::fat32().build().unwrap();
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("uring.img");
+    std::fs::write(&path, image.as_bytes()).unwrap();
+
+    // Containers often forbid io_uring altogether.
+    let device = match UringDevice::new(FileDevice::open_rw(&path).unwrap()) {
+        Err(SDError::IO(_)) => return,
+        result => result.unwrap(),
+    };
+    let mut controller = SDController::from_device(device);
+    controller.enable_writes();
+    let data: Vec<u8> = (0..3 << 20).map(|i: u32| (i % 251) as u8).collect();
+    controller.write_blocks(4096, &data).unwrap();
+    assert_eq!(
+        controller
+            .read_blocks(4096, (data.len() / 512) as u32)
+            .unwrap(),
+        data
+    );
+    controller
+        .create_file("/URING.BIN", &data[..200_000])
+        .unwrap();
+    assert_eq!(controller.open("/URING.BIN").unwrap(), data[..200_000]);
+    let past_end = controller
+        .read_blocks(controller.num_blocks() as u32 - 1, 2)
+        .unwrap_err();
+    assert!(matches!(past_end.root_cause(), SDError::BlockOutOfRange(_)));
+    assert_clean(&mut controller);
+
+    let mut reopened = SDController::from_device(FileDevice::open(&path).unwrap());
+    assert_eq!(reopened.read_blocks(4096, 8).unwrap(), data[..4096]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn memory_mapped_images_are_written_in_place() {
+    use sd_controller::{FileDevice, MmapDevice};
+
+    let image = FatImageBuilder::fat32().build().unwrap();
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("mapped.img");
+    std::fs::write(&path, image.as_bytes()).unwrap();
+
+    let mut controller = SDController::from_device(MmapDevice::open_rw(&path).unwrap());
+    controller.enable_writes();
+    controller.create_file("/MAPPED.TXT", b"mapped").unwrap();
+    let past_end = controller
+        .read_block(controller.num_blocks() as u32)
+        .unwrap_err();
+    assert!(matches!(past_end.root_cause(), SDError::BlockOutOfRange(_)));
+    controller.flush().unwrap();
+    drop(controller);
+
+    let mut reopened = SDController::from_device(FileDevice::open(&path).unwrap());
+    assert_eq!(reopened.open("/MAPPED.TXT").unwrap(), b"mapped");
+    assert_clean(&mut reopened);
+
+    let mut read_only = SDController::from_device(MmapDevice::open(&path).unwrap());
+    read_only.enable_writes();
+    let refused = read_only.write_block(0, &[0; 512]).unwrap_err();
+    assert!(matches!(refused.root_cause(), SDError::ReadOnly));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn clones_copy_used_clusters_and_refuse_small_targets() {
+    use sd_controller::{CloneOptions, MemBlockDevice, Verify};
+
+    let mut source = FatImageBuilder::fat32()
+        .dir("/DIR")
+        .file("/DIR/DATA.BIN", &vec![0x42; 100_000])
+        .file("/A.TXT", b"a")
+        .build_controller()
+        .unwrap();
+    let blocks = source.num_blocks();
+    let mut target = MemBlockDevice::new(512, blocks + 64).unwrap();
+    target.as_bytes_mut().fill(0xEE);
+    let mut dest = SDController::from_device(target);
+    dest.enable_writes();
+
+    let options = CloneOptions {
+        used_only: true,
+        verify: Verify::Checksum,
+    };
+    let report = source.clone_to(&mut dest, &options, &mut ()).unwrap();
+    assert!(report.skipped_blocks > report.copied_blocks);
+    assert_eq!(report.copied_blocks + report.skipped_blocks, blocks);
+    assert!(source
+        .diff_files(&mut dest, &mut ())
+        .unwrap()
+        .is_identical());
+    assert_eq!(dest.open("/DIR/DATA.BIN").unwrap(), vec![0x42; 100_000]);
+    // Free space keeps what the destination held.
+    assert_eq!(dest.read_block(blocks as u32 - 1).unwrap(), [0xEE; 512]);
+    assert_clean(&mut dest);
+
+    let report = source
+        .clone_to(&mut dest, &CloneOptions::default(), &mut ())
+        .unwrap();
+    assert_eq!(report.skipped_blocks, 0);
+    let diff = source.diff_blocks(&mut dest, &mut ()).unwrap();
+    assert!(diff.differing.is_empty());
+
+    let mut small = SDController::from_device(MemBlockDevice::new(512, blocks - 1).unwrap());
+    small.enable_writes();
+    let refused = source.clone_to(&mut small, &CloneOptions::default(), &mut ());
+    assert!(matches!(refused, Err(SDError::DestinationTooSmall { .. })));
+}
+
+#[test]
+fn free_space_wipes_keep_files_and_clear_deleted_data() {
+    use sd_controller::{WipeOptions, WipePass};
+
+    let mut controller = FatImageBuilder::fat16()
+        .file("/KEEP.BIN", &vec![0x11; 9_000])
+        .file("/GONE.BIN", &vec![0x77; 9_000])
+        .build_controller()
+        .unwrap();
+    controller.delete_file("/GONE.BIN").unwrap();
+
+    let mut options = WipeOptions::with_passes(&[WipePass::Random, WipePass::Byte(0xA5)]);
+    options.verify = true;
+    let wiped = controller.wipe_free_space(&options, &mut ()).unwrap();
+    assert!(wiped > 0);
+    assert_eq!(controller.open("/KEEP.BIN").unwrap(), vec![0x11; 9_000]);
+    assert_clean(&mut controller);
+    let blocks = controller.num_blocks() as u32;
+    let image = controller.read_blocks(0, blocks).unwrap();
+    assert!(!image.windows(16).any(|run| run == [0x77; 16]));
+
+    let options = WipeOptions {
+        verify: true,
+        ..WipeOptions::three_pass()
+    };
+    controller
+        .wipe(0..blocks as u64, &options, &mut ())
+        .unwrap();
+    assert!(controller.stat("/KEEP.BIN").is_err());
+
+    let device = FatImageBuilder::fat16().build().unwrap();
+    let mut read_only = SDController::from_device(device);
+    let refused = read_only.wipe(0..1, &WipeOptions::zeros(), &mut ());
+    assert!(matches!(refused, Err(SDError::ReadOnly)));
+}
+
+#[test]
+fn discarding_free_space_leaves_files_alone() {
+    let mut controller = FatImageBuilder::fat32()
+        .file("/KEEP.BIN", &vec![0x11; 5_000])
+        .file("/GONE.BIN", &vec![0x77; 5_000])
+        .build_controller()
+        .unwrap();
+    controller.delete_file("/GONE.BIN").unwrap();
+    let discarded = controller.discard_free_space().unwrap();
+    assert!(discarded > 0);
+    assert_eq!(controller.open("/KEEP.BIN").unwrap(), vec![0x11; 5_000]);
+    assert_clean(&mut controller);
+    // Memory devices read discarded blocks as zeros.
+    let blocks = controller.num_blocks() as u32;
+    let image = controller.read_blocks(0, blocks).unwrap();
+    assert!(!image.windows(16).any(|run| run == [0x77; 16]));
+}
+
+#[test]
+fn interrupted_defragmentation_resumes_or_rolls_back() {
+    use sd_controller::testing::{Fault, FaultyDevice};
+    use sd_controller::JournalState;
+
+    let mut controller = FatImageBuilder::fat16()
+        .sectors_per_cluster(4)
+        .file("/A.BIN", &[1; 4096])
+        .file("/B.BIN", &[2; 4096])
+        .file("/C.BIN", &[3; 4096])
+        .build_controller()
+        .unwrap();
+    controller.delete_file("/B.BIN").unwrap();
+    let data: Vec<u8> = (0..12_000u32).map(|i| i as u8).collect();
+    let entry = controller.create_file("/D.BIN", &data).unwrap();
+    let chain: Vec<u32> = controller
+        .cluster_chain(entry.first_cluster)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(chain[1], chain[0] + 1);
+    assert_ne!(chain[2], chain[1] + 1, "D.BIN should be fragmented");
+
+    // Reading the second run of D.BIN fails, cutting the copy short.
+    let boot_sector = controller.read_boot_sector().unwrap();
+    let layout = controller.calculate_layout(&boot_sector);
+    let bad = layout.cluster_to_sector(chain[2]) as u64;
+    let mut controller = SDController::from_device(
+        FaultyDevice::new(controller.into_inner()).with_fault(bad, Fault::IoError),
+    );
+    controller.enable_writes();
+    let journal = std::env::temp_dir().join(format!("sd-defrag-{}.journal", std::process::id()));
+    assert!(controller.defragment(&journal, &mut ()).is_err());
+    assert!(matches!(
+        controller.defragment(&journal, &mut ()).unwrap_err(),
+        SDError::Interrupted(_)
+    ));
+    let mut device = controller.into_inner();
+    device.clear_faults();
+    let mut controller = SDController::from_device(device);
+    controller.enable_writes();
+    let interrupted = controller.defragment_journal(&journal).unwrap();
+    assert_eq!(
+        (interrupted.path.as_str(), interrupted.state),
+        ("/D.BIN", JournalState::Copying)
+    );
+
+    let saved = std::fs::read(&journal).unwrap();
+    let mut copy = SDController::from_device(controller.device().clone());
+    copy.enable_writes();
+    assert_eq!(copy.roll_back_defragment(&journal).unwrap(), "/D.BIN");
+    assert_eq!(
+        copy.cluster_chain(entry.first_cluster).unwrap().count(),
+        chain.len()
+    );
+    assert_eq!(copy.open("/D.BIN").unwrap(), data);
+    assert_clean(&mut copy);
+
+    std::fs::write(&journal, saved).unwrap();
+    assert_eq!(controller.resume_defragment(&journal).unwrap(), "/D.BIN");
+    assert!(!journal.exists());
+    let moved = controller.stat("/D.BIN").unwrap();
+    let clusters: Vec<u32> = controller
+        .cluster_chain(moved.first_cluster)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert!(clusters.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    assert_eq!(controller.open("/D.BIN").unwrap(), data);
+    assert_clean(&mut controller);
+
+    let report = controller.defragment(&journal, &mut ()).unwrap();
+    assert_eq!((report.fragmented, report.moved.len()), (0, 0));
+}
+
+#[test]
+fn overlays_keep_writes_off_the_base_until_committed() {
+    use sd_controller::OverlayDevice;
+
+    let base = FatImageBuilder::fat32()
+        .file("/KEEP.TXT", b"kept")
+        .build()
+        .unwrap();
+    let original = base.as_bytes().to_vec();
+    let store = std::env::temp_dir().join(format!("sd-overlay-{}.ovl", std::process::id()));
+    let _ = std::fs::remove_file(&store);
+
+    let mut controller = SDController::from_device(OverlayDevice::with_file(base, &store).unwrap());
+    controller.enable_writes();
+    controller.create_file("/NEW.TXT", b"new").unwrap();
+    controller.delete_file("/KEEP.TXT").unwrap();
+    assert_clean(&mut controller);
+    let mut overlay = controller.into_inner();
+    assert_eq!(overlay.base().as_bytes(), original);
+    let diff = overlay.diff().unwrap();
+    assert!(!diff.is_identical());
+    let stored: u64 = overlay
+        .changed_blocks()
+        .iter()
+        .map(|run| run.end - run.start)
+        .sum();
+    assert!(diff.differing_blocks() <= stored);
+
+    // A later run over the same file sees the changes.
+    let mut reopened =
+        SDController::from_device(OverlayDevice::with_file(overlay.into_base(), &store).unwrap());
+    assert_eq!(reopened.open("/NEW.TXT").unwrap(), b"new");
+    assert!(reopened.open("/KEEP.TXT").is_err());
+    let mut base = SDController::from_device(reopened.device().base().clone());
+    assert_eq!(base.open("/KEEP.TXT").unwrap(), b"kept");
+
+    let mut overlay = reopened.into_inner();
+    let written = overlay.commit().unwrap();
+    assert_eq!(written, stored);
+    assert!(overlay.changed_blocks().is_empty());
+    let mut committed = SDController::from_device(overlay.into_base());
+    assert_eq!(committed.open("/NEW.TXT").unwrap(), b"new");
+    assert_clean(&mut committed);
+    std::fs::remove_file(&store).unwrap();
+}
+
+#[test]
+fn read_only_controllers_never_reach_the_device() {
+    use sd_controller::ReadOnlyController;
+
+    let image = FatImageBuilder::fat16()
+        .file("/EVIDENCE.JPG", b"exhibit")
+        .build()
+        .unwrap();
+    let original = image.clone();
+    let mut controller = ReadOnlyController::read_only(image);
+    assert_eq!(controller.open("/EVIDENCE.JPG").unwrap(), b"exhibit");
+    controller.enable_writes();
+    let error = controller.create_file("/NEW.TXT", b"x").unwrap_err();
+    assert!(matches!(error.root_cause(), SDError::ReadOnly));
+    assert!(controller.delete_file("/EVIDENCE.JPG").is_err());
+    assert!(controller.discard_blocks(0, 1).is_err());
+    assert_eq!(controller.into_inner().into_inner(), original);
+}
+
+#[test]
+fn nbd_clients_read_and_write_the_export() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use sd_controller::{MemBlockDevice, NbdOptions};
+
+    fn u32_at(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes(bytes[..4].try_into().unwrap())
+    }
+
+    fn request(stream: &mut TcpStream, command: u16, offset: u64, length: u32, data: &[u8]) {
+        let mut header = 0x2560_9513u32.to_be_bytes().to_vec();
+        header.extend_from_slice(&0u16.to_be_bytes());
+        header.extend_from_slice(&command.to_be_bytes());
+        header.extend_from_slice(&7u64.to_be_bytes());
+        header.extend_from_slice(&offset.to_be_bytes());
+        header.extend_from_slice(&length.to_be_bytes());
+        stream.write_all(&header).unwrap();
+        stream.write_all(data).unwrap();
+    }
+
+    /// The error of a simple reply, after checking its handle.
+    fn reply(stream: &mut TcpStream) -> u32 {
+        let mut reply = [0; 16];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(u32_at(&reply), 0x6744_6698);
+        assert_eq!(&reply[8..], &7u64.to_be_bytes());
+        u32_at(&reply[4..])
+    }
+
+    let mut device = MemBlockDevice::new(512, 64).unwrap();
+    device.as_bytes_mut()[512..516].copy_from_slice(b"BOOT");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let mut controller = SDController::from_device(device);
+        controller.enable_writes();
+        let (stream, _) = listener.accept().unwrap();
+        let stats = controller
+            .serve_nbd(stream, &NbdOptions::default())
+            .unwrap();
+        (controller.into_inner(), stats)
+    });
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    let mut greeting = [0; 18];
+    stream.read_exact(&mut greeting).unwrap();
+    assert_eq!(&greeting[..8], b"NBDMAGIC");
+    stream.write_all(&3u32.to_be_bytes()).unwrap();
+    let mut go = b"IHAVEOPT".to_vec();
+    go.extend_from_slice(&7u32.to_be_bytes());
+    go.extend_from_slice(&(4 + 2 + 2u32).to_be_bytes());
+    go.extend_from_slice(&2u32.to_be_bytes());
+    go.extend_from_slice(b"sd");
+    go.extend_from_slice(&0u16.to_be_bytes());
+    stream.write_all(&go).unwrap();
+    let mut export_size = None;
+    loop {
+        let mut header = [0; 20];
+        stream.read_exact(&mut header).unwrap();
+        let mut data = vec![0; u32_at(&header[16..]) as usize];
+        stream.read_exact(&mut data).unwrap();
+        match u32_at(&header[12..]) {
+            1 => break,
+            3 if data[..2] == [0, 0] => {
+                export_size = Some(u64::from_be_bytes(data[2..10].try_into().unwrap()))
+            }
+            3 => {}
+            other => panic!("unexpected option reply {other:#x}"),
+        }
+    }
+    assert_eq!(export_size, Some(64 * 512));
+
+    request(&mut stream, 0, 512, 4, &[]);
+    assert_eq!(reply(&mut stream), 0);
+    let mut data = [0; 4];
+    stream.read_exact(&mut data).unwrap();
+    assert_eq!(&data, b"BOOT");
+
+    request(&mut stream, 1, 1000, 100, &[0xEE; 100]);
+    assert_eq!(reply(&mut stream), 0);
+    request(&mut stream, 1, 64 * 512 - 2, 4, &[1; 4]);
+    assert_eq!(reply(&mut stream), 28);
+    // An offset and length that overflow together are refused, not wrapped.
+    request(&mut stream, 4, u64::MAX - 1, 1024, &[]);
+    assert_eq!(reply(&mut stream), 22);
+    request(&mut stream, 3, 0, 0, &[]);
+    assert_eq!(reply(&mut stream), 0);
+    request(&mut stream, 2, 0, 0, &[]);
+
+    let (device, stats) = server.join().unwrap();
+    assert_eq!(&device.as_bytes()[1000..1100], &[0xEE; 100]);
+    assert_eq!(device.as_bytes()[999], 0);
+    assert_eq!(device.as_bytes()[1100], 0);
+    assert_eq!(
+        (
+            stats.requests,
+            stats.bytes_read,
+            stats.bytes_written,
+            stats.errors
+        ),
+        (6, 4, 100, 2)
+    );
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn config_files_name_devices_and_gate_writes() {
+    use std::path::Path;
+
+    use sd_controller::Config;
+
+    let path = std::env::temp_dir().join(format!("sd-config-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "cache_blocks = 64\n\
+         [aliases]\n\
+         camera = \"/dev/mmcblk0\"\n\
+         [safety]\n\
+         allow_write = [\"/dev/mmcblk[0-9]\", \"/dev/sd?\"]\n\
+         deny_write = [\"/dev/sda\"]\n",
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.cache_blocks, Some(64));
+    assert_eq!(config.block_size, None);
+    let camera = config.resolve_device(Path::new("camera"));
+    assert_eq!(camera, Path::new("/dev/mmcblk0"));
+    assert_eq!(
+        config.resolve_device(Path::new("card.img")),
+        Path::new("card.img")
+    );
+    assert!(config.allows_write(camera));
+    assert!(!config.allows_write(Path::new("/dev/mmcblk0p1")));
+    assert!(config.allows_write(Path::new("/dev/sdb")));
+    assert!(config.denies_write(Path::new("/dev/sda")));
+
+    std::fs::write(&path, "cache-size = 64\n").unwrap();
+    let error = Config::load(&path).err().unwrap();
+    assert!(matches!(error, SDError::InvalidConfig { .. }), "{error}");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn dry_runs_describe_writes_without_making_them() {
+    use sd_controller::{DryRunDevice, WriteKind};
+
+    let image = FatImageBuilder::fat16()
+        .file("/HELLO.TXT", b"Hello")
+        .build()
+        .unwrap();
+    let before = image.as_bytes().to_vec();
+    let mut controller = SDController::from_device(DryRunDevice::new(image));
+    controller.enable_writes();
+    controller.open_volume().unwrap();
+    controller.create_file("/NEW.TXT", b"new").unwrap();
+    assert_eq!(controller.open("/NEW.TXT").unwrap(), b"new");
+
+    let writes = controller.device().writes().to_vec();
+    assert!(writes.iter().all(|write| write.kind == WriteKind::Write));
+    let changes: Vec<&str> = writes
+        .iter()
+        .flat_map(|write| &write.changes)
+        .map(String::as_str)
+        .collect();
+    assert!(
+        changes.contains(&"add root directory entry NEW.TXT"),
+        "{changes:?}"
+    );
+    assert!(
+        changes
+            .iter()
+            .any(|change| change.starts_with("update FAT entry") && change.ends_with("-> EOC")),
+        "{changes:?}"
+    );
+    assert!(changes
+        .iter()
+        .any(|change| change.starts_with("write cluster")));
+    assert_eq!(controller.into_inner().into_base().as_bytes(), before);
+}
+
+#[test]
+fn boot_sector_fields_are_edited_with_the_backup_in_sync() {
+    use sd_controller::BpbField;
+
+    let mut controller = FatImageBuilder::fat32().build_controller().unwrap();
+    let mut bpb = controller.read_bpb().unwrap();
+    assert_eq!(bpb.get(BpbField::BackupBootSector), Some(6));
+    bpb.set(BpbField::VolumeId, 0xCAFE_F00D).unwrap();
+    assert!(bpb.set(BpbField::SectorsPerCluster, 256).is_err());
+    controller.write_bpb(&bpb).unwrap();
+    assert_eq!(controller.volume_id().unwrap(), Some(0xCAFE_F00D));
+    assert_eq!(controller.read_block(6).unwrap(), bpb.as_bytes());
+
+    // A FAT too small for the clusters is grown to fit them.
+    let needed = bpb.get(BpbField::SectorsPerFat32).unwrap();
+    bpb.set(BpbField::SectorsPerFat32, needed / 2).unwrap();
+    let changes = bpb.recompute(0);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].field, BpbField::SectorsPerFat32);
+    assert!(changes[0].new >= needed - 1);
+
+    bpb.set(BpbField::SectorsPerCluster, 3).unwrap();
+    assert!(matches!(
+        controller.write_bpb(&bpb),
+        Err(SDError::Parse { offset: 13, .. })
+    ));
+    assert_clean(&mut controller);
+
+    let mut fat16 = FatImageBuilder::fat16().build_controller().unwrap();
+    let mut bpb = fat16.read_bpb().unwrap();
+    assert_eq!(bpb.get(BpbField::RootCluster), None);
+    assert!(bpb.set(BpbField::RootCluster, 2).is_err());
+}
+
+#[test]
+fn clusters_are_read_and_written_by_number() {
+    let mut controller = FatImageBuilder::fat16()
+        .file("/DATA.BIN", &[7; 3000])
+        .build_controller()
+        .unwrap();
+    let entry = controller.stat("/DATA.BIN").unwrap();
+    let cluster = controller.read_cluster(entry.first_cluster).unwrap();
+    assert_eq!(cluster.len(), 2048);
+    assert!(cluster.iter().all(|&b| b == 7));
+
+    controller
+        .write_cluster(entry.first_cluster, &[9; 2048])
+        .unwrap();
+    let data = controller.read_file(&entry).unwrap();
+    assert_eq!(&data[..2048], &[9; 2048][..]);
+    assert_eq!(&data[2048..], &[7; 952][..]);
+
+    assert!(matches!(
+        controller.read_cluster(1),
+        Err(SDError::InvalidCluster(1))
+    ));
+    assert!(controller
+        .write_cluster(entry.first_cluster, &[0; 512])
+        .is_err());
+    assert_clean(&mut controller);
+}
+
+#[test]
+fn a_refused_discard_keeps_cached_writes() {
+    use sd_controller::{CachedDevice, MemBlockDevice};
+
+    /// A device with the trait's default discard, which is unsupported.
+    struct NoDiscard(MemBlockDevice);
+
+    impl BlockDevice for NoDiscard {
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.0.num_blocks()
+        }
+
+        fn read_block(&mut self, block_index: u32, buffer: &mut [u8]) -> Result<(), SDError> {
+            self.0.read_block(block_index, buffer)
+        }
+
+        fn write_block(&mut self, block_index: u32, data: &[u8]) -> Result<(), SDError> {
+            self.0.write_block(block_index, data)
+        }
+    }
+
+    let mut cache = CachedDevice::new(NoDiscard(MemBlockDevice::new(512, 16).unwrap()), 8);
+    cache.write_block(3, &[0xAB; 512]).unwrap();
+    assert!(matches!(cache.discard(0, 8), Err(SDError::Unsupported(_))));
+    cache.flush().unwrap();
+    assert_eq!(&cache.inner().0.as_bytes()[3 * 512..4 * 512], &[0xAB; 512]);
+}
+
+#[test]
+fn replacing_imports_keep_the_old_file_until_the_new_one_is_written() {
+    use sd_controller::OverwritePolicy;
+
+    let mut controller = FatImageBuilder::fat16()
+        .size(5 << 20)
+        .file("/Field notes.txt", b"old notes")
+        .build_controller()
+        .unwrap();
+    let src = std::env::temp_dir().join(format!("sd-import-{}", std::process::id()));
+    std::fs::create_dir_all(&src).unwrap();
+    let host = src.join("Field notes.txt");
+
+    std::fs::write(&host, b"new notes").unwrap();
+    let summary = controller
+        .import(&src, "/", OverwritePolicy::Replace)
+        .unwrap();
+    assert_eq!(summary.files, 1);
+    assert_eq!(controller.open("/Field notes.txt").unwrap(), b"new notes");
+    assert_clean(&mut controller);
+
+    // Too big to sit beside the old copy, so the old copy stays.
+    let free = controller.usage().unwrap().free_bytes() as usize;
+    std::fs::write(&host, vec![7; free + 1]).unwrap();
+    assert!(controller
+        .import(&src, "/", OverwritePolicy::Replace)
+        .is_err());
+    std::fs::remove_dir_all(&src).unwrap();
+    assert_eq!(controller.open("/Field notes.txt").unwrap(), b"new notes");
+    assert_eq!(controller.usage().unwrap().free_bytes() as usize, free);
+    assert_clean(&mut controller);
+}